@@ -0,0 +1,86 @@
+//! Large-value SET arriving in small reads
+//!
+//! Compares the pending-storage path before and after the command line is
+//! consumed up front: `rescan` keeps the command line buffered, lets the
+//! buffer grow one read at a time and clones the key on every retry, while
+//! `consume_line` drops the line, reserves room for the whole data block and
+//! moves the key out once.
+
+use bytes::BytesMut;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use petracache::protocol::{
+    ParseLimits, ParseResult, parse_storage_command_line, parse_storage_data,
+};
+use std::hint::black_box;
+
+const READ_SIZE: usize = 8 * 1024;
+
+fn request(value_len: usize) -> Vec<u8> {
+    let mut buf = format!("set some:fairly:long:cache:key 0 0 {value_len}\r\n").into_bytes();
+    buf.resize(buf.len() + value_len, b'x');
+    buf.extend_from_slice(b"\r\n");
+    buf
+}
+
+fn rescan(request: &[u8], limits: &ParseLimits) -> usize {
+    let mut read_buf = BytesMut::new();
+    let mut reads = request.chunks(READ_SIZE);
+    read_buf.extend_from_slice(reads.next().unwrap());
+    let pending = parse_storage_command_line(&read_buf, limits)
+        .unwrap()
+        .unwrap();
+    for chunk in reads {
+        read_buf.extend_from_slice(chunk);
+        let mut retry = pending.clone();
+        if let ParseResult::Complete(cmd, consumed) =
+            parse_storage_data(&read_buf, &mut retry, limits)
+        {
+            black_box(cmd);
+            return consumed;
+        }
+    }
+    unreachable!("request never completed")
+}
+
+fn consume_line(request: &[u8], limits: &ParseLimits) -> usize {
+    let mut read_buf = BytesMut::new();
+    let mut reads = request.chunks(READ_SIZE);
+    read_buf.extend_from_slice(reads.next().unwrap());
+    let mut pending = parse_storage_command_line(&read_buf, limits)
+        .unwrap()
+        .unwrap();
+    let _ = read_buf.split_to(pending.data_start);
+    pending.data_start = 0;
+    read_buf.reserve(pending.bytes + 2);
+    for chunk in reads {
+        read_buf.extend_from_slice(chunk);
+        if let ParseResult::Complete(cmd, consumed) =
+            parse_storage_data(&read_buf, &mut pending, limits)
+        {
+            black_box(cmd);
+            return consumed;
+        }
+    }
+    unreachable!("request never completed")
+}
+
+fn bench_large_set(c: &mut Criterion) {
+    let limits = ParseLimits::default();
+    let mut group = c.benchmark_group("large_set");
+    for value_len in [64 * 1024, 1024 * 1024 - 64] {
+        let request = request(value_len);
+        group.throughput(Throughput::Bytes(request.len() as u64));
+        group.bench_with_input(BenchmarkId::new("rescan", value_len), &request, |b, r| {
+            b.iter(|| rescan(r, &limits));
+        });
+        group.bench_with_input(
+            BenchmarkId::new("consume_line", value_len),
+            &request,
+            |b, r| b.iter(|| consume_line(r, &limits)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_large_set);
+criterion_main!(benches);
@@ -0,0 +1,56 @@
+//! Pipelined `set ... noreply` flood against RocksDB
+//!
+//! Compares storing each set with its own write (`set`) against storing a
+//! pipeline's worth with one write batch (`set_batch`), as the connection
+//! loop does for consecutive sets.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use petracache::config::StorageConfig;
+use petracache::storage::{RocksStorage, StoredValue};
+use tempfile::TempDir;
+
+const VALUE_LEN: usize = 100;
+
+fn items(n: usize) -> Vec<(Vec<u8>, StoredValue)> {
+    (0..n)
+        .map(|i| {
+            (
+                format!("flood:key:{i}").into_bytes(),
+                StoredValue::new(0, 0, vec![b'x'; VALUE_LEN]),
+            )
+        })
+        .collect()
+}
+
+fn bench_set_flood(c: &mut Criterion) {
+    let tmp_dir = TempDir::new().unwrap();
+    let storage = RocksStorage::open(&StorageConfig {
+        db_path: tmp_dir.path().join("bench_db"),
+        ..StorageConfig::default()
+    })
+    .unwrap();
+
+    let mut group = c.benchmark_group("set_flood");
+    for batch_size in [16, 64, 256] {
+        let items = items(batch_size);
+        group.throughput(Throughput::Elements(batch_size as u64));
+        group.bench_with_input(BenchmarkId::new("set", batch_size), &items, |b, items| {
+            b.iter(|| {
+                for (key, value) in items {
+                    storage.set(key, value.clone()).unwrap();
+                }
+            });
+        });
+        group.bench_with_input(
+            BenchmarkId::new("set_batch", batch_size),
+            &items,
+            |b, items| {
+                b.iter(|| storage.set_batch(&mut items.clone()).unwrap());
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_set_flood);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Concurrent `set` throughput with one RocksDB instance vs several shards
+//!
+//! Every thread writes its own keys. With one instance all writers queue
+//! on its single write path; with `storage.shards = 4` keys hash over four
+//! independent instances, so on a multi-core box more writes proceed at
+//! once.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use petracache::config::StorageConfig;
+use petracache::storage::{RocksStorage, StoredValue};
+use std::thread;
+use tempfile::TempDir;
+
+const VALUE_LEN: usize = 100;
+const SETS_PER_THREAD: usize = 1000;
+
+fn bench_sharded_set(c: &mut Criterion) {
+    let threads = thread::available_parallelism().map_or(4, usize::from);
+    let keys: Vec<Vec<Vec<u8>>> = (0..threads)
+        .map(|t| {
+            (0..SETS_PER_THREAD)
+                .map(|i| format!("shard:key:{t}:{i}").into_bytes())
+                .collect()
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("sharded_set");
+    group.throughput(Throughput::Elements((threads * SETS_PER_THREAD) as u64));
+    for shards in [1, 4] {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("bench_db"),
+            shards,
+            ..StorageConfig::default()
+        })
+        .unwrap();
+
+        group.bench_with_input(BenchmarkId::new("shards", shards), &keys, |b, keys| {
+            b.iter(|| {
+                thread::scope(|scope| {
+                    for thread_keys in keys {
+                        let storage = &storage;
+                        scope.spawn(move || {
+                            for key in thread_keys {
+                                let value = StoredValue::new(0, 0, vec![b'x'; VALUE_LEN]);
+                                storage.set(key, value).unwrap();
+                            }
+                        });
+                    }
+                });
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_sharded_set);
+criterion_main!(benches);
@@ -0,0 +1,287 @@
+//! Audit log of destructive operations (`[audit]`)
+//!
+//! `delete`, `md`, `flush_all` (ASCII, meta and binary) and every admin
+//! endpoint call are recorded as JSON lines with the time, peer, command,
+//! key and outcome. Requests only format the line and hand it to a bounded
+//! queue; a dedicated thread writes and rotates the file. When the queue is
+//! full the entry is dropped and counted in `petracache_audit_dropped_total`,
+//! so a slow disk never holds up a request.
+
+use crate::config::AuditConfig;
+use crate::health::json_escape;
+use crate::metrics::Metrics;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// Records destructive operations through the writer thread
+pub struct AuditLog {
+    sender: SyncSender<String>,
+    hash_keys: bool,
+    metrics: Arc<Metrics>,
+}
+
+/// One audited operation
+pub struct AuditEntry<'a> {
+    /// The client; `None` on the Unix socket
+    pub peer: Option<SocketAddr>,
+    /// Command name, or the admin request (`POST /admin/compact`)
+    pub command: &'a str,
+    pub key: Option<&'a [u8]>,
+    /// Reply line or status, e.g. `DELETED` or `200`
+    pub outcome: &'a str,
+}
+
+impl AuditLog {
+    /// Open `config.path` and start the writer thread
+    pub fn open(config: &AuditConfig, metrics: Arc<Metrics>) -> std::io::Result<Option<Self>> {
+        let Some(path) = &config.path else {
+            return Ok(None);
+        };
+        let file = RotatingFile::open(path.clone(), config.max_file_bytes, config.max_files)?;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(config.queue_size.max(1));
+        let writer_metrics = Arc::clone(&metrics);
+        std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || write_entries(&receiver, file, &writer_metrics))?;
+        Ok(Some(Self {
+            sender,
+            hash_keys: config.hash_keys,
+            metrics,
+        }))
+    }
+
+    /// Queue `entry`, or drop it if the writer is behind
+    pub fn record(&self, entry: &AuditEntry<'_>) {
+        let line = self.format(entry, SystemTime::now());
+        match self.sender.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.metrics.audit_dropped.inc();
+            }
+        }
+    }
+
+    fn format(&self, entry: &AuditEntry<'_>, now: SystemTime) -> String {
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let peer = entry
+            .peer
+            .map_or_else(|| "unix".to_string(), |peer| peer.to_string());
+        let key = match entry.key {
+            None => "null".to_string(),
+            Some(key) if self.hash_keys => format!(r#""crc32c:{:08x}""#, crc32c::crc32c(key)),
+            Some(key) => format!(r#""{}""#, json_escape(&key.escape_ascii().to_string())),
+        };
+        format!(
+            r#"{{"ts":{}.{:03},"peer":"{}","command":"{}","key":{},"outcome":"{}"}}"#,
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis(),
+            peer,
+            json_escape(entry.command),
+            key,
+            json_escape(entry.outcome)
+        ) + "\n"
+    }
+}
+
+/// Write queued lines until every `AuditLog` is dropped, flushing whenever
+/// the queue runs dry
+fn write_entries(receiver: &Receiver<String>, mut file: RotatingFile, metrics: &Metrics) {
+    let mut failing = false;
+    while let Ok(line) = receiver.recv() {
+        let mut result = file.write_line(&line);
+        let mut written = 1;
+        while result.is_ok()
+            && let Ok(line) = receiver.try_recv()
+        {
+            result = file.write_line(&line);
+            written += 1;
+        }
+        match result.and_then(|()| file.flush()) {
+            Ok(()) => {
+                metrics.audit_entries.inc_by(written);
+                failing = false;
+            }
+            Err(e) => {
+                // Reported once per run of failures, not per entry
+                if !failing {
+                    error!("Failed to write audit log {:?}: {}", file.path, e);
+                }
+                failing = true;
+                metrics.audit_dropped.inc_by(written);
+            }
+        }
+    }
+}
+
+/// The audit file, rotated to `path.1`, `path.2`, ... by size
+struct RotatingFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.writer.write_all(line.as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Shift `path.N` to `path.N+1`, dropping the oldest, and start a new
+    /// file at `path`
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        if self.max_files > 0 {
+            info!("Rotated audit log {:?}", self.path);
+        }
+        Ok(())
+    }
+}
+
+/// `path` with `.n` appended
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn config(dir: &TempDir) -> AuditConfig {
+        AuditConfig {
+            path: Some(dir.path().join("audit.jsonl")),
+            ..AuditConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let dir = TempDir::new().unwrap();
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let log = AuditLog::open(&config(&dir), Arc::clone(&metrics))
+            .unwrap()
+            .unwrap();
+        let at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        let entry = AuditEntry {
+            peer: Some("10.0.0.1:5000".parse().unwrap()),
+            command: "delete",
+            key: Some(b"user:\"1\"\x01"),
+            outcome: "DELETED",
+        };
+        assert_eq!(
+            log.format(&entry, at),
+            "{\"ts\":1700000000.250,\"peer\":\"10.0.0.1:5000\",\"command\":\"delete\",\
+             \"key\":\"user:\\\\\\\"1\\\\\\\"\\\\x01\",\"outcome\":\"DELETED\"}\n"
+        );
+
+        let log = AuditLog::open(
+            &AuditConfig {
+                hash_keys: true,
+                ..config(&dir)
+            },
+            metrics,
+        )
+        .unwrap()
+        .unwrap();
+        let entry = AuditEntry {
+            peer: None,
+            key: Some(b"secret"),
+            ..entry
+        };
+        let line = log.format(&entry, at);
+        assert!(line.contains(r#""peer":"unix""#), "{line}");
+        let hash = format!(r#""key":"crc32c:{:08x}""#, crc32c::crc32c(b"secret"));
+        assert!(line.contains(&hash), "{line}");
+    }
+
+    #[test]
+    fn test_writes_and_rotates() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let log = AuditLog::open(
+            &AuditConfig {
+                max_file_bytes: 200,
+                max_files: 2,
+                ..config(&dir)
+            },
+            Arc::clone(&metrics),
+        )
+        .unwrap()
+        .unwrap();
+        for _ in 0..10 {
+            log.record(&AuditEntry {
+                peer: None,
+                command: "flush_all",
+                key: None,
+                outcome: "OK",
+            });
+        }
+        // The writer exits once the log is dropped and the queue drained
+        drop(log);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while metrics.audit_entries.get() + metrics.audit_dropped.get() < 10 {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(metrics.audit_entries.get(), 10);
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        let current = lines(&path);
+        let rotated = lines(&rotated_path(&path, 1)) + lines(&rotated_path(&path, 2));
+        assert!(current > 0 && rotated > 0);
+        // Older entries than the two rotated files hold were dropped
+        assert!(current + rotated < 10);
+        assert!(!rotated_path(&path, 3).exists());
+        assert!(fs::metadata(&path).unwrap().len() <= 200);
+    }
+}
@@ -0,0 +1,396 @@
+//! Incremental RocksDB backups, on a schedule and on demand
+//!
+//! Losing a node means a cold start; a recent backup avoids the miss storm.
+//! `BackupRunner` creates one backup at a time in `backup.dir` (unchanged SST
+//! files are shared with earlier backups) and then deletes all but the
+//! `backup.keep` newest. `run` triggers it every `backup.interval_secs`, and
+//! the health server's `POST /admin/backup` triggers it on demand.
+//! `restore_at_startup` brings a node back from such a backup.
+//!
+//! Checkpoints (`POST /admin/checkpoint?path=...`) are the other way to seed
+//! a node: a hard-linked snapshot under `backup.checkpoint_dir` that can be
+//! copied elsewhere and opened as is.
+
+use crate::StorageError;
+use crate::config::{BackupConfig, StorageConfig};
+use crate::metrics::Metrics;
+use crate::storage::{BackupInfo, RocksStorage, current_timestamp};
+use parking_lot::Mutex;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Creates and prunes backups, never two at once
+pub struct BackupRunner {
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    config: BackupConfig,
+    /// Held while a backup runs
+    running: Mutex<()>,
+    /// Held while a checkpoint is created; later requests wait for it
+    checkpointing: Mutex<()>,
+}
+
+/// Outcome of one backup
+#[derive(Debug, Clone, Copy)]
+pub struct BackupResult {
+    pub info: BackupInfo,
+    pub elapsed: Duration,
+}
+
+impl BackupRunner {
+    pub fn new(storage: Arc<RocksStorage>, metrics: Arc<Metrics>, config: BackupConfig) -> Self {
+        Self {
+            storage,
+            metrics,
+            config,
+            running: Mutex::new(()),
+            checkpointing: Mutex::new(()),
+        }
+    }
+
+    /// Create a backup and prune old ones, blocking until done
+    ///
+    /// Returns `None` without doing anything if a backup is already running.
+    /// Call from a blocking thread, never from an async worker.
+    pub fn backup(&self) -> Option<Result<BackupResult, StorageError>> {
+        let _running = self.running.try_lock()?;
+        let started = Instant::now();
+        let result = self
+            .storage
+            .create_backup(&self.config.dir)
+            .and_then(|info| {
+                if self.config.keep > 0 {
+                    self.storage
+                        .purge_old_backups(&self.config.dir, self.config.keep)?;
+                }
+                Ok(BackupResult {
+                    info,
+                    elapsed: started.elapsed(),
+                })
+            });
+
+        match &result {
+            Ok(backup) => {
+                self.metrics
+                    .backup_last_success_timestamp
+                    .set(i64::try_from(current_timestamp()).unwrap_or(i64::MAX));
+                self.metrics
+                    .backup_last_duration_seconds
+                    .set(backup.elapsed.as_secs_f64());
+                self.metrics
+                    .backup_last_size_bytes
+                    .set(i64::try_from(backup.info.size).unwrap_or(i64::MAX));
+            }
+            Err(e) => {
+                self.metrics.backup_failures.inc();
+                error!("Backup to {:?} failed: {}", self.config.dir, e);
+            }
+        }
+        Some(result)
+    }
+
+    /// Whether `checkpoint` is allowed at all (`backup.checkpoint_dir` set)
+    pub fn checkpoints_enabled(&self) -> bool {
+        self.config.checkpoint_dir.is_some()
+    }
+
+    /// Create a checkpoint at `requested`, relative to
+    /// `backup.checkpoint_dir` or absolute inside it, returning where it was
+    /// written and its size in bytes
+    ///
+    /// Concurrent calls run one after the other. Blocks on I/O; call it from
+    /// a blocking thread.
+    pub fn checkpoint(&self, requested: &Path) -> Result<(PathBuf, u64), CheckpointError> {
+        let base = self
+            .config
+            .checkpoint_dir
+            .as_deref()
+            .ok_or(CheckpointError::Disabled)?;
+        let _checkpointing = self.checkpointing.lock();
+        let target = resolve_checkpoint_path(base, requested)?;
+        let size = self.storage.create_checkpoint(&target)?;
+        Ok((target, size))
+    }
+}
+
+/// Why `BackupRunner::checkpoint` failed
+#[derive(Debug, thiserror::Error)]
+pub enum CheckpointError {
+    #[error("checkpoints are disabled (backup.checkpoint_dir is not set)")]
+    Disabled,
+
+    #[error("invalid checkpoint path: {0}")]
+    InvalidPath(String),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Resolve `requested` to a directory that does not exist yet, directly in
+/// an existing directory under `base`
+///
+/// Parent directories are canonicalized, so neither `..` nor a symlink can
+/// lead outside `base`.
+fn resolve_checkpoint_path(base: &Path, requested: &Path) -> Result<PathBuf, CheckpointError> {
+    let invalid =
+        |reason: &str| CheckpointError::InvalidPath(format!("{}: {reason}", requested.display()));
+    if requested.components().any(|c| c == Component::ParentDir) {
+        return Err(invalid("\"..\" is not allowed"));
+    }
+    let name = requested
+        .file_name()
+        .ok_or_else(|| invalid("no directory name"))?;
+
+    std::fs::create_dir_all(base)
+        .and_then(|()| base.canonicalize())
+        .map_err(|e| invalid(&format!("checkpoint_dir unusable: {e}")))
+        .and_then(|base| {
+            let parent = base.join(requested);
+            let parent = parent.parent().unwrap_or(&base);
+            let parent = parent
+                .canonicalize()
+                .map_err(|e| invalid(&format!("parent directory: {e}")))?;
+            if !parent.starts_with(&base) {
+                return Err(invalid("outside checkpoint_dir"));
+            }
+            let target = parent.join(name);
+            if target.exists() {
+                return Err(invalid("already exists"));
+            }
+            Ok(target)
+        })
+}
+
+/// Back up every `backup.interval_secs` until `cancel` fires; returns
+/// immediately if periodic backups are disabled
+pub async fn run(runner: Arc<BackupRunner>, cancel: CancellationToken) {
+    if runner.config.interval_secs == 0 {
+        return;
+    }
+    info!(
+        interval_secs = runner.config.interval_secs,
+        dir = ?runner.config.dir,
+        keep = runner.config.keep,
+        "Periodic backups enabled"
+    );
+    let interval = Duration::from_secs(runner.config.interval_secs);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let task_runner = Arc::clone(&runner);
+        match tokio::task::spawn_blocking(move || task_runner.backup()).await {
+            Ok(Some(Ok(backup))) => debug!(
+                backup_id = backup.info.backup_id,
+                elapsed_ms = backup.elapsed.as_millis(),
+                "Periodic backup completed"
+            ),
+            // Failures were logged and counted by `backup`
+            Ok(Some(Err(_))) => {}
+            Ok(None) => debug!("Periodic backup skipped, one is already running"),
+            Err(e) => error!("Backup task failed: {}", e),
+        }
+    }
+    debug!("Backup scheduler stopped");
+}
+
+/// Restore `db_path` from the newest backup in `restore_from`, if set
+///
+/// A `db_path` that already holds data is left alone unless
+/// `restore_overwrite` is set. Blocks on I/O; run it before opening the
+/// database, so nothing is served from a half-restored store.
+pub fn restore_at_startup(config: &StorageConfig) -> Result<Option<BackupInfo>, StorageError> {
+    let Some(backup_dir) = &config.restore_from else {
+        return Ok(None);
+    };
+    if !is_empty_dir(&config.db_path)? {
+        if !config.restore_overwrite {
+            warn!(
+                db_path = ?config.db_path,
+                "Not restoring from backup: database directory is not empty (set restore_overwrite to replace it)"
+            );
+            return Ok(None);
+        }
+        warn!(db_path = ?config.db_path, "Replacing existing database with backup");
+    }
+    RocksStorage::restore_latest_backup(backup_dir, &config.db_path, config.shards).map(Some)
+}
+
+/// Whether `path` is missing or an empty directory
+fn is_empty_dir(path: &Path) -> Result<bool, StorageError> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(StorageError::Internal(format!(
+            "cannot read {}: {e}",
+            path.display()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::StoredValue;
+    use tempfile::TempDir;
+
+    fn runner(tmp_dir: &TempDir, keep: usize) -> BackupRunner {
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"value".to_vec()))
+            .unwrap();
+        let config = BackupConfig {
+            dir: tmp_dir.path().join("backups"),
+            interval_secs: 0,
+            keep,
+            checkpoint_dir: None,
+        };
+        BackupRunner::new(Arc::new(storage), Arc::new(Metrics::new().unwrap()), config)
+    }
+
+    #[test]
+    fn test_backup_ids_increase_across_purges() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner(&tmp_dir, 2);
+
+        let ids: Vec<u32> = (0..3)
+            .map(|_| runner.backup().unwrap().unwrap().info.backup_id)
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Purging the oldest never makes an id reused
+        let next = runner.backup().unwrap().unwrap();
+        assert!(next.info.backup_id > ids[2]);
+        assert!(runner.metrics.backup_last_success_timestamp.get() > 0);
+        assert_eq!(runner.metrics.backup_failures.get(), 0);
+    }
+
+    #[test]
+    fn test_restore_at_startup() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner(&tmp_dir, 0);
+        for i in 0..100 {
+            runner
+                .storage
+                .set(
+                    format!("key{i}").as_bytes(),
+                    StoredValue::new(i, 0, format!("value{i}").into_bytes()),
+                )
+                .unwrap();
+        }
+        runner.backup().unwrap().unwrap();
+
+        let restored_path = tmp_dir.path().join("restored");
+        let config = StorageConfig {
+            db_path: restored_path.clone(),
+            restore_from: Some(tmp_dir.path().join("backups")),
+            ..StorageConfig::default()
+        };
+        assert!(restore_at_startup(&config).unwrap().is_some());
+
+        let restored = RocksStorage::open(&config).unwrap();
+        for i in (0..100).step_by(7) {
+            let value = restored.get(format!("key{i}").as_bytes()).unwrap().unwrap();
+            assert_eq!(value.data, format!("value{i}").into_bytes());
+            assert_eq!(value.flags, i);
+        }
+        assert_eq!(restored.get(b"key").unwrap().unwrap().data, b"value");
+
+        // The store now has data of its own: left alone unless overwriting
+        restored
+            .set(b"new", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        drop(restored);
+        assert!(restore_at_startup(&config).unwrap().is_none());
+        let config = StorageConfig {
+            restore_overwrite: true,
+            ..config
+        };
+        assert!(restore_at_startup(&config).unwrap().is_some());
+        let restored = RocksStorage::open(&config).unwrap();
+        assert!(restored.get(b"new").unwrap().is_none());
+        assert!(restored.get(b"key7").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_checkpoint_opens_as_database() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut runner = runner(&tmp_dir, 0);
+        assert!(matches!(
+            runner.checkpoint(Path::new("snap")),
+            Err(CheckpointError::Disabled)
+        ));
+
+        runner.config.checkpoint_dir = Some(tmp_dir.path().join("checkpoints"));
+        let (path, size) = runner.checkpoint(Path::new("snap")).unwrap();
+        assert!(path.ends_with("checkpoints/snap"));
+        assert!(size > 0);
+
+        let db =
+            rust_rocksdb::DB::open_for_read_only(&rust_rocksdb::Options::default(), &path, false)
+                .unwrap();
+        let raw = db.get(b"key").unwrap().unwrap();
+        assert_eq!(StoredValue::decode(&raw).unwrap().data, b"value");
+
+        // Checkpoints never replace an existing directory
+        assert!(matches!(
+            runner.checkpoint(Path::new("snap")),
+            Err(CheckpointError::InvalidPath(_))
+        ));
+    }
+
+    #[test]
+    fn test_checkpoint_path_stays_in_base() {
+        let tmp_dir = TempDir::new().unwrap();
+        let base = tmp_dir.path().join("checkpoints");
+        std::fs::create_dir_all(base.join("nested")).unwrap();
+
+        let resolved = resolve_checkpoint_path(&base, Path::new("nested/snap")).unwrap();
+        assert!(resolved.ends_with("checkpoints/nested/snap"));
+        let absolute = base.join("snap");
+        assert!(resolve_checkpoint_path(&base, &absolute).is_ok());
+
+        for escape in [
+            "../snap",
+            "nested/../../snap",
+            "/tmp/snap",
+            "missing/snap",
+            "",
+        ] {
+            assert!(
+                resolve_checkpoint_path(&base, Path::new(escape)).is_err(),
+                "{escape:?}"
+            );
+        }
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(tmp_dir.path(), base.join("link")).unwrap();
+            assert!(resolve_checkpoint_path(&base, Path::new("link/snap")).is_err());
+        }
+    }
+
+    #[test]
+    fn test_overlapping_backup_is_noop() {
+        let tmp_dir = TempDir::new().unwrap();
+        let runner = runner(&tmp_dir, 0);
+        let running = runner.running.lock();
+        assert!(runner.backup().is_none());
+
+        drop(running);
+        assert!(runner.backup().is_some());
+    }
+}
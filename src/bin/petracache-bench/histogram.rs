@@ -0,0 +1,165 @@
+//! Log-linear latency histogram, in the style of HdrHistogram
+//!
+//! Values below `2 * SUB_BUCKETS` get a bucket each; above that every
+//! power of two is split into `SUB_BUCKETS` equal buckets, so a recorded
+//! value is off by less than 1/64 (1.6%) whatever its magnitude. A
+//! histogram is a fixed ~30 KB array, cheap to record into and to merge.
+
+/// Linear buckets per power of two
+const SUB_BUCKETS: u64 = 64;
+/// Values below this are recorded exactly
+const LINEAR: u64 = 2 * SUB_BUCKETS;
+/// Enough buckets for any `u64`
+const BUCKETS: usize = (LINEAR + (64 - 7) * SUB_BUCKETS) as usize;
+
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Box<[u64]>,
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; BUCKETS].into_boxed_slice(),
+            count: 0,
+            sum: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: u64) {
+        self.counts[index(value)] += 1;
+        self.count += 1;
+        self.sum += u128::from(value);
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Smallest value recorded, 0 when empty
+    pub fn min(&self) -> u64 {
+        if self.count == 0 { 0 } else { self.min }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum as f64 / self.count as f64
+        }
+    }
+
+    /// The value `quantile` (0.0 to 1.0) of the recorded values are at or
+    /// below: the top of its bucket, capped at `max`. 0 when empty.
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn value_at_quantile(&self, quantile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return highest_equivalent(index).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Bucket of `value`
+fn index(value: u64) -> usize {
+    if value < LINEAR {
+        return value as usize;
+    }
+    // value >> shift is in SUB_BUCKETS..LINEAR
+    let shift = u64::from(value.ilog2()) - 6;
+    let top = value >> shift;
+    (LINEAR + (shift - 1) * SUB_BUCKETS + (top - SUB_BUCKETS)) as usize
+}
+
+/// Largest value that lands in bucket `index`
+fn highest_equivalent(index: usize) -> u64 {
+    let index = index as u64;
+    if index < LINEAR {
+        return index;
+    }
+    let shift = (index - LINEAR) / SUB_BUCKETS + 1;
+    let top = (index - LINEAR) % SUB_BUCKETS + SUB_BUCKETS;
+    ((top + 1) << shift).wrapping_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        for value in [0, 1, 127, 128, 129, 255, 256, 1000, 123_456_789, u64::MAX] {
+            let index = index(value);
+            assert!(index < BUCKETS, "{value}");
+            assert!(highest_equivalent(index) >= value, "{value}");
+            // Within 1/64 of the value
+            assert!(
+                highest_equivalent(index) - value <= value / SUB_BUCKETS,
+                "{value}"
+            );
+            if index > 0 {
+                assert!(highest_equivalent(index - 1) < value, "{value}");
+            }
+        }
+        assert_eq!(index(u64::MAX), BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!((histogram.value_at_quantile(0.5), histogram.min()), (0, 0));
+        for value in 1..=100 {
+            histogram.record(value);
+        }
+        assert_eq!(histogram.value_at_quantile(0.5), 50);
+        assert_eq!(histogram.value_at_quantile(0.99), 99);
+        assert_eq!(histogram.value_at_quantile(1.0), 100);
+        assert!((histogram.mean() - 50.5).abs() < f64::EPSILON);
+
+        let mut slow = Histogram::default();
+        slow.record(1_000_000);
+        histogram.merge(&slow);
+        assert_eq!(histogram.count(), 101);
+        assert_eq!((histogram.min(), histogram.max()), (1, 1_000_000));
+        // Capped at the largest value recorded, not the bucket's top
+        assert_eq!(histogram.value_at_quantile(1.0), 1_000_000);
+        assert_eq!(histogram.value_at_quantile(0.99), 100);
+    }
+}
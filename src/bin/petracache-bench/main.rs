@@ -0,0 +1,633 @@
+//! petracache-bench - load generator for PetraCache (or any memcached)
+//!
+//! Each connection keeps `--pipeline` gets and sets in flight through
+//! `petracache::client`, drawing keys, value sizes and TTLs from the
+//! configured distributions. At the end it prints throughput and latency
+//! percentiles; `--csv` adds one row per second. `--verify` checks that
+//! every get returns the connection's own last set of the key.
+//!
+//! Exit status: 0 on success, 1 if `--verify` found a wrong value or a
+//! failed request, 2 for errors.
+
+mod histogram;
+mod verify;
+mod workload;
+
+use anyhow::{Context, bail};
+use clap::{Parser, ValueEnum};
+use histogram::Histogram;
+use parking_lot::Mutex;
+use petracache::client::{Client, Reply};
+use petracache::config::units::{parse_duration, parse_size};
+use petracache::protocol::Command;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::task::JoinSet;
+use verify::Tracker;
+use workload::{Dist, Ratio, Rng};
+
+/// Longest TTL sent as relative seconds; memcached reads larger exptimes
+/// as Unix timestamps
+const MAX_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+
+/// How long after `--duration` to wait for replies still in flight
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load generator for PetraCache
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Memcached address
+    #[arg(
+        short,
+        long,
+        value_name = "HOST:PORT",
+        default_value = "127.0.0.1:11211"
+    )]
+    server: String,
+
+    /// Connect to this Unix socket instead of --server
+    #[arg(short, long, value_name = "PATH")]
+    unix: Option<PathBuf>,
+
+    /// Concurrent connections
+    #[arg(short, long, default_value_t = 50, value_parser = clap::value_parser!(u32).range(1..))]
+    connections: u32,
+
+    /// Requests in flight per connection
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pipeline: u32,
+
+    /// Sets to gets, e.g. 1:10 or 1:0 to only set
+    #[arg(long, value_name = "SETS:GETS", default_value = "1:10", value_parser = Ratio::parse)]
+    ratio: Ratio,
+
+    /// Number of distinct keys
+    #[arg(short, long, default_value_t = 100_000, value_parser = clap::value_parser!(u64).range(1..))]
+    keys: u64,
+
+    /// How keys are picked
+    #[arg(long, value_enum, default_value_t = KeyDist::Uniform)]
+    key_dist: KeyDist,
+
+    /// Keys are this prefix followed by their index
+    #[arg(long, default_value = "bench:")]
+    key_prefix: String,
+
+    /// Value sizes: 100, uniform:10-1KiB or zipfian:10-1MiB (favoring
+    /// small values)
+    #[arg(long, value_name = "DIST", default_value = "100", value_parser = parse_value_size)]
+    value_size: Dist,
+
+    /// TTLs of sets: 0 (none), 10m, uniform:1m-1h or zipfian:1s-1d
+    #[arg(long, value_name = "DIST", default_value = "0", value_parser = parse_ttl)]
+    ttl: Dist,
+
+    /// How long to run (e.g. 30s, 5m)
+    #[arg(short, long, value_name = "DURATION", default_value = "30s", value_parser = parse_run_time)]
+    duration: Duration,
+
+    /// Write per-second throughput and latency to this CSV file
+    #[arg(long, value_name = "PATH")]
+    csv: Option<PathBuf>,
+
+    /// Check that every get returns the connection's own last set of the
+    /// key; each connection then sets only its share of the keys
+    #[arg(long)]
+    verify: bool,
+
+    /// Seed for the random workload, to repeat a run [default: random]
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum KeyDist {
+    Uniform,
+    /// A few hot keys (YCSB's zipfian, lowest indexes hottest)
+    Zipfian,
+}
+
+fn parse_value_size(spec: &str) -> Result<Dist, String> {
+    Dist::parse(spec, parse_size)
+}
+
+fn parse_ttl(spec: &str) -> Result<Dist, String> {
+    Dist::parse(spec, |value| parse_duration(value, 1000))
+}
+
+fn parse_run_time(value: &str) -> Result<Duration, String> {
+    parse_duration(value, 1000).map(Duration::from_secs)
+}
+
+impl Args {
+    /// Checks clap can't express
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.ttl.max() > MAX_TTL_SECS {
+            bail!("--ttl can't exceed 30d");
+        }
+        if self.duration.is_zero() {
+            bail!("--duration must be positive");
+        }
+        if self.verify {
+            if self.keys < u64::from(self.connections) {
+                bail!("--verify needs at least as many --keys as --connections");
+            }
+            if self.value_size.min() < verify::HEADER_LEN as u64 {
+                bail!(
+                    "--verify needs values of at least {} bytes",
+                    verify::HEADER_LEN
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts and latencies (in nanoseconds) of one connection or interval
+#[derive(Default)]
+struct Stats {
+    gets: u64,
+    hits: u64,
+    misses: u64,
+    sets: u64,
+    /// Error replies, and set replies other than `STORED`
+    errors: u64,
+    latency: Histogram,
+}
+
+impl Stats {
+    fn merge(&mut self, other: &Stats) {
+        self.gets += other.gets;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.sets += other.sets;
+        self.errors += other.errors;
+        self.latency.merge(&other.latency);
+    }
+
+    fn ops(&self) -> u64 {
+        self.latency.count()
+    }
+}
+
+/// A request in flight
+enum Op {
+    Get {
+        key: u64,
+        expect: Option<verify::Expect>,
+    },
+    Set,
+}
+
+/// One connection's workload
+struct Worker {
+    conn: u64,
+    args: Arc<Args>,
+    key_dist: Arc<Dist>,
+    rng: Rng,
+    tracker: Option<Tracker>,
+    key: Vec<u8>,
+    /// Value bytes: `max value size` x's, or the value `tracker` filled in
+    value: Vec<u8>,
+    /// This interval's numbers, taken by the reporter every second
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl Worker {
+    async fn run<S: AsyncRead + AsyncWrite + Unpin>(
+        mut self,
+        mut client: Client<S>,
+        deadline: Instant,
+    ) -> anyhow::Result<Option<Tracker>> {
+        let mut in_flight = VecDeque::new();
+        while Instant::now() < deadline {
+            while in_flight.len() < self.args.pipeline as usize {
+                let op = self.send(&mut client)?;
+                in_flight.push_back((Instant::now(), op));
+            }
+            self.receive(&mut client, &mut in_flight, deadline).await?;
+        }
+        while !in_flight.is_empty() {
+            self.receive(&mut client, &mut in_flight, deadline).await?;
+        }
+        Ok(self.tracker)
+    }
+
+    /// Queue the next request
+    fn send<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        client: &mut Client<S>,
+    ) -> anyhow::Result<Op> {
+        let args = &self.args;
+        let mut key = self.key_dist.sample(&mut self.rng);
+        if self.tracker.is_some() {
+            key = workload::owned_key(key, self.conn, u64::from(args.connections), args.keys);
+        }
+        self.key.clear();
+        self.key.extend_from_slice(args.key_prefix.as_bytes());
+        self.key
+            .extend_from_slice(itoa::Buffer::new().format(key).as_bytes());
+
+        if !args.ratio.next_is_set(&mut self.rng) {
+            let expect = self
+                .tracker
+                .as_ref()
+                .and_then(|tracker| tracker.expect(key));
+            client.send(&Command::Get {
+                keys: vec![Cow::Borrowed(&self.key)],
+            })?;
+            return Ok(Op::Get { key, expect });
+        }
+
+        let len = self.args.value_size.sample(&mut self.rng) as usize;
+        let ttl = args.ttl.sample(&mut self.rng);
+        let (flags, data) = match &mut self.tracker {
+            Some(tracker) => (tracker.set(key, len, ttl, &mut self.value), &self.value[..]),
+            None => (0, &self.value[..len]),
+        };
+        client.send(&Command::Set {
+            key: Cow::Borrowed(&self.key),
+            flags,
+            exptime: i64::try_from(ttl)?,
+            data: Cow::Borrowed(data),
+            noreply: false,
+        })?;
+        Ok(Op::Set)
+    }
+
+    /// Read the reply to the oldest request in flight
+    async fn receive<S: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        client: &mut Client<S>,
+        in_flight: &mut VecDeque<(Instant, Op)>,
+        deadline: Instant,
+    ) -> anyhow::Result<()> {
+        let reply = tokio::time::timeout_at((deadline + DRAIN_TIMEOUT).into(), client.recv())
+            .await
+            .context("timed out waiting for a reply")??;
+        let (sent, op) = in_flight.pop_front().expect("a request in flight");
+        let latency = u64::try_from(sent.elapsed().as_nanos()).unwrap_or(u64::MAX);
+
+        let mut stats = self.stats.lock();
+        stats.latency.record(latency);
+        match (op, reply) {
+            (Op::Get { key, expect }, Reply::Values(values)) => {
+                stats.gets += 1;
+                if values.is_empty() {
+                    stats.misses += 1;
+                } else {
+                    stats.hits += 1;
+                }
+                if let Some(tracker) = &mut self.tracker {
+                    let name = format!("{}{key}", self.args.key_prefix);
+                    tracker.check(name.as_bytes(), expect, values.first());
+                }
+            }
+            (Op::Get { .. }, _) => {
+                stats.gets += 1;
+                stats.errors += 1;
+            }
+            (Op::Set, reply) => {
+                stats.sets += 1;
+                if reply != Reply::Stored {
+                    stats.errors += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    if let Err(e) = args.validate() {
+        eprintln!("petracache-bench: {e:#}");
+        return ExitCode::from(2);
+    }
+    let result = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| runtime.block_on(run(Arc::new(args))));
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("petracache-bench: {e:#}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Run the benchmark and print the report; whether it passed
+async fn run(args: Arc<Args>) -> anyhow::Result<bool> {
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64)
+    });
+    let key_dist = Arc::new(match args.key_dist {
+        KeyDist::Uniform => Dist::uniform(0, args.keys - 1),
+        KeyDist::Zipfian => Dist::zipfian(0, args.keys - 1),
+    });
+    let mut csv = args.csv.as_deref().map(Csv::create).transpose()?;
+
+    // Every connection is open before the clock starts
+    let mut stats = Vec::new();
+    let mut connections = Vec::new();
+    for conn in 0..u64::from(args.connections) {
+        let worker = Worker {
+            conn,
+            args: Arc::clone(&args),
+            key_dist: Arc::clone(&key_dist),
+            rng: Rng::new(seed.wrapping_add(conn)),
+            tracker: args.verify.then(Tracker::default),
+            key: Vec::new(),
+            value: vec![
+                b'x';
+                if args.verify {
+                    0
+                } else {
+                    args.value_size.max() as usize
+                }
+            ],
+            stats: Arc::default(),
+        };
+        stats.push(Arc::clone(&worker.stats));
+        connections.push((connect(&args).await?, worker));
+    }
+    let start = Instant::now();
+    let deadline = start + args.duration;
+    let mut workers = JoinSet::new();
+    for (connection, worker) in connections {
+        match connection {
+            Connection::Tcp(client) => workers.spawn(worker.run(client, deadline)),
+            #[cfg(unix)]
+            Connection::Unix(client) => workers.spawn(worker.run(client, deadline)),
+        };
+    }
+
+    let mut total = Stats::default();
+    let mut verified = verify::Counts::default();
+    let mut ticker = tokio::time::interval_at(
+        (start + Duration::from_secs(1)).into(),
+        Duration::from_secs(1),
+    );
+    let mut second = 0;
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                second += 1;
+                let interval = collect(&stats, &mut total);
+                progress(second, &interval);
+                if let Some(csv) = &mut csv {
+                    csv.row(second, &interval)?;
+                }
+            }
+            joined = workers.join_next() => match joined {
+                None => break,
+                Some(Ok(Ok(tracker))) => {
+                    if let Some(tracker) = tracker {
+                        verified.merge(tracker.counts);
+                    }
+                }
+                Some(Ok(Err(e))) => {
+                    workers.abort_all();
+                    return Err(e);
+                }
+                Some(Err(e)) => {
+                    workers.abort_all();
+                    return Err(e.into());
+                }
+            },
+        }
+    }
+    let elapsed = start.elapsed();
+    // What's left since the last tick
+    let interval = collect(&stats, &mut total);
+    if let Some(csv) = &mut csv {
+        if interval.ops() > 0 {
+            csv.row(second + 1, &interval)?;
+        }
+    }
+
+    report(&args, &total, elapsed);
+    if args.verify {
+        println!(
+            "verify       {} hits checked: {} stale, {} corrupt, {} missing",
+            verified.checked, verified.stale, verified.corrupt, verified.missing
+        );
+    }
+    Ok(verified.failures() == 0 && !(args.verify && total.errors > 0))
+}
+
+enum Connection {
+    Tcp(Client<tokio::net::TcpStream>),
+    #[cfg(unix)]
+    Unix(Client<tokio::net::UnixStream>),
+}
+
+async fn connect(args: &Args) -> anyhow::Result<Connection> {
+    match &args.unix {
+        #[cfg(unix)]
+        Some(path) => Ok(Connection::Unix(
+            Client::connect_unix(path)
+                .await
+                .with_context(|| format!("connecting to {}", path.display()))?,
+        )),
+        #[cfg(not(unix))]
+        Some(_) => bail!("--unix is only supported on Unix platforms"),
+        None => Ok(Connection::Tcp(
+            Client::connect(args.server.as_str())
+                .await
+                .with_context(|| format!("connecting to {}", args.server))?,
+        )),
+    }
+}
+
+/// Take every connection's numbers since the last call, adding them to
+/// `total`
+fn collect(stats: &[Arc<Mutex<Stats>>], total: &mut Stats) -> Stats {
+    let mut interval = Stats::default();
+    for stats in stats {
+        let taken = std::mem::take(&mut *stats.lock());
+        interval.merge(&taken);
+    }
+    total.merge(&interval);
+    interval
+}
+
+/// Nanoseconds as milliseconds
+#[allow(clippy::cast_precision_loss)]
+fn ms(nanos: u64) -> f64 {
+    nanos as f64 / 1e6
+}
+
+/// One line per second on stderr
+fn progress(second: u64, interval: &Stats) {
+    eprintln!(
+        "[{second:>4}s] {:>9} ops/s  p50 {:.3} ms  p99 {:.3} ms  errors {}",
+        interval.ops(),
+        ms(interval.latency.value_at_quantile(0.5)),
+        ms(interval.latency.value_at_quantile(0.99)),
+        interval.errors
+    );
+}
+
+/// Final throughput and latency summary on stdout
+#[allow(clippy::cast_precision_loss)]
+fn report(args: &Args, total: &Stats, elapsed: Duration) {
+    let target = match &args.unix {
+        Some(path) => path.display().to_string(),
+        None => args.server.clone(),
+    };
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "petracache-bench: {target}, {} connections, pipeline {}, ratio {}:{}, {} keys ({}), values {} B, ttl {} s, {secs:.1} s",
+        args.connections,
+        args.pipeline,
+        args.ratio.sets,
+        args.ratio.gets,
+        args.keys,
+        args.key_dist
+            .to_possible_value()
+            .map_or_else(String::new, |value| value.get_name().to_string()),
+        args.value_size,
+        args.ttl,
+    );
+    println!(
+        "ops          {:>12}  {:.0} ops/s",
+        total.ops(),
+        total.ops() as f64 / secs
+    );
+    let hit_ratio = if total.gets == 0 {
+        0.0
+    } else {
+        total.hits as f64 * 100.0 / total.gets as f64
+    };
+    println!(
+        "gets         {:>12}  {:.0} ops/s, {} hits ({hit_ratio:.1}%), {} misses",
+        total.gets,
+        total.gets as f64 / secs,
+        total.hits,
+        total.misses
+    );
+    println!(
+        "sets         {:>12}  {:.0} ops/s",
+        total.sets,
+        total.sets as f64 / secs
+    );
+    println!("errors       {:>12}", total.errors);
+    let latency = &total.latency;
+    print!(
+        "latency (ms) min {:.3}  mean {:.3}",
+        ms(latency.min()),
+        latency.mean() / 1e6
+    );
+    for (name, quantile) in QUANTILES {
+        print!("  {name} {:.3}", ms(latency.value_at_quantile(quantile)));
+    }
+    println!("  max {:.3}", ms(latency.max()));
+}
+
+/// Percentiles in the report
+const QUANTILES: [(&str, f64); 5] = [
+    ("p50", 0.5),
+    ("p90", 0.9),
+    ("p99", 0.99),
+    ("p99.9", 0.999),
+    ("p99.99", 0.9999),
+];
+
+/// `--csv` output, flushed every row so it can be followed during a run
+struct Csv(BufWriter<File>);
+
+impl Csv {
+    fn create(path: &std::path::Path) -> anyhow::Result<Self> {
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        let mut csv = Self(BufWriter::new(file));
+        writeln!(
+            csv.0,
+            "second,ops,gets,sets,hits,misses,errors,p50_us,p90_us,p99_us,p999_us,max_us"
+        )?;
+        Ok(csv)
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn row(&mut self, second: u64, interval: &Stats) -> anyhow::Result<()> {
+        let us = |nanos: u64| nanos as f64 / 1e3;
+        let latency = &interval.latency;
+        writeln!(
+            self.0,
+            "{second},{},{},{},{},{},{},{:.1},{:.1},{:.1},{:.1},{:.1}",
+            interval.ops(),
+            interval.gets,
+            interval.sets,
+            interval.hits,
+            interval.misses,
+            interval.errors,
+            us(latency.value_at_quantile(0.5)),
+            us(latency.value_at_quantile(0.9)),
+            us(latency.value_at_quantile(0.99)),
+            us(latency.value_at_quantile(0.999)),
+            us(latency.max()),
+        )?;
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let args = Args::try_parse_from([
+            "petracache-bench",
+            "-c",
+            "4",
+            "--ratio",
+            "1:3",
+            "--value-size",
+            "zipfian:16-1KiB",
+            "--ttl",
+            "uniform:1m-1h",
+            "--duration",
+            "10s",
+            "--verify",
+        ])
+        .unwrap();
+        assert_eq!((args.connections, args.pipeline), (4, 1));
+        assert_eq!(args.ratio, Ratio { sets: 1, gets: 3 });
+        assert_eq!((args.value_size.min(), args.value_size.max()), (16, 1024));
+        assert_eq!((args.ttl.min(), args.ttl.max()), (60, 3600));
+        assert_eq!(args.duration, Duration::from_secs(10));
+        args.validate().unwrap();
+
+        for flags in [
+            &["--connections", "0"][..],
+            &["--ttl", "uniform:1h-1m"],
+            &["--value-size", "lots"],
+        ] {
+            let parsed = Args::try_parse_from(["petracache-bench"].iter().chain(flags));
+            assert!(parsed.is_err(), "{flags:?}");
+        }
+        for flags in [
+            &["--ttl", "31d"][..],
+            &["--verify", "--value-size", "8"],
+            &["--verify", "--keys", "10"],
+        ] {
+            let args = Args::try_parse_from(["petracache-bench"].iter().chain(flags)).unwrap();
+            assert!(args.validate().is_err(), "{flags:?}");
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! `--verify`: read-your-writes checks under concurrency
+//!
+//! Each connection sets only the keys it owns (index % connections), so it
+//! knows what every get must return. A value starts with the set's
+//! sequence number in hex, which is also its flags, followed by filler
+//! derived from it. The expectation is taken when the get is sent, after
+//! every earlier set on the connection and before any later one.
+
+use petracache::client::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Bytes of the sequence number at the start of each value
+pub const HEADER_LEN: usize = 16;
+
+/// Failures printed on stderr per connection; the rest are only counted
+const MAX_REPORTED: u32 = 10;
+
+/// What the last set of a key stored
+#[derive(Debug, Clone, Copy)]
+pub struct Expect {
+    seq: u64,
+    len: usize,
+    /// When the key may have expired; `None` for no TTL
+    expires: Option<Instant>,
+}
+
+/// One connection's sets, and the gets checked against them
+#[derive(Debug, Default)]
+pub struct Tracker {
+    written: HashMap<u64, Expect>,
+    seq: u64,
+    reported: u32,
+    pub counts: Counts,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counts {
+    /// Hits compared with the value set
+    pub checked: u64,
+    /// An older value of ours
+    pub stale: u64,
+    /// Not a value set by this run
+    pub corrupt: u64,
+    /// Misses for keys set with no TTL, or before it was up
+    pub missing: u64,
+}
+
+impl Counts {
+    pub fn merge(&mut self, other: Counts) {
+        self.checked += other.checked;
+        self.stale += other.stale;
+        self.corrupt += other.corrupt;
+        self.missing += other.missing;
+    }
+
+    pub fn failures(self) -> u64 {
+        self.stale + self.corrupt + self.missing
+    }
+}
+
+impl Tracker {
+    /// Fill `data` with the next value for `key` and record it; returns
+    /// the flags to set it with
+    pub fn set(&mut self, key: u64, len: usize, ttl_secs: u64, data: &mut Vec<u8>) -> u32 {
+        self.seq += 1;
+        fill(self.seq, len, data);
+        // Expiry has one-second resolution and rounds down
+        let expires = (ttl_secs > 0).then(|| {
+            Instant::now() + Duration::from_secs(ttl_secs).saturating_sub(Duration::from_secs(1))
+        });
+        self.written.insert(
+            key,
+            Expect {
+                seq: self.seq,
+                len,
+                expires,
+            },
+        );
+        self.seq as u32
+    }
+
+    /// What a get of `key` sent now must return; `None` if this run
+    /// hasn't set it
+    pub fn expect(&self, key: u64) -> Option<Expect> {
+        self.written.get(&key).copied()
+    }
+
+    /// Check a get's reply against `expect`
+    pub fn check(&mut self, key: &[u8], expect: Option<Expect>, value: Option<&Value>) {
+        let Some(expect) = expect else {
+            return;
+        };
+        let Some(value) = value else {
+            if expect
+                .expires
+                .is_none_or(|expires| Instant::now() < expires)
+            {
+                self.counts.missing += 1;
+                self.report(key, &format!("missing, expected seq {}", expect.seq));
+            }
+            return;
+        };
+        self.counts.checked += 1;
+        match parse(value) {
+            Some(seq) if seq == expect.seq && value.data.len() == expect.len => {}
+            Some(seq) if seq < expect.seq => {
+                self.counts.stale += 1;
+                self.report(key, &format!("stale seq {seq}, expected {}", expect.seq));
+            }
+            _ => {
+                self.counts.corrupt += 1;
+                let head = String::from_utf8_lossy(&value.data[..value.data.len().min(32)]);
+                self.report(
+                    key,
+                    &format!(
+                        "expected seq {} ({} bytes), got {} bytes, flags {}: {head:?}",
+                        expect.seq,
+                        expect.len,
+                        value.data.len(),
+                        value.flags
+                    ),
+                );
+            }
+        }
+    }
+
+    fn report(&mut self, key: &[u8], message: &str) {
+        if self.reported < MAX_REPORTED {
+            self.reported += 1;
+            eprintln!("verify: {}: {message}", String::from_utf8_lossy(key));
+        }
+    }
+}
+
+/// Value number `seq`, `len` >= `HEADER_LEN` bytes
+pub fn fill(seq: u64, len: usize, data: &mut Vec<u8>) {
+    data.clear();
+    data.extend_from_slice(format!("{seq:016x}").as_bytes());
+    data.extend((HEADER_LEN..len).map(|i| filler(seq, i)));
+}
+
+fn filler(seq: u64, i: usize) -> u8 {
+    b'a' + ((seq + i as u64) % 26) as u8
+}
+
+/// Sequence number of a well-formed value
+fn parse(value: &Value) -> Option<u64> {
+    let header = std::str::from_utf8(value.data.get(..HEADER_LEN)?).ok()?;
+    let seq = u64::from_str_radix(header, 16).ok()?;
+    let intact = value.flags == seq as u32
+        && value.data[HEADER_LEN..]
+            .iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == filler(seq, HEADER_LEN + i));
+    intact.then_some(seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(data: &[u8], flags: u32) -> Value {
+        Value {
+            key: b"k".to_vec(),
+            flags,
+            data: data.to_vec(),
+            cas: None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let mut tracker = Tracker::default();
+        let mut first = Vec::new();
+        let flags = tracker.set(1, 20, 0, &mut first);
+        assert_eq!((flags, &first[..]), (1, &b"0000000000000001rstu"[..]));
+        let expect_first = tracker.expect(1);
+        let mut second = Vec::new();
+        tracker.set(1, 30, 0, &mut second);
+        let expect_second = tracker.expect(1);
+
+        // Unwritten keys aren't checked
+        tracker.check(b"k", tracker.expect(2), Some(&value(b"junk", 0)));
+        tracker.check(b"k", expect_first, Some(&value(&first, 1)));
+        tracker.check(b"k", expect_second, Some(&value(&second, 2)));
+        assert_eq!(tracker.counts.checked, 2);
+        assert_eq!(tracker.counts.failures(), 0);
+
+        tracker.check(b"k", expect_second, Some(&value(&first, 1)));
+        assert_eq!(tracker.counts.stale, 1);
+        second[25] = b'!';
+        tracker.check(b"k", expect_second, Some(&value(&second, 2)));
+        tracker.check(b"k", expect_first, Some(&value(&first, 7)));
+        assert_eq!(tracker.counts.corrupt, 2);
+        tracker.check(b"k", expect_first, None);
+        assert_eq!(tracker.counts.missing, 1);
+        assert_eq!(tracker.counts.failures(), 4);
+    }
+
+    #[test]
+    fn test_ttl_misses() {
+        let mut tracker = Tracker::default();
+        let mut data = Vec::new();
+        // Within the one-second slack a miss may be expiry
+        tracker.set(1, 16, 1, &mut data);
+        tracker.check(b"k", tracker.expect(1), None);
+        tracker.set(2, 16, 3600, &mut data);
+        tracker.check(b"k", tracker.expect(2), None);
+        assert_eq!(tracker.counts.missing, 1);
+    }
+}
@@ -0,0 +1,289 @@
+//! What to send: random numbers, distributions and the set/get mix
+
+use std::fmt;
+
+/// Skew of zipfian distributions, YCSB's default: 10% of 100k items get
+/// 80% of the picks
+const ZIPF_THETA: f64 = 0.99;
+
+/// xorshift64*, one per connection; seeded so runs can be repeated
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // splitmix64, so nearby seeds (per connection) diverge and the
+        // state is never 0
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        Self((z ^ (z >> 31)) | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in `0..n`; `n` > 0
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((u128::from(self.next_u64()) * u128::from(n)) >> 64) as u64
+    }
+
+    /// Uniform in `[0, 1)`
+    #[allow(clippy::cast_precision_loss)]
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Zipfian over `0..n`, 0 the most frequent (Gray et al., "Quickly
+/// Generating Billion-Record Synthetic Databases", as in YCSB)
+///
+/// Setup sums `n` terms, about a second for 100M items.
+#[derive(Debug, Clone)]
+pub struct Zipf {
+    n: u64,
+    alpha: f64,
+    zeta_all: f64,
+    eta: f64,
+    /// zeta(2): picks below it are 1
+    zeta_two: f64,
+}
+
+impl Zipf {
+    #[allow(clippy::cast_precision_loss)]
+    pub fn new(n: u64) -> Self {
+        let n = n.max(1);
+        let zeta_all: f64 = (1..=n).map(|i| 1.0 / (i as f64).powf(ZIPF_THETA)).sum();
+        let zeta_two = 1.0 + 0.5f64.powf(ZIPF_THETA);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - ZIPF_THETA)) / (1.0 - zeta_two / zeta_all);
+        Self {
+            n,
+            alpha: 1.0 / (1.0 - ZIPF_THETA),
+            zeta_all,
+            eta,
+            zeta_two,
+        }
+    }
+
+    #[allow(
+        clippy::cast_possible_truncation,
+        clippy::cast_precision_loss,
+        clippy::cast_sign_loss
+    )]
+    pub fn sample(&self, rng: &mut Rng) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zeta_all;
+        let pick = if uz < 1.0 {
+            0
+        } else if uz < self.zeta_two {
+            1
+        } else {
+            (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64
+        };
+        pick.min(self.n - 1)
+    }
+}
+
+/// Distribution of a value size, a TTL or a key index
+///
+/// Written `<n>` (or `fixed:<n>`), `uniform:<min>-<max>` or
+/// `zipfian:<min>-<max>`, where zipfian favors the low end.
+#[derive(Debug, Clone)]
+pub enum Dist {
+    Fixed(u64),
+    Uniform { min: u64, max: u64 },
+    Zipfian { min: u64, max: u64, zipf: Zipf },
+}
+
+impl Dist {
+    pub fn uniform(min: u64, max: u64) -> Self {
+        Self::Uniform { min, max }
+    }
+
+    pub fn zipfian(min: u64, max: u64) -> Self {
+        Self::Zipfian {
+            min,
+            max,
+            zipf: Zipf::new(max - min + 1),
+        }
+    }
+
+    /// Parse a spec whose numbers are read by `number` (e.g. `parse_size`)
+    pub fn parse(spec: &str, number: fn(&str) -> Result<u64, String>) -> Result<Self, String> {
+        let (kind, range) = spec.split_once(':').unwrap_or(("fixed", spec));
+        if kind == "fixed" {
+            return number(range).map(Dist::Fixed);
+        }
+        let (min, max) = range
+            .split_once('-')
+            .ok_or_else(|| format!("expected {kind}:<min>-<max>, got {spec:?}"))?;
+        let (min, max) = (number(min)?, number(max)?);
+        if min > max {
+            return Err(format!("{spec:?}: min is above max"));
+        }
+        match kind {
+            "uniform" => Ok(Self::uniform(min, max)),
+            "zipfian" => Ok(Self::zipfian(min, max)),
+            _ => Err(format!(
+                "unknown distribution {kind:?}, expected fixed, uniform or zipfian"
+            )),
+        }
+    }
+
+    pub fn sample(&self, rng: &mut Rng) -> u64 {
+        match self {
+            Self::Fixed(value) => *value,
+            Self::Uniform { min, max } => match (max - min).checked_add(1) {
+                Some(span) => min + rng.below(span),
+                None => rng.next_u64(),
+            },
+            Self::Zipfian { min, zipf, .. } => min + zipf.sample(rng),
+        }
+    }
+
+    pub fn min(&self) -> u64 {
+        match self {
+            Self::Fixed(value) => *value,
+            Self::Uniform { min, .. } | Self::Zipfian { min, .. } => *min,
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        match self {
+            Self::Fixed(value) => *value,
+            Self::Uniform { max, .. } | Self::Zipfian { max, .. } => *max,
+        }
+    }
+}
+
+impl fmt::Display for Dist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fixed(value) => write!(f, "{value}"),
+            Self::Uniform { min, max } => write!(f, "uniform {min}-{max}"),
+            Self::Zipfian { min, max, .. } => write!(f, "zipfian {min}-{max}"),
+        }
+    }
+}
+
+/// Sets to gets, memtier's `--ratio`, e.g. `1:10`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ratio {
+    pub sets: u32,
+    pub gets: u32,
+}
+
+impl Ratio {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid ratio {spec:?}, expected <sets>:<gets>, e.g. 1:10");
+        let (sets, gets) = spec.split_once(':').ok_or_else(invalid)?;
+        let ratio = Self {
+            sets: sets.trim().parse().map_err(|_| invalid())?,
+            gets: gets.trim().parse().map_err(|_| invalid())?,
+        };
+        if ratio.sets == 0 && ratio.gets == 0 {
+            return Err(invalid());
+        }
+        Ok(ratio)
+    }
+
+    /// Whether the next request is a set
+    pub fn next_is_set(self, rng: &mut Rng) -> bool {
+        rng.below(u64::from(self.sets) + u64::from(self.gets)) < u64::from(self.sets)
+    }
+}
+
+/// Key `index` moved to the nearest one connection `conn` of `conns` owns
+/// (index % conns == conn), staying below `keys`; `keys` >= `conns`
+pub fn owned_key(index: u64, conn: u64, conns: u64, keys: u64) -> u64 {
+    let owned = index - index % conns + conn;
+    if owned < keys { owned } else { owned - conns }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use petracache::config::units::parse_size;
+
+    #[test]
+    fn test_dist_parse() {
+        assert!(matches!(
+            Dist::parse("1KiB", parse_size),
+            Ok(Dist::Fixed(1024))
+        ));
+        assert!(matches!(
+            Dist::parse("fixed:100", parse_size),
+            Ok(Dist::Fixed(100))
+        ));
+        let dist = Dist::parse("uniform:10-1KB", parse_size).unwrap();
+        assert_eq!((dist.min(), dist.max()), (10, 1000));
+        assert_eq!(dist.to_string(), "uniform 10-1000");
+        assert!(matches!(
+            Dist::parse("zipfian:1-100", parse_size),
+            Ok(Dist::Zipfian {
+                min: 1,
+                max: 100,
+                ..
+            })
+        ));
+
+        for spec in ["uniform:10", "uniform:100-10", "normal:1-2", "x", "fixed:"] {
+            assert!(Dist::parse(spec, parse_size).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_dist_sample() {
+        let mut rng = Rng::new(1);
+        let uniform = Dist::uniform(10, 20);
+        let mut seen = [false; 11];
+        for _ in 0..1000 {
+            let value = uniform.sample(&mut rng);
+            assert!((10..=20).contains(&value));
+            seen[(value - 10) as usize] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+        assert!(Dist::uniform(0, u64::MAX).sample(&mut rng) > 0);
+
+        // Zipfian: the low end dominates, and the whole range is reachable
+        let zipfian = Dist::zipfian(1, 1000);
+        let samples: Vec<u64> = (0..100_000).map(|_| zipfian.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|value| (1..=1000).contains(value)));
+        let ones = samples.iter().filter(|&&value| value == 1).count();
+        let top_tenth = samples.iter().filter(|&&value| value <= 100).count();
+        assert!((10_000..20_000).contains(&ones), "{ones}");
+        assert!(top_tenth > 60_000, "{top_tenth}");
+        assert!(samples.iter().any(|&value| value > 900));
+    }
+
+    #[test]
+    fn test_ratio() {
+        assert_eq!(Ratio::parse("1:10"), Ok(Ratio { sets: 1, gets: 10 }));
+        let sets_only = Ratio::parse("1:0").unwrap();
+        let mut rng = Rng::new(7);
+        assert!((0..100).all(|_| sets_only.next_is_set(&mut rng)));
+        let even = Ratio::parse("1:1").unwrap();
+        let sets = (0..10_000).filter(|_| even.next_is_set(&mut rng)).count();
+        assert!((4_500..5_500).contains(&sets), "{sets}");
+        for spec in ["0:0", "1", "a:b", "-1:2"] {
+            assert!(Ratio::parse(spec).is_err(), "{spec}");
+        }
+    }
+
+    #[test]
+    fn test_owned_key() {
+        for index in 0..10 {
+            for conn in 0..3 {
+                let key = owned_key(index, conn, 3, 10);
+                assert!(key < 10);
+                assert_eq!(key % 3, conn);
+                assert!(key.abs_diff(index) < 3);
+            }
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! petracache-cli - command-line client for PetraCache nodes
+//!
+//! Memcached commands go through `petracache::client` over TCP or a Unix
+//! socket; `admin` tasks are `POST /admin/...` requests to the health
+//! server. With `--json` every command prints one JSON object, for scripts.
+//!
+//! Exit status: 0 on success, 1 for a miss (`get`, `delete`) or a value not
+//! stored, 2 for errors.
+
+use anyhow::{Context, bail};
+use clap::{Parser, Subcommand};
+use petracache::client::{Client, Value};
+use petracache::config::units::parse_duration;
+use serde_json::{Map, json};
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Command-line client for PetraCache
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Memcached address
+    #[arg(
+        short,
+        long,
+        value_name = "HOST:PORT",
+        default_value = "127.0.0.1:11211",
+        global = true
+    )]
+    server: String,
+
+    /// Connect to this Unix socket instead of --server
+    #[arg(short, long, value_name = "PATH", global = true)]
+    unix: Option<PathBuf>,
+
+    /// Health server address, for `admin`
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        default_value = "127.0.0.1:9090",
+        global = true
+    )]
+    admin_addr: String,
+
+    /// Give up on a command after this long (e.g. 5s, 500ms); admin tasks
+    /// wait until they finish
+    #[arg(long, value_name = "DURATION", default_value = "5s", value_parser = parse_timeout, global = true)]
+    timeout: Duration,
+
+    /// Print JSON instead of text
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Print a value
+    Get { key: String },
+
+    /// Store a value read from stdin or --file
+    Set {
+        key: String,
+        /// Read the value from this file instead of stdin
+        #[arg(short, long, value_name = "PATH")]
+        file: Option<PathBuf>,
+        /// Expire after this long (e.g. 60, 10m, 1d); 0 = server default
+        #[arg(short, long, value_name = "DURATION", default_value = "0", value_parser = parse_ttl)]
+        ttl: i64,
+        /// Client flags stored with the value
+        #[arg(long, default_value_t = 0)]
+        flags: u32,
+    },
+
+    /// Delete a key
+    Delete { key: String },
+
+    /// Print server statistics (`stats [group]`, e.g. settings or conns)
+    Stats { group: Option<String> },
+
+    /// Round-trip `version` and report the latency
+    Ping,
+
+    /// Run an admin task on the health server
+    Admin {
+        #[command(subcommand)]
+        task: AdminTask,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum AdminTask {
+    /// Full compaction (`POST /admin/compact`)
+    Compact,
+    /// Drop every key of a namespace (`POST /admin/flush_namespace`)
+    Flush { namespace: String },
+    /// Back up the database (`POST /admin/backup`)
+    Backup,
+}
+
+impl AdminTask {
+    /// Request target on the health server
+    fn target(&self) -> String {
+        match self {
+            AdminTask::Compact => "/admin/compact".to_string(),
+            AdminTask::Flush { namespace } => {
+                format!("/admin/flush_namespace?ns={}", percent_encode(namespace))
+            }
+            AdminTask::Backup => "/admin/backup".to_string(),
+        }
+    }
+}
+
+/// What a command found, for the exit status
+enum Outcome {
+    Done,
+    /// A miss, or a value that wasn't stored
+    Miss,
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    parse_duration(value, 1).map(Duration::from_millis)
+}
+
+fn parse_ttl(value: &str) -> Result<i64, String> {
+    let secs = parse_duration(value, 1000)?;
+    i64::try_from(secs).map_err(|_| format!("ttl {value:?} is too long"))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => return fail(&args, &e.into()),
+    };
+    match runtime.block_on(run(&args)) {
+        Ok(Outcome::Done) => ExitCode::SUCCESS,
+        Ok(Outcome::Miss) => ExitCode::from(1),
+        Err(e) => fail(&args, &e),
+    }
+}
+
+/// Report `error` on stderr (as JSON on stdout with `--json`)
+fn fail(args: &Args, error: &anyhow::Error) -> ExitCode {
+    if args.json {
+        println!("{}", json!({ "error": format!("{error:#}") }));
+    } else {
+        eprintln!("petracache-cli: {error:#}");
+    }
+    ExitCode::from(2)
+}
+
+async fn run(args: &Args) -> anyhow::Result<Outcome> {
+    if let Cmd::Admin { task } = &args.command {
+        return admin(args, task).await;
+    }
+    // The value is read before connecting, so a slow pipe can't time out
+    let value = match &args.command {
+        Cmd::Set {
+            file: Some(path), ..
+        } => Some(std::fs::read(path).with_context(|| format!("reading {}", path.display()))?),
+        Cmd::Set { file: None, .. } => {
+            let mut value = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut value)
+                .context("reading stdin")?;
+            Some(value)
+        }
+        _ => None,
+    };
+
+    let result = tokio::time::timeout(args.timeout, async {
+        match &args.unix {
+            #[cfg(unix)]
+            Some(path) => {
+                let client = Client::connect_unix(path)
+                    .await
+                    .with_context(|| format!("connecting to {}", path.display()))?;
+                execute(args, client, value.as_deref()).await
+            }
+            #[cfg(not(unix))]
+            Some(_) => bail!("--unix is only supported on Unix platforms"),
+            None => {
+                let client = Client::connect(args.server.as_str())
+                    .await
+                    .with_context(|| format!("connecting to {}", args.server))?;
+                execute(args, client, value.as_deref()).await
+            }
+        }
+    })
+    .await;
+    result.map_err(|_| anyhow::anyhow!("timed out after {:?}", args.timeout))?
+}
+
+/// Run a memcached command and print its result
+async fn execute<S: AsyncRead + AsyncWrite + Unpin>(
+    args: &Args,
+    mut client: Client<S>,
+    value: Option<&[u8]>,
+) -> anyhow::Result<Outcome> {
+    match &args.command {
+        Cmd::Get { key } => {
+            let found = client.get(key.as_bytes()).await?;
+            print_value(args.json, key, found.as_ref())?;
+            Ok(found.map_or(Outcome::Miss, |_| Outcome::Done))
+        }
+        Cmd::Set {
+            key, ttl, flags, ..
+        } => {
+            let data = value.unwrap_or_default();
+            let stored = client.set(key.as_bytes(), *flags, *ttl, data).await?;
+            let status = if stored { "STORED" } else { "NOT_STORED" };
+            let report = json!({ "key": key, "stored": stored, "bytes": data.len() });
+            print(args.json, &report, status);
+            Ok(if stored { Outcome::Done } else { Outcome::Miss })
+        }
+        Cmd::Delete { key } => {
+            let deleted = client.delete(key.as_bytes()).await?;
+            let status = if deleted { "DELETED" } else { "NOT_FOUND" };
+            print(
+                args.json,
+                &json!({ "key": key, "deleted": deleted }),
+                status,
+            );
+            Ok(if deleted {
+                Outcome::Done
+            } else {
+                Outcome::Miss
+            })
+        }
+        Cmd::Stats { group } => {
+            let stats = client.stats(group.as_deref()).await?;
+            if args.json {
+                let stats: Map<_, _> = stats
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect();
+                println!("{}", serde_json::Value::Object(stats));
+            } else {
+                for (name, value) in stats {
+                    println!("{name} {value}");
+                }
+            }
+            Ok(Outcome::Done)
+        }
+        Cmd::Ping => {
+            let started = Instant::now();
+            let version = client.version().await?;
+            let latency_ms = started.elapsed().as_secs_f64() * 1000.0;
+            let report = json!({ "version": version, "latency_ms": latency_ms });
+            print(
+                args.json,
+                &report,
+                &format!("{version} in {latency_ms:.3} ms"),
+            );
+            Ok(Outcome::Done)
+        }
+        Cmd::Admin { .. } => unreachable!("admin tasks don't use the memcached port"),
+    }
+}
+
+/// Print the value as is, or its details as JSON (the data as a string,
+/// invalid UTF-8 replaced)
+fn print_value(json: bool, key: &str, value: Option<&Value>) -> anyhow::Result<()> {
+    match (json, value) {
+        (true, Some(value)) => println!(
+            "{}",
+            json!({
+                "key": key,
+                "found": true,
+                "flags": value.flags,
+                "value": String::from_utf8_lossy(&value.data),
+            })
+        ),
+        (true, None) => println!("{}", json!({ "key": key, "found": false })),
+        (false, Some(value)) => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&value.data)?;
+            stdout.flush()?;
+        }
+        (false, None) => eprintln!("NOT_FOUND"),
+    }
+    Ok(())
+}
+
+fn print(json: bool, report: &serde_json::Value, text: &str) {
+    if json {
+        println!("{report}");
+    } else {
+        println!("{text}");
+    }
+}
+
+/// Run an admin task and print the health server's JSON reply
+async fn admin(args: &Args, task: &AdminTask) -> anyhow::Result<Outcome> {
+    let (status, body) = http_post(&args.admin_addr, &task.target(), args.timeout).await?;
+    if !(200..300).contains(&status) {
+        bail!("{} returned HTTP {status}: {}", task.target(), body.trim());
+    }
+    if args.json {
+        println!("{}", body.trim());
+    } else {
+        // {"status":"compacted","ttl_removed":3,...} as `status: compacted` lines
+        match serde_json::from_str::<Map<String, serde_json::Value>>(&body) {
+            Ok(fields) => {
+                for (name, value) in fields {
+                    match value {
+                        serde_json::Value::String(value) => println!("{name}: {value}"),
+                        value => println!("{name}: {value}"),
+                    }
+                }
+            }
+            Err(_) => println!("{}", body.trim()),
+        }
+    }
+    Ok(Outcome::Done)
+}
+
+/// `POST target` on `addr`, returning the status code and body
+///
+/// Only connecting is bounded by `timeout`: compactions and backups take as
+/// long as they take.
+async fn http_post(addr: &str, target: &str, timeout: Duration) -> anyhow::Result<(u16, String)> {
+    let mut stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out connecting to {addr}"))?
+        .with_context(|| format!("connecting to {addr}"))?;
+    let request = format!(
+        "POST {target} HTTP/1.1\r\nHost: {addr}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    parse_http_response(&response)
+}
+
+/// Status code and body of an HTTP/1.1 response read to the end
+fn parse_http_response(response: &[u8]) -> anyhow::Result<(u16, String)> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .context("incomplete HTTP response")?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("bad HTTP status line {:?}", head.lines().next()))?;
+    Ok((status, body.to_string()))
+}
+
+/// Percent-encode a query parameter value
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let args = Args::try_parse_from([
+            "petracache-cli",
+            "--unix",
+            "/run/petracache.sock",
+            "set",
+            "foo",
+            "--ttl",
+            "10m",
+            "--flags",
+            "3",
+            "--json",
+        ])
+        .unwrap();
+        assert_eq!(args.unix, Some(PathBuf::from("/run/petracache.sock")));
+        assert!(args.json);
+        assert!(matches!(
+            args.command,
+            Cmd::Set {
+                ttl: 600,
+                flags: 3,
+                file: None,
+                ..
+            }
+        ));
+
+        let args = Args::try_parse_from(["petracache-cli", "admin", "flush", "users/eu"]).unwrap();
+        let Cmd::Admin { task } = args.command else {
+            panic!("not an admin command");
+        };
+        assert_eq!(task.target(), "/admin/flush_namespace?ns=users%2Feu");
+        assert_eq!(args.timeout, Duration::from_secs(5));
+
+        assert!(Args::try_parse_from(["petracache-cli", "set", "foo", "--ttl", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_http_response() {
+        let (status, body) = parse_http_response(
+            b"HTTP/1.1 409 Conflict\r\nContent-Type: application/json\r\n\r\n{\"status\":\"already running\"}",
+        )
+        .unwrap();
+        assert_eq!(status, 409);
+        assert_eq!(body, r#"{"status":"already running"}"#);
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_http_response(b"garbage\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,793 @@
+//! Async memcached ASCII client for tests and tooling
+//!
+//! Requests are `protocol::Command`s, the type the server parses, so the
+//! client can't drift from what the server accepts. It works over any
+//! `AsyncRead + AsyncWrite` stream: TCP, a Unix socket, TLS or an in-memory
+//! pipe.
+//!
+//! Requests pipeline: `send` queues commands, `flush` writes them all at
+//! once and `recv` returns the replies in order. A `noreply` command is not
+//! waited for; if the server still answers it with an error line (memcached
+//! does, e.g. for a disabled command), that line is taken as the reply to
+//! the next request. Meta commands are not supported.
+
+use crate::ProtocolError;
+use crate::protocol::command::validate_key;
+use crate::protocol::{Command, CommandKind, find_line};
+use bytes::{Buf, BytesMut};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// Initial size of the read and write buffers
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// Client for one connection
+pub struct Client<S> {
+    stream: S,
+    read_buf: BytesMut,
+    /// Requests queued by `send`, written by `flush`
+    write_buf: BytesMut,
+    /// Requests sent and not yet answered, oldest first
+    pending: VecDeque<CommandKind>,
+}
+
+/// A value from `get`, `gets`, `gat` or `gats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Value {
+    pub key: Vec<u8>,
+    pub flags: u32,
+    pub data: Vec<u8>,
+    /// CAS unique, for `gets` and `gats`
+    pub cas: Option<u64>,
+}
+
+/// One reply from the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// `VALUE` lines up to `END`; empty on a miss
+    Values(Vec<Value>),
+    /// `STAT <name> <value>` lines up to `END`
+    Stats(Vec<(String, String)>),
+    Stored,
+    NotStored,
+    Exists,
+    NotFound,
+    Deleted,
+    Ok,
+    /// Reply to `stats reset`
+    Reset,
+    /// New value after `incr` / `decr`
+    Numeric(u64),
+    Version(String),
+    /// `ERROR`: unknown command
+    Error,
+    /// `CLIENT_ERROR <message>`
+    ClientError(String),
+    /// `SERVER_ERROR <message>`
+    ServerError(String),
+}
+
+impl Reply {
+    /// Whether this is `ERROR`, `CLIENT_ERROR` or `SERVER_ERROR`
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Reply::Error | Reply::ClientError(_) | Reply::ServerError(_)
+        )
+    }
+}
+
+/// Why a client call failed
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("connection closed")]
+    Closed,
+
+    /// A key the server couldn't parse; sending it would desynchronize
+    /// the connection
+    #[error(transparent)]
+    InvalidKey(#[from] ProtocolError),
+
+    #[error("{0} is not supported by the client")]
+    Unsupported(&'static str),
+
+    #[error("no request is waiting for a reply")]
+    NothingPending,
+
+    /// `request` while replies to pipelined requests are still unread
+    #[error("{0} pipelined replies are still unread")]
+    RepliesPending(usize),
+
+    #[error("malformed reply: {0}")]
+    Malformed(String),
+
+    /// `ERROR`, `CLIENT_ERROR` or `SERVER_ERROR`
+    #[error("server replied {0:?}")]
+    Server(Reply),
+
+    #[error("unexpected reply {0:?}")]
+    Unexpected(Reply),
+}
+
+impl Client<TcpStream> {
+    /// Connect over TCP
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, ClientError> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream))
+    }
+}
+
+#[cfg(unix)]
+impl Client<tokio::net::UnixStream> {
+    /// Connect to a Unix socket (`server.unix_socket_path`)
+    pub async fn connect_unix(path: impl AsRef<std::path::Path>) -> Result<Self, ClientError> {
+        Ok(Self::new(tokio::net::UnixStream::connect(path).await?))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Use an already connected stream
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            read_buf: BytesMut::with_capacity(BUFFER_SIZE),
+            write_buf: BytesMut::with_capacity(BUFFER_SIZE),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The underlying stream, e.g. to write raw bytes
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Requests sent or queued that still await a reply
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Queue a request; nothing is written until `flush` or `recv`
+    pub fn send(&mut self, command: &Command<'_>) -> Result<(), ClientError> {
+        encode(command, &mut self.write_buf)?;
+        if expects_reply(command) {
+            self.pending.push_back(command.kind());
+        }
+        Ok(())
+    }
+
+    /// Write the queued requests
+    pub async fn flush(&mut self) -> Result<(), ClientError> {
+        if !self.write_buf.is_empty() {
+            self.stream.write_all(&self.write_buf).await?;
+            self.write_buf.clear();
+        }
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Read the reply to the oldest pending request, flushing queued
+    /// requests first
+    ///
+    /// Error replies are returned as `Reply`s; only failures to read one are
+    /// errors.
+    pub async fn recv(&mut self) -> Result<Reply, ClientError> {
+        let kind = self
+            .pending
+            .pop_front()
+            .ok_or(ClientError::NothingPending)?;
+        self.flush().await?;
+
+        let mut values = Vec::new();
+        let mut stats = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if let Some(header) = line.strip_prefix(b"VALUE ") {
+                let (mut value, len) = parse_value_header(header)?;
+                value.data = self.read_data(len).await?;
+                values.push(value);
+                continue;
+            }
+            if let Some(stat) = line.strip_prefix(b"STAT ") {
+                let stat = text(stat)?;
+                let (name, value) = stat.split_once(' ').unwrap_or((stat, ""));
+                stats.push((name.to_string(), value.to_string()));
+                continue;
+            }
+            return match &line[..] {
+                b"END" if kind == CommandKind::Stats => Ok(Reply::Stats(stats)),
+                b"END" => Ok(Reply::Values(values)),
+                _ => parse_status(&line),
+            };
+        }
+    }
+
+    /// Send one request and read its reply (`None` for noreply)
+    pub async fn request(&mut self, command: &Command<'_>) -> Result<Option<Reply>, ClientError> {
+        if !expects_reply(command) {
+            self.send(command)?;
+            self.flush().await?;
+            return Ok(None);
+        }
+        // Replies to earlier pipelined requests are the caller's to read
+        if !self.pending.is_empty() {
+            return Err(ClientError::RepliesPending(self.pending.len()));
+        }
+        self.send(command)?;
+        self.recv().await.map(Some)
+    }
+
+    /// `get <key>`
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Value>, ClientError> {
+        Ok(self.get_multi(&[key]).await?.pop())
+    }
+
+    /// `get <key>*`; misses are left out
+    pub async fn get_multi(&mut self, keys: &[&[u8]]) -> Result<Vec<Value>, ClientError> {
+        let keys = keys.iter().map(|key| Cow::Borrowed(*key)).collect();
+        match self.call(&Command::Get { keys }).await? {
+            Reply::Values(values) => Ok(values),
+            reply => Err(ClientError::Unexpected(reply)),
+        }
+    }
+
+    /// `set`; whether the value was stored
+    pub async fn set(
+        &mut self,
+        key: &[u8],
+        flags: u32,
+        exptime: i64,
+        data: &[u8],
+    ) -> Result<bool, ClientError> {
+        let command = set_command(key, flags, exptime, data, false);
+        match self.call(&command).await? {
+            Reply::Stored => Ok(true),
+            Reply::NotStored => Ok(false),
+            reply => Err(ClientError::Unexpected(reply)),
+        }
+    }
+
+    /// `set ... noreply`
+    pub async fn set_noreply(
+        &mut self,
+        key: &[u8],
+        flags: u32,
+        exptime: i64,
+        data: &[u8],
+    ) -> Result<(), ClientError> {
+        let command = set_command(key, flags, exptime, data, true);
+        self.request(&command).await.map(drop)
+    }
+
+    /// `delete`; whether the key existed
+    pub async fn delete(&mut self, key: &[u8]) -> Result<bool, ClientError> {
+        match self.call(&delete_command(key, false)).await? {
+            Reply::Deleted => Ok(true),
+            Reply::NotFound => Ok(false),
+            reply => Err(ClientError::Unexpected(reply)),
+        }
+    }
+
+    /// `delete ... noreply`
+    pub async fn delete_noreply(&mut self, key: &[u8]) -> Result<(), ClientError> {
+        self.request(&delete_command(key, true)).await.map(drop)
+    }
+
+    /// `version`, without the `VERSION ` prefix
+    pub async fn version(&mut self) -> Result<String, ClientError> {
+        match self.call(&Command::Version).await? {
+            Reply::Version(version) => Ok(version),
+            reply => Err(ClientError::Unexpected(reply)),
+        }
+    }
+
+    /// `stats [args]`, e.g. `stats(Some("settings"))`
+    pub async fn stats(
+        &mut self,
+        args: Option<&str>,
+    ) -> Result<Vec<(String, String)>, ClientError> {
+        let args = args.map(|args| Cow::Borrowed(args.as_bytes()));
+        match self.call(&Command::Stats { args }).await? {
+            Reply::Stats(stats) => Ok(stats),
+            reply => Err(ClientError::Unexpected(reply)),
+        }
+    }
+
+    /// A request expecting a reply, with error replies turned into errors
+    async fn call(&mut self, command: &Command<'_>) -> Result<Reply, ClientError> {
+        match self.request(command).await? {
+            Some(reply) if reply.is_error() => Err(ClientError::Server(reply)),
+            Some(reply) => Ok(reply),
+            None => Err(ClientError::NothingPending),
+        }
+    }
+
+    /// Read more from the stream
+    async fn fill(&mut self) -> Result<(), ClientError> {
+        if self.stream.read_buf(&mut self.read_buf).await? == 0 {
+            return Err(ClientError::Closed);
+        }
+        Ok(())
+    }
+
+    /// Next reply line, without its CRLF
+    async fn read_line(&mut self) -> Result<BytesMut, ClientError> {
+        loop {
+            if let Some((end, next)) = find_line(&self.read_buf, false) {
+                let mut line = self.read_buf.split_to(next);
+                line.truncate(end);
+                return Ok(line);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// A `len`-byte data block and its CRLF
+    async fn read_data(&mut self, len: usize) -> Result<Vec<u8>, ClientError> {
+        self.read_buf
+            .reserve((len + 2).saturating_sub(self.read_buf.len()));
+        while self.read_buf.len() < len + 2 {
+            self.fill().await?;
+        }
+        if &self.read_buf[len..len + 2] != b"\r\n" {
+            return Err(ClientError::Malformed("bad data chunk".to_string()));
+        }
+        let data = self.read_buf[..len].to_vec();
+        self.read_buf.advance(len + 2);
+        Ok(data)
+    }
+}
+
+/// Whether the server answers `command` (`quit` just closes)
+fn expects_reply(command: &Command<'_>) -> bool {
+    !command.is_noreply() && command.kind() != CommandKind::Quit
+}
+
+fn set_command<'a>(
+    key: &'a [u8],
+    flags: u32,
+    exptime: i64,
+    data: &'a [u8],
+    noreply: bool,
+) -> Command<'a> {
+    Command::Set {
+        key: Cow::Borrowed(key),
+        flags,
+        exptime,
+        data: Cow::Borrowed(data),
+        noreply,
+    }
+}
+
+fn delete_command(key: &[u8], noreply: bool) -> Command<'_> {
+    Command::Delete {
+        key: Cow::Borrowed(key),
+        noreply,
+    }
+}
+
+/// Write `command` as the server expects to parse it
+fn encode(command: &Command<'_>, buf: &mut BytesMut) -> Result<(), ClientError> {
+    match command {
+        Command::Get { keys } | Command::Gets { keys } => {
+            retrieval(buf, command.name(), None, keys)?;
+        }
+        Command::Gat { exptime, keys } | Command::Gats { exptime, keys } => {
+            retrieval(buf, command.name(), Some(*exptime), keys)?;
+        }
+        Command::Set {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        }
+        | Command::Add {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        }
+        | Command::Replace {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        } => storage(
+            buf,
+            command.name(),
+            key,
+            (*flags, *exptime),
+            data,
+            None,
+            *noreply,
+        )?,
+        Command::Append { key, data, noreply } | Command::Prepend { key, data, noreply } => {
+            storage(buf, command.name(), key, (0, 0), data, None, *noreply)?;
+        }
+        Command::Cas {
+            key,
+            flags,
+            exptime,
+            data,
+            cas_unique,
+            noreply,
+        } => storage(
+            buf,
+            command.name(),
+            key,
+            (*flags, *exptime),
+            data,
+            Some(*cas_unique),
+            *noreply,
+        )?,
+        Command::Delete { key, noreply } => {
+            validate_key(key, false)?;
+            line(buf, &[b"delete", key], *noreply);
+        }
+        Command::Incr {
+            key,
+            delta,
+            noreply,
+        }
+        | Command::Decr {
+            key,
+            delta,
+            noreply,
+        } => {
+            validate_key(key, false)?;
+            let delta = delta.to_string();
+            line(
+                buf,
+                &[command.name().as_bytes(), key, delta.as_bytes()],
+                *noreply,
+            );
+        }
+        Command::FlushAll { delay, noreply } => {
+            let delay = delay.to_string();
+            line(buf, &[b"flush_all", delay.as_bytes()], *noreply);
+        }
+        Command::Stats { args: Some(args) } => line(buf, &[b"stats", args], false),
+        Command::Stats { args: None } => line(buf, &[b"stats"], false),
+        Command::Version => line(buf, &[b"version"], false),
+        Command::Quit => line(buf, &[b"quit"], false),
+        Command::MetaGet { .. }
+        | Command::MetaSet { .. }
+        | Command::MetaDelete { .. }
+        | Command::MetaArithmetic { .. }
+        | Command::MetaNoop => return Err(ClientError::Unsupported(command.name())),
+    }
+    Ok(())
+}
+
+/// `<name> [<exptime>] <key>*`
+fn retrieval(
+    buf: &mut BytesMut,
+    name: &str,
+    exptime: Option<i64>,
+    keys: &[Cow<'_, [u8]>],
+) -> Result<(), ClientError> {
+    let exptime = exptime.map(|exptime| exptime.to_string());
+    let mut words = vec![name.as_bytes()];
+    words.extend(exptime.as_ref().map(String::as_bytes));
+    for key in keys {
+        validate_key(key, false)?;
+        words.push(key);
+    }
+    line(buf, &words, false);
+    Ok(())
+}
+
+/// `<name> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]` and
+/// the data block
+fn storage(
+    buf: &mut BytesMut,
+    name: &str,
+    key: &[u8],
+    (flags, exptime): (u32, i64),
+    data: &[u8],
+    cas_unique: Option<u64>,
+    noreply: bool,
+) -> Result<(), ClientError> {
+    validate_key(key, false)?;
+    let numbers = [
+        flags.to_string(),
+        exptime.to_string(),
+        data.len().to_string(),
+    ];
+    let cas_unique = cas_unique.map(|cas| cas.to_string());
+    let mut words = vec![name.as_bytes(), key];
+    words.extend(numbers.iter().map(String::as_bytes));
+    words.extend(cas_unique.as_ref().map(String::as_bytes));
+    line(buf, &words, noreply);
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
+    Ok(())
+}
+
+/// Space-separated `words`, ` noreply` if set, and CRLF
+fn line(buf: &mut BytesMut, words: &[&[u8]], noreply: bool) {
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            buf.extend_from_slice(b" ");
+        }
+        buf.extend_from_slice(word);
+    }
+    if noreply {
+        buf.extend_from_slice(b" noreply");
+    }
+    buf.extend_from_slice(b"\r\n");
+}
+
+/// Parse `<key> <flags> <bytes> [<cas unique>]` (after `VALUE `); the data
+/// is filled in by the caller
+fn parse_value_header(header: &[u8]) -> Result<(Value, usize), ClientError> {
+    let malformed = || ClientError::Malformed(String::from_utf8_lossy(header).to_string());
+    let mut parts = header.split(|&b| b == b' ');
+    let key = parts.next().ok_or_else(malformed)?.to_vec();
+    let mut numbers = parts.map(|part| std::str::from_utf8(part).ok());
+    let mut number = || numbers.next().flatten().ok_or_else(malformed);
+    let flags = number()?.parse().map_err(|_| malformed())?;
+    let len = number()?.parse().map_err(|_| malformed())?;
+    let cas = match numbers.next() {
+        Some(cas) => Some(cas.and_then(|cas| cas.parse().ok()).ok_or_else(malformed)?),
+        None => None,
+    };
+    let value = Value {
+        key,
+        flags,
+        data: Vec::new(),
+        cas,
+    };
+    Ok((value, len))
+}
+
+/// A single-line reply
+fn parse_status(line: &[u8]) -> Result<Reply, ClientError> {
+    let reply = match line {
+        b"STORED" => Reply::Stored,
+        b"NOT_STORED" => Reply::NotStored,
+        b"EXISTS" => Reply::Exists,
+        b"NOT_FOUND" => Reply::NotFound,
+        b"DELETED" => Reply::Deleted,
+        b"OK" => Reply::Ok,
+        b"RESET" => Reply::Reset,
+        b"ERROR" => Reply::Error,
+        _ => {
+            let line = text(line)?;
+            if let Some(version) = line.strip_prefix("VERSION ") {
+                Reply::Version(version.to_string())
+            } else if let Some(message) = line.strip_prefix("CLIENT_ERROR ") {
+                Reply::ClientError(message.to_string())
+            } else if let Some(message) = line.strip_prefix("SERVER_ERROR ") {
+                Reply::ServerError(message.to_string())
+            } else {
+                Reply::Numeric(
+                    line.parse()
+                        .map_err(|_| ClientError::Malformed(line.to_string()))?,
+                )
+            }
+        }
+    };
+    Ok(reply)
+}
+
+fn text(bytes: &[u8]) -> Result<&str, ClientError> {
+    std::str::from_utf8(bytes)
+        .map_err(|_| ClientError::Malformed(String::from_utf8_lossy(bytes).to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ParseResult, parse};
+    use crate::testing::TestServer;
+
+    #[test]
+    fn test_encode_parses_back() {
+        let commands = [
+            Command::Get {
+                keys: vec![Cow::Borrowed(&b"a"[..]), Cow::Borrowed(&b"b"[..])],
+            },
+            Command::Gats {
+                exptime: 60,
+                keys: vec![Cow::Borrowed(&b"a"[..])],
+            },
+            set_command(b"foo", 5, -1, b"bar\r\nbaz", true),
+            Command::Cas {
+                key: Cow::Borrowed(b"foo"),
+                flags: 0,
+                exptime: 0,
+                data: Cow::Borrowed(b""),
+                cas_unique: 42,
+                noreply: false,
+            },
+            Command::Prepend {
+                key: Cow::Borrowed(b"foo"),
+                data: Cow::Borrowed(b"x"),
+                noreply: false,
+            },
+            delete_command(b"foo", true),
+            Command::Decr {
+                key: Cow::Borrowed(b"n"),
+                delta: 7,
+                noreply: false,
+            },
+            Command::FlushAll {
+                delay: 10,
+                noreply: true,
+            },
+            Command::Stats {
+                args: Some(Cow::Borrowed(b"settings")),
+            },
+            Command::Stats { args: None },
+            Command::Version,
+            Command::Quit,
+        ];
+        for command in commands {
+            let mut buf = BytesMut::new();
+            encode(&command, &mut buf).unwrap();
+            match parse(&buf) {
+                ParseResult::Complete(parsed, consumed) => {
+                    assert_eq!(parsed, command);
+                    assert_eq!(consumed, buf.len());
+                }
+                other => panic!("{command:?} parsed as {other:?}"),
+            }
+        }
+
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            encode(&delete_command(b"two words", false), &mut buf),
+            Err(ClientError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            encode(&Command::MetaNoop, &mut buf),
+            Err(ClientError::Unsupported("mn"))
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_replies() {
+        let (value, len) = parse_value_header(b"foo 5 3 42").unwrap();
+        assert_eq!(
+            (&value.key[..], value.flags, value.cas, len),
+            (&b"foo"[..], 5, Some(42), 3)
+        );
+        assert_eq!(parse_value_header(b"foo 0 3").unwrap().0.cas, None);
+        assert!(parse_value_header(b"foo x 3").is_err());
+        assert!(parse_value_header(b"foo 0").is_err());
+
+        assert_eq!(parse_status(b"NOT_STORED").unwrap(), Reply::NotStored);
+        assert_eq!(parse_status(b"12").unwrap(), Reply::Numeric(12));
+        assert_eq!(
+            parse_status(b"VERSION petracache 1.0").unwrap(),
+            Reply::Version("petracache 1.0".to_string())
+        );
+        assert_eq!(
+            parse_status(b"SERVER_ERROR out of memory").unwrap(),
+            Reply::ServerError("out of memory".to_string())
+        );
+        assert!(matches!(
+            parse_status(b"HD"),
+            Err(ClientError::Malformed(line)) if line == "HD"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let server = TestServer::spawn().await;
+        let mut client = server.client().await;
+
+        assert_eq!(client.get(b"foo").await.unwrap(), None);
+        assert!(client.set(b"foo", 3, 0, b"bar").await.unwrap());
+        client.set_noreply(b"baz", 0, 0, b"").await.unwrap();
+        let values = client
+            .get_multi(&[b"foo", b"missing", b"baz"])
+            .await
+            .unwrap();
+        let found: Vec<_> = values
+            .iter()
+            .map(|v| (&v.key[..], v.flags, &v.data[..]))
+            .collect();
+        assert_eq!(found, [(&b"foo"[..], 3, &b"bar"[..]), (b"baz", 0, b"")]);
+
+        assert!(client.delete(b"foo").await.unwrap());
+        assert!(!client.delete(b"foo").await.unwrap());
+        client.delete_noreply(b"baz").await.unwrap();
+        assert_eq!(client.get(b"baz").await.unwrap(), None);
+
+        assert!(client.version().await.unwrap().starts_with("petracache "));
+        let stats = client.stats(Some("settings")).await.unwrap();
+        assert!(
+            stats.contains(&("max_connections".to_string(), "10000".to_string())),
+            "{stats:?}"
+        );
+
+        // Error replies are errors from the convenience methods and plain
+        // replies from `request`
+        server.server().set_read_only(true);
+        assert!(matches!(
+            client.set(b"foo", 0, 0, b"bar").await,
+            Err(ClientError::Server(Reply::ServerError(message))) if message == "server is read-only"
+        ));
+        let incr = Command::Incr {
+            key: Cow::Borrowed(b"foo"),
+            delta: 1,
+            noreply: false,
+        };
+        assert!(client.request(&incr).await.unwrap().unwrap().is_error());
+        assert!(matches!(
+            client.recv().await,
+            Err(ClientError::NothingPending)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let server = TestServer::spawn().await;
+        let mut client = server.client().await;
+
+        for i in 0..100 {
+            let key = format!("key:{i}");
+            let data = format!("value:{i}");
+            client
+                .send(&set_command(
+                    key.as_bytes(),
+                    i,
+                    0,
+                    data.as_bytes(),
+                    i % 2 == 0,
+                ))
+                .unwrap();
+            client
+                .send(&Command::Gets {
+                    keys: vec![Cow::Owned(key.into_bytes())],
+                })
+                .unwrap();
+        }
+        client.send(&Command::Stats { args: None }).unwrap();
+        // Only the sets without noreply and the gets await replies
+        assert_eq!(client.pending(), 151);
+
+        client.flush().await.unwrap();
+        for i in 0..100 {
+            if i % 2 == 1 {
+                assert_eq!(client.recv().await.unwrap(), Reply::Stored);
+            }
+            let Reply::Values(values) = client.recv().await.unwrap() else {
+                panic!("no values for key:{i}");
+            };
+            assert_eq!(values.len(), 1);
+            assert_eq!(values[0].flags, i);
+            assert_eq!(values[0].data, format!("value:{i}").into_bytes());
+            assert!(values[0].cas.is_some());
+        }
+        let Reply::Stats(stats) = client.recv().await.unwrap() else {
+            panic!("no stats");
+        };
+        assert!(stats.contains(&("cmd_set".to_string(), "100".to_string())));
+        assert_eq!(client.pending(), 0);
+        assert_eq!(server.metrics().cmd_set.get(), 100);
+
+        // Quit expects no reply; the server closes the connection
+        client.request(&Command::Quit).await.unwrap();
+        assert!(matches!(
+            client.version().await,
+            Err(ClientError::Closed | ClientError::Io(_))
+        ));
+    }
+}
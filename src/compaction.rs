@@ -0,0 +1,189 @@
+//! Manual full compaction, on a schedule and on demand
+//!
+//! RocksDB only compacts a file once enough data lands on top of it, so a
+//! quiet keyspace can keep expired items on disk long after the TTL filter
+//! would drop them. `Compactor` runs `RocksStorage::compact` on a blocking
+//! thread, at most one at a time; `run` triggers it every
+//! `compaction_schedule.interval_secs` (optionally only inside a UTC time
+//! window) and the health server's `POST /admin/compact` triggers it on
+//! demand.
+
+use crate::config::CompactionScheduleConfig;
+use crate::metrics::Metrics;
+use crate::storage::RocksStorage;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// How often the scheduler checks whether a compaction is due
+const SCHEDULE_CHECK_PERIOD: Duration = Duration::from_secs(60);
+
+/// Runs full compactions, never two at once
+pub struct Compactor {
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    running: AtomicBool,
+}
+
+/// Outcome of one full compaction
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionResult {
+    /// Keys the TTL filter removed while it ran
+    pub ttl_removed: u64,
+    pub elapsed: Duration,
+}
+
+impl Compactor {
+    pub fn new(storage: Arc<RocksStorage>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            storage,
+            metrics,
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Compact the whole keyspace, blocking until done
+    ///
+    /// Returns `None` without doing anything if a compaction is already
+    /// running. Call from a blocking thread, never from an async worker.
+    pub fn compact(&self) -> Option<CompactionResult> {
+        let _running = RunningGuard::acquire(self)?;
+        let started = Instant::now();
+        let ttl_removed = self.storage.compact();
+        Some(CompactionResult {
+            ttl_removed,
+            elapsed: started.elapsed(),
+        })
+    }
+
+    /// Whether a compaction is running
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+}
+
+/// Holds `Compactor::running` (and the gauge) for one compaction
+struct RunningGuard<'a> {
+    compactor: &'a Compactor,
+}
+
+impl<'a> RunningGuard<'a> {
+    fn acquire(compactor: &'a Compactor) -> Option<Self> {
+        compactor
+            .running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .ok()?;
+        compactor.metrics.compaction_running.set(1);
+        Some(Self { compactor })
+    }
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.compactor.metrics.compaction_running.set(0);
+        self.compactor.running.store(false, Ordering::Release);
+    }
+}
+
+/// Minutes since UTC midnight
+fn utc_minute_of_day() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    ((secs % 86_400) / 60) as u32
+}
+
+/// Compact on `schedule` until `cancel` fires; returns immediately if the
+/// schedule is disabled
+///
+/// The interval counts from the last scheduled compaction (or startup). A
+/// compaction that comes due outside the window waits for the window to
+/// open.
+pub async fn run(
+    compactor: Arc<Compactor>,
+    schedule: &CompactionScheduleConfig,
+    cancel: CancellationToken,
+) {
+    if schedule.interval_secs == 0 {
+        return;
+    }
+    info!(
+        interval_secs = schedule.interval_secs,
+        window = ?schedule.window,
+        "Scheduled compaction enabled"
+    );
+    let interval = Duration::from_secs(schedule.interval_secs);
+    let mut last_run = Instant::now();
+    let mut ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + interval.min(SCHEDULE_CHECK_PERIOD),
+        interval.min(SCHEDULE_CHECK_PERIOD),
+    );
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+        if last_run.elapsed() < interval {
+            continue;
+        }
+        if let Some(window) = schedule.window
+            && !window.contains(utc_minute_of_day())
+        {
+            continue;
+        }
+
+        last_run = Instant::now();
+        let task_compactor = Arc::clone(&compactor);
+        match tokio::task::spawn_blocking(move || task_compactor.compact()).await {
+            Ok(Some(result)) => info!(
+                ttl_removed = result.ttl_removed,
+                elapsed_ms = result.elapsed.as_millis(),
+                "Scheduled compaction completed"
+            ),
+            Ok(None) => debug!("Scheduled compaction skipped, one is already running"),
+            Err(e) => error!("Scheduled compaction task failed: {}", e),
+        }
+    }
+    debug!("Compaction scheduler stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use tempfile::TempDir;
+
+    fn compactor(tmp_dir: &TempDir) -> Compactor {
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        Compactor::new(Arc::new(storage), Arc::new(Metrics::new().unwrap()))
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp_dir = TempDir::new().unwrap();
+        let compactor = compactor(&tmp_dir);
+        assert!(compactor.compact().is_some());
+        assert!(!compactor.is_running());
+        assert_eq!(compactor.metrics.compaction_running.get(), 0);
+    }
+
+    #[test]
+    fn test_overlapping_compaction_is_noop() {
+        let tmp_dir = TempDir::new().unwrap();
+        let compactor = compactor(&tmp_dir);
+        let running = RunningGuard::acquire(&compactor).unwrap();
+        assert_eq!(compactor.metrics.compaction_running.get(), 1);
+        assert!(compactor.compact().is_none());
+
+        drop(running);
+        assert!(compactor.compact().is_some());
+    }
+}
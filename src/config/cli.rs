@@ -0,0 +1,91 @@
+//! Command-line flags
+//!
+//! Flags override the environment, which overrides the config file (see
+//! `config::load`). Only the settings an operator most often changes for a
+//! single run have a flag; everything else goes through the file or a
+//! `PETRACACHE_*` variable.
+
+use super::Config;
+use clap::Parser;
+use std::path::PathBuf;
+
+/// High-performance memcached-compatible cache server backed by RocksDB
+#[derive(Debug, Clone, Default, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Config file (TOML, or YAML/JSON by extension)
+    #[arg(short, long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Config file (same as --config)
+    #[arg(value_name = "CONFIG", conflicts_with = "config")]
+    pub config_path: Option<PathBuf>,
+
+    /// TCP address to listen on (server.listen_addr)
+    #[arg(long, value_name = "ADDR")]
+    pub listen_addr: Option<String>,
+
+    /// RocksDB data directory (storage.db_path)
+    #[arg(long, value_name = "PATH")]
+    pub db_path: Option<PathBuf>,
+
+    /// Metrics and health server address (metrics.listen_addr)
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<String>,
+
+    /// Maximum concurrent connections (server.max_connections)
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+
+    /// Tokio worker threads, 0 = one per CPU (server.worker_threads)
+    #[arg(long, value_name = "N")]
+    pub worker_threads: Option<usize>,
+
+    /// Log filter directives (log.level); wins over RUST_LOG
+    #[arg(long, value_name = "DIRECTIVES")]
+    pub log_level: Option<String>,
+
+    /// Start in read-only mode (server.read_only)
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long, conflicts_with = "check_config")]
+    pub print_config: bool,
+
+    /// Check the configuration and exit, non-zero if it is invalid
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+impl Args {
+    /// The config file, given either way
+    pub fn config_file(&self) -> Option<&PathBuf> {
+        self.config.as_ref().or(self.config_path.as_ref())
+    }
+
+    /// Apply the flags that were given to `config`
+    pub(super) fn apply(&self, config: &mut Config) {
+        if let Some(addr) = &self.listen_addr {
+            config.server.listen_addr.clone_from(addr);
+        }
+        if let Some(path) = &self.db_path {
+            config.storage.db_path.clone_from(path);
+        }
+        if let Some(addr) = &self.metrics_addr {
+            config.metrics.listen_addr.clone_from(addr);
+        }
+        if let Some(n) = self.max_connections {
+            config.server.max_connections = n;
+        }
+        if let Some(n) = self.worker_threads {
+            config.server.worker_threads = n;
+        }
+        if let Some(level) = &self.log_level {
+            config.log.level.clone_from(level);
+        }
+        if self.read_only {
+            config.server.read_only = true;
+        }
+    }
+}
@@ -0,0 +1,879 @@
+//! Environment variable overrides for every configuration field
+//!
+//! `PETRACACHE_<SECTION>_<FIELD>` sets `<section>.<field>`, with one more
+//! segment per nested section (`PETRACACHE_STORAGE_COMPACTION_SCHEDULE_WINDOW`).
+//! There is no hand-kept table of names: `Layered` deserializes the config
+//! over the file's TOML values and, at every section, looks up the
+//! variables of the fields serde asks for, so a new field gets a variable
+//! without further code. A variable wins over the file. Its value is parsed
+//! as the field's type; lists are comma-separated, and an empty value
+//! unsets an optional field. Variables that match no field fail loading,
+//! so a typo doesn't go unnoticed, except for the ones Kubernetes defines
+//! for a Service named `petracache` or `petracache-*` (`PETRACACHE_PORT`,
+//! `PETRACACHE_SERVICE_HOST`, `PETRACACHE_PORT_11211_TCP_ADDR`, ...), which
+//! are ignored.
+
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, SeqAccess,
+    Visitor,
+};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+
+/// Prefix of every variable
+const PREFIX: &str = "PETRACACHE";
+
+/// Variables from before the nested names, and the ones they stand for
+const LEGACY_NAMES: &[(&str, &str)] = &[
+    ("PETRACACHE_LISTEN_ADDR", "PETRACACHE_SERVER_LISTEN_ADDR"),
+    (
+        "PETRACACHE_MAX_CONNECTIONS",
+        "PETRACACHE_SERVER_MAX_CONNECTIONS",
+    ),
+    (
+        "PETRACACHE_MAX_ITEM_SIZE",
+        "PETRACACHE_SERVER_MAX_ITEM_SIZE",
+    ),
+    ("PETRACACHE_DB_PATH", "PETRACACHE_STORAGE_DB_PATH"),
+    ("PETRACACHE_METRICS_ADDR", "PETRACACHE_METRICS_LISTEN_ADDR"),
+];
+
+/// Whether `name` is one of the variables Kubernetes sets for every
+/// Service in the namespace (`<SVC>_SERVICE_HOST`, `<SVC>_SERVICE_PORT[_<PORT
+/// NAME>]`, and Docker-link style `<SVC>_PORT[_<n>_<PROTO>[_PROTO|_PORT|_ADDR]]`
+/// holding `tcp://...`)
+fn is_service_link(name: &str, value: &str) -> bool {
+    if let Some((_, rest)) = name.rsplit_once("_SERVICE_") {
+        return rest == "HOST" || rest == "PORT" || rest.starts_with("PORT_");
+    }
+    let Some(at) = name.find("_PORT") else {
+        return false;
+    };
+    let rest = &name[at + "_PORT".len()..];
+    if rest.is_empty() {
+        return ["tcp://", "udp://", "sctp://"]
+            .iter()
+            .any(|scheme| value.starts_with(scheme));
+    }
+    let Some(rest) = rest.strip_prefix('_') else {
+        return false;
+    };
+    let mut parts = rest.split('_');
+    parts
+        .next()
+        .is_some_and(|port| !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()))
+        && parts
+            .next()
+            .is_some_and(|proto| ["TCP", "UDP", "SCTP"].contains(&proto))
+        && parts
+            .next()
+            .is_none_or(|field| ["PROTO", "PORT", "ADDR"].contains(&field))
+        && parts.next().is_none()
+}
+
+/// The `PETRACACHE_*` variables to apply, and which of them were used
+pub(super) struct EnvVars {
+    vars: BTreeMap<String, String>,
+    used: RefCell<BTreeSet<String>>,
+    /// Dotted path of every field of every section deserialized
+    #[cfg(test)]
+    fields: RefCell<BTreeSet<String>>,
+}
+
+impl EnvVars {
+    /// The `PETRACACHE_*` variables among `vars`
+    pub(super) fn new(vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        let prefix = format!("{PREFIX}_");
+        let mut vars: BTreeMap<String, String> = vars
+            .into_iter()
+            .filter(|(name, value)| name.starts_with(&prefix) && !is_service_link(name, value))
+            .collect();
+        for (legacy, name) in LEGACY_NAMES {
+            if let Some(value) = vars.remove(*legacy) {
+                vars.entry((*name).to_string()).or_insert(value);
+            }
+        }
+        Self {
+            vars,
+            used: RefCell::default(),
+            #[cfg(test)]
+            fields: RefCell::default(),
+        }
+    }
+
+    /// The process environment's `PETRACACHE_*` variables
+    pub(super) fn from_process() -> Self {
+        Self::new(std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
+
+    /// Deserialize `T` from `file`'s values (an empty file if `None`) with
+    /// the variables applied
+    pub(super) fn apply<T: DeserializeOwned>(
+        &self,
+        file: Option<toml::Value>,
+    ) -> Result<T, String> {
+        let source = file.map_or(Source::Missing, Source::File);
+        let value = T::deserialize(Layered {
+            env: self,
+            path: String::new(),
+            source,
+        })
+        .map_err(|e| e.0)?;
+
+        let used = self.used.borrow();
+        let unknown: Vec<&str> = self
+            .vars
+            .keys()
+            .filter(|name| !used.contains(*name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!(
+                "unknown environment variables: {}",
+                unknown.join(", ")
+            ));
+        }
+        Ok(value)
+    }
+
+    /// The value of the variable for `path`, marked used
+    fn take(&self, path: &str) -> Option<String> {
+        let name = var_name(path);
+        let value = self.vars.get(&name)?.clone();
+        self.used.borrow_mut().insert(name);
+        Some(value)
+    }
+
+    /// Fields of `fields` set through variables below section `path`; each
+    /// variable counts for the longest field name it starts with, so
+    /// `STORAGE_COMPACTION_SCHEDULE_*` goes to `compaction_schedule`, not
+    /// `compaction`
+    fn fields_below(&self, path: &str, fields: &[&'static str]) -> BTreeSet<&'static str> {
+        let prefix = format!("{}_", var_name(path));
+        let upper: Vec<(&'static str, String)> = fields
+            .iter()
+            .map(|field| (*field, field.to_uppercase()))
+            .collect();
+        self.vars
+            .range(prefix.clone()..)
+            .map(|(name, _)| name)
+            .take_while(|name| name.starts_with(&prefix))
+            .filter_map(|name| {
+                let rest = &name[prefix.len()..];
+                upper
+                    .iter()
+                    .filter(|(_, field)| {
+                        rest.strip_prefix(field.as_str())
+                            .is_some_and(|tail| tail.is_empty() || tail.starts_with('_'))
+                    })
+                    .max_by_key(|(_, field)| field.len())
+                    .map(|(field, _)| *field)
+            })
+            .collect()
+    }
+
+    /// Every field deserialized so far, as a dotted path
+    #[cfg(test)]
+    pub(super) fn fields(&self) -> BTreeSet<String> {
+        self.fields.borrow().clone()
+    }
+}
+
+/// `PETRACACHE_` followed by `path` in upper case, dots as underscores
+fn var_name(path: &str) -> String {
+    let mut name = PREFIX.to_string();
+    for segment in path.split('.').filter(|segment| !segment.is_empty()) {
+        name.push('_');
+        name.push_str(&segment.to_uppercase());
+    }
+    name
+}
+
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{path}.{field}")
+    }
+}
+
+/// A configuration error, with the setting it is about
+#[derive(Debug)]
+pub(super) struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// Where a value comes from
+enum Source {
+    /// An environment variable (or one item of its list)
+    Var(String),
+    File(toml::Value),
+    /// Neither: a section only set through its fields' variables
+    Missing,
+}
+
+/// A value of the configuration at `path`, read from its variable, the
+/// file or, for a section, both
+struct Layered<'a> {
+    env: &'a EnvVars,
+    /// Dotted path, e.g. `server.max_connections`
+    path: String,
+    source: Source,
+}
+
+impl Layered<'_> {
+    fn file_error(&self, e: &toml::de::Error) -> Error {
+        Error(format!("{}: {}", self.path, e.message()))
+    }
+
+    /// A value only reached through variables below it, which it
+    /// doesn't have
+    fn missing(&self) -> Error {
+        Error(format!(
+            "{}_*: {} is not a section",
+            var_name(&self.path),
+            self.path
+        ))
+    }
+
+    fn parse<T: FromStr>(&self, value: &str) -> Result<T, Error>
+    where
+        T::Err: fmt::Display,
+    {
+        value
+            .trim()
+            .parse()
+            .map_err(|e| Error(format!("{}={value:?}: {e}", var_name(&self.path))))
+    }
+
+    fn parse_bool(&self, value: &str) -> Result<bool, Error> {
+        match value.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" | "on" => Ok(true),
+            "false" | "0" | "no" | "off" => Ok(false),
+            _ => Err(Error(format!(
+                "{}={value:?}: expected true or false",
+                var_name(&self.path)
+            ))),
+        }
+    }
+}
+
+/// Leaf types: a variable is parsed, a file value deserialized as usual
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            match &self.source {
+                Source::Var(value) => visitor.$visit(self.parse(value)?),
+                Source::File(value) => value
+                    .clone()
+                    .$method(visitor)
+                    .map_err(|e| self.file_error(&e)),
+                Source::Missing => Err(self.missing()),
+            }
+        }
+    )*};
+}
+
+impl<'de> Deserializer<'de> for Layered<'_> {
+    type Error = Error;
+
+    deserialize_parsed! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.source {
+            Source::Var(value) => visitor.visit_bool(self.parse_bool(value)?),
+            Source::File(value) => value
+                .clone()
+                .deserialize_bool(visitor)
+                .map_err(|e| self.file_error(&e)),
+            Source::Missing => Err(self.missing()),
+        }
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.source {
+            // Strings with their own syntax: sizes, durations, networks
+            Source::Var(value) => visitor
+                .visit_str::<Error>(&value)
+                .map_err(|e| Error(format!("{}={value:?}: {}", var_name(&self.path), e.0))),
+            Source::File(ref value) => value
+                .clone()
+                .deserialize_any(visitor)
+                .map_err(|e| self.file_error(&e)),
+            Source::Missing => Err(self.missing()),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match &self.source {
+            Source::Var(value) if value.is_empty() => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.source {
+            Source::Var(ref value) => {
+                let items: Vec<String> = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from)
+                    .collect();
+                visitor.visit_seq(List {
+                    env: self.env,
+                    path: &self.path,
+                    items: items.into_iter(),
+                })
+            }
+            Source::File(ref value) => value
+                .clone()
+                .deserialize_seq(visitor)
+                .map_err(|e| self.file_error(&e)),
+            Source::Missing => Err(self.missing()),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.source {
+            Source::Var(value) => visitor.visit_enum(value.trim().into_deserializer()),
+            Source::File(ref value) => value
+                .clone()
+                .deserialize_enum(name, variants, visitor)
+                .map_err(|e| self.file_error(&e)),
+            Source::Missing => Err(self.missing()),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let table = match self.source {
+            Source::File(toml::Value::Table(table)) => table,
+            Source::Missing => toml::Table::new(),
+            Source::File(ref value) => {
+                return value
+                    .clone()
+                    .deserialize_struct(name, fields, visitor)
+                    .map_err(|e| self.file_error(&e));
+            }
+            Source::Var(_) => {
+                return Err(Error(format!(
+                    "{}: {} is a section, set its fields instead",
+                    var_name(&self.path),
+                    self.path
+                )));
+            }
+        };
+        #[cfg(test)]
+        self.env
+            .fields
+            .borrow_mut()
+            .extend(fields.iter().map(|field| join(&self.path, field)));
+
+        // File keys that aren't fields are passed on, for serde to ignore
+        let from_env = self.env.fields_below(&self.path, fields);
+        let mut entries: Vec<(String, Source)> = Vec::new();
+        for (key, value) in table {
+            entries.push((key, Source::File(value)));
+        }
+        for field in from_env {
+            if !entries.iter().any(|(key, _)| key == field) {
+                entries.push((field.to_string(), Source::Missing));
+            }
+        }
+        for (key, source) in &mut entries {
+            if let Some(value) = self.env.take(&join(&self.path, key)) {
+                *source = Source::Var(value);
+            }
+        }
+        visitor.visit_map(Fields {
+            env: self.env,
+            path: &self.path,
+            entries: entries.into_iter(),
+            next: None,
+        })
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        char str string bytes byte_buf unit unit_struct tuple tuple_struct map identifier
+    }
+}
+
+/// The fields of a section
+struct Fields<'a> {
+    env: &'a EnvVars,
+    path: &'a str,
+    entries: std::vec::IntoIter<(String, Source)>,
+    /// The entry whose key was just returned
+    next: Option<(String, Source)>,
+}
+
+impl<'de> MapAccess<'de> for Fields<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((key, source)) = self.entries.next() else {
+            return Ok(None);
+        };
+        let deserializer: de::value::StrDeserializer<'_, Error> = key.as_str().into_deserializer();
+        let key_value = seed.deserialize(deserializer)?;
+        self.next = Some((key, source));
+        Ok(Some(key_value))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, source) = self
+            .next
+            .take()
+            .ok_or_else(|| Error("value requested before its key".to_string()))?;
+        seed.deserialize(Layered {
+            env: self.env,
+            path: join(self.path, &key),
+            source,
+        })
+    }
+}
+
+/// The items of a comma-separated list variable
+struct List<'a> {
+    env: &'a EnvVars,
+    path: &'a str,
+    items: std::vec::IntoIter<String>,
+}
+
+impl<'de> SeqAccess<'de> for List<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        self.items
+            .next()
+            .map(|item| {
+                seed.deserialize(Layered {
+                    env: self.env,
+                    path: self.path.to_string(),
+                    source: Source::Var(item),
+                })
+            })
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    /// Every field: its path, a variable value and the same value in TOML;
+    /// none are defaults
+    const FIELDS: &[(&str, &str, &str)] = &[
+        ("server.listen_addr", "0.0.0.0:11311", r#""0.0.0.0:11311""#),
+        ("server.num_acceptors", "2", "2"),
+        (
+            "server.unix_socket_path",
+            "/run/pc.sock",
+            r#""/run/pc.sock""#,
+        ),
+        ("server.unix_socket_mode", "384", "384"),
+        ("server.max_connections", "5", "5"),
+        ("server.max_connections_per_ip", "6", "6"),
+        (
+            "server.allowed_cidrs",
+            "10.0.0.0/8, 192.168.1.7",
+            r#"["10.0.0.0/8", "192.168.1.7"]"#,
+        ),
+        ("server.read_buffer_size", "4096", "4096"),
+        ("server.write_buffer_size", "4097", "4097"),
+        ("server.buffer_shrink_factor", "8", "8"),
+        ("server.zero_copy_min_value_size", "1", "1"),
+        ("server.worker_threads", "3", "3"),
+        ("server.inline_storage", "false", "false"),
+        ("server.storage_threads", "7", "7"),
+        ("server.set_batch_size", "9", "9"),
+        ("server.connection_timeout_secs", "30", "30"),
+        ("server.shutdown_grace_secs", "11", "11"),
+        ("server.stats_sample_limit", "12", "12"),
+        ("server.max_item_size", "2048", "2048"),
+        ("server.max_get_keys", "13", "13"),
+        ("server.max_command_line_bytes", "4096", "4096"),
+        ("server.strict_ascii_keys", "TRUE", "true"),
+        ("server.accept_bare_lf", "1", "true"),
+        ("server.max_protocol_errors", "14", "14"),
+        (
+            "server.disabled_commands",
+            "flush_all,set",
+            r#"["flush_all", "set"]"#,
+        ),
+        ("server.read_only", "on", "true"),
+        ("server.proxy_protocol", "yes", "true"),
+        ("server.slow_log_threshold_ms", "15", "15"),
+        ("server.slow_log_hash_keys", "true", "true"),
+        ("server.slow_log_max_per_sec", "16", "16"),
+        (
+            "server.tls.cert_path",
+            "/etc/pc/cert.pem",
+            r#""/etc/pc/cert.pem""#,
+        ),
+        (
+            "server.tls.key_path",
+            "/etc/pc/key.pem",
+            r#""/etc/pc/key.pem""#,
+        ),
+        (
+            "server.tls.client_ca_path",
+            "/etc/pc/ca.pem",
+            r#""/etc/pc/ca.pem""#,
+        ),
+        ("server.auth.username", "app", r#""app""#),
+        // Numeric-looking, but a string field
+        ("server.auth.password", "12345", r#""12345""#),
+        ("storage.db_path", "/var/lib/pc", r#""/var/lib/pc""#),
+        (
+            "storage.db_paths",
+            "/mnt/nvme1=100GiB,/mnt/nvme2=1TB",
+            r#"[{ path = "/mnt/nvme1", target_size_bytes = "100GiB" }, { path = "/mnt/nvme2", target_size_bytes = 1000000000000 }]"#,
+        ),
+        ("storage.block_cache_size", "1024", "1024"),
+        ("storage.write_buffer_size", "2048", "2048"),
+        ("storage.max_write_buffer_number", "5", "5"),
+        ("storage.target_file_size_base", "4096", "4096"),
+        ("storage.max_background_jobs", "6", "6"),
+        ("storage.rate_limit_bytes_per_sec", "100MiB", "104857600"),
+        ("storage.compaction_readahead_size", "4MiB", "4194304"),
+        ("storage.max_subcompactions", "4", "4"),
+        ("storage.low_priority_compaction_io", "true", "true"),
+        ("storage.enable_compression", "true", "true"),
+        ("storage.compression", "zstd", r#""zstd""#),
+        ("storage.bottommost_compression", "lz4", r#""lz4""#),
+        ("storage.zstd_level", "6", "6"),
+        ("storage.block_size", "4KiB", "4096"),
+        ("storage.bloom_bits_per_key", "0", "0"),
+        ("storage.cache_index_and_filter_blocks", "false", "false"),
+        ("storage.index_type", "partitioned", r#""partitioned""#),
+        ("storage.partition_filters", "true", "true"),
+        ("storage.format_version", "5", "5"),
+        ("storage.enable_ttl_compaction", "false", "false"),
+        ("storage.enable_statistics", "true", "true"),
+        ("storage.flush_on_shutdown", "false", "false"),
+        ("storage.wal", "enabled_sync", r#""enabled_sync""#),
+        ("storage.wal_dir", "/var/lib/pc-wal", r#""/var/lib/pc-wal""#),
+        ("storage.max_total_wal_size", "1GiB", "1073741824"),
+        ("storage.rocksdb_log_level", "info", r#""info""#),
+        ("storage.rocksdb_max_log_file_size", "1000", "1000"),
+        ("storage.rocksdb_keep_log_file_num", "2", "2"),
+        ("storage.hot_cache_size_bytes", "1000", "1000"),
+        ("storage.negative_cache_size", "100", "100"),
+        ("storage.negative_cache_ttl_secs", "9", "9"),
+        ("storage.expiry_scan_interval_ms", "500", "500"),
+        ("storage.expiry_scan_batch_keys", "50", "50"),
+        ("storage.expiry_scan_budget_ms", "5", "5"),
+        ("storage.expired_log_sample_rate", "10", "10"),
+        ("storage.expired_log_keys", "true", "true"),
+        ("storage.expired_log_interval_secs", "30", "30"),
+        ("storage.default_ttl_secs", "1h", "3600"),
+        ("storage.max_ttl_secs", "86400", "86400"),
+        ("storage.ttl_jitter_percent", "10", "10"),
+        ("storage.compaction_schedule.interval_secs", "3600", "3600"),
+        (
+            "storage.compaction_schedule.window",
+            "01:00-04:00",
+            r#""01:00-04:00""#,
+        ),
+        (
+            "storage.restore_from",
+            "/backups/latest",
+            r#""/backups/latest""#,
+        ),
+        ("storage.restore_overwrite", "true", "true"),
+        ("storage.on_corruption", "recreate", r#""recreate""#),
+        ("storage.mode", "secondary", r#""secondary""#),
+        ("storage.primary_path", "/primary", r#""/primary""#),
+        ("storage.catch_up_interval_ms", "200", "200"),
+        ("storage.catch_up_max_failures", "4", "4"),
+        ("storage.ingest_dir", "/ingest", r#""/ingest""#),
+        ("storage.max_db_size_bytes", "1000000", "1000000"),
+        ("storage.on_full", "evict", r#""evict""#),
+        ("storage.disk_check_interval_ms", "2000", "2000"),
+        ("storage.health_check_interval_ms", "0", "0"),
+        ("storage.low_water_percent", "80", "80"),
+        ("storage.compaction", "fifo", r#""fifo""#),
+        ("storage.compress_values_over_bytes", "512", "512"),
+        ("storage.verify_checksums", "true", "true"),
+        (
+            "storage.namespaces",
+            "users,sessions",
+            r#"["users", "sessions"]"#,
+        ),
+        ("storage.shards", "4", "4"),
+        ("metrics.enabled", "false", "false"),
+        ("metrics.listen_addr", "0.0.0.0:9191", r#""0.0.0.0:9191""#),
+        ("metrics.readiness_write_check", "true", "true"),
+        ("metrics.readiness_max_latency_ms", "50", "50"),
+        ("metrics.readiness_cache_ms", "0", "0"),
+        ("metrics.instance", "node-1", r#""node-1""#),
+        ("metrics.key_size_buckets", "16,64", "[16.0, 64.0]"),
+        (
+            "metrics.value_size_buckets",
+            "1024, 65536.5",
+            "[1024.0, 65536.5]",
+        ),
+        ("metrics.observe_hit_sizes", "true", "true"),
+        ("metrics.hit_ratio_window_secs", "30", "30"),
+        ("backup.dir", "/backups", r#""/backups""#),
+        ("backup.interval_secs", "3600", "3600"),
+        ("backup.keep", "3", "3"),
+        ("backup.checkpoint_dir", "/checkpoints", r#""/checkpoints""#),
+        ("upstream.addr", "10.0.0.2:11211", r#""10.0.0.2:11211""#),
+        ("upstream.timeout_ms", "50", "50"),
+        ("upstream.max_concurrent_fetches", "8", "8"),
+        ("upstream.ttl_secs", "60", "60"),
+        (
+            "audit.path",
+            "/var/log/audit.jsonl",
+            r#""/var/log/audit.jsonl""#,
+        ),
+        ("audit.hash_keys", "true", "true"),
+        ("audit.max_file_bytes", "1000", "1000"),
+        ("audit.max_files", "2", "2"),
+        ("audit.queue_size", "100", "100"),
+        ("log.format", "json", r#""json""#),
+        ("log.level", "debug", r#""debug""#),
+    ];
+
+    fn env(vars: &[(&str, &str)]) -> EnvVars {
+        EnvVars::new(
+            vars.iter()
+                .map(|(name, value)| ((*name).to_string(), (*value).to_string())),
+        )
+    }
+
+    /// A TOML document setting `path` to `value` for every entry
+    fn file(entries: &[(&str, &str)]) -> toml::Value {
+        let mut document = toml::Table::new();
+        for (path, value) in entries {
+            let (sections, field) = path.rsplit_once('.').unwrap();
+            let mut table = &mut document;
+            for section in sections.split('.') {
+                table = table
+                    .entry(section)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .unwrap();
+            }
+            let value: toml::Table = toml::from_str(&format!("v = {value}")).unwrap();
+            table.insert(field.to_string(), value["v"].clone());
+        }
+        toml::Value::Table(document)
+    }
+
+    #[test]
+    fn test_every_field_has_a_variable() {
+        let vars: Vec<(String, &str)> = FIELDS
+            .iter()
+            .map(|(path, value, _)| (var_name(path), *value))
+            .collect();
+        let vars: Vec<(&str, &str)> = vars.iter().map(|(n, v)| (n.as_str(), *v)).collect();
+        let env = env(&vars);
+        let from_env = Config::layered(None, &env).unwrap();
+
+        let toml: Vec<(&str, &str)> = FIELDS
+            .iter()
+            .map(|(path, _, toml)| (*path, *toml))
+            .collect();
+        let from_file = Config::layered(Some(file(&toml)), &EnvVars::new([])).unwrap();
+        assert_eq!(format!("{from_env:?}"), format!("{from_file:?}"));
+        assert_eq!(from_env.server.auth.unwrap().password, "12345");
+
+        // A field added without an entry above fails here
+        for field in env.fields() {
+            assert!(
+                FIELDS
+                    .iter()
+                    .any(|(path, _, _)| *path == field || path.starts_with(&format!("{field}."))),
+                "no test entry for {field} ({})",
+                var_name(&field)
+            );
+        }
+        assert!(env.fields().contains("storage.compaction_schedule.window"));
+        assert!(
+            FIELDS
+                .iter()
+                .all(|(path, value, _)| !is_service_link(&var_name(path), value)),
+            "a field's variable would be ignored as a Service link"
+        );
+    }
+
+    #[test]
+    fn test_kubernetes_service_links_are_ignored() {
+        // Services `petracache` and `petracache-metrics` in the namespace
+        let vars = [
+            ("PETRACACHE_SERVICE_HOST", "10.0.0.11"),
+            ("PETRACACHE_SERVICE_PORT", "11211"),
+            ("PETRACACHE_SERVICE_PORT_MEMCACHE", "11211"),
+            ("PETRACACHE_PORT", "tcp://10.0.0.11:11211"),
+            ("PETRACACHE_PORT_11211_TCP", "tcp://10.0.0.11:11211"),
+            ("PETRACACHE_PORT_11211_TCP_PROTO", "tcp"),
+            ("PETRACACHE_PORT_11211_TCP_PORT", "11211"),
+            ("PETRACACHE_PORT_11211_TCP_ADDR", "10.0.0.11"),
+            ("PETRACACHE_METRICS_SERVICE_HOST", "10.0.0.12"),
+            ("PETRACACHE_METRICS_PORT", "tcp://10.0.0.12:9090"),
+            ("PETRACACHE_METRICS_PORT_9090_TCP_ADDR", "10.0.0.12"),
+            ("PETRACACHE_SERVER_MAX_CONNECTIONS", "5"),
+        ];
+        let config = Config::layered(None, &env(&vars)).unwrap();
+        assert_eq!(config.server.max_connections, 5);
+
+        // Other names are still typos
+        for (name, value) in [
+            ("PETRACACHE_PORT", "11211"),
+            ("PETRACACHE_PORT_11211", "tcp://10.0.0.11:11211"),
+            ("PETRACACHE_PORT_11211_TCP_HOST", "10.0.0.11"),
+            ("PETRACACHE_PORTS_11211_TCP", "tcp://10.0.0.11:11211"),
+            ("PETRACACHE_SERVER_SERVICE", "x"),
+        ] {
+            assert!(
+                Config::layered(None, &env(&[(name, value)])).is_err(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_variables_override_file() {
+        let file = file(&[
+            ("server.max_connections", "5"),
+            ("server.max_item_size", r#""2KiB""#),
+            ("storage.compaction_schedule.interval_secs", r#""1m""#),
+            ("metrics.instance", r#""node-1""#),
+        ]);
+        let env = env(&[
+            ("PETRACACHE_SERVER_MAX_CONNECTIONS", "6"),
+            (
+                "PETRACACHE_STORAGE_COMPACTION_SCHEDULE_WINDOW",
+                "01:00-02:00",
+            ),
+            ("PETRACACHE_STORAGE_COMPACTION", "fifo"),
+            ("PETRACACHE_METRICS_INSTANCE", ""),
+            ("PETRACACHE_STORAGE_BLOCK_CACHE_SIZE", "512MiB"),
+            ("PETRACACHE_SERVER_CONNECTION_TIMEOUT_SECS", "90"),
+        ]);
+        let config = Config::layered(Some(file), &env).unwrap();
+        assert_eq!(config.server.max_connections, 6);
+        assert_eq!(config.server.max_item_size, 2048);
+        assert_eq!(config.storage.block_cache_size, 512 << 20);
+        assert_eq!(config.server.connection_timeout_secs, 90);
+        let schedule = &config.storage.compaction_schedule;
+        assert_eq!(schedule.interval_secs, 60);
+        assert!(schedule.window.unwrap().contains(90));
+        assert_eq!(
+            config.storage.compaction,
+            super::super::CompactionStyle::Fifo
+        );
+        // Empty unsets an optional field
+        assert_eq!(config.metrics.instance, None);
+    }
+
+    #[test]
+    fn test_legacy_names() {
+        let env = env(&[
+            ("PETRACACHE_LISTEN_ADDR", "0.0.0.0:1"),
+            ("PETRACACHE_DB_PATH", "/data"),
+            ("PETRACACHE_METRICS_ADDR", "0.0.0.0:2"),
+            ("PETRACACHE_METRICS_ENABLED", "0"),
+            // The new name wins
+            ("PETRACACHE_MAX_CONNECTIONS", "5"),
+            ("PETRACACHE_SERVER_MAX_CONNECTIONS", "6"),
+        ]);
+        let config = Config::layered(None, &env).unwrap();
+        assert_eq!(config.server.listen_addr, "0.0.0.0:1");
+        assert_eq!(config.storage.db_path.to_str(), Some("/data"));
+        assert_eq!(config.metrics.listen_addr, "0.0.0.0:2");
+        assert!(!config.metrics.enabled);
+        assert_eq!(config.server.max_connections, 6);
+    }
+
+    #[test]
+    fn test_errors_name_the_variable() {
+        let error =
+            |vars: &[(&str, &str)]| Config::layered(None, &env(vars)).unwrap_err().to_string();
+        let message = error(&[("PETRACACHE_SERVER_MAX_CONECTIONS", "5")]);
+        assert!(
+            message.contains("unknown environment variables: PETRACACHE_SERVER_MAX_CONECTIONS"),
+            "{message}"
+        );
+        let message = error(&[("PETRACACHE_SERVER_MAX_CONNECTIONS", "many")]);
+        assert!(
+            message.contains(r#"PETRACACHE_SERVER_MAX_CONNECTIONS="many""#),
+            "{message}"
+        );
+        let message = error(&[("PETRACACHE_SERVER_READ_ONLY", "maybe")]);
+        assert!(message.contains("PETRACACHE_SERVER_READ_ONLY"), "{message}");
+        let message = error(&[("PETRACACHE_SERVER_ALLOWED_CIDRS", "10.0.0.0/33")]);
+        assert!(message.contains("invalid CIDR"), "{message}");
+        let message = error(&[("PETRACACHE_STORAGE_BLOCK_CACHE_SIZE", "1.5GBB")]);
+        assert!(
+            message.contains(r#"PETRACACHE_STORAGE_BLOCK_CACHE_SIZE="1.5GBB": invalid size"#),
+            "{message}"
+        );
+        let message = error(&[("PETRACACHE_SERVER_LISTEN_ADDR_PORT", "1")]);
+        assert!(
+            message.contains("PETRACACHE_SERVER_LISTEN_ADDR_*"),
+            "{message}"
+        );
+        let message = error(&[("PETRACACHE_SERVER", "x")]);
+        assert!(message.contains("is a section"), "{message}");
+    }
+}
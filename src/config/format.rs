@@ -0,0 +1,221 @@
+//! Config file formats: TOML, YAML and JSON
+//!
+//! The format follows the file extension (`.yaml`/`.yml`, `.json`, TOML
+//! for anything else). Every format deserializes into the same `Config`;
+//! for the environment layering in `env` the file is also turned into a
+//! TOML table, with YAML and JSON nulls dropped (unset, like an empty
+//! `PETRACACHE_*` variable).
+
+use serde::de::DeserializeOwned;
+use std::fmt;
+use std::path::Path;
+
+/// A config file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum FileFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl FileFormat {
+    /// The format of `path`, from its extension
+    pub(super) fn of(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("yaml" | "yml") => FileFormat::Yaml,
+            Some("json") => FileFormat::Json,
+            _ => FileFormat::Toml,
+        }
+    }
+
+    /// Parse `contents` as a `T`
+    pub(super) fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T, String> {
+        match self {
+            FileFormat::Toml => toml::from_str(contents).map_err(|e| e.to_string()),
+            FileFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string()),
+            FileFormat::Json => serde_json::from_str(contents).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Parse `contents` as a table of settings
+    pub(super) fn parse_table(self, contents: &str) -> Result<toml::Table, String> {
+        if self == FileFormat::Toml {
+            return self.parse(contents);
+        }
+        let mut value: serde_json::Value = self.parse(contents)?;
+        drop_nulls(&mut value);
+        match toml::Value::try_from(value).map_err(|e| e.to_string())? {
+            toml::Value::Table(table) => Ok(table),
+            _ => Err("expected a mapping of sections".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for FileFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FileFormat::Toml => "TOML",
+            FileFormat::Yaml => "YAML",
+            FileFormat::Json => "JSON",
+        })
+    }
+}
+
+/// Remove null fields, which TOML can't express, at any depth
+fn drop_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            fields.retain(|_, field| !field.is_null());
+            fields.values_mut().for_each(drop_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(drop_nulls),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::config::env::EnvVars;
+    use tempfile::TempDir;
+
+    const TOML: &str = r#"
+[server]
+listen_addr = "0.0.0.0:11211"
+max_connections = 500
+disabled_commands = ["flush_all"]
+allowed_cidrs = ["10.0.0.0/8"]
+
+[server.auth]
+username = "app"
+password = "secret"
+
+[storage]
+db_path = "/srv/petracache"
+block_cache_size = "2GiB"
+namespaces = ["users", { name = "sessions", max_bytes = 1048576 }]
+
+[storage.compaction_schedule]
+interval_secs = "1d"
+window = "02:00-05:00"
+
+[metrics]
+value_size_buckets = [64.0, 1024.0]
+
+[log]
+format = "json"
+"#;
+
+    const YAML: &str = r#"
+server:
+  listen_addr: "0.0.0.0:11211"
+  max_connections: 500
+  disabled_commands: [flush_all]
+  allowed_cidrs: ["10.0.0.0/8"]
+  auth:
+    username: app
+    password: secret
+storage:
+  db_path: /srv/petracache
+  block_cache_size: 2GiB
+  namespaces:
+    - users
+    - name: sessions
+      max_bytes: 1048576
+  compaction_schedule:
+    interval_secs: 1d
+    window: "02:00-05:00"
+metrics:
+  instance: ~
+  value_size_buckets: [64.0, 1024.0]
+log:
+  format: json
+"#;
+
+    const JSON: &str = r#"{
+  "server": {
+    "listen_addr": "0.0.0.0:11211",
+    "max_connections": 500,
+    "disabled_commands": ["flush_all"],
+    "allowed_cidrs": ["10.0.0.0/8"],
+    "auth": { "username": "app", "password": "secret" }
+  },
+  "storage": {
+    "db_path": "/srv/petracache",
+    "block_cache_size": "2GiB",
+    "namespaces": ["users", { "name": "sessions", "max_bytes": 1048576 }],
+    "compaction_schedule": { "interval_secs": "1d", "window": "02:00-05:00" }
+  },
+  "metrics": { "instance": null, "value_size_buckets": [64.0, 1024.0] },
+  "log": { "format": "json" }
+}"#;
+
+    fn write(dir: &TempDir, name: &str, contents: &str) -> std::path::PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_of() {
+        assert_eq!(FileFormat::of(Path::new("a.toml")), FileFormat::Toml);
+        assert_eq!(FileFormat::of(Path::new("a.yaml")), FileFormat::Yaml);
+        assert_eq!(FileFormat::of(Path::new("a.YML")), FileFormat::Yaml);
+        assert_eq!(FileFormat::of(Path::new("a.json")), FileFormat::Json);
+        assert_eq!(
+            FileFormat::of(Path::new("petracache.conf")),
+            FileFormat::Toml
+        );
+    }
+
+    #[test]
+    fn test_formats_load_the_same_config() {
+        let dir = TempDir::new().unwrap();
+        let env = EnvVars::new([]);
+        let load = |name: &str, contents: &str| {
+            Config::read_file(&write(&dir, name, contents), &env).unwrap()
+        };
+        let toml = load("petracache.toml", TOML);
+        assert_eq!(toml.server.max_connections, 500);
+        assert_eq!(toml.storage.block_cache_size, 2 << 30);
+        assert_eq!(toml.storage.namespaces[1].max_bytes, 1 << 20);
+        assert_eq!(load("petracache.yaml", YAML), toml);
+        assert_eq!(load("petracache.yml", YAML), toml);
+        assert_eq!(load("petracache.json", JSON), toml);
+
+        // Environment variables layer over every format
+        let env = EnvVars::new([(
+            "PETRACACHE_SERVER_MAX_CONNECTIONS".to_string(),
+            "7".to_string(),
+        )]);
+        let path = write(&dir, "override.yaml", YAML);
+        let config = Config::read_file(&path, &env).unwrap();
+        assert_eq!(config.server.max_connections, 7);
+    }
+
+    #[test]
+    fn test_errors_name_the_format() {
+        let dir = TempDir::new().unwrap();
+        let env = EnvVars::new([]);
+        for (name, contents, format) in [
+            ("bad.toml", "[server\n", "TOML"),
+            ("bad.yaml", "server: [1\n", "YAML"),
+            ("bad.json", "{\"server\": ", "JSON"),
+            ("wrong.yaml", "server:\n  max_connections: many\n", "YAML"),
+            ("list.json", "[1, 2]", "JSON"),
+        ] {
+            let error = Config::read_file(&write(&dir, name, contents), &env)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error.contains(&format!("Failed to parse config as {format}")),
+                "{name}: {error}"
+            );
+        }
+    }
+}
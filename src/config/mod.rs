@@ -0,0 +1,1370 @@
+//! Configuration for PetraCache
+
+mod cli;
+mod env;
+mod format;
+pub mod units;
+mod validate;
+
+pub use cli::Args;
+pub use validate::ConfigError;
+
+use crate::protocol::CommandSet;
+use env::EnvVars;
+use format::FileFormat;
+use serde::{Deserialize, Serialize, Serializer};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// Main configuration structure
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub metrics: MetricsConfig,
+    pub backup: BackupConfig,
+    pub upstream: UpstreamConfig,
+    pub audit: AuditConfig,
+    pub log: LogConfig,
+}
+
+/// Server configuration
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ServerConfig {
+    /// TCP address to listen on (empty = no TCP listener)
+    pub listen_addr: String,
+
+    /// Number of TCP accept loops; above 1 each gets its own socket bound
+    /// with `SO_REUSEPORT` (Unix only), for high connection rates
+    pub num_acceptors: usize,
+
+    /// Unix domain socket to listen on as well (Unix only); a stale socket
+    /// file is replaced on startup and removed on shutdown
+    pub unix_socket_path: Option<PathBuf>,
+
+    /// Permission bits of the Unix socket file
+    pub unix_socket_mode: u32,
+
+    /// Maximum number of concurrent connections
+    pub max_connections: usize,
+
+    /// Maximum concurrent connections from one client IP (0 = unlimited);
+    /// behind a load balancer this needs `proxy_protocol`
+    pub max_connections_per_ip: usize,
+
+    /// Only accept TCP clients from these networks (`"10.0.0.0/8"`, or a
+    /// bare address); empty = any. Checked against the PROXY header's
+    /// address with `proxy_protocol`
+    pub allowed_cidrs: Vec<IpNet>,
+
+    /// Read buffer size per connection (bytes)
+    #[serde(with = "units::size")]
+    pub read_buffer_size: usize,
+
+    /// Write buffer size per connection (bytes)
+    #[serde(with = "units::size")]
+    pub write_buffer_size: usize,
+
+    /// Shrink a connection's read or write buffer back to its configured
+    /// size once it is empty and has grown beyond this multiple of it
+    /// (0 = never shrink)
+    pub buffer_shrink_factor: usize,
+
+    /// Values at least this large are written straight from storage with
+    /// vectored writes instead of being copied into the write buffer
+    /// (0 = always copy)
+    #[serde(with = "units::size")]
+    pub zero_copy_min_value_size: usize,
+
+    /// Number of Tokio worker threads (0 = number of CPUs)
+    pub worker_threads: usize,
+
+    /// Run storage calls for ASCII, meta and binary commands on the async
+    /// workers instead of the blocking pool; cheaper when the data set fits
+    /// in memory and RocksDB never stalls. On by default until offloading
+    /// is measured to pay off on real RocksDB; turn it off when cold reads
+    /// or write stalls hold up other connections
+    pub inline_storage: bool,
+
+    /// Maximum threads in the blocking pool that runs storage calls
+    /// (0 = tokio's default)
+    pub storage_threads: usize,
+
+    /// Most consecutive pipelined `set` commands stored with one RocksDB
+    /// write batch (0 or 1 = one write per command)
+    pub set_batch_size: usize,
+
+    /// Close connections that send nothing for this many seconds (0 = no timeout)
+    #[serde(with = "units::secs")]
+    pub connection_timeout_secs: u64,
+
+    /// On shutdown, how long connections may finish their current command
+    /// before they are aborted
+    #[serde(with = "units::secs")]
+    pub shutdown_grace_secs: u64,
+
+    /// Maximum number of items scanned by `stats items` / `stats sizes`
+    pub stats_sample_limit: usize,
+
+    /// Maximum size of a stored value in bytes (larger sets are rejected)
+    #[serde(with = "units::size")]
+    pub max_item_size: usize,
+
+    /// Maximum number of keys in one get/gets/gat/gats
+    pub max_get_keys: usize,
+
+    /// Maximum command line length in bytes, terminated or not
+    #[serde(with = "units::size")]
+    pub max_command_line_bytes: usize,
+
+    /// Only accept printable ASCII keys (by default any byte except
+    /// whitespace, control characters and DEL is allowed, like memcached)
+    pub strict_ascii_keys: bool,
+
+    /// Accept a bare `\n` (without `\r`) as line terminator, for legacy
+    /// tools; off by default
+    pub accept_bare_lf: bool,
+
+    /// Close a connection after this many protocol errors in a row, with a
+    /// final `CLIENT_ERROR` (0 = never); any successful command resets the
+    /// count
+    pub max_protocol_errors: u32,
+
+    /// Commands answered with `SERVER_ERROR command disabled` instead of
+    /// being executed, by name (e.g. `["flush_all"]`); `stats`, `version`,
+    /// `quit` and `mn` can't be disabled
+    pub disabled_commands: CommandSet,
+
+    /// Start in read-only mode: commands that change stored data get
+    /// `SERVER_ERROR server is read-only`; flipped at runtime with
+    /// `POST /admin/readonly?enabled=true|false`
+    pub read_only: bool,
+
+    /// Require a PROXY protocol (v1 or v2) header at the start of every TCP
+    /// connection and use the client address it carries; connections
+    /// without a valid header are closed
+    pub proxy_protocol: bool,
+
+    /// Log commands that take longer than this many milliseconds to
+    /// execute, with their key, value size and hit or miss (0 = disabled)
+    #[serde(with = "units::millis")]
+    pub slow_log_threshold_ms: u64,
+
+    /// Log a hash of the key instead of its first bytes in the slow log
+    pub slow_log_hash_keys: bool,
+
+    /// Most slow log entries written per second; the rest are counted in
+    /// `petracache_slow_log_suppressed_total`
+    pub slow_log_max_per_sec: u32,
+
+    /// Serve TLS on the TCP listener (`[server.tls]`; the Unix socket stays
+    /// plaintext)
+    pub tls: Option<TlsConfig>,
+
+    /// Require memcached's ASCII authentication (`[server.auth]`): each
+    /// connection must first send `set <any> 0 0 <n>` with `username
+    /// password` as its data
+    pub auth: Option<AuthConfig>,
+}
+
+/// An IP network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(cidr: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid CIDR {cidr:?}, expected e.g. \"10.0.0.0/8\"");
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (cidr.as_str(), None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.trim().parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl From<IpNet> for String {
+    fn from(net: IpNet) -> Self {
+        net.to_string()
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Shared-secret credentials for ASCII authentication
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+pub struct AuthConfig {
+    pub username: String,
+    #[serde(serialize_with = "redacted")]
+    pub password: String,
+}
+
+/// Serialized in place of a secret (`--print-config`)
+fn redacted<S: Serializer>(_: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str("<redacted>")
+}
+
+// The configuration is logged at startup
+impl std::fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// TLS configuration for the memcached port
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain presented to clients
+    pub cert_path: PathBuf,
+
+    /// PEM private key for `cert_path`
+    pub key_path: PathBuf,
+
+    /// PEM CA bundle; when set, clients must present a certificate signed
+    /// by one of these CAs or the handshake fails
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "127.0.0.1:11211".to_string(),
+            num_acceptors: 1,
+            unix_socket_path: None,
+            unix_socket_mode: 0o700,
+            max_connections: 10000,
+            max_connections_per_ip: 0,
+            allowed_cidrs: Vec::new(),
+            read_buffer_size: 8192,
+            write_buffer_size: 8192,
+            buffer_shrink_factor: 4,
+            zero_copy_min_value_size: 16 * 1024,
+            worker_threads: 0,
+            inline_storage: true,
+            storage_threads: 0,
+            set_batch_size: 64,
+            connection_timeout_secs: 0,
+            shutdown_grace_secs: 10,
+            stats_sample_limit: 10_000,
+            max_item_size: 1024 * 1024, // 1MB, like memcached
+            max_get_keys: 1024,
+            max_command_line_bytes: 256 * 1024, // fits max_get_keys full-length keys
+            strict_ascii_keys: false,
+            accept_bare_lf: false,
+            max_protocol_errors: 20,
+            disabled_commands: CommandSet::default(),
+            read_only: false,
+            proxy_protocol: false,
+            slow_log_threshold_ms: 0,
+            slow_log_hash_keys: false,
+            slow_log_max_per_sec: 10,
+            tls: None,
+            auth: None,
+        }
+    }
+}
+
+/// Storage (RocksDB) configuration
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct StorageConfig {
+    /// Path to RocksDB data directory
+    pub db_path: PathBuf,
+
+    /// Directories for SST files, filled in order up to their target sizes
+    /// (empty = all in `db_path`, which keeps the manifest and the WAL
+    /// either way)
+    pub db_paths: Vec<DataPath>,
+
+    /// Block cache size in bytes (1GB default for in-memory performance)
+    #[serde(with = "units::size")]
+    pub block_cache_size: usize,
+
+    /// Write buffer size in bytes
+    #[serde(with = "units::size")]
+    pub write_buffer_size: usize,
+
+    /// Maximum number of write buffers
+    pub max_write_buffer_number: i32,
+
+    /// Target file size for level-1 in bytes
+    #[serde(with = "units::size")]
+    pub target_file_size_base: u64,
+
+    /// Maximum number of background jobs
+    pub max_background_jobs: i32,
+
+    /// Cap on flush and compaction writes, in bytes per second and shared
+    /// evenly by the shards, so they leave disk bandwidth to gets (0 =
+    /// unlimited)
+    #[serde(with = "units::size")]
+    pub rate_limit_bytes_per_sec: u64,
+
+    /// Readahead for compaction input files (0 = none)
+    #[serde(with = "units::size")]
+    pub compaction_readahead_size: usize,
+
+    /// Threads one compaction job may be split across
+    pub max_subcompactions: u32,
+
+    /// Lower the I/O priority of compaction threads below that of client
+    /// requests (Linux only)
+    pub low_priority_compaction_io: bool,
+
+    /// Deprecated: `true` means `compression = "lz4"`; ignored when
+    /// `compression` is set
+    pub enable_compression: bool,
+
+    /// SST block compression on every level but the bottommost (unset =
+    /// from `enable_compression`)
+    pub compression: Option<Compression>,
+
+    /// SST block compression on the bottommost level, where most (and the
+    /// coldest) data lives (unset = same as `compression`)
+    pub bottommost_compression: Option<Compression>,
+
+    /// zstd compression level, for whichever of `compression` and
+    /// `bottommost_compression` is zstd (unset = RocksDB's default)
+    pub zstd_level: Option<i32>,
+
+    /// Size of an SST data block, the unit the block cache holds; smaller
+    /// blocks waste less cache on small values but need a larger index
+    #[serde(with = "units::size")]
+    pub block_size: usize,
+
+    /// Bloom filter bits per key, which let a get skip SST files without
+    /// the key (0 = no filter)
+    pub bloom_bits_per_key: u32,
+
+    /// Keep index and filter blocks in the block cache, bounded by
+    /// `block_cache_size`, instead of in unaccounted table reader memory
+    pub cache_index_and_filter_blocks: bool,
+
+    /// SST index layout; `partitioned` only loads the index partitions a
+    /// lookup needs
+    pub index_type: IndexType,
+
+    /// Split bloom filters like a partitioned index (needs `index_type =
+    /// "partitioned"`)
+    pub partition_filters: bool,
+
+    /// Block-based table format version of new SST files (unset =
+    /// RocksDB's default)
+    pub format_version: Option<i32>,
+
+    /// Enable TTL compaction filter (runs during RocksDB compaction)
+    pub enable_ttl_compaction: bool,
+
+    /// Collect RocksDB statistics (tickers such as stall time and block
+    /// cache hits, latency histograms) and export them on /metrics; costs
+    /// some CPU on every operation
+    pub enable_statistics: bool,
+
+    /// Flush memtables on clean shutdown when the WAL is disabled (unflushed
+    /// data is otherwise lost on restart)
+    pub flush_on_shutdown: bool,
+
+    /// Write-ahead log: `disabled` loses unflushed writes on a crash,
+    /// `enabled` survives a process crash, `enabled_sync` also fsyncs every
+    /// write to survive power loss
+    pub wal: Wal,
+
+    /// Directory of the WAL files (unset = `db_path`); each shard gets a
+    /// `shard-N` subdirectory
+    pub wal_dir: Option<PathBuf>,
+
+    /// Flush memtables once the WAL files exceed this many bytes (0 =
+    /// RocksDB's default)
+    #[serde(with = "units::size")]
+    pub max_total_wal_size: u64,
+
+    /// RocksDB log level: debug, info, warn, error, fatal, header
+    pub rocksdb_log_level: String,
+
+    /// Maximum RocksDB log file size in bytes (0 = unlimited)
+    #[serde(with = "units::size")]
+    pub rocksdb_max_log_file_size: usize,
+
+    /// Number of RocksDB log files to keep
+    pub rocksdb_keep_log_file_num: usize,
+
+    /// Bytes of recently read values kept decoded in memory in front of
+    /// RocksDB (0 = disabled)
+    #[serde(with = "units::size")]
+    pub hot_cache_size_bytes: usize,
+
+    /// Keys remembered as recent misses, answered without a RocksDB lookup
+    /// until written or `negative_cache_ttl_secs` pass (0 = disabled)
+    pub negative_cache_size: usize,
+
+    /// How long a remembered miss is trusted
+    #[serde(with = "units::secs")]
+    pub negative_cache_ttl_secs: u64,
+
+    /// Run the background expiry scan every this many milliseconds, deleting
+    /// expired items nobody reads again (0 = disabled)
+    #[serde(with = "units::millis")]
+    pub expiry_scan_interval_ms: u64,
+
+    /// Most keys the expiry scan inspects per tick
+    pub expiry_scan_batch_keys: usize,
+
+    /// Most time the expiry scan spends per tick, in milliseconds
+    #[serde(with = "units::millis")]
+    pub expiry_scan_budget_ms: u64,
+
+    /// Log one in this many keys removed by lazy expiration at `debug`
+    /// (0 = none); every removal is logged at `trace`
+    pub expired_log_sample_rate: u64,
+
+    /// Include the key in those log entries
+    pub expired_log_keys: bool,
+
+    /// Log how many expired keys were removed every this many seconds
+    /// (0 = never)
+    #[serde(with = "units::secs")]
+    pub expired_log_interval_secs: u64,
+
+    /// TTL in seconds for items stored with exptime 0 (0 = they never
+    /// expire, as in memcached)
+    #[serde(with = "units::secs")]
+    pub default_ttl_secs: u64,
+
+    /// Longest TTL in seconds a client may set; longer ones are cut to it
+    /// (0 = no limit)
+    #[serde(with = "units::secs")]
+    pub max_ttl_secs: u64,
+
+    /// Move relative TTLs by a random offset of up to this percentage
+    /// either way, so keys written together don't all expire together
+    /// (0 = exact TTLs)
+    pub ttl_jitter_percent: u32,
+
+    /// Periodic full compaction (`[storage.compaction_schedule]`)
+    pub compaction_schedule: CompactionScheduleConfig,
+
+    /// Backup directory to restore `db_path` from at startup, before the
+    /// listeners bind; skipped if `db_path` already holds data
+    pub restore_from: Option<PathBuf>,
+
+    /// Restore even over a non-empty `db_path`, replacing its data (on
+    /// every start while set)
+    pub restore_overwrite: bool,
+
+    /// What to do when RocksDB reports the database corrupt at startup:
+    /// `fail`, `repair` it, or `recreate` it empty after moving the broken
+    /// files aside
+    pub on_corruption: OnCorruption,
+
+    /// `primary` owns the database; `secondary` opens `primary_path`
+    /// read-only and follows it, rejecting writes
+    pub mode: StorageMode,
+
+    /// Database a secondary follows (required in secondary mode); the
+    /// secondary keeps its own metadata and logs in `db_path`
+    pub primary_path: Option<PathBuf>,
+
+    /// How often a secondary catches up with the primary, in milliseconds
+    #[serde(with = "units::millis")]
+    pub catch_up_interval_ms: u64,
+
+    /// Consecutive failed catch-ups before a secondary reports not ready
+    pub catch_up_max_failures: u32,
+
+    /// `POST /admin/ingest` may only ingest SST files from under this
+    /// directory (unset = endpoint disabled)
+    pub ingest_dir: Option<PathBuf>,
+
+    /// Cap on SST, WAL and memtable bytes (0 = unlimited)
+    #[serde(with = "units::size")]
+    pub max_db_size_bytes: u64,
+
+    /// What to do once `max_db_size_bytes` is reached
+    pub on_full: OnFull,
+
+    /// How often the database size is sampled, in milliseconds
+    #[serde(with = "units::millis")]
+    pub disk_check_interval_ms: u64,
+
+    /// How often RocksDB is checked for background errors and a canary
+    /// write, in milliseconds (0 = disabled); a failed check reports the
+    /// node not ready
+    #[serde(with = "units::millis")]
+    pub health_check_interval_ms: u64,
+
+    /// Low-water mark, in percent of `max_db_size_bytes`: eviction frees
+    /// space down to it, and rejected writes resume below it
+    pub low_water_percent: u64,
+
+    /// RocksDB compaction style; `fifo` bounds SST files to
+    /// `max_db_size_bytes` by dropping the oldest
+    pub compaction: CompactionStyle,
+
+    /// LZ4-compress values with more data bytes than this before storing
+    /// them (0 = disabled); independent of `compression`
+    #[serde(with = "units::size")]
+    pub compress_values_over_bytes: usize,
+
+    /// Store a CRC32C with every value written; values that carry one are
+    /// verified on every read
+    pub verify_checksums: bool,
+
+    /// Key-prefix namespaces, each stored in a column family of its own:
+    /// keys `<name>:<rest>` go to namespace `name` (see `RocksStorage`)
+    pub namespaces: Vec<NamespaceConfig>,
+
+    /// Independent RocksDB instances keys are spread over by hash, each in
+    /// `db_path/shard-{i}` when above 1; can't change once data is written
+    pub shards: usize,
+}
+
+/// One entry of `storage.namespaces`: a bare name, or a table with a quota
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "NamespaceEntry")]
+pub struct NamespaceConfig {
+    pub name: String,
+    /// Sets into the namespace fail once its column family holds this many
+    /// bytes, until it drops below 95% of it (0 = unlimited)
+    #[serde(serialize_with = "units::serialize_size")]
+    pub max_bytes: u64,
+}
+
+impl NamespaceConfig {
+    /// A namespace without a quota
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            max_bytes: 0,
+        }
+    }
+}
+
+/// One entry of `storage.db_paths`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "DataPathEntry")]
+pub struct DataPath {
+    pub path: PathBuf,
+    /// New SST files go to the next path once this one holds this many
+    /// bytes (the last path takes the rest); shared evenly by the shards
+    #[serde(serialize_with = "units::serialize_size")]
+    pub target_size_bytes: u64,
+}
+
+/// The forms a `storage.db_paths` entry may take: a table, or
+/// `"<path>=<size>"` (as in `PETRACACHE_STORAGE_DB_PATHS`)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DataPathEntry {
+    Spec(String),
+    Table {
+        path: PathBuf,
+        #[serde(deserialize_with = "units::deserialize_size")]
+        target_size_bytes: u64,
+    },
+}
+
+impl TryFrom<DataPathEntry> for DataPath {
+    type Error = String;
+
+    fn try_from(entry: DataPathEntry) -> Result<Self, String> {
+        match entry {
+            DataPathEntry::Spec(spec) => {
+                let (path, size) = spec
+                    .rsplit_once('=')
+                    .ok_or_else(|| format!("expected <path>=<size>, got {spec:?}"))?;
+                Ok(Self {
+                    path: PathBuf::from(path),
+                    target_size_bytes: units::parse_size(size)?,
+                })
+            }
+            DataPathEntry::Table {
+                path,
+                target_size_bytes,
+            } => Ok(Self {
+                path,
+                target_size_bytes,
+            }),
+        }
+    }
+}
+
+/// The forms a `storage.namespaces` entry may take in TOML
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NamespaceEntry {
+    Name(String),
+    Table {
+        name: String,
+        #[serde(default, deserialize_with = "units::deserialize_size")]
+        max_bytes: u64,
+    },
+}
+
+impl From<NamespaceEntry> for NamespaceConfig {
+    fn from(entry: NamespaceEntry) -> Self {
+        match entry {
+            NamespaceEntry::Name(name) => Self::new(name),
+            NamespaceEntry::Table { name, max_bytes } => Self { name, max_bytes },
+        }
+    }
+}
+
+/// How RocksDB compacts, and so what bounds the data size
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionStyle {
+    /// Leveled compaction: data is kept until deleted or expired
+    #[default]
+    Level,
+    /// FIFO compaction: the oldest SST files are dropped whole once all
+    /// files exceed `max_db_size_bytes`, and are otherwise never rewritten
+    Fifo,
+}
+
+impl CompactionStyle {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Level => "level",
+            Self::Fifo => "fifo",
+        }
+    }
+}
+
+/// An SST block compression algorithm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Snappy,
+    Zstd,
+}
+
+impl Compression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Lz4 => "lz4",
+            Self::Snappy => "snappy",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Index layout of block-based SST files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexType {
+    /// One index block per file, loaded whole
+    #[default]
+    BinarySearch,
+    /// A two-level index: a small top level over index partitions
+    Partitioned,
+}
+
+impl IndexType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::BinarySearch => "binary_search",
+            Self::Partitioned => "partitioned",
+        }
+    }
+}
+
+/// Reaction to a corrupt database at startup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnCorruption {
+    /// Refuse to start
+    #[default]
+    Fail,
+    /// Run RocksDB's repair, which keeps what it can read, and open again
+    Repair,
+    /// Move the shard's files into a `corrupt-<unix time>` subdirectory of
+    /// each of its directories and start empty
+    Recreate,
+}
+
+impl OnCorruption {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Fail => "fail",
+            Self::Repair => "repair",
+            Self::Recreate => "recreate",
+        }
+    }
+}
+
+/// Write-ahead log mode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Wal {
+    /// Writes go to the memtable only (a cache can afford to lose them)
+    #[default]
+    Disabled,
+    /// Writes are logged before they are acknowledged
+    Enabled,
+    /// Writes are logged and fsynced before they are acknowledged
+    EnabledSync,
+}
+
+impl Wal {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Disabled => "disabled",
+            Self::Enabled => "enabled",
+            Self::EnabledSync => "enabled_sync",
+        }
+    }
+}
+
+/// Reaction to the database reaching `max_db_size_bytes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFull {
+    /// Fail writes with `SERVER_ERROR out of memory storing object`
+    #[default]
+    Reject,
+    /// Delete the entries expiring soonest (then the oldest) to make room
+    Evict,
+}
+
+impl OnFull {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reject => "reject",
+            Self::Evict => "evict",
+        }
+    }
+}
+
+/// Whether this instance owns the database or follows another's
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageMode {
+    #[default]
+    Primary,
+    Secondary,
+}
+
+impl StorageMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Primary => "primary",
+            Self::Secondary => "secondary",
+        }
+    }
+}
+
+/// When to run a full manual compaction in the background
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CompactionScheduleConfig {
+    /// Compact the whole keyspace every this many seconds (0 = disabled)
+    #[serde(with = "units::secs")]
+    pub interval_secs: u64,
+
+    /// Only start a scheduled compaction inside this UTC time window,
+    /// written `"HH:MM-HH:MM"`; it may wrap past midnight
+    pub window: Option<CompactionWindow>,
+}
+
+/// Daily UTC time window, as minutes since midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CompactionWindow {
+    pub start_minute: u32,
+    pub end_minute: u32,
+}
+
+impl CompactionWindow {
+    /// Whether `minute` (since UTC midnight) falls inside the window
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start_minute <= self.end_minute {
+            (self.start_minute..self.end_minute).contains(&minute)
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        }
+    }
+}
+
+impl From<CompactionWindow> for String {
+    fn from(window: CompactionWindow) -> Self {
+        let time = |minute: u32| format!("{:02}:{:02}", minute / 60, minute % 60);
+        format!("{}-{}", time(window.start_minute), time(window.end_minute))
+    }
+}
+
+impl TryFrom<String> for CompactionWindow {
+    type Error = String;
+
+    fn try_from(window: String) -> Result<Self, Self::Error> {
+        let parse_time = |time: &str| -> Option<u32> {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+            (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+        };
+        let invalid = || format!("invalid compaction window {window:?}, expected \"HH:MM-HH:MM\"");
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        match (parse_time(start), parse_time(end)) {
+            (Some(start_minute), Some(end_minute)) if start_minute != end_minute => Ok(Self {
+                start_minute,
+                end_minute,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl StorageConfig {
+    /// `compression`, or LZ4 / none from the deprecated `enable_compression`
+    pub fn effective_compression(&self) -> Compression {
+        match self.compression {
+            Some(compression) => compression,
+            None if self.enable_compression => Compression::Lz4,
+            None => Compression::None,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            db_path: PathBuf::from("./data/rocksdb"),
+            db_paths: Vec::new(),
+            block_cache_size: 1024 * 1024 * 1024, // 1GB block cache
+            write_buffer_size: 64 * 1024 * 1024,  // 64MB
+            max_write_buffer_number: 3,
+            target_file_size_base: 64 * 1024 * 1024, // 64MB
+            max_background_jobs: 4,
+            rate_limit_bytes_per_sec: 0,
+            compaction_readahead_size: 2 * 1024 * 1024, // 2MB, RocksDB's default
+            max_subcompactions: 1,
+            low_priority_compaction_io: false,
+            enable_compression: false,
+            compression: None,
+            bottommost_compression: None,
+            zstd_level: None,
+            block_size: 16 * 1024, // 16KB
+            bloom_bits_per_key: 10,
+            cache_index_and_filter_blocks: true,
+            index_type: IndexType::BinarySearch,
+            partition_filters: false,
+            format_version: None,
+            enable_ttl_compaction: true,
+            enable_statistics: false,
+            flush_on_shutdown: true,
+            wal: Wal::Disabled,
+            wal_dir: None,
+            max_total_wal_size: 0,
+            rocksdb_log_level: "error".to_string(),
+            rocksdb_max_log_file_size: 10 * 1024 * 1024, // 10MB
+            rocksdb_keep_log_file_num: 5,
+            hot_cache_size_bytes: 0,
+            negative_cache_size: 0,
+            negative_cache_ttl_secs: 5,
+            expiry_scan_interval_ms: 0,
+            expiry_scan_batch_keys: 1000,
+            expiry_scan_budget_ms: 10,
+            expired_log_sample_rate: 1000,
+            expired_log_keys: false,
+            expired_log_interval_secs: 60,
+            default_ttl_secs: 0,
+            max_ttl_secs: 0,
+            ttl_jitter_percent: 0,
+            compaction_schedule: CompactionScheduleConfig::default(),
+            restore_from: None,
+            restore_overwrite: false,
+            on_corruption: OnCorruption::Fail,
+            mode: StorageMode::Primary,
+            primary_path: None,
+            catch_up_interval_ms: 1000,
+            catch_up_max_failures: 3,
+            ingest_dir: None,
+            max_db_size_bytes: 0,
+            on_full: OnFull::Reject,
+            disk_check_interval_ms: 1000,
+            health_check_interval_ms: 1000,
+            low_water_percent: 90,
+            compaction: CompactionStyle::Level,
+            compress_values_over_bytes: 0,
+            verify_checksums: false,
+            namespaces: Vec::new(),
+            shards: 1,
+        }
+    }
+}
+
+/// Metrics and health check configuration
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// Enable metrics collection
+    pub enabled: bool,
+
+    /// Address for metrics/health HTTP server
+    pub listen_addr: String,
+
+    /// Also write and delete a reserved key on each readiness probe, not
+    /// just read one
+    pub readiness_write_check: bool,
+
+    /// A readiness check slower than this fails the probe (0 = no limit)
+    #[serde(with = "units::millis")]
+    pub readiness_max_latency_ms: u64,
+
+    /// Reuse a readiness probe's result for this long, so frequent probes
+    /// don't add load
+    #[serde(with = "units::millis")]
+    pub readiness_cache_ms: u64,
+
+    /// Value of an `instance` label on every series, to tell several
+    /// servers sharing a registry apart
+    pub instance: Option<String>,
+
+    /// Upper bounds of the `petracache_key_size_bytes` buckets, ascending
+    pub key_size_buckets: Vec<f64>,
+
+    /// Upper bounds of the `petracache_value_size_bytes` buckets, ascending
+    pub value_size_buckets: Vec<f64>,
+
+    /// Also observe key and value sizes of get hits, not only of stores
+    pub observe_hit_sizes: bool,
+
+    /// Window of the `petracache_hit_ratio` gauge (0 = not computed)
+    #[serde(with = "units::secs")]
+    pub hit_ratio_window_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen_addr: "127.0.0.1:9090".to_string(),
+            readiness_write_check: false,
+            readiness_max_latency_ms: 100,
+            readiness_cache_ms: 1000,
+            instance: None,
+            key_size_buckets: vec![8.0, 16.0, 32.0, 64.0, 128.0, 250.0],
+            value_size_buckets: vec![
+                64.0,
+                256.0,
+                1024.0,
+                4096.0,
+                16384.0,
+                65536.0,
+                262_144.0,
+                1_048_576.0,
+            ],
+            observe_hit_sizes: false,
+            hit_ratio_window_secs: 60,
+        }
+    }
+}
+
+/// RocksDB backup configuration
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BackupConfig {
+    /// Backup directory; backups in it share unchanged SST files
+    pub dir: PathBuf,
+
+    /// Back up every this many seconds (0 = only via `POST /admin/backup`)
+    #[serde(with = "units::secs")]
+    pub interval_secs: u64,
+
+    /// Backups to keep, oldest deleted first (0 = keep all)
+    pub keep: usize,
+
+    /// `POST /admin/checkpoint` may only create checkpoints under this
+    /// directory (unset = endpoint disabled)
+    pub checkpoint_dir: Option<PathBuf>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            dir: PathBuf::from("./data/backups"),
+            interval_secs: 0,
+            keep: 7,
+            checkpoint_dir: None,
+        }
+    }
+}
+
+/// Read-through from an upstream memcached on GET misses
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UpstreamConfig {
+    /// Upstream memcached address, `host:port` (empty = read-through
+    /// disabled)
+    pub addr: String,
+
+    /// Timeout for connecting to, writing to and reading from the upstream,
+    /// in milliseconds
+    #[serde(with = "units::millis")]
+    pub timeout_ms: u64,
+
+    /// Most upstream requests in flight at once; misses beyond that stay
+    /// misses
+    pub max_concurrent_fetches: usize,
+
+    /// Expiration of values filled from the upstream, in seconds (0 = never)
+    #[serde(with = "units::secs")]
+    pub ttl_secs: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            addr: String::new(),
+            timeout_ms: 100,
+            max_concurrent_fetches: 64,
+            ttl_secs: 300,
+        }
+    }
+}
+
+/// Audit log of destructive operations
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    /// JSON-lines file recording every `delete`, `md`, `flush_all` and
+    /// admin endpoint call (unset = no audit log)
+    pub path: Option<PathBuf>,
+
+    /// Record a hash of the key instead of the key
+    pub hash_keys: bool,
+
+    /// Rotate the file once it would grow past this many bytes
+    #[serde(with = "units::size")]
+    pub max_file_bytes: u64,
+
+    /// Rotated files to keep (`path.1` is the newest)
+    pub max_files: usize,
+
+    /// Entries buffered for the writer; entries beyond that are dropped
+    /// and counted rather than slowing down requests
+    pub queue_size: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            hash_keys: false,
+            max_file_bytes: 100 * 1024 * 1024,
+            max_files: 10,
+            queue_size: 10_000,
+        }
+    }
+}
+
+/// Log output
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+
+    /// Filter directives, e.g. `info` or `info,petracache::server=debug`;
+    /// `RUST_LOG` overrides it, and `PUT /admin/loglevel` changes it at
+    /// runtime
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with event fields at the top level
+    Json,
+}
+
+/// The effective configuration for `args`: defaults, overridden by the
+/// config file, then by `PETRACACHE_*` environment variables, then by
+/// command-line flags
+pub fn load(args: &Args) -> crate::Result<Config> {
+    load_with(args, &EnvVars::from_process())
+}
+
+fn load_with(args: &Args, env: &EnvVars) -> crate::Result<Config> {
+    let mut config = match args.config_file() {
+        Some(path) => Config::read_file(path, env)?,
+        None => Config::layered(None, env)?,
+    };
+    args.apply(&mut config);
+    Ok(config)
+}
+
+impl Config {
+    /// Load configuration from a TOML, YAML (`.yaml`/`.yml`) or JSON
+    /// (`.json`) file, overridden by any `PETRACACHE_*` environment
+    /// variables (see `env`)
+    pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Self> {
+        Self::read_file(path.as_ref(), &EnvVars::from_process())
+    }
+
+    /// Load configuration from `PETRACACHE_*` environment variables over
+    /// the defaults
+    pub fn from_env() -> crate::Result<Self> {
+        Self::layered(None, &EnvVars::from_process())
+    }
+
+    /// The configuration as TOML, secrets redacted
+    pub fn to_toml(&self) -> crate::Result<String> {
+        toml::to_string(self)
+            .map_err(|e| crate::PetraCacheError::Config(format!("Failed to serialize config: {e}")))
+    }
+
+    fn read_file(path: &Path, env: &EnvVars) -> crate::Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            crate::PetraCacheError::Config(format!("Failed to read config file: {e}"))
+        })?;
+        let format = FileFormat::of(path);
+        let parse_error =
+            |e| crate::PetraCacheError::Config(format!("Failed to parse config as {format}: {e}"));
+
+        // Parsed as a whole first for errors with line numbers
+        format.parse::<Self>(&contents).map_err(parse_error)?;
+        let file = format.parse_table(&contents).map_err(parse_error)?;
+        Self::layered(Some(toml::Value::Table(file)), env)
+    }
+
+    fn layered(file: Option<toml::Value>, env: &EnvVars) -> crate::Result<Self> {
+        env.apply(file)
+            .map_err(|e| crate::PetraCacheError::Config(format!("Invalid configuration: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::CommandKind;
+
+    #[test]
+    fn test_compaction_window() {
+        let night = CompactionWindow::try_from("02:00-05:30".to_string()).unwrap();
+        assert_eq!((night.start_minute, night.end_minute), (120, 330));
+        assert!(night.contains(120));
+        assert!(night.contains(329));
+        assert!(!night.contains(330));
+        assert!(!night.contains(60));
+
+        // Wraps past midnight
+        let wrapping = CompactionWindow::try_from("23:00-01:00".to_string()).unwrap();
+        assert!(wrapping.contains(23 * 60 + 30));
+        assert!(wrapping.contains(30));
+        assert!(!wrapping.contains(12 * 60));
+
+        for invalid in [
+            "",
+            "02:00",
+            "2-5",
+            "24:00-01:00",
+            "02:60-03:00",
+            "03:00-03:00",
+        ] {
+            assert!(
+                CompactionWindow::try_from(invalid.to_string()).is_err(),
+                "{invalid:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_namespaces_from_toml() {
+        let config: Config = toml::from_str(
+            "[storage]\nnamespaces = [\"users\", { name = \"sessions\", max_bytes = 1024 }]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.storage.namespaces,
+            [
+                NamespaceConfig::new("users"),
+                NamespaceConfig {
+                    name: "sessions".to_string(),
+                    max_bytes: 1024,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compaction_schedule_from_toml() {
+        let config: Config = toml::from_str(
+            "[storage.compaction_schedule]\ninterval_secs = 3600\nwindow = \"01:00-04:00\"\n",
+        )
+        .unwrap();
+        let schedule = &config.storage.compaction_schedule;
+        assert_eq!(schedule.interval_secs, 3600);
+        assert!(schedule.window.unwrap().contains(90));
+
+        assert!(
+            toml::from_str::<Config>("[storage.compaction_schedule]\nwindow = \"nightly\"\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_disabled_commands() {
+        let config: Config =
+            toml::from_str("[server]\ndisabled_commands = [\"flush_all\"]\n").unwrap();
+        let disabled = config.server.disabled_commands;
+        assert!(disabled.contains(CommandKind::FlushAll));
+        assert!(!disabled.contains(CommandKind::Set));
+
+        assert!(toml::from_str::<Config>("[server]\ndisabled_commands = [\"quit\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_load_precedence() {
+        use clap::Parser;
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("petracache.toml");
+        std::fs::write(
+            &path,
+            "[server]\nlisten_addr = \"file:1\"\nmax_item_size = 2048\n",
+        )
+        .unwrap();
+
+        // Every combination of sources setting server.listen_addr; the
+        // highest one present wins
+        for (file, env, cli, expected) in [
+            (false, false, false, "127.0.0.1:11211"),
+            (true, false, false, "file:1"),
+            (false, true, false, "env:1"),
+            (true, true, false, "env:1"),
+            (false, false, true, "cli:1"),
+            (true, false, true, "cli:1"),
+            (false, true, true, "cli:1"),
+            (true, true, true, "cli:1"),
+        ] {
+            let mut command_line = vec!["petracache".to_string()];
+            if file {
+                command_line.push(path.to_string_lossy().into_owned());
+            }
+            if cli {
+                command_line.extend(["--listen-addr".to_string(), "cli:1".to_string()]);
+            }
+            let vars = env.then(|| {
+                (
+                    "PETRACACHE_SERVER_LISTEN_ADDR".to_string(),
+                    "env:1".to_string(),
+                )
+            });
+            let args = Args::try_parse_from(command_line).unwrap();
+            let config = load_with(&args, &EnvVars::new(vars)).unwrap();
+            assert_eq!(
+                config.server.listen_addr, expected,
+                "file={file} env={env} cli={cli}"
+            );
+            // Settings no higher source touches keep the file's value
+            let max_item_size = if file { 2048 } else { 1024 * 1024 };
+            assert_eq!(config.server.max_item_size, max_item_size);
+        }
+    }
+
+    #[test]
+    fn test_cli_flags() {
+        use clap::Parser;
+        let args = Args::try_parse_from([
+            "petracache",
+            "--db-path",
+            "/data",
+            "--metrics-addr",
+            "0.0.0.0:9191",
+            "--max-connections",
+            "5",
+            "--worker-threads",
+            "3",
+            "--log-level",
+            "debug",
+            "--read-only",
+        ])
+        .unwrap();
+        let config = load_with(&args, &EnvVars::new([])).unwrap();
+        assert_eq!(config.storage.db_path, PathBuf::from("/data"));
+        assert_eq!(config.metrics.listen_addr, "0.0.0.0:9191");
+        assert_eq!(config.server.max_connections, 5);
+        assert_eq!(config.server.worker_threads, 3);
+        assert_eq!(config.log.level, "debug");
+        assert!(config.server.read_only);
+
+        // A flag left out doesn't reset what the environment set
+        let env = EnvVars::new([(
+            "PETRACACHE_SERVER_READ_ONLY".to_string(),
+            "true".to_string(),
+        )]);
+        let args = Args::try_parse_from(["petracache"]).unwrap();
+        assert!(load_with(&args, &env).unwrap().server.read_only);
+
+        assert!(Args::try_parse_from(["petracache", "--config", "a.toml", "b.toml"]).is_err());
+        assert!(Args::try_parse_from(["petracache", "--max-connections", "many"]).is_err());
+    }
+
+    #[test]
+    fn test_to_toml_round_trips() {
+        let mut config: Config = toml::from_str(
+            "[server]\nallowed_cidrs = [\"10.0.0.0/8\"]\ndisabled_commands = [\"flush_all\"]\n\
+             [server.auth]\nusername = \"app\"\npassword = \"secret\"\n\
+             [storage]\nnamespaces = [{ name = \"users\", max_bytes = \"1GiB\" }]\n\
+             [storage.compaction_schedule]\nwindow = \"23:30-01:05\"\n",
+        )
+        .unwrap();
+        let printed = config.to_toml().unwrap();
+        assert!(!printed.contains("secret"), "{printed}");
+        // Sizes and durations in human form
+        for line in [
+            "block_cache_size = \"1GiB\"",
+            "shutdown_grace_secs = \"10s\"",
+            "readiness_max_latency_ms = \"100ms\"",
+            "max_bytes = \"1GiB\"",
+            "connection_timeout_secs = 0",
+        ] {
+            assert!(printed.contains(line), "{line} in {printed}");
+        }
+
+        let parsed: Config = toml::from_str(&printed).unwrap();
+        config.server.auth.as_mut().unwrap().password = "<redacted>".to_string();
+        assert_eq!(format!("{parsed:?}"), format!("{config:?}"));
+        assert_eq!(parsed.server.auth.unwrap().password, "<redacted>");
+    }
+}
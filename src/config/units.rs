@@ -0,0 +1,368 @@
+//! Sizes and durations written with units (`"1GiB"`, `"30s"`)
+//!
+//! Size and duration settings keep their integer types and accept either a
+//! plain integer, in the field's own unit (bytes, or the seconds or
+//! milliseconds its name ends in), or a string with a unit. Sizes take `B`,
+//! `KB`/`MB`/`GB`/`TB` (powers of 1000) and `KiB`/`MiB`/`GiB`/`TiB` (powers
+//! of 1024), case-insensitively; durations take `ms`, `s`, `m`, `h` and
+//! `d`. A fraction is fine as long as it comes to a whole number of the
+//! field's unit (`"1.5GiB"`, but not `"1.5s"` for a `_secs` field).
+//!
+//! Serialization picks the largest unit that represents the value exactly,
+//! so `--print-config` prints `"1GiB"` and `"5m"`.
+
+use serde::de::{self, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt;
+
+/// Size units, largest first
+const SIZE_UNITS: [(&str, u64); 9] = [
+    ("TiB", 1 << 40),
+    ("TB", 1_000_000_000_000),
+    ("GiB", 1 << 30),
+    ("GB", 1_000_000_000),
+    ("MiB", 1 << 20),
+    ("MB", 1_000_000),
+    ("KiB", 1 << 10),
+    ("KB", 1000),
+    ("B", 1),
+];
+
+/// Duration units in milliseconds, largest first
+const DURATION_UNITS: [(&str, u64); 5] = [
+    ("d", 86_400_000),
+    ("h", 3_600_000),
+    ("m", 60_000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+/// Units `serialize_size` writes: binary ones only, so reading a printed
+/// config back gives the same number of bytes
+const SIZE_FORMAT_UNITS: [&str; 4] = ["TiB", "GiB", "MiB", "KiB"];
+
+/// Number of bytes in `value`, e.g. `"512MB"`, `"1.5GiB"` or `"4096"`
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse(value, &SIZE_UNITS, 1).map_err(|reason| format!("invalid size {value:?}: {reason}"))
+}
+
+/// `value` in units of `unit_ms` milliseconds, e.g. `"5m"` is 300 for
+/// seconds; a bare number is already in those units
+pub fn parse_duration(value: &str, unit_ms: u64) -> Result<u64, String> {
+    parse(value, &DURATION_UNITS, unit_ms)
+        .map_err(|reason| format!("invalid duration {value:?}: {reason}"))
+}
+
+/// `<number><unit>` in multiples of `base`, the unit a bare number is in
+fn parse(value: &str, units: &[(&str, u64)], base: u64) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let unit = unit.trim_start();
+    let multiplier = if unit.is_empty() {
+        base
+    } else {
+        units
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+            .map(|&(_, multiplier)| multiplier)
+            .ok_or_else(|| {
+                let names: Vec<&str> = units.iter().rev().map(|(name, _)| *name).collect();
+                format!(
+                    "unknown unit {unit:?}, expected one of {}",
+                    names.join(", ")
+                )
+            })?
+    };
+
+    // Exact decimal arithmetic: "1.5" is 15 tenths
+    let (whole, fraction) = match number.split_once('.') {
+        Some((whole, fraction)) if !fraction.is_empty() => (whole, fraction),
+        Some(_) => ("", ""),
+        None => (number, ""),
+    };
+    if whole.is_empty() || fraction.contains('.') || fraction.len() > 9 {
+        return Err("expected a number followed by an optional unit".to_string());
+    }
+    let scale = 10u128.pow(u32::try_from(fraction.len()).unwrap_or(0));
+    let mantissa: u128 = format!("{whole}{fraction}")
+        .parse()
+        .map_err(|_| "expected a number followed by an optional unit".to_string())?;
+    let amount = mantissa * u128::from(multiplier);
+    let divisor = scale * u128::from(base);
+    if amount % divisor != 0 {
+        return Err("not a whole number of the setting's unit".to_string());
+    }
+    u64::try_from(amount / divisor).map_err(|_| "too large".to_string())
+}
+
+/// `value` with the largest unit that represents it exactly, if any
+fn format(value: u64, units: &[(&str, u64)], allowed: impl Fn(&str) -> bool) -> Option<String> {
+    if value == 0 {
+        return None;
+    }
+    units
+        .iter()
+        .find(|&&(name, multiplier)| allowed(name) && value % multiplier == 0)
+        .map(|&(name, multiplier)| format!("{}{name}", value / multiplier))
+}
+
+/// Deserialize a size from an integer number of bytes or a string with a
+/// unit
+pub fn deserialize_size<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    let bytes = deserializer.deserialize_any(UnitVisitor {
+        expecting: "a size in bytes, like 1048576 or \"1MiB\"",
+        parse: parse_size,
+    })?;
+    T::try_from(bytes).map_err(|_| de::Error::custom(format!("size {bytes} is too large")))
+}
+
+/// Serialize a size in KiB, MiB, ... when it is a whole number of them
+pub fn serialize_size<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: Copy,
+    u64: TryFrom<T>,
+{
+    let bytes = u64::try_from(*value).unwrap_or(u64::MAX);
+    match format(bytes, &SIZE_UNITS, |name| SIZE_FORMAT_UNITS.contains(&name)) {
+        Some(size) => serializer.serialize_str(&size),
+        None => serializer.serialize_u64(bytes),
+    }
+}
+
+/// Deserialize a duration in units of `unit_ms` milliseconds from an
+/// integer or a string with a unit
+pub fn deserialize_duration<'de, D>(deserializer: D, unit_ms: u64) -> Result<u64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let parse = move |value: &str| parse_duration(value, unit_ms);
+    deserializer.deserialize_any(UnitVisitor {
+        expecting: "a duration, like 30 or \"5m\"",
+        parse,
+    })
+}
+
+/// Serialize a duration of `value` units of `unit_ms` milliseconds with
+/// the largest unit that fits exactly; zero (usually "disabled") stays 0
+pub fn serialize_duration<S: Serializer>(
+    value: u64,
+    unit_ms: u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let formatted = value
+        .checked_mul(unit_ms)
+        .and_then(|ms| format(ms, &DURATION_UNITS, |_| true));
+    match formatted {
+        Some(duration) => serializer.serialize_str(&duration),
+        None => serializer.serialize_u64(value),
+    }
+}
+
+/// Accepts a non-negative integer as is, or a string through `parse`
+struct UnitVisitor<F> {
+    expecting: &'static str,
+    parse: F,
+}
+
+impl<F: Fn(&str) -> Result<u64, String>> Visitor<'_> for UnitVisitor<F> {
+    type Value = u64;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.expecting)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<u64, E> {
+        Ok(value)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<u64, E> {
+        u64::try_from(value).map_err(|_| E::invalid_value(de::Unexpected::Signed(value), &self))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<u64, E> {
+        (self.parse)(value).map_err(E::custom)
+    }
+}
+
+/// `#[serde(with = "units::size")]` for `usize` and `u64` byte counts
+pub mod size {
+    pub use super::{deserialize_size as deserialize, serialize_size as serialize};
+}
+
+/// `#[serde(with = "units::secs")]` for `u64` seconds
+pub mod secs {
+    use serde::{Deserializer, Serializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        super::deserialize_duration(deserializer, 1000)
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)] // serde passes a reference
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        super::serialize_duration(*value, 1000, serializer)
+    }
+}
+
+/// `#[serde(with = "units::millis")]` for `u64` milliseconds
+pub mod millis {
+    use serde::{Deserializer, Serializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        super::deserialize_duration(deserializer, 1)
+    }
+
+    #[allow(clippy::trivially_copy_pass_by_ref)] // serde passes a reference
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        super::serialize_duration(*value, 1, serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[test]
+    fn test_parse_size() {
+        let cases = [
+            ("0", 0),
+            ("4096", 4096),
+            ("  4096 ", 4096),
+            ("10B", 10),
+            ("64kb", 64_000),
+            ("64KiB", 65_536),
+            ("512MB", 512_000_000),
+            ("512mib", 512 << 20),
+            ("1GiB", 1 << 30),
+            ("1 GiB", 1 << 30),
+            ("2GB", 2_000_000_000),
+            ("1.5GiB", 3 << 29),
+            ("0.5KB", 500),
+            ("1TiB", 1 << 40),
+            ("16384TiB", 1 << 54),
+        ];
+        for (value, bytes) in cases {
+            assert_eq!(parse_size(value), Ok(bytes), "{value}");
+        }
+
+        let invalid = [
+            "",
+            "GiB",
+            "1.5GBB",
+            "1.5.5GiB",
+            ".5GiB",
+            "1.GiB",
+            "-1",
+            "1 G",
+            "1k",
+            "1e9",
+            "0.3B",
+            "1.0000000001GiB",
+            "17000000TiB",
+            "99999999999999999999999",
+        ];
+        for value in invalid {
+            assert!(parse_size(value).is_err(), "{value}");
+        }
+        assert_eq!(
+            parse_size("1.5GBB").unwrap_err(),
+            "invalid size \"1.5GBB\": unknown unit \"GBB\", expected one of \
+             B, KB, KiB, MB, MiB, GB, GiB, TB, TiB"
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        let secs = [
+            ("30", 30),
+            ("30s", 30),
+            ("5m", 300),
+            ("1h", 3600),
+            ("1.5h", 5400),
+        ];
+        for (value, expected) in secs {
+            assert_eq!(parse_duration(value, 1000), Ok(expected), "{value}");
+        }
+        let millis = [
+            ("250", 250),
+            ("250ms", 250),
+            ("2s", 2000),
+            ("1d", 86_400_000),
+        ];
+        for (value, expected) in millis {
+            assert_eq!(parse_duration(value, 1), Ok(expected), "{value}");
+        }
+        for value in ["1500ms", "0.5s", "5x", "5 minutes", "s", "-5s"] {
+            assert!(parse_duration(value, 1000).is_err(), "{value}");
+        }
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Settings {
+        #[serde(with = "size")]
+        cache: usize,
+        #[serde(with = "size")]
+        limit: u64,
+        #[serde(with = "secs")]
+        timeout_secs: u64,
+        #[serde(with = "millis")]
+        interval_ms: u64,
+    }
+
+    #[test]
+    fn test_serde() {
+        let settings: Settings = toml::from_str(
+            "cache = \"1GiB\"\nlimit = 1000\ntimeout_secs = \"2m\"\ninterval_ms = 1500\n",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                cache: 1 << 30,
+                limit: 1000,
+                timeout_secs: 120,
+                interval_ms: 1500,
+            }
+        );
+
+        // Printed with the largest exact unit, and read back the same
+        let printed = toml::to_string(&settings).unwrap();
+        assert_eq!(
+            printed,
+            "cache = \"1GiB\"\nlimit = 1000\ntimeout_secs = \"2m\"\ninterval_ms = \"1500ms\"\n"
+        );
+        assert_eq!(toml::from_str::<Settings>(&printed).unwrap(), settings);
+
+        let zero = Settings {
+            cache: 0,
+            limit: 0,
+            timeout_secs: 0,
+            interval_ms: 0,
+        };
+        let printed = toml::to_string(&zero).unwrap();
+        assert!(printed.contains("timeout_secs = 0\n"), "{printed}");
+        assert_eq!(toml::from_str::<Settings>(&printed).unwrap(), zero);
+
+        let error = toml::from_str::<Settings>(
+            "cache = \"1.5GBB\"\nlimit = 0\ntimeout_secs = 0\ninterval_ms = 0\n",
+        )
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("unknown unit \"GBB\""),
+            "{error}"
+        );
+        let error = toml::from_str::<Settings>(
+            "cache = -1\nlimit = 0\ntimeout_secs = 0\ninterval_ms = 0\n",
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("a size in bytes"), "{error}");
+    }
+}
@@ -0,0 +1,524 @@
+//! Startup checks of the effective configuration
+//!
+//! `Config::validate` reports every violation at once, each naming the
+//! field and its value, so a bad deployment fails before RocksDB is opened
+//! or a port is bound. Checks that need the database (shard layout,
+//! namespace names) stay in `RocksStorage::open`.
+
+use super::{CompactionStyle, Compression, Config, IndexType, StorageMode};
+use crate::storage::MAX_SHARDS;
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::Path;
+use thiserror::Error;
+
+/// Levels `storage.rocksdb_log_level` accepts
+const ROCKSDB_LOG_LEVELS: [&str; 6] = ["debug", "info", "warn", "error", "fatal", "header"];
+
+/// One setting that can't work
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{field} = {value}: {reason}")]
+pub struct ConfigError {
+    /// Dotted path of the setting, e.g. `server.listen_addr`
+    pub field: String,
+    /// The offending value, as written in TOML
+    pub value: String,
+    pub reason: String,
+}
+
+/// Violations found so far
+#[derive(Default)]
+struct Errors(Vec<ConfigError>);
+
+impl Errors {
+    fn push(&mut self, field: &str, value: impl Debug, reason: impl Into<String>) {
+        self.0.push(ConfigError {
+            field: field.to_string(),
+            value: format!("{value:?}"),
+            reason: reason.into(),
+        });
+    }
+
+    /// Record a violation unless `ok`
+    fn check(&mut self, ok: bool, field: &str, value: impl Debug, reason: &str) {
+        if !ok {
+            self.push(field, value, reason);
+        }
+    }
+}
+
+impl Config {
+    /// Check that the configuration can work, returning every violation
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Errors::default();
+        self.validate_listeners(&mut errors);
+        self.validate_server(&mut errors);
+        self.validate_storage(&mut errors);
+        self.validate_metrics(&mut errors);
+        if !self.upstream.addr.is_empty() && !is_host_port(&self.upstream.addr) {
+            errors.push(
+                "upstream.addr",
+                &self.upstream.addr,
+                "expected host:port, e.g. \"10.0.0.5:11211\"",
+            );
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            errors.push("log.level", &self.log.level, e.to_string());
+        }
+        if errors.0.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.0)
+        }
+    }
+
+    /// Listener addresses parse and don't collide
+    fn validate_listeners(&self, errors: &mut Errors) {
+        let server = &self.server;
+        let data = parse_addr(errors, "server.listen_addr", &server.listen_addr);
+        if server.listen_addr.is_empty() && server.unix_socket_path.is_none() {
+            errors.push(
+                "server.listen_addr",
+                &server.listen_addr,
+                "no listener configured: set listen_addr or unix_socket_path",
+            );
+        }
+        if !self.metrics.enabled {
+            return;
+        }
+        let metrics = &self.metrics.listen_addr;
+        if metrics.is_empty() {
+            errors.push(
+                "metrics.listen_addr",
+                metrics,
+                "required while metrics.enabled",
+            );
+        } else if let (Some(data), Some(metrics)) =
+            (data, parse_addr(errors, "metrics.listen_addr", metrics))
+            && conflicts(data, metrics)
+        {
+            errors.push(
+                "metrics.listen_addr",
+                &self.metrics.listen_addr,
+                format!(
+                    "port {} is already used by server.listen_addr",
+                    metrics.port()
+                ),
+            );
+        }
+    }
+
+    fn validate_server(&self, errors: &mut Errors) {
+        let server = &self.server;
+        let positive = [
+            ("server.num_acceptors", server.num_acceptors),
+            ("server.max_connections", server.max_connections),
+            ("server.read_buffer_size", server.read_buffer_size),
+            ("server.write_buffer_size", server.write_buffer_size),
+            ("server.max_item_size", server.max_item_size),
+            ("server.max_get_keys", server.max_get_keys),
+            (
+                "server.max_command_line_bytes",
+                server.max_command_line_bytes,
+            ),
+        ];
+        for (field, value) in positive {
+            errors.check(value > 0, field, value, "must be at least 1");
+        }
+        if let Some(tls) = &server.tls {
+            let files = [
+                ("server.tls.cert_path", Some(&tls.cert_path)),
+                ("server.tls.key_path", Some(&tls.key_path)),
+                ("server.tls.client_ca_path", tls.client_ca_path.as_ref()),
+            ];
+            for (field, path) in files {
+                if let Some(path) = path {
+                    errors.check(path.is_file(), field, path, "no such file");
+                }
+            }
+        }
+    }
+
+    fn validate_storage(&self, errors: &mut Errors) {
+        let storage = &self.storage;
+        if let Err(reason) = check_writable(&storage.db_path) {
+            errors.push("storage.db_path", &storage.db_path, reason);
+        }
+        let mut seen = HashSet::new();
+        for data_path in &storage.db_paths {
+            if !seen.insert(&data_path.path) {
+                errors.push("storage.db_paths", &data_path.path, "listed twice");
+            } else if let Err(reason) = check_writable(&data_path.path) {
+                errors.push("storage.db_paths", &data_path.path, reason);
+            }
+            errors.check(
+                data_path.target_size_bytes > 0,
+                "storage.db_paths",
+                &data_path.path,
+                "target_size_bytes must be at least 1",
+            );
+        }
+        self.validate_rocksdb(errors);
+        errors.check(
+            (1..=MAX_SHARDS).contains(&storage.shards),
+            "storage.shards",
+            storage.shards,
+            &format!("must be between 1 and {MAX_SHARDS}"),
+        );
+        errors.check(
+            (1..=100).contains(&storage.low_water_percent),
+            "storage.low_water_percent",
+            storage.low_water_percent,
+            "must be between 1 and 100",
+        );
+        errors.check(
+            storage.compaction != CompactionStyle::Fifo || storage.max_db_size_bytes > 0,
+            "storage.max_db_size_bytes",
+            storage.max_db_size_bytes,
+            "fifo compaction needs a size limit",
+        );
+        errors.check(
+            storage.max_ttl_secs == 0 || storage.default_ttl_secs <= storage.max_ttl_secs,
+            "storage.default_ttl_secs",
+            storage.default_ttl_secs,
+            &format!("must not exceed max_ttl_secs ({})", storage.max_ttl_secs),
+        );
+        errors.check(
+            storage.ttl_jitter_percent <= 100,
+            "storage.ttl_jitter_percent",
+            storage.ttl_jitter_percent,
+            "must be between 0 and 100",
+        );
+        if storage.mode == StorageMode::Secondary {
+            match &storage.primary_path {
+                None => errors.push(
+                    "storage.primary_path",
+                    format_args!("(unset)"),
+                    "secondary mode needs it",
+                ),
+                Some(path) if *path == storage.db_path => {
+                    errors.push("storage.primary_path", path, "must differ from db_path");
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// RocksDB tuning: block cache and table options, memtables,
+    /// compression
+    fn validate_rocksdb(&self, errors: &mut Errors) {
+        let storage = &self.storage;
+        errors.check(
+            storage.block_cache_size >= storage.block_size,
+            "storage.block_cache_size",
+            storage.block_cache_size,
+            &format!("must hold at least one {}-byte block", storage.block_size),
+        );
+        errors.check(
+            storage.block_size > 0,
+            "storage.block_size",
+            storage.block_size,
+            "must be at least 1",
+        );
+        errors.check(
+            !storage.partition_filters || storage.index_type == IndexType::Partitioned,
+            "storage.partition_filters",
+            storage.partition_filters,
+            "needs index_type = \"partitioned\"",
+        );
+        errors.check(
+            storage.write_buffer_size > 0,
+            "storage.write_buffer_size",
+            storage.write_buffer_size,
+            "must be at least 1",
+        );
+        errors.check(
+            storage.max_write_buffer_number >= 1,
+            "storage.max_write_buffer_number",
+            storage.max_write_buffer_number,
+            "must be at least 1",
+        );
+        errors.check(
+            storage.max_background_jobs >= 1,
+            "storage.max_background_jobs",
+            storage.max_background_jobs,
+            "must be at least 1",
+        );
+        errors.check(
+            storage.max_subcompactions >= 1,
+            "storage.max_subcompactions",
+            storage.max_subcompactions,
+            "must be at least 1",
+        );
+        errors.check(
+            storage.target_file_size_base > 0,
+            "storage.target_file_size_base",
+            storage.target_file_size_base,
+            "must be at least 1",
+        );
+        errors.check(
+            ROCKSDB_LOG_LEVELS.contains(&storage.rocksdb_log_level.to_lowercase().as_str()),
+            "storage.rocksdb_log_level",
+            &storage.rocksdb_log_level,
+            &format!("expected one of {}", ROCKSDB_LOG_LEVELS.join(", ")),
+        );
+        if let Some(level) = storage.zstd_level {
+            let zstd = Some(Compression::Zstd);
+            if !(1..=22).contains(&level) {
+                errors.push("storage.zstd_level", level, "must be between 1 and 22");
+            } else if storage.compression != zstd && storage.bottommost_compression != zstd {
+                errors.push(
+                    "storage.zstd_level",
+                    level,
+                    "compression or bottommost_compression must be zstd",
+                );
+            }
+        }
+    }
+
+    fn validate_metrics(&self, errors: &mut Errors) {
+        let buckets = [
+            ("metrics.key_size_buckets", &self.metrics.key_size_buckets),
+            (
+                "metrics.value_size_buckets",
+                &self.metrics.value_size_buckets,
+            ),
+        ];
+        for (field, bounds) in buckets {
+            let ascending = bounds.windows(2).all(|pair| pair[0] < pair[1]);
+            let finite = bounds.iter().all(|bound| bound.is_finite());
+            errors.check(
+                ascending && finite,
+                field,
+                bounds,
+                "bucket bounds must be finite and strictly ascending",
+            );
+        }
+    }
+}
+
+/// `addr` as a socket address, recording a violation if it doesn't parse;
+/// `None` for an empty (disabled) address too
+fn parse_addr(errors: &mut Errors, field: &str, addr: &str) -> Option<SocketAddr> {
+    if addr.is_empty() {
+        return None;
+    }
+    match addr.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            errors.push(
+                field,
+                addr,
+                "expected ip:port, e.g. \"127.0.0.1:11211\" or \"[::1]:11211\"",
+            );
+            None
+        }
+    }
+}
+
+/// Whether binding both addresses would fail: same port, and the same IP
+/// or either on all interfaces (port 0 picks a free port)
+fn conflicts(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() != 0
+        && a.port() == b.port()
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+/// `host:port` with a numeric port
+fn is_host_port(addr: &str) -> bool {
+    addr.rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+}
+
+/// Whether `path` can be created or written: its nearest existing ancestor
+/// must be a directory we can create a file in
+fn check_writable(path: &Path) -> Result<(), String> {
+    let existing = path
+        .ancestors()
+        .map(|dir| {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            }
+        })
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    let probe = existing.join(format!(".petracache-write-check-{}", std::process::id()));
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .map_err(|e| format!("cannot write to {}: {e}", existing.display()))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DataPath, NamespaceConfig, TlsConfig};
+    use tempfile::TempDir;
+
+    fn config(dir: &TempDir) -> Config {
+        let mut config = Config::default();
+        config.storage.db_path = dir.path().join("db");
+        config
+    }
+
+    fn fields(config: &Config) -> Vec<String> {
+        let errors = config.validate().unwrap_err();
+        errors.into_iter().map(|e| e.field).collect()
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let dir = TempDir::new().unwrap();
+        config(&dir).validate().unwrap();
+
+        // Checks that need the database are left to RocksStorage::open
+        let mut config = config(&dir);
+        config.storage.namespaces = vec![NamespaceConfig::new("bad name")];
+        config.validate().unwrap();
+        assert!(std::fs::read_dir(dir.path()).unwrap().next().is_none());
+    }
+
+    #[test]
+    fn test_reports_every_violation() {
+        let dir = TempDir::new().unwrap();
+        let mut config = config(&dir);
+        config.server.listen_addr = "127.0.0.1:11211x".to_string();
+        config.server.read_buffer_size = 0;
+        config.storage.max_write_buffer_number = 0;
+        config.storage.block_cache_size = 1024;
+        config.storage.partition_filters = true;
+        config.storage.rocksdb_log_level = "verbose".to_string();
+        config.storage.compaction = CompactionStyle::Fifo;
+        config.storage.default_ttl_secs = 120;
+        config.storage.max_ttl_secs = 60;
+        config.storage.zstd_level = Some(3);
+        config.storage.mode = StorageMode::Secondary;
+        config.metrics.value_size_buckets = vec![10.0, 5.0];
+        config.upstream.addr = "memcached".to_string();
+        config.log.level = "x=y=z".to_string();
+
+        let errors = config.validate().unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(
+            fields,
+            [
+                "server.listen_addr",
+                "server.read_buffer_size",
+                "storage.block_cache_size",
+                "storage.partition_filters",
+                "storage.max_write_buffer_number",
+                "storage.rocksdb_log_level",
+                "storage.zstd_level",
+                "storage.max_db_size_bytes",
+                "storage.default_ttl_secs",
+                "storage.primary_path",
+                "metrics.value_size_buckets",
+                "upstream.addr",
+                "log.level",
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "server.listen_addr = \"127.0.0.1:11211x\": expected ip:port, \
+             e.g. \"127.0.0.1:11211\" or \"[::1]:11211\""
+        );
+        assert_eq!(
+            errors[2].to_string(),
+            "storage.block_cache_size = 1024: must hold at least one 16384-byte block"
+        );
+    }
+
+    #[test]
+    fn test_port_conflicts() {
+        let dir = TempDir::new().unwrap();
+        let with = |data: &str, metrics: &str| {
+            let mut config = config(&dir);
+            config.server.listen_addr = data.to_string();
+            config.metrics.listen_addr = metrics.to_string();
+            config
+        };
+        assert_eq!(
+            fields(&with("127.0.0.1:9090", "127.0.0.1:9090")),
+            ["metrics.listen_addr"]
+        );
+        assert_eq!(
+            fields(&with("0.0.0.0:11211", "127.0.0.1:11211")),
+            ["metrics.listen_addr"]
+        );
+        with("127.0.0.1:11211", "127.0.0.2:11211")
+            .validate()
+            .unwrap();
+        with("127.0.0.1:0", "127.0.0.1:0").validate().unwrap();
+
+        // The metrics address only matters while metrics are enabled
+        let mut config = with("127.0.0.1:9090", "127.0.0.1:9090");
+        config.metrics.enabled = false;
+        config.validate().unwrap();
+
+        config.server.listen_addr = String::new();
+        assert_eq!(fields(&config), ["server.listen_addr"]);
+        config.server.unix_socket_path = Some(dir.path().join("sock"));
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn test_paths() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+
+        let mut config = config(&dir);
+        config.storage.db_path = file.join("db");
+        let errors = config.validate().unwrap_err();
+        assert_eq!(errors[0].field, "storage.db_path");
+        assert!(
+            errors[0].reason.ends_with("is not a directory"),
+            "{}",
+            errors[0]
+        );
+
+        let mut config = self::config(&dir);
+        config.server.tls = Some(TlsConfig {
+            cert_path: file.clone(),
+            key_path: dir.path().join("missing.key"),
+            client_ca_path: None,
+        });
+        assert_eq!(fields(&config), ["server.tls.key_path"]);
+    }
+
+    #[test]
+    fn test_db_paths() {
+        let dir = TempDir::new().unwrap();
+        let data_path = |name: &str, target_size_bytes| DataPath {
+            path: dir.path().join(name),
+            target_size_bytes,
+        };
+        let mut config = config(&dir);
+        config.storage.db_paths = vec![data_path("a", 1 << 30), data_path("b", 1 << 40)];
+        config.validate().unwrap();
+
+        config.storage.db_paths = vec![
+            data_path("a", 1 << 30),
+            data_path("b", 0),
+            data_path("a", 1 << 30),
+        ];
+        let errors = config.validate().unwrap_err();
+        let reasons: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(reasons.len(), 2, "{reasons:?}");
+        assert!(
+            reasons[0].ends_with("target_size_bytes must be at least 1"),
+            "{reasons:?}"
+        );
+        assert!(reasons[1].ends_with("listed twice"), "{reasons:?}");
+    }
+}
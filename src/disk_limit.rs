@@ -0,0 +1,118 @@
+//! Database size cap
+//!
+//! RocksDB grows until the disk is full, and then every write fails,
+//! including the deletes and compactions that would free space. With
+//! `storage.max_db_size_bytes` set, this task samples the database size
+//! every `disk_check_interval_ms`. At the cap it either rejects sets until
+//! the size drops below `low_water_percent` of it (`on_full = "reject"`),
+//! or evicts entries down to that mark (`on_full = "evict"`). With
+//! `compaction = "fifo"` RocksDB enforces the cap itself and this task only
+//! reports the size.
+//!
+//! The same tick enforces `max_bytes` quotas of `storage.namespaces`.
+
+use crate::config::{CompactionStyle, OnFull, StorageConfig};
+use crate::metrics::Metrics;
+use crate::storage::RocksStorage;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Enforce the cap and namespace quotas until `cancel` fires; returns
+/// immediately if there are neither or `storage` is a read-only secondary
+pub async fn run(
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    config: &StorageConfig,
+    cancel: CancellationToken,
+) {
+    let limit = config.max_db_size_bytes;
+    let quotas = storage.has_namespace_quotas();
+    if (limit == 0 && !quotas) || storage.is_read_only() {
+        return;
+    }
+    let low_water = low_water_mark(limit, config.low_water_percent);
+    if limit > 0 {
+        info!(
+            limit,
+            low_water,
+            on_full = config.on_full.as_str(),
+            compaction = config.compaction.as_str(),
+            "Database size cap enabled"
+        );
+        metrics
+            .db_size_limit_bytes
+            .set(i64::try_from(limit).unwrap_or(i64::MAX));
+    }
+    let mut ticker =
+        tokio::time::interval(Duration::from_millis(config.disk_check_interval_ms.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        if quotas {
+            storage.enforce_namespace_quotas();
+        }
+        if limit == 0 {
+            continue;
+        }
+        let size = storage.db_size();
+        metrics
+            .db_size_bytes
+            .set(i64::try_from(size).unwrap_or(i64::MAX));
+        if config.compaction == CompactionStyle::Fifo {
+            continue;
+        }
+        match config.on_full {
+            OnFull::Reject => {
+                if size >= limit && !storage.set_full(true) {
+                    warn!(size, limit, "Database is full, rejecting sets");
+                } else if size < low_water && storage.set_full(false) {
+                    info!(
+                        size,
+                        low_water, "Database below low-water mark, accepting sets"
+                    );
+                }
+            }
+            OnFull::Evict if size >= limit => {
+                let task_storage = Arc::clone(&storage);
+                let result =
+                    tokio::task::spawn_blocking(move || task_storage.evict(size - low_water)).await;
+                match result {
+                    Ok(Ok(eviction)) => metrics.evicted_keys.inc_by(eviction.keys),
+                    Ok(Err(e)) => {
+                        metrics.storage_errors.inc();
+                        error!("Eviction failed: {}", e);
+                    }
+                    Err(e) => error!("Eviction task failed: {}", e),
+                }
+            }
+            OnFull::Evict => {}
+        }
+    }
+    debug!("Database size check stopped");
+}
+
+/// `percent` of `limit`, clamped so the mark never exceeds the cap
+fn low_water_mark(limit: u64, percent: u64) -> u64 {
+    let mark = u128::from(limit) * u128::from(percent.min(100)) / 100;
+    u64::try_from(mark).unwrap_or(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_low_water_mark() {
+        assert_eq!(low_water_mark(1000, 90), 900);
+        assert_eq!(low_water_mark(1000, 150), 1000);
+        assert_eq!(low_water_mark(u64::MAX, 50), u64::MAX / 2);
+    }
+}
@@ -1,5 +1,6 @@
 //! Error types for PetraCache
 
+use crate::protocol::ResponseWriter;
 use thiserror::Error;
 
 /// Main error type for PetraCache
@@ -21,6 +22,10 @@ pub enum PetraCacheError {
 /// Protocol parsing errors
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum ProtocolError {
+    /// Unrecognized command name (memcached replies with a bare ERROR)
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+
     #[error("Invalid command: {0}")]
     InvalidCommand(String),
 
@@ -39,22 +44,66 @@ pub enum ProtocolError {
     #[error("Invalid bytes length")]
     InvalidBytesLength,
 
+    #[error("Invalid cas unique")]
+    InvalidCasUnique,
+
     #[error("Invalid numeric value")]
     InvalidNumericValue,
 
+    #[error("Invalid meta flag: {0}")]
+    InvalidMetaFlag(char),
+
+    #[error("bad base64 key")]
+    InvalidBase64Key,
+
     #[error("Key too long (max 250 bytes)")]
     KeyTooLong,
 
-    #[error("Value too large")]
-    ValueTooLarge,
+    /// Retrieval command names more than `max_get_keys` keys
+    #[error("too many keys")]
+    TooManyKeys,
 
-    #[error("Unexpected data")]
-    UnexpectedData,
+    /// Command line exceeds `max_command_line_bytes`
+    #[error("line too long")]
+    LineTooLong,
+
+    /// Announced data block exceeds `max_item_size`; `discard` is the number
+    /// of bytes (command line + data block + CRLF) to skip to stay in sync
+    #[error("object too large for cache")]
+    ValueTooLarge { discard: usize },
+
+    /// Data block not terminated by CRLF where `<bytes>` said it would be;
+    /// `discard` covers the command line, the announced block and its
+    /// terminator, exactly as memcached consumes them
+    #[error("bad data chunk")]
+    BadDataChunk { discard: usize },
 
     #[error("Incomplete command")]
     IncompleteCommand,
 }
 
+impl ProtocolError {
+    /// Build `ValueTooLarge` for a command line whose `bytes`-long data
+    /// block would start at `data_start`
+    pub fn value_too_large(data_start: usize, bytes: usize) -> Self {
+        Self::ValueTooLarge {
+            discard: data_start + bytes + 2,
+        }
+    }
+
+    /// Write the memcached reply for this error
+    ///
+    /// Unknown verbs get a bare `ERROR` (clients key off that exact token);
+    /// malformed arguments to known commands get `CLIENT_ERROR <message>`.
+    pub fn write_response(&self, response: &mut ResponseWriter) {
+        match self {
+            Self::UnknownCommand(_) => response.error(),
+            Self::ValueTooLarge { .. } => response.server_error(&self.to_string()),
+            _ => response.client_error(&self.to_string()),
+        }
+    }
+}
+
 /// Storage layer errors
 #[derive(Error, Debug)]
 pub enum StorageError {
@@ -84,6 +133,68 @@ pub enum StorageError {
 
     #[error("Numeric underflow")]
     NumericUnderflow,
+
+    #[error("read-only")]
+    ReadOnly,
+
+    #[error("out of memory storing object")]
+    Full,
+
+    #[error("unknown namespace: {0}")]
+    UnknownNamespace(String),
+
+    #[error("namespace quota exceeded")]
+    QuotaExceeded,
 }
 
 pub type Result<T> = std::result::Result<T, PetraCacheError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(error: &ProtocolError) -> Vec<u8> {
+        let mut response = ResponseWriter::new(256);
+        error.write_response(&mut response);
+        response.buffer().to_vec()
+    }
+
+    #[test]
+    fn test_protocol_error_responses() {
+        assert_eq!(
+            reply(&ProtocolError::UnknownCommand("bogus".into())),
+            b"ERROR\r\n"
+        );
+        assert_eq!(
+            reply(&ProtocolError::UnknownCommand(String::new())),
+            b"ERROR\r\n"
+        );
+
+        let cases = [
+            (
+                ProtocolError::InvalidCommand("missing key".into()),
+                "Invalid command: missing key",
+            ),
+            (ProtocolError::InvalidKey("a b".into()), "Invalid key: a b"),
+            (ProtocolError::InvalidValue("x".into()), "Invalid value: x"),
+            (ProtocolError::InvalidFlags, "Invalid flags"),
+            (ProtocolError::InvalidExptime, "Invalid exptime"),
+            (ProtocolError::InvalidBytesLength, "Invalid bytes length"),
+            (ProtocolError::InvalidCasUnique, "Invalid cas unique"),
+            (ProtocolError::InvalidNumericValue, "Invalid numeric value"),
+            (ProtocolError::InvalidMetaFlag('x'), "Invalid meta flag: x"),
+            (ProtocolError::InvalidBase64Key, "bad base64 key"),
+            (ProtocolError::KeyTooLong, "Key too long (max 250 bytes)"),
+            (ProtocolError::TooManyKeys, "too many keys"),
+            (ProtocolError::LineTooLong, "line too long"),
+            (ProtocolError::BadDataChunk { discard: 9 }, "bad data chunk"),
+            (ProtocolError::IncompleteCommand, "Incomplete command"),
+        ];
+        for (error, message) in cases {
+            assert_eq!(
+                reply(&error),
+                format!("CLIENT_ERROR {message}\r\n").as_bytes()
+            );
+        }
+    }
+}
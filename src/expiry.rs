@@ -0,0 +1,231 @@
+//! Background expiry scan, and the periodic count of expired keys removed
+//!
+//! Lazy expiration only runs when a key is read, and the compaction filter
+//! only when compaction reaches the key's file, so short-TTL items that are
+//! never read again can hold space for a long time. With
+//! `expiry_scan_interval_ms` set, this task walks the keyspace in bounded
+//! slices (`expiry_scan_batch_keys` keys or `expiry_scan_budget_ms` per
+//! tick), deleting expired and flushed items, and starts over once it
+//! reaches the end.
+
+use crate::config::StorageConfig;
+use crate::metrics::Metrics;
+use crate::storage::{RocksStorage, ScanCursor};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// Scan until `cancel` fires; returns immediately if the scan is disabled
+/// or `storage` is a read-only secondary
+pub async fn run(
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    config: &StorageConfig,
+    cancel: CancellationToken,
+) {
+    if config.expiry_scan_interval_ms == 0 || storage.is_read_only() {
+        return;
+    }
+    info!(
+        interval_ms = config.expiry_scan_interval_ms,
+        batch_keys = config.expiry_scan_batch_keys,
+        budget_ms = config.expiry_scan_budget_ms,
+        "Background expiry scan enabled"
+    );
+    let budget = Duration::from_millis(config.expiry_scan_budget_ms);
+    let max_keys = config.expiry_scan_batch_keys.max(1);
+    let mut ticker = tokio::time::interval(Duration::from_millis(config.expiry_scan_interval_ms));
+    // A slow slice shouldn't be followed by a burst of catch-up ticks
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut resume_from: Option<ScanCursor> = None;
+    let (mut pass_scanned, mut pass_removed) = (0u64, 0u64);
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let task_storage = Arc::clone(&storage);
+        let from = resume_from.take();
+        let slice = tokio::task::spawn_blocking(move || {
+            task_storage.scan_expired(from.as_ref(), max_keys, Instant::now() + budget)
+        })
+        .await;
+        let scan = match slice {
+            Ok(Ok(scan)) => scan,
+            Ok(Err(e)) => {
+                // Start the pass over rather than retrying a broken key forever
+                metrics.storage_errors.inc();
+                error!("Expiry scan failed: {}", e);
+                (pass_scanned, pass_removed) = (0, 0);
+                continue;
+            }
+            Err(e) => {
+                error!("Expiry scan task failed: {}", e);
+                (pass_scanned, pass_removed) = (0, 0);
+                continue;
+            }
+        };
+
+        metrics.expiry_scan_keys_scanned.inc_by(scan.scanned);
+        metrics.expiry_scan_keys_removed.inc_by(scan.removed);
+        pass_scanned += scan.scanned;
+        pass_removed += scan.removed;
+        resume_from = scan.resume_from;
+
+        if resume_from.is_none() {
+            metrics.expiry_scan_passes.inc();
+            metrics
+                .expiry_scan_last_pass_scanned
+                .set(i64::try_from(pass_scanned).unwrap_or(i64::MAX));
+            metrics
+                .expiry_scan_last_pass_removed
+                .set(i64::try_from(pass_removed).unwrap_or(i64::MAX));
+            debug!(
+                scanned = pass_scanned,
+                removed = pass_removed,
+                "Expiry scan pass complete"
+            );
+            (pass_scanned, pass_removed) = (0, 0);
+        }
+    }
+    debug!("Expiry scan stopped");
+}
+
+/// Log how many expired keys were removed every
+/// `expired_log_interval_secs`, until `cancel` fires; the per-key entries
+/// are only sampled (see `expired_log_sample_rate`)
+pub async fn report(storage: Arc<RocksStorage>, config: &StorageConfig, cancel: CancellationToken) {
+    if config.expired_log_interval_secs == 0 {
+        return;
+    }
+    let interval = Duration::from_secs(config.expired_log_interval_secs);
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last = storage.ttl_stats().expired_removed;
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+        let removed = storage.ttl_stats().expired_removed;
+        if removed > last {
+            info!(
+                removed = removed - last,
+                interval_secs = config.expired_log_interval_secs,
+                "Removed {} expired keys in the last {}s",
+                removed - last,
+                config.expired_log_interval_secs
+            );
+        }
+        last = removed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StoredValue;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_scan_removes_expired_keys_in_slices() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            expiry_scan_interval_ms: 1,
+            expiry_scan_batch_keys: 3,
+            ..StorageConfig::default()
+        };
+        let storage = Arc::new(RocksStorage::open(&config).unwrap());
+        for i in 0..10 {
+            let key = format!("key{i}");
+            let value = if i % 2 == 0 {
+                StoredValue::with_expire_at(0, 1, b"expired".to_vec())
+            } else {
+                StoredValue::new(0, 0, b"live".to_vec())
+            };
+            storage.set(key.as_bytes(), value).unwrap();
+        }
+
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn({
+            let (storage, metrics, cancel) =
+                (Arc::clone(&storage), Arc::clone(&metrics), cancel.clone());
+            async move { run(storage, metrics, &config, cancel).await }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.expiry_scan_passes.get() == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        cancel.cancel();
+        task.await.unwrap();
+
+        // The first pass walked all 10 keys in 3-key slices; later passes
+        // may have started over on the 5 survivors
+        assert!(metrics.expiry_scan_keys_scanned.get() >= 10);
+        assert_eq!(metrics.expiry_scan_keys_removed.get(), 5);
+        assert!(matches!(
+            metrics.expiry_scan_last_pass_scanned.get(),
+            5 | 10
+        ));
+        assert_eq!(storage.sample_items(100).unwrap().sampled, 5);
+        for i in (1..10).step_by(2) {
+            assert!(storage.get(format!("key{i}").as_bytes()).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_scan_slice_resumes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        for key in [b"a", b"b", b"c"] {
+            storage
+                .set(key, StoredValue::with_expire_at(0, 1, b"x".to_vec()))
+                .unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(60);
+
+        let first = storage.scan_expired(None, 2, deadline).unwrap();
+        assert_eq!((first.scanned, first.removed), (2, 2));
+        assert_eq!(
+            first.resume_from,
+            Some(ScanCursor {
+                shard: 0,
+                key: b"c".to_vec()
+            })
+        );
+
+        let rest = storage
+            .scan_expired(first.resume_from.as_ref(), 2, deadline)
+            .unwrap();
+        assert_eq!((rest.scanned, rest.removed), (1, 1));
+        assert!(rest.resume_from.is_none());
+
+        // An exhausted budget still inspects one key
+        storage
+            .set(b"d", StoredValue::new(0, 0, b"x".to_vec()))
+            .unwrap();
+        storage
+            .set(b"e", StoredValue::new(0, 0, b"x".to_vec()))
+            .unwrap();
+        let slice = storage.scan_expired(None, 2, Instant::now()).unwrap();
+        assert_eq!((slice.scanned, slice.removed), (1, 0));
+        assert_eq!(
+            slice.resume_from.map(|cursor| cursor.key),
+            Some(b"e".to_vec())
+        );
+    }
+}
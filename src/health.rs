@@ -1,18 +1,147 @@
-//! Simple HTTP health and metrics server (synchronous)
+//! HTTP health, metrics and admin server
+//!
+//! Runs on the tokio runtime: every connection is served by a task of its
+//! own, with HTTP/1.1 keep-alive and HEAD support, so a slow `/metrics`
+//! scrape or admin task never delays a liveness probe. Anything touching
+//! RocksDB runs on a blocking thread.
 
+use crate::StorageError;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::backup::{BackupRunner, CheckpointError};
+use crate::compaction::Compactor;
 use crate::config::MetricsConfig;
+use crate::ingest::{IngestError, Ingester};
+use crate::logging::LogLevelHandle;
 use crate::metrics::Metrics;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
+use crate::server::{ConnSnapshot, ConnectionRegistry};
+use crate::storage::RocksStorage;
+use crate::storage_health::StorageHealth;
+use parking_lot::Mutex;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use tracing::{error, info};
+use std::time::{Duration, Instant};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// `POST /admin/...` operations, and `PUT /admin/loglevel`
+#[derive(Debug, Clone)]
+enum AdminTask {
+    Compact,
+    Backup,
+    /// Requested checkpoint path, not validated yet
+    Checkpoint(PathBuf),
+    /// Requested SST file or directory, not validated yet
+    Ingest(PathBuf),
+    /// Namespace to drop every item of
+    FlushNamespace(String),
+    /// Turn the memcached port's read-only mode on or off
+    SetReadOnly(bool),
+    /// Log filter directives to switch to
+    SetLogLevel(String),
+    /// RocksDB option names and values to apply
+    SetRocksDbOptions(Vec<(String, String)>),
+}
+
+impl AdminTask {
+    /// The HTTP method the task is requested with
+    fn method(&self) -> &'static str {
+        match self {
+            AdminTask::SetLogLevel(_) => "PUT",
+            _ => "POST",
+        }
+    }
+}
 
 /// Health server state
 pub struct HealthServer {
     metrics: Arc<Metrics>,
     ready: Arc<AtomicBool>,
-    running: Arc<AtomicBool>,
+    /// Adds RocksDB database and memory gauges to `/metrics`, and serves
+    /// `POST /admin/flush_namespace`, when set
+    storage: Option<Arc<RocksStorage>>,
+    /// Serves `POST /admin/compact` when set
+    compactor: Option<Arc<Compactor>>,
+    /// Serves `POST /admin/backup` and `/admin/checkpoint` when set
+    backups: Option<Arc<BackupRunner>>,
+    /// Serves `POST /admin/ingest` when set and enabled
+    ingester: Option<Arc<Ingester>>,
+    /// Fails `/ready` while unhealthy, when set
+    storage_health: Option<Arc<StorageHealth>>,
+    /// Storage checks `/ready` runs, when set along with `storage`
+    readiness_probe: Option<ReadinessProbe>,
+    /// Serves `GET /debug/connections` when set
+    connections: Option<Arc<ConnectionRegistry>>,
+    /// The memcached server's read-only flag; serves `POST /admin/readonly`
+    /// when set
+    read_only: Option<Arc<AtomicBool>>,
+    /// Serves `PUT /admin/loglevel` when set
+    log_level: Option<LogLevelHandle>,
+    /// Records every admin endpoint call, when set
+    audit: Option<Arc<AuditLog>>,
+}
+
+/// Storage operations behind `/ready` (see `with_readiness_probe`)
+struct ReadinessProbe {
+    write_check: bool,
+    /// Slower checks fail the probe (zero = no limit)
+    max_latency: Duration,
+    cache_for: Duration,
+    /// When the last probe ran, and the response body if it failed
+    last: Mutex<Option<(Instant, Option<String>)>>,
+}
+
+impl ReadinessProbe {
+    /// Check `storage`, or reuse a result younger than `cache_for`;
+    /// returns the response body on failure
+    fn check(&self, storage: &RocksStorage) -> Option<String> {
+        let mut last = self.last.lock();
+        if let Some((at, failure)) = &*last
+            && at.elapsed() < self.cache_for
+        {
+            return failure.clone();
+        }
+        let failure = self.run(storage);
+        *last = Some((Instant::now(), failure.clone()));
+        failure
+    }
+
+    fn run(&self, storage: &RocksStorage) -> Option<String> {
+        type Check = fn(&RocksStorage) -> Result<(), StorageError>;
+        let mut checks: Vec<(&str, Check)> = vec![("read", RocksStorage::probe_read)];
+        // A secondary can't write
+        if self.write_check && !storage.is_read_only() {
+            checks.push(("write", RocksStorage::probe_write));
+        }
+        for (name, check) in checks {
+            let started = Instant::now();
+            let result = check(storage);
+            let elapsed = started.elapsed();
+            let error = match result {
+                Err(e) => Some(e.to_string()),
+                Ok(()) if !self.max_latency.is_zero() && elapsed > self.max_latency => None,
+                Ok(()) => continue,
+            };
+            warn!(
+                check = name,
+                elapsed_ms = elapsed.as_millis(),
+                error = error.as_deref().unwrap_or("too slow"),
+                "Readiness probe failed"
+            );
+            return Some(probe_failure(
+                name,
+                elapsed,
+                error.as_deref(),
+                self.max_latency,
+            ));
+        }
+        None
+    }
 }
 
 impl HealthServer {
@@ -21,145 +150,782 @@ impl HealthServer {
         Self {
             metrics,
             ready: Arc::new(AtomicBool::new(false)),
-            running: Arc::new(AtomicBool::new(true)),
+            storage: None,
+            compactor: None,
+            backups: None,
+            ingester: None,
+            storage_health: None,
+            readiness_probe: None,
+            connections: None,
+            read_only: None,
+            log_level: None,
+            audit: None,
         }
     }
 
+    /// Report `storage`'s database and memory statistics on `/metrics`,
+    /// and flush its namespaces on request
+    #[must_use]
+    pub fn with_storage(mut self, storage: Arc<RocksStorage>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Serve `POST /admin/compact` with `compactor`
+    #[must_use]
+    pub fn with_compactor(mut self, compactor: Arc<Compactor>) -> Self {
+        self.compactor = Some(compactor);
+        self
+    }
+
+    /// Serve `POST /admin/backup` (and `POST /admin/checkpoint` if
+    /// enabled) with `backups`
+    #[must_use]
+    pub fn with_backups(mut self, backups: Arc<BackupRunner>) -> Self {
+        self.backups = Some(backups);
+        self
+    }
+
+    /// Serve `POST /admin/ingest` (if enabled) with `ingester`
+    #[must_use]
+    pub fn with_ingester(mut self, ingester: Arc<Ingester>) -> Self {
+        self.ingester = Some(ingester);
+        self
+    }
+
+    /// Report not ready while `storage_health` is unhealthy
+    #[must_use]
+    pub fn with_storage_health(mut self, storage_health: Arc<StorageHealth>) -> Self {
+        self.storage_health = Some(storage_health);
+        self
+    }
+
+    /// List the memcached port's open connections on `GET /debug/connections`
+    #[must_use]
+    pub fn with_connections(mut self, connections: Arc<ConnectionRegistry>) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    /// Toggle the memcached server's `read_only` flag on
+    /// `POST /admin/readonly?enabled=true|false`
+    #[must_use]
+    pub fn with_read_only(mut self, read_only: Arc<AtomicBool>) -> Self {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Change the log level on `PUT /admin/loglevel?level=...`
+    #[must_use]
+    pub fn with_log_level(mut self, log_level: LogLevelHandle) -> Self {
+        self.log_level = Some(log_level);
+        self
+    }
+
+    /// Record every `/admin/` request in `audit`, whatever its outcome
+    #[must_use]
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Check storage on `/ready` (needs `with_storage`): read a reserved
+    /// key, and write and delete it with `readiness_write_check`; an error,
+    /// or a check slower than `readiness_max_latency_ms`, fails the probe
+    #[must_use]
+    pub fn with_readiness_probe(mut self, config: &MetricsConfig) -> Self {
+        self.readiness_probe = Some(ReadinessProbe {
+            write_check: config.readiness_write_check,
+            max_latency: Duration::from_millis(config.readiness_max_latency_ms),
+            cache_for: Duration::from_millis(config.readiness_cache_ms),
+            last: Mutex::new(None),
+        });
+        self
+    }
+
     /// Set the ready state
     pub fn set_ready(&self, ready: bool) {
         self.ready.store(ready, Ordering::SeqCst);
     }
 
-    /// Check if the server is ready
+    /// Check if the server is ready: marked ready, and storage healthy
     pub fn is_ready(&self) -> bool {
-        self.ready.load(Ordering::SeqCst)
+        self.ready.load(Ordering::SeqCst) && self.is_storage_healthy()
     }
 
-    /// Stop the server
-    pub fn stop(&self) {
-        self.running.store(false, Ordering::SeqCst);
+    /// Status code and body for `/ready`
+    fn readiness(&self) -> (u16, String) {
+        if !self.is_storage_healthy() {
+            return (503, r#"{"status":"storage unhealthy"}"#.to_string());
+        }
+        if !self.is_ready() {
+            return (503, r#"{"status":"not ready"}"#.to_string());
+        }
+        if let (Some(probe), Some(storage)) = (&self.readiness_probe, &self.storage)
+            && let Some(failure) = probe.check(storage)
+        {
+            return (503, failure);
+        }
+        (200, r#"{"status":"ready"}"#.to_string())
+    }
+
+    fn is_storage_healthy(&self) -> bool {
+        self.storage_health
+            .as_ref()
+            .is_none_or(|health| health.is_healthy())
     }
 
-    /// Start the health server (blocking, run in separate thread)
-    pub fn run(self: Arc<Self>, config: &MetricsConfig) -> std::io::Result<()> {
-        let listener = TcpListener::bind(&config.listen_addr)?;
-        listener.set_nonblocking(true)?;
+    /// Serve HTTP on `config.listen_addr` until `cancel` fires
+    pub async fn run(
+        self: Arc<Self>,
+        config: &MetricsConfig,
+        cancel: CancellationToken,
+    ) -> std::io::Result<()> {
+        let listener = TcpListener::bind(&config.listen_addr).await?;
         info!("Health server listening on {}", config.listen_addr);
+        self.serve(listener, cancel).await;
+        Ok(())
+    }
+
+    /// Like `run`, on an already bound `listener`
+    pub async fn run_on(self: Arc<Self>, listener: TcpListener, cancel: CancellationToken) {
+        self.serve(listener, cancel).await;
+    }
 
-        while self.running.load(Ordering::SeqCst) {
-            match listener.accept() {
-                Ok((stream, _)) => {
-                    let server = Arc::clone(&self);
-                    // Handle in same thread (simple approach)
-                    if let Err(e) = server.handle_connection(stream) {
-                        error!("Health connection error: {}", e);
+    /// Accept connections on `listener`, each served by a task of its own
+    async fn serve(self: Arc<Self>, listener: TcpListener, cancel: CancellationToken) {
+        loop {
+            tokio::select! {
+                () = cancel.cancelled() => break,
+                result = listener.accept() => match result {
+                    Ok((stream, peer)) => {
+                        let server = Arc::clone(&self);
+                        let cancel = cancel.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = server.serve_connection(stream, peer, cancel).await {
+                                debug!("Health connection error: {}", e);
+                            }
+                        });
                     }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // No connection ready, sleep briefly
-                    std::thread::sleep(std::time::Duration::from_millis(100));
-                }
-                Err(e) => {
-                    error!("Health server accept error: {}", e);
-                }
+                    Err(e) => error!("Health server accept error: {}", e),
+                },
             }
         }
-
         info!("Health server stopped");
-        Ok(())
     }
 
-    /// Handle a single HTTP connection
-    fn handle_connection(&self, mut stream: TcpStream) -> std::io::Result<()> {
-        stream.set_nonblocking(false)?;
+    /// Answer requests on one connection until the client closes it, asks
+    /// for it to be closed, idles for `IDLE_TIMEOUT`, or `cancel` fires
+    async fn serve_connection(
+        self: Arc<Self>,
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        cancel: CancellationToken,
+    ) -> std::io::Result<()> {
+        let (read, mut write) = stream.split();
+        let mut reader = BufReader::new(read);
+        loop {
+            let request = tokio::select! {
+                () = cancel.cancelled() => return Ok(()),
+                request = tokio::time::timeout(IDLE_TIMEOUT, read_request(&mut reader)) => {
+                    match request {
+                        Ok(request) => request?,
+                        Err(_) => return Ok(()),
+                    }
+                }
+            };
+            let Some(request) = request else {
+                return Ok(());
+            };
+            let response = match &request.target {
+                Some((method, path)) => self.respond(method, path, &request.body, peer).await,
+                None => Response::text(400, "Bad Request"),
+            };
+            let keep_alive = request.keep_alive && !cancel.is_cancelled();
+            response
+                .write(&mut write, request.is_head(), keep_alive)
+                .await?;
+            if !keep_alive {
+                return Ok(());
+            }
+        }
+    }
 
-        let mut reader = BufReader::new(&stream);
-        let mut request_line = String::new();
-        reader.read_line(&mut request_line)?;
+    /// Route one request from `peer`; storage work runs on a blocking
+    /// thread, so health checks keep being answered meanwhile
+    async fn respond(
+        self: &Arc<Self>,
+        method: &str,
+        path: &str,
+        body: &str,
+        peer: SocketAddr,
+    ) -> Response {
+        if let Some(task) = path.strip_prefix("/admin/") {
+            let response = self.respond_admin(method, task, body, peer).await;
+            if let Some(audit) = &self.audit {
+                audit.record(&AuditEntry {
+                    peer: Some(peer),
+                    command: &format!("{method} {path}"),
+                    key: None,
+                    outcome: &response.status.to_string(),
+                });
+            }
+            return response;
+        }
 
-        // Parse simple HTTP request: "GET /path HTTP/1.1"
-        let parts: Vec<&str> = request_line.split_whitespace().collect();
-        if parts.len() < 2 {
-            return self.send_response(&mut stream, 400, "text/plain", "Bad Request");
+        if method != "GET" && method != "HEAD" {
+            return Response::text(405, "Method Not Allowed");
         }
 
-        let method = parts[0];
-        let path = parts[1];
+        let server = Arc::clone(self);
+        match path {
+            "/health" | "/healthz" => Response::json(200, r#"{"status":"healthy"}"#),
+            "/ready" | "/readyz" => {
+                blocking(move || {
+                    let (status, body) = server.readiness();
+                    Response::json(status, body)
+                })
+                .await
+            }
+            "/metrics" => {
+                blocking(move || {
+                    let metrics = match &server.storage {
+                        Some(storage) => server.metrics.gather_with_storage_stats(
+                            &storage.db_stats(),
+                            &storage.memory_usage(),
+                        ),
+                        None => server.metrics.gather(),
+                    };
+                    match metrics {
+                        Ok(metrics) => Response::new(200, "text/plain; version=0.0.4", metrics),
+                        Err(e) => {
+                            error!("Failed to encode metrics: {}", e);
+                            Response::text(500, "Internal Server Error")
+                        }
+                    }
+                })
+                .await
+            }
+            "/debug/connections" => match &server.connections {
+                Some(connections) => Response::json(200, connections_json(&connections.snapshot())),
+                None => Response::text(404, "Not Found"),
+            },
+            _ => Response::text(404, "Not Found"),
+        }
+    }
 
-        if method != "GET" {
-            return self.send_response(&mut stream, 405, "text/plain", "Method Not Allowed");
+    /// Run `/admin/{task}` on a blocking thread
+    async fn respond_admin(
+        self: &Arc<Self>,
+        method: &str,
+        task: &str,
+        body: &str,
+        peer: SocketAddr,
+    ) -> Response {
+        let task = match self.admin_task(task, body) {
+            Ok(task) => task,
+            Err(response) => return response,
+        };
+        if method != task.method() {
+            return Response::text(405, "Method Not Allowed");
         }
+        let server = Arc::clone(self);
+        blocking(move || {
+            let (status, body) = server.run_admin_task(task, peer);
+            Response::json(status, body)
+        })
+        .await
+    }
 
-        match path {
-            "/health" | "/healthz" => self.send_response(
-                &mut stream,
-                200,
-                "application/json",
-                r#"{"status":"healthy"}"#,
+    /// Parse `/admin/{task}` and its request body, or the response
+    /// refusing it
+    fn admin_task(&self, task: &str, body: &str) -> Result<AdminTask, Response> {
+        let (task, query) = task.split_once('?').unwrap_or((task, ""));
+        let checkpoints = self
+            .backups
+            .as_ref()
+            .is_some_and(|b| b.checkpoints_enabled());
+        let ingest = self.ingester.as_ref().is_some_and(|i| i.enabled());
+        let param = |name: &str| {
+            query_param(query, name)
+                .ok_or_else(|| Response::text(400, format!("Missing {name} parameter")))
+        };
+        Ok(match task {
+            "compact" if self.compactor.is_some() => AdminTask::Compact,
+            "backup" if self.backups.is_some() => AdminTask::Backup,
+            "checkpoint" if checkpoints => AdminTask::Checkpoint(PathBuf::from(param("path")?)),
+            "ingest" if ingest => AdminTask::Ingest(PathBuf::from(param("path")?)),
+            "flush_namespace" if self.storage.is_some() => AdminTask::FlushNamespace(param("ns")?),
+            "readonly" if self.read_only.is_some() => match param("enabled")?.as_str() {
+                "true" => AdminTask::SetReadOnly(true),
+                "false" => AdminTask::SetReadOnly(false),
+                _ => return Err(Response::text(400, "enabled must be true or false")),
+            },
+            "loglevel" if self.log_level.is_some() => AdminTask::SetLogLevel(param("level")?),
+            "rocksdb_options" if self.storage.is_some() => AdminTask::SetRocksDbOptions(
+                parse_options(body).map_err(|e| Response::text(400, e))?,
             ),
-            "/ready" | "/readyz" => {
-                if self.is_ready() {
-                    self.send_response(
-                        &mut stream,
+            _ => return Err(Response::text(404, "Not Found")),
+        })
+    }
+
+    /// Run `task`, requested by `peer`, to completion, returning the HTTP
+    /// status and JSON body
+    fn run_admin_task(&self, task: AdminTask, peer: SocketAddr) -> (u16, String) {
+        const ALREADY_RUNNING: &str = r#"{"status":"already running"}"#;
+        match task {
+            AdminTask::Compact => match self.compactor.as_ref().and_then(|c| c.compact()) {
+                Some(result) => (
+                    200,
+                    format!(
+                        r#"{{"status":"compacted","ttl_removed":{},"elapsed_ms":{}}}"#,
+                        result.ttl_removed,
+                        result.elapsed.as_millis()
+                    ),
+                ),
+                None => (409, ALREADY_RUNNING.to_string()),
+            },
+            AdminTask::Backup => match self.backups.as_ref().and_then(|b| b.backup()) {
+                Some(Ok(backup)) => (
+                    200,
+                    format!(
+                        r#"{{"status":"backed up","backup_id":{},"size":{},"elapsed_ms":{}}}"#,
+                        backup.info.backup_id,
+                        backup.info.size,
+                        backup.elapsed.as_millis()
+                    ),
+                ),
+                Some(Err(_)) => (500, r#"{"status":"backup failed"}"#.to_string()),
+                None => (409, ALREADY_RUNNING.to_string()),
+            },
+            AdminTask::Checkpoint(path) => {
+                let Some(backups) = &self.backups else {
+                    return (404, r#"{"status":"disabled"}"#.to_string());
+                };
+                match backups.checkpoint(&path) {
+                    Ok((dir, size)) => (
                         200,
-                        "application/json",
-                        r#"{"status":"ready"}"#,
-                    )
-                } else {
-                    self.send_response(
-                        &mut stream,
-                        503,
-                        "application/json",
-                        r#"{"status":"not ready"}"#,
-                    )
+                        format!(
+                            r#"{{"status":"checkpointed","path":"{}","size":{}}}"#,
+                            json_escape(&dir.to_string_lossy()),
+                            size
+                        ),
+                    ),
+                    Err(e) => {
+                        error!("Checkpoint failed: {}", e);
+                        let status = match e {
+                            CheckpointError::Disabled => 404,
+                            CheckpointError::InvalidPath(_) => 400,
+                            CheckpointError::Storage(_) => 500,
+                        };
+                        (
+                            status,
+                            format!(r#"{{"status":"{}"}}"#, json_escape(&e.to_string())),
+                        )
+                    }
                 }
             }
-            "/metrics" => {
-                let metrics = self.metrics.gather();
-                self.send_response(&mut stream, 200, "text/plain; version=0.0.4", &metrics)
+            AdminTask::Ingest(path) => {
+                let Some(ingester) = &self.ingester else {
+                    return (404, r#"{"status":"disabled"}"#.to_string());
+                };
+                match ingester.ingest(&path) {
+                    Ok(result) => (
+                        200,
+                        format!(
+                            r#"{{"status":"ingested","files":{},"keys":{},"elapsed_ms":{}}}"#,
+                            result.stats.files,
+                            result.stats.keys,
+                            result.elapsed.as_millis()
+                        ),
+                    ),
+                    Err(IngestError::AlreadyRunning) => (409, ALREADY_RUNNING.to_string()),
+                    Err(e) => {
+                        error!("Ingestion failed: {}", e);
+                        let status = match e {
+                            IngestError::Disabled => 404,
+                            IngestError::InvalidPath(_)
+                            | IngestError::Storage(StorageError::Decoding(_)) => 400,
+                            _ => 500,
+                        };
+                        (
+                            status,
+                            format!(r#"{{"status":"{}"}}"#, json_escape(&e.to_string())),
+                        )
+                    }
+                }
+            }
+            AdminTask::FlushNamespace(ns) => self.flush_namespace(&ns),
+            AdminTask::SetReadOnly(enabled) => self.set_read_only(enabled, peer),
+            AdminTask::SetLogLevel(level) => self.set_log_level(&level, peer),
+            AdminTask::SetRocksDbOptions(options) => self.set_rocksdb_options(&options, peer),
+        }
+    }
+
+    /// Turn read-only mode on or off, returning the HTTP status and JSON body
+    fn set_read_only(&self, enabled: bool, peer: SocketAddr) -> (u16, String) {
+        let Some(read_only) = &self.read_only else {
+            return (404, r#"{"status":"disabled"}"#.to_string());
+        };
+        let was = read_only.swap(enabled, Ordering::SeqCst);
+        self.metrics.read_only.set(i64::from(enabled));
+        if was != enabled {
+            let mode = if enabled { "enabled" } else { "disabled" };
+            warn!("Read-only mode {} by {}", mode, peer);
+        }
+        (200, format!(r#"{{"status":"ok","read_only":{enabled}}}"#))
+    }
+
+    /// Switch the log filter to `level`, returning the HTTP status and
+    /// JSON body
+    fn set_log_level(&self, level: &str, peer: SocketAddr) -> (u16, String) {
+        let Some(log_level) = &self.log_level else {
+            return (404, r#"{"status":"disabled"}"#.to_string());
+        };
+        let previous = log_level.current().unwrap_or_default();
+        if let Err(e) = log_level.set(level) {
+            return (400, format!(r#"{{"status":"{}"}}"#, json_escape(&e)));
+        }
+        // Logged at warn so the change shows up whatever the new level
+        warn!(
+            "Log level changed from {:?} to {:?} by {}",
+            previous, level, peer
+        );
+        (
+            200,
+            format!(
+                r#"{{"status":"ok","level":"{}","previous":"{}"}}"#,
+                json_escape(level),
+                json_escape(&previous)
+            ),
+        )
+    }
+
+    /// Apply RocksDB `options`, returning the HTTP status and JSON body:
+    /// 200 unless every option was rejected
+    fn set_rocksdb_options(&self, options: &[(String, String)], peer: SocketAddr) -> (u16, String) {
+        let Some(storage) = &self.storage else {
+            return (404, r#"{"status":"disabled"}"#.to_string());
+        };
+        let update = storage.set_options(options);
+        if !update.applied.is_empty() {
+            warn!("RocksDB options changed by {}", peer);
+        }
+        let object = |pairs: &[(String, String)]| {
+            let fields: Vec<String> = pairs
+                .iter()
+                .map(|(name, value)| format!(r#""{}":"{}""#, json_escape(name), json_escape(value)))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+        let status = if update.applied.is_empty() { 400 } else { 200 };
+        (
+            status,
+            format!(
+                r#"{{"status":"{}","applied":{},"rejected":{}}}"#,
+                if status == 200 { "ok" } else { "rejected" },
+                object(&update.applied),
+                object(&update.rejected)
+            ),
+        )
+    }
+
+    /// Flush namespace `ns`, returning the HTTP status and JSON body
+    fn flush_namespace(&self, ns: &str) -> (u16, String) {
+        let Some(storage) = &self.storage else {
+            return (404, r#"{"status":"disabled"}"#.to_string());
+        };
+        let started = std::time::Instant::now();
+        match storage.flush_namespace(ns) {
+            Ok(()) => (
+                200,
+                format!(
+                    r#"{{"status":"flushed","namespace":"{}","elapsed_ms":{}}}"#,
+                    json_escape(ns),
+                    started.elapsed().as_millis()
+                ),
+            ),
+            Err(e) => {
+                error!("Namespace flush failed: {}", e);
+                let status = match e {
+                    StorageError::UnknownNamespace(_) => 404,
+                    _ => 500,
+                };
+                (
+                    status,
+                    format!(r#"{{"status":"{}"}}"#, json_escape(&e.to_string())),
+                )
             }
-            _ => self.send_response(&mut stream, 404, "text/plain", "Not Found"),
         }
     }
+}
+
+/// Request line or header line length limit
+const MAX_LINE_BYTES: u64 = 8 * 1024;
+
+/// Request body length limit
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+/// Keep-alive connections without a request for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the server needs from one request
+struct Request {
+    /// Method and path, `None` for a malformed request line
+    target: Option<(String, String)>,
+    keep_alive: bool,
+    body: String,
+}
+
+impl Request {
+    fn is_head(&self) -> bool {
+        self.target
+            .as_ref()
+            .is_some_and(|(method, _)| method == "HEAD")
+    }
+}
+
+/// Read one request's line, headers and body; `None` once the client
+/// closed the connection
+async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> std::io::Result<Option<Request>> {
+    let mut line = String::new();
+    if read_line(reader, &mut line).await? == 0 {
+        return Ok(None);
+    }
+    // "GET /path HTTP/1.1"; HTTP/1.1 connections stay open by default
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let target = (parts.len() >= 2).then(|| (parts[0].to_string(), parts[1].to_string()));
+    let mut keep_alive = target.is_some() && parts.get(2) == Some(&"HTTP/1.1");
+
+    let mut body_len = 0;
+    loop {
+        line.clear();
+        if read_line(reader, &mut line).await? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") && target.is_some() {
+                keep_alive = true;
+            }
+        } else if name.eq_ignore_ascii_case("content-length") {
+            body_len = value.parse().map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, "bad Content-Length")
+            })?;
+        }
+    }
+    if body_len > MAX_BODY_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "request body too large",
+        ));
+    }
+    let mut body = Vec::new();
+    reader.take(body_len).read_to_end(&mut body).await?;
+    Ok(Some(Request {
+        target,
+        keep_alive,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }))
+}
+
+/// Read a line of at most `MAX_LINE_BYTES`
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> std::io::Result<usize> {
+    let n = reader.take(MAX_LINE_BYTES).read_line(line).await?;
+    if n as u64 == MAX_LINE_BYTES && !line.ends_with('\n') {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "request line too long",
+        ));
+    }
+    Ok(n)
+}
+
+/// An HTTP response
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "text/plain", body)
+    }
+
+    fn json(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "application/json", body)
+    }
 
-    /// Send HTTP response
-    fn send_response(
+    /// Send the response, without the body for a HEAD request
+    async fn write<W: AsyncWrite + Unpin>(
         &self,
-        stream: &mut TcpStream,
-        status: u16,
-        content_type: &str,
-        body: &str,
+        writer: &mut W,
+        head_only: bool,
+        keep_alive: bool,
     ) -> std::io::Result<()> {
-        let status_text = match status {
+        let status_text = match self.status {
             200 => "OK",
             400 => "Bad Request",
             404 => "Not Found",
             405 => "Method Not Allowed",
+            409 => "Conflict",
+            500 => "Internal Server Error",
             503 => "Service Unavailable",
             _ => "Unknown",
         };
-
-        let response = format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
-            status,
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+            self.status,
             status_text,
-            content_type,
-            body.len(),
-            body
+            self.content_type,
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
         );
+        writer.write_all(head.as_bytes()).await?;
+        if !head_only {
+            writer.write_all(self.body.as_bytes()).await?;
+        }
+        writer.flush().await
+    }
+}
 
-        stream.write_all(response.as_bytes())?;
-        stream.flush()
+/// Run `f` on a blocking thread
+async fn blocking(f: impl FnOnce() -> Response + Send + 'static) -> Response {
+    tokio::task::spawn_blocking(f).await.unwrap_or_else(|e| {
+        error!("Health request task failed: {}", e);
+        Response::text(500, "Internal Server Error")
+    })
+}
+
+/// A JSON object of option names to values (strings, numbers or
+/// booleans), sorted by name
+fn parse_options(body: &str) -> Result<Vec<(String, String)>, String> {
+    let object: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(body).map_err(|e| format!("Expected a JSON object: {e}"))?;
+    if object.is_empty() {
+        return Err("No options given".to_string());
+    }
+    object
+        .into_iter()
+        .map(|(name, value)| match value {
+            serde_json::Value::String(value) => Ok((name, value)),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                Ok((name, value.to_string()))
+            }
+            _ => Err(format!("{name}: expected a string, number or boolean")),
+        })
+        .collect()
+}
+
+/// Value of `name` in a URL query string, percent-decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    let value = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))?;
+    let mut decoded = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = std::str::from_utf8(&hex).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// `/ready` body for a failed readiness check: its error, or its latency
+/// over `max_latency`
+fn probe_failure(
+    check: &str,
+    elapsed: Duration,
+    error: Option<&str>,
+    max_latency: Duration,
+) -> String {
+    let elapsed_ms = elapsed.as_secs_f64() * 1000.0;
+    match error {
+        Some(error) => format!(
+            r#"{{"status":"not ready","check":"{check}","elapsed_ms":{elapsed_ms:.3},"error":"{}"}}"#,
+            json_escape(error)
+        ),
+        None => format!(
+            r#"{{"status":"not ready","check":"{check}","elapsed_ms":{elapsed_ms:.3},"max_latency_ms":{}}}"#,
+            max_latency.as_millis()
+        ),
+    }
+}
+
+/// `GET /debug/connections` body: `{"connections":[{"id":..,..}]}`
+fn connections_json(conns: &[ConnSnapshot]) -> String {
+    let entries: Vec<String> = conns
+        .iter()
+        .map(|conn| {
+            let last_command_at = conn
+                .last_command_at
+                .map_or_else(|| "null".to_string(), |at| at.to_string());
+            format!(
+                r#"{{"id":{},"addr":"{}","connected_at":{},"last_command_at":{},"commands":{},"bytes_read":{},"bytes_written":{},"state":"{}"}}"#,
+                conn.id,
+                json_escape(&conn.addr()),
+                conn.connected_at,
+                last_command_at,
+                conn.commands,
+                conn.bytes_read,
+                conn.bytes_written,
+                conn.state.as_str()
+            )
+        })
+        .collect();
+    format!(r#"{{"connections":[{}]}}"#, entries.join(","))
+}
+
+/// Escape `value` for use inside a JSON string
+pub(crate) fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
     }
+    escaped
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::server::ConnState;
 
     #[test]
     fn test_ready_state() {
-        let metrics = Arc::new(Metrics::new());
+        let metrics = Arc::new(Metrics::new().unwrap());
         let server = HealthServer::new(metrics);
 
         assert!(!server.is_ready());
@@ -168,4 +934,520 @@ mod tests {
         server.set_ready(false);
         assert!(!server.is_ready());
     }
+
+    #[tokio::test]
+    async fn test_stop_releases_port() {
+        let server = Arc::new(HealthServer::new(Arc::new(Metrics::new().unwrap())));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn(Arc::clone(&server).serve(listener, cancel.clone()));
+
+        // An idle keep-alive connection must not hold the server up
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut client = BufReader::new(client);
+        read_response(&mut client, false).await;
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("server did not stop")
+            .unwrap();
+        TcpListener::bind(addr).await.unwrap();
+        // The idle connection is closed too
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_end(&mut rest))
+            .await
+            .expect("connection not closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_while_storage_unhealthy() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let storage_health = Arc::new(StorageHealth::new());
+        let server = Arc::new(
+            HealthServer::new(Arc::clone(&metrics))
+                .with_storage_health(Arc::clone(&storage_health)),
+        );
+        server.set_ready(true);
+        let response = request(&server, "GET /ready HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+
+        let mut check = crate::storage::HealthCheck {
+            background_errors: 1,
+            write_error: Some("IO error: No space left on device".to_string()),
+        };
+        storage_health.record(&check, &metrics);
+        let response = request(&server, "GET /ready HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        assert!(response.contains("storage unhealthy"), "{response}");
+
+        check.write_error = None;
+        storage_health.record(&check, &metrics);
+        assert!(server.is_ready());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_readiness_probe() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let storage = crate::storage::RocksStorage::open(&crate::config::StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..Default::default()
+        })
+        .unwrap();
+        let config = MetricsConfig {
+            readiness_write_check: true,
+            ..MetricsConfig::default()
+        };
+        let server = Arc::new(
+            HealthServer::new(Arc::new(Metrics::new().unwrap()))
+                .with_storage(Arc::new(storage))
+                .with_readiness_probe(&config),
+        );
+        let response = request(&server, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 503"), "{response}");
+        server.set_ready(true);
+        let response = request(&server, "GET /readyz HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        // The probe key is not a client key
+        let storage = server.storage.as_ref().unwrap();
+        assert_eq!(storage.sample_items(10).unwrap().sampled, 0);
+
+        let body = probe_failure(
+            "write",
+            Duration::from_micros(1500),
+            Some("IO error: \"disk\""),
+            Duration::from_millis(100),
+        );
+        assert_eq!(
+            body,
+            r#"{"status":"not ready","check":"write","elapsed_ms":1.500,"error":"IO error: \"disk\""}"#
+        );
+        let body = probe_failure(
+            "read",
+            Duration::from_millis(250),
+            None,
+            Duration::from_millis(100),
+        );
+        assert_eq!(
+            body,
+            r#"{"status":"not ready","check":"read","elapsed_ms":250.000,"max_latency_ms":100}"#
+        );
+    }
+
+    /// Serve `server` on an ephemeral port
+    async fn serve(server: &Arc<HealthServer>) -> (std::net::SocketAddr, CancellationToken) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        tokio::spawn(Arc::clone(server).serve(listener, cancel.clone()));
+        (addr, cancel)
+    }
+
+    /// Read one response with a `Content-Length` body
+    async fn read_response<R: AsyncBufRead + Unpin>(reader: &mut R, head_only: bool) -> String {
+        let mut response = String::new();
+        let mut body_len = 0;
+        loop {
+            let mut line = String::new();
+            assert!(reader.read_line(&mut line).await.unwrap() > 0, "{response}");
+            if let Some(len) = line.strip_prefix("Content-Length: ") {
+                body_len = len.trim().parse().unwrap();
+            }
+            response.push_str(&line);
+            if line == "\r\n" {
+                break;
+            }
+        }
+        if !head_only {
+            let mut body = vec![0; body_len];
+            reader.read_exact(&mut body).await.unwrap();
+            response.push_str(&String::from_utf8(body).unwrap());
+        }
+        response
+    }
+
+    /// Send `request` to `server` on a fresh connection and return the raw
+    /// HTTP response
+    async fn request(server: &Arc<HealthServer>, request: &str) -> String {
+        let (addr, cancel) = serve(server).await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = read_response(&mut BufReader::new(client), false).await;
+        cancel.cancel();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_and_head() {
+        let server = Arc::new(HealthServer::new(Arc::new(Metrics::new().unwrap())));
+        server.set_ready(true);
+        let (addr, cancel) = serve(&server).await;
+        let mut client = BufReader::new(TcpStream::connect(addr).await.unwrap());
+
+        client
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut client, false).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("Connection: keep-alive"), "{response}");
+        assert!(response.ends_with(r#"{"status":"healthy"}"#), "{response}");
+
+        // Same connection; a HEAD response has the headers only
+        client
+            .write_all(b"HEAD /ready HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_response(&mut client, true).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains("Content-Length: 18\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\n"), "{response}");
+
+        client
+            .write_all(
+                b"POST /health HTTP/1.1\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc",
+            )
+            .await
+            .unwrap();
+        let response = read_response(&mut client, false).await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+        assert!(response.contains("Connection: close"), "{response}");
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        // HTTP/1.0 closes unless asked otherwise
+        let response = request(&server, "GET /healthz HTTP/1.0\r\n\r\n").await;
+        assert!(response.contains("Connection: close"), "{response}");
+        cancel.cancel();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_admin_compact() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let plain = Arc::new(HealthServer::new(Arc::clone(&metrics)));
+        let response = request(&plain, "POST /admin/compact HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let storage = crate::storage::RocksStorage::open(&crate::config::StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..Default::default()
+        })
+        .unwrap();
+        let compactor = Arc::new(Compactor::new(Arc::new(storage), Arc::clone(&metrics)));
+        let server = Arc::new(HealthServer::new(metrics).with_compactor(compactor));
+
+        let response = request(&server, "GET /admin/compact HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+
+        let response = request(&server, "POST /admin/compact HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains(r#""ttl_removed":0"#), "{response}");
+
+        // Not configured on this server
+        let response = request(&server, "POST /admin/backup HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_admin_backup() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let storage = crate::storage::RocksStorage::open(&crate::config::StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..Default::default()
+        })
+        .unwrap();
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let backups = Arc::new(BackupRunner::new(
+            Arc::new(storage),
+            Arc::clone(&metrics),
+            crate::config::BackupConfig {
+                dir: tmp_dir.path().join("backups"),
+                checkpoint_dir: Some(tmp_dir.path().join("checkpoints")),
+                ..Default::default()
+            },
+        ));
+        let server = Arc::new(HealthServer::new(metrics).with_backups(backups));
+
+        let response = request(&server, "POST /admin/backup HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains(r#""backup_id":1"#), "{response}");
+
+        let response = request(
+            &server,
+            "POST /admin/checkpoint?path=seed%2D1 HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.contains(r#"checkpoints/seed-1""#), "{response}");
+
+        for target in ["/admin/checkpoint", "/admin/checkpoint?path=..%2Fescape"] {
+            let response = request(&server, &format!("POST {target} HTTP/1.1\r\n\r\n")).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{target}: {response}");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_admin_flush_namespace() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let storage = Arc::new(
+            crate::storage::RocksStorage::open(&crate::config::StorageConfig {
+                db_path: tmp_dir.path().join("test_db"),
+                namespaces: vec![crate::config::NamespaceConfig::new("users")],
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        storage
+            .set(
+                b"users:1",
+                crate::storage::StoredValue::new(0, 0, b"v".to_vec()),
+            )
+            .unwrap();
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let server = Arc::new(HealthServer::new(metrics).with_storage(Arc::clone(&storage)));
+
+        let response = request(
+            &server,
+            "POST /admin/flush_namespace?ns=users HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(storage.get(b"users:1").unwrap().is_none());
+
+        let response = request(
+            &server,
+            "POST /admin/flush_namespace?ns=orders HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+        let response = request(&server, "POST /admin/flush_namespace HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_admin_rocksdb_options() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let storage = Arc::new(
+            crate::storage::RocksStorage::open(&crate::config::StorageConfig {
+                db_path: tmp_dir.path().join("test_db"),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let server = Arc::new(HealthServer::new(metrics).with_storage(Arc::clone(&storage)));
+        let post = |body: &str| {
+            format!(
+                "POST /admin/rocksdb_options HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            )
+        };
+
+        let response = request(
+            &server,
+            &post(r#"{"max_background_jobs":6,"write_buffer_size":"8388608","compression":"lz4"}"#),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(
+            response
+                .contains(r#""applied":{"max_background_jobs":"6","write_buffer_size":"8388608"}"#),
+            "{response}"
+        );
+        assert!(
+            response.contains(r#""rejected":{"compression":"#),
+            "{response}"
+        );
+        assert_eq!(storage.settings().max_background_jobs, 6);
+
+        let response = request(&server, &post(r#"{"compression":"lz4"}"#)).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        for body in ["", "[1]", "{}", r#"{"write_buffer_size":null}"#] {
+            let response = request(&server, &post(body)).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{body}: {response}");
+        }
+        let get = post(r#"{"max_background_jobs":4}"#).replacen("POST", "GET", 1);
+        let response = request(&server, &get).await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+    }
+
+    #[test]
+    fn test_query_param() {
+        assert_eq!(
+            query_param("a=1&path=%2Fdata%2Fsnap+1", "path").as_deref(),
+            Some("/data/snap 1")
+        );
+        assert_eq!(query_param("paths=x", "path"), None);
+        assert_eq!(query_param("path=%zz", "path"), None);
+        assert_eq!(json_escape(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+
+    #[tokio::test]
+    async fn test_admin_readonly() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let server = Arc::new(HealthServer::new(Arc::clone(&metrics)));
+        let response = request(
+            &server,
+            "POST /admin/readonly?enabled=true HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        let read_only = Arc::new(AtomicBool::new(false));
+        let server = Arc::new(
+            HealthServer::new(Arc::clone(&metrics)).with_read_only(Arc::clone(&read_only)),
+        );
+        let response = request(
+            &server,
+            "POST /admin/readonly?enabled=true HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(
+            response.ends_with(r#"{"status":"ok","read_only":true}"#),
+            "{response}"
+        );
+        assert!(read_only.load(Ordering::SeqCst));
+        assert_eq!(metrics.read_only.get(), 1);
+
+        let response = request(
+            &server,
+            "POST /admin/readonly?enabled=false HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(!read_only.load(Ordering::SeqCst));
+        assert_eq!(metrics.read_only.get(), 0);
+
+        for target in ["/admin/readonly", "/admin/readonly?enabled=yes"] {
+            let response = request(&server, &format!("POST {target} HTTP/1.1\r\n\r\n")).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        }
+        let response = request(&server, "GET /admin/readonly?enabled=true HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+        assert!(!read_only.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_admin_loglevel() {
+        let (_filter, log_level) = crate::logging::detached("info");
+        let server = Arc::new(
+            HealthServer::new(Arc::new(Metrics::new().unwrap())).with_log_level(log_level.clone()),
+        );
+        let response = request(&server, "PUT /admin/loglevel?level=debug HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(
+            response.ends_with(r#"{"status":"ok","level":"debug","previous":"info"}"#),
+            "{response}"
+        );
+        assert_eq!(log_level.current().as_deref(), Some("debug"));
+
+        // Full directives, percent-encoded
+        let response = request(
+            &server,
+            "PUT /admin/loglevel?level=info%2Cpetracache%3A%3Aserver%3Dtrace HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(
+            log_level
+                .current()
+                .unwrap()
+                .contains("petracache::server=trace")
+        );
+
+        for target in ["/admin/loglevel", "/admin/loglevel?level=petracache%3Dloud"] {
+            let response = request(&server, &format!("PUT {target} HTTP/1.1\r\n\r\n")).await;
+            assert!(response.starts_with("HTTP/1.1 400"), "{response}");
+        }
+        let response = request(&server, "POST /admin/loglevel?level=warn HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 405"), "{response}");
+        assert!(
+            log_level
+                .current()
+                .unwrap()
+                .contains("petracache::server=trace")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_calls_audited() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let path = dir.path().join("audit.jsonl");
+        let config = crate::config::AuditConfig {
+            path: Some(path.clone()),
+            ..crate::config::AuditConfig::default()
+        };
+        let audit = AuditLog::open(&config, Arc::clone(&metrics))
+            .unwrap()
+            .unwrap();
+        let server = Arc::new(
+            HealthServer::new(Arc::clone(&metrics))
+                .with_read_only(Arc::new(AtomicBool::new(false)))
+                .with_audit_log(Arc::new(audit)),
+        );
+        request(
+            &server,
+            "POST /admin/readonly?enabled=true HTTP/1.1\r\n\r\n",
+        )
+        .await;
+        request(&server, "POST /admin/compact HTTP/1.1\r\n\r\n").await;
+        request(&server, "GET /health HTTP/1.1\r\n\r\n").await;
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while metrics.audit_entries.get() < 2 {
+            assert!(Instant::now() < deadline);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let log = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<&str> = log.lines().collect();
+        assert_eq!(entries.len(), 2, "{log}");
+        assert!(entries[0].contains(r#""peer":"127.0.0.1:"#));
+        assert!(entries[0].contains(
+            r#""command":"POST /admin/readonly?enabled=true","key":null,"outcome":"200""#
+        ));
+        // Refused calls are recorded too
+        assert!(
+            entries[1].contains(r#""command":"POST /admin/compact","key":null,"outcome":"404""#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_debug_connections() {
+        let server = Arc::new(HealthServer::new(Arc::new(Metrics::new().unwrap())));
+        let response = request(&server, "GET /debug/connections HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        let conns = [ConnSnapshot {
+            id: 7,
+            peer: Some("10.0.0.1:5000".parse().unwrap()),
+            connected_at: 1_700_000_000,
+            commands: 3,
+            bytes_read: 40,
+            bytes_written: 12,
+            last_command_at: None,
+            state: ConnState::Waiting,
+        }];
+        assert_eq!(
+            connections_json(&conns),
+            r#"{"connections":[{"id":7,"addr":"tcp:10.0.0.1:5000","connected_at":1700000000,"last_command_at":null,"commands":3,"bytes_read":40,"bytes_written":12,"state":"conn_waiting"}]}"#
+        );
+
+        let server = Arc::new(
+            HealthServer::new(Arc::new(Metrics::new().unwrap()))
+                .with_connections(Arc::new(ConnectionRegistry::new())),
+        );
+        let response = request(&server, "GET /debug/connections HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with(r#"{"connections":[]}"#), "{response}");
+    }
 }
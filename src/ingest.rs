@@ -0,0 +1,222 @@
+//! Bulk loading of precomputed SST files
+//!
+//! Replaying hundreds of millions of offline-computed entries as sets takes
+//! hours; writing them with `storage::sst_writer::SstBuilder` and ingesting
+//! the files takes minutes. `POST /admin/ingest?path=...` ingests one `.sst`
+//! file, or every `.sst` file in a directory, from under
+//! `storage.ingest_dir`.
+
+use crate::StorageError;
+use crate::storage::{IngestStats, RocksStorage};
+use parking_lot::Mutex;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Ingests SST files, one request at a time
+pub struct Ingester {
+    storage: Arc<RocksStorage>,
+    /// Files may only be ingested from under this directory
+    dir: Option<PathBuf>,
+    /// Held while an ingestion runs
+    running: Mutex<()>,
+}
+
+/// Outcome of one ingestion
+#[derive(Debug, Clone, Copy)]
+pub struct IngestResult {
+    pub stats: IngestStats,
+    pub elapsed: Duration,
+}
+
+/// Why `Ingester::ingest` failed
+#[derive(Debug, thiserror::Error)]
+pub enum IngestError {
+    #[error("ingestion is disabled (storage.ingest_dir is not set)")]
+    Disabled,
+
+    #[error("invalid ingest path: {0}")]
+    InvalidPath(String),
+
+    #[error("an ingestion is already running")]
+    AlreadyRunning,
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+impl Ingester {
+    pub fn new(storage: Arc<RocksStorage>) -> Self {
+        let dir = storage.config().ingest_dir.clone();
+        Self {
+            storage,
+            dir,
+            running: Mutex::new(()),
+        }
+    }
+
+    /// Whether `ingest` is allowed at all (`storage.ingest_dir` set)
+    pub fn enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Validate and ingest the SST file or directory of `.sst` files at
+    /// `requested`, relative to `storage.ingest_dir` or absolute inside it
+    ///
+    /// Blocks on I/O; call it from a blocking thread.
+    pub fn ingest(&self, requested: &Path) -> Result<IngestResult, IngestError> {
+        let base = self.dir.as_deref().ok_or(IngestError::Disabled)?;
+        let _running = self.running.try_lock().ok_or(IngestError::AlreadyRunning)?;
+        let started = Instant::now();
+        let files = resolve_ingest_files(base, requested)?;
+        let stats = self.storage.ingest_sst_files(&files)?;
+        Ok(IngestResult {
+            stats,
+            elapsed: started.elapsed(),
+        })
+    }
+}
+
+/// Resolve `requested` to the SST files to ingest: the file itself, or the
+/// `.sst` files directly in the directory, by name
+///
+/// The path is canonicalized, so neither `..` nor a symlink can lead
+/// outside `base`.
+fn resolve_ingest_files(base: &Path, requested: &Path) -> Result<Vec<PathBuf>, IngestError> {
+    let invalid =
+        |reason: &str| IngestError::InvalidPath(format!("{}: {reason}", requested.display()));
+    if requested.components().any(|c| c == Component::ParentDir) {
+        return Err(invalid("\"..\" is not allowed"));
+    }
+    let base = base
+        .canonicalize()
+        .map_err(|e| invalid(&format!("ingest_dir unusable: {e}")))?;
+    let path = base
+        .join(requested)
+        .canonicalize()
+        .map_err(|e| invalid(&e.to_string()))?;
+    if !path.starts_with(&base) {
+        return Err(invalid("outside ingest_dir"));
+    }
+    if path.is_file() {
+        return Ok(vec![path]);
+    }
+
+    let mut files = std::fs::read_dir(&path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .map_err(|e| invalid(&e.to_string()))?;
+    files.retain(|file| file.extension().is_some_and(|ext| ext == "sst") && file.is_file());
+    if files.is_empty() {
+        return Err(invalid("no .sst files"));
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use crate::storage::StoredValue;
+    use crate::storage::sst_writer::SstBuilder;
+    use tempfile::TempDir;
+
+    fn ingester(tmp_dir: &TempDir) -> Ingester {
+        let ingest_dir = tmp_dir.path().join("ingest");
+        std::fs::create_dir_all(&ingest_dir).unwrap();
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ingest_dir: Some(ingest_dir),
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        Ingester::new(Arc::new(storage))
+    }
+
+    fn write_sst(path: &Path, keys: std::ops::Range<u32>) {
+        let mut builder = SstBuilder::create(path).unwrap();
+        for i in keys {
+            let value = StoredValue::new(3, 0, format!("value{i}").into_bytes());
+            builder
+                .add(format!("key{i:05}").as_bytes(), &value)
+                .unwrap();
+        }
+        builder.finish().unwrap();
+    }
+
+    #[test]
+    fn test_ingest_directory() {
+        let tmp_dir = TempDir::new().unwrap();
+        let ingester = ingester(&tmp_dir);
+        let batch = tmp_dir.path().join("ingest/batch");
+        std::fs::create_dir(&batch).unwrap();
+        write_sst(&batch.join("000.sst"), 0..100);
+        write_sst(&batch.join("001.sst"), 100..250);
+        std::fs::write(batch.join("README"), "not an sst").unwrap();
+
+        ingester
+            .storage
+            .set(b"key00007", StoredValue::new(0, 0, b"old".to_vec()))
+            .unwrap();
+        let result = ingester.ingest(Path::new("batch")).unwrap();
+        assert_eq!((result.stats.files, result.stats.keys), (2, 250));
+
+        let value = ingester.storage.get(b"key00007").unwrap().unwrap();
+        assert_eq!(
+            (value.flags, value.data.as_slice()),
+            (3, b"value7".as_slice())
+        );
+        assert!(ingester.storage.get(b"key00249").unwrap().is_some());
+
+        // Items ingested before a flush_all are flushed by it
+        ingester.storage.flush_all(0).unwrap();
+        assert!(ingester.storage.get(b"key00100").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_ingest_rejects_bad_files() {
+        let tmp_dir = TempDir::new().unwrap();
+        let ingester = ingester(&tmp_dir);
+        let dir = tmp_dir.path().join("ingest");
+        std::fs::write(dir.join("garbage.sst"), "definitely not an sst").unwrap();
+        assert!(matches!(
+            ingester.ingest(Path::new("garbage.sst")),
+            Err(IngestError::Storage(_))
+        ));
+        assert_eq!(ingester.storage.sample_items(10).unwrap().sampled, 0);
+    }
+
+    #[test]
+    fn test_ingest_paths_stay_inside_ingest_dir() {
+        let tmp_dir = TempDir::new().unwrap();
+        let ingester = ingester(&tmp_dir);
+        write_sst(&tmp_dir.path().join("outside.sst"), 0..1);
+        for path in ["../outside.sst", "missing.sst", ""] {
+            assert!(
+                matches!(
+                    ingester.ingest(Path::new(path)),
+                    Err(IngestError::InvalidPath(_))
+                ),
+                "{path:?}"
+            );
+        }
+        let absolute = tmp_dir.path().join("outside.sst");
+        assert!(matches!(
+            ingester.ingest(&absolute),
+            Err(IngestError::InvalidPath(_))
+        ));
+
+        let disabled = Ingester {
+            dir: None,
+            ..ingester
+        };
+        assert!(matches!(
+            disabled.ingest(Path::new("x.sst")),
+            Err(IngestError::Disabled)
+        ));
+    }
+}
@@ -36,13 +36,27 @@
 //! ```
 
 // Modules
+pub mod audit;
+pub mod backup;
+pub mod client;
+pub mod compaction;
 pub mod config;
+pub mod disk_limit;
 pub mod error;
+pub mod expiry;
 pub mod health;
+pub mod ingest;
+pub mod logging;
 pub mod metrics;
 pub mod protocol;
+pub mod reload;
+pub mod replica;
 pub mod server;
 pub mod storage;
+pub mod storage_health;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod upstream;
 
 // Re-exports for convenience
 pub use error::{PetraCacheError, ProtocolError, Result, StorageError};
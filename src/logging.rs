@@ -0,0 +1,66 @@
+//! Log output (`[log]`) and the runtime log level
+//!
+//! The subscriber is built once at startup from `log.format` and
+//! `log.level` (`RUST_LOG` wins when set, `--log-level` over both). The
+//! level filter sits behind a `reload` layer so `PUT /admin/loglevel` can
+//! swap it without a restart.
+
+use crate::config::{LogConfig, LogFormat};
+use crate::{PetraCacheError, Result};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, fmt, reload};
+
+/// Changes the level filter of the installed subscriber
+#[derive(Clone)]
+pub struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevelHandle {
+    /// Replace the filter with `directives` (`debug`,
+    /// `info,petracache::server=trace`, ...); returns the error message if
+    /// they don't parse
+    pub fn set(&self, directives: &str) -> std::result::Result<(), String> {
+        let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())
+    }
+
+    /// The filter in effect, as directives
+    pub fn current(&self) -> Option<String> {
+        self.handle.with_current(ToString::to_string).ok()
+    }
+}
+
+/// Install the global subscriber described by `config`; `level` (from the
+/// command line) wins over `RUST_LOG`, which wins over `config.level`
+pub fn init(config: &LogConfig, level: Option<&str>) -> Result<LogLevelHandle> {
+    let filter = match (level, EnvFilter::try_from_default_env()) {
+        (None, Ok(filter)) => filter,
+        (Some(level), _) => parse_level(level)?,
+        (None, Err(_)) => parse_level(&config.level)?,
+    };
+    let (filter, handle) = reload::Layer::new(filter);
+    let json = config.format == LogFormat::Json;
+    tracing_subscriber::registry()
+        .with(filter)
+        .with((!json).then(fmt::layer))
+        .with(json.then(|| fmt::layer().json().flatten_event(true)))
+        .try_init()
+        .map_err(|e| PetraCacheError::Config(format!("Failed to initialize logging: {e}")))?;
+    Ok(LogLevelHandle { handle })
+}
+
+/// The filter for `directives`
+fn parse_level(directives: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(directives)
+        .map_err(|e| PetraCacheError::Config(format!("Invalid log level {directives:?}: {e}")))
+}
+
+/// A handle to a filter that isn't installed, kept alive by the returned
+/// layer
+#[cfg(test)]
+pub(crate) fn detached(directives: &str) -> (reload::Layer<EnvFilter, Registry>, LogLevelHandle) {
+    let (layer, handle) = reload::Layer::new(EnvFilter::new(directives));
+    (layer, LogLevelHandle { handle })
+}
@@ -11,35 +11,63 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-use petracache::config::Config;
+use clap::Parser;
+use petracache::audit::AuditLog;
+use petracache::backup::{self, BackupRunner};
+use petracache::compaction::{self, Compactor};
+use petracache::config::{self, Args, Config, MetricsConfig, StorageConfig, Wal};
+use petracache::disk_limit;
+use petracache::expiry;
 use petracache::health::HealthServer;
+use petracache::ingest::Ingester;
+use petracache::logging::{self, LogLevelHandle};
 use petracache::metrics::Metrics;
-use petracache::server::Server;
+use petracache::reload::Reloader;
+use petracache::replica;
+use petracache::server::{ConnectionRegistry, Server};
 use petracache::storage::RocksStorage;
+use petracache::storage_health::{self, StorageHealth};
+use petracache::upstream::Upstream;
+use prometheus::Registry;
 use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 use tokio::runtime::Builder;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
-use tracing_subscriber::EnvFilter;
+use tracing::{error, info, warn};
+
+/// Upper bound on the memtable flush during shutdown
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
 
 fn main() -> anyhow::Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
-        )
-        .init();
+    let args = Args::parse();
+    let config = config::load(&args)?;
+    if args.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+    // Every violation at once, before the database is opened
+    if let Err(errors) = config.validate() {
+        eprintln!("Invalid configuration:");
+        for error in &errors {
+            eprintln!("  {error}");
+        }
+        std::process::exit(1);
+    }
+    if args.check_config {
+        println!("Configuration OK");
+        return Ok(());
+    }
 
-    info!("Starting PetraCache");
+    // Initialize tracing; the handle serves PUT /admin/loglevel
+    let log_level = logging::init(&config.log, args.log_level.as_deref())?;
 
-    // Load configuration
-    let config = if let Some(config_path) = std::env::args().nth(1) {
-        info!("Loading configuration from {}", config_path);
-        Config::from_file(&config_path)?
-    } else {
-        info!("Using default configuration (set PETRACACHE_* env vars to customize)");
-        Config::from_env()
-    };
+    info!("Starting PetraCache");
+    match args.config_file() {
+        Some(path) => info!("Loaded configuration from {:?}", path),
+        None => info!("Using default configuration (set PETRACACHE_* env vars to customize)"),
+    }
 
     info!("Configuration: {:?}", config);
 
@@ -51,49 +79,110 @@ fn main() -> anyhow::Result<()> {
     } else {
         info!("Using default worker threads (auto-detected)");
     }
+    if config.server.storage_threads > 0 {
+        runtime_builder.max_blocking_threads(config.server.storage_threads);
+    }
     let runtime = runtime_builder.enable_all().build()?;
 
-    runtime.block_on(async_main(config))
+    runtime.block_on(async_main(args, config, log_level))
 }
 
-async fn async_main(config: Config) -> anyhow::Result<()> {
+async fn async_main(args: Args, config: Config, log_level: LogLevelHandle) -> anyhow::Result<()> {
     // Create cancellation token for graceful shutdown
     let cancel_token = CancellationToken::new();
 
     // Initialize storage
-    info!("Opening RocksDB at {:?}", config.storage.db_path);
-    let storage = Arc::new(
-        RocksStorage::open(&config.storage)
-            .map_err(|e| anyhow::anyhow!("Failed to open RocksDB: {e}"))?,
-    );
+    let storage = open_storage(&config.storage).await?;
 
     // Initialize metrics
-    let metrics = Arc::new(Metrics::new());
+    let metrics = Arc::new(Metrics::with_config(&Registry::new(), &config.metrics)?);
+    metrics.register_storage(&storage)?;
 
-    // Start health server in separate thread if enabled
-    let health_server = if config.metrics.enabled {
-        let health = Arc::new(HealthServer::new(Arc::clone(&metrics)));
-        let health_clone = Arc::clone(&health);
-        let metrics_config = config.metrics.clone();
+    // Full compactions, scheduled and via POST /admin/compact
+    let compactor = Arc::new(Compactor::new(Arc::clone(&storage), Arc::clone(&metrics)));
 
-        std::thread::spawn(move || {
-            if let Err(e) = health_clone.run(&metrics_config) {
-                error!("Health server error: {}", e);
-            }
-        });
+    // Backups, periodic and via POST /admin/backup
+    let backups = Arc::new(BackupRunner::new(
+        Arc::clone(&storage),
+        Arc::clone(&metrics),
+        config.backup.clone(),
+    ));
+
+    // A secondary can't compact, back up or delete anything
+    let read_only = storage.is_read_only();
 
-        Some(health)
+    // Set by the background error check, read by /ready and `stats`
+    let storage_health = Arc::new(StorageHealth::new());
+
+    // Connections, read-only mode and audit log, also used by the health server
+    let shared = SharedState::new(&config, &metrics)?;
+
+    // Start health server if enabled; it outlives the drain, so it has
+    // its own cancellation
+    let health_cancel = CancellationToken::new();
+    let (health_server, health_task) = if config.metrics.enabled {
+        let mut health = HealthServer::new(Arc::clone(&metrics))
+            .with_storage(Arc::clone(&storage))
+            .with_storage_health(Arc::clone(&storage_health))
+            .with_connections(Arc::clone(&shared.connections))
+            .with_read_only(Arc::clone(&shared.read_only))
+            .with_log_level(log_level.clone())
+            .with_readiness_probe(&config.metrics);
+        if let Some(audit) = &shared.audit {
+            health = health.with_audit_log(Arc::clone(audit));
+        }
+        if !read_only {
+            health = health
+                .with_compactor(Arc::clone(&compactor))
+                .with_backups(Arc::clone(&backups))
+                .with_ingester(Arc::new(Ingester::new(Arc::clone(&storage))));
+        }
+        let (health, task) = spawn_health_server(health, &config.metrics, health_cancel.clone());
+        (Some(health), Some(task))
     } else {
-        None
+        (None, None)
     };
 
+    // Reclaim space from expired items nobody reads (if enabled)
+    let expiry_scan = tokio::spawn({
+        let storage = Arc::clone(&storage);
+        let metrics = Arc::clone(&metrics);
+        let storage_config = config.storage.clone();
+        let cancel = cancel_token.clone();
+        async move { expiry::run(storage, metrics, &storage_config, cancel).await }
+    });
+
+    // Database size cap, background error checks and expired-key counts
+    // (if enabled)
+    spawn_storage_checks(
+        &storage,
+        &metrics,
+        &storage_health,
+        &config.storage,
+        &cancel_token,
+    );
+
+    // Replica catch-up on a secondary, else scheduled compactions and
+    // backups (if enabled)
+    spawn_maintenance(
+        &storage,
+        &metrics,
+        health_server.as_ref(),
+        compactor,
+        backups,
+        &config.storage,
+        &cancel_token,
+    );
+
     // Create and start main server
-    let server = Arc::new(Server::new(
-        config.server.clone(),
-        Arc::clone(&storage),
-        Arc::clone(&metrics),
+    let server = build_server(
+        &config,
+        &storage,
+        &metrics,
+        storage_health,
+        shared,
         cancel_token.clone(),
-    ));
+    );
 
     // Mark as ready after initialization
     if let Some(ref health) = health_server {
@@ -101,9 +190,183 @@ async fn async_main(config: Config) -> anyhow::Result<()> {
         info!("Server is ready");
     }
 
-    // Setup signal handlers
-    let cancel_for_signal = cancel_token.clone();
-    let health_for_signal = health_server.clone();
+    // Setup signal handlers; SIGHUP reloads the configuration
+    spawn_signal_handler(cancel_token.clone(), health_server.clone());
+    let reloader = Reloader::new(
+        args,
+        config.clone(),
+        Arc::clone(&server),
+        log_level,
+        Arc::clone(&metrics),
+    );
+    spawn_reload_handler(reloader, cancel_token.clone());
+
+    // Run the main server (returns once connections have drained)
+    if let Err(e) = server.run().await {
+        error!("Server error: {}", e);
+    }
+
+    // The scan deletes keys; let its current slice finish before the flush
+    if let Err(e) = expiry_scan.await {
+        error!("Expiry scan task failed: {}", e);
+    }
+
+    // Writes skip the WAL: persist memtables before exiting (with the WAL
+    // on, RocksDB replays it on the next open instead)
+    if config.storage.flush_on_shutdown && config.storage.wal == Wal::Disabled && !read_only {
+        flush_memtables(storage).await;
+    }
+    // Release the health port before returning, not at runtime teardown
+    health_cancel.cancel();
+    if let Some(task) = health_task
+        && let Err(e) = task.await
+    {
+        error!("Health server task failed: {}", e);
+    }
+
+    info!("PetraCache stopped");
+    Ok(())
+}
+
+/// State the memcached server shares with the health server
+struct SharedState {
+    /// Open connections, for `stats conns` and /debug/connections
+    connections: Arc<ConnectionRegistry>,
+    /// Refuses writes on the memcached port; toggled by POST /admin/readonly
+    read_only: Arc<AtomicBool>,
+    /// Destructive commands and admin calls (if enabled)
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl SharedState {
+    fn new(config: &Config, metrics: &Arc<Metrics>) -> std::io::Result<Self> {
+        let audit = AuditLog::open(&config.audit, Arc::clone(metrics))?.map(Arc::new);
+        if let Some(path) = &config.audit.path {
+            info!("Writing audit log to {:?}", path);
+        }
+        Ok(Self {
+            connections: Arc::new(ConnectionRegistry::new()),
+            read_only: Arc::new(AtomicBool::new(config.server.read_only)),
+            audit,
+        })
+    }
+}
+
+/// The memcached server, filling misses from `upstream.addr` if set
+fn build_server(
+    config: &Config,
+    storage: &Arc<RocksStorage>,
+    metrics: &Arc<Metrics>,
+    storage_health: Arc<StorageHealth>,
+    shared: SharedState,
+    cancel: CancellationToken,
+) -> Arc<Server> {
+    let mut server = Server::new(
+        config.server.clone(),
+        Arc::clone(storage),
+        Arc::clone(metrics),
+        cancel,
+    )
+    .with_storage_health(storage_health)
+    .with_connections(shared.connections)
+    .with_read_only(shared.read_only)
+    .with_hit_ratio_window(Duration::from_secs(config.metrics.hit_ratio_window_secs));
+    if let Some(audit) = shared.audit {
+        server = server.with_audit_log(audit);
+    }
+    if !config.upstream.addr.is_empty() {
+        info!("Filling GET misses from upstream {}", config.upstream.addr);
+        server = server.with_upstream(Arc::new(Upstream::new(
+            config.upstream.clone(),
+            Arc::clone(storage),
+            Arc::clone(metrics),
+            config.server.max_item_size,
+        )));
+    }
+    Arc::new(server)
+}
+
+/// Serve `health` until `cancel` fires; the task ends once the listener
+/// is closed
+fn spawn_health_server(
+    health: HealthServer,
+    config: &MetricsConfig,
+    cancel: CancellationToken,
+) -> (Arc<HealthServer>, JoinHandle<()>) {
+    let health = Arc::new(health);
+    let health_clone = Arc::clone(&health);
+    let metrics_config = config.clone();
+    let task = tokio::spawn(async move {
+        if let Err(e) = health_clone.run(&metrics_config, cancel).await {
+            error!("Health server error: {}", e);
+        }
+    });
+    (health, task)
+}
+
+/// Start the periodic database size and background error checks
+fn spawn_storage_checks(
+    storage: &Arc<RocksStorage>,
+    metrics: &Arc<Metrics>,
+    storage_health: &Arc<StorageHealth>,
+    config: &StorageConfig,
+    cancel: &CancellationToken,
+) {
+    tokio::spawn({
+        let storage = Arc::clone(storage);
+        let metrics = Arc::clone(metrics);
+        let storage_config = config.clone();
+        let cancel = cancel.clone();
+        async move { disk_limit::run(storage, metrics, &storage_config, cancel).await }
+    });
+    tokio::spawn({
+        let storage = Arc::clone(storage);
+        let health = Arc::clone(storage_health);
+        let metrics = Arc::clone(metrics);
+        let storage_config = config.clone();
+        let cancel = cancel.clone();
+        async move { storage_health::run(storage, health, metrics, &storage_config, cancel).await }
+    });
+    tokio::spawn({
+        let storage = Arc::clone(storage);
+        let storage_config = config.clone();
+        let cancel = cancel.clone();
+        async move { expiry::report(storage, &storage_config, cancel).await }
+    });
+}
+
+/// Follow the primary's writes on a secondary; run the compaction and
+/// backup schedules otherwise
+fn spawn_maintenance(
+    storage: &Arc<RocksStorage>,
+    metrics: &Arc<Metrics>,
+    health_server: Option<&Arc<HealthServer>>,
+    compactor: Arc<Compactor>,
+    backups: Arc<BackupRunner>,
+    config: &StorageConfig,
+    cancel: &CancellationToken,
+) {
+    if storage.is_read_only() {
+        tokio::spawn({
+            let storage = Arc::clone(storage);
+            let metrics = Arc::clone(metrics);
+            let health = health_server.cloned();
+            let storage_config = config.clone();
+            let cancel = cancel.clone();
+            async move { replica::run(storage, metrics, health, &storage_config, cancel).await }
+        });
+    } else {
+        tokio::spawn({
+            let schedule = config.compaction_schedule.clone();
+            let cancel = cancel.clone();
+            async move { compaction::run(compactor, &schedule, cancel).await }
+        });
+        tokio::spawn(backup::run(backups, cancel.clone()));
+    }
+}
+
+/// Fail readiness and cancel `cancel` on SIGINT or SIGTERM
+fn spawn_signal_handler(cancel: CancellationToken, health_server: Option<Arc<HealthServer>>) {
     tokio::spawn(async move {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
@@ -124,17 +387,74 @@ async fn async_main(config: Config) -> anyhow::Result<()> {
                 info!("Received SIGTERM, shutting down...");
             }
         }
-        cancel_for_signal.cancel();
-        if let Some(health) = health_for_signal {
-            health.stop();
+        // Fail readiness first so load balancers stop sending traffic while
+        // open connections drain
+        if let Some(health) = health_server {
+            health.set_ready(false);
         }
+        cancel.cancel();
     });
+}
 
-    // Run the main server
-    if let Err(e) = server.run().await {
-        error!("Server error: {}", e);
-    }
+/// Reload the configuration on every SIGHUP until `cancel` fires
+fn spawn_reload_handler(reloader: Reloader, cancel: CancellationToken) {
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(sighup) => sighup,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                () = cancel.cancelled() => break,
+                Some(()) = sighup.recv() => {
+                    info!("Received SIGHUP, reloading configuration");
+                    // Logged and counted by the reloader
+                    let _ = reloader.reload();
+                }
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    drop((reloader, cancel));
+}
 
-    info!("PetraCache stopped");
-    Ok(())
+/// Open RocksDB, restoring it from a backup first if configured
+///
+/// Runs before anything listens or reports ready, so a half-restored store
+/// is never served.
+async fn open_storage(config: &StorageConfig) -> anyhow::Result<Arc<RocksStorage>> {
+    let restore_config = config.clone();
+    tokio::task::spawn_blocking(move || backup::restore_at_startup(&restore_config))
+        .await?
+        .map_err(|e| anyhow::anyhow!("Failed to restore from backup: {e}"))?;
+
+    info!("Opening RocksDB at {:?}", config.db_path);
+    let storage =
+        RocksStorage::open(config).map_err(|e| anyhow::anyhow!("Failed to open RocksDB: {e}"))?;
+    Ok(Arc::new(storage))
+}
+
+/// Flush RocksDB memtables, giving up after `SHUTDOWN_FLUSH_TIMEOUT`
+async fn flush_memtables(storage: Arc<RocksStorage>) {
+    info!("Flushing memtables");
+    let started = Instant::now();
+    let flush = tokio::task::spawn_blocking(move || storage.flush());
+    match tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, flush).await {
+        Ok(Ok(Ok(bytes))) => info!(
+            bytes,
+            elapsed_ms = started.elapsed().as_millis(),
+            "Memtables flushed"
+        ),
+        Ok(Ok(Err(e))) => error!("Failed to flush memtables: {}", e),
+        Ok(Err(e)) => error!("Memtable flush task failed: {}", e),
+        Err(_) => warn!(
+            "Memtable flush still running after {}s, exiting without it",
+            SHUTDOWN_FLUSH_TIMEOUT.as_secs()
+        ),
+    }
 }
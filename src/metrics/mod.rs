@@ -0,0 +1,1566 @@
+//! Prometheus metrics for RocksProxy
+
+use crate::config::MetricsConfig;
+use crate::storage::{
+    COMPRESSION_BYTES_SAVED, CORRUPT_VALUES_REMOVED, DB_RECREATES, DB_REPAIRS, DbStats,
+    FULL_REJECTED_WRITES, HOT_CACHE_HITS, HOT_CACHE_MISSES, MemoryUsage, NEGATIVE_CACHE_HITS,
+    RocksStorage, TtlCounters,
+};
+use parking_lot::Mutex;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Gauge, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry,
+};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub mod process;
+
+/// Global metrics instance
+pub struct Metrics {
+    pub registry: Registry,
+
+    // Command counters
+    pub cmd_get: IntCounter,
+    pub cmd_set: IntCounter,
+    pub cmd_add: IntCounter,
+    pub cmd_replace: IntCounter,
+    pub cmd_append: IntCounter,
+    pub cmd_prepend: IntCounter,
+    pub cmd_cas: IntCounter,
+    pub cmd_delete: IntCounter,
+    pub cmd_incr: IntCounter,
+    pub cmd_decr: IntCounter,
+    pub cmd_touch: IntCounter,
+    pub cmd_flush: IntCounter,
+
+    // Hit/miss counters
+    pub get_hits: IntCounter,
+    pub get_misses: IntCounter,
+
+    // Connection metrics
+    pub active_connections: IntGauge,
+    /// Capacity of all connections' read and write buffers
+    pub connection_buffer_bytes: IntGauge,
+    pub total_connections: IntCounter,
+    pub rejected_connections: IntCounter,
+    pub rejected_connections_per_ip: IntCounter,
+    /// Connections from outside `allowed_cidrs`
+    pub rejected_connections_acl: IntCounter,
+    pub idle_timeouts: IntCounter,
+    pub tls_handshake_errors: IntCounter,
+    pub proxy_protocol_errors: IntCounter,
+    /// Connections closed after `max_protocol_errors` errors in a row
+    pub connections_closed_for_errors: IntCounter,
+    /// ASCII authentication attempts (`[server.auth]`)
+    pub auth_successes: IntCounter,
+    pub auth_failures: IntCounter,
+
+    // Bytes counters
+    pub bytes_read: IntCounter,
+    pub bytes_written: IntCounter,
+
+    // Latency histograms
+    /// Time spent executing a command, labeled by command name
+    pub cmd_latency: HistogramVec,
+    /// Time to hand a response to the client socket (slow clients show here)
+    pub response_write_latency: Histogram,
+
+    // Slow log (`server.slow_log_threshold_ms`)
+    /// Commands slower than the threshold, labeled by command name; counted
+    /// whether or not the rate limit let their entry through
+    pub slow_commands: IntCounterVec,
+    /// Slow log entries dropped by `server.slow_log_max_per_sec`
+    pub slow_log_suppressed: IntCounter,
+
+    // Error counters
+    pub protocol_errors: IntCounter,
+    pub storage_errors: IntCounter,
+    /// Commands refused by `disabled_commands` or `read_only`, labeled by
+    /// command name
+    pub commands_denied: IntCounterVec,
+    /// 1 while the server refuses writes (`read_only`)
+    pub read_only: IntGauge,
+    /// Audit log entries written, and dropped because the writer was
+    /// behind or failing
+    pub audit_entries: IntCounter,
+    pub audit_dropped: IntCounter,
+    /// Configuration reloads (SIGHUP), labeled `result` = success/failure
+    pub config_reloads: IntCounterVec,
+
+    // Background expiry scan
+    pub expiry_scan_keys_scanned: IntCounter,
+    pub expiry_scan_keys_removed: IntCounter,
+    pub expiry_scan_passes: IntCounter,
+    /// Keys inspected by the last complete pass over the keyspace
+    pub expiry_scan_last_pass_scanned: IntGauge,
+    /// Keys removed by the last complete pass over the keyspace
+    pub expiry_scan_last_pass_removed: IntGauge,
+
+    // Manual compaction
+    /// 1 while a scheduled or admin-triggered compaction runs
+    pub compaction_running: IntGauge,
+
+    // Backups
+    /// Unix time the last successful backup finished
+    pub backup_last_success_timestamp: IntGauge,
+    /// Duration of the last successful backup
+    pub backup_last_duration_seconds: Gauge,
+    /// Size of the last successful backup
+    pub backup_last_size_bytes: IntGauge,
+    pub backup_failures: IntCounter,
+
+    // Secondary mode
+    /// Seconds since the last successful catch-up with the primary
+    pub replication_lag_seconds: Gauge,
+    pub catch_up_failures: IntCounter,
+
+    // Read-through (`[upstream]`)
+    /// Local misses the upstream had a value for
+    pub upstream_hits: IntCounter,
+    /// Local misses the upstream didn't have either
+    pub upstream_misses: IntCounter,
+    /// Upstream requests that timed out
+    pub upstream_timeouts: IntCounter,
+    /// Upstream requests that failed otherwise, or were skipped at
+    /// `max_concurrent_fetches`
+    pub upstream_errors: IntCounter,
+    /// Misses that waited for a fetch already in flight for the same key
+    pub upstream_coalesced: IntCounter,
+
+    // Disk usage cap (`storage.max_db_size_bytes`)
+    /// Bytes of SST files, WAL and memtables at the last check
+    pub db_size_bytes: IntGauge,
+    /// `storage.max_db_size_bytes` (0 = unlimited)
+    pub db_size_limit_bytes: IntGauge,
+    /// Entries deleted by `on_full = "evict"`
+    pub evicted_keys: IntCounter,
+
+    // Storage health (`storage.health_check_interval_ms`)
+    /// 1 while RocksDB refuses writes after a background error
+    pub storage_unhealthy: IntGauge,
+    /// Background errors RocksDB reported (flush, compaction)
+    pub storage_background_errors: IntCounter,
+
+    // Sizes and hit ratio
+    /// Keys of stored items
+    pub key_size: Histogram,
+    /// Values of stored items
+    pub value_size: Histogram,
+    /// Hit ratio over a sliding window, updated by `Server::run`
+    pub hit_ratio: Gauge,
+    /// Also observe sizes of items hit
+    observe_hit_sizes: bool,
+
+    /// Value of the `instance` label on every series, if any
+    instance: Option<String>,
+
+    // Counter values at the last `stats reset` (Prometheus counters can't be reset)
+    stats_baseline: Mutex<StatsSnapshot>,
+}
+
+/// Resettable counters reported by the ASCII `stats` command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub cmd_get: u64,
+    pub cmd_set: u64,
+    pub cmd_add: u64,
+    pub cmd_replace: u64,
+    pub cmd_append: u64,
+    pub cmd_prepend: u64,
+    pub cmd_cas: u64,
+    pub cmd_delete: u64,
+    pub cmd_incr: u64,
+    pub cmd_decr: u64,
+    pub cmd_touch: u64,
+    pub cmd_flush: u64,
+    pub get_hits: u64,
+    pub get_misses: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl StatsSnapshot {
+    /// Counter deltas since `baseline`
+    fn since(self, baseline: Self) -> Self {
+        Self {
+            cmd_get: self.cmd_get.saturating_sub(baseline.cmd_get),
+            cmd_set: self.cmd_set.saturating_sub(baseline.cmd_set),
+            cmd_add: self.cmd_add.saturating_sub(baseline.cmd_add),
+            cmd_replace: self.cmd_replace.saturating_sub(baseline.cmd_replace),
+            cmd_append: self.cmd_append.saturating_sub(baseline.cmd_append),
+            cmd_prepend: self.cmd_prepend.saturating_sub(baseline.cmd_prepend),
+            cmd_cas: self.cmd_cas.saturating_sub(baseline.cmd_cas),
+            cmd_delete: self.cmd_delete.saturating_sub(baseline.cmd_delete),
+            cmd_incr: self.cmd_incr.saturating_sub(baseline.cmd_incr),
+            cmd_decr: self.cmd_decr.saturating_sub(baseline.cmd_decr),
+            cmd_touch: self.cmd_touch.saturating_sub(baseline.cmd_touch),
+            cmd_flush: self.cmd_flush.saturating_sub(baseline.cmd_flush),
+            get_hits: self.get_hits.saturating_sub(baseline.get_hits),
+            get_misses: self.get_misses.saturating_sub(baseline.get_misses),
+            bytes_read: self.bytes_read.saturating_sub(baseline.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(baseline.bytes_written),
+        }
+    }
+}
+
+impl Metrics {
+    /// Create a metrics instance with a registry of its own
+    pub fn new() -> prometheus::Result<Self> {
+        Self::new_with_registry(&Registry::new(), None)
+    }
+
+    /// Create a metrics instance registered in `registry`, for embedders
+    /// exposing their own metrics too
+    ///
+    /// With an `instance`, every series carries an `instance` label, so
+    /// several servers can share one registry; without one, a second
+    /// `Metrics` in the same registry fails with `AlreadyReg`.
+    pub fn new_with_registry(
+        registry: &Registry,
+        instance: Option<&str>,
+    ) -> prometheus::Result<Self> {
+        let config = MetricsConfig {
+            instance: instance.map(str::to_string),
+            ..MetricsConfig::default()
+        };
+        Self::with_config(registry, &config)
+    }
+
+    /// Create a metrics instance registered in `registry`, with the
+    /// `instance` label and size histogram buckets of `config`
+    #[allow(clippy::too_many_lines)]
+    pub fn with_config(registry: &Registry, config: &MetricsConfig) -> prometheus::Result<Self> {
+        let instance = config.instance.as_deref();
+        let r = Registrar::new(registry, instance);
+
+        let cmd_get = r.counter("petracache_cmd_get_total", "Total GET commands")?;
+        let cmd_set = r.counter("petracache_cmd_set_total", "Total SET commands")?;
+        let cmd_add = r.counter("petracache_cmd_add_total", "Total ADD commands")?;
+        let cmd_replace = r.counter("petracache_cmd_replace_total", "Total REPLACE commands")?;
+        let cmd_append = r.counter("petracache_cmd_append_total", "Total APPEND commands")?;
+        let cmd_prepend = r.counter("petracache_cmd_prepend_total", "Total PREPEND commands")?;
+        let cmd_cas = r.counter("petracache_cmd_cas_total", "Total CAS commands")?;
+        let cmd_delete = r.counter("petracache_cmd_delete_total", "Total DELETE commands")?;
+        let cmd_incr = r.counter("petracache_cmd_incr_total", "Total INCR commands")?;
+        let cmd_decr = r.counter("petracache_cmd_decr_total", "Total DECR commands")?;
+        let cmd_touch = r.counter("petracache_cmd_touch_total", "Total TOUCH commands")?;
+        let cmd_flush = r.counter("petracache_cmd_flush_total", "Total FLUSH_ALL commands")?;
+
+        let get_hits = r.counter("petracache_get_hits_total", "Total GET hits")?;
+        let get_misses = r.counter("petracache_get_misses_total", "Total GET misses")?;
+
+        let active_connections = r.gauge(
+            "petracache_active_connections",
+            "Current active connections",
+        )?;
+        let connection_buffer_bytes = r.gauge(
+            "petracache_connection_buffer_bytes",
+            "Capacity of per-connection read and write buffers in bytes",
+        )?;
+        let total_connections =
+            r.counter("petracache_connections_total", "Total connections accepted")?;
+        let rejected_connections = r.counter(
+            "petracache_rejected_connections_total",
+            "Total connections rejected",
+        )?;
+        let rejected_connections_per_ip = r.counter(
+            "petracache_rejected_connections_per_ip_total",
+            "Total connections rejected by max_connections_per_ip",
+        )?;
+        let rejected_connections_acl = r.counter(
+            "petracache_rejected_connections_acl_total",
+            "Total connections rejected by allowed_cidrs",
+        )?;
+        let idle_timeouts = r.counter(
+            "petracache_idle_timeouts_total",
+            "Total connections closed after connection_timeout_secs without input",
+        )?;
+        let tls_handshake_errors = r.counter(
+            "petracache_tls_handshake_errors_total",
+            "Total TLS handshakes that failed or timed out",
+        )?;
+        let proxy_protocol_errors = r.counter(
+            "petracache_proxy_protocol_errors_total",
+            "Total connections closed for a missing or malformed PROXY header",
+        )?;
+        let connections_closed_for_errors = r.counter(
+            "petracache_connections_closed_for_errors_total",
+            "Total connections closed after max_protocol_errors protocol errors in a row",
+        )?;
+        let auth_successes = r.counter(
+            "petracache_auth_successes_total",
+            "Total successful ASCII authentications",
+        )?;
+        let auth_failures = r.counter(
+            "petracache_auth_failures_total",
+            "Total ASCII authentication attempts with wrong credentials",
+        )?;
+
+        let bytes_read = r.counter("petracache_bytes_read_total", "Total bytes read")?;
+        let bytes_written = r.counter("petracache_bytes_written_total", "Total bytes written")?;
+
+        let latency_buckets = vec![
+            0.0001, 0.0005, 0.001, 0.002, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+        ];
+        let cmd_latency = r.histogram_vec(
+            "petracache_cmd_latency_seconds",
+            "Command execution latency in seconds",
+            &latency_buckets,
+            &["command"],
+        )?;
+        let response_write_latency = r.histogram(
+            "petracache_response_write_seconds",
+            "Time to write a response to the client socket in seconds",
+            &latency_buckets,
+        )?;
+        let slow_commands = r.counter_vec(
+            "petracache_slow_commands_total",
+            "Commands slower than slow_log_threshold_ms",
+            &["command"],
+        )?;
+        let slow_log_suppressed = r.counter(
+            "petracache_slow_log_suppressed_total",
+            "Slow log entries dropped by the slow_log_max_per_sec rate limit",
+        )?;
+
+        let protocol_errors =
+            r.counter("petracache_protocol_errors_total", "Total protocol errors")?;
+        let storage_errors =
+            r.counter("petracache_storage_errors_total", "Total storage errors")?;
+        let commands_denied = r.counter_vec(
+            "petracache_commands_denied_total",
+            "Commands refused by disabled_commands or read_only",
+            &["command"],
+        )?;
+        let read_only = r.gauge(
+            "petracache_read_only",
+            "1 while the server refuses commands that change stored data",
+        )?;
+        let audit_entries = r.counter(
+            "petracache_audit_entries_total",
+            "Audit log entries written",
+        )?;
+        let audit_dropped = r.counter(
+            "petracache_audit_dropped_total",
+            "Audit log entries dropped because the writer was behind or failing",
+        )?;
+        let config_reloads = r.counter_vec(
+            "petracache_config_reloads_total",
+            "Configuration reloads on SIGHUP, by result",
+            &["result"],
+        )?;
+
+        let expiry_scan_keys_scanned = r.counter(
+            "petracache_expiry_scan_keys_scanned_total",
+            "Keys inspected by the background expiry scan",
+        )?;
+        let expiry_scan_keys_removed = r.counter(
+            "petracache_expiry_scan_keys_removed_total",
+            "Expired or flushed keys deleted by the background expiry scan",
+        )?;
+        let expiry_scan_passes = r.counter(
+            "petracache_expiry_scan_passes_total",
+            "Complete passes of the background expiry scan over the keyspace",
+        )?;
+        let expiry_scan_last_pass_scanned = r.gauge(
+            "petracache_expiry_scan_last_pass_scanned",
+            "Keys inspected by the last complete expiry scan pass",
+        )?;
+        let expiry_scan_last_pass_removed = r.gauge(
+            "petracache_expiry_scan_last_pass_removed",
+            "Keys deleted by the last complete expiry scan pass",
+        )?;
+        let compaction_running = r.gauge(
+            "petracache_compaction_running",
+            "1 while a manual full compaction is running",
+        )?;
+        let backup_last_success_timestamp = r.gauge(
+            "petracache_backup_last_success_timestamp_seconds",
+            "Unix time the last successful backup finished",
+        )?;
+        let backup_last_duration_seconds = r.float_gauge(
+            "petracache_backup_last_duration_seconds",
+            "Duration of the last successful backup",
+        )?;
+        let backup_last_size_bytes = r.gauge(
+            "petracache_backup_last_size_bytes",
+            "Size of the last successful backup, including shared files",
+        )?;
+        let backup_failures = r.counter("petracache_backup_failures_total", "Failed backups")?;
+        let replication_lag_seconds = r.float_gauge(
+            "petracache_replication_lag_seconds",
+            "Seconds since a secondary last caught up with the primary",
+        )?;
+        let catch_up_failures = r.counter(
+            "petracache_catch_up_failures_total",
+            "Failed catch-ups with the primary",
+        )?;
+        let upstream_hits = r.counter(
+            "petracache_upstream_hits_total",
+            "Local misses filled from the upstream",
+        )?;
+        let upstream_misses = r.counter(
+            "petracache_upstream_misses_total",
+            "Local misses the upstream missed too",
+        )?;
+        let upstream_timeouts = r.counter(
+            "petracache_upstream_timeouts_total",
+            "Upstream requests that timed out",
+        )?;
+        let upstream_errors = r.counter(
+            "petracache_upstream_errors_total",
+            "Upstream requests that failed or were skipped",
+        )?;
+        let upstream_coalesced = r.counter(
+            "petracache_upstream_coalesced_total",
+            "Misses that joined an upstream fetch already in flight",
+        )?;
+        let db_size_bytes = r.gauge(
+            "petracache_db_size_bytes",
+            "Bytes of SST files, WAL and memtables",
+        )?;
+        let db_size_limit_bytes = r.gauge(
+            "petracache_db_size_limit_bytes",
+            "Configured database size cap (0 = unlimited)",
+        )?;
+        let evicted_keys = r.counter(
+            "petracache_evicted_keys_total",
+            "Entries evicted to stay under the database size cap",
+        )?;
+        let storage_unhealthy = r.gauge(
+            "petracache_storage_unhealthy",
+            "1 while RocksDB refuses writes after a background error",
+        )?;
+        let storage_background_errors = r.counter(
+            "petracache_storage_background_errors_total",
+            "Background errors reported by RocksDB",
+        )?;
+        let key_size = r.histogram(
+            "petracache_key_size_bytes",
+            "Size of keys stored (and of keys hit, with observe_hit_sizes)",
+            &config.key_size_buckets,
+        )?;
+        let value_size = r.histogram(
+            "petracache_value_size_bytes",
+            "Size of values stored (and of values hit, with observe_hit_sizes)",
+            &config.value_size_buckets,
+        )?;
+        let hit_ratio = r.float_gauge(
+            "petracache_hit_ratio",
+            "Get hits / gets over the last hit_ratio_window_secs",
+        )?;
+
+        registry.register(Box::new(SyncedCounters::statics(&r)?))?;
+        registry.register(Box::new(process::ProcessCollector::new(&r)?))?;
+
+        Ok(Self {
+            registry: registry.clone(),
+            instance: instance.map(str::to_string),
+            cmd_get,
+            cmd_set,
+            cmd_add,
+            cmd_replace,
+            cmd_append,
+            cmd_prepend,
+            cmd_cas,
+            cmd_delete,
+            cmd_incr,
+            cmd_decr,
+            cmd_touch,
+            cmd_flush,
+            get_hits,
+            get_misses,
+            active_connections,
+            connection_buffer_bytes,
+            total_connections,
+            rejected_connections,
+            rejected_connections_per_ip,
+            rejected_connections_acl,
+            idle_timeouts,
+            tls_handshake_errors,
+            proxy_protocol_errors,
+            connections_closed_for_errors,
+            auth_successes,
+            auth_failures,
+            bytes_read,
+            bytes_written,
+            cmd_latency,
+            response_write_latency,
+            slow_commands,
+            slow_log_suppressed,
+            protocol_errors,
+            storage_errors,
+            commands_denied,
+            read_only,
+            audit_entries,
+            audit_dropped,
+            config_reloads,
+            expiry_scan_keys_scanned,
+            expiry_scan_keys_removed,
+            expiry_scan_passes,
+            expiry_scan_last_pass_scanned,
+            expiry_scan_last_pass_removed,
+            compaction_running,
+            backup_last_success_timestamp,
+            backup_last_duration_seconds,
+            backup_last_size_bytes,
+            backup_failures,
+            replication_lag_seconds,
+            catch_up_failures,
+            upstream_hits,
+            upstream_misses,
+            upstream_timeouts,
+            upstream_errors,
+            upstream_coalesced,
+            db_size_bytes,
+            db_size_limit_bytes,
+            evicted_keys,
+            storage_unhealthy,
+            storage_background_errors,
+            key_size,
+            value_size,
+            hit_ratio,
+            observe_hit_sizes: config.observe_hit_sizes,
+            stats_baseline: Mutex::new(StatsSnapshot::default()),
+        })
+    }
+
+    /// Observe the key and value size of a stored item
+    #[allow(clippy::cast_precision_loss)] // sizes are far below 2^52
+    pub fn observe_store(&self, key: &[u8], value_len: usize) {
+        self.key_size.observe(key.len() as f64);
+        self.value_size.observe(value_len as f64);
+    }
+
+    /// Observe the key and value size of an item hit, if enabled
+    pub fn observe_hit(&self, key: &[u8], value_len: usize) {
+        if self.observe_hit_sizes {
+            self.observe_store(key, value_len);
+        }
+    }
+
+    /// Raw counter values since process start
+    fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            cmd_get: self.cmd_get.get(),
+            cmd_set: self.cmd_set.get(),
+            cmd_add: self.cmd_add.get(),
+            cmd_replace: self.cmd_replace.get(),
+            cmd_append: self.cmd_append.get(),
+            cmd_prepend: self.cmd_prepend.get(),
+            cmd_cas: self.cmd_cas.get(),
+            cmd_delete: self.cmd_delete.get(),
+            cmd_incr: self.cmd_incr.get(),
+            cmd_decr: self.cmd_decr.get(),
+            cmd_touch: self.cmd_touch.get(),
+            cmd_flush: self.cmd_flush.get(),
+            get_hits: self.get_hits.get(),
+            get_misses: self.get_misses.get(),
+            bytes_read: self.bytes_read.get(),
+            bytes_written: self.bytes_written.get(),
+        }
+    }
+
+    /// Counter values since the last `stats reset`
+    pub fn stats(&self) -> StatsSnapshot {
+        let baseline = *self.stats_baseline.lock();
+        self.snapshot().since(baseline)
+    }
+
+    /// Zero the counters reported by `stats` (Prometheus totals are unaffected)
+    pub fn reset_stats(&self) {
+        *self.stats_baseline.lock() = self.snapshot();
+    }
+
+    /// Export `storage`'s TTL expiration counters; once per `Metrics`
+    pub fn register_storage(&self, storage: &RocksStorage) -> prometheus::Result<()> {
+        let r = Registrar::new(&self.registry, self.instance.as_deref());
+        let counters = SyncedCounters::ttl(&r, &storage.ttl_counters())?;
+        self.registry.register(Box::new(counters))
+    }
+
+    /// Get Prometheus formatted metrics
+    pub fn gather(&self) -> prometheus::Result<String> {
+        use prometheus::Encoder;
+        let encoder = prometheus::TextEncoder::new();
+        let metric_families = self.registry.gather();
+        let mut buffer = Vec::new();
+        encoder.encode(&metric_families, &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+
+    /// `gather()` plus RocksDB database and memory gauges, read fresh for
+    /// each scrape
+    ///
+    /// Database properties RocksDB did not report are left out rather than
+    /// shown as 0.
+    pub fn gather_with_storage_stats(
+        &self,
+        db: &DbStats,
+        memory: &MemoryUsage,
+    ) -> prometheus::Result<String> {
+        let mut output = String::new();
+
+        let memory_gauges = [
+            (
+                "petracache_rocksdb_block_cache_usage_bytes",
+                "Block cache usage",
+                memory.block_cache_usage,
+            ),
+            (
+                "petracache_rocksdb_block_cache_pinned_bytes",
+                "Block cache entries pinned by iterators and table readers",
+                memory.block_cache_pinned_usage,
+            ),
+            (
+                "petracache_rocksdb_memtable_unflushed_bytes",
+                "Active and unflushed immutable memtables",
+                memory.memtables_unflushed,
+            ),
+            (
+                "petracache_rocksdb_memtable_total_bytes",
+                "All memtables, including flushed ones pinned by iterators",
+                memory.memtables_total,
+            ),
+            (
+                "petracache_rocksdb_table_readers_bytes",
+                "Index and filter blocks held outside the block cache",
+                memory.table_readers,
+            ),
+            (
+                "petracache_rocksdb_memory_bytes",
+                "RocksDB memory: block cache, memtables and table readers",
+                memory.total,
+            ),
+        ];
+        for (name, help, value) in memory_gauges {
+            output.push_str(&format!(
+                "\n# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+            ));
+        }
+
+        let gauges = [
+            (
+                "petracache_rocksdb_estimated_keys",
+                "Estimated keys in RocksDB, including expired items not yet compacted",
+                db.estimated_keys,
+            ),
+            (
+                "petracache_rocksdb_sst_bytes",
+                "Total size of all SST files",
+                db.total_sst_bytes,
+            ),
+            (
+                "petracache_rocksdb_live_data_bytes",
+                "Estimated size of live data",
+                db.live_data_bytes,
+            ),
+            (
+                "petracache_rocksdb_memtable_bytes",
+                "Size of active and unflushed memtables",
+                db.memtable_bytes,
+            ),
+            (
+                "petracache_rocksdb_pending_compaction_bytes",
+                "Estimated bytes compaction still has to rewrite",
+                db.pending_compaction_bytes,
+            ),
+            (
+                "petracache_rocksdb_running_compactions",
+                "Compactions currently running",
+                db.running_compactions,
+            ),
+            (
+                "petracache_rocksdb_running_flushes",
+                "Memtable flushes currently running",
+                db.running_flushes,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            if let Some(value) = value {
+                output.push_str(&format!(
+                    "\n# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n"
+                ));
+            }
+        }
+
+        push_sst_file_stats(&mut output, db);
+        push_namespace_stats(&mut output, db);
+        push_data_path_stats(&mut output, db);
+        push_rocksdb_statistics(&mut output, db);
+
+        let mut metrics = self.gather()?;
+        match &self.instance {
+            Some(instance) => metrics.push_str(&label_instance(&output, instance)),
+            None => metrics.push_str(&output),
+        }
+        Ok(metrics)
+    }
+}
+
+/// Add an `instance` label to every sample in hand-formatted exposition
+/// `text`
+fn label_instance(text: &str, instance: &str) -> String {
+    let label = format!(
+        "instance=\"{}\"",
+        instance.replace('\\', "\\\\").replace('"', "\\\"")
+    );
+    let mut labelled = String::with_capacity(text.len());
+    for line in text.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            labelled.push_str(line);
+        } else if let Some((name, rest)) = line.split_once('{') {
+            labelled.push_str(&format!("{name}{{{label},{rest}"));
+        } else if let Some((name, value)) = line.split_once(' ') {
+            labelled.push_str(&format!("{name}{{{label}}} {value}"));
+        } else {
+            labelled.push_str(line);
+        }
+        labelled.push('\n');
+    }
+    labelled
+}
+
+/// Gets and items per configured namespace
+fn push_namespace_stats(output: &mut String, db: &DbStats) {
+    if db.namespaces.is_empty() {
+        return;
+    }
+    output.push_str(
+        "\n# HELP petracache_namespace_get_hits_total Get hits per namespace\n\
+         # TYPE petracache_namespace_get_hits_total counter\n",
+    );
+    for ns in &db.namespaces {
+        output.push_str(&format!(
+            "petracache_namespace_get_hits_total{{namespace=\"{}\"}} {}\n",
+            ns.name, ns.hits
+        ));
+    }
+    output.push_str(
+        "\n# HELP petracache_namespace_get_misses_total Get misses per namespace\n\
+         # TYPE petracache_namespace_get_misses_total counter\n",
+    );
+    for ns in &db.namespaces {
+        output.push_str(&format!(
+            "petracache_namespace_get_misses_total{{namespace=\"{}\"}} {}\n",
+            ns.name, ns.misses
+        ));
+    }
+    output.push_str(
+        "\n# HELP petracache_namespace_items Estimated keys per namespace\n\
+         # TYPE petracache_namespace_items gauge\n",
+    );
+    for ns in &db.namespaces {
+        if let Some(items) = ns.items {
+            output.push_str(&format!(
+                "petracache_namespace_items{{namespace=\"{}\"}} {items}\n",
+                ns.name
+            ));
+        }
+    }
+    output.push_str(
+        "\n# HELP petracache_namespace_used_bytes SST and memtable bytes per namespace\n\
+         # TYPE petracache_namespace_used_bytes gauge\n",
+    );
+    for ns in &db.namespaces {
+        output.push_str(&format!(
+            "petracache_namespace_used_bytes{{namespace=\"{}\"}} {}\n",
+            ns.name, ns.used_bytes
+        ));
+    }
+
+    // Namespaces with a quota only
+    if db.namespaces.iter().all(|ns| ns.max_bytes == 0) {
+        return;
+    }
+    output.push_str(
+        "\n# HELP petracache_namespace_limit_bytes Quota per namespace\n\
+         # TYPE petracache_namespace_limit_bytes gauge\n",
+    );
+    for ns in db.namespaces.iter().filter(|ns| ns.max_bytes > 0) {
+        output.push_str(&format!(
+            "petracache_namespace_limit_bytes{{namespace=\"{}\"}} {}\n",
+            ns.name, ns.max_bytes
+        ));
+    }
+    output.push_str(
+        "\n# HELP petracache_namespace_writes_rejected_total Sets rejected because the namespace is over quota\n\
+         # TYPE petracache_namespace_writes_rejected_total counter\n",
+    );
+    for ns in db.namespaces.iter().filter(|ns| ns.max_bytes > 0) {
+        output.push_str(&format!(
+            "petracache_namespace_writes_rejected_total{{namespace=\"{}\"}} {}\n",
+            ns.name, ns.rejected_writes
+        ));
+    }
+}
+
+/// SST files per level and, with FIFO compaction, its drops of old files;
+/// with a rate limiter, its drains
+fn push_sst_file_stats(output: &mut String, db: &DbStats) {
+    if let Some(drops) = db.fifo_drops {
+        output.push_str(&format!(
+            "\n# HELP petracache_rocksdb_fifo_drops_total FIFO compactions that dropped the oldest SST files\n\
+             # TYPE petracache_rocksdb_fifo_drops_total counter\n\
+             petracache_rocksdb_fifo_drops_total {drops}\n"
+        ));
+    }
+    if let Some(drains) = db.rate_limiter_drains {
+        output.push_str(&format!(
+            "\n# HELP petracache_rocksdb_rate_limiter_drains_total Times flushes and compactions waited for the rate limiter\n\
+             # TYPE petracache_rocksdb_rate_limiter_drains_total counter\n\
+             petracache_rocksdb_rate_limiter_drains_total {drains}\n"
+        ));
+    }
+
+    if !db.files_per_level.is_empty() {
+        output.push_str(
+            "\n# HELP petracache_rocksdb_sst_files SST files per LSM level\n\
+             # TYPE petracache_rocksdb_sst_files gauge\n",
+        );
+        for (level, files) in &db.files_per_level {
+            output.push_str(&format!(
+                "petracache_rocksdb_sst_files{{level=\"{level}\"}} {files}\n"
+            ));
+        }
+    }
+}
+
+/// One line of RocksDB's statistics dump
+#[derive(Debug, PartialEq)]
+enum RocksStat {
+    /// `rocksdb.block.cache.miss COUNT : 5`
+    Ticker(u64),
+    /// `rocksdb.db.get.micros P50 : 1.5 P95 : 4 P99 : 9 P100 : 12 COUNT : 7 SUM : 30`
+    Histogram {
+        /// (quantile, value)
+        quantiles: Vec<(f64, f64)>,
+        count: u64,
+        sum: f64,
+    },
+}
+
+/// Parse one line of a statistics dump; `None` for a format this doesn't
+/// know, so tickers RocksDB adds or renames never break a scrape
+fn parse_statistics_line(line: &str) -> Option<(&str, RocksStat)> {
+    let (name, rest) = line.trim().split_once(' ')?;
+    let tokens: Vec<&str> = rest.split_whitespace().collect();
+    if tokens.is_empty() || tokens.len() % 3 != 0 {
+        return None;
+    }
+    let mut quantiles = Vec::new();
+    let mut count = None;
+    let mut sum = None;
+    for field in tokens.chunks_exact(3) {
+        let [key, ":", value] = field else {
+            return None;
+        };
+        match *key {
+            "COUNT" => count = Some(value.parse().ok()?),
+            "SUM" => sum = Some(value.parse().ok()?),
+            _ => {
+                let percentile: f64 = key.strip_prefix('P')?.parse().ok()?;
+                quantiles.push((percentile / 100.0, value.parse().ok()?));
+            }
+        }
+    }
+    let stat = if quantiles.is_empty() && sum.is_none() {
+        RocksStat::Ticker(count?)
+    } else {
+        RocksStat::Histogram {
+            quantiles,
+            count: count.unwrap_or(0),
+            sum: sum.unwrap_or(0.0),
+        }
+    };
+    Some((name, stat))
+}
+
+/// `rocksdb.block.cache.miss` -> `petracache_rocksdb_block_cache_miss`
+fn statistic_metric_name(name: &str) -> Option<String> {
+    let name = name.strip_prefix("rocksdb.").unwrap_or(name);
+    if name.is_empty() {
+        return None;
+    }
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    Some(format!("petracache_rocksdb_{name}"))
+}
+
+/// SST bytes and target size per `db_paths` entry
+fn push_data_path_stats(output: &mut String, db: &DbStats) {
+    if db.data_paths.is_empty() {
+        return;
+    }
+    let label = |path: &std::path::Path| {
+        path.to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+    };
+    output.push_str(
+        "\n# HELP petracache_rocksdb_data_path_bytes SST bytes per storage.db_paths entry\n\
+         # TYPE petracache_rocksdb_data_path_bytes gauge\n",
+    );
+    for data_path in &db.data_paths {
+        output.push_str(&format!(
+            "petracache_rocksdb_data_path_bytes{{path=\"{}\"}} {}\n",
+            label(&data_path.path),
+            data_path.used_bytes
+        ));
+    }
+    output.push_str(
+        "\n# HELP petracache_rocksdb_data_path_target_bytes Target size per storage.db_paths entry\n\
+         # TYPE petracache_rocksdb_data_path_target_bytes gauge\n",
+    );
+    for data_path in &db.data_paths {
+        output.push_str(&format!(
+            "petracache_rocksdb_data_path_target_bytes{{path=\"{}\"}} {}\n",
+            label(&data_path.path),
+            data_path.target_bytes
+        ));
+    }
+}
+
+/// RocksDB tickers, summed over shards, and histograms, per shard, from
+/// `enable_statistics`
+fn push_rocksdb_statistics(output: &mut String, db: &DbStats) {
+    type ShardHistogram<'a> = (usize, &'a [(f64, f64)], u64, f64);
+    let parsed: Vec<Vec<(&str, RocksStat)>> = db
+        .statistics
+        .iter()
+        .map(|dump| dump.lines().filter_map(parse_statistics_line).collect())
+        .collect();
+
+    // Keyed by metric name: (RocksDB name, value)
+    let mut tickers: BTreeMap<String, (&str, u64)> = BTreeMap::new();
+    let mut histograms: BTreeMap<String, (&str, Vec<ShardHistogram>)> = BTreeMap::new();
+    for (shard, stats) in parsed.iter().enumerate() {
+        for (name, stat) in stats {
+            let Some(metric) = statistic_metric_name(name) else {
+                continue;
+            };
+            match stat {
+                RocksStat::Ticker(count) => tickers.entry(metric).or_insert((name, 0)).1 += count,
+                RocksStat::Histogram {
+                    quantiles,
+                    count,
+                    sum,
+                } => histograms
+                    .entry(metric)
+                    .or_insert((name, Vec::new()))
+                    .1
+                    .push((shard, quantiles, *count, *sum)),
+            }
+        }
+    }
+
+    for (metric, (name, count)) in tickers {
+        output.push_str(&format!(
+            "\n# HELP {metric}_total RocksDB ticker {name}\n# TYPE {metric}_total counter\n{metric}_total {count}\n"
+        ));
+    }
+    for (metric, (name, shards)) in histograms {
+        output.push_str(&format!(
+            "\n# HELP {metric} RocksDB histogram {name}\n# TYPE {metric} summary\n"
+        ));
+        for (shard, quantiles, count, sum) in shards {
+            for (quantile, value) in quantiles {
+                output.push_str(&format!(
+                    "{metric}{{shard=\"{shard}\",quantile=\"{quantile}\"}} {value}\n"
+                ));
+            }
+            output.push_str(&format!("{metric}_sum{{shard=\"{shard}\"}} {sum}\n"));
+            output.push_str(&format!("{metric}_count{{shard=\"{shard}\"}} {count}\n"));
+        }
+    }
+}
+
+/// Lightweight atomic counters for hot path (used when Prometheus overhead is too high)
+pub struct AtomicCounters {
+    pub cmd_get: AtomicU64,
+    pub cmd_set: AtomicU64,
+    pub get_hits: AtomicU64,
+    pub get_misses: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+}
+
+impl AtomicCounters {
+    pub fn new() -> Self {
+        Self {
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn inc_cmd_get(&self) {
+        self.cmd_get.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inc_cmd_set(&self) {
+        self.cmd_set.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inc_get_hits(&self) {
+        self.get_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn inc_get_misses(&self) {
+        self.get_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_bytes_read(&self, n: u64) {
+        self.bytes_read.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_bytes_written(&self, n: u64) {
+        self.bytes_written.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl Default for AtomicCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hits and misses over a sliding window, from samples of the cumulative
+/// counters
+#[derive(Debug)]
+pub struct HitWindow {
+    window: Duration,
+    /// (taken at, hits, misses), oldest first
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl HitWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record the counters at `now`, returning the hit ratio since the
+    /// oldest sample inside the window; `None` without gets meanwhile
+    #[allow(clippy::cast_precision_loss)] // a ratio needs no exact counts
+    pub fn sample(&mut self, now: Instant, hits: u64, misses: u64) -> Option<f64> {
+        self.samples.push_back((now, hits, misses));
+        // Keep one sample at or before the window start as the baseline
+        while self.samples.len() > 1 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+        let (_, first_hits, first_misses) = self.samples[0];
+        let hits = hits.saturating_sub(first_hits);
+        let gets = hits + misses.saturating_sub(first_misses);
+        (gets > 0).then(|| hits as f64 / gets as f64)
+    }
+}
+
+/// Creates metrics carrying the `instance` label, if any, and registers
+/// them
+struct Registrar<'a> {
+    registry: &'a Registry,
+    labels: HashMap<String, String>,
+}
+
+impl<'a> Registrar<'a> {
+    fn new(registry: &'a Registry, instance: Option<&str>) -> Self {
+        let labels = instance
+            .map(|instance| HashMap::from([("instance".to_string(), instance.to_string())]))
+            .unwrap_or_default();
+        Self { registry, labels }
+    }
+
+    fn opts(&self, name: &str, help: &str) -> Opts {
+        Opts::new(name, help).const_labels(self.labels.clone())
+    }
+
+    fn histogram_opts(&self, name: &str, help: &str, buckets: &[f64]) -> HistogramOpts {
+        HistogramOpts::new(name, help)
+            .const_labels(self.labels.clone())
+            .buckets(buckets.to_vec())
+    }
+
+    /// Register `metric`, returning it for recording
+    fn register<C: Collector + Clone + 'static>(&self, metric: C) -> prometheus::Result<C> {
+        self.registry.register(Box::new(metric.clone()))?;
+        Ok(metric)
+    }
+
+    fn counter(&self, name: &str, help: &str) -> prometheus::Result<IntCounter> {
+        self.register(IntCounter::with_opts(self.opts(name, help))?)
+    }
+
+    fn counter_vec(
+        &self,
+        name: &str,
+        help: &str,
+        labels: &[&str],
+    ) -> prometheus::Result<IntCounterVec> {
+        self.register(IntCounterVec::new(self.opts(name, help), labels)?)
+    }
+
+    fn gauge(&self, name: &str, help: &str) -> prometheus::Result<IntGauge> {
+        self.register(IntGauge::with_opts(self.opts(name, help))?)
+    }
+
+    fn float_gauge(&self, name: &str, help: &str) -> prometheus::Result<Gauge> {
+        self.register(Gauge::with_opts(self.opts(name, help))?)
+    }
+
+    fn histogram(&self, name: &str, help: &str, buckets: &[f64]) -> prometheus::Result<Histogram> {
+        self.register(Histogram::with_opts(
+            self.histogram_opts(name, help, buckets),
+        )?)
+    }
+
+    fn histogram_vec(
+        &self,
+        name: &str,
+        help: &str,
+        buckets: &[f64],
+        labels: &[&str],
+    ) -> prometheus::Result<HistogramVec> {
+        self.register(HistogramVec::new(
+            self.histogram_opts(name, help, buckets),
+            labels,
+        )?)
+    }
+}
+
+/// Reads a counter kept outside the registry
+type CounterSource = Box<dyn Fn() -> u64 + Send + Sync>;
+
+/// Counters the storage layer keeps in atomics (it has no `Metrics`
+/// handle), copied into `IntCounter`s whenever the registry is gathered
+struct SyncedCounters {
+    counters: Vec<(IntCounter, CounterSource)>,
+}
+
+impl SyncedCounters {
+    fn new(r: &Registrar, sources: Vec<(&str, &str, CounterSource)>) -> prometheus::Result<Self> {
+        Ok(Self {
+            counters: sources
+                .into_iter()
+                .map(|(name, help, source)| {
+                    Ok((IntCounter::with_opts(r.opts(name, help))?, source))
+                })
+                .collect::<prometheus::Result<_>>()?,
+        })
+    }
+
+    /// The process-wide `static` counters
+    fn statics(r: &Registrar) -> prometheus::Result<Self> {
+        let statics: [(&str, &str, &'static AtomicU64); 8] = [
+            (
+                "petracache_hot_cache_hits_total",
+                "Gets answered from the in-process hot cache",
+                &HOT_CACHE_HITS,
+            ),
+            (
+                "petracache_hot_cache_misses_total",
+                "Gets the hot cache passed on to RocksDB",
+                &HOT_CACHE_MISSES,
+            ),
+            // Also counted in petracache_get_misses_total
+            (
+                "petracache_negative_cache_hits_total",
+                "Misses answered by the negative cache without a RocksDB lookup",
+                &NEGATIVE_CACHE_HITS,
+            ),
+            (
+                "petracache_writes_rejected_full_total",
+                "Sets rejected because the database is over its size cap",
+                &FULL_REJECTED_WRITES,
+            ),
+            (
+                "petracache_compression_saved_bytes_total",
+                "Value bytes saved by per-value compression",
+                &COMPRESSION_BYTES_SAVED,
+            ),
+            (
+                "petracache_corrupt_values_removed_total",
+                "Values deleted because they failed to decode",
+                &CORRUPT_VALUES_REMOVED,
+            ),
+            (
+                "petracache_db_repairs_total",
+                "Shards found corrupt at startup and repaired",
+                &DB_REPAIRS,
+            ),
+            (
+                "petracache_db_recreated_total",
+                "Shards found corrupt at startup and recreated empty",
+                &DB_RECREATES,
+            ),
+        ];
+        Self::new(
+            r,
+            statics
+                .into_iter()
+                .map(|(name, help, atomic)| {
+                    let source: CounterSource = Box::new(|| atomic.load(Ordering::Relaxed));
+                    (name, help, source)
+                })
+                .collect(),
+        )
+    }
+
+    /// One storage's TTL expiration counters
+    fn ttl(r: &Registrar, counters: &Arc<TtlCounters>) -> prometheus::Result<Self> {
+        let expired = Arc::clone(counters);
+        let compaction = Arc::clone(counters);
+        Self::new(
+            r,
+            vec![
+                (
+                    "petracache_expired_keys_removed_total",
+                    "Keys removed by lazy expiration or background scan",
+                    Box::new(move || expired.stats().expired_removed),
+                ),
+                (
+                    "petracache_ttl_compaction_removed_total",
+                    "Keys removed by TTL compaction filter",
+                    Box::new(move || compaction.stats().compaction_removed),
+                ),
+            ],
+        )
+    }
+}
+
+impl Collector for SyncedCounters {
+    fn desc(&self) -> Vec<&Desc> {
+        self.counters
+            .iter()
+            .flat_map(|(counter, _)| counter.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.counters
+            .iter()
+            .flat_map(|(counter, source)| {
+                // The sources only grow
+                counter.inc_by(source().saturating_sub(counter.get()));
+                counter.collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{DataPathStats, NamespaceStats};
+
+    #[test]
+    fn test_metrics_creation() {
+        let metrics = Metrics::new().unwrap();
+        metrics.cmd_get.inc();
+        metrics.cmd_set.inc();
+        metrics.get_hits.inc();
+        metrics.active_connections.set(5);
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("petracache_cmd_get_total"));
+        assert!(output.contains("petracache_active_connections"));
+    }
+
+    #[test]
+    fn test_storage_counters_registered() {
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        let storage = RocksStorage::open(&crate::config::StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..Default::default()
+        })
+        .unwrap();
+        let metrics = Metrics::new().unwrap();
+        metrics.register_storage(&storage).unwrap();
+        // Expired in the past: the read removes it
+        storage
+            .set(
+                b"k",
+                crate::storage::StoredValue::with_expire_at(0, 1, b"v".to_vec()),
+            )
+            .unwrap();
+        assert!(storage.get(b"k").unwrap().is_none());
+
+        let families = metrics.registry.gather();
+        let value = |name: &str| {
+            let family = families
+                .iter()
+                .find(|f| f.name() == name)
+                .unwrap_or_else(|| panic!("{name} not registered"));
+            assert_eq!(
+                family.get_field_type(),
+                prometheus::proto::MetricType::COUNTER
+            );
+            family.get_metric()[0].get_counter().value()
+        };
+        assert!((value("petracache_expired_keys_removed_total") - 1.0).abs() < f64::EPSILON);
+        assert!(value("petracache_ttl_compaction_removed_total").abs() < f64::EPSILON);
+        value("petracache_corrupt_values_removed_total");
+
+        // Exposed once, in the text format too
+        let output = metrics.gather().unwrap();
+        assert_eq!(
+            output
+                .matches("# TYPE petracache_expired_keys_removed_total counter")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_instances_share_registry() {
+        let registry = Registry::new();
+        let a = Metrics::new_with_registry(&registry, Some("a")).unwrap();
+        let b = Metrics::new_with_registry(&registry, Some("b")).unwrap();
+        a.cmd_get.inc();
+        b.cmd_get.inc_by(2);
+        a.cmd_latency.with_label_values(&["get"]).observe(0.001);
+
+        let output = a.gather().unwrap();
+        assert!(output.contains("petracache_cmd_get_total{instance=\"a\"} 1\n"));
+        assert!(output.contains("petracache_cmd_get_total{instance=\"b\"} 2\n"));
+        assert!(
+            output.contains(
+                "petracache_cmd_latency_seconds_count{command=\"get\",instance=\"a\"} 1\n"
+            )
+        );
+
+        // Unlabelled series would collide
+        assert!(matches!(
+            Metrics::new_with_registry(&registry, Some("a")),
+            Err(prometheus::Error::AlreadyReg)
+        ));
+        let plain = Registry::new();
+        Metrics::new_with_registry(&plain, None).unwrap();
+        assert!(Metrics::new_with_registry(&plain, None).is_err());
+
+        // Hand-formatted storage gauges are labelled too
+        let db = DbStats {
+            estimated_keys: Some(42),
+            files_per_level: vec![(1, 7)],
+            ..DbStats::default()
+        };
+        let output = a
+            .gather_with_storage_stats(&db, &MemoryUsage::default())
+            .unwrap();
+        assert!(output.contains("petracache_rocksdb_estimated_keys{instance=\"a\"} 42\n"));
+        assert!(output.contains("petracache_rocksdb_sst_files{instance=\"a\",level=\"1\"} 7\n"));
+
+        let db = DbStats {
+            data_paths: vec![DataPathStats {
+                path: "/mnt/nvme\"1".into(),
+                used_bytes: 5,
+                target_bytes: 1 << 30,
+            }],
+            ..DbStats::default()
+        };
+        let output = a
+            .gather_with_storage_stats(&db, &MemoryUsage::default())
+            .unwrap();
+        assert!(output.contains(
+            "petracache_rocksdb_data_path_bytes{instance=\"a\",path=\"/mnt/nvme\\\"1\"} 5\n"
+        ));
+        assert!(output.contains(
+            "petracache_rocksdb_data_path_target_bytes{instance=\"a\",path=\"/mnt/nvme\\\"1\"} 1073741824\n"
+        ));
+    }
+
+    #[test]
+    fn test_rocksdb_statistics() {
+        let shard = |miss: u64| {
+            format!(
+                "rocksdb.block.cache.miss COUNT : {miss}\n\
+                 rocksdb.stall.micros COUNT : 250\n\
+                 rocksdb.db.get.micros P50 : 1.500000 P95 : 4.000000 P99 : 9.000000 P100 : 12.000000 COUNT : 7 SUM : 30\n\
+                 rocksdb.some.future.format VALUE = 3\n\
+                 \n"
+            )
+        };
+        let db = DbStats {
+            statistics: vec![shard(5), shard(6)],
+            ..DbStats::default()
+        };
+        let output = Metrics::new()
+            .unwrap()
+            .gather_with_storage_stats(&db, &MemoryUsage::default())
+            .unwrap();
+
+        // Tickers are summed over shards
+        assert!(output.contains("# TYPE petracache_rocksdb_block_cache_miss_total counter\n"));
+        assert!(output.contains("petracache_rocksdb_block_cache_miss_total 11\n"));
+        assert!(output.contains("petracache_rocksdb_stall_micros_total 500\n"));
+        // Histograms are summaries per shard
+        assert!(output.contains("# TYPE petracache_rocksdb_db_get_micros summary\n"));
+        assert!(
+            output.contains("petracache_rocksdb_db_get_micros{shard=\"1\",quantile=\"0.95\"} 4\n")
+        );
+        assert!(output.contains("petracache_rocksdb_db_get_micros_count{shard=\"0\"} 7\n"));
+        assert!(output.contains("petracache_rocksdb_db_get_micros_sum{shard=\"0\"} 30\n"));
+        // Unknown formats are skipped
+        assert!(!output.contains("future"));
+
+        assert_eq!(
+            parse_statistics_line("rocksdb.db.write.micros P50 : x COUNT : 1"),
+            None
+        );
+        assert_eq!(
+            parse_statistics_line("rocksdb.number.keys.written COUNT : 3"),
+            Some(("rocksdb.number.keys.written", RocksStat::Ticker(3)))
+        );
+    }
+
+    #[test]
+    fn test_hit_window() {
+        let mut window = HitWindow::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(window.sample(start, 0, 0), None);
+        assert_eq!(
+            window.sample(start + Duration::from_secs(30), 3, 1),
+            Some(0.75)
+        );
+        // The first 60s slide out: only the last 30s count
+        assert_eq!(
+            window.sample(start + Duration::from_secs(90), 4, 4),
+            Some(0.25)
+        );
+        assert_eq!(window.sample(start + Duration::from_secs(200), 4, 4), None);
+    }
+
+    #[test]
+    fn test_size_histograms() {
+        let config = MetricsConfig {
+            value_size_buckets: vec![100.0, 10_000.0],
+            ..MetricsConfig::default()
+        };
+        let metrics = Metrics::with_config(&Registry::new(), &config).unwrap();
+        metrics.observe_store(b"key", 50);
+        metrics.observe_store(b"key", 5000);
+        metrics.observe_hit(b"key", 50);
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains("petracache_value_size_bytes_bucket{le=\"100\"} 1\n"));
+        assert!(output.contains("petracache_value_size_bytes_bucket{le=\"10000\"} 2\n"));
+        assert!(output.contains("petracache_key_size_bytes_sum 6\n"));
+
+        // Unsorted buckets are a registration error, not a panic
+        let config = MetricsConfig {
+            key_size_buckets: vec![64.0, 16.0],
+            ..MetricsConfig::default()
+        };
+        assert!(Metrics::with_config(&Registry::new(), &config).is_err());
+    }
+
+    #[test]
+    fn test_cmd_latency_by_command() {
+        let metrics = Metrics::new().unwrap();
+        metrics
+            .cmd_latency
+            .with_label_values(&["get"])
+            .observe(0.0002);
+        metrics
+            .cmd_latency
+            .with_label_values(&["set"])
+            .observe(0.003);
+        metrics.response_write_latency.observe(0.0001);
+
+        let output = metrics.gather().unwrap();
+        assert!(output.contains(r#"petracache_cmd_latency_seconds_count{command="get"} 1"#));
+        assert!(output.contains(r#"petracache_cmd_latency_seconds_count{command="set"} 1"#));
+        assert!(output.contains("petracache_response_write_seconds_count 1"));
+    }
+
+    #[test]
+    fn test_stats_reset() {
+        let metrics = Metrics::new().unwrap();
+        metrics.cmd_get.inc();
+        metrics.bytes_read.inc_by(10);
+        metrics.reset_stats();
+        assert_eq!(metrics.stats(), StatsSnapshot::default());
+
+        metrics.cmd_get.inc();
+        let stats = metrics.stats();
+        assert_eq!(stats.cmd_get, 1);
+        assert_eq!(stats.bytes_read, 0);
+        // Prometheus totals keep counting across resets
+        assert_eq!(metrics.cmd_get.get(), 2);
+    }
+
+    #[test]
+    fn test_storage_stats_gauges() {
+        let metrics = Metrics::new().unwrap();
+        let db = DbStats {
+            estimated_keys: Some(42),
+            total_sst_bytes: None,
+            files_per_level: vec![(0, 3), (1, 7)],
+            namespaces: vec![NamespaceStats {
+                name: "users".to_string(),
+                items: Some(5),
+                hits: 2,
+                misses: 1,
+                used_bytes: 900,
+                max_bytes: 1000,
+                rejected_writes: 0,
+            }],
+            ..DbStats::default()
+        };
+
+        let memory = MemoryUsage {
+            block_cache_usage: 100,
+            memtables_total: 20,
+            table_readers: 3,
+            total: 123,
+            ..MemoryUsage::default()
+        };
+
+        let output = metrics.gather_with_storage_stats(&db, &memory).unwrap();
+        assert!(output.contains("petracache_rocksdb_memory_bytes 123\n"));
+        assert!(output.contains("petracache_rocksdb_table_readers_bytes 3\n"));
+        assert!(output.contains("petracache_rocksdb_estimated_keys 42\n"));
+        assert!(output.contains("petracache_rocksdb_sst_files{level=\"1\"} 7\n"));
+        assert!(output.contains("petracache_namespace_get_hits_total{namespace=\"users\"} 2\n"));
+        assert!(output.contains("petracache_namespace_items{namespace=\"users\"} 5\n"));
+        assert!(output.contains("petracache_namespace_limit_bytes{namespace=\"users\"} 1000\n"));
+        // Unreported properties are left out
+        assert!(!output.contains("petracache_rocksdb_sst_bytes"));
+        assert!(!output.contains("petracache_rocksdb_memtable_bytes"));
+    }
+
+    #[test]
+    fn test_atomic_counters() {
+        let counters = AtomicCounters::new();
+        counters.inc_cmd_get();
+        counters.inc_cmd_get();
+        counters.inc_get_hits();
+
+        assert_eq!(counters.cmd_get.load(Ordering::Relaxed), 2);
+        assert_eq!(counters.get_hits.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,258 @@
+//! Process and allocator metrics, read on every scrape
+//!
+//! Resident memory, open file descriptors, start time and CPU seconds come
+//! from `/proc/self` on Linux; elsewhere they are left out of the output.
+//! jemalloc's `allocated`/`active`/`resident` come from `tikv_jemalloc_ctl`
+//! wherever jemalloc is the global allocator (everywhere but MSVC).
+
+use super::Registrar;
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Counter, Gauge, IntGauge};
+
+/// Refreshes the process gauges in `collect`, so their values are as of
+/// the scrape rather than of the last timer tick
+pub struct ProcessCollector {
+    resident_memory: IntGauge,
+    open_fds: IntGauge,
+    start_time: Gauge,
+    cpu_seconds: Counter,
+    jemalloc_allocated: IntGauge,
+    jemalloc_active: IntGauge,
+    jemalloc_resident: IntGauge,
+}
+
+impl ProcessCollector {
+    pub(super) fn new(r: &Registrar) -> prometheus::Result<Self> {
+        Ok(Self {
+            resident_memory: IntGauge::with_opts(r.opts(
+                "petracache_process_resident_memory_bytes",
+                "Resident memory size in bytes",
+            ))?,
+            open_fds: IntGauge::with_opts(r.opts(
+                "petracache_process_open_fds",
+                "Number of open file descriptors",
+            ))?,
+            start_time: Gauge::with_opts(r.opts(
+                "petracache_process_start_time_seconds",
+                "Start time of the process since the Unix epoch in seconds",
+            ))?,
+            cpu_seconds: Counter::with_opts(r.opts(
+                "petracache_process_cpu_seconds_total",
+                "Total user and system CPU time spent in seconds",
+            ))?,
+            jemalloc_allocated: IntGauge::with_opts(r.opts(
+                "petracache_jemalloc_allocated_bytes",
+                "Bytes allocated by the application through jemalloc",
+            ))?,
+            jemalloc_active: IntGauge::with_opts(r.opts(
+                "petracache_jemalloc_active_bytes",
+                "Bytes in active jemalloc pages",
+            ))?,
+            jemalloc_resident: IntGauge::with_opts(r.opts(
+                "petracache_jemalloc_resident_bytes",
+                "Bytes in physically resident jemalloc pages",
+            ))?,
+        })
+    }
+
+    fn collect_process(&self, families: &mut Vec<MetricFamily>) {
+        let Some(stat) = ProcStat::read() else {
+            return;
+        };
+        if let Some(rss) = resident_memory_bytes() {
+            self.resident_memory.set(rss);
+            families.extend(self.resident_memory.collect());
+        }
+        if let Some(fds) = open_fds() {
+            self.open_fds.set(fds);
+            families.extend(self.open_fds.collect());
+        }
+        if let Some(start) = stat.start_time_seconds() {
+            self.start_time.set(start);
+            families.extend(self.start_time.collect());
+        }
+        // CPU time only grows
+        let cpu = stat.cpu_seconds();
+        self.cpu_seconds
+            .inc_by((cpu - self.cpu_seconds.get()).max(0.0));
+        families.extend(self.cpu_seconds.collect());
+    }
+
+    #[cfg(not(target_env = "msvc"))]
+    fn collect_jemalloc(&self, families: &mut Vec<MetricFamily>) {
+        use tikv_jemalloc_ctl::{epoch, stats};
+
+        // jemalloc caches its statistics until the epoch advances
+        if let Err(e) = epoch::advance() {
+            tracing::debug!("Failed to refresh jemalloc statistics: {}", e);
+            return;
+        }
+        let gauges: [(&IntGauge, tikv_jemalloc_ctl::Result<usize>); 3] = [
+            (&self.jemalloc_allocated, stats::allocated::read()),
+            (&self.jemalloc_active, stats::active::read()),
+            (&self.jemalloc_resident, stats::resident::read()),
+        ];
+        for (gauge, value) in gauges {
+            if let Ok(bytes) = value {
+                gauge.set(i64::try_from(bytes).unwrap_or(i64::MAX));
+                families.extend(gauge.collect());
+            }
+        }
+    }
+
+    #[cfg(target_env = "msvc")]
+    fn collect_jemalloc(&self, _families: &mut Vec<MetricFamily>) {}
+}
+
+impl Collector for ProcessCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        [
+            self.resident_memory.desc(),
+            self.open_fds.desc(),
+            self.start_time.desc(),
+            self.cpu_seconds.desc(),
+            self.jemalloc_allocated.desc(),
+            self.jemalloc_active.desc(),
+            self.jemalloc_resident.desc(),
+        ]
+        .concat()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = Vec::new();
+        self.collect_process(&mut families);
+        self.collect_jemalloc(&mut families);
+        families
+    }
+}
+
+/// `/proc` reports CPU and start times in clock ticks of `USER_HZ`, which
+/// is 100 on every platform Linux exports it to userspace on
+const TICKS_PER_SECOND: f64 = 100.0;
+
+/// The fields of `/proc/self/stat` the collector exports, named as in
+/// proc(5), in clock ticks
+struct ProcStat {
+    utime: u64,
+    stime: u64,
+    /// Time between boot and process start
+    starttime: u64,
+}
+
+impl ProcStat {
+    fn read() -> Option<Self> {
+        // Other systems' /proc, if any, lays out these files differently
+        if !cfg!(target_os = "linux") {
+            return None;
+        }
+        Self::parse(&std::fs::read_to_string("/proc/self/stat").ok()?)
+    }
+
+    fn parse(stat: &str) -> Option<Self> {
+        // The command name is in parentheses and may contain spaces; the
+        // fields after it start with the third, the state
+        let (_, rest) = stat.rsplit_once(')')?;
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+        Some(Self {
+            utime: field(14)?,
+            stime: field(15)?,
+            starttime: field(22)?,
+        })
+    }
+
+    #[allow(clippy::cast_precision_loss)] // tick counts are far below 2^52
+    fn cpu_seconds(&self) -> f64 {
+        (self.utime + self.stime) as f64 / TICKS_PER_SECOND
+    }
+
+    #[allow(clippy::cast_precision_loss)] // see cpu_seconds
+    fn start_time_seconds(&self) -> Option<f64> {
+        Some(boot_time_seconds()? as f64 + self.starttime as f64 / TICKS_PER_SECOND)
+    }
+}
+
+/// The `btime` line of `/proc/stat`
+fn boot_time_seconds() -> Option<u64> {
+    std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// The `VmRSS` line of `/proc/self/status`, which is in kB
+fn resident_memory_bytes() -> Option<i64> {
+    let kb: i64 = std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+fn open_fds() -> Option<i64> {
+    let count = std::fs::read_dir("/proc/self/fd").ok()?.count();
+    i64::try_from(count).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::Registry;
+
+    #[test]
+    fn test_parse_proc_stat() {
+        // A command name with spaces and a closing parenthesis
+        let stat = "4242 (petra (cache) x) S 1 4242 4242 0 -1 4194560 2066 0 0 0 \
+                    150 50 0 0 20 0 9 0 12345 2147483648 2560 18446744073709551615";
+        let stat = ProcStat::parse(stat).unwrap();
+        assert_eq!(stat.utime, 150);
+        assert_eq!(stat.stime, 50);
+        assert_eq!(stat.starttime, 12345);
+        assert!((stat.cpu_seconds() - 2.0).abs() < f64::EPSILON);
+
+        assert!(ProcStat::parse("4242 (petracache) S 1").is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_gauges_positive() {
+        let registry = Registry::new();
+        let collector = ProcessCollector::new(&Registrar::new(&registry, None)).unwrap();
+        // Burn CPU until the process has used at least one tick
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        let mut sum = 0u64;
+        while ProcStat::read().unwrap().cpu_seconds() <= 0.0 && std::time::Instant::now() < deadline
+        {
+            for i in 0..100_000u64 {
+                sum = sum.wrapping_add(std::hint::black_box(i));
+            }
+        }
+        std::hint::black_box(sum);
+
+        let families = collector.collect();
+        let value = |name: &str| {
+            let family = families.iter().find(|f| f.name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            if family.get_field_type() == prometheus::proto::MetricType::COUNTER {
+                metric.get_counter().value()
+            } else {
+                metric.get_gauge().value()
+            }
+        };
+        assert!(value("petracache_process_resident_memory_bytes") > 0.0);
+        assert!(value("petracache_process_open_fds") > 0.0);
+        assert!(value("petracache_process_start_time_seconds") > 1_500_000_000.0);
+        assert!(value("petracache_process_cpu_seconds_total") > 0.0);
+        #[cfg(not(target_env = "msvc"))]
+        assert!(value("petracache_jemalloc_resident_bytes") > 0.0);
+    }
+}
@@ -0,0 +1,97 @@
+//! Minimal standard-alphabet base64 codec for meta protocol keys (`b` flag)
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode bytes as padded base64
+pub fn encode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        out.push(ALPHABET[(n >> 18) as usize & 63]);
+        out.push(ALPHABET[(n >> 12) as usize & 63]);
+        out.push(if chunk.len() > 1 {
+            ALPHABET[(n >> 6) as usize & 63]
+        } else {
+            b'='
+        });
+        out.push(if chunk.len() > 2 {
+            ALPHABET[n as usize & 63]
+        } else {
+            b'='
+        });
+    }
+    out
+}
+
+/// Decode padded base64, returning `None` on any malformed input
+pub fn decode(input: &[u8]) -> Option<Vec<u8>> {
+    if input.is_empty() || input.len() % 4 != 0 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(input.len() / 4 * 3);
+    let last = input.len() / 4 - 1;
+    for (i, chunk) in input.chunks(4).enumerate() {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && i != last) {
+            return None;
+        }
+
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            n = (n << 6) | u32::from(sextet(c)?);
+        }
+        n <<= 6 * padding;
+
+        let bytes = n.to_be_bytes();
+        out.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Some(out)
+}
+
+/// Map a base64 character to its 6-bit value
+fn sextet(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for input in [
+            &b"f"[..],
+            b"fo",
+            b"foo",
+            b"foob",
+            b"key with spaces",
+            &[0xff, 0x00, 0x80],
+        ] {
+            assert_eq!(decode(&encode(input)).unwrap(), input);
+        }
+        assert_eq!(encode(b"foobar"), b"Zm9vYmFy");
+        assert_eq!(encode(b"fo"), b"Zm8=");
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(decode(b"").is_none());
+        assert!(decode(b"Zm9").is_none());
+        assert!(decode(b"Zm9v!A==").is_none());
+        assert!(decode(b"Z===").is_none());
+        assert!(decode(b"Zg==Zg==").is_none());
+    }
+}
@@ -0,0 +1,267 @@
+//! Memcached binary protocol framing
+//!
+//! Every packet starts with a 24-byte header followed by `extras`, `key` and
+//! `value`. A connection is switched to binary framing when its first byte is
+//! the request magic (0x80); see `server::connection`.
+
+use crate::ProtocolError;
+use bytes::{BufMut, BytesMut};
+
+/// Magic byte of a request packet
+pub const REQUEST_MAGIC: u8 = 0x80;
+
+/// Magic byte of a response packet
+pub const RESPONSE_MAGIC: u8 = 0x81;
+
+/// Size of the fixed packet header
+pub const HEADER_LEN: usize = 24;
+
+/// Binary protocol opcodes
+pub mod opcode {
+    pub const GET: u8 = 0x00;
+    pub const SET: u8 = 0x01;
+    pub const ADD: u8 = 0x02;
+    pub const REPLACE: u8 = 0x03;
+    pub const DELETE: u8 = 0x04;
+    pub const QUIT: u8 = 0x07;
+    pub const GETQ: u8 = 0x09;
+    pub const NOOP: u8 = 0x0a;
+    pub const VERSION: u8 = 0x0b;
+    pub const GETK: u8 = 0x0c;
+    pub const GETKQ: u8 = 0x0d;
+    pub const STAT: u8 = 0x10;
+    pub const SETQ: u8 = 0x11;
+    pub const ADDQ: u8 = 0x12;
+    pub const REPLACEQ: u8 = 0x13;
+    pub const DELETEQ: u8 = 0x14;
+    pub const QUITQ: u8 = 0x17;
+}
+
+/// Binary protocol response status codes
+pub mod status {
+    pub const NO_ERROR: u16 = 0x0000;
+    pub const KEY_NOT_FOUND: u16 = 0x0001;
+    pub const KEY_EXISTS: u16 = 0x0002;
+    pub const VALUE_TOO_LARGE: u16 = 0x0003;
+    pub const INVALID_ARGUMENTS: u16 = 0x0004;
+    pub const ITEM_NOT_STORED: u16 = 0x0005;
+    pub const UNKNOWN_COMMAND: u16 = 0x0081;
+    pub const OUT_OF_MEMORY: u16 = 0x0082;
+    pub const NOT_SUPPORTED: u16 = 0x0083;
+    pub const INTERNAL_ERROR: u16 = 0x0084;
+}
+
+/// Whether the opcode is one of the gets (GET, GETQ, GETK, GETKQ)
+pub fn is_get(op: u8) -> bool {
+    matches!(
+        op,
+        opcode::GET | opcode::GETQ | opcode::GETK | opcode::GETKQ
+    )
+}
+
+/// A parsed request packet, borrowing its body from the read buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BinaryRequest<'a> {
+    pub opcode: u8,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: &'a [u8],
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+/// A response packet to encode
+#[derive(Debug, Clone, Default)]
+pub struct BinaryResponse<'a> {
+    pub opcode: u8,
+    pub status: u16,
+    pub opaque: u32,
+    pub cas: u64,
+    pub extras: &'a [u8],
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+}
+
+impl BinaryResponse<'_> {
+    /// Whether this answers a get with a value
+    pub fn is_hit(&self) -> bool {
+        self.status == status::NO_ERROR && is_get(self.opcode)
+    }
+
+    /// Encode header and body into `buf`
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let body_len = self.extras.len() + self.key.len() + self.value.len();
+        buf.reserve(HEADER_LEN + body_len);
+        buf.put_u8(RESPONSE_MAGIC);
+        buf.put_u8(self.opcode);
+        // Key length is bounded by MAX_KEY_LENGTH, extras by the fixed layouts
+        buf.put_u16(u16::try_from(self.key.len()).unwrap_or(u16::MAX));
+        buf.put_u8(u8::try_from(self.extras.len()).unwrap_or(u8::MAX));
+        buf.put_u8(0); // data type
+        buf.put_u16(self.status);
+        buf.put_u32(u32::try_from(body_len).unwrap_or(u32::MAX));
+        buf.put_u32(self.opaque);
+        buf.put_u64(self.cas);
+        buf.extend_from_slice(self.extras);
+        buf.extend_from_slice(self.key);
+        buf.extend_from_slice(self.value);
+    }
+}
+
+/// Parse one request packet from the start of `buf`
+///
+/// Returns `Ok(None)` until the whole packet has arrived, and the request
+/// with the number of bytes it occupies otherwise. A value longer than
+/// `max_value_len` fails with `ProtocolError::ValueTooLarge` as soon as the
+/// header has arrived, so the body it announces is never buffered.
+pub fn parse_request(
+    buf: &[u8],
+    max_value_len: usize,
+) -> Result<Option<(BinaryRequest<'_>, usize)>, ProtocolError> {
+    if buf.len() < HEADER_LEN {
+        return Ok(None);
+    }
+
+    if buf[0] != REQUEST_MAGIC {
+        return Err(ProtocolError::InvalidCommand(format!(
+            "bad binary magic 0x{:02x}",
+            buf[0]
+        )));
+    }
+
+    let opcode = buf[1];
+    let key_len = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+    let extras_len = usize::from(buf[4]);
+    let body_len = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]) as usize;
+    let opaque = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    let cas = u64::from_be_bytes(buf[16..24].try_into().unwrap_or([0; 8]));
+
+    if extras_len + key_len > body_len {
+        return Err(ProtocolError::InvalidBytesLength);
+    }
+
+    let total = HEADER_LEN + body_len;
+    if body_len - extras_len - key_len > max_value_len {
+        return Err(ProtocolError::ValueTooLarge { discard: total });
+    }
+    if buf.len() < total {
+        return Ok(None);
+    }
+
+    let body = &buf[HEADER_LEN..total];
+    let (extras, rest) = body.split_at(extras_len);
+    let (key, value) = rest.split_at(key_len);
+
+    Ok(Some((
+        BinaryRequest {
+            opcode,
+            opaque,
+            cas,
+            extras,
+            key,
+            value,
+        },
+        total,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(opcode: u8, extras: &[u8], key: &[u8], value: &[u8], opaque: u32) -> Vec<u8> {
+        let mut buf = vec![REQUEST_MAGIC, opcode];
+        buf.extend_from_slice(&u16::try_from(key.len()).unwrap().to_be_bytes());
+        buf.push(u8::try_from(extras.len()).unwrap());
+        buf.extend_from_slice(&[0, 0, 0]);
+        let body_len = extras.len() + key.len() + value.len();
+        buf.extend_from_slice(&u32::try_from(body_len).unwrap().to_be_bytes());
+        buf.extend_from_slice(&opaque.to_be_bytes());
+        buf.extend_from_slice(&7u64.to_be_bytes());
+        buf.extend_from_slice(extras);
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(value);
+        buf
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let packet = request(opcode::SET, &[0, 0, 0, 1, 0, 0, 0, 0], b"foo", b"bar", 42);
+        let (req, consumed) = parse_request(&packet, 1024).unwrap().unwrap();
+        assert_eq!(consumed, packet.len());
+        assert_eq!(req.opcode, opcode::SET);
+        assert_eq!(req.opaque, 42);
+        assert_eq!(req.cas, 7);
+        assert_eq!(req.extras.len(), 8);
+        assert_eq!(req.key, b"foo");
+        assert_eq!(req.value, b"bar");
+    }
+
+    #[test]
+    fn test_parse_request_partial() {
+        let packet = request(opcode::GET, &[], b"foo", &[], 1);
+        assert!(parse_request(&packet[..10], 1024).unwrap().is_none());
+        assert!(
+            parse_request(&packet[..=HEADER_LEN], 1024)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_parse_request_invalid() {
+        let mut packet = request(opcode::GET, &[], b"foo", &[], 1);
+        packet[0] = b'g';
+        assert!(parse_request(&packet, 1024).is_err());
+
+        // Key length larger than the body
+        let mut packet = request(opcode::GET, &[], b"foo", &[], 1);
+        packet[3] = 10;
+        assert_eq!(
+            parse_request(&packet, 1024),
+            Err(ProtocolError::InvalidBytesLength)
+        );
+
+        // An oversized value is refused from the header alone
+        let packet = request(opcode::SET, &[0; 8], b"foo", &[0; 100], 1);
+        assert!(parse_request(&packet[..HEADER_LEN], 100).unwrap().is_none());
+        assert_eq!(
+            parse_request(&packet[..HEADER_LEN], 99),
+            Err(ProtocolError::ValueTooLarge {
+                discard: packet.len()
+            })
+        );
+        let mut header = packet[..HEADER_LEN].to_vec();
+        header[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            parse_request(&header, 1 << 20),
+            Err(ProtocolError::ValueTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_encode_response() {
+        let mut buf = BytesMut::new();
+        BinaryResponse {
+            opcode: opcode::GETK,
+            status: status::NO_ERROR,
+            opaque: 9,
+            cas: 5,
+            extras: &[0, 0, 0, 3],
+            key: b"k",
+            value: b"vv",
+        }
+        .encode(&mut buf);
+
+        assert_eq!(buf.len(), HEADER_LEN + 7);
+        assert_eq!(buf[0], RESPONSE_MAGIC);
+        assert_eq!(buf[1], opcode::GETK);
+        assert_eq!(&buf[2..4], &[0, 1]);
+        assert_eq!(buf[4], 4);
+        assert_eq!(&buf[6..8], &[0, 0]);
+        assert_eq!(&buf[8..12], &[0, 0, 0, 7]);
+        assert_eq!(&buf[12..16], &[0, 0, 0, 9]);
+        assert_eq!(&buf[16..24], &5u64.to_be_bytes());
+        assert_eq!(&buf[24..], b"\0\0\0\x03kvv");
+    }
+}
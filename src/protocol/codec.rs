@@ -0,0 +1,266 @@
+//! `tokio_util::codec` framing for the ASCII protocol
+//!
+//! `MemcachedCodec` owns the buffer management the server's connection loop
+//! does by hand: storage commands whose data block is still arriving, the
+//! bytes each command consumed, and resynchronization after a rejected
+//! command. Protocol errors are yielded as items rather than decoder errors,
+//! because `Framed` stops reading after the first `Err`.
+//!
+//! ```ignore
+//! let mut framed = Framed::new(stream, MemcachedCodec::default());
+//! while let Some(frame) = framed.next().await {
+//!     let mut response = ResponseWriter::new(1024);
+//!     match frame? {
+//!         Ok(cmd) => handle(cmd, &mut response),
+//!         Err(e) => e.write_response(&mut response),
+//!     }
+//!     framed.send(response.take()).await?;
+//! }
+//! ```
+
+use crate::ProtocolError;
+use crate::protocol::command::Command;
+use crate::protocol::parser::{
+    ParseLimits, ParseResult, PendingStorageCommand, find_line, parse_storage_command_line,
+    parse_storage_data, parse_with_limits,
+};
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Decoder for ASCII commands, encoder for replies built with `ResponseWriter`
+#[derive(Debug, Clone, Default)]
+pub struct MemcachedCodec {
+    limits: ParseLimits,
+    pending: Option<PendingStorageCommand>,
+    discard: Discard,
+}
+
+impl MemcachedCodec {
+    /// Create a codec enforcing `limits`
+    pub fn new(limits: ParseLimits) -> Self {
+        Self {
+            limits,
+            ..Self::default()
+        }
+    }
+
+    /// The limits this codec enforces
+    pub fn limits(&self) -> &ParseLimits {
+        &self.limits
+    }
+}
+
+impl Decoder for MemcachedCodec {
+    type Item = Result<Command<'static>, ProtocolError>;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if !self.discard.skip(src, self.limits.accept_bare_lf) {
+            return Ok(None);
+        }
+
+        let result = match self.pending {
+            Some(ref mut pending) => parse_storage_data(src, pending, &self.limits),
+            None => parse_with_limits(src, &self.limits),
+        };
+        match result {
+            ParseResult::Complete(cmd, consumed) => {
+                let cmd = cmd.into_owned();
+                self.pending = None;
+                src.advance(consumed);
+                Ok(Some(Ok(cmd)))
+            }
+            ParseResult::NeedMoreData => {
+                if self.pending.is_none() {
+                    self.pending = start_pending_storage(src, &self.limits);
+                }
+                Ok(None)
+            }
+            ParseResult::Error(e) => {
+                self.pending = None;
+                self.discard = Discard::after(&e);
+                Ok(Some(Err(e)))
+            }
+        }
+    }
+}
+
+impl Encoder<BytesMut> for MemcachedCodec {
+    type Error = std::io::Error;
+
+    /// Queue a reply, typically `ResponseWriter::take()`
+    fn encode(&mut self, item: BytesMut, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+/// Recognize a storage command whose data block is still arriving
+///
+/// The command line is consumed right away so later reads only check whether
+/// the data has arrived, and the buffer makes room for all of it at once.
+/// Oversize values were already rejected by the parser, so nothing larger
+/// than `max_item_size` is ever reserved.
+pub(crate) fn start_pending_storage(
+    read_buf: &mut BytesMut,
+    limits: &ParseLimits,
+) -> Option<PendingStorageCommand> {
+    let mut pending = parse_storage_command_line(read_buf, limits).ok()??;
+    read_buf.advance(pending.data_start);
+    pending.data_start = 0;
+    read_buf.reserve(pending.bytes + 2);
+    Some(pending)
+}
+
+/// Input still to be dropped after a rejected command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Discard {
+    #[default]
+    None,
+    /// A rejected data block (with its command line) of known length
+    Bytes(usize),
+    /// The rest of a rejected command line, up to and including its terminator
+    Line,
+}
+
+impl Discard {
+    /// What to skip so the input after `e` is parsed as the next command
+    pub(crate) fn after(e: &ProtocolError) -> Self {
+        match e {
+            // Oversize value or bad data chunk: skip the announced data block
+            // so it isn't re-parsed as commands
+            ProtocolError::ValueTooLarge { discard: n }
+            | ProtocolError::BadDataChunk { discard: n } => Self::Bytes(*n),
+            // Skip the rejected line; an overlong one may still be missing
+            // its terminator
+            _ => Self::Line,
+        }
+    }
+
+    /// Drop skipped input from the buffer; returns false while more is expected
+    pub(crate) fn skip(&mut self, read_buf: &mut BytesMut, accept_bare_lf: bool) -> bool {
+        match *self {
+            Self::None => true,
+            Self::Bytes(n) => {
+                let available = n.min(read_buf.len());
+                read_buf.advance(available);
+                *self = if available == n {
+                    Self::None
+                } else {
+                    Self::Bytes(n - available)
+                };
+                available == n
+            }
+            Self::Line => {
+                if let Some((_, next)) = find_line(read_buf, accept_bare_lf) {
+                    read_buf.advance(next);
+                    *self = Self::None;
+                    true
+                } else {
+                    // Keep a trailing \r, its \n may come with the next read
+                    let keep = usize::from(read_buf.ends_with(b"\r"));
+                    read_buf.advance(read_buf.len() - keep);
+                    false
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode every frame currently available
+    fn decode_all(
+        codec: &mut MemcachedCodec,
+        buf: &mut BytesMut,
+    ) -> Vec<Result<Command<'static>, ProtocolError>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = codec.decode(buf).unwrap() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    #[test]
+    fn test_decode_pipelined() {
+        let mut codec = MemcachedCodec::default();
+        let mut buf = BytesMut::from(&b"set foo 0 0 3\r\nbar\r\nget foo\r\nver"[..]);
+
+        let frames = decode_all(&mut codec, &mut buf);
+        assert_eq!(frames.len(), 2);
+        assert!(matches!(&frames[0], Ok(Command::Set { data, .. }) if data.as_ref() == b"bar"));
+        assert!(matches!(&frames[1], Ok(Command::Get { keys }) if keys.len() == 1));
+        assert_eq!(&buf[..], b"ver");
+
+        buf.extend_from_slice(b"sion\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(Command::Version)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_partial_data_block() {
+        let mut codec = MemcachedCodec::default();
+        let mut buf = BytesMut::from(&b"set foo 0 0 5\r\nhe"[..]);
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        // The command line is consumed once recognized
+        assert_eq!(&buf[..], b"he");
+
+        buf.extend_from_slice(b"llo\r\n");
+        match codec.decode(&mut buf).unwrap() {
+            Some(Ok(Command::Set { key, data, .. })) => {
+                assert_eq!(key.as_ref(), b"foo");
+                assert_eq!(data.as_ref(), b"hello");
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_recovers_from_errors() {
+        let mut codec = MemcachedCodec::default();
+        let mut buf = BytesMut::from(&b"bogus\r\nset foo 0 0 3\r\nabcdeget foo\r\n"[..]);
+
+        let frames = decode_all(&mut codec, &mut buf);
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(frames[0], Err(ProtocolError::UnknownCommand(_))));
+        assert!(matches!(frames[1], Err(ProtocolError::BadDataChunk { .. })));
+        // Only the announced block and its terminator were skipped
+        assert!(matches!(&frames[2], Ok(Command::Get { keys }) if keys[0].as_ref() == b"foo"));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_skips_oversize_value() {
+        let mut codec = MemcachedCodec::new(ParseLimits {
+            max_item_size: 4,
+            ..ParseLimits::default()
+        });
+        let mut buf = BytesMut::from(&b"set foo 0 0 10\r\n0123"[..]);
+
+        assert!(matches!(
+            codec.decode(&mut buf).unwrap(),
+            Some(Err(ProtocolError::ValueTooLarge { .. }))
+        ));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"456789\r\nversion\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Ok(Command::Version)));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut codec = MemcachedCodec::default();
+        let mut dst = BytesMut::new();
+        codec
+            .encode(BytesMut::from(&b"STORED\r\n"[..]), &mut dst)
+            .unwrap();
+        codec
+            .encode(BytesMut::from(&b"END\r\n"[..]), &mut dst)
+            .unwrap();
+        assert_eq!(&dst[..], b"STORED\r\nEND\r\n");
+    }
+}
@@ -1,5 +1,8 @@
 //! Memcached ASCII protocol command types
 
+use crate::ProtocolError;
+use crate::protocol::meta::{MetaFlags, MetaSetMode};
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 
 /// Maximum key length (memcached spec)
@@ -11,19 +14,131 @@ pub enum Command<'a> {
     /// get <key>*
     Get { keys: Vec<Cow<'a, [u8]>> },
 
+    /// gets <key>* - like get, but each value carries its CAS unique token
+    Gets { keys: Vec<Cow<'a, [u8]>> },
+
+    /// gat <exptime> <key>* - get and update the expiration time
+    Gat {
+        exptime: i64,
+        keys: Vec<Cow<'a, [u8]>>,
+    },
+
+    /// gats <exptime> <key>* - gat with CAS unique tokens
+    Gats {
+        exptime: i64,
+        keys: Vec<Cow<'a, [u8]>>,
+    },
+
     /// set <key> <flags> <exptime> <bytes> [noreply]
     Set {
         key: Cow<'a, [u8]>,
         flags: u32,
-        exptime: u64,
+        exptime: i64,
+        data: Cow<'a, [u8]>,
+        noreply: bool,
+    },
+
+    /// add <key> <flags> <exptime> <bytes> [noreply]
+    /// Stores only if the key does not already exist
+    Add {
+        key: Cow<'a, [u8]>,
+        flags: u32,
+        exptime: i64,
         data: Cow<'a, [u8]>,
         noreply: bool,
     },
 
+    /// replace <key> <flags> <exptime> <bytes> [noreply]
+    /// Stores only if the key already exists
+    Replace {
+        key: Cow<'a, [u8]>,
+        flags: u32,
+        exptime: i64,
+        data: Cow<'a, [u8]>,
+        noreply: bool,
+    },
+
+    /// append <key> <flags> <exptime> <bytes> [noreply]
+    /// flags and exptime are ignored; the existing item keeps its own
+    Append {
+        key: Cow<'a, [u8]>,
+        data: Cow<'a, [u8]>,
+        noreply: bool,
+    },
+
+    /// prepend <key> <flags> <exptime> <bytes> [noreply]
+    /// flags and exptime are ignored; the existing item keeps its own
+    Prepend {
+        key: Cow<'a, [u8]>,
+        data: Cow<'a, [u8]>,
+        noreply: bool,
+    },
+
+    /// cas <key> <flags> <exptime> <bytes> <cas unique> [noreply]
+    /// Stores only if the item is unchanged since `gets` returned `cas_unique`
+    Cas {
+        key: Cow<'a, [u8]>,
+        flags: u32,
+        exptime: i64,
+        data: Cow<'a, [u8]>,
+        cas_unique: u64,
+        noreply: bool,
+    },
+
     /// delete <key> [exptime] [noreply]
     /// exptime is ignored but parsed for mcrouter compatibility
     Delete { key: Cow<'a, [u8]>, noreply: bool },
 
+    /// incr <key> <delta> [noreply]
+    Incr {
+        key: Cow<'a, [u8]>,
+        delta: u64,
+        noreply: bool,
+    },
+
+    /// decr <key> <delta> [noreply]
+    Decr {
+        key: Cow<'a, [u8]>,
+        delta: u64,
+        noreply: bool,
+    },
+
+    /// flush_all [delay] [noreply]
+    FlushAll { delay: u64, noreply: bool },
+
+    /// mg <key> <flags>* (meta get)
+    MetaGet {
+        key: Cow<'a, [u8]>,
+        flags: MetaFlags<'a>,
+    },
+
+    /// ms <key> <datalen> <flags>* (meta set)
+    MetaSet {
+        key: Cow<'a, [u8]>,
+        data: Cow<'a, [u8]>,
+        mode: MetaSetMode,
+        flags: MetaFlags<'a>,
+    },
+
+    /// md <key> <flags>* (meta delete)
+    MetaDelete {
+        key: Cow<'a, [u8]>,
+        flags: MetaFlags<'a>,
+    },
+
+    /// ma <key> <flags>* (meta arithmetic)
+    MetaArithmetic {
+        key: Cow<'a, [u8]>,
+        incr: bool,
+        flags: MetaFlags<'a>,
+    },
+
+    /// mn (meta no-op, fences quiet pipelines)
+    MetaNoop,
+
+    /// stats [args]
+    Stats { args: Option<Cow<'a, [u8]>> },
+
     /// version - returns server version (used by mcrouter for health checks)
     Version,
 
@@ -32,22 +147,475 @@ pub enum Command<'a> {
 }
 
 impl Command<'_> {
+    /// Detach the command from the read buffer, copying borrowed keys and data
+    #[allow(clippy::too_many_lines)]
+    pub fn into_owned(self) -> Command<'static> {
+        fn owned(bytes: Cow<'_, [u8]>) -> Cow<'static, [u8]> {
+            Cow::Owned(bytes.into_owned())
+        }
+        fn owned_keys(keys: Vec<Cow<'_, [u8]>>) -> Vec<Cow<'static, [u8]>> {
+            keys.into_iter().map(owned).collect()
+        }
+
+        match self {
+            Command::Get { keys } => Command::Get {
+                keys: owned_keys(keys),
+            },
+            Command::Gets { keys } => Command::Gets {
+                keys: owned_keys(keys),
+            },
+            Command::Gat { exptime, keys } => Command::Gat {
+                exptime,
+                keys: owned_keys(keys),
+            },
+            Command::Gats { exptime, keys } => Command::Gats {
+                exptime,
+                keys: owned_keys(keys),
+            },
+            Command::Set {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            } => Command::Set {
+                key: owned(key),
+                flags,
+                exptime,
+                data: owned(data),
+                noreply,
+            },
+            Command::Add {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            } => Command::Add {
+                key: owned(key),
+                flags,
+                exptime,
+                data: owned(data),
+                noreply,
+            },
+            Command::Replace {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            } => Command::Replace {
+                key: owned(key),
+                flags,
+                exptime,
+                data: owned(data),
+                noreply,
+            },
+            Command::Append { key, data, noreply } => Command::Append {
+                key: owned(key),
+                data: owned(data),
+                noreply,
+            },
+            Command::Prepend { key, data, noreply } => Command::Prepend {
+                key: owned(key),
+                data: owned(data),
+                noreply,
+            },
+            Command::Cas {
+                key,
+                flags,
+                exptime,
+                data,
+                cas_unique,
+                noreply,
+            } => Command::Cas {
+                key: owned(key),
+                flags,
+                exptime,
+                data: owned(data),
+                cas_unique,
+                noreply,
+            },
+            Command::Delete { key, noreply } => Command::Delete {
+                key: owned(key),
+                noreply,
+            },
+            Command::Incr {
+                key,
+                delta,
+                noreply,
+            } => Command::Incr {
+                key: owned(key),
+                delta,
+                noreply,
+            },
+            Command::Decr {
+                key,
+                delta,
+                noreply,
+            } => Command::Decr {
+                key: owned(key),
+                delta,
+                noreply,
+            },
+            Command::FlushAll { delay, noreply } => Command::FlushAll { delay, noreply },
+            Command::MetaGet { key, flags } => Command::MetaGet {
+                key: owned(key),
+                flags: flags.into_owned(),
+            },
+            Command::MetaSet {
+                key,
+                data,
+                mode,
+                flags,
+            } => Command::MetaSet {
+                key: owned(key),
+                data: owned(data),
+                mode,
+                flags: flags.into_owned(),
+            },
+            Command::MetaDelete { key, flags } => Command::MetaDelete {
+                key: owned(key),
+                flags: flags.into_owned(),
+            },
+            Command::MetaArithmetic { key, incr, flags } => Command::MetaArithmetic {
+                key: owned(key),
+                incr,
+                flags: flags.into_owned(),
+            },
+            Command::MetaNoop => Command::MetaNoop,
+            Command::Stats { args } => Command::Stats {
+                args: args.map(owned),
+            },
+            Command::Version => Command::Version,
+            Command::Quit => Command::Quit,
+        }
+    }
+
+    /// Command name as sent on the wire (metric label)
+    pub fn name(&self) -> &'static str {
+        self.kind().name()
+    }
+
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Command::Get { .. } => CommandKind::Get,
+            Command::Gets { .. } => CommandKind::Gets,
+            Command::Gat { .. } => CommandKind::Gat,
+            Command::Gats { .. } => CommandKind::Gats,
+            Command::Set { .. } => CommandKind::Set,
+            Command::Add { .. } => CommandKind::Add,
+            Command::Replace { .. } => CommandKind::Replace,
+            Command::Append { .. } => CommandKind::Append,
+            Command::Prepend { .. } => CommandKind::Prepend,
+            Command::Cas { .. } => CommandKind::Cas,
+            Command::Delete { .. } => CommandKind::Delete,
+            Command::Incr { .. } => CommandKind::Incr,
+            Command::Decr { .. } => CommandKind::Decr,
+            Command::FlushAll { .. } => CommandKind::FlushAll,
+            Command::MetaGet { .. } => CommandKind::MetaGet,
+            Command::MetaSet { .. } => CommandKind::MetaSet,
+            Command::MetaDelete { .. } => CommandKind::MetaDelete,
+            Command::MetaArithmetic { .. } => CommandKind::MetaArithmetic,
+            Command::MetaNoop => CommandKind::MetaNoop,
+            Command::Stats { .. } => CommandKind::Stats,
+            Command::Version => CommandKind::Version,
+            Command::Quit => CommandKind::Quit,
+        }
+    }
+
+    /// The key the command acts on; the first one for multi-key gets
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::Get { keys }
+            | Command::Gets { keys }
+            | Command::Gat { keys, .. }
+            | Command::Gats { keys, .. } => keys.first().map(AsRef::as_ref),
+            Command::Set { key, .. }
+            | Command::Add { key, .. }
+            | Command::Replace { key, .. }
+            | Command::Append { key, .. }
+            | Command::Prepend { key, .. }
+            | Command::Cas { key, .. }
+            | Command::Delete { key, .. }
+            | Command::Incr { key, .. }
+            | Command::Decr { key, .. }
+            | Command::MetaGet { key, .. }
+            | Command::MetaSet { key, .. }
+            | Command::MetaDelete { key, .. }
+            | Command::MetaArithmetic { key, .. } => Some(key),
+            Command::FlushAll { .. }
+            | Command::MetaNoop
+            | Command::Stats { .. }
+            | Command::Version
+            | Command::Quit => None,
+        }
+    }
+
+    /// Length of the data block of a storage command
+    pub fn data_len(&self) -> Option<usize> {
+        match self {
+            Command::Set { data, .. }
+            | Command::Add { data, .. }
+            | Command::Replace { data, .. }
+            | Command::Append { data, .. }
+            | Command::Prepend { data, .. }
+            | Command::Cas { data, .. }
+            | Command::MetaSet { data, .. } => Some(data.len()),
+            _ => None,
+        }
+    }
+
+    /// Returns true for commands that return values (get family, mg)
+    pub fn is_retrieval(&self) -> bool {
+        matches!(
+            self,
+            Command::Get { .. }
+                | Command::Gets { .. }
+                | Command::Gat { .. }
+                | Command::Gats { .. }
+                | Command::MetaGet { .. }
+        )
+    }
+
     /// Returns true if this command should not send a response
     pub fn is_noreply(&self) -> bool {
         match self {
-            Command::Set { noreply, .. } | Command::Delete { noreply, .. } => *noreply,
+            Command::Set { noreply, .. }
+            | Command::Add { noreply, .. }
+            | Command::Replace { noreply, .. }
+            | Command::Append { noreply, .. }
+            | Command::Prepend { noreply, .. }
+            | Command::Cas { noreply, .. }
+            | Command::Delete { noreply, .. }
+            | Command::Incr { noreply, .. }
+            | Command::Decr { noreply, .. }
+            | Command::FlushAll { noreply, .. } => *noreply,
             _ => false,
         }
     }
 }
 
+/// A command without its arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandKind {
+    Get,
+    Gets,
+    Gat,
+    Gats,
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas,
+    Delete,
+    Incr,
+    Decr,
+    FlushAll,
+    MetaGet,
+    MetaSet,
+    MetaDelete,
+    MetaArithmetic,
+    MetaNoop,
+    Stats,
+    Version,
+    Quit,
+}
+
+impl CommandKind {
+    pub const ALL: [CommandKind; 22] = [
+        CommandKind::Get,
+        CommandKind::Gets,
+        CommandKind::Gat,
+        CommandKind::Gats,
+        CommandKind::Set,
+        CommandKind::Add,
+        CommandKind::Replace,
+        CommandKind::Append,
+        CommandKind::Prepend,
+        CommandKind::Cas,
+        CommandKind::Delete,
+        CommandKind::Incr,
+        CommandKind::Decr,
+        CommandKind::FlushAll,
+        CommandKind::MetaGet,
+        CommandKind::MetaSet,
+        CommandKind::MetaDelete,
+        CommandKind::MetaArithmetic,
+        CommandKind::MetaNoop,
+        CommandKind::Stats,
+        CommandKind::Version,
+        CommandKind::Quit,
+    ];
+
+    /// Command name as sent on the wire
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandKind::Get => "get",
+            CommandKind::Gets => "gets",
+            CommandKind::Gat => "gat",
+            CommandKind::Gats => "gats",
+            CommandKind::Set => "set",
+            CommandKind::Add => "add",
+            CommandKind::Replace => "replace",
+            CommandKind::Append => "append",
+            CommandKind::Prepend => "prepend",
+            CommandKind::Cas => "cas",
+            CommandKind::Delete => "delete",
+            CommandKind::Incr => "incr",
+            CommandKind::Decr => "decr",
+            CommandKind::FlushAll => "flush_all",
+            CommandKind::MetaGet => "mg",
+            CommandKind::MetaSet => "ms",
+            CommandKind::MetaDelete => "md",
+            CommandKind::MetaArithmetic => "ma",
+            CommandKind::MetaNoop => "mn",
+            CommandKind::Stats => "stats",
+            CommandKind::Version => "version",
+            CommandKind::Quit => "quit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+
+    /// Whether the command can change stored data (`gat` and `gats` touch
+    /// the expiration time)
+    pub fn is_write(self) -> bool {
+        matches!(
+            self,
+            CommandKind::Gat
+                | CommandKind::Gats
+                | CommandKind::Set
+                | CommandKind::Add
+                | CommandKind::Replace
+                | CommandKind::Append
+                | CommandKind::Prepend
+                | CommandKind::Cas
+                | CommandKind::Delete
+                | CommandKind::Incr
+                | CommandKind::Decr
+                | CommandKind::FlushAll
+                | CommandKind::MetaSet
+                | CommandKind::MetaDelete
+                | CommandKind::MetaArithmetic
+        )
+    }
+
+    /// Whether the command removes data, for the audit log
+    pub fn is_destructive(self) -> bool {
+        matches!(
+            self,
+            CommandKind::Delete | CommandKind::MetaDelete | CommandKind::FlushAll
+        )
+    }
+
+    /// Whether the command may be disabled; health checks and clients
+    /// rely on `version`, `stats`, `quit` and `mn` always working
+    pub fn is_deniable(self) -> bool {
+        !matches!(
+            self,
+            CommandKind::Stats | CommandKind::Version | CommandKind::Quit | CommandKind::MetaNoop
+        )
+    }
+}
+
+/// A set of command kinds, as a bitset so a lookup per request is one mask
+///
+/// Deserializes from a list of command names, e.g. `["flush_all", "ms"]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct CommandSet(u32);
+
+impl CommandSet {
+    pub fn contains(self, kind: CommandKind) -> bool {
+        self.0 & Self::bit(kind) != 0
+    }
+
+    pub fn insert(&mut self, kind: CommandKind) {
+        self.0 |= Self::bit(kind);
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The set as a bitmask, for keeping it in an atomic
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// The set `bits` (from `bits`) stands for
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// The commands in the set, in `CommandKind::ALL` order
+    pub fn iter(self) -> impl Iterator<Item = CommandKind> {
+        CommandKind::ALL
+            .into_iter()
+            .filter(move |&kind| self.contains(kind))
+    }
+
+    fn bit(kind: CommandKind) -> u32 {
+        1 << kind as u32
+    }
+}
+
+impl FromIterator<CommandKind> for CommandSet {
+    fn from_iter<I: IntoIterator<Item = CommandKind>>(iter: I) -> Self {
+        let mut set = Self::default();
+        for kind in iter {
+            set.insert(kind);
+        }
+        set
+    }
+}
+
+impl From<CommandSet> for Vec<String> {
+    fn from(set: CommandSet) -> Self {
+        set.iter().map(|kind| kind.name().to_string()).collect()
+    }
+}
+
+impl TryFrom<Vec<String>> for CommandSet {
+    type Error = String;
+
+    fn try_from(names: Vec<String>) -> Result<Self, Self::Error> {
+        names
+            .iter()
+            .map(|name| match CommandKind::from_name(name) {
+                Some(kind) if kind.is_deniable() => Ok(kind),
+                Some(_) => Err(format!("command {name:?} can't be disabled")),
+                None => Err(format!("unknown command {name:?}")),
+            })
+            .collect()
+    }
+}
+
 /// Check if a key is valid
 pub fn is_valid_key(key: &[u8]) -> bool {
     if key.is_empty() || key.len() > MAX_KEY_LENGTH {
         return false;
     }
-    // Keys cannot contain control characters or whitespace
-    key.iter().all(|&b| b > 32 && b < 127)
+    // Keys cannot contain control characters, whitespace or DEL; like
+    // memcached, bytes >= 128 (e.g. UTF-8 sequences) are allowed
+    key.iter().all(|&b| b > 32 && b != 127)
+}
+
+/// Validate a key, restricted to printable ASCII when `strict_ascii` is set
+pub fn validate_key(key: &[u8], strict_ascii: bool) -> Result<(), ProtocolError> {
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(ProtocolError::KeyTooLong);
+    }
+    if !is_valid_key(key) || (strict_ascii && !key.is_ascii()) {
+        return Err(ProtocolError::InvalidKey(
+            String::from_utf8_lossy(key).to_string(),
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -63,6 +631,27 @@ mod tests {
         assert!(!is_valid_key(b"key with space"));
         assert!(!is_valid_key(b"key\twith\ttab"));
         assert!(!is_valid_key(&[b'a'; 251])); // Too long
+        assert!(!is_valid_key(b"key\x7fdel"));
+        assert!(is_valid_key("clé-ключ-键".as_bytes()));
+    }
+
+    #[test]
+    fn test_validate_key() {
+        let utf8 = "clé".as_bytes();
+        assert_eq!(validate_key(utf8, false), Ok(()));
+        assert_eq!(
+            validate_key(utf8, true),
+            Err(ProtocolError::InvalidKey("clé".to_string()))
+        );
+        assert_eq!(validate_key(b"plain", true), Ok(()));
+        assert_eq!(
+            validate_key(&[b'a'; 251], false),
+            Err(ProtocolError::KeyTooLong)
+        );
+        assert!(matches!(
+            validate_key(b"a b", false),
+            Err(ProtocolError::InvalidKey(_))
+        ));
     }
 
     #[test]
@@ -81,4 +670,24 @@ mod tests {
         };
         assert!(!cmd.is_noreply());
     }
+
+    #[test]
+    fn test_command_set() {
+        for kind in CommandKind::ALL {
+            assert_eq!(CommandKind::from_name(kind.name()), Some(kind));
+        }
+
+        let set = CommandSet::try_from(vec!["flush_all".to_string(), "ms".to_string()]).unwrap();
+        assert!(set.contains(CommandKind::FlushAll));
+        assert!(set.contains(CommandKind::MetaSet));
+        assert!(!set.contains(CommandKind::Set));
+        assert_eq!(
+            set.iter().collect::<Vec<_>>(),
+            [CommandKind::FlushAll, CommandKind::MetaSet]
+        );
+        assert!(CommandSet::default().is_empty());
+
+        assert!(CommandSet::try_from(vec!["stats".to_string()]).is_err());
+        assert!(CommandSet::try_from(vec!["flush".to_string()]).is_err());
+    }
 }
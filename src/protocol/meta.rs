@@ -0,0 +1,428 @@
+//! Memcached meta text protocol parser (`mg`, `ms`, `md`, `ma`, `mn`)
+//!
+//! Meta commands replace positional arguments with single-character flags:
+//! `mg <key> <flags>*`. Flags that carry a token (`T30`, `Oabc`) take the
+//! rest of the word as their value.
+//!
+//! `ms` is re-parsed from the start of its command line until the data block
+//! has arrived; it does not use the `PendingStorageCommand` fast path.
+
+use crate::ProtocolError;
+use crate::protocol::base64;
+use crate::protocol::command::{Command, MAX_KEY_LENGTH, validate_key};
+use crate::protocol::parser::{
+    ParseLimits, ParseResult, data_block_end, parse_i64, parse_u32, parse_u64, parse_usize,
+};
+use std::borrow::Cow;
+
+/// Storage mode selected by the `ms` `M<mode>` flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaSetMode {
+    /// `MS` (default): store unconditionally
+    #[default]
+    Set,
+    /// `ME`: store only if the key does not exist
+    Add,
+    /// `MR`: store only if the key exists
+    Replace,
+    /// `MA`: append to existing data
+    Append,
+    /// `MP`: prepend to existing data
+    Prepend,
+}
+
+/// Flags parsed from a meta command line
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct MetaFlags<'a> {
+    /// `b`: the key token is base64 (decoded before storage, re-encoded for `k`)
+    pub base64: bool,
+    /// `v`: return the item value
+    pub return_value: bool,
+    /// `f`: return client flags
+    pub return_flags: bool,
+    /// `t`: return remaining TTL in seconds (-1 for none)
+    pub return_ttl: bool,
+    /// `k`: return the key
+    pub return_key: bool,
+    /// `c`: return the CAS unique token
+    pub return_cas: bool,
+    /// `s`: return the value size
+    pub return_size: bool,
+    /// `q`: suppress uninteresting replies (EN for mg, HD for mutations)
+    pub quiet: bool,
+    /// `O<token>`: opaque token echoed back in the reply
+    pub opaque: Option<Cow<'a, [u8]>>,
+    /// `T<ttl>`: expiration time to set (mg touches, ms stores)
+    pub ttl: Option<i64>,
+    /// `F<flags>`: client flags to store (ms)
+    pub client_flags: Option<u32>,
+    /// `C<cas>`: compare CAS before storing (ms)
+    pub compare_cas: Option<u64>,
+    /// `D<delta>`: delta for ma (default 1)
+    pub delta: Option<u64>,
+    /// `M<mode>`: mode switch for ms and ma, kept raw until the command is known
+    pub mode: Option<u8>,
+}
+
+impl<'a> MetaFlags<'a> {
+    /// Parse the flag words following the key (and datalen for `ms`)
+    fn parse(parts: impl Iterator<Item = &'a [u8]>) -> Result<Self, ProtocolError> {
+        let mut flags = Self::default();
+
+        for part in parts.filter(|p| !p.is_empty()) {
+            let (flag, token) = (part[0], &part[1..]);
+            match flag {
+                b'b' => flags.base64 = true,
+                b'v' => flags.return_value = true,
+                b'f' => flags.return_flags = true,
+                b't' => flags.return_ttl = true,
+                b'k' => flags.return_key = true,
+                b'c' => flags.return_cas = true,
+                b's' => flags.return_size = true,
+                b'q' => flags.quiet = true,
+                b'O' => flags.opaque = Some(Cow::Borrowed(token)),
+                b'T' => flags.ttl = Some(parse_i64(token).ok_or(ProtocolError::InvalidExptime)?),
+                b'F' => {
+                    flags.client_flags = Some(parse_u32(token).ok_or(ProtocolError::InvalidFlags)?);
+                }
+                b'C' => {
+                    flags.compare_cas =
+                        Some(parse_u64(token).ok_or(ProtocolError::InvalidCasUnique)?);
+                }
+                b'D' => {
+                    flags.delta = Some(parse_u64(token).ok_or(ProtocolError::InvalidNumericValue)?);
+                }
+                b'M' => match token {
+                    [mode] => flags.mode = Some(mode.to_ascii_uppercase()),
+                    _ => return Err(ProtocolError::InvalidMetaFlag(char::from(flag))),
+                },
+                _ => return Err(ProtocolError::InvalidMetaFlag(char::from(flag))),
+            }
+        }
+
+        Ok(flags)
+    }
+
+    /// Detach the flags from the command line buffer
+    pub fn into_owned(self) -> MetaFlags<'static> {
+        MetaFlags {
+            base64: self.base64,
+            return_value: self.return_value,
+            return_flags: self.return_flags,
+            return_ttl: self.return_ttl,
+            return_key: self.return_key,
+            return_cas: self.return_cas,
+            return_size: self.return_size,
+            quiet: self.quiet,
+            opaque: self.opaque.map(|o| Cow::Owned(o.into_owned())),
+            ttl: self.ttl,
+            client_flags: self.client_flags,
+            compare_cas: self.compare_cas,
+            delta: self.delta,
+            mode: self.mode,
+        }
+    }
+
+    /// Storage mode for `ms` (`M` flag, default set)
+    fn set_mode(&self) -> Result<MetaSetMode, ProtocolError> {
+        match self.mode {
+            None | Some(b'S') => Ok(MetaSetMode::Set),
+            Some(b'E') => Ok(MetaSetMode::Add),
+            Some(b'R') => Ok(MetaSetMode::Replace),
+            Some(b'A') => Ok(MetaSetMode::Append),
+            Some(b'P') => Ok(MetaSetMode::Prepend),
+            Some(_) => Err(ProtocolError::InvalidMetaFlag('M')),
+        }
+    }
+
+    /// Arithmetic direction for `ma` (`M` flag, default increment)
+    fn is_incr(&self) -> Result<bool, ProtocolError> {
+        match self.mode {
+            None | Some(b'I' | b'+') => Ok(true),
+            Some(b'D' | b'-') => Ok(false),
+            Some(_) => Err(ProtocolError::InvalidMetaFlag('M')),
+        }
+    }
+}
+
+/// Take the key token of a meta command (validated by `resolve_key`)
+fn parse_key<'a>(parts: &mut impl Iterator<Item = &'a [u8]>) -> Result<&'a [u8], ProtocolError> {
+    match parts.next() {
+        Some(k) if !k.is_empty() => Ok(k),
+        _ => Err(ProtocolError::InvalidCommand("missing key".to_string())),
+    }
+}
+
+/// Validate the key token, decoding it first when the `b` flag is set
+///
+/// Decoded keys are arbitrary bytes, so `strict_ascii_keys` only applies to
+/// plain keys.
+fn resolve_key<'a>(
+    key: &'a [u8],
+    flags: &MetaFlags<'_>,
+    limits: &ParseLimits,
+) -> Result<Cow<'a, [u8]>, ProtocolError> {
+    if flags.base64 {
+        let decoded = base64::decode(key).ok_or(ProtocolError::InvalidBase64Key)?;
+        if decoded.len() > MAX_KEY_LENGTH {
+            return Err(ProtocolError::KeyTooLong);
+        }
+        // Keys starting with 0x00 are reserved for internal storage metadata
+        if decoded.first() == Some(&0) {
+            return Err(ProtocolError::InvalidKey("reserved key prefix".to_string()));
+        }
+        return Ok(Cow::Owned(decoded));
+    }
+
+    validate_key(key, limits.strict_ascii_keys)?;
+    Ok(Cow::Borrowed(key))
+}
+
+/// Parse the key and flags shared by mg, md and ma
+fn parse_key_and_flags<'a>(
+    mut parts: impl Iterator<Item = &'a [u8]>,
+    limits: &ParseLimits,
+) -> Result<(Cow<'a, [u8]>, MetaFlags<'a>), ProtocolError> {
+    let key = parse_key(&mut parts)?;
+    let flags = MetaFlags::parse(parts)?;
+    Ok((resolve_key(key, &flags, limits)?, flags))
+}
+
+/// Parse mg command
+/// Format: mg <key> <flags>*\r\n
+pub(super) fn parse_meta_get<'a>(
+    parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    match parse_key_and_flags(parts, limits) {
+        Ok((key, flags)) => ParseResult::Complete(Command::MetaGet { key, flags }, consumed),
+        Err(e) => ParseResult::Error(e),
+    }
+}
+
+/// Parse ms command
+/// Format: ms <key> <datalen> <flags>*\r\n<data>\r\n
+pub(super) fn parse_meta_set<'a>(
+    mut parts: impl Iterator<Item = &'a [u8]>,
+    buf: &'a [u8],
+    data_start: usize,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    let key = match parse_key(&mut parts) {
+        Ok(key) => key,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    let Some(bytes) = parts.next().and_then(parse_usize) else {
+        return ParseResult::Error(ProtocolError::InvalidBytesLength);
+    };
+
+    if bytes > limits.max_item_size {
+        return ParseResult::Error(ProtocolError::value_too_large(data_start, bytes));
+    }
+
+    let flags = match MetaFlags::parse(parts) {
+        Ok(flags) => flags,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    let mode = match flags.set_mode() {
+        Ok(mode) => mode,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    let key = match resolve_key(key, &flags, limits) {
+        Ok(key) => key,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    let data_end = data_start + bytes;
+    let total_needed = match data_block_end(buf, data_end, limits.accept_bare_lf) {
+        Ok(Some(end)) => end,
+        Ok(None) => return ParseResult::NeedMoreData,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    ParseResult::Complete(
+        Command::MetaSet {
+            key,
+            data: Cow::Borrowed(&buf[data_start..data_end]),
+            mode,
+            flags,
+        },
+        total_needed,
+    )
+}
+
+/// Parse md command
+/// Format: md <key> <flags>*\r\n
+pub(super) fn parse_meta_delete<'a>(
+    parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    match parse_key_and_flags(parts, limits) {
+        Ok((key, flags)) => ParseResult::Complete(Command::MetaDelete { key, flags }, consumed),
+        Err(e) => ParseResult::Error(e),
+    }
+}
+
+/// Parse ma command
+/// Format: ma <key> <flags>*\r\n
+pub(super) fn parse_meta_arithmetic<'a>(
+    parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    let parsed = parse_key_and_flags(parts, limits).and_then(|(key, flags)| {
+        let incr = flags.is_incr()?;
+        Ok((key, incr, flags))
+    });
+    match parsed {
+        Ok((key, incr, flags)) => {
+            ParseResult::Complete(Command::MetaArithmetic { key, incr, flags }, consumed)
+        }
+        Err(e) => ParseResult::Error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetaSetMode;
+    use crate::ProtocolError;
+    use crate::protocol::{Command, ParseResult, parse};
+
+    #[test]
+    fn test_parse_meta_get() {
+        match parse(b"mg foo v f t k c s q Oabc T30\r\n") {
+            ParseResult::Complete(Command::MetaGet { key, flags }, consumed) => {
+                assert_eq!(key.as_ref(), b"foo");
+                assert!(flags.return_value && flags.return_flags && flags.return_ttl);
+                assert!(flags.return_key && flags.return_cas && flags.return_size);
+                assert!(flags.quiet);
+                assert_eq!(flags.opaque.as_deref(), Some(&b"abc"[..]));
+                assert_eq!(flags.ttl, Some(30));
+                assert_eq!(consumed, 31);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_meta_get_errors() {
+        assert!(matches!(
+            parse(b"mg\r\n"),
+            ParseResult::Error(ProtocolError::InvalidCommand(_))
+        ));
+        assert!(matches!(
+            parse(b"mg foo x\r\n"),
+            ParseResult::Error(ProtocolError::InvalidMetaFlag('x'))
+        ));
+        assert!(matches!(
+            parse(b"mg foo Tabc\r\n"),
+            ParseResult::Error(ProtocolError::InvalidExptime)
+        ));
+    }
+
+    #[test]
+    fn test_parse_meta_base64_key() {
+        // "a key\xff" is not expressible as a plain ASCII key
+        match parse(b"mg YSBrZXn/ b v k\r\n") {
+            ParseResult::Complete(Command::MetaGet { key, flags }, _) => {
+                assert_eq!(key.as_ref(), b"a key\xff");
+                assert!(flags.base64 && flags.return_key);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"ms Zm9v 3 b\r\nbar\r\n") {
+            ParseResult::Complete(Command::MetaSet { key, data, .. }, _) => {
+                assert_eq!(key.as_ref(), b"foo");
+                assert_eq!(data.as_ref(), b"bar");
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        assert!(matches!(
+            parse(b"md !!!! b\r\n"),
+            ParseResult::Error(ProtocolError::InvalidBase64Key)
+        ));
+        // Internal key namespace is not reachable through base64
+        assert!(matches!(
+            parse(b"mg AGZsdXNo b\r\n"),
+            ParseResult::Error(ProtocolError::InvalidKey(_))
+        ));
+
+        let long = crate::protocol::base64::encode(&[b'x'; 251]);
+        let buf = [&b"mg "[..], &long, b" b\r\n"].concat();
+        assert!(matches!(
+            parse(&buf),
+            ParseResult::Error(ProtocolError::KeyTooLong)
+        ));
+    }
+
+    #[test]
+    fn test_parse_meta_set() {
+        match parse(b"ms foo 5 F7 T60 ME q\r\nhello\r\n") {
+            ParseResult::Complete(
+                Command::MetaSet {
+                    key,
+                    data,
+                    mode,
+                    flags,
+                },
+                consumed,
+            ) => {
+                assert_eq!(key.as_ref(), b"foo");
+                assert_eq!(data.as_ref(), b"hello");
+                assert_eq!(mode, MetaSetMode::Add);
+                assert_eq!(flags.client_flags, Some(7));
+                assert_eq!(flags.ttl, Some(60));
+                assert!(flags.quiet);
+                assert_eq!(consumed, 29);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        assert!(matches!(
+            parse(b"ms foo 5\r\nhel"),
+            ParseResult::NeedMoreData
+        ));
+        assert!(matches!(
+            parse(b"ms foo 5 MX\r\nhello\r\n"),
+            ParseResult::Error(ProtocolError::InvalidMetaFlag('M'))
+        ));
+        assert!(matches!(
+            parse(b"ms foo 3\r\nhello\r\n"),
+            ParseResult::Error(ProtocolError::BadDataChunk { discard: 15 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_meta_delete_arithmetic_noop() {
+        match parse(b"md foo q k\r\n") {
+            ParseResult::Complete(Command::MetaDelete { key, flags }, _) => {
+                assert_eq!(key.as_ref(), b"foo");
+                assert!(flags.quiet && flags.return_key);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"ma counter MD D5 v\r\n") {
+            ParseResult::Complete(Command::MetaArithmetic { key, incr, flags }, _) => {
+                assert_eq!(key.as_ref(), b"counter");
+                assert!(!incr);
+                assert_eq!(flags.delta, Some(5));
+                assert!(flags.return_value);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        assert!(matches!(
+            parse(b"mn\r\n"),
+            ParseResult::Complete(Command::MetaNoop, 4)
+        ));
+    }
+}
@@ -1,11 +1,19 @@
 //! Memcached ASCII protocol implementation
 
+pub(crate) mod base64;
+pub mod binary;
+pub mod codec;
 pub mod command;
+pub mod meta;
 pub mod parser;
 pub mod response;
 
-pub use command::{Command, MAX_KEY_LENGTH};
+pub use codec::MemcachedCodec;
+pub use command::{Command, CommandKind, CommandSet, MAX_KEY_LENGTH};
+pub use meta::{MetaFlags, MetaSetMode};
 pub use parser::{
-    ParseResult, PendingStorageCommand, parse, parse_storage_command_line, parse_storage_data,
+    DEFAULT_MAX_COMMAND_LINE_BYTES, DEFAULT_MAX_GET_KEYS, DEFAULT_MAX_ITEM_SIZE, ParseLimits,
+    ParseResult, PendingStorageCommand, StorageKind, find_line, parse, parse_storage_command_line,
+    parse_storage_data, parse_with_limits,
 };
-pub use response::ResponseWriter;
+pub use response::{ReplyValues, ResponseWriter};
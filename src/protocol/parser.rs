@@ -5,7 +5,8 @@
 //! 2. For storage commands, read data block
 
 use crate::ProtocolError;
-use crate::protocol::command::{Command, MAX_KEY_LENGTH, is_valid_key};
+use crate::protocol::command::{Command, validate_key};
+use crate::protocol::meta;
 use std::borrow::Cow;
 
 /// Case-insensitive command comparison (avoids allocation from to_ascii_lowercase)
@@ -29,135 +30,400 @@ pub enum ParseResult<'a> {
     Error(ProtocolError),
 }
 
+/// Storage commands that share the `<key> <flags> <exptime> <bytes>` grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas,
+}
+
+impl StorageKind {
+    /// Match a command name against the storage commands (case-insensitive)
+    #[inline]
+    fn from_name(name: &[u8]) -> Option<Self> {
+        if cmd_eq(name, b"set") {
+            Some(Self::Set)
+        } else if cmd_eq(name, b"add") {
+            Some(Self::Add)
+        } else if cmd_eq(name, b"replace") {
+            Some(Self::Replace)
+        } else if cmd_eq(name, b"append") {
+            Some(Self::Append)
+        } else if cmd_eq(name, b"prepend") {
+            Some(Self::Prepend)
+        } else if cmd_eq(name, b"cas") {
+            Some(Self::Cas)
+        } else {
+            None
+        }
+    }
+
+    /// Parse the `<cas unique>` token that only `cas` carries
+    fn parse_cas_unique<'a>(
+        self,
+        parts: &mut impl Iterator<Item = &'a [u8]>,
+    ) -> Result<u64, ProtocolError> {
+        if self == Self::Cas {
+            parts
+                .next()
+                .and_then(parse_u64)
+                .ok_or(ProtocolError::InvalidCasUnique)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Build the parsed command once the data block is available
+    fn into_command<'a>(
+        self,
+        key: Cow<'a, [u8]>,
+        flags: u32,
+        exptime: i64,
+        data: Cow<'a, [u8]>,
+        cas_unique: u64,
+        noreply: bool,
+    ) -> Command<'a> {
+        match self {
+            Self::Set => Command::Set {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            },
+            Self::Add => Command::Add {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            },
+            Self::Replace => Command::Replace {
+                key,
+                flags,
+                exptime,
+                data,
+                noreply,
+            },
+            // flags and exptime are ignored for append/prepend (memcached spec)
+            Self::Append => Command::Append { key, data, noreply },
+            Self::Prepend => Command::Prepend { key, data, noreply },
+            Self::Cas => Command::Cas {
+                key,
+                flags,
+                exptime,
+                data,
+                cas_unique,
+                noreply,
+            },
+        }
+    }
+}
+
 /// Parser state for handling storage commands that need data
 #[derive(Debug, Clone)]
 pub struct PendingStorageCommand {
+    pub kind: StorageKind,
     pub key: Vec<u8>,
     pub flags: u32,
-    pub exptime: u64,
+    pub exptime: i64,
     pub bytes: usize,
+    /// Only meaningful for `cas`
+    pub cas_unique: u64,
     pub noreply: bool,
-    pub command_line_end: usize,
+    /// Offset of the data block (command line plus its terminator); the
+    /// connection consumes the command line and resets this to 0
+    pub data_start: usize,
 }
 
-/// Parse a memcached command from a buffer
+/// Default maximum size of a stored value (1 MiB, like memcached)
+pub const DEFAULT_MAX_ITEM_SIZE: usize = 1024 * 1024;
+
+/// Default maximum number of keys in one get/gets/gat/gats
+pub const DEFAULT_MAX_GET_KEYS: usize = 1024;
+
+/// Default maximum command line length, enough for `DEFAULT_MAX_GET_KEYS`
+/// keys of the maximum length
+pub const DEFAULT_MAX_COMMAND_LINE_BYTES: usize = 256 * 1024;
+
+/// Per-connection limits enforced while parsing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Largest data block a storage command may announce
+    pub max_item_size: usize,
+    /// Most keys a single retrieval command may name
+    pub max_get_keys: usize,
+    /// Longest command line (excluding CRLF), terminated or not
+    pub max_command_line_bytes: usize,
+    /// Reject keys with bytes outside printable ASCII (pre-UTF-8 behavior)
+    pub strict_ascii_keys: bool,
+    /// Also accept a bare `\n` as line and data block terminator
+    pub accept_bare_lf: bool,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            max_get_keys: DEFAULT_MAX_GET_KEYS,
+            max_command_line_bytes: DEFAULT_MAX_COMMAND_LINE_BYTES,
+            strict_ascii_keys: false,
+            accept_bare_lf: false,
+        }
+    }
+}
+
+/// Parse a memcached command from a buffer, using the default limits
 pub fn parse(buf: &[u8]) -> ParseResult<'_> {
-    // Find the end of the command line
-    let line_end = match find_crlf(buf) {
-        Some(pos) => pos,
+    parse_with_limits(buf, &ParseLimits::default())
+}
+
+/// Parse a memcached command from a buffer
+///
+/// Storage commands announcing more than `max_item_size` bytes fail with
+/// `ProtocolError::ValueTooLarge`, which carries how many bytes the caller
+/// must discard to skip the command line and its data block. A command line
+/// longer than `max_command_line_bytes` fails with `LineTooLong` as soon as
+/// that many bytes are buffered, whether or not its CRLF has arrived.
+pub fn parse_with_limits<'a>(buf: &'a [u8], limits: &ParseLimits) -> ParseResult<'a> {
+    // Find the end of the command line; `consumed` includes its terminator
+    let (line_end, consumed) = match find_line(buf, limits.accept_bare_lf) {
+        Some((end, _)) if end > limits.max_command_line_bytes => {
+            return ParseResult::Error(ProtocolError::LineTooLong);
+        }
+        Some(line) => line,
+        None if buf.len() > limits.max_command_line_bytes => {
+            return ParseResult::Error(ProtocolError::LineTooLong);
+        }
         None => return ParseResult::NeedMoreData,
     };
 
     let line = &buf[..line_end];
 
     // Parse the command name
-    let mut parts = line.split(|&b| b == b' ');
+    let mut parts = tokens(line);
     let cmd_name = match parts.next() {
-        Some(name) if !name.is_empty() => name,
-        _ => return ParseResult::Error(ProtocolError::InvalidCommand("empty command".to_string())),
+        Some(name) => name,
+        _ => return ParseResult::Error(ProtocolError::UnknownCommand(String::new())),
     };
 
     // Match command (case-insensitive, no allocation)
     if cmd_eq(cmd_name, b"get") {
-        parse_get(parts, line_end + 2)
-    } else if cmd_eq(cmd_name, b"set") {
-        parse_set(parts, buf, line_end)
+        parse_get(parts, consumed, false, limits)
+    } else if cmd_eq(cmd_name, b"gets") {
+        parse_get(parts, consumed, true, limits)
+    } else if cmd_eq(cmd_name, b"gat") {
+        parse_gat(parts, consumed, false, limits)
+    } else if cmd_eq(cmd_name, b"gats") {
+        parse_gat(parts, consumed, true, limits)
+    } else if let Some(kind) = StorageKind::from_name(cmd_name) {
+        parse_storage(kind, parts, buf, consumed, limits)
     } else if cmd_eq(cmd_name, b"delete") {
-        parse_delete(parts, line_end + 2)
+        parse_delete(parts, consumed, limits)
+    } else if cmd_eq(cmd_name, b"incr") {
+        parse_incr_decr(parts, consumed, true, limits)
+    } else if cmd_eq(cmd_name, b"decr") {
+        parse_incr_decr(parts, consumed, false, limits)
+    } else if cmd_eq(cmd_name, b"flush_all") {
+        parse_flush_all(parts, consumed)
+    } else if cmd_eq(cmd_name, b"mg") {
+        meta::parse_meta_get(parts, consumed, limits)
+    } else if cmd_eq(cmd_name, b"ms") {
+        meta::parse_meta_set(parts, buf, consumed, limits)
+    } else if cmd_eq(cmd_name, b"md") {
+        meta::parse_meta_delete(parts, consumed, limits)
+    } else if cmd_eq(cmd_name, b"ma") {
+        meta::parse_meta_arithmetic(parts, consumed, limits)
+    } else if cmd_eq(cmd_name, b"mn") {
+        ParseResult::Complete(Command::MetaNoop, consumed)
+    } else if cmd_eq(cmd_name, b"stats") {
+        parse_stats(parts, consumed)
     } else if cmd_eq(cmd_name, b"version") {
-        ParseResult::Complete(Command::Version, line_end + 2)
+        ParseResult::Complete(Command::Version, consumed)
     } else if cmd_eq(cmd_name, b"quit") {
-        ParseResult::Complete(Command::Quit, line_end + 2)
+        ParseResult::Complete(Command::Quit, consumed)
     } else {
-        ParseResult::Error(ProtocolError::InvalidCommand(
+        ParseResult::Error(ProtocolError::UnknownCommand(
             String::from_utf8_lossy(cmd_name).to_string(),
         ))
     }
 }
 
 /// Continue parsing a storage command after receiving data block
-pub fn parse_storage_data<'a>(buf: &'a [u8], pending: &PendingStorageCommand) -> ParseResult<'a> {
-    // Need: command_line_end + data_bytes + 2 (for \r\n after data)
-    let data_start = pending.command_line_end + 2;
+///
+/// Only the data block terminator is inspected, so calling this on every
+/// read while a large value trickles in costs O(1) per call. On `Complete`
+/// the key is moved out of `pending`, which must then be dropped.
+pub fn parse_storage_data<'a>(
+    buf: &'a [u8],
+    pending: &mut PendingStorageCommand,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    let data_start = pending.data_start;
     let data_end = data_start + pending.bytes;
-    let total_needed = data_end + 2; // +2 for trailing \r\n
-
-    if buf.len() < total_needed {
-        return ParseResult::NeedMoreData;
-    }
-
-    // Verify trailing \r\n
-    if buf[data_end] != b'\r' || buf[data_end + 1] != b'\n' {
-        return ParseResult::Error(ProtocolError::UnexpectedData);
-    }
+    let total_needed = match data_block_end(buf, data_end, limits.accept_bare_lf) {
+        Ok(Some(end)) => end,
+        Ok(None) => return ParseResult::NeedMoreData,
+        Err(e) => return ParseResult::Error(e),
+    };
 
     let data = Cow::Borrowed(&buf[data_start..data_end]);
-    let key = Cow::Owned(pending.key.clone());
+    let key = Cow::Owned(std::mem::take(&mut pending.key));
 
-    let cmd = Command::Set {
+    let cmd = pending.kind.into_command(
         key,
-        flags: pending.flags,
-        exptime: pending.exptime,
+        pending.flags,
+        pending.exptime,
         data,
-        noreply: pending.noreply,
-    };
+        pending.cas_unique,
+        pending.noreply,
+    );
 
     ParseResult::Complete(cmd, total_needed)
 }
 
-/// Find \r\n in buffer using SIMD-accelerated search
+/// Split a command line into space-separated tokens
+///
+/// Leading, trailing and repeated spaces yield no empty tokens, so sloppy
+/// clients (`set key  0 0 3`) still parse positionally.
+fn tokens(line: &[u8]) -> impl Iterator<Item = &[u8]> {
+    line.split(|&b| b == b' ').filter(|token| !token.is_empty())
+}
+
+/// Find the end of the first line: `(line_end, next_line_start)`
+///
+/// The terminator is \r\n, or also a bare \n when `accept_bare_lf` is set;
+/// `next_line_start - line_end` is the terminator length. A stray \r before
+/// the terminator must not hide it, or the line would only ever end by
+/// hitting `max_command_line_bytes`.
 #[inline]
-fn find_crlf(buf: &[u8]) -> Option<usize> {
-    memchr::memchr(b'\r', buf).filter(|&i| buf.get(i + 1) == Some(&b'\n'))
+pub fn find_line(buf: &[u8], accept_bare_lf: bool) -> Option<(usize, usize)> {
+    if accept_bare_lf {
+        let lf = memchr::memchr(b'\n', buf)?;
+        if lf > 0 && buf[lf - 1] == b'\r' {
+            Some((lf - 1, lf + 1))
+        } else {
+            Some((lf, lf + 1))
+        }
+    } else {
+        memchr::memmem::find(buf, b"\r\n").map(|pos| (pos, pos + 2))
+    }
+}
+
+/// Check the terminator of a data block ending at `data_end`
+///
+/// Returns where the next command starts, `None` until the terminator has
+/// arrived, or `BadDataChunk` (discarding `<bytes>` + 2, as memcached does)
+/// when something else follows the announced length.
+pub(super) fn data_block_end(
+    buf: &[u8],
+    data_end: usize,
+    accept_bare_lf: bool,
+) -> Result<Option<usize>, ProtocolError> {
+    let rest = buf.get(data_end..).unwrap_or_default();
+    if rest.starts_with(b"\r\n") {
+        Ok(Some(data_end + 2))
+    } else if accept_bare_lf && rest.first() == Some(&b'\n') {
+        Ok(Some(data_end + 1))
+    } else if rest.len() < 2 {
+        Ok(None)
+    } else {
+        Err(ProtocolError::BadDataChunk {
+            discard: data_end + 2,
+        })
+    }
+}
+
+/// Parse get/gets command
+fn parse_get<'a>(
+    parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    with_cas: bool,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    let keys = match parse_keys(parts, "get requires at least one key", limits) {
+        Ok(keys) => keys,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    let cmd = if with_cas {
+        Command::Gets { keys }
+    } else {
+        Command::Get { keys }
+    };
+    ParseResult::Complete(cmd, consumed)
 }
 
-/// Parse get command
-fn parse_get<'a>(mut parts: impl Iterator<Item = &'a [u8]>, consumed: usize) -> ParseResult<'a> {
+/// Parse gat/gats command
+/// Format: gat|gats <exptime> <key>+\r\n
+fn parse_gat<'a>(
+    mut parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    with_cas: bool,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    let exptime = match parts.next().and_then(parse_i64) {
+        Some(e) => e,
+        None => return ParseResult::Error(ProtocolError::InvalidExptime),
+    };
+
+    let keys = match parse_keys(parts, "gat requires at least one key", limits) {
+        Ok(keys) => keys,
+        Err(e) => return ParseResult::Error(e),
+    };
+
+    let cmd = if with_cas {
+        Command::Gats { exptime, keys }
+    } else {
+        Command::Gat { exptime, keys }
+    };
+    ParseResult::Complete(cmd, consumed)
+}
+
+/// Parse and validate the remaining tokens as a non-empty list of at most
+/// `max_get_keys` keys
+fn parse_keys<'a>(
+    parts: impl Iterator<Item = &'a [u8]>,
+    missing_message: &str,
+    limits: &ParseLimits,
+) -> Result<Vec<Cow<'a, [u8]>>, ProtocolError> {
     let mut keys = Vec::new();
 
-    for part in parts.by_ref() {
-        if part.is_empty() {
-            continue;
-        }
-        if !is_valid_key(part) {
-            if part.len() > MAX_KEY_LENGTH {
-                return ParseResult::Error(ProtocolError::KeyTooLong);
-            }
-            return ParseResult::Error(ProtocolError::InvalidKey(
-                String::from_utf8_lossy(part).to_string(),
-            ));
+    for part in parts {
+        if keys.len() == limits.max_get_keys {
+            return Err(ProtocolError::TooManyKeys);
         }
+        validate_key(part, limits.strict_ascii_keys)?;
         keys.push(Cow::Borrowed(part));
     }
 
     if keys.is_empty() {
-        return ParseResult::Error(ProtocolError::InvalidCommand(
-            "get requires at least one key".to_string(),
-        ));
+        return Err(ProtocolError::InvalidCommand(missing_message.to_string()));
     }
 
-    ParseResult::Complete(Command::Get { keys }, consumed)
+    Ok(keys)
 }
 
-/// Parse set command
-fn parse_set<'a>(
+/// Parse a storage command (set, add, replace, append, prepend, cas)
+fn parse_storage<'a>(
+    kind: StorageKind,
     mut parts: impl Iterator<Item = &'a [u8]>,
     buf: &'a [u8],
-    line_end: usize,
+    data_start: usize,
+    limits: &ParseLimits,
 ) -> ParseResult<'a> {
-    // <key> <flags> <exptime> <bytes> [noreply]
+    // <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]
     let key = match parts.next() {
-        Some(k) if !k.is_empty() => k,
+        Some(k) => k,
         _ => return ParseResult::Error(ProtocolError::InvalidCommand("missing key".to_string())),
     };
 
-    if !is_valid_key(key) {
-        if key.len() > MAX_KEY_LENGTH {
-            return ParseResult::Error(ProtocolError::KeyTooLong);
-        }
-        return ParseResult::Error(ProtocolError::InvalidKey(
-            String::from_utf8_lossy(key).to_string(),
-        ));
+    if let Err(e) = validate_key(key, limits.strict_ascii_keys) {
+        return ParseResult::Error(e);
     }
 
     let flags = match parts.next().and_then(parse_u32) {
@@ -165,7 +431,7 @@ fn parse_set<'a>(
         None => return ParseResult::Error(ProtocolError::InvalidFlags),
     };
 
-    let exptime = match parts.next().and_then(parse_u64) {
+    let exptime = match parts.next().and_then(parse_i64) {
         Some(e) => e,
         None => return ParseResult::Error(ProtocolError::InvalidExptime),
     };
@@ -175,32 +441,29 @@ fn parse_set<'a>(
         None => return ParseResult::Error(ProtocolError::InvalidBytesLength),
     };
 
-    let noreply = parts.next().is_some_and(|s| s == b"noreply");
+    if bytes > limits.max_item_size {
+        return ParseResult::Error(ProtocolError::value_too_large(data_start, bytes));
+    }
 
-    // Check if we have enough data for the data block
-    let data_start = line_end + 2;
-    let data_end = data_start + bytes;
-    let total_needed = data_end + 2;
+    let cas_unique = match kind.parse_cas_unique(&mut parts) {
+        Ok(c) => c,
+        Err(e) => return ParseResult::Error(e),
+    };
 
-    if buf.len() < total_needed {
-        return ParseResult::NeedMoreData;
-    }
+    let noreply = parts.next().is_some_and(|s| s == b"noreply");
 
-    // Verify trailing \r\n
-    if buf[data_end] != b'\r' || buf[data_end + 1] != b'\n' {
-        return ParseResult::Error(ProtocolError::UnexpectedData);
-    }
+    // Check if we have the whole data block and its terminator
+    let data_end = data_start + bytes;
+    let total_needed = match data_block_end(buf, data_end, limits.accept_bare_lf) {
+        Ok(Some(end)) => end,
+        Ok(None) => return ParseResult::NeedMoreData,
+        Err(e) => return ParseResult::Error(e),
+    };
 
     let data = Cow::Borrowed(&buf[data_start..data_end]);
     let key = Cow::Borrowed(key);
 
-    let cmd = Command::Set {
-        key,
-        flags,
-        exptime,
-        data,
-        noreply,
-    };
+    let cmd = kind.into_command(key, flags, exptime, data, cas_unique, noreply);
 
     ParseResult::Complete(cmd, total_needed)
 }
@@ -208,38 +471,31 @@ fn parse_set<'a>(
 /// Parse pending storage command line (for partial reads)
 pub fn parse_storage_command_line(
     buf: &[u8],
+    limits: &ParseLimits,
 ) -> Result<Option<PendingStorageCommand>, ProtocolError> {
-    let line_end = match find_crlf(buf) {
-        Some(pos) => pos,
-        None => return Ok(None),
+    let Some((line_end, data_start)) = find_line(buf, limits.accept_bare_lf) else {
+        return Ok(None);
     };
 
     let line = &buf[..line_end];
-    let mut parts = line.split(|&b| b == b' ');
+    let mut parts = tokens(line);
 
     let cmd_name = match parts.next() {
-        Some(name) if !name.is_empty() => name,
+        Some(name) => name,
         _ => return Err(ProtocolError::InvalidCommand("empty command".to_string())),
     };
 
-    // Only handle set command (case-insensitive, no allocation)
-    if !cmd_eq(cmd_name, b"set") {
+    // Only handle storage commands (case-insensitive, no allocation)
+    let Some(kind) = StorageKind::from_name(cmd_name) else {
         return Ok(None);
-    }
+    };
 
     let key = match parts.next() {
-        Some(k) if !k.is_empty() => k,
+        Some(k) => k,
         _ => return Err(ProtocolError::InvalidCommand("missing key".to_string())),
     };
 
-    if !is_valid_key(key) {
-        if key.len() > MAX_KEY_LENGTH {
-            return Err(ProtocolError::KeyTooLong);
-        }
-        return Err(ProtocolError::InvalidKey(
-            String::from_utf8_lossy(key).to_string(),
-        ));
-    }
+    validate_key(key, limits.strict_ascii_keys)?;
 
     let flags = parts
         .next()
@@ -248,7 +504,7 @@ pub fn parse_storage_command_line(
 
     let exptime = parts
         .next()
-        .and_then(parse_u64)
+        .and_then(parse_i64)
         .ok_or(ProtocolError::InvalidExptime)?;
 
     let bytes = parts
@@ -256,24 +512,36 @@ pub fn parse_storage_command_line(
         .and_then(parse_usize)
         .ok_or(ProtocolError::InvalidBytesLength)?;
 
+    if bytes > limits.max_item_size {
+        return Err(ProtocolError::value_too_large(data_start, bytes));
+    }
+
+    let cas_unique = kind.parse_cas_unique(&mut parts)?;
+
     let noreply = parts.next().is_some_and(|s| s == b"noreply");
 
     Ok(Some(PendingStorageCommand {
+        kind,
         key: key.to_vec(),
         flags,
         exptime,
         bytes,
+        cas_unique,
         noreply,
-        command_line_end: line_end,
+        data_start,
     }))
 }
 
 /// Parse delete command
 /// Format: delete <key> [exptime] [noreply]\r\n
 /// exptime is parsed but ignored (for mcrouter compatibility)
-fn parse_delete<'a>(mut parts: impl Iterator<Item = &'a [u8]>, consumed: usize) -> ParseResult<'a> {
+fn parse_delete<'a>(
+    mut parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
     let key = match parts.next() {
-        Some(k) if !k.is_empty() => k,
+        Some(k) => k,
         _ => {
             return ParseResult::Error(ProtocolError::InvalidCommand(
                 "delete requires a key".to_string(),
@@ -281,22 +549,14 @@ fn parse_delete<'a>(mut parts: impl Iterator<Item = &'a [u8]>, consumed: usize)
         }
     };
 
-    if !is_valid_key(key) {
-        if key.len() > MAX_KEY_LENGTH {
-            return ParseResult::Error(ProtocolError::KeyTooLong);
-        }
-        return ParseResult::Error(ProtocolError::InvalidKey(
-            String::from_utf8_lossy(key).to_string(),
-        ));
+    if let Err(e) = validate_key(key, limits.strict_ascii_keys) {
+        return ParseResult::Error(e);
     }
 
     // Parse optional exptime and noreply
     // Format: [exptime] [noreply] where exptime is a number
     let mut noreply = false;
     for part in parts {
-        if part.is_empty() {
-            continue;
-        }
         if part == b"noreply" {
             noreply = true;
         }
@@ -313,18 +573,97 @@ fn parse_delete<'a>(mut parts: impl Iterator<Item = &'a [u8]>, consumed: usize)
     )
 }
 
+/// Parse incr/decr command
+/// Format: incr|decr <key> <delta> [noreply]\r\n
+fn parse_incr_decr<'a>(
+    mut parts: impl Iterator<Item = &'a [u8]>,
+    consumed: usize,
+    incr: bool,
+    limits: &ParseLimits,
+) -> ParseResult<'a> {
+    let key = match parts.next() {
+        Some(k) => k,
+        _ => return ParseResult::Error(ProtocolError::InvalidCommand("missing key".to_string())),
+    };
+
+    if let Err(e) = validate_key(key, limits.strict_ascii_keys) {
+        return ParseResult::Error(e);
+    }
+
+    let delta = match parts.next().and_then(parse_u64) {
+        Some(d) => d,
+        None => return ParseResult::Error(ProtocolError::InvalidNumericValue),
+    };
+
+    let noreply = parts.next().is_some_and(|s| s == b"noreply");
+    let key = Cow::Borrowed(key);
+
+    let cmd = if incr {
+        Command::Incr {
+            key,
+            delta,
+            noreply,
+        }
+    } else {
+        Command::Decr {
+            key,
+            delta,
+            noreply,
+        }
+    };
+    ParseResult::Complete(cmd, consumed)
+}
+
+/// Parse flush_all command
+/// Format: flush_all [delay] [noreply]\r\n
+fn parse_flush_all<'a>(parts: impl Iterator<Item = &'a [u8]>, consumed: usize) -> ParseResult<'a> {
+    let mut delay = 0;
+    let mut noreply = false;
+    for part in parts {
+        if part == b"noreply" {
+            noreply = true;
+        } else {
+            match parse_u64(part) {
+                Some(d) => delay = d,
+                None => return ParseResult::Error(ProtocolError::InvalidExptime),
+            }
+        }
+    }
+
+    ParseResult::Complete(Command::FlushAll { delay, noreply }, consumed)
+}
+
+/// Parse stats command
+/// Format: stats [reset|settings|items|...]\r\n
+fn parse_stats<'a>(parts: impl Iterator<Item = &'a [u8]>, consumed: usize) -> ParseResult<'a> {
+    let mut parts = parts;
+    let args = parts.next().map(Cow::Borrowed);
+    if parts.next().is_some() {
+        return ParseResult::Error(ProtocolError::InvalidCommand(
+            "stats takes at most one argument".to_string(),
+        ));
+    }
+
+    ParseResult::Complete(Command::Stats { args }, consumed)
+}
+
 /// Parse bytes as u32
-fn parse_u32(bytes: &[u8]) -> Option<u32> {
+pub(super) fn parse_u32(bytes: &[u8]) -> Option<u32> {
     std::str::from_utf8(bytes).ok()?.parse().ok()
 }
 
 /// Parse bytes as u64
-fn parse_u64(bytes: &[u8]) -> Option<u64> {
+pub(super) fn parse_u64(bytes: &[u8]) -> Option<u64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// Parse bytes as i64 (exptimes may be negative)
+pub(super) fn parse_i64(bytes: &[u8]) -> Option<i64> {
     std::str::from_utf8(bytes).ok()?.parse().ok()
 }
 
 /// Parse bytes as usize
-fn parse_usize(bytes: &[u8]) -> Option<usize> {
+pub(super) fn parse_usize(bytes: &[u8]) -> Option<usize> {
     std::str::from_utf8(bytes).ok()?.parse().ok()
 }
 
@@ -343,7 +682,7 @@ mod tests {
                 assert_eq!(keys[2].as_ref(), b"baz");
                 assert_eq!(consumed, buf.len());
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -368,10 +707,32 @@ mod tests {
                 assert!(!noreply);
                 assert_eq!(consumed, buf.len());
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
+    #[test]
+    fn test_parse_negative_exptime() {
+        match parse(b"set mykey 0 -1 5\r\nhello\r\n") {
+            ParseResult::Complete(Command::Set { exptime, .. }, _) => assert_eq!(exptime, -1),
+            other => panic!("unexpected: {other:?}"),
+        }
+        match parse(b"gat -1 mykey\r\n") {
+            ParseResult::Complete(Command::Gat { exptime, .. }, _) => assert_eq!(exptime, -1),
+            other => panic!("unexpected: {other:?}"),
+        }
+        let pending =
+            parse_storage_command_line(b"cas mykey 0 -30 5 7\r\n", &ParseLimits::default())
+                .unwrap()
+                .unwrap();
+        assert_eq!(pending.exptime, -30);
+
+        assert!(matches!(
+            parse(b"set mykey 0 -x 5\r\nhello\r\n"),
+            ParseResult::Error(ProtocolError::InvalidExptime)
+        ));
+    }
+
     #[test]
     fn test_parse_set_noreply() {
         let buf = b"set mykey 0 0 3 noreply\r\nfoo\r\n";
@@ -379,7 +740,212 @@ mod tests {
             ParseResult::Complete(Command::Set { noreply, .. }, _) => {
                 assert!(noreply);
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_add() {
+        let buf = b"add mykey 7 60 5 noreply\r\nhello\r\n";
+        match parse(buf) {
+            ParseResult::Complete(
+                Command::Add {
+                    key,
+                    flags,
+                    exptime,
+                    data,
+                    noreply,
+                },
+                consumed,
+            ) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(flags, 7);
+                assert_eq!(exptime, 60);
+                assert_eq!(data.as_ref(), b"hello");
+                assert!(noreply);
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_add_partial_data() {
+        let line = b"add mykey 0 0 5\r\n";
+        let mut pending = parse_storage_command_line(line, &ParseLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending.kind, StorageKind::Add);
+
+        let buf = b"add mykey 0 0 5\r\nhel";
+        assert!(matches!(
+            parse_storage_data(buf, &mut pending, &ParseLimits::default()),
+            ParseResult::NeedMoreData
+        ));
+
+        let buf = b"add mykey 0 0 5\r\nhello\r\n";
+        match parse_storage_data(buf, &mut pending, &ParseLimits::default()) {
+            ParseResult::Complete(Command::Add { key, data, .. }, consumed) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(data.as_ref(), b"hello");
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_storage_data_after_line_consumed() {
+        let mut pending =
+            parse_storage_command_line(b"set mykey 0 0 5\r\n", &ParseLimits::default())
+                .unwrap()
+                .unwrap();
+        // The connection drops the command line once it has parsed it
+        pending.data_start = 0;
+
+        assert!(matches!(
+            parse_storage_data(b"hel", &mut pending, &ParseLimits::default()),
+            ParseResult::NeedMoreData
+        ));
+        match parse_storage_data(b"hello\r\nget", &mut pending, &ParseLimits::default()) {
+            ParseResult::Complete(Command::Set { key, data, .. }, consumed) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(data.as_ref(), b"hello");
+                assert_eq!(consumed, 7);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+        // The key was moved into the command
+        assert!(pending.key.is_empty());
+    }
+
+    #[test]
+    fn test_parse_replace() {
+        let buf = b"REPLACE mykey 3 0 2\r\nhi\r\n";
+        match parse(buf) {
+            ParseResult::Complete(
+                Command::Replace {
+                    key, flags, data, ..
+                },
+                consumed,
+            ) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(flags, 3);
+                assert_eq!(data.as_ref(), b"hi");
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        let pending =
+            parse_storage_command_line(b"replace mykey 0 0 2\r\n", &ParseLimits::default())
+                .unwrap()
+                .unwrap();
+        assert_eq!(pending.kind, StorageKind::Replace);
+    }
+
+    #[test]
+    fn test_parse_append_prepend() {
+        let buf = b"append mykey 99 3600 4 noreply\r\n,foo\r\n";
+        match parse(buf) {
+            ParseResult::Complete(Command::Append { key, data, noreply }, consumed) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(data.as_ref(), b",foo");
+                assert!(noreply);
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        let buf = b"prepend mykey 0 0 4\r\nfoo,\r\n";
+        match parse(buf) {
+            ParseResult::Complete(Command::Prepend { key, data, noreply }, _) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(data.as_ref(), b"foo,");
+                assert!(!noreply);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_gets() {
+        let buf = b"gets foo bar\r\n";
+        match parse(buf) {
+            ParseResult::Complete(Command::Gets { keys }, consumed) => {
+                assert_eq!(keys.len(), 2);
+                assert_eq!(keys[0].as_ref(), b"foo");
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_gat() {
+        let buf = b"gat 300 session:1 session:2\r\n";
+        match parse(buf) {
+            ParseResult::Complete(Command::Gat { exptime, keys }, consumed) => {
+                assert_eq!(exptime, 300);
+                assert_eq!(keys.len(), 2);
+                assert_eq!(keys[1].as_ref(), b"session:2");
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"gats 0 session:1\r\n") {
+            ParseResult::Complete(Command::Gats { exptime, keys }, _) => {
+                assert_eq!(exptime, 0);
+                assert_eq!(keys[0].as_ref(), b"session:1");
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"gat 300\r\n") {
+            ParseResult::Error(ProtocolError::InvalidCommand(_)) => {}
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_cas() {
+        let buf = b"cas mykey 1 0 3 12345 noreply\r\nnew\r\n";
+        match parse(buf) {
+            ParseResult::Complete(
+                Command::Cas {
+                    key,
+                    flags,
+                    data,
+                    cas_unique,
+                    noreply,
+                    ..
+                },
+                consumed,
+            ) => {
+                assert_eq!(key.as_ref(), b"mykey");
+                assert_eq!(flags, 1);
+                assert_eq!(data.as_ref(), b"new");
+                assert_eq!(cas_unique, 12345);
+                assert!(noreply);
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        let pending =
+            parse_storage_command_line(b"cas mykey 0 0 3 99\r\n", &ParseLimits::default())
+                .unwrap()
+                .unwrap();
+        assert_eq!(pending.kind, StorageKind::Cas);
+        assert_eq!(pending.cas_unique, 99);
+    }
+
+    #[test]
+    fn test_parse_cas_missing_unique() {
+        match parse(b"cas mykey 0 0 3\r\nnew\r\n") {
+            ParseResult::Error(ProtocolError::InvalidCasUnique) => {}
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -391,7 +957,7 @@ mod tests {
                 assert_eq!(key.as_ref(), b"mykey");
                 assert!(!noreply);
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -402,7 +968,7 @@ mod tests {
             ParseResult::Complete(Command::Delete { noreply, .. }, _) => {
                 assert!(noreply);
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -415,7 +981,7 @@ mod tests {
                 assert_eq!(key.as_ref(), b"mykey");
                 assert!(!noreply);
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
 
         // delete <key> <exptime> noreply\r\n
@@ -425,7 +991,68 @@ mod tests {
                 assert_eq!(key.as_ref(), b"mykey");
                 assert!(noreply);
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_incr_decr() {
+        let buf = b"incr counter 5\r\n";
+        match parse(buf) {
+            ParseResult::Complete(
+                Command::Incr {
+                    key,
+                    delta,
+                    noreply,
+                },
+                consumed,
+            ) => {
+                assert_eq!(key.as_ref(), b"counter");
+                assert_eq!(delta, 5);
+                assert!(!noreply);
+                assert_eq!(consumed, buf.len());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"decr counter 2 noreply\r\n") {
+            ParseResult::Complete(Command::Decr { delta, noreply, .. }, _) => {
+                assert_eq!(delta, 2);
+                assert!(noreply);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"incr counter abc\r\n") {
+            ParseResult::Error(ProtocolError::InvalidNumericValue) => {}
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_flush_all() {
+        match parse(b"flush_all\r\n") {
+            ParseResult::Complete(Command::FlushAll { delay, noreply }, _) => {
+                assert_eq!(delay, 0);
+                assert!(!noreply);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"flush_all 30 noreply\r\n") {
+            ParseResult::Complete(Command::FlushAll { delay, noreply }, _) => {
+                assert_eq!(delay, 30);
+                assert!(noreply);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        match parse(b"flush_all noreply\r\n") {
+            ParseResult::Complete(Command::FlushAll { delay, noreply }, _) => {
+                assert_eq!(delay, 0);
+                assert!(noreply);
+            }
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -434,10 +1061,36 @@ mod tests {
         let buf = b"quit\r\n";
         match parse(buf) {
             ParseResult::Complete(Command::Quit, _) => {}
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
+    #[test]
+    fn test_parse_stats() {
+        match parse(b"stats\r\n") {
+            ParseResult::Complete(Command::Stats { args }, consumed) => {
+                assert!(args.is_none());
+                assert_eq!(consumed, 7);
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        for sub in [&b"reset"[..], b"settings", b"items"] {
+            let buf = [&b"stats "[..], sub, b"\r\n"].concat();
+            match parse(&buf) {
+                ParseResult::Complete(Command::Stats { args: Some(args) }, _) => {
+                    assert_eq!(args.as_ref(), sub);
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+        }
+
+        assert!(matches!(
+            parse(b"stats items extra\r\n"),
+            ParseResult::Error(ProtocolError::InvalidCommand(_))
+        ));
+    }
+
     #[test]
     fn test_parse_version() {
         let buf = b"version\r\n";
@@ -445,14 +1098,14 @@ mod tests {
             ParseResult::Complete(Command::Version, consumed) => {
                 assert_eq!(consumed, buf.len());
             }
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
 
         // Case insensitive
         let buf = b"VERSION\r\n";
         match parse(buf) {
             ParseResult::Complete(Command::Version, _) => {}
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -461,17 +1114,297 @@ mod tests {
         let buf = b"get foo";
         match parse(buf) {
             ParseResult::NeedMoreData => {}
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_value_too_large() {
+        let limits = ParseLimits {
+            max_item_size: 10,
+            ..ParseLimits::default()
+        };
+
+        // Rejected from the command line alone, before the data block arrives
+        let buf = b"set big 0 0 11\r\nhello";
+        match parse_with_limits(buf, &limits) {
+            ParseResult::Error(ProtocolError::ValueTooLarge { discard }) => {
+                assert_eq!(discard, 16 + 11 + 2);
+            }
+            other => panic!("unexpected: {other:?}"),
         }
+        assert_eq!(
+            parse_storage_command_line(buf, &limits).unwrap_err(),
+            ProtocolError::ValueTooLarge { discard: 29 }
+        );
+
+        let buf = b"set ok 0 0 10\r\n0123456789\r\n";
+        assert!(matches!(
+            parse_with_limits(buf, &limits),
+            ParseResult::Complete(Command::Set { .. }, _)
+        ));
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let limits = ParseLimits {
+            max_get_keys: 2,
+            max_command_line_bytes: 16,
+            ..ParseLimits::default()
+        };
+
+        assert!(matches!(
+            parse_with_limits(b"get a b\r\n", &limits),
+            ParseResult::Complete(Command::Get { .. }, 9)
+        ));
+        for buf in [&b"get a b c\r\n"[..], b"gats 0 a b c\r\n"] {
+            assert!(matches!(
+                parse_with_limits(buf, &limits),
+                ParseResult::Error(ProtocolError::TooManyKeys)
+            ));
+        }
+
+        // Too long with or without its terminator
+        assert!(matches!(
+            parse_with_limits(b"get aaaaaaaaaaaaaaaa\r\n", &limits),
+            ParseResult::Error(ProtocolError::LineTooLong)
+        ));
+        assert!(matches!(
+            parse_with_limits(b"get aaaaaaaaaaaaaa", &limits),
+            ParseResult::Error(ProtocolError::LineTooLong)
+        ));
+        assert!(matches!(
+            parse_with_limits(b"get aaaaaaaaaa", &limits),
+            ParseResult::NeedMoreData
+        ));
+
+        // A stray \r does not hide the terminator
+        assert!(matches!(
+            parse(b"get a\rb\r\n"),
+            ParseResult::Error(ProtocolError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_utf8_keys() {
+        let buf = "set clé 0 0 1\r\nx\r\n".as_bytes();
+        match parse(buf) {
+            ParseResult::Complete(Command::Set { key, .. }, _) => {
+                assert_eq!(key.as_ref(), "clé".as_bytes());
+            }
+            other => panic!("unexpected: {other:?}"),
+        }
+
+        let strict = ParseLimits {
+            strict_ascii_keys: true,
+            ..ParseLimits::default()
+        };
+        for buf in [buf, "get a clé\r\n".as_bytes(), "mg clé v\r\n".as_bytes()] {
+            assert!(matches!(
+                parse_with_limits(buf, &strict),
+                ParseResult::Error(ProtocolError::InvalidKey(_))
+            ));
+        }
+        assert!(matches!(
+            parse(b"delete key\x7f\r\n"),
+            ParseResult::Error(ProtocolError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_bare_lf() {
+        let lf = ParseLimits {
+            accept_bare_lf: true,
+            ..ParseLimits::default()
+        };
+
+        // Rejected (still waiting for \r\n) unless enabled
+        assert!(matches!(parse(b"version\n"), ParseResult::NeedMoreData));
+        assert!(matches!(
+            parse_with_limits(b"version\n", &lf),
+            ParseResult::Complete(Command::Version, 8)
+        ));
+        assert!(matches!(
+            parse_with_limits(b"get a\r\n", &lf),
+            ParseResult::Complete(Command::Get { .. }, 7)
+        ));
+
+        // Either terminator after the data block
+        for (buf, len) in [
+            (&b"set k 0 0 3\nbar\n"[..], 16),
+            (b"set k 0 0 3\nbar\r\n", 17),
+            (b"ms k 3\nbar\n", 11),
+        ] {
+            match parse_with_limits(buf, &lf) {
+                ParseResult::Complete(
+                    Command::Set { data, .. } | Command::MetaSet { data, .. },
+                    consumed,
+                ) => {
+                    assert_eq!(data.as_ref(), b"bar");
+                    assert_eq!(consumed, len);
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+        }
+
+        // The pending fast path tracks the shorter command line
+        let mut pending = parse_storage_command_line(b"add k 0 0 3\n", &lf)
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending.data_start, 12);
+        assert!(matches!(
+            parse_storage_data(b"add k 0 0 3\nba", &mut pending, &lf),
+            ParseResult::NeedMoreData
+        ));
+        assert!(matches!(
+            parse_storage_data(b"add k 0 0 3\nbar\n", &mut pending, &lf),
+            ParseResult::Complete(Command::Add { .. }, 16)
+        ));
+    }
+
+    #[test]
+    fn test_parse_extra_spaces() {
+        for buf in [&b"get  a   b\r\n"[..], b" get a b\r\n", b"get a b \r\n"] {
+            match parse(buf) {
+                ParseResult::Complete(Command::Get { keys }, consumed) => {
+                    assert_eq!(keys.len(), 2);
+                    assert_eq!(keys[1].as_ref(), b"b");
+                    assert_eq!(consumed, buf.len());
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+        }
+
+        for buf in [
+            &b"set  foo 5  0 3\r\nbar\r\n"[..],
+            b" set foo 5 0 3\r\nbar\r\n",
+            b"set foo 5 0 3  \r\nbar\r\n",
+        ] {
+            match parse(buf) {
+                ParseResult::Complete(
+                    Command::Set {
+                        key, flags, data, ..
+                    },
+                    consumed,
+                ) => {
+                    assert_eq!(key.as_ref(), b"foo");
+                    assert_eq!(flags, 5);
+                    assert_eq!(data.as_ref(), b"bar");
+                    assert_eq!(consumed, buf.len());
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+
+            let line_len = buf.len() - 5;
+            let pending = parse_storage_command_line(&buf[..line_len], &ParseLimits::default())
+                .unwrap()
+                .unwrap();
+            assert_eq!(pending.key, b"foo");
+            assert_eq!(pending.data_start, line_len);
+        }
+
+        for buf in [
+            &b"delete  foo  noreply\r\n"[..],
+            b" delete foo noreply\r\n",
+            b"delete foo noreply \r\n",
+        ] {
+            match parse(buf) {
+                ParseResult::Complete(Command::Delete { key, noreply }, _) => {
+                    assert_eq!(key.as_ref(), b"foo");
+                    assert!(noreply);
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+        }
+
+        for buf in [
+            &b"incr  foo  7\r\n"[..],
+            b" incr foo 7\r\n",
+            b"incr foo 7 \r\n",
+        ] {
+            match parse(buf) {
+                ParseResult::Complete(Command::Incr { key, delta, .. }, _) => {
+                    assert_eq!(key.as_ref(), b"foo");
+                    assert_eq!(delta, 7);
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+        }
+
+        for buf in [
+            &b"gat  10  foo\r\n"[..],
+            b" gat 10 foo\r\n",
+            b"gat 10 foo \r\n",
+        ] {
+            match parse(buf) {
+                ParseResult::Complete(Command::Gat { exptime, keys }, _) => {
+                    assert_eq!(exptime, 10);
+                    assert_eq!(keys.len(), 1);
+                }
+                other => panic!("unexpected: {other:?}"),
+            }
+        }
+
+        for buf in [&b"mg  foo  v\r\n"[..], b" mg foo v\r\n", b"mg foo v \r\n"] {
+            assert!(matches!(
+                parse(buf),
+                ParseResult::Complete(Command::MetaGet { .. }, _)
+            ));
+        }
+
+        assert!(matches!(
+            parse(b"flush_all  0  noreply \r\n"),
+            ParseResult::Complete(
+                Command::FlushAll {
+                    delay: 0,
+                    noreply: true
+                },
+                _
+            )
+        ));
+    }
+
+    #[test]
+    fn test_parse_bad_data_chunk() {
+        // Five bytes where three were announced: discard line + 3 + 2 only,
+        // leaving the following command intact
+        let buf = b"set foo 0 0 3\r\nhello\r\nget foo\r\n";
+        let discard = match parse(buf) {
+            ParseResult::Error(ProtocolError::BadDataChunk { discard }) => discard,
+            other => panic!("unexpected: {other:?}"),
+        };
+        assert_eq!(discard, 15 + 3 + 2);
+        assert_eq!(&buf[discard..], b"\r\nget foo\r\n");
+
+        // Same accounting when the data block arrived after the command line
+        let mut pending = parse_storage_command_line(b"set foo 0 0 3\r\n", &ParseLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            match parse_storage_data(
+                b"set foo 0 0 3\r\nabcde",
+                &mut pending,
+                &ParseLimits::default()
+            ) {
+                ParseResult::Error(e) => e,
+                other => panic!("unexpected: {other:?}"),
+            },
+            ProtocolError::BadDataChunk { discard: 20 }
+        );
     }
 
     #[test]
     fn test_parse_invalid_command() {
         let buf = b"invalid\r\n";
         match parse(buf) {
-            ParseResult::Error(ProtocolError::InvalidCommand(_)) => {}
-            other => panic!("unexpected: {:?}", other),
+            ParseResult::Error(ProtocolError::UnknownCommand(name)) => assert_eq!(name, "invalid"),
+            other => panic!("unexpected: {other:?}"),
         }
+
+        assert!(matches!(
+            parse(b"\r\n"),
+            ParseResult::Error(ProtocolError::UnknownCommand(_))
+        ));
     }
 
     #[test]
@@ -483,7 +1416,7 @@ mod tests {
 
         match parse(&buf) {
             ParseResult::Error(ProtocolError::KeyTooLong) => {}
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 
@@ -492,13 +1425,13 @@ mod tests {
         let buf = b"GET foo\r\n";
         match parse(buf) {
             ParseResult::Complete(Command::Get { .. }, _) => {}
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
 
         let buf = b"SET mykey 0 0 3\r\nbar\r\n";
         match parse(buf) {
             ParseResult::Complete(Command::Set { .. }, _) => {}
-            other => panic!("unexpected: {:?}", other),
+            other => panic!("unexpected: {other:?}"),
         }
     }
 }
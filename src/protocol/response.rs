@@ -1,11 +1,45 @@
 //! Memcached ASCII protocol response builder
 
-use bytes::BytesMut;
+use crate::protocol::binary::BinaryResponse;
+use bytes::{Bytes, BytesMut};
 use itoa::Buffer;
+use std::io::IoSlice;
 
 /// Response writer for memcached ASCII protocol
+///
+/// Replies are built in one buffer. Large values passed to `value_owned` are
+/// queued as separate segments instead (see `with_zero_copy_min`), so the
+/// queued output is `segments` followed by `buf`.
 pub struct ResponseWriter {
     buf: BytesMut,
+    /// Output queued ahead of `buf`: earlier parts of `buf` and large values
+    segments: Vec<Bytes>,
+    /// Total length of `segments`
+    segments_len: usize,
+    /// Values of at least this many bytes become their own segment (0 = never)
+    zero_copy_min: usize,
+    /// Set once an error reply has been written; such replies are sent even
+    /// for `noreply` commands so a lost write never goes unnoticed
+    error: bool,
+    /// Where the current command's reply starts (`segments` count, `buf`
+    /// length), when several are batched
+    reply_start: (usize, usize),
+    /// Values returned since `begin_reply`
+    reply_values: ReplyValues,
+}
+
+/// Values a reply returned, to tell hits from misses after the fact
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplyValues {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl ReplyValues {
+    fn add(&mut self, len: usize) {
+        self.count += 1;
+        self.bytes += len;
+    }
 }
 
 impl ResponseWriter {
@@ -13,32 +47,201 @@ impl ResponseWriter {
     pub fn new(capacity: usize) -> Self {
         Self {
             buf: BytesMut::with_capacity(capacity),
+            segments: Vec::new(),
+            segments_len: 0,
+            zero_copy_min: 0,
+            error: false,
+            reply_start: (0, 0),
+            reply_values: ReplyValues::default(),
         }
     }
 
-    /// Get the internal buffer
+    /// Queue values of at least `min` bytes without copying them (0 = always copy)
+    ///
+    /// Such replies are written with vectored writes; small values are
+    /// still copied, since a separate segment costs more than the copy.
+    #[must_use]
+    pub fn with_zero_copy_min(mut self, min: usize) -> Self {
+        self.zero_copy_min = min;
+        self
+    }
+
+    /// Get the internal buffer (everything queued unless a value was kept
+    /// as its own segment; see `io_slices`)
     pub fn buffer(&self) -> &[u8] {
         &self.buf
     }
 
-    /// Take the buffer, leaving an empty buffer in its place
+    /// The queued output in order, for a vectored write
+    pub fn io_slices(&self) -> Vec<IoSlice<'_>> {
+        self.segments
+            .iter()
+            .map(|segment| IoSlice::new(segment))
+            .chain((!self.buf.is_empty()).then(|| IoSlice::new(&self.buf)))
+            .collect()
+    }
+
+    /// Take the queued output, leaving an empty buffer in its place
+    ///
+    /// Segments are joined into one buffer, which copies them.
     pub fn take(&mut self) -> BytesMut {
-        std::mem::take(&mut self.buf)
+        self.error = false;
+        self.reply_start = (0, 0);
+        self.reply_values = ReplyValues::default();
+        if self.segments.is_empty() {
+            return std::mem::take(&mut self.buf);
+        }
+        let mut out = BytesMut::with_capacity(self.len());
+        for segment in self.segments.drain(..) {
+            out.extend_from_slice(&segment);
+        }
+        self.segments_len = 0;
+        out.extend_from_slice(&self.buf);
+        self.buf.clear();
+        out
     }
 
     /// Clear the buffer
     pub fn clear(&mut self) {
+        self.error = false;
+        self.reply_start = (0, 0);
+        self.reply_values = ReplyValues::default();
+        self.segments.clear();
+        self.segments_len = 0;
         self.buf.clear();
     }
 
-    /// Returns true if the buffer is empty
+    /// Returns true if nothing is queued
     pub fn is_empty(&self) -> bool {
-        self.buf.is_empty()
+        self.segments.is_empty() && self.buf.is_empty()
+    }
+
+    /// Number of queued bytes
+    pub fn len(&self) -> usize {
+        self.segments_len + self.buf.len()
+    }
+
+    /// Capacity of the reply buffer
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Replace an empty reply buffer that grew large with one of `capacity`
+    pub fn shrink_to(&mut self, capacity: usize) {
+        if self.is_empty() {
+            self.buf = BytesMut::with_capacity(capacity);
+        }
+    }
+
+    /// Whether the output has to be written with `io_slices`
+    pub fn is_segmented(&self) -> bool {
+        !self.segments.is_empty()
+    }
+
+    /// Start the next command's reply, keeping earlier replies queued
+    pub fn begin_reply(&mut self) {
+        self.error = false;
+        self.reply_start = (self.segments.len(), self.buf.len());
+        self.reply_values = ReplyValues::default();
+    }
+
+    /// Drop what was written since `begin_reply` (a `noreply` command's reply)
+    pub fn discard_reply(&mut self) {
+        let (segments, len) = self.reply_start;
+        if self.segments.len() > segments {
+            // The reply was split into segments; the first one added holds
+            // the output queued before it
+            let head = (len > 0).then(|| self.segments[segments].slice(..len));
+            self.segments.truncate(segments);
+            self.buf.clear();
+            if let Some(head) = head {
+                self.segments.push(head);
+            }
+            self.segments_len = self.segments.iter().map(Bytes::len).sum();
+        } else {
+            self.buf.truncate(len);
+        }
+    }
+
+    /// What was written since `begin_reply`; empty if part of it was
+    /// queued as a separate segment
+    pub fn reply(&self) -> &[u8] {
+        let (segments, len) = self.reply_start;
+        if self.segments.len() > segments {
+            return &[];
+        }
+        &self.buf[len..]
+    }
+
+    /// Returns true if an ERROR, CLIENT_ERROR or SERVER_ERROR was written
+    /// since the last `begin_reply`, `clear` or `take`
+    pub fn has_error(&self) -> bool {
+        self.error
+    }
+
+    /// Values written since the last `begin_reply`, `clear` or `take`
+    pub fn reply_values(&self) -> ReplyValues {
+        self.reply_values
     }
 
     /// Write a VALUE line for get response
     /// Format: VALUE <key> <flags> <bytes>\r\n<data>\r\n
     pub fn value(&mut self, key: &[u8], flags: u32, data: &[u8]) {
+        self.value_line(key, flags, data.len(), None);
+        self.buf.extend_from_slice(data);
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Write a VALUE line for gets response
+    /// Format: VALUE <key> <flags> <bytes> <cas unique>\r\n<data>\r\n
+    pub fn value_with_cas(&mut self, key: &[u8], flags: u32, data: &[u8], cas: u64) {
+        self.value_line(key, flags, data.len(), Some(cas));
+        self.buf.extend_from_slice(data);
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Like `value` / `value_with_cas`, taking the data so a large value is
+    /// queued without copying it
+    pub fn value_owned(&mut self, key: &[u8], flags: u32, data: Vec<u8>, cas: Option<u64>) {
+        self.value_line(key, flags, data.len(), cas);
+        if self.zero_copy_min > 0 && data.len() >= self.zero_copy_min {
+            if !self.buf.is_empty() {
+                let head = self.buf.split().freeze();
+                self.push_segment(head);
+            }
+            self.push_segment(Bytes::from(data));
+        } else {
+            self.buf.extend_from_slice(&data);
+        }
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Queue everything `other` holds after this writer's output, e.g. a
+    /// reply built on another thread
+    pub fn append(&mut self, mut other: ResponseWriter) {
+        if other.is_segmented() {
+            if !self.buf.is_empty() {
+                let head = self.buf.split().freeze();
+                self.push_segment(head);
+            }
+            for segment in other.segments.drain(..) {
+                self.push_segment(segment);
+            }
+        }
+        self.buf.extend_from_slice(&other.buf);
+        self.error |= other.error;
+        self.reply_values.count += other.reply_values.count;
+        self.reply_values.bytes += other.reply_values.bytes;
+    }
+
+    fn push_segment(&mut self, segment: Bytes) {
+        self.segments_len += segment.len();
+        self.segments.push(segment);
+    }
+
+    /// `VALUE <key> <flags> <bytes>[ <cas unique>]\r\n`
+    fn value_line(&mut self, key: &[u8], flags: u32, len: usize, cas: Option<u64>) {
+        self.reply_values.add(len);
         let mut itoa_buf = Buffer::new();
         self.buf.extend_from_slice(b"VALUE ");
         self.buf.extend_from_slice(key);
@@ -46,10 +249,11 @@ impl ResponseWriter {
         self.buf
             .extend_from_slice(itoa_buf.format(flags).as_bytes());
         self.buf.extend_from_slice(b" ");
-        self.buf
-            .extend_from_slice(itoa_buf.format(data.len()).as_bytes());
-        self.buf.extend_from_slice(b"\r\n");
-        self.buf.extend_from_slice(data);
+        self.buf.extend_from_slice(itoa_buf.format(len).as_bytes());
+        if let Some(cas) = cas {
+            self.buf.extend_from_slice(b" ");
+            self.buf.extend_from_slice(itoa_buf.format(cas).as_bytes());
+        }
         self.buf.extend_from_slice(b"\r\n");
     }
 
@@ -63,6 +267,21 @@ impl ResponseWriter {
         self.buf.extend_from_slice(b"STORED\r\n");
     }
 
+    /// Write OK response (flush_all)
+    pub fn ok(&mut self) {
+        self.buf.extend_from_slice(b"OK\r\n");
+    }
+
+    /// Write NOT_STORED response (add/replace condition not met)
+    pub fn not_stored(&mut self) {
+        self.buf.extend_from_slice(b"NOT_STORED\r\n");
+    }
+
+    /// Write EXISTS response (cas token mismatch)
+    pub fn exists(&mut self) {
+        self.buf.extend_from_slice(b"EXISTS\r\n");
+    }
+
     /// Write NOT_FOUND response
     pub fn not_found(&mut self) {
         self.buf.extend_from_slice(b"NOT_FOUND\r\n");
@@ -73,6 +292,90 @@ impl ResponseWriter {
         self.buf.extend_from_slice(b"DELETED\r\n");
     }
 
+    /// Write the new value after INCR/DECR
+    /// Format: <value>\r\n
+    pub fn numeric(&mut self, value: u64) {
+        let mut itoa_buf = Buffer::new();
+        self.buf
+            .extend_from_slice(itoa_buf.format(value).as_bytes());
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Write RESET response (after `stats reset`)
+    pub fn reset(&mut self) {
+        self.buf.extend_from_slice(b"RESET\r\n");
+    }
+
+    /// Write ERROR response (unknown command or subcommand)
+    pub fn error(&mut self) {
+        self.error = true;
+        self.buf.extend_from_slice(b"ERROR\r\n");
+    }
+
+    /// Write a single statistic line
+    /// Format: STAT <name> <value>\r\n
+    pub fn stat(&mut self, name: &str, value: &str) {
+        self.buf.extend_from_slice(b"STAT ");
+        self.buf.extend_from_slice(name.as_bytes());
+        self.buf.extend_from_slice(b" ");
+        self.buf.extend_from_slice(value.as_bytes());
+        self.buf.extend_from_slice(b"\r\n");
+    }
+
+    /// Write a numeric statistic line
+    pub fn stat_u64(&mut self, name: &str, value: u64) {
+        let mut itoa_buf = Buffer::new();
+        self.stat(name, itoa_buf.format(value));
+    }
+
+    /// Start a meta status line (HD, EN, NS, EX, NF); finish with `meta_end`
+    pub fn meta_status(&mut self, code: &str) {
+        self.buf.extend_from_slice(code.as_bytes());
+    }
+
+    /// Start a meta value line; finish with `meta_end(Some(data))`
+    /// Format: VA <len> <flags>*\r\n<data>\r\n
+    pub fn meta_value(&mut self, len: usize) {
+        self.reply_values.add(len);
+        let mut itoa_buf = Buffer::new();
+        self.buf.extend_from_slice(b"VA ");
+        self.buf.extend_from_slice(itoa_buf.format(len).as_bytes());
+    }
+
+    /// Append a return flag to the current meta line: ` <flag><token>`
+    pub fn meta_flag(&mut self, flag: u8, token: &[u8]) {
+        self.buf.extend_from_slice(&[b' ', flag]);
+        self.buf.extend_from_slice(token);
+    }
+
+    /// Append a numeric return flag to the current meta line
+    pub fn meta_flag_u64(&mut self, flag: u8, value: u64) {
+        let mut itoa_buf = Buffer::new();
+        self.meta_flag(flag, itoa_buf.format(value).as_bytes());
+    }
+
+    /// Finish the current meta line, followed by the data block for VA
+    pub fn meta_end(&mut self, data: Option<&[u8]>) {
+        self.buf.extend_from_slice(b"\r\n");
+        if let Some(data) = data {
+            self.buf.extend_from_slice(data);
+            self.buf.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /// Write MN response (meta no-op)
+    pub fn meta_noop(&mut self) {
+        self.buf.extend_from_slice(b"MN\r\n");
+    }
+
+    /// Write a binary protocol response packet
+    pub fn binary(&mut self, response: &BinaryResponse<'_>) {
+        if response.is_hit() {
+            self.reply_values.add(response.value.len());
+        }
+        response.encode(&mut self.buf);
+    }
+
     /// Write VERSION response
     /// Format: VERSION <version_string>\r\n
     /// Used by mcrouter for health checks (TKO recovery probes)
@@ -84,6 +387,7 @@ impl ResponseWriter {
 
     /// Write CLIENT_ERROR response
     pub fn client_error(&mut self, message: &str) {
+        self.error = true;
         self.buf.extend_from_slice(b"CLIENT_ERROR ");
         self.buf.extend_from_slice(message.as_bytes());
         self.buf.extend_from_slice(b"\r\n");
@@ -91,6 +395,7 @@ impl ResponseWriter {
 
     /// Write SERVER_ERROR response
     pub fn server_error(&mut self, message: &str) {
+        self.error = true;
         self.buf.extend_from_slice(b"SERVER_ERROR ");
         self.buf.extend_from_slice(message.as_bytes());
         self.buf.extend_from_slice(b"\r\n");
@@ -114,6 +419,13 @@ mod tests {
         assert_eq!(writer.buffer(), b"VALUE mykey 42 5\r\nhello\r\n");
     }
 
+    #[test]
+    fn test_value_with_cas() {
+        let mut writer = ResponseWriter::new(256);
+        writer.value_with_cas(b"mykey", 42, b"hello", 987);
+        assert_eq!(writer.buffer(), b"VALUE mykey 42 5 987\r\nhello\r\n");
+    }
+
     #[test]
     fn test_get_response() {
         let mut writer = ResponseWriter::new(256);
@@ -132,11 +444,203 @@ mod tests {
         writer.stored();
         assert_eq!(writer.take().as_ref(), b"STORED\r\n");
 
+        writer.ok();
+        assert_eq!(writer.take().as_ref(), b"OK\r\n");
+
+        writer.not_stored();
+        assert_eq!(writer.take().as_ref(), b"NOT_STORED\r\n");
+
+        writer.exists();
+        assert_eq!(writer.take().as_ref(), b"EXISTS\r\n");
+
         writer.deleted();
         assert_eq!(writer.take().as_ref(), b"DELETED\r\n");
 
         writer.not_found();
         assert_eq!(writer.take().as_ref(), b"NOT_FOUND\r\n");
+
+        writer.reset();
+        assert_eq!(writer.take().as_ref(), b"RESET\r\n");
+
+        writer.error();
+        assert_eq!(writer.take().as_ref(), b"ERROR\r\n");
+    }
+
+    /// Concatenate the queued output the way a vectored write sends it
+    fn wire(writer: &ResponseWriter) -> Vec<u8> {
+        writer.io_slices().iter().flat_map(|s| s.to_vec()).collect()
+    }
+
+    #[test]
+    fn test_zero_copy_matches_copy() {
+        let values: [(&[u8], Vec<u8>); 3] = [
+            (b"small", b"abc".to_vec()),
+            (b"large", vec![b'x'; 4096]),
+            (b"empty", Vec::new()),
+        ];
+        let mut copy = ResponseWriter::new(256);
+        let mut zero_copy = ResponseWriter::new(256).with_zero_copy_min(1024);
+        for writer in [&mut copy, &mut zero_copy] {
+            for (i, (key, data)) in values.iter().enumerate() {
+                let cas = (i % 2 == 1).then_some(42);
+                writer.value_owned(key, 5, data.clone(), cas);
+            }
+            writer.end();
+        }
+
+        // The borrowing variants write the same bytes too
+        let mut borrowed = ResponseWriter::new(256);
+        borrowed.value(b"small", 5, b"abc");
+        borrowed.value_with_cas(b"large", 5, &values[1].1, 42);
+        borrowed.value(b"empty", 5, b"");
+        borrowed.end();
+        assert_eq!(borrowed.buffer(), copy.buffer());
+
+        assert!(!copy.is_segmented());
+        assert!(zero_copy.is_segmented());
+        assert_eq!(zero_copy.len(), copy.len());
+        assert_eq!(wire(&zero_copy), copy.buffer());
+        assert_eq!(zero_copy.take(), copy.take());
+        assert!(zero_copy.is_empty());
+    }
+
+    #[test]
+    fn test_discard_segmented_reply() {
+        let mut writer = ResponseWriter::new(256).with_zero_copy_min(4);
+        writer.begin_reply();
+        writer.stored();
+        writer.begin_reply();
+        writer.value_owned(b"foo", 0, b"large".to_vec(), None);
+        writer.end();
+        writer.discard_reply();
+        assert_eq!(wire(&writer), b"STORED\r\n");
+        assert_eq!(writer.len(), b"STORED\r\n".len());
+    }
+
+    #[test]
+    fn test_append() {
+        let mut writer = ResponseWriter::new(64).with_zero_copy_min(4);
+        writer.stored();
+        writer.begin_reply();
+
+        let mut other = ResponseWriter::new(64).with_zero_copy_min(4);
+        other.value_owned(b"foo", 0, b"large".to_vec(), None);
+        other.end();
+        other.server_error("disk full");
+        writer.append(other);
+
+        assert!(writer.has_error());
+        assert_eq!(
+            wire(&writer),
+            b"STORED\r\nVALUE foo 0 5\r\nlarge\r\nEND\r\nSERVER_ERROR disk full\r\n"
+        );
+        writer.discard_reply();
+        assert_eq!(wire(&writer), b"STORED\r\n");
+    }
+
+    #[test]
+    fn test_shrink_to() {
+        let mut writer = ResponseWriter::new(64);
+        writer.value(b"big", 0, &vec![b'x'; 100_000]);
+        writer.shrink_to(64);
+        // Not while anything is queued
+        assert!(writer.capacity() >= 100_000);
+        writer.clear();
+        writer.shrink_to(64);
+        assert!(writer.capacity() < 1024);
+    }
+
+    #[test]
+    fn test_batched_replies() {
+        let mut writer = ResponseWriter::new(256);
+        writer.begin_reply();
+        writer.stored();
+        // A noreply command's success reply is dropped, earlier replies stay
+        writer.begin_reply();
+        writer.stored();
+        writer.discard_reply();
+        assert_eq!(writer.buffer(), b"STORED\r\n");
+
+        writer.begin_reply();
+        writer.server_error("disk full");
+        assert!(writer.has_error());
+        writer.begin_reply();
+        assert!(!writer.has_error());
+        assert_eq!(writer.len(), b"STORED\r\nSERVER_ERROR disk full\r\n".len());
+    }
+
+    #[test]
+    fn test_error_flag() {
+        let mut writer = ResponseWriter::new(256);
+        writer.stored();
+        assert!(!writer.has_error());
+
+        writer.server_error("disk full");
+        assert!(writer.has_error());
+        writer.clear();
+        assert!(!writer.has_error());
+
+        writer.client_error("bad command line format");
+        assert!(writer.has_error());
+        let _ = writer.take();
+        assert!(!writer.has_error());
+
+        writer.error();
+        assert!(writer.has_error());
+    }
+
+    #[test]
+    fn test_reply_values() {
+        let mut writer = ResponseWriter::new(256);
+        writer.value(b"key1", 0, b"value1");
+        writer.begin_reply();
+        writer.value(b"key2", 0, b"abc");
+        writer.meta_value(4);
+        writer.meta_end(Some(b"abcd"));
+        writer.end();
+        assert_eq!(writer.reply_values(), ReplyValues { count: 2, bytes: 7 });
+
+        // A miss returns nothing
+        writer.begin_reply();
+        writer.end();
+        assert_eq!(writer.reply_values(), ReplyValues::default());
+    }
+
+    #[test]
+    fn test_numeric() {
+        let mut writer = ResponseWriter::new(256);
+        writer.numeric(18_446_744_073_709_551_615);
+        assert_eq!(writer.buffer(), b"18446744073709551615\r\n");
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut writer = ResponseWriter::new(256);
+        writer.stat("version", "0.1.0");
+        writer.stat_u64("curr_items", 42);
+        writer.end();
+        assert_eq!(
+            writer.buffer(),
+            b"STAT version 0.1.0\r\nSTAT curr_items 42\r\nEND\r\n"
+        );
+    }
+
+    #[test]
+    fn test_meta() {
+        let mut writer = ResponseWriter::new(256);
+        writer.meta_value(5);
+        writer.meta_flag_u64(b'f', 30);
+        writer.meta_flag(b'k', b"foo");
+        writer.meta_end(Some(b"hello"));
+        assert_eq!(writer.take().as_ref(), b"VA 5 f30 kfoo\r\nhello\r\n");
+
+        writer.meta_status("HD");
+        writer.meta_flag(b'O', b"123");
+        writer.meta_end(None);
+        assert_eq!(writer.take().as_ref(), b"HD O123\r\n");
+
+        writer.meta_noop();
+        assert_eq!(writer.take().as_ref(), b"MN\r\n");
     }
 
     #[test]
@@ -0,0 +1,418 @@
+//! Configuration reload on SIGHUP
+//!
+//! The configuration is loaded again the way it was at startup (file,
+//! `PETRACACHE_*` variables, command-line flags) and validated. Changes to
+//! the settings in `RELOADABLE` are applied to the running server; changes
+//! to anything else are logged as ignored until the next restart. An
+//! invalid configuration changes nothing. Every reload is counted in
+//! `petracache_config_reloads_total` by result.
+
+use crate::config::{self, Args, Config, StorageConfig};
+use crate::logging::LogLevelHandle;
+use crate::metrics::Metrics;
+use crate::server::Server;
+use crate::storage::runtime_options;
+use parking_lot::Mutex;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+/// Settings a reload applies; see `take_reloadable`
+const RELOADABLE: [&str; 13] = [
+    "server.max_connections",
+    "server.max_connections_per_ip",
+    "server.connection_timeout_secs",
+    "server.disabled_commands",
+    "server.read_only",
+    "server.slow_log_threshold_ms",
+    "server.slow_log_hash_keys",
+    "server.slow_log_max_per_sec",
+    "storage.write_buffer_size",
+    "storage.max_write_buffer_number",
+    "storage.target_file_size_base",
+    "storage.max_background_jobs",
+    "log.level",
+];
+
+/// One setting that differs between two configurations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// Dotted path, e.g. `server.max_connections`
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} -> {})", self.field, self.old, self.new)
+    }
+}
+
+/// What a reload did
+#[derive(Debug, Default)]
+pub struct ReloadOutcome {
+    pub applied: Vec<Change>,
+    /// Changed settings that need a restart
+    pub ignored: Vec<Change>,
+    /// RocksDB options the database refused; tried again on the next
+    /// reload
+    pub rejected: Vec<Change>,
+}
+
+/// Reloads the configuration into the running server
+pub struct Reloader {
+    args: Args,
+    /// The startup configuration with every reload's applied settings
+    running: Mutex<Config>,
+    server: Arc<Server>,
+    log_level: LogLevelHandle,
+    metrics: Arc<Metrics>,
+}
+
+impl Reloader {
+    /// `config` is what `args` loaded at startup
+    pub fn new(
+        args: Args,
+        config: Config,
+        server: Arc<Server>,
+        log_level: LogLevelHandle,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            args,
+            running: Mutex::new(config),
+            server,
+            log_level,
+            metrics,
+        }
+    }
+
+    /// Load the configuration again and apply what changed, logging and
+    /// counting the result
+    pub fn reload(&self) -> Result<ReloadOutcome, String> {
+        let result = config::load(&self.args)
+            .map_err(|e| e.to_string())
+            .and_then(|config| self.apply(&config));
+        match &result {
+            Ok(outcome) => {
+                self.metrics
+                    .config_reloads
+                    .with_label_values(&["success"])
+                    .inc();
+                if outcome.applied.is_empty() {
+                    info!("Configuration reloaded, nothing to apply");
+                } else {
+                    info!(
+                        "Configuration reloaded, applied: {}",
+                        list(&outcome.applied)
+                    );
+                }
+                if !outcome.rejected.is_empty() {
+                    error!(
+                        "RocksDB rejected configuration changes: {}",
+                        list(&outcome.rejected)
+                    );
+                }
+                if !outcome.ignored.is_empty() {
+                    warn!(
+                        "Ignored configuration changes that need a restart: {}",
+                        list(&outcome.ignored)
+                    );
+                }
+            }
+            Err(e) => {
+                self.metrics
+                    .config_reloads
+                    .with_label_values(&["failure"])
+                    .inc();
+                error!("Configuration reload failed, nothing changed: {}", e);
+            }
+        }
+        result
+    }
+
+    fn apply(&self, config: &Config) -> Result<ReloadOutcome, String> {
+        if let Err(errors) = config.validate() {
+            let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            return Err(errors.join("; "));
+        }
+        let mut running = self.running.lock();
+        let (applied, ignored): (Vec<Change>, Vec<Change>) = changes(&running, config)
+            .into_iter()
+            .partition(|change| RELOADABLE.contains(&change.field.as_str()));
+        let changed = |field: &str| applied.iter().any(|change| change.field == field);
+
+        if changed("log.level") {
+            self.log_level.set(&config.log.level)?;
+        }
+        self.server.reconfigure(&config.server);
+        // Only a changed file overrides POST /admin/readonly
+        if changed("server.read_only") {
+            self.server.set_read_only(config.server.read_only);
+        }
+        let options: Vec<(String, String)> = runtime_options(&config.storage)
+            .into_iter()
+            .filter(|(name, _)| changed(&format!("storage.{name}")))
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        let refused = self.server.storage.set_options(&options).rejected;
+        let (rejected, applied) = applied.into_iter().partition(|change| {
+            refused
+                .iter()
+                .any(|(name, _)| change.field == format!("storage.{name}"))
+        });
+
+        take_reloadable(&mut running, config);
+        // Keep what RocksDB has in effect
+        take_storage_options(&mut running.storage, &self.server.storage.settings());
+        Ok(ReloadOutcome {
+            applied,
+            ignored,
+            rejected,
+        })
+    }
+}
+
+/// Copy the `RELOADABLE` settings of `config` into `running`
+fn take_reloadable(running: &mut Config, config: &Config) {
+    let (server, new) = (&mut running.server, &config.server);
+    server.max_connections = new.max_connections;
+    server.max_connections_per_ip = new.max_connections_per_ip;
+    server.connection_timeout_secs = new.connection_timeout_secs;
+    server.disabled_commands = new.disabled_commands;
+    server.read_only = new.read_only;
+    server.slow_log_threshold_ms = new.slow_log_threshold_ms;
+    server.slow_log_hash_keys = new.slow_log_hash_keys;
+    server.slow_log_max_per_sec = new.slow_log_max_per_sec;
+    take_storage_options(&mut running.storage, &config.storage);
+    running.log.level.clone_from(&config.log.level);
+}
+
+/// Copy the `[storage]` settings `RocksStorage::set_options` changes
+fn take_storage_options(running: &mut StorageConfig, new: &StorageConfig) {
+    running.write_buffer_size = new.write_buffer_size;
+    running.max_write_buffer_number = new.max_write_buffer_number;
+    running.target_file_size_base = new.target_file_size_base;
+    running.max_background_jobs = new.max_background_jobs;
+}
+
+/// Every setting that differs from `old` in `new`, sorted by field, with
+/// a changed password last
+pub fn changes(old: &Config, new: &Config) -> Vec<Change> {
+    let mut changes = Vec::new();
+    match (toml::Value::try_from(old), toml::Value::try_from(new)) {
+        (Ok(old), Ok(new)) => diff("", Some(&old), Some(&new), &mut changes),
+        (Err(e), _) | (_, Err(e)) => error!("Failed to compare configurations: {}", e),
+    }
+    // Serialized redacted, so compared here
+    let password = |config: &Config| {
+        config
+            .server
+            .auth
+            .as_ref()
+            .map(|auth| auth.password.clone())
+    };
+    if password(old) != password(new) {
+        changes.push(Change {
+            field: "server.auth.password".to_string(),
+            old: "<redacted>".to_string(),
+            new: "<redacted>".to_string(),
+        });
+    }
+    changes
+}
+
+fn diff(
+    path: &str,
+    old: Option<&toml::Value>,
+    new: Option<&toml::Value>,
+    changes: &mut Vec<Change>,
+) {
+    if let (Some(toml::Value::Table(old)), Some(toml::Value::Table(new))) = (old, new) {
+        let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            let path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{path}.{key}")
+            };
+            diff(&path, old.get(key), new.get(key), changes);
+        }
+    } else if old != new {
+        changes.push(Change {
+            field: path.to_string(),
+            old: show(old),
+            new: show(new),
+        });
+    }
+}
+
+/// A value for the log
+fn show(value: Option<&toml::Value>) -> String {
+    match value {
+        None => "unset".to_string(),
+        Some(toml::Value::Table(_)) => "{...}".to_string(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn list(changes: &[Change]) -> String {
+    let changes: Vec<String> = changes.iter().map(ToString::to_string).collect();
+    changes.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AuthConfig;
+    use crate::protocol::CommandKind;
+    use crate::testing::test_server;
+    use tempfile::TempDir;
+
+    fn fields(changes: &[Change]) -> Vec<&str> {
+        changes.iter().map(|change| change.field.as_str()).collect()
+    }
+
+    #[test]
+    fn test_changes() {
+        let old = Config::default();
+        let mut new = Config::default();
+        assert!(changes(&old, &new).is_empty());
+
+        new.server.max_connections = 5;
+        new.storage.db_path = "/elsewhere".into();
+        new.server.auth = Some(AuthConfig {
+            username: "app".to_string(),
+            password: "secret".to_string(),
+        });
+        let found = changes(&old, &new);
+        assert_eq!(
+            fields(&found),
+            [
+                "server.auth",
+                "server.max_connections",
+                "storage.db_path",
+                "server.auth.password"
+            ]
+        );
+        assert_eq!(found[0].old, "unset");
+        assert_eq!(found[1].to_string(), "server.max_connections (10000 -> 5)");
+        assert!(!format!("{found:?}").contains("secret"));
+    }
+
+    #[test]
+    fn test_take_reloadable_covers_every_field() {
+        let old = Config::default();
+        let mut new = Config::default();
+        let server = &mut new.server;
+        server.max_connections = 1;
+        server.max_connections_per_ip = 1;
+        server.connection_timeout_secs = 1;
+        server.disabled_commands.insert(CommandKind::FlushAll);
+        server.read_only = true;
+        server.slow_log_threshold_ms = 1;
+        server.slow_log_hash_keys = true;
+        server.slow_log_max_per_sec = 1;
+        let storage = &mut new.storage;
+        storage.write_buffer_size = 1;
+        storage.max_write_buffer_number = 1;
+        storage.target_file_size_base = 1;
+        storage.max_background_jobs = 1;
+        new.log.level = "debug".to_string();
+        let found = changes(&old, &new);
+        let mut changed = fields(&found);
+        changed.sort_unstable();
+        let mut reloadable = RELOADABLE.to_vec();
+        reloadable.sort_unstable();
+        assert_eq!(changed, reloadable);
+
+        let mut running = old;
+        take_reloadable(&mut running, &new);
+        assert!(changes(&running, &new).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("petracache.toml");
+        // Where `test_server` opens the store
+        let db_path = dir.path().join("test_db");
+        let write_config = |server: &str, storage: &str| {
+            let toml = format!(
+                "[server]\n{server}\n[storage]\ndb_path = {:?}\n{storage}\n",
+                db_path.to_str().unwrap()
+            );
+            std::fs::write(&path, toml).unwrap();
+        };
+        write_config("max_connections = 100", "");
+
+        let args = Args {
+            config: Some(path.clone()),
+            ..Args::default()
+        };
+        let config = config::load(&args).unwrap();
+        let server = test_server(&dir, config.server.clone());
+        let metrics = Arc::clone(&server.metrics);
+        let (_layer, log_level) = crate::logging::detached("info");
+        let reloader = Reloader::new(
+            args,
+            config,
+            Arc::clone(&server),
+            log_level.clone(),
+            Arc::clone(&metrics),
+        );
+
+        write_config(
+            "max_connections = 200\nslow_log_threshold_ms = \"50ms\"\n\
+             disabled_commands = [\"flush_all\"]\nread_only = true\n\
+             listen_addr = \"127.0.0.1:22122\"",
+            "write_buffer_size = \"32MiB\"\n[log]\nlevel = \"debug\"",
+        );
+        let outcome = reloader.reload().unwrap();
+        assert_eq!(
+            fields(&outcome.applied),
+            [
+                "log.level",
+                "server.disabled_commands",
+                "server.max_connections",
+                "server.read_only",
+                "server.slow_log_threshold_ms",
+                "storage.write_buffer_size"
+            ]
+        );
+        assert_eq!(fields(&outcome.ignored), ["server.listen_addr"]);
+        assert!(outcome.rejected.is_empty());
+        assert_eq!(server.storage.settings().write_buffer_size, 32 << 20);
+        let settings = server.settings();
+        assert_eq!(settings.max_connections, 200);
+        assert_eq!(settings.slow_log_threshold_ms, 50);
+        assert!(settings.disabled_commands.contains(CommandKind::FlushAll));
+        assert!(server.is_read_only());
+        assert_eq!(settings.listen_addr, "127.0.0.1:11211");
+        assert_eq!(log_level.current().unwrap(), "debug");
+
+        // read_only set through the admin endpoint since is kept while the
+        // file doesn't change it; the ignored change is reported again
+        server.set_read_only(false);
+        let outcome = reloader.reload().unwrap();
+        assert!(outcome.applied.is_empty());
+        assert_eq!(fields(&outcome.ignored), ["server.listen_addr"]);
+        assert!(!server.is_read_only());
+
+        // An invalid file changes nothing
+        write_config("max_connections = 0", "");
+        assert!(
+            reloader
+                .reload()
+                .unwrap_err()
+                .contains("server.max_connections")
+        );
+        assert_eq!(server.settings().max_connections, 200);
+
+        let reloads = |result: &str| metrics.config_reloads.with_label_values(&[result]).get();
+        assert_eq!(reloads("success"), 2);
+        assert_eq!(reloads("failure"), 1);
+    }
+}
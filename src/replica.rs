@@ -0,0 +1,140 @@
+//! Catch-up loop for secondary mode
+//!
+//! A secondary opens the primary's database read-only and only sees writes
+//! made up to its last catch-up. This task catches up every
+//! `catch_up_interval_ms`, keeps `replication_lag_seconds` current, and
+//! reports the instance not ready after `catch_up_max_failures` failures in
+//! a row, so traffic moves to replicas that still follow the primary.
+
+use crate::config::StorageConfig;
+use crate::health::HealthServer;
+use crate::metrics::Metrics;
+use crate::storage::RocksStorage;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Catch up until `cancel` fires; returns immediately unless `storage` is a
+/// secondary
+pub async fn run(
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    health: Option<Arc<HealthServer>>,
+    config: &StorageConfig,
+    cancel: CancellationToken,
+) {
+    if !storage.is_read_only() {
+        return;
+    }
+    info!(
+        primary_path = ?config.primary_path,
+        interval_ms = config.catch_up_interval_ms,
+        "Following primary"
+    );
+    let max_failures = config.catch_up_max_failures.max(1);
+    let mut ticker =
+        tokio::time::interval(Duration::from_millis(config.catch_up_interval_ms.max(1)));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut last_success = Instant::now();
+    let mut failures = 0u32;
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let task_storage = Arc::clone(&storage);
+        let result = tokio::task::spawn_blocking(move || task_storage.catch_up_with_primary())
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string()));
+        match result {
+            Ok(()) => {
+                last_success = Instant::now();
+                if failures >= max_failures {
+                    info!("Caught up with primary again");
+                    // Shutdown may have failed readiness meanwhile
+                    if let Some(health) = &health
+                        && !cancel.is_cancelled()
+                    {
+                        health.set_ready(true);
+                    }
+                }
+                failures = 0;
+            }
+            Err(e) => {
+                metrics.catch_up_failures.inc();
+                failures = failures.saturating_add(1);
+                error!(failures, "Catch-up with primary failed: {}", e);
+                if failures == max_failures {
+                    warn!("Reporting not ready until catch-up succeeds");
+                    if let Some(health) = &health {
+                        health.set_ready(false);
+                    }
+                }
+            }
+        }
+        metrics
+            .replication_lag_seconds
+            .set(last_success.elapsed().as_secs_f64());
+    }
+    debug!("Catch-up loop stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageMode;
+    use crate::storage::StoredValue;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_secondary_follows_primary() {
+        let tmp_dir = TempDir::new().unwrap();
+        let primary_path = tmp_dir.path().join("primary");
+        let primary = RocksStorage::open(&StorageConfig {
+            db_path: primary_path.clone(),
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        primary
+            .set(b"before", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        primary.flush().unwrap();
+
+        let config = StorageConfig {
+            db_path: tmp_dir.path().join("secondary"),
+            mode: StorageMode::Secondary,
+            primary_path: Some(primary_path),
+            catch_up_interval_ms: 1,
+            ..StorageConfig::default()
+        };
+        let secondary = Arc::new(RocksStorage::open(&config).unwrap());
+        assert!(secondary.get(b"before").unwrap().is_some());
+
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn({
+            let (storage, metrics, cancel) =
+                (Arc::clone(&secondary), Arc::clone(&metrics), cancel.clone());
+            async move { run(storage, metrics, None, &config, cancel).await }
+        });
+
+        primary
+            .set(b"after", StoredValue::new(0, 0, b"2".to_vec()))
+            .unwrap();
+        primary.flush().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while secondary.get(b"after").unwrap().is_none() {
+            assert!(Instant::now() < deadline, "secondary never caught up");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        cancel.cancel();
+        task.await.unwrap();
+        assert_eq!(metrics.catch_up_failures.get(), 0);
+    }
+}
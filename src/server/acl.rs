@@ -0,0 +1,106 @@
+//! Client IP allowlist for the data port (`server.allowed_cidrs`)
+
+use crate::config::IpNet;
+use std::net::IpAddr;
+
+/// Networks allowed to connect, as (network, mask) pairs per family
+pub(super) struct IpAllowlist {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl IpAllowlist {
+    /// The allowlist for `nets`; `None` when empty, which allows everyone
+    pub(super) fn new(nets: &[IpNet]) -> Option<Self> {
+        if nets.is_empty() {
+            return None;
+        }
+        let mut allowlist = Self {
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        for net in nets {
+            match net.addr {
+                IpAddr::V4(addr) => allowlist.push_v4(u32::from(addr), net.prefix_len),
+                // An IPv4-mapped network is an IPv4 network
+                IpAddr::V6(addr) => match addr.to_ipv4_mapped() {
+                    Some(v4) if net.prefix_len >= 96 => {
+                        allowlist.push_v4(u32::from(v4), net.prefix_len - 96);
+                    }
+                    _ => {
+                        let mask = u128::MAX.checked_shl(128 - u32::from(net.prefix_len));
+                        let mask = mask.unwrap_or(0);
+                        allowlist.v6.push((u128::from(addr) & mask, mask));
+                    }
+                },
+            }
+        }
+        Some(allowlist)
+    }
+
+    fn push_v4(&mut self, addr: u32, prefix_len: u8) {
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0);
+        self.v4.push((addr & mask, mask));
+    }
+
+    /// Whether `ip` is in one of the networks; IPv4-mapped IPv6 addresses
+    /// (from a dual-stack listener) are matched as IPv4
+    pub(super) fn allows(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let ip = u32::from(ip);
+                self.v4.iter().any(|&(net, mask)| ip & mask == net)
+            }
+            IpAddr::V6(ip) => {
+                let ip = u128::from(ip);
+                self.v6.iter().any(|&(net, mask)| ip & mask == net)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(cidrs: &[&str]) -> IpAllowlist {
+        let nets: Vec<IpNet> = cidrs
+            .iter()
+            .map(|cidr| IpNet::try_from((*cidr).to_string()).unwrap())
+            .collect();
+        IpAllowlist::new(&nets).unwrap()
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allows() {
+        let acl = allowlist(&["10.0.0.0/8", "192.168.1.7", "fd00::/8"]);
+        assert!(acl.allows(ip("10.1.2.3")));
+        assert!(!acl.allows(ip("11.0.0.1")));
+        assert!(acl.allows(ip("192.168.1.7")));
+        assert!(!acl.allows(ip("192.168.1.8")));
+        assert!(acl.allows(ip("fd12::1")));
+        assert!(!acl.allows(ip("fe80::1")));
+
+        // IPv4-mapped peers match IPv4 networks, not IPv6 ones
+        assert!(acl.allows(ip("::ffff:10.9.9.9")));
+        assert!(!acl.allows(ip("::ffff:11.0.0.1")));
+        let acl = allowlist(&["::ffff:172.16.0.0/108"]);
+        assert!(acl.allows(ip("172.16.5.5")));
+        assert!(!acl.allows(ip("172.32.0.1")));
+
+        let acl = allowlist(&["0.0.0.0/0"]);
+        assert!(acl.allows(ip("8.8.8.8")));
+        assert!(!acl.allows(ip("::1")));
+
+        assert!(IpAllowlist::new(&[]).is_none());
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0/8", "host/8", ""] {
+            assert!(IpNet::try_from(invalid.to_string()).is_err(), "{invalid}");
+        }
+    }
+}
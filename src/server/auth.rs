@@ -0,0 +1,122 @@
+//! ASCII authentication (`[server.auth]`), as in memcached 1.6
+//!
+//! A connection starts unauthenticated. Its first `set` carries the
+//! credentials as data, `username password`; any key, flags and exptime
+//! are accepted. Until one succeeds only `version` and `quit` run, other
+//! commands get `CLIENT_ERROR unauthenticated`, and the connection is
+//! closed after `MAX_AUTH_FAILURES` wrong credentials.
+
+use super::Server;
+use crate::config::AuthConfig;
+use crate::protocol::{Command, ResponseWriter};
+use tracing::debug;
+
+/// Wrong credentials a connection may send before it is closed
+const MAX_AUTH_FAILURES: u32 = 3;
+
+/// One connection's authentication state
+pub(super) struct AuthState {
+    authenticated: bool,
+    failures: u32,
+}
+
+/// What the connection does with a command after `AuthState::check`
+pub(super) enum AuthCheck<'a> {
+    /// Execute it as usual
+    Pass(Command<'a>),
+    /// It was answered here
+    Replied,
+    /// It was answered here; close the connection
+    Close,
+}
+
+impl AuthState {
+    pub(super) fn new(server: &Server) -> Self {
+        Self {
+            authenticated: server.config.auth.is_none(),
+            failures: 0,
+        }
+    }
+
+    pub(super) fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Let `cmd` through once authenticated; before that, treat a `set` as
+    /// an authentication attempt and refuse everything but `version` and
+    /// `quit`
+    pub(super) fn check<'a>(
+        &mut self,
+        server: &Server,
+        cmd: Command<'a>,
+        response: &mut ResponseWriter,
+    ) -> AuthCheck<'a> {
+        if self.authenticated {
+            return AuthCheck::Pass(cmd);
+        }
+        let (Some(auth), Command::Set { data, .. }) = (&server.config.auth, &cmd) else {
+            if matches!(cmd, Command::Version | Command::Quit) {
+                return AuthCheck::Pass(cmd);
+            }
+            response.client_error("unauthenticated");
+            return AuthCheck::Replied;
+        };
+
+        if credentials_match(auth, data) {
+            self.authenticated = true;
+            server.metrics.auth_successes.inc();
+            response.stored();
+            return AuthCheck::Replied;
+        }
+        self.failures += 1;
+        server.metrics.auth_failures.inc();
+        response.client_error("authentication failure");
+        if self.failures >= MAX_AUTH_FAILURES {
+            debug!(
+                "Closing connection after {} failed authentications",
+                self.failures
+            );
+            return AuthCheck::Close;
+        }
+        AuthCheck::Replied
+    }
+}
+
+/// Whether `data` is `username password` for `auth`
+fn credentials_match(auth: &AuthConfig, data: &[u8]) -> bool {
+    let Some(space) = memchr::memchr(b' ', data) else {
+        return false;
+    };
+    let (username, password) = (&data[..space], &data[space + 1..]);
+    // Both are compared in full so the reply time doesn't reveal which
+    // one, or how much of it, was right
+    let username_ok = constant_time_eq(username, auth.username.as_bytes());
+    let password_ok = constant_time_eq(password, auth.password.as_bytes());
+    username_ok & password_ok
+}
+
+/// Compare without stopping at the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_match() {
+        let auth = AuthConfig {
+            username: "app".to_string(),
+            password: "s3cret pass".to_string(),
+        };
+        assert!(credentials_match(&auth, b"app s3cret pass"));
+        assert!(!credentials_match(&auth, b"app s3cret"));
+        assert!(!credentials_match(&auth, b"app"));
+        assert!(!credentials_match(&auth, b"ap s3cret pass"));
+        assert!(!credentials_match(&auth, b""));
+    }
+}
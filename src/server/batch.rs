@@ -0,0 +1,138 @@
+//! Coalescing of pipelined `set` commands into one RocksDB write
+//!
+//! Bulk loaders pipeline long runs of `set ... noreply`. Consecutive sets
+//! from one connection are queued here and written with a single
+//! `WriteBatch` instead of one `put` each. The connection commits the
+//! batch before anything else can reply or observe storage: another
+//! command, a parse error, or waiting on the client.
+
+use super::Server;
+use crate::StorageError;
+use crate::protocol::{Command, CommandKind, ResponseWriter};
+use crate::storage::StoredValue;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// A batch is committed once its values add up to this many bytes
+const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// `set` commands queued for one write
+pub(super) struct SetBatch {
+    items: Vec<(Vec<u8>, StoredValue)>,
+    noreply: Vec<bool>,
+    bytes: usize,
+    max_items: usize,
+}
+
+impl SetBatch {
+    /// A batch of up to `max_items` sets; with 0 or 1 nothing is queued
+    pub(super) fn new(max_items: usize) -> Self {
+        Self {
+            items: Vec::new(),
+            noreply: Vec::new(),
+            bytes: 0,
+            max_items,
+        }
+    }
+
+    /// Queue `cmd` if it is a plain `set`; any other command is handed back,
+    /// as are sets while `set` is disabled or the server is read-only
+    pub(super) fn push<'a>(&mut self, server: &Server, cmd: Command<'a>) -> Option<Command<'a>> {
+        if self.max_items <= 1
+            || server.denied_commands().contains(CommandKind::Set)
+            || server.is_read_only()
+        {
+            return Some(cmd);
+        }
+        let Command::Set {
+            key,
+            flags,
+            exptime,
+            data,
+            noreply,
+        } = cmd
+        else {
+            return Some(cmd);
+        };
+        server.metrics.cmd_set.inc();
+        server.metrics.observe_store(&key, data.len());
+        self.bytes += key.len() + data.len();
+        self.items.push((
+            key.into_owned(),
+            StoredValue::new(flags, server.exptime(exptime), data.into_owned()),
+        ));
+        self.noreply.push(noreply);
+        None
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Whether the batch should be committed before queueing more
+    pub(super) fn is_full(&self) -> bool {
+        self.items.len() >= self.max_items || self.bytes >= MAX_BATCH_BYTES
+    }
+
+    /// Write the queued sets and add their replies to `response`
+    ///
+    /// Each set gets `STORED` unless it was noreply, or the `SERVER_ERROR`
+    /// it failed with (errors are always sent, even for noreply). Sets are
+    /// written per shard, so a failed shard write fails only its own sets.
+    pub(super) async fn commit(&mut self, server: &Arc<Server>, response: &mut ResponseWriter) {
+        if self.is_empty() {
+            return;
+        }
+        let mut items = std::mem::take(&mut self.items);
+        let noreply = std::mem::take(&mut self.noreply);
+        self.bytes = 0;
+
+        let slow = server.slow_log().map(|log| {
+            let (key, value) = &items[0];
+            (
+                log,
+                log.describe_parts("set", Some(key), Some(value.data.len()), false),
+            )
+        });
+        let started = Instant::now();
+        let result = if server.config.inline_storage {
+            server.storage.set_batch(&mut items)
+        } else {
+            let task_server = Arc::clone(server);
+            tokio::task::spawn_blocking(move || task_server.storage.set_batch(&mut items))
+                .await
+                .unwrap_or_else(|e| Err(StorageError::Internal(e.to_string())))
+        };
+        // Each set waited for the whole batch
+        let latency = server.metrics.cmd_latency.with_label_values(&["set"]);
+        let elapsed = started.elapsed();
+        for _ in 0..noreply.len() {
+            latency.observe(elapsed.as_secs_f64());
+        }
+        if let Some((log, first)) = slow {
+            log.record_batch(&server.metrics, &first, noreply.len(), elapsed);
+        }
+
+        match result {
+            Ok(results) => {
+                for (result, noreply) in results.into_iter().zip(noreply) {
+                    match result {
+                        Ok(()) if noreply => {}
+                        Ok(()) => response.stored(),
+                        Err(e) => {
+                            server.metrics.storage_errors.inc();
+                            response.server_error(&e.to_string());
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                server.metrics.storage_errors.inc();
+                let message = e.to_string();
+                for _ in 0..noreply.len() {
+                    response.server_error(&message);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Handlers for the memcached binary protocol
+//!
+//! Requests map onto the same storage calls as the ASCII commands. Quiet
+//! opcodes (GetQ, SetQ, DeleteQ, ...) suppress their uninteresting replies:
+//! misses for gets, successes for mutations.
+
+use super::Server;
+use super::handler::general_stats;
+use crate::StorageError;
+use crate::protocol::binary::{BinaryRequest, BinaryResponse, opcode, status};
+use crate::protocol::{CommandKind, MAX_KEY_LENGTH, ResponseWriter};
+use crate::storage::{CasOutcome, StoredValue};
+use std::sync::Arc;
+
+/// Execute one binary request on the blocking pool, like
+/// `handler::execute_offloaded`; returns true if the connection should close
+///
+/// Runs inline instead with `inline_storage`, and for requests that don't
+/// wait on storage.
+pub async fn execute_offloaded(
+    server: &Arc<Server>,
+    req: &BinaryRequest<'_>,
+    response: &mut ResponseWriter,
+) -> bool {
+    let inline = matches!(
+        req.opcode,
+        opcode::NOOP | opcode::VERSION | opcode::STAT | opcode::QUIT | opcode::QUITQ
+    );
+    if inline || server.config.inline_storage {
+        return execute(server, req, response);
+    }
+
+    // The request borrows the read buffer, which can't cross threads
+    let (opcode, opaque, cas) = (req.opcode, req.opaque, req.cas);
+    let (extras, key, value) = (req.extras.to_vec(), req.key.to_vec(), req.value.to_vec());
+    let task_server = Arc::clone(server);
+    let mut task_reply =
+        ResponseWriter::new(256).with_zero_copy_min(server.config.zero_copy_min_value_size);
+    let result = tokio::task::spawn_blocking(move || {
+        let req = BinaryRequest {
+            opcode,
+            opaque,
+            cas,
+            extras: &extras,
+            key: &key,
+            value: &value,
+        };
+        let quit = execute(&task_server, &req, &mut task_reply);
+        (quit, task_reply)
+    })
+    .await;
+    match result {
+        Ok((quit, task_reply)) => {
+            response.append(task_reply);
+            quit
+        }
+        Err(e) => {
+            reply(
+                response,
+                req,
+                status::INTERNAL_ERROR,
+                e.to_string().as_bytes(),
+            );
+            false
+        }
+    }
+}
+
+/// Execute one binary request; returns true if the connection should close
+pub fn execute(
+    server: &Arc<Server>,
+    req: &BinaryRequest<'_>,
+    response: &mut ResponseWriter,
+) -> bool {
+    if let Some(kind) = command_kind(req.opcode)
+        && server.is_denied(kind, command_name(req.opcode))
+    {
+        reply(response, req, status::NOT_SUPPORTED, b"Command disabled");
+        return false;
+    }
+    if let Some(kind) = command_kind(req.opcode)
+        && server.rejects_write(kind, command_name(req.opcode))
+    {
+        reply(response, req, status::NOT_SUPPORTED, b"Server is read-only");
+        return false;
+    }
+    match req.opcode {
+        opcode::GET | opcode::GETQ | opcode::GETK | opcode::GETKQ => {
+            handle_get(server, req, response);
+        }
+        opcode::SET
+        | opcode::SETQ
+        | opcode::ADD
+        | opcode::ADDQ
+        | opcode::REPLACE
+        | opcode::REPLACEQ => {
+            handle_store(server, req, response);
+        }
+        opcode::DELETE | opcode::DELETEQ => handle_delete(server, req, response),
+        opcode::NOOP => reply(response, req, status::NO_ERROR, &[]),
+        opcode::VERSION => reply(
+            response,
+            req,
+            status::NO_ERROR,
+            env!("CARGO_PKG_VERSION").as_bytes(),
+        ),
+        opcode::STAT => handle_stat(server, req, response),
+        opcode::QUIT => {
+            reply(response, req, status::NO_ERROR, &[]);
+            return true;
+        }
+        opcode::QUITQ => return true,
+        _ => reply(response, req, status::UNKNOWN_COMMAND, b"Unknown command"),
+    }
+    false
+}
+
+/// Command name for an opcode, matching the ASCII names (metric label)
+pub fn command_name(op: u8) -> &'static str {
+    match op {
+        opcode::GET | opcode::GETQ => "get",
+        opcode::GETK | opcode::GETKQ => "getk",
+        opcode::SET | opcode::SETQ => "set",
+        opcode::ADD | opcode::ADDQ => "add",
+        opcode::REPLACE | opcode::REPLACEQ => "replace",
+        opcode::DELETE | opcode::DELETEQ => "delete",
+        opcode::NOOP => "noop",
+        opcode::VERSION => "version",
+        opcode::STAT => "stats",
+        opcode::QUIT | opcode::QUITQ => "quit",
+        _ => "unknown",
+    }
+}
+
+/// The ASCII command an opcode does the work of, for `disabled_commands`
+fn command_kind(op: u8) -> Option<CommandKind> {
+    match op {
+        opcode::GET | opcode::GETQ | opcode::GETK | opcode::GETKQ => Some(CommandKind::Get),
+        opcode::SET | opcode::SETQ => Some(CommandKind::Set),
+        opcode::ADD | opcode::ADDQ => Some(CommandKind::Add),
+        opcode::REPLACE | opcode::REPLACEQ => Some(CommandKind::Replace),
+        opcode::DELETE | opcode::DELETEQ => Some(CommandKind::Delete),
+        _ => None,
+    }
+}
+
+/// Write a response carrying only a status and an optional message body
+fn reply(response: &mut ResponseWriter, req: &BinaryRequest<'_>, status: u16, value: &[u8]) {
+    response.binary(&BinaryResponse {
+        opcode: req.opcode,
+        status,
+        opaque: req.opaque,
+        value,
+        ..BinaryResponse::default()
+    });
+}
+
+/// Reply VALUE_TOO_LARGE to the request `header` starts, refused before
+/// its body was read
+pub fn value_too_large(header: &[u8], response: &mut ResponseWriter) {
+    let req = BinaryRequest {
+        opcode: header.get(1).copied().unwrap_or(opcode::SET),
+        opaque: header
+            .get(12..16)
+            .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        cas: 0,
+        extras: &[],
+        key: &[],
+        value: &[],
+    };
+    reply(response, &req, status::VALUE_TOO_LARGE, b"Too large.");
+}
+
+/// Reply with INVALID_ARGUMENTS (bad extras, key or value for the opcode)
+fn invalid_arguments(response: &mut ResponseWriter, req: &BinaryRequest<'_>) {
+    reply(
+        response,
+        req,
+        status::INVALID_ARGUMENTS,
+        b"Invalid arguments",
+    );
+}
+
+/// Count a storage failure and reply with INTERNAL_ERROR (OUT_OF_MEMORY
+/// while the database or the key's namespace is full)
+fn internal_error(
+    server: &Arc<Server>,
+    response: &mut ResponseWriter,
+    req: &BinaryRequest<'_>,
+    e: &StorageError,
+) {
+    server.metrics.storage_errors.inc();
+    let code = if matches!(e, StorageError::Full | StorageError::QuotaExceeded) {
+        status::OUT_OF_MEMORY
+    } else {
+        status::INTERNAL_ERROR
+    };
+    reply(response, req, code, e.to_string().as_bytes());
+}
+
+/// Check the key is present, within the length limit and outside the
+/// reserved 0x00 namespace (binary keys are otherwise arbitrary bytes)
+fn valid_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key[0] != 0
+}
+
+/// Handle Get/GetQ/GetK/GetKQ
+fn handle_get(server: &Arc<Server>, req: &BinaryRequest<'_>, response: &mut ResponseWriter) {
+    let quiet = matches!(req.opcode, opcode::GETQ | opcode::GETKQ);
+    let with_key = matches!(req.opcode, opcode::GETK | opcode::GETKQ);
+
+    server.metrics.cmd_get.inc();
+    if !req.extras.is_empty() || !req.value.is_empty() || !valid_key(req.key) {
+        invalid_arguments(response, req);
+        return;
+    }
+
+    let key = if with_key { req.key } else { &[] };
+    match server.storage.get(req.key) {
+        Ok(Some(value)) => {
+            server.record_get(req.key, Some(&value));
+            response.binary(&BinaryResponse {
+                opcode: req.opcode,
+                status: status::NO_ERROR,
+                opaque: req.opaque,
+                cas: value.cas,
+                extras: &value.flags.to_be_bytes(),
+                key,
+                value: &value.data,
+            });
+        }
+        Ok(None) => {
+            server.record_get(req.key, None);
+            if !quiet {
+                response.binary(&BinaryResponse {
+                    opcode: req.opcode,
+                    status: status::KEY_NOT_FOUND,
+                    opaque: req.opaque,
+                    key,
+                    value: b"Not found",
+                    ..BinaryResponse::default()
+                });
+            }
+        }
+        Err(e) => internal_error(server, response, req, &e),
+    }
+}
+
+/// Handle Set/Add/Replace and their quiet variants
+///
+/// Extras are `<flags u32> <expiration u32>`; a non-zero request CAS turns
+/// Set into a compare-and-swap.
+fn handle_store(server: &Arc<Server>, req: &BinaryRequest<'_>, response: &mut ResponseWriter) {
+    let quiet = matches!(req.opcode, opcode::SETQ | opcode::ADDQ | opcode::REPLACEQ);
+
+    let (Ok(extras), true) = (<[u8; 8]>::try_from(req.extras), valid_key(req.key)) else {
+        invalid_arguments(response, req);
+        return;
+    };
+    if req.value.len() > server.config.max_item_size {
+        reply(response, req, status::VALUE_TOO_LARGE, b"Too large.");
+        return;
+    }
+    let flags = u32::from_be_bytes([extras[0], extras[1], extras[2], extras[3]]);
+    let exptime = u32::from_be_bytes([extras[4], extras[5], extras[6], extras[7]]);
+
+    let value = StoredValue::new(
+        flags,
+        server.exptime(i64::from(exptime)),
+        req.value.to_vec(),
+    );
+    let storage = &server.storage;
+    let metrics = &server.metrics;
+    metrics.observe_store(req.key, req.value.len());
+    let result = match req.opcode {
+        opcode::ADD | opcode::ADDQ => {
+            metrics.cmd_add.inc();
+            storage.add(req.key, value).map(|stored| {
+                if stored {
+                    CasOutcome::Stored
+                } else {
+                    CasOutcome::Exists
+                }
+            })
+        }
+        opcode::REPLACE | opcode::REPLACEQ => {
+            metrics.cmd_replace.inc();
+            storage.replace(req.key, value).map(|stored| {
+                if stored {
+                    CasOutcome::Stored
+                } else {
+                    CasOutcome::NotFound
+                }
+            })
+        }
+        _ if req.cas != 0 => {
+            metrics.cmd_cas.inc();
+            storage.cas(req.key, value, req.cas)
+        }
+        _ => {
+            metrics.cmd_set.inc();
+            storage.set(req.key, value).map(|()| CasOutcome::Stored)
+        }
+    };
+
+    match result {
+        Ok(CasOutcome::Stored) => {
+            if !quiet {
+                reply(response, req, status::NO_ERROR, &[]);
+            }
+        }
+        Ok(CasOutcome::Exists) => reply(response, req, status::KEY_EXISTS, b"Data exists for key."),
+        Ok(CasOutcome::NotFound) => reply(response, req, status::KEY_NOT_FOUND, b"Not found"),
+        Err(e) => internal_error(server, response, req, &e),
+    }
+}
+
+/// Handle Delete/DeleteQ
+fn handle_delete(server: &Arc<Server>, req: &BinaryRequest<'_>, response: &mut ResponseWriter) {
+    server.metrics.cmd_delete.inc();
+    if !req.extras.is_empty() || !req.value.is_empty() || !valid_key(req.key) {
+        invalid_arguments(response, req);
+        return;
+    }
+
+    match server.storage.delete(req.key) {
+        Ok(true) => {
+            if req.opcode != opcode::DELETEQ {
+                reply(response, req, status::NO_ERROR, &[]);
+            }
+        }
+        Ok(false) => reply(response, req, status::KEY_NOT_FOUND, b"Not found"),
+        Err(e) => internal_error(server, response, req, &e),
+    }
+}
+
+/// Handle Stat: one packet per statistic, terminated by an empty packet
+fn handle_stat(server: &Arc<Server>, req: &BinaryRequest<'_>, response: &mut ResponseWriter) {
+    match req.key {
+        b"" => {
+            for (name, value) in general_stats(server) {
+                response.binary(&BinaryResponse {
+                    opcode: req.opcode,
+                    status: status::NO_ERROR,
+                    opaque: req.opaque,
+                    key: name.as_bytes(),
+                    value: value.as_bytes(),
+                    ..BinaryResponse::default()
+                });
+            }
+        }
+        b"reset" => server.metrics.reset_stats(),
+        _ => {
+            reply(response, req, status::KEY_NOT_FOUND, b"Not found");
+            return;
+        }
+    }
+    reply(response, req, status::NO_ERROR, &[]);
+}
@@ -1,122 +1,1229 @@
 //! Connection handling for individual client connections
+//!
+//! The ASCII loop follows `protocol::MemcachedCodec` (and shares its resync
+//! helpers) but parses in place, so values are not copied out of the read
+//! buffer before they are stored. Replies to pipelined commands are queued
+//! and written together once the buffered input is used up, and runs of
+//! pipelined `set`s are stored with one write (see `batch`).
 
 use super::Server;
-use super::handler;
+use super::auth::{AuthCheck, AuthState};
+use super::batch::SetBatch;
+use super::conns::{ConnState, ConnStats};
+use super::{binary, handler};
+use crate::ProtocolError;
+use crate::audit::AuditEntry;
+use crate::config::ServerConfig;
+use crate::protocol::binary::{REQUEST_MAGIC, is_get, opcode, parse_request, status};
+use crate::protocol::codec::{Discard, start_pending_storage};
 use crate::protocol::{
-    Command, ParseResult, PendingStorageCommand, ResponseWriter, parse, parse_storage_command_line,
-    parse_storage_data,
+    Command, ParseLimits, ParseResult, PendingStorageCommand, ResponseWriter, parse_storage_data,
+    parse_with_limits,
 };
 use bytes::BytesMut;
+use prometheus::IntGauge;
+use std::io::IoSlice;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::OwnedSemaphorePermit;
 use tracing::debug;
 
+/// Queued replies are written once this much is buffered, even if more
+/// pipelined commands are waiting
+const FLUSH_HIGH_WATER: usize = 64 * 1024;
+
 /// Handle a single client connection
-pub async fn handle(
+#[allow(clippy::too_many_lines)]
+pub async fn handle<S>(
     server: Arc<Server>,
-    mut stream: TcpStream,
+    mut stream: S,
+    peer_addr: Option<SocketAddr>,
     _permit: OwnedSemaphorePermit,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let mut read_buf = BytesMut::with_capacity(server.config.read_buffer_size);
-    let mut response = ResponseWriter::new(server.config.write_buffer_size);
+    let mut response = ResponseWriter::new(server.config.write_buffer_size)
+        .with_zero_copy_min(server.config.zero_copy_min_value_size);
     let mut pending_storage: Option<PendingStorageCommand> = None;
+    // Framing is negotiated from the first byte the client sends
+    let mut binary_mode: Option<bool> = None;
+    // Input of a rejected command still to be skipped
+    let mut discard = Discard::None;
+    let limits = parse_limits(&server.config);
+    let mut buffers = BufferGauge::new(&server);
+    let mut set_batch = SetBatch::new(server.config.set_batch_size);
+    // Listed in `stats conns` until this function returns
+    let conn = server.connections.register(peer_addr);
+    // Protocol errors since the last complete command
+    let mut error_streak = 0u32;
+    let mut auth = AuthState::new(&server);
 
-    loop {
-        tokio::select! {
-            _ = server.cancel_token.cancelled() => {
+    'conn: while read_more(&server, &conn, &mut stream, &mut read_buf, true).await {
+        if *binary_mode.get_or_insert(read_buf[0] == REQUEST_MAGIC) {
+            // Authentication is only defined for the ASCII protocol
+            if !auth.is_authenticated() {
+                debug!("Closing unauthenticated binary protocol connection");
                 break;
             }
-            result = stream.read_buf(&mut read_buf) => {
-                match result {
-                    Ok(0) => {
-                        // Connection closed
-                        break;
-                    }
-                    Ok(n) => {
-                        server.metrics.bytes_read.inc_by(n as u64);
-
-                        // Process all complete commands in the buffer
-                        loop {
-                            let parse_result = if let Some(ref pending) = pending_storage {
-                                // We're waiting for data block
-                                parse_storage_data(&read_buf, pending)
-                            } else {
-                                // Parse new command
-                                parse(&read_buf)
-                            };
-
-                            match parse_result {
-                                ParseResult::Complete(cmd, consumed) => {
-                                    pending_storage = None;
-
-                                    let should_quit = matches!(cmd, Command::Quit);
-                                    let noreply = cmd.is_noreply();
-
-                                    // Execute command
-                                    handler::execute(&server, cmd, &mut response);
-
-                                    // Consume processed bytes
-                                    let _ = read_buf.split_to(consumed);
-
-                                    // Send response if not noreply
-                                    if !noreply && !response.is_empty() {
-                                        let buf = response.take();
-                                        server.metrics.bytes_written.inc_by(buf.len() as u64);
-                                        stream.write_all(&buf).await?;
-                                    }
-                                    response.clear();
-
-                                    if should_quit {
-                                        return Ok(());
-                                    }
-                                }
-                                ParseResult::NeedMoreData => {
-                                    // Check if this is a storage command waiting for data
-                                    if pending_storage.is_none()
-                                        && let Ok(Some(pending)) = parse_storage_command_line(&read_buf)
-                                    {
-                                        pending_storage = Some(pending);
-                                    }
-                                    break;
-                                }
-                                ParseResult::Error(e) => {
-                                    server.metrics.protocol_errors.inc();
-                                    response.client_error(&e.to_string());
-
-                                    // Try to recover by finding next command
-                                    if let Some(pos) = find_crlf(&read_buf) {
-                                        let _ = read_buf.split_to(pos + 2);
-                                    } else {
-                                        read_buf.clear();
-                                    }
-                                    pending_storage = None;
-
-                                    let buf = response.take();
-                                    server.metrics.bytes_written.inc_by(buf.len() as u64);
-                                    stream.write_all(&buf).await?;
-                                    response.clear();
-                                    break;
-                                }
-                            }
+            if process_binary(&server, &conn, &mut stream, &mut read_buf, &mut response).await? {
+                break;
+            }
+            buffers.tend(&server.config, &mut read_buf, &mut response);
+            continue;
+        }
+
+        // Process all complete commands in the buffer
+        loop {
+            if !discard.skip(&mut read_buf, limits.accept_bare_lf) {
+                break;
+            }
+
+            let parse_result = if let Some(ref mut pending) = pending_storage {
+                // We're waiting for data block
+                parse_storage_data(&read_buf, pending, &limits)
+            } else {
+                // Parse new command
+                parse_with_limits(&read_buf, &limits)
+            };
+
+            match parse_result {
+                ParseResult::Complete(cmd, consumed) => {
+                    pending_storage = None;
+                    error_streak = 0;
+                    conn.start_command();
+
+                    let cmd = match auth.check(&server, cmd, &mut response) {
+                        AuthCheck::Pass(cmd) => cmd,
+                        AuthCheck::Replied => {
+                            let _ = read_buf.split_to(consumed);
+                            continue;
+                        }
+                        AuthCheck::Close => {
+                            flush(&server, &conn, &mut stream, &mut response).await?;
+                            break 'conn;
                         }
+                    };
+                    let Some(cmd) = set_batch.push(&server, cmd) else {
+                        let _ = read_buf.split_to(consumed);
+                        if set_batch.is_full() {
+                            set_batch.commit(&server, &mut response).await;
+                        }
+                        continue;
+                    };
+                    // Queued sets reply (and are visible) before this command
+                    set_batch.commit(&server, &mut response).await;
+
+                    let should_quit = matches!(cmd, Command::Quit);
+                    let noreply = cmd.is_noreply();
+
+                    // Execute command (storage work runs off the worker threads)
+                    response.begin_reply();
+                    execute(&server, &conn, cmd, &mut response).await;
+                    // Errors are always sent, even for noreply
+                    if noreply && !response.has_error() {
+                        response.discard_reply();
+                    }
+
+                    // Consume processed bytes
+                    let _ = read_buf.split_to(consumed);
+
+                    if should_quit {
+                        flush(&server, &conn, &mut stream, &mut response).await?;
+                        return Ok(());
+                    }
+                    if response.len() >= FLUSH_HIGH_WATER {
+                        flush(&server, &conn, &mut stream, &mut response).await?;
+                    }
+                }
+                ParseResult::NeedMoreData => {
+                    // Check if this is a storage command waiting for data
+                    if pending_storage.is_none() {
+                        pending_storage = start_pending_storage(&mut read_buf, &limits);
                     }
-                    Err(e) => {
-                        debug!("Read error: {}", e);
+                    let Some(ref pending) = pending_storage else {
                         break;
+                    };
+                    // Replies so far go out before waiting on the client
+                    set_batch.commit(&server, &mut response).await;
+                    flush(&server, &conn, &mut stream, &mut response).await?;
+                    // Fill the data block with counted reads instead of
+                    // going back through the parser on every wakeup
+                    let len = pending.bytes + if limits.accept_bare_lf { 1 } else { 2 };
+                    if !read_data_block(&server, &conn, &mut stream, &mut read_buf, len).await {
+                        break 'conn;
                     }
                 }
+                ParseResult::Error(e) => {
+                    server.metrics.protocol_errors.inc();
+                    set_batch.commit(&server, &mut response).await;
+                    e.write_response(&mut response);
+                    pending_storage = None;
+                    discard = Discard::after(&e);
+                    // 0 never matches: the streak is at least 1 here
+                    error_streak = error_streak.saturating_add(1);
+                    if error_streak == server.config.max_protocol_errors {
+                        close_for_errors(&server, &conn, &mut stream, &mut response).await?;
+                        break 'conn;
+                    }
+                }
+            }
+        }
+
+        // Input exhausted: send this read's replies in one write
+        set_batch.commit(&server, &mut response).await;
+        flush(&server, &conn, &mut stream, &mut response).await?;
+        // Never while a data block is still being collected
+        if pending_storage.is_none() {
+            buffers.tend(&server.config, &mut read_buf, &mut response);
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer a client stuck sending malformed input with a last error and
+/// stop serving it (`max_protocol_errors`)
+async fn close_for_errors(
+    server: &Server,
+    conn: &ConnStats,
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: &mut ResponseWriter,
+) -> std::io::Result<()> {
+    server.metrics.connections_closed_for_errors.inc();
+    debug!(
+        "Closing connection after {} protocol errors in a row",
+        server.config.max_protocol_errors
+    );
+    response.client_error("too many errors, closing connection");
+    flush(server, conn, stream, response).await
+}
+
+/// Execute one ASCII or meta command, timing it for `cmd_latency` and the
+/// slow log, and recording it in the audit log if destructive
+async fn execute(
+    server: &Arc<Server>,
+    conn: &ConnStats,
+    cmd: Command<'_>,
+    response: &mut ResponseWriter,
+) {
+    if server.is_denied(cmd.kind(), cmd.name()) {
+        response.server_error("command disabled");
+        return;
+    }
+    let slow = server
+        .slow_log()
+        .map(|log| (log, log.describe(&cmd), Instant::now()));
+    // The command is consumed by running it
+    let audited = (server.audit.is_some() && cmd.kind().is_destructive())
+        .then(|| (cmd.name(), cmd.key().map(<[u8]>::to_vec)));
+
+    if let Some(kind) = handler::SampledStats::from_command(&cmd) {
+        handler::execute_sampled_stats(server, kind, response).await;
+    } else {
+        // Observed on drop; covers the storage work and the hop to the
+        // blocking pool
+        let _timer = server
+            .metrics
+            .cmd_latency
+            .with_label_values(&[cmd.name()])
+            .start_timer();
+        handler::execute_offloaded(server, cmd, response).await;
+    }
+    if let Some((log, cmd, started)) = slow {
+        log.record(
+            &server.metrics,
+            &cmd,
+            response.reply_values(),
+            started.elapsed(),
+        );
+    }
+    if let Some((command, key)) = audited {
+        let reply = response.reply();
+        let line = memchr::memmem::find(reply, b"\r\n").map_or(reply, |end| &reply[..end]);
+        audit(
+            server,
+            conn,
+            command,
+            key.as_deref(),
+            &String::from_utf8_lossy(line),
+        );
+    }
+}
+
+/// Record a destructive command and its reply in the audit log
+fn audit(server: &Server, conn: &ConnStats, command: &str, key: Option<&[u8]>, outcome: &str) {
+    if let Some(log) = &server.audit {
+        log.record(&AuditEntry {
+            peer: conn.peer(),
+            command,
+            key,
+            outcome,
+        });
+    }
+}
+
+/// Tracks a connection's buffer capacity in `Metrics::connection_buffer_bytes`
+///
+/// The connection's share is removed on drop, however the connection ends.
+struct BufferGauge {
+    gauge: IntGauge,
+    bytes: i64,
+}
+
+impl BufferGauge {
+    fn new(server: &Server) -> Self {
+        let mut this = Self {
+            gauge: server.metrics.connection_buffer_bytes.clone(),
+            bytes: 0,
+        };
+        this.set(server.config.read_buffer_size + server.config.write_buffer_size);
+        this
+    }
+
+    /// Shrink buffers a large request or reply left more than
+    /// `buffer_shrink_factor` times their configured size, if they are
+    /// empty, then report the current capacity
+    fn tend(
+        &mut self,
+        config: &ServerConfig,
+        read_buf: &mut BytesMut,
+        response: &mut ResponseWriter,
+    ) {
+        let factor = config.buffer_shrink_factor;
+        if factor > 0 {
+            let limit = config.read_buffer_size.saturating_mul(factor);
+            if read_buf.is_empty() && read_buf.capacity() > limit {
+                *read_buf = BytesMut::with_capacity(config.read_buffer_size);
+            }
+            let limit = config.write_buffer_size.saturating_mul(factor);
+            if response.is_empty() && response.capacity() > limit {
+                response.shrink_to(config.write_buffer_size);
             }
         }
+        self.set(read_buf.capacity() + response.capacity());
+    }
+
+    fn set(&mut self, bytes: usize) {
+        let bytes = i64::try_from(bytes).unwrap_or(i64::MAX);
+        self.gauge.add(bytes - self.bytes);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for BufferGauge {
+    fn drop(&mut self) {
+        self.gauge.sub(self.bytes);
+    }
+}
+
+/// Parser limits configured for this server
+fn parse_limits(config: &ServerConfig) -> ParseLimits {
+    ParseLimits {
+        max_item_size: config.max_item_size,
+        max_get_keys: config.max_get_keys,
+        max_command_line_bytes: config.max_command_line_bytes,
+        strict_ascii_keys: config.strict_ascii_keys,
+        accept_bare_lf: config.accept_bare_lf,
+    }
+}
+
+/// Read until `read_buf` holds at least `len` bytes, reading at least once
+///
+/// Returns false when the connection should be closed (see `read_more`).
+async fn read_data_block(
+    server: &Server,
+    conn: &ConnStats,
+    stream: &mut (impl AsyncRead + Unpin),
+    read_buf: &mut BytesMut,
+    len: usize,
+) -> bool {
+    // A command that is partly received is finished during shutdown drain
+    while read_more(server, conn, stream, read_buf, false).await {
+        if read_buf.len() >= len {
+            return true;
+        }
     }
+    false
+}
 
-    server.metrics.active_connections.dec();
+/// Read more input into `read_buf`
+///
+/// Returns false when the client hung up, the read failed, nothing arrived
+/// within `connection_timeout_secs`, or (with `stop_on_shutdown`) the server
+/// is shutting down. Every read uses the same idle clock, including reads of
+/// a data block (the only reads that don't stop on shutdown).
+async fn read_more(
+    server: &Server,
+    conn: &ConnStats,
+    stream: &mut (impl AsyncRead + Unpin),
+    read_buf: &mut BytesMut,
+    stop_on_shutdown: bool,
+) -> bool {
+    conn.set_state(if stop_on_shutdown {
+        ConnState::Waiting
+    } else {
+        ConnState::Nread
+    });
+    let timeout_secs = server.connection_timeout_secs();
+    let read = async {
+        if timeout_secs == 0 {
+            Ok(stream.read_buf(read_buf).await)
+        } else {
+            tokio::time::timeout(Duration::from_secs(timeout_secs), stream.read_buf(read_buf)).await
+        }
+    };
+
+    let result = if stop_on_shutdown {
+        tokio::select! {
+            _ = server.cancel_token.cancelled() => return false,
+            result = read => result,
+        }
+    } else {
+        read.await
+    };
+    match result {
+        // Connection closed
+        Ok(Ok(0)) => false,
+        Ok(Ok(n)) => {
+            server.metrics.bytes_read.inc_by(n as u64);
+            conn.add_bytes_read(n as u64);
+            true
+        }
+        Ok(Err(e)) => {
+            debug!("Read error: {}", e);
+            false
+        }
+        Err(_) => {
+            debug!("Closing connection idle for {}s", timeout_secs);
+            server.metrics.idle_timeouts.inc();
+            false
+        }
+    }
+}
+
+/// Write the queued replies, counting their bytes and timing the socket write
+///
+/// The write time is kept apart from `cmd_latency` so slow clients don't
+/// show up as slow storage.
+async fn flush(
+    server: &Server,
+    conn: &ConnStats,
+    stream: &mut (impl AsyncWrite + Unpin),
+    response: &mut ResponseWriter,
+) -> std::io::Result<()> {
+    if response.is_empty() {
+        return Ok(());
+    }
+    conn.set_state(ConnState::Write);
+    server.metrics.bytes_written.inc_by(response.len() as u64);
+    conn.add_bytes_written(response.len() as u64);
+    let _timer = server.metrics.response_write_latency.start_timer();
+    let result = if response.is_segmented() {
+        write_all_vectored(stream, &mut response.io_slices()).await
+    } else {
+        stream.write_all(response.buffer()).await
+    };
+    response.clear();
+    result
+}
+
+/// `write_all` for a list of buffers, with as few `writev` calls as the
+/// socket allows
+async fn write_all_vectored(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut slices: &mut [IoSlice<'_>],
+) -> std::io::Result<()> {
+    while !slices.is_empty() {
+        let n = stream.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
     Ok(())
 }
 
-/// Find \r\n in buffer using SIMD-accelerated search
-#[inline]
-fn find_crlf(buf: &[u8]) -> Option<usize> {
-    memchr::memchr(b'\r', buf).filter(|&i| buf.get(i + 1) == Some(&b'\n'))
+/// Process all complete binary protocol packets in the buffer, writing
+/// their replies together
+///
+/// Returns true when the client asked to quit.
+async fn process_binary(
+    server: &Arc<Server>,
+    conn: &ConnStats,
+    stream: &mut (impl AsyncWrite + Unpin),
+    read_buf: &mut BytesMut,
+    response: &mut ResponseWriter,
+) -> anyhow::Result<bool> {
+    loop {
+        let (quit, consumed) = match parse_request(read_buf, server.config.max_item_size) {
+            Ok(Some((req, consumed))) => {
+                conn.start_command();
+                let name = binary::command_name(req.opcode);
+                let _timer = server
+                    .metrics
+                    .cmd_latency
+                    .with_label_values(&[name])
+                    .start_timer();
+                let started = Instant::now();
+                response.begin_reply();
+                let quit = binary::execute_offloaded(server, &req, response).await;
+                if let Some(log) = server.slow_log() {
+                    let data_len = (!req.value.is_empty()).then_some(req.value.len());
+                    let key = (!req.key.is_empty()).then_some(req.key);
+                    let cmd = log.describe_parts(name, key, data_len, is_get(req.opcode));
+                    log.record(
+                        &server.metrics,
+                        &cmd,
+                        response.reply_values(),
+                        started.elapsed(),
+                    );
+                }
+                if server.audit.is_some() && matches!(req.opcode, opcode::DELETE | opcode::DELETEQ)
+                {
+                    // Quiet deletes reply nothing on success
+                    let status = response
+                        .reply()
+                        .get(6..8)
+                        .map_or(status::NO_ERROR, |s| u16::from_be_bytes([s[0], s[1]]));
+                    audit(
+                        server,
+                        conn,
+                        name,
+                        Some(req.key),
+                        &format!("status {status:#06x}"),
+                    );
+                }
+                (quit, consumed)
+            }
+            Ok(None) => {
+                flush(server, conn, stream, response).await?;
+                return Ok(false);
+            }
+            Err(e) => {
+                // Binary framing cannot resynchronize; drop the connection
+                server.metrics.protocol_errors.inc();
+                if let ProtocolError::ValueTooLarge { .. } = e {
+                    binary::value_too_large(read_buf, response);
+                }
+                flush(server, conn, stream, response).await?;
+                return Err(e.into());
+            }
+        };
+        let _ = read_buf.split_to(consumed);
+
+        if quit {
+            flush(server, conn, stream, response).await?;
+            return Ok(true);
+        }
+        if response.len() >= FLUSH_HIGH_WATER {
+            flush(server, conn, stream, response).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditLog;
+    use crate::client::Client;
+    use crate::config::{AuditConfig, AuthConfig, StorageConfig};
+    use crate::protocol::CommandSet;
+    use crate::storage::{StoredValue, current_timestamp};
+    use crate::testing::{test_server, test_server_with};
+    use std::sync::Arc;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+    use tempfile::TempDir;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::Semaphore;
+
+    /// Serve a single connection on an ephemeral port with a custom server
+    /// configuration and return the client side
+    async fn connect_with(tmp_dir: &TempDir, config: ServerConfig) -> TcpStream {
+        serve(tmp_dir, config).await.0
+    }
+
+    /// Like `connect_with`, also returning the server for metric checks
+    async fn serve(tmp_dir: &TempDir, config: ServerConfig) -> (TcpStream, Arc<Server>) {
+        serve_storage(tmp_dir, config, StorageConfig::default()).await
+    }
+
+    /// Like `serve`, with a custom storage configuration (`db_path` is
+    /// replaced)
+    async fn serve_storage(
+        tmp_dir: &TempDir,
+        config: ServerConfig,
+        storage: StorageConfig,
+    ) -> (TcpStream, Arc<Server>) {
+        let server = Arc::new(test_server_with(tmp_dir, config, storage));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handler_server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+            handle(handler_server, stream, Some(peer), permit)
+                .await
+                .unwrap();
+        });
+        (TcpStream::connect(addr).await.unwrap(), server)
+    }
+
+    /// Read until the reply ends with `terminator`
+    async fn read_until(stream: &mut TcpStream, terminator: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        while !buf.ends_with(terminator) {
+            let read = tokio::time::timeout(Duration::from_secs(5), stream.read_buf(&mut buf))
+                .await
+                .unwrap_or_else(|_| panic!("no {terminator:?} in {buf:?}"));
+            assert_ne!(read.unwrap(), 0);
+        }
+        buf
+    }
+
+    #[tokio::test]
+    async fn test_handle_any_stream() {
+        let tmp_dir = TempDir::new().unwrap();
+        let server = test_server(&tmp_dir, ServerConfig::default());
+
+        // Not a socket at all: an in-memory pipe
+        let (mut client, server_side) = tokio::io::duplex(1024);
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        tokio::spawn(handle(server, server_side, None, permit));
+
+        client
+            .write_all(b"set foo 0 0 3\r\nbar\r\nget foo\r\nquit\r\n")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_disabled_commands() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            disabled_commands: CommandSet::try_from(vec![
+                "set".to_string(),
+                "flush_all".to_string(),
+            ])
+            .unwrap(),
+            ..ServerConfig::default()
+        };
+        let (mut client, server) = serve(&tmp_dir, config).await;
+        server
+            .storage
+            .set(b"foo", StoredValue::new(0, 0, b"bar".to_vec()))
+            .unwrap();
+
+        // Errors are sent even for noreply; sets aren't batched past the check
+        client
+            .write_all(b"set foo 0 0 3 noreply\r\nbaz\r\nflush_all\r\nget foo\r\n")
+            .await
+            .unwrap();
+        let reply = read_until(&mut client, b"END\r\n").await;
+        assert_eq!(
+            reply,
+            b"SERVER_ERROR command disabled\r\nSERVER_ERROR command disabled\r\n\
+              VALUE foo 0 3\r\nbar\r\nEND\r\n"
+        );
+
+        // Health checks keep working
+        client.write_all(b"version\r\n").await.unwrap();
+        read_until(&mut client, b"\r\n").await;
+        client.write_all(b"stats settings\r\n").await.unwrap();
+        let reply = String::from_utf8(read_until(&mut client, b"END\r\n").await).unwrap();
+        assert!(
+            reply.contains("STAT disabled_commands set,flush_all\r\n"),
+            "{reply}"
+        );
+
+        let denied = |name: &str| {
+            server
+                .metrics
+                .commands_denied
+                .with_label_values(&[name])
+                .get()
+        };
+        assert_eq!(denied("set"), 1);
+        assert_eq!(denied("flush_all"), 1);
+        assert_eq!(denied("get"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_read_only() {
+        let tmp_dir = TempDir::new().unwrap();
+        let (mut client, server) = serve(&tmp_dir, ServerConfig::default()).await;
+        client.write_all(b"set foo 0 0 3\r\nbar\r\n").await.unwrap();
+        read_until(&mut client, b"STORED\r\n").await;
+
+        // Flipped while the connection is open, as POST /admin/readonly does
+        server.read_only.store(true, Ordering::SeqCst);
+        client
+            .write_all(b"set foo 0 0 3 noreply\r\nbaz\r\ndelete foo\r\nincr foo 1\r\nget foo\r\n")
+            .await
+            .unwrap();
+        let reply = String::from_utf8(read_until(&mut client, b"END\r\n").await).unwrap();
+        assert_eq!(
+            reply,
+            "SERVER_ERROR server is read-only\r\n".repeat(3) + "VALUE foo 0 3\r\nbar\r\nEND\r\n"
+        );
+        client.write_all(b"stats settings\r\n").await.unwrap();
+        let reply = String::from_utf8(read_until(&mut client, b"END\r\n").await).unwrap();
+        assert!(reply.contains("STAT read_only yes\r\n"), "{reply}");
+
+        server.read_only.store(false, Ordering::SeqCst);
+        client.write_all(b"delete foo\r\n").await.unwrap();
+        read_until(&mut client, b"DELETED\r\n").await;
+        assert_eq!(
+            server
+                .metrics
+                .commands_denied
+                .with_label_values(&["set"])
+                .get(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let tmp_dir = TempDir::new().unwrap();
+        let server = test_server_with(&tmp_dir, ServerConfig::default(), StorageConfig::default());
+        let metrics = Arc::clone(&server.metrics);
+        let path = tmp_dir.path().join("audit.jsonl");
+        let audit = AuditLog::open(
+            &AuditConfig {
+                path: Some(path.clone()),
+                ..AuditConfig::default()
+            },
+            Arc::clone(&metrics),
+        )
+        .unwrap()
+        .unwrap();
+        let server = Arc::new(server.with_audit_log(Arc::new(audit)));
+
+        let (mut client, server_side) = tokio::io::duplex(64 * 1024);
+        client
+            .write_all(
+                b"set foo 0 0 3\r\nbar\r\nget foo\r\ndelete foo\r\n\
+                  delete foo noreply\r\nflush_all\r\nquit\r\n",
+            )
+            .await
+            .unwrap();
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        handle(Arc::clone(&server), server_side, None, permit)
+            .await
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while metrics.audit_entries.get() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<&str> = log.lines().collect();
+        assert_eq!(entries.len(), 3, "{log}");
+        assert!(
+            entries[0]
+                .contains(r#""peer":"unix","command":"delete","key":"foo","outcome":"DELETED""#)
+        );
+        // noreply commands are recorded with the reply they didn't get
+        assert!(entries[1].contains(r#""command":"delete","key":"foo","outcome":"NOT_FOUND""#));
+        assert!(entries[2].contains(r#""command":"flush_all","key":null,"outcome":"OK""#));
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let config = ServerConfig {
+            auth: Some(AuthConfig {
+                username: "app".to_string(),
+                password: "secret".to_string(),
+            }),
+            ..ServerConfig::default()
+        };
+        let tmp_dir = TempDir::new().unwrap();
+        let (mut client, server) = serve(&tmp_dir, config.clone()).await;
+
+        client
+            .write_all(b"get foo\r\nversion\r\nset auth 0 0 9\r\napp wrong\r\n")
+            .await
+            .unwrap();
+        let reply = String::from_utf8(read_until(&mut client, b"failure\r\n").await).unwrap();
+        assert!(reply.starts_with("CLIENT_ERROR unauthenticated\r\nVERSION "));
+        assert!(reply.ends_with("\r\nCLIENT_ERROR authentication failure\r\n"));
+
+        // The authenticating set isn't stored
+        client
+            .write_all(b"set auth 0 0 10\r\napp secret\r\nget auth\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_until(&mut client, b"END\r\n").await,
+            b"STORED\r\nEND\r\n"
+        );
+        assert_eq!(server.metrics.auth_successes.get(), 1);
+        assert_eq!(server.metrics.auth_failures.get(), 1);
+
+        // Three failures close the connection
+        let tmp_dir = TempDir::new().unwrap();
+        let (mut client, server) = serve(&tmp_dir, config).await;
+        for _ in 0..3 {
+            client.write_all(b"set a 0 0 3\r\na b\r\n").await.unwrap();
+        }
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, b"CLIENT_ERROR authentication failure\r\n".repeat(3));
+        assert_eq!(server.metrics.auth_failures.get(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stats_conns() {
+        let tmp_dir = TempDir::new().unwrap();
+        let (stream, server) = serve(&tmp_dir, ServerConfig::default()).await;
+        let local = stream.local_addr().unwrap();
+        let mut client = Client::new(stream);
+
+        assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+        let stats = client.stats(Some("conns")).await.unwrap();
+
+        let conns = server.connections.snapshot();
+        assert_eq!(conns.len(), 1);
+        let id = conns[0].id;
+        let stat = |name: &str| {
+            let name = format!("{id}:{name}");
+            let found = stats.iter().find(|(stat, _)| *stat == name);
+            found.map(|(_, value)| value.as_str())
+        };
+        assert_eq!(
+            stat("addr"),
+            Some(format!("tcp:{local}").as_str()),
+            "{stats:?}"
+        );
+        // The connection asking is executing its command
+        assert_eq!(stat("state"), Some("conn_parse_cmd"));
+        assert_eq!(stat("cmds"), Some("2"));
+        assert_eq!(stat("bytes_read"), Some("33"));
+        assert_eq!(stat("bytes_written"), Some("8"));
+        assert_eq!(stat("secs_since_last_cmd"), Some("0"));
+
+        // Removed once the connection ends
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !server.connections.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_after_protocol_errors() {
+        let tmp_dir = TempDir::new().unwrap();
+        let server = test_server(
+            &tmp_dir,
+            ServerConfig {
+                max_protocol_errors: 3,
+                ..ServerConfig::default()
+            },
+        );
+        let semaphore = Arc::new(Semaphore::new(2));
+        let connect = || {
+            let (client, server_side) = tokio::io::duplex(64 * 1024);
+            let permit = Arc::clone(&semaphore).try_acquire_owned().unwrap();
+            let task = tokio::spawn(handle(Arc::clone(&server), server_side, None, permit));
+            (client, task)
+        };
+        let (mut bad, bad_task) = connect();
+        let (mut good, good_task) = connect();
+
+        // A complete command resets the streak; the bad connection reads a
+        // key the good one never writes, so its miss doesn't race the set
+        bad.write_all(b"bogus\r\nbogus\r\nget other\r\nbogus\r\nbogus\r\n")
+            .await
+            .unwrap();
+        good.write_all(b"set foo 0 0 3\r\nbar\r\n").await.unwrap();
+        bad.write_all(b"bogus\r\nget other\r\n").await.unwrap();
+
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), bad.read_to_end(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        let reply = String::from_utf8(reply).unwrap();
+        assert_eq!(
+            reply,
+            "ERROR\r\nERROR\r\nEND\r\nERROR\r\nERROR\r\nERROR\r\n\
+             CLIENT_ERROR too many errors, closing connection\r\n"
+        );
+        bad_task.await.unwrap().unwrap();
+        assert_eq!(server.metrics.connections_closed_for_errors.get(), 1);
+
+        // The other connection is still served
+        good.write_all(b"get foo\r\nquit\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        good.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\n");
+        good_task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_replies_are_batched() {
+        let tmp_dir = TempDir::new().unwrap();
+        let server = test_server(&tmp_dir, ServerConfig::default());
+
+        // The whole pipeline is buffered before the connection reads it
+        let (mut client, server_side) = tokio::io::duplex(64 * 1024);
+        let mut request = b"set foo 0 0 3 noreply\r\nbar\r\nbogus\r\n".to_vec();
+        for _ in 0..50 {
+            request.extend_from_slice(b"get foo\r\n");
+        }
+        request.extend_from_slice(b"quit\r\n");
+        client.write_all(&request).await.unwrap();
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        handle(Arc::clone(&server), server_side, None, permit)
+            .await
+            .unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        let mut expected = b"ERROR\r\n".to_vec();
+        for _ in 0..50 {
+            expected.extend_from_slice(b"VALUE foo 0 3\r\nbar\r\nEND\r\n");
+        }
+        assert_eq!(reply, expected);
+        assert_eq!(server.metrics.response_write_latency.get_sample_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_sets_batched_in_order() {
+        // Sets split across batches (size 3), a get and a parse error in
+        // between, and a set left queued when the input runs out
+        let mut request = Vec::new();
+        for i in 0..5 {
+            request.extend_from_slice(format!("set k{i} {i} 0 2 noreply\r\nv{i}\r\n").as_bytes());
+        }
+        request
+            .extend_from_slice(b"set k5 5 0 2\r\nv5\r\nget k4\r\nbogus\r\nset k4 0 0 1\r\nx\r\n");
+
+        for set_batch_size in [0, 3] {
+            let tmp_dir = TempDir::new().unwrap();
+            let config = ServerConfig {
+                set_batch_size,
+                ..ServerConfig::default()
+            };
+            let (mut client, server) = serve(&tmp_dir, config).await;
+            client.write_all(&request).await.unwrap();
+            let reply = read_until(&mut client, b"ERROR\r\nSTORED\r\n").await;
+            assert_eq!(
+                reply, b"STORED\r\nVALUE k4 4 2\r\nv4\r\nEND\r\nERROR\r\nSTORED\r\n",
+                "set_batch_size {set_batch_size}"
+            );
+
+            assert_eq!(server.metrics.cmd_set.get(), 7);
+            let value = server.storage.get(b"k2").unwrap().unwrap();
+            assert_eq!((value.flags, &value.data[..]), (2, &b"v2"[..]));
+            let value = server.storage.get(b"k4").unwrap().unwrap();
+            assert_eq!(&value.data[..], b"x");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inline_and_offloaded_storage_agree() {
+        let request = b"set foo 0 0 3\r\nbar\r\nappend foo 0 0 1 noreply\r\nz\r\n\
+            get foo missing\r\nincr foo 1\r\ndelete foo\r\nmg foo v\r\nquit\r\n";
+        let mut replies = Vec::new();
+        for inline_storage in [false, true] {
+            let tmp_dir = TempDir::new().unwrap();
+            let config = ServerConfig {
+                inline_storage,
+                ..ServerConfig::default()
+            };
+            let mut stream = connect_with(&tmp_dir, config).await;
+            stream.write_all(request).await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            replies.push(reply);
+        }
+        assert_eq!(
+            replies[0],
+            b"STORED\r\nVALUE foo 0 4\r\nbarz\r\nEND\r\n\
+            CLIENT_ERROR cannot increment or decrement non-numeric value\r\nDELETED\r\nEN\r\n"
+        );
+        assert_eq!(replies[0], replies[1]);
+    }
+
+    #[tokio::test]
+    async fn test_binary_inline_and_offloaded_storage_agree() {
+        let packet = |op: u8, extras: &[u8], key: &[u8], value: &[u8]| {
+            let mut buf = vec![REQUEST_MAGIC, op, 0, u8::try_from(key.len()).unwrap()];
+            buf.push(u8::try_from(extras.len()).unwrap());
+            buf.extend_from_slice(&[0; 3]);
+            let body_len = extras.len() + key.len() + value.len();
+            buf.extend_from_slice(&u32::try_from(body_len).unwrap().to_be_bytes());
+            buf.extend_from_slice(&[0; 12]);
+            buf.extend_from_slice(extras);
+            buf.extend_from_slice(key);
+            buf.extend_from_slice(value);
+            buf
+        };
+        let request = [
+            packet(opcode::SET, &[0, 0, 0, 5, 0, 0, 0, 0], b"foo", b"bar"),
+            packet(opcode::GET, &[], b"foo", &[]),
+            packet(opcode::GETQ, &[], b"missing", &[]),
+            packet(opcode::DELETE, &[], b"foo", &[]),
+            packet(opcode::GET, &[], b"foo", &[]),
+            packet(opcode::QUIT, &[], &[], &[]),
+        ]
+        .concat();
+        let mut replies = Vec::new();
+        for inline_storage in [false, true] {
+            let tmp_dir = TempDir::new().unwrap();
+            let config = ServerConfig {
+                inline_storage,
+                ..ServerConfig::default()
+            };
+            let mut stream = connect_with(&tmp_dir, config).await;
+            stream.write_all(&request).await.unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).await.unwrap();
+            // Opcode, status and body of each packet; CAS tokens differ
+            let mut packets = Vec::new();
+            let mut rest = &reply[..];
+            while !rest.is_empty() {
+                let body_len = u32::from_be_bytes(rest[8..12].try_into().unwrap()) as usize;
+                let (head, tail) = rest.split_at(24 + body_len);
+                packets.push((head[1], head[6..8].to_vec(), head[24..].to_vec()));
+                rest = tail;
+            }
+            replies.push(packets);
+        }
+        let statuses: Vec<_> = replies[0].iter().map(|(op, st, _)| (*op, st[1])).collect();
+        assert_eq!(
+            statuses,
+            [
+                (opcode::SET, 0),
+                (opcode::GET, 0),
+                (opcode::DELETE, 0),
+                (opcode::GET, 1),
+                (opcode::QUIT, 0)
+            ]
+        );
+        assert_eq!(replies[0][1].2, [&[0, 0, 0, 5][..], b"bar"].concat());
+        assert_eq!(replies[0], replies[1]);
+    }
+
+    #[tokio::test]
+    async fn test_zero_copy_values_over_small_pipe() {
+        let tmp_dir = TempDir::new().unwrap();
+        let server = test_server(
+            &tmp_dir,
+            ServerConfig {
+                zero_copy_min_value_size: 1024,
+                ..ServerConfig::default()
+            },
+        );
+        let large = vec![b'x'; 100_000];
+        let storage = &server.storage;
+        storage
+            .set(b"large", StoredValue::new(3, 0, large.clone()))
+            .unwrap();
+        storage
+            .set(b"small", StoredValue::new(0, 0, b"abc".to_vec()))
+            .unwrap();
+
+        // A pipe much smaller than the reply forces partial vectored writes
+        let (mut client, server_side) = tokio::io::duplex(512);
+        let permit = Arc::new(Semaphore::new(1)).try_acquire_owned().unwrap();
+        tokio::spawn(handle(server, server_side, None, permit));
+        client
+            .write_all(b"get small large small\r\nquit\r\n")
+            .await
+            .unwrap();
+
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        let mut expected = b"VALUE small 0 3\r\nabc\r\nVALUE large 3 100000\r\n".to_vec();
+        expected.extend_from_slice(&large);
+        expected.extend_from_slice(b"\r\nVALUE small 0 3\r\nabc\r\nEND\r\n");
+        assert_eq!(reply, expected);
+    }
+
+    #[tokio::test]
+    async fn test_large_value_in_chunks() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            max_item_size: 8 * 1024 * 1024,
+            ..ServerConfig::default()
+        };
+        let mut stream = connect_with(&tmp_dir, config).await;
+
+        let value = vec![b'x'; 4 * 1024 * 1024];
+        stream
+            .write_all(format!("set big 0 0 {}\r\n", value.len()).as_bytes())
+            .await
+            .unwrap();
+        for chunk in value.chunks(1024) {
+            stream.write_all(chunk).await.unwrap();
+        }
+        stream.write_all(b"\r\nget big\r\n").await.unwrap();
+
+        let reply = read_until(&mut stream, b"END\r\n").await;
+        let header = format!("STORED\r\nVALUE big 0 {}\r\n", value.len());
+        assert!(reply.starts_with(header.as_bytes()));
+        assert_eq!(
+            reply.len(),
+            header.len() + value.len() + b"\r\nEND\r\n".len()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffers_shrink_after_large_value() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            max_item_size: 8 * 1024 * 1024,
+            zero_copy_min_value_size: 0,
+            ..ServerConfig::default()
+        };
+        let baseline = i64::try_from(config.read_buffer_size + config.write_buffer_size).unwrap();
+        let (stream, server) = serve(&tmp_dir, config).await;
+        let mut client = Client::new(stream);
+        let buffered = || server.metrics.connection_buffer_bytes.get();
+
+        let value = vec![b'x'; 1024 * 1024];
+        assert!(client.set(b"big", 0, 0, &value).await.unwrap());
+        assert_eq!(client.get(b"big").await.unwrap().unwrap().data, value);
+
+        // Buffers are tended before the next read, so after this reply both
+        // are back near their configured size
+        client.version().await.unwrap();
+        assert!(buffered() <= 2 * baseline, "{} bytes buffered", buffered());
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while buffered() != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("buffer gauge not released");
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_closed() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            connection_timeout_secs: 1,
+            ..ServerConfig::default()
+        };
+        let (mut stream, server) = serve(&tmp_dir, config).await;
+
+        stream.write_all(b"version\r\n").await.unwrap();
+        read_until(&mut stream, b"\r\n").await;
+
+        // A stalled data block is idle too
+        stream.write_all(b"set foo 0 0 10\r\nabc").await.unwrap();
+        let mut buf = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .expect("connection was not closed");
+        assert_eq!(read.unwrap(), 0);
+        assert_eq!(server.metrics.idle_timeouts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finishes_current_command() {
+        let tmp_dir = TempDir::new().unwrap();
+        let (mut stream, server) = serve(&tmp_dir, ServerConfig::default()).await;
+
+        stream.write_all(b"set foo 0 0 6\r\nbar").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.cancel_token.cancel();
+
+        // The data block still completes and is answered, then the
+        // connection closes instead of waiting for another command
+        stream.write_all(b"baz\r\n").await.unwrap();
+        let mut buf = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut buf))
+            .await
+            .expect("connection was not closed")
+            .unwrap();
+        assert_eq!(buf, b"STORED\r\n");
+        assert_eq!(server.storage.get(b"foo").unwrap().unwrap().data, b"barbaz");
+    }
+
+    #[tokio::test]
+    async fn test_default_and_max_ttl() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = StorageConfig {
+            default_ttl_secs: 60,
+            max_ttl_secs: 3600,
+            ..StorageConfig::default()
+        };
+        let (mut stream, server) = serve_storage(&tmp_dir, ServerConfig::default(), storage).await;
+
+        stream
+            .write_all(
+                b"set foo 0 0 3\r\nbar\r\nset keep 0 1800 3\r\nbar\r\n\
+                  set long 0 7200 3\r\nbar\r\nms meta 3 T0\r\nbar\r\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            read_until(&mut stream, b"HD\r\n").await,
+            b"STORED\r\nSTORED\r\nSTORED\r\nHD\r\n"
+        );
+        let ttl = |key: &[u8]| {
+            let value = server.storage.get(key).unwrap().unwrap();
+            value.expire_at.saturating_sub(current_timestamp())
+        };
+        // exptime 0 gets the default, long TTLs the cap, the rest is kept
+        assert!((59..=60).contains(&ttl(b"foo")));
+        assert!((59..=60).contains(&ttl(b"meta")));
+        assert!((1799..=1800).contains(&ttl(b"keep")));
+        assert!((3599..=3600).contains(&ttl(b"long")));
+        drop(stream);
+
+        // Items stored with exptime 0 do expire
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = StorageConfig {
+            default_ttl_secs: 1,
+            ..StorageConfig::default()
+        };
+        let (stream, _) = serve_storage(&tmp_dir, ServerConfig::default(), storage).await;
+        let mut client = Client::new(stream);
+        assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(client.get(b"foo").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_accept_bare_lf() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = ServerConfig {
+            accept_bare_lf: true,
+            ..ServerConfig::default()
+        };
+        let mut stream = connect_with(&tmp_dir, config).await;
+
+        stream
+            .write_all(b"set foo 0 0 3\nbar\nget foo\r\ndelete foo\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_until(&mut stream, b"DELETED\r\n").await,
+            b"STORED\r\nVALUE foo 0 3\r\nbar\r\nEND\r\nDELETED\r\n"
+        );
+    }
 }
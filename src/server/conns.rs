@@ -0,0 +1,235 @@
+//! Per-connection state for `stats conns` and `GET /debug/connections`
+//!
+//! Every connection updates its own `ConnStats` with relaxed atomics as it
+//! runs. The registry's lock is only taken when a connection opens or
+//! closes and when someone asks for a snapshot, so it is not touched per
+//! command.
+
+use crate::storage::current_timestamp;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+/// What a connection is doing, named like memcached's connection states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConnState {
+    /// Waiting for the next command
+    Waiting,
+    /// Reading the data block of a storage command
+    Nread,
+    /// Executing a command
+    ParseCmd,
+    /// Writing replies to the socket
+    Write,
+}
+
+impl ConnState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnState::Waiting => "conn_waiting",
+            ConnState::Nread => "conn_nread",
+            ConnState::ParseCmd => "conn_parse_cmd",
+            ConnState::Write => "conn_write",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ConnState::Nread,
+            2 => ConnState::ParseCmd,
+            3 => ConnState::Write,
+            _ => ConnState::Waiting,
+        }
+    }
+}
+
+/// One connection's counters, updated by the connection's own task
+#[derive(Debug)]
+pub struct ConnStats {
+    id: u64,
+    /// Client address (from the PROXY header if used); `None` on the Unix
+    /// socket
+    peer: Option<SocketAddr>,
+    /// Unix timestamp of the accept
+    connected_at: u64,
+    commands: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    /// Unix timestamp of the last command (0 = none yet)
+    last_command_at: AtomicU64,
+    state: AtomicU8,
+}
+
+impl ConnStats {
+    /// A command was parsed and is about to run
+    pub(super) fn start_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        self.last_command_at
+            .store(current_timestamp(), Ordering::Relaxed);
+        self.set_state(ConnState::ParseCmd);
+    }
+
+    /// Client address; `None` on the Unix socket
+    pub(super) fn peer(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+
+    pub(super) fn set_state(&self, state: ConnState) {
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_bytes_read(&self, n: u64) {
+        self.bytes_read.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(super) fn add_bytes_written(&self, n: u64) {
+        self.bytes_written.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ConnSnapshot {
+        let last_command_at = self.last_command_at.load(Ordering::Relaxed);
+        ConnSnapshot {
+            id: self.id,
+            peer: self.peer,
+            connected_at: self.connected_at,
+            commands: self.commands.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            last_command_at: (last_command_at > 0).then_some(last_command_at),
+            state: ConnState::from_u8(self.state.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A connection's state at the time of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnSnapshot {
+    pub id: u64,
+    pub peer: Option<SocketAddr>,
+    pub connected_at: u64,
+    pub commands: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub last_command_at: Option<u64>,
+    pub state: ConnState,
+}
+
+impl ConnSnapshot {
+    /// The address as memcached prints it (`tcp:1.2.3.4:5678`, `unix`)
+    pub fn addr(&self) -> String {
+        match self.peer {
+            Some(peer) => format!("tcp:{peer}"),
+            None => "unix".to_string(),
+        }
+    }
+}
+
+/// Open connections by id
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    conns: Mutex<BTreeMap<u64, Arc<ConnStats>>>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a connection; it is removed when the returned handle drops
+    pub(super) fn register(self: &Arc<Self>, peer: Option<SocketAddr>) -> ConnHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let stats = Arc::new(ConnStats {
+            id,
+            peer,
+            connected_at: current_timestamp(),
+            commands: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            bytes_written: AtomicU64::new(0),
+            last_command_at: AtomicU64::new(0),
+            state: AtomicU8::new(ConnState::Waiting as u8),
+        });
+        self.conns.lock().insert(id, Arc::clone(&stats));
+        ConnHandle {
+            registry: Arc::clone(self),
+            stats,
+        }
+    }
+
+    /// Every open connection, oldest first
+    pub fn snapshot(&self) -> Vec<ConnSnapshot> {
+        // Copy the handles out so the lock isn't held while reading them
+        let conns: Vec<Arc<ConnStats>> = self.conns.lock().values().cloned().collect();
+        conns.iter().map(|conn| conn.snapshot()).collect()
+    }
+
+    /// Number of open connections
+    pub fn len(&self) -> usize {
+        self.conns.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A connection's entry in the registry, removed on drop however the
+/// connection ends (error, shutdown, abort or panic)
+pub(super) struct ConnHandle {
+    registry: Arc<ConnectionRegistry>,
+    stats: Arc<ConnStats>,
+}
+
+impl Deref for ConnHandle {
+    type Target = ConnStats;
+
+    fn deref(&self) -> &ConnStats {
+        &self.stats
+    }
+}
+
+impl Drop for ConnHandle {
+    fn drop(&mut self) {
+        self.registry.conns.lock().remove(&self.stats.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_remove() {
+        let registry = Arc::new(ConnectionRegistry::new());
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let first = registry.register(Some(peer));
+        let second = registry.register(None);
+
+        first.start_command();
+        first.add_bytes_read(20);
+        first.add_bytes_written(8);
+        first.set_state(ConnState::Write);
+
+        let conns = registry.snapshot();
+        assert_eq!(conns.len(), 2);
+        assert_eq!(conns[0].id, first.id);
+        assert_eq!(conns[0].addr(), "tcp:10.0.0.1:5000");
+        assert_eq!(conns[0].commands, 1);
+        assert_eq!(conns[0].bytes_read, 20);
+        assert_eq!(conns[0].bytes_written, 8);
+        assert!(conns[0].last_command_at.is_some());
+        assert_eq!(conns[0].state, ConnState::Write);
+        assert_eq!(conns[1].addr(), "unix");
+        assert_eq!(conns[1].last_command_at, None);
+        assert_eq!(conns[1].state, ConnState::Waiting);
+
+        drop(first);
+        assert_eq!(registry.snapshot()[0].id, second.id);
+        drop(second);
+        assert!(registry.is_empty());
+    }
+}
@@ -1,16 +1,74 @@
 //! Command handlers for memcached protocol commands
 
 use super::Server;
-use crate::protocol::{Command, ResponseWriter};
-use crate::storage::StoredValue;
+use super::meta;
+use crate::StorageError;
+use crate::config::{Compression, ServerConfig, StorageConfig};
+use crate::protocol::{Command, CommandKind, ResponseWriter};
+use crate::storage::{CasOutcome, StoredValue, current_timestamp};
 use std::sync::Arc;
 
+/// Execute a parsed command on the blocking pool, so a RocksDB write stall
+/// or cold read doesn't hold up the async worker and every connection on it
+///
+/// Runs inline instead with `inline_storage`, and for commands that don't
+/// wait on storage.
+pub async fn execute_offloaded(
+    server: &Arc<Server>,
+    cmd: Command<'_>,
+    response: &mut ResponseWriter,
+) {
+    let inline = matches!(
+        cmd,
+        Command::Version | Command::Quit | Command::MetaNoop | Command::Stats { .. }
+    );
+    // Upstream fetches block on the network
+    let reads_upstream =
+        server.upstream.is_some() && matches!(cmd, Command::Get { .. } | Command::Gets { .. });
+    if inline || (server.config.inline_storage && !reads_upstream) {
+        execute(server, cmd, response);
+        return;
+    }
+
+    // The command borrows the read buffer, which can't cross threads
+    let cmd = cmd.into_owned();
+    let task_server = Arc::clone(server);
+    let mut reply =
+        ResponseWriter::new(256).with_zero_copy_min(server.config.zero_copy_min_value_size);
+    let result = tokio::task::spawn_blocking(move || {
+        execute(&task_server, cmd, &mut reply);
+        reply
+    })
+    .await;
+    match result {
+        Ok(reply) => response.append(reply),
+        Err(e) => response.server_error(&e.to_string()),
+    }
+}
+
 /// Execute a parsed command
+#[allow(clippy::too_many_lines)]
 pub fn execute(server: &Arc<Server>, cmd: Command<'_>, response: &mut ResponseWriter) {
+    if server.rejects_write(cmd.kind(), cmd.name()) {
+        response.server_error("server is read-only");
+        return;
+    }
     match cmd {
         Command::Get { keys } => {
             server.metrics.cmd_get.inc();
-            handle_get(server, keys, response);
+            handle_get(server, keys, false, response);
+        }
+        Command::Gets { keys } => {
+            server.metrics.cmd_get.inc();
+            handle_get(server, keys, true, response);
+        }
+        Command::Gat { exptime, keys } => {
+            server.metrics.cmd_touch.inc();
+            handle_gat(server, exptime, &keys, false, response);
+        }
+        Command::Gats { exptime, keys } => {
+            server.metrics.cmd_touch.inc();
+            handle_gat(server, exptime, &keys, true, response);
         }
         Command::Set {
             key,
@@ -20,12 +78,98 @@ pub fn execute(server: &Arc<Server>, cmd: Command<'_>, response: &mut ResponseWr
             ..
         } => {
             server.metrics.cmd_set.inc();
+            server.metrics.observe_store(&key, data.len());
             handle_set(server, &key, flags, exptime, &data, response);
         }
+        Command::Add {
+            key,
+            flags,
+            exptime,
+            data,
+            ..
+        } => {
+            server.metrics.cmd_add.inc();
+            server.metrics.observe_store(&key, data.len());
+            handle_add(server, &key, flags, exptime, &data, response);
+        }
+        Command::Replace {
+            key,
+            flags,
+            exptime,
+            data,
+            ..
+        } => {
+            server.metrics.cmd_replace.inc();
+            server.metrics.observe_store(&key, data.len());
+            handle_replace(server, &key, flags, exptime, &data, response);
+        }
+        Command::Append { key, data, .. } => {
+            server.metrics.cmd_append.inc();
+            server.metrics.observe_store(&key, data.len());
+            handle_concat(server.storage.append(&key, &data), server, response);
+        }
+        Command::Prepend { key, data, .. } => {
+            server.metrics.cmd_prepend.inc();
+            server.metrics.observe_store(&key, data.len());
+            handle_concat(server.storage.prepend(&key, &data), server, response);
+        }
+        Command::Cas {
+            key,
+            flags,
+            exptime,
+            data,
+            cas_unique,
+            ..
+        } => {
+            server.metrics.cmd_cas.inc();
+            server.metrics.observe_store(&key, data.len());
+            handle_cas(server, &key, flags, exptime, &data, cas_unique, response);
+        }
         Command::Delete { key, .. } => {
             server.metrics.cmd_delete.inc();
             handle_delete(server, &key, response);
         }
+        Command::Incr { key, delta, .. } => {
+            server.metrics.cmd_incr.inc();
+            handle_incr_decr(server, &key, delta, true, response);
+        }
+        Command::Decr { key, delta, .. } => {
+            server.metrics.cmd_decr.inc();
+            handle_incr_decr(server, &key, delta, false, response);
+        }
+        Command::FlushAll { delay, .. } => {
+            server.metrics.cmd_flush.inc();
+            handle_flush_all(server, delay, response);
+        }
+        Command::MetaGet { key, flags } => {
+            server.metrics.cmd_get.inc();
+            meta::handle_get(server, &key, &flags, response);
+        }
+        Command::MetaSet {
+            key,
+            data,
+            mode,
+            flags,
+        } => meta::handle_set(server, &key, &data, mode, &flags, response),
+        Command::MetaDelete { key, flags } => {
+            server.metrics.cmd_delete.inc();
+            meta::handle_delete(server, &key, &flags, response);
+        }
+        Command::MetaArithmetic { key, incr, flags } => {
+            meta::handle_arithmetic(server, &key, incr, &flags, response);
+        }
+        Command::MetaNoop => response.meta_noop(),
+        Command::Stats { args } => match args.as_deref() {
+            None => handle_stats(server, response),
+            Some(b"reset") => {
+                server.metrics.reset_stats();
+                response.reset();
+            }
+            Some(b"settings") => handle_stats_settings(server, response),
+            Some(b"conns") => handle_stats_conns(server, response),
+            // `stats items` / `stats sizes` go through `execute_sampled_stats`
+            Some(_) => response.error(),
+        },
         Command::Version => {
             handle_version(response);
         }
@@ -40,21 +184,399 @@ fn handle_version(response: &mut ResponseWriter) {
     response.version(concat!("petracache ", env!("CARGO_PKG_VERSION")));
 }
 
-/// Handle GET command
+/// Handle STATS command using the standard memcached stat names
+fn handle_stats(server: &Arc<Server>, response: &mut ResponseWriter) {
+    for (name, value) in general_stats(server) {
+        response.stat(name, &value);
+    }
+    response.end();
+}
+
+/// Handle STATS CONNS: a group of `<id>:<name>` lines per open connection,
+/// like memcached
+fn handle_stats_conns(server: &Arc<Server>, response: &mut ResponseWriter) {
+    let now = current_timestamp();
+    for conn in server.connections.snapshot() {
+        let id = conn.id;
+        response.stat(&format!("{id}:addr"), &conn.addr());
+        response.stat(&format!("{id}:state"), conn.state.as_str());
+        response.stat_u64(
+            &format!("{id}:connected_secs"),
+            now.saturating_sub(conn.connected_at),
+        );
+        if let Some(at) = conn.last_command_at {
+            response.stat_u64(&format!("{id}:secs_since_last_cmd"), now.saturating_sub(at));
+        }
+        response.stat_u64(&format!("{id}:cmds"), conn.commands);
+        response.stat_u64(&format!("{id}:bytes_read"), conn.bytes_read);
+        response.stat_u64(&format!("{id}:bytes_written"), conn.bytes_written);
+    }
+    response.end();
+}
+
+/// General server statistics, shared by ASCII `stats` and binary Stat
+pub(super) fn general_stats(server: &Arc<Server>) -> Vec<(&'static str, String)> {
+    let metrics = &server.metrics;
+    let stats = metrics.stats();
+    let db = server.storage.db_stats();
+    let now = current_timestamp();
+    let curr_connections = u64::try_from(metrics.active_connections.get()).unwrap_or(0);
+
+    let mut general = vec![
+        ("pid", std::process::id().to_string()),
+        ("uptime", now.saturating_sub(server.started_at).to_string()),
+        ("time", now.to_string()),
+        ("version", env!("CARGO_PKG_VERSION").to_string()),
+        ("curr_connections", curr_connections.to_string()),
+        (
+            "total_connections",
+            metrics.total_connections.get().to_string(),
+        ),
+        ("cmd_get", stats.cmd_get.to_string()),
+        ("cmd_set", stats.cmd_set.to_string()),
+        ("cmd_flush", stats.cmd_flush.to_string()),
+        ("cmd_touch", stats.cmd_touch.to_string()),
+        ("get_hits", stats.get_hits.to_string()),
+        ("get_misses", stats.get_misses.to_string()),
+        ("bytes_read", stats.bytes_read.to_string()),
+        ("bytes_written", stats.bytes_written.to_string()),
+    ];
+    // Skipped rather than reported as 0 when RocksDB has no estimate
+    if let Some(curr_items) = db.estimated_keys {
+        general.push(("curr_items", curr_items.to_string()));
+    }
+    if let Some(bytes) = db.live_data_bytes {
+        general.push(("bytes", bytes.to_string()));
+    }
+    // Entries evicted for `on_full = "evict"`, or FIFO compaction's drops
+    // of the oldest SST files; zero when neither bounds the size
+    let evictions = metrics.evicted_keys.get() + db.fifo_drops.unwrap_or(0);
+    general.push(("evictions", evictions.to_string()));
+    if let Some(health) = &server.storage_health {
+        let healthy = u8::from(health.is_healthy());
+        general.push(("storage_healthy", healthy.to_string()));
+    }
+    general
+}
+
+/// Stats subcommands that scan storage and must run on a blocking thread
+#[derive(Debug, Clone, Copy)]
+pub enum SampledStats {
+    /// `stats items`: item counts per TTL bucket
+    Items,
+    /// `stats sizes`: value size histogram
+    Sizes,
+}
+
+impl SampledStats {
+    /// Detect a sampled stats subcommand
+    pub fn from_command(cmd: &Command<'_>) -> Option<Self> {
+        match cmd {
+            Command::Stats { args: Some(args) } => match args.as_ref() {
+                b"items" => Some(Self::Items),
+                b"sizes" => Some(Self::Sizes),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Handle STATS ITEMS / STATS SIZES from a bounded sample of the keyspace
+///
+/// TTL buckets are reported as memcached-style slab ids so `memcached-tool`
+/// can parse them: 1 = no TTL, 2 = under 1h, 3 = under 1d, 4 = longer.
+pub async fn execute_sampled_stats(
+    server: &Arc<Server>,
+    kind: SampledStats,
+    response: &mut ResponseWriter,
+) {
+    let storage = Arc::clone(&server.storage);
+    let limit = server.config.stats_sample_limit;
+    let sample = match tokio::task::spawn_blocking(move || storage.sample_items(limit)).await {
+        Ok(Ok(sample)) => sample,
+        Ok(Err(e)) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+            return;
+        }
+        Err(e) => {
+            response.server_error(&e.to_string());
+            return;
+        }
+    };
+
+    match kind {
+        SampledStats::Items => {
+            let buckets = [
+                sample.no_ttl,
+                sample.ttl_under_hour,
+                sample.ttl_under_day,
+                sample.ttl_over_day,
+            ];
+            for (id, count) in (1..).zip(buckets) {
+                if count > 0 {
+                    response.stat_u64(&format!("items:{id}:number"), count);
+                }
+            }
+            response.stat_u64("items:sampled", sample.sampled);
+        }
+        SampledStats::Sizes => {
+            let mut itoa_buf = itoa::Buffer::new();
+            for (size, count) in &sample.sizes {
+                response.stat_u64(itoa_buf.format(*size), *count);
+            }
+        }
+    }
+    response.end();
+}
+
+/// Handle STATS SETTINGS: dump the effective server and storage configuration
+fn handle_stats_settings(server: &Arc<Server>, response: &mut ResponseWriter) {
+    // With the changes of a SIGHUP reload and `POST /admin/readonly`
+    let settings = server.settings();
+    server_settings(&settings, response);
+    response.stat("read_only", bool_str(settings.read_only));
+
+    // With the changes of `RocksStorage::set_options`
+    let storage = server.storage.settings();
+    response.stat("db_path", &storage.db_path.to_string_lossy());
+    if storage.db_paths.is_empty() {
+        response.stat("db_paths", "NULL");
+    } else {
+        let paths: Vec<String> = storage
+            .db_paths
+            .iter()
+            .map(|data_path| {
+                format!(
+                    "{}={}",
+                    data_path.path.to_string_lossy(),
+                    data_path.target_size_bytes
+                )
+            })
+            .collect();
+        response.stat("db_paths", &paths.join(","));
+    }
+    response.stat_u64("block_cache_size", storage.block_cache_size as u64);
+    rocksdb_settings(&storage, response);
+    response.stat(
+        "enable_ttl_compaction",
+        bool_str(storage.enable_ttl_compaction),
+    );
+    response.stat("enable_statistics", bool_str(storage.enable_statistics));
+    response.stat("flush_on_shutdown", bool_str(storage.flush_on_shutdown));
+    response.stat("wal", storage.wal.as_str());
+    match &storage.wal_dir {
+        Some(dir) => response.stat("wal_dir", &dir.to_string_lossy()),
+        None => response.stat("wal_dir", "NULL"),
+    }
+    response.stat_u64("max_total_wal_size", storage.max_total_wal_size);
+    response.stat("rocksdb_log_level", &storage.rocksdb_log_level);
+    response.stat_u64(
+        "rocksdb_max_log_file_size",
+        storage.rocksdb_max_log_file_size as u64,
+    );
+    response.stat_u64(
+        "rocksdb_keep_log_file_num",
+        storage.rocksdb_keep_log_file_num as u64,
+    );
+    response.stat_u64("hot_cache_size_bytes", storage.hot_cache_size_bytes as u64);
+    response.stat_u64("negative_cache_size", storage.negative_cache_size as u64);
+    response.stat_u64("negative_cache_ttl_secs", storage.negative_cache_ttl_secs);
+    response.stat_u64("expiry_scan_interval_ms", storage.expiry_scan_interval_ms);
+    response.stat_u64(
+        "expiry_scan_batch_keys",
+        storage.expiry_scan_batch_keys as u64,
+    );
+    response.stat_u64("expiry_scan_budget_ms", storage.expiry_scan_budget_ms);
+    response.stat_u64("expired_log_sample_rate", storage.expired_log_sample_rate);
+    response.stat("expired_log_keys", bool_str(storage.expired_log_keys));
+    response.stat_u64(
+        "expired_log_interval_secs",
+        storage.expired_log_interval_secs,
+    );
+    response.stat_u64("default_ttl_secs", storage.default_ttl_secs);
+    response.stat_u64("max_ttl_secs", storage.max_ttl_secs);
+    response.stat(
+        "ttl_jitter_percent",
+        &storage.ttl_jitter_percent.to_string(),
+    );
+    response.stat("on_corruption", storage.on_corruption.as_str());
+    response.stat("mode", storage.mode.as_str());
+    response.stat_u64("catch_up_interval_ms", storage.catch_up_interval_ms);
+    response.stat_u64("max_db_size_bytes", storage.max_db_size_bytes);
+    response.stat_u64("health_check_interval_ms", storage.health_check_interval_ms);
+    response.stat("on_full", storage.on_full.as_str());
+    response.stat("compaction", storage.compaction.as_str());
+    response.stat_u64(
+        "compress_values_over_bytes",
+        storage.compress_values_over_bytes as u64,
+    );
+    response.stat("verify_checksums", bool_str(storage.verify_checksums));
+    if storage.namespaces.is_empty() {
+        response.stat("namespaces", "NULL");
+    } else {
+        let names: Vec<&str> = storage
+            .namespaces
+            .iter()
+            .map(|ns| ns.name.as_str())
+            .collect();
+        response.stat("namespaces", &names.join(","));
+    }
+    response.stat("shards", &storage.shards.to_string());
+    match &server.upstream {
+        Some(upstream) => response.stat("upstream_addr", &upstream.config().addr),
+        None => response.stat("upstream_addr", "NULL"),
+    }
+    response.end();
+}
+
+/// The RocksDB tuning part of STATS SETTINGS
+fn rocksdb_settings(storage: &StorageConfig, response: &mut ResponseWriter) {
+    response.stat_u64(
+        "storage_write_buffer_size",
+        storage.write_buffer_size as u64,
+    );
+    response.stat(
+        "max_write_buffer_number",
+        &storage.max_write_buffer_number.to_string(),
+    );
+    response.stat_u64("target_file_size_base", storage.target_file_size_base);
+    response.stat(
+        "max_background_jobs",
+        &storage.max_background_jobs.to_string(),
+    );
+    response.stat_u64("rate_limit_bytes_per_sec", storage.rate_limit_bytes_per_sec);
+    response.stat_u64(
+        "compaction_readahead_size",
+        storage.compaction_readahead_size as u64,
+    );
+    response.stat(
+        "max_subcompactions",
+        &storage.max_subcompactions.to_string(),
+    );
+    response.stat(
+        "low_priority_compaction_io",
+        bool_str(storage.low_priority_compaction_io),
+    );
+    response.stat("enable_compression", bool_str(storage.enable_compression));
+    response.stat("compression", storage.effective_compression().as_str());
+    response.stat(
+        "bottommost_compression",
+        storage
+            .bottommost_compression
+            .map_or("NULL", Compression::as_str),
+    );
+    match storage.zstd_level {
+        Some(level) => response.stat("zstd_level", &level.to_string()),
+        None => response.stat("zstd_level", "NULL"),
+    }
+    response.stat_u64("block_size", storage.block_size as u64);
+    response.stat(
+        "bloom_bits_per_key",
+        &storage.bloom_bits_per_key.to_string(),
+    );
+    response.stat(
+        "cache_index_and_filter_blocks",
+        bool_str(storage.cache_index_and_filter_blocks),
+    );
+    response.stat("index_type", storage.index_type.as_str());
+    response.stat("partition_filters", bool_str(storage.partition_filters));
+    match storage.format_version {
+        Some(version) => response.stat("format_version", &version.to_string()),
+        None => response.stat("format_version", "NULL"),
+    }
+}
+
+/// The `[server]` half of STATS SETTINGS
+fn server_settings(cfg: &ServerConfig, response: &mut ResponseWriter) {
+    response.stat("listen_addr", &cfg.listen_addr);
+    response.stat_u64("num_acceptors", cfg.num_acceptors as u64);
+    match &cfg.unix_socket_path {
+        Some(path) => response.stat("unix_socket_path", &path.to_string_lossy()),
+        None => response.stat("unix_socket_path", "NULL"),
+    }
+    response.stat("unix_socket_mode", &format!("{:o}", cfg.unix_socket_mode));
+    response.stat("proxy_protocol", bool_str(cfg.proxy_protocol));
+    response.stat("tls", bool_str(cfg.tls.is_some()));
+    response.stat_u64("max_connections", cfg.max_connections as u64);
+    response.stat_u64("max_connections_per_ip", cfg.max_connections_per_ip as u64);
+    if cfg.allowed_cidrs.is_empty() {
+        response.stat("allowed_cidrs", "any");
+    } else {
+        let cidrs: Vec<String> = cfg.allowed_cidrs.iter().map(ToString::to_string).collect();
+        response.stat("allowed_cidrs", &cidrs.join(","));
+    }
+    response.stat_u64("read_buffer_size", cfg.read_buffer_size as u64);
+    response.stat_u64("write_buffer_size", cfg.write_buffer_size as u64);
+    response.stat_u64("buffer_shrink_factor", cfg.buffer_shrink_factor as u64);
+    response.stat_u64(
+        "zero_copy_min_value_size",
+        cfg.zero_copy_min_value_size as u64,
+    );
+    response.stat_u64("worker_threads", cfg.worker_threads as u64);
+    response.stat("inline_storage", bool_str(cfg.inline_storage));
+    response.stat_u64("storage_threads", cfg.storage_threads as u64);
+    response.stat_u64("set_batch_size", cfg.set_batch_size as u64);
+    response.stat_u64("connection_timeout_secs", cfg.connection_timeout_secs);
+    response.stat_u64("shutdown_grace_secs", cfg.shutdown_grace_secs);
+    response.stat_u64("stats_sample_limit", cfg.stats_sample_limit as u64);
+    response.stat_u64("max_item_size", cfg.max_item_size as u64);
+    response.stat_u64("max_get_keys", cfg.max_get_keys as u64);
+    response.stat_u64("max_command_line_bytes", cfg.max_command_line_bytes as u64);
+    response.stat("strict_ascii_keys", bool_str(cfg.strict_ascii_keys));
+    response.stat("accept_bare_lf", bool_str(cfg.accept_bare_lf));
+    response.stat_u64("max_protocol_errors", u64::from(cfg.max_protocol_errors));
+    let disabled: Vec<&str> = cfg
+        .disabled_commands
+        .iter()
+        .map(CommandKind::name)
+        .collect();
+    if disabled.is_empty() {
+        response.stat("disabled_commands", "none");
+    } else {
+        response.stat("disabled_commands", &disabled.join(","));
+    }
+    response.stat("auth_enabled", bool_str(cfg.auth.is_some()));
+    response.stat_u64("slow_log_threshold_ms", cfg.slow_log_threshold_ms);
+    response.stat("slow_log_hash_keys", bool_str(cfg.slow_log_hash_keys));
+    response.stat_u64("slow_log_max_per_sec", u64::from(cfg.slow_log_max_per_sec));
+}
+
+/// Render a boolean setting the way memcached does
+fn bool_str(value: bool) -> &'static str {
+    if value { "yes" } else { "no" }
+}
+
+/// Handle GET/GETS command (GETS includes the CAS unique token)
+///
+/// With an upstream configured, local misses are filled from it; they
+/// still count as `get_misses`.
 fn handle_get(
     server: &Arc<Server>,
     keys: Vec<std::borrow::Cow<'_, [u8]>>,
+    with_cas: bool,
     response: &mut ResponseWriter,
 ) {
+    let write_value = |response: &mut ResponseWriter, key: &[u8], value: StoredValue| {
+        let cas = with_cas.then_some(value.cas);
+        response.value_owned(key, value.flags, value.data, cas);
+    };
+
     if keys.len() == 1 {
         // Fast path - single key (most common case)
         match server.storage.get(&keys[0]) {
             Ok(Some(value)) => {
-                server.metrics.get_hits.inc();
-                response.value(&keys[0], value.flags, &value.data);
+                server.record_get(&keys[0], Some(&value));
+                write_value(response, &keys[0], value);
             }
             Ok(None) => {
-                server.metrics.get_misses.inc();
+                server.record_get(&keys[0], None);
+                if let Some(upstream) = &server.upstream
+                    && let Some(value) = upstream.fetch(&keys).pop().flatten()
+                {
+                    write_value(response, &keys[0], value);
+                }
             }
             Err(e) => {
                 server.metrics.storage_errors.inc();
@@ -63,18 +585,54 @@ fn handle_get(
             }
         }
     } else {
-        // Multi-key path
-        let keys_vec: Vec<Vec<u8>> = keys.iter().map(|k| k.to_vec()).collect();
-        match server.storage.get_multi(&keys_vec) {
-            Ok(results) => {
-                for (key, value_opt) in results {
-                    if let Some(value) = value_opt {
-                        server.metrics.get_hits.inc();
-                        response.value(&key, value.flags, &value.data);
-                    } else {
-                        server.metrics.get_misses.inc();
-                    }
-                }
+        // Multi-key path; results line up with `keys`
+        let mut results = match server.storage.get_multi(&keys) {
+            Ok(results) => results,
+            Err(e) => {
+                server.metrics.storage_errors.inc();
+                response.server_error(&e.to_string());
+                return;
+            }
+        };
+        // Indexes into `keys` that missed locally
+        let missed: Vec<usize> = (0..keys.len()).filter(|&i| results[i].is_none()).collect();
+        for (key, value) in keys.iter().zip(&results) {
+            server.record_get(key, value.as_ref());
+        }
+        if let Some(upstream) = &server.upstream
+            && !missed.is_empty()
+        {
+            let missed_keys: Vec<&[u8]> = missed.iter().map(|&i| keys[i].as_ref()).collect();
+            for (i, value) in missed.iter().zip(upstream.fetch(&missed_keys)) {
+                results[*i] = value;
+            }
+        }
+        for (key, value_opt) in keys.iter().zip(results) {
+            if let Some(value) = value_opt {
+                write_value(response, key, value);
+            }
+        }
+    }
+    response.end();
+}
+
+/// Handle GAT/GATS command (GATS includes the CAS unique token)
+fn handle_gat(
+    server: &Arc<Server>,
+    exptime: i64,
+    keys: &[std::borrow::Cow<'_, [u8]>],
+    with_cas: bool,
+    response: &mut ResponseWriter,
+) {
+    for key in keys {
+        match server.storage.get_and_touch(key, server.exptime(exptime)) {
+            Ok(Some(value)) => {
+                server.record_get(key, Some(&value));
+                let cas = with_cas.then_some(value.cas);
+                response.value_owned(key, value.flags, value.data, cas);
+            }
+            Ok(None) => {
+                server.record_get(key, None);
             }
             Err(e) => {
                 server.metrics.storage_errors.inc();
@@ -91,11 +649,11 @@ fn handle_set(
     server: &Arc<Server>,
     key: &[u8],
     flags: u32,
-    exptime: u64,
+    exptime: i64,
     data: &[u8],
     response: &mut ResponseWriter,
 ) {
-    let value = StoredValue::new(flags, exptime, data.to_vec());
+    let value = StoredValue::new(flags, server.exptime(exptime), data.to_vec());
     match server.storage.set(key, value) {
         Ok(()) => response.stored(),
         Err(e) => {
@@ -105,6 +663,116 @@ fn handle_set(
     }
 }
 
+/// Handle ADD command (store only if the key does not exist)
+fn handle_add(
+    server: &Arc<Server>,
+    key: &[u8],
+    flags: u32,
+    exptime: i64,
+    data: &[u8],
+    response: &mut ResponseWriter,
+) {
+    let value = StoredValue::new(flags, server.exptime(exptime), data.to_vec());
+    match server.storage.add(key, value) {
+        Ok(true) => response.stored(),
+        Ok(false) => response.not_stored(),
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Handle REPLACE command (store only if the key exists)
+fn handle_replace(
+    server: &Arc<Server>,
+    key: &[u8],
+    flags: u32,
+    exptime: i64,
+    data: &[u8],
+    response: &mut ResponseWriter,
+) {
+    let value = StoredValue::new(flags, server.exptime(exptime), data.to_vec());
+    match server.storage.replace(key, value) {
+        Ok(true) => response.stored(),
+        Ok(false) => response.not_stored(),
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Handle CAS command (store only if the item is unchanged)
+fn handle_cas(
+    server: &Arc<Server>,
+    key: &[u8],
+    flags: u32,
+    exptime: i64,
+    data: &[u8],
+    cas_unique: u64,
+    response: &mut ResponseWriter,
+) {
+    let value = StoredValue::new(flags, server.exptime(exptime), data.to_vec());
+    match server.storage.cas(key, value, cas_unique) {
+        Ok(CasOutcome::Stored) => response.stored(),
+        Ok(CasOutcome::Exists) => response.exists(),
+        Ok(CasOutcome::NotFound) => response.not_found(),
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Reply to APPEND/PREPEND based on the storage result
+fn handle_concat(
+    result: Result<bool, StorageError>,
+    server: &Arc<Server>,
+    response: &mut ResponseWriter,
+) {
+    match result {
+        Ok(true) => response.stored(),
+        Ok(false) => response.not_stored(),
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Handle INCR/DECR commands
+fn handle_incr_decr(
+    server: &Arc<Server>,
+    key: &[u8],
+    delta: u64,
+    incr: bool,
+    response: &mut ResponseWriter,
+) {
+    match server.storage.incr_decr(key, delta, incr) {
+        Ok(Some(value)) => response.numeric(value),
+        Ok(None) => response.not_found(),
+        Err(StorageError::NotNumeric) => {
+            response.client_error("cannot increment or decrement non-numeric value");
+        }
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Handle FLUSH_ALL command
+fn handle_flush_all(server: &Arc<Server>, delay: u64, response: &mut ResponseWriter) {
+    match server.storage.flush_all(delay) {
+        Ok(()) => response.ok(),
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
 /// Handle DELETE command
 fn handle_delete(server: &Arc<Server>, key: &[u8], response: &mut ResponseWriter) {
     match server.storage.delete(key) {
@@ -0,0 +1,256 @@
+//! Connection limits, overall and per source IP
+//!
+//! Both can change at runtime (SIGHUP reload), so neither is fixed at
+//! construction: `ConnectionLimit` resizes its semaphore, and `IpLimiter`
+//! counts every peer even while unlimited, so a limit switched on later
+//! sees the connections already open.
+
+use crate::storage::current_timestamp;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Minimum seconds between "over the per-IP limit" warnings
+const WARN_INTERVAL_SECS: u64 = 10;
+
+/// `max_connections`: one semaphore permit per open connection
+pub(super) struct ConnectionLimit {
+    semaphore: Arc<Semaphore>,
+    /// Permits the semaphore stands for, handed out or not
+    max: Mutex<usize>,
+}
+
+impl ConnectionLimit {
+    pub(super) fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max: Mutex::new(max),
+        }
+    }
+
+    /// A slot for a new connection, or `None` at the limit
+    pub(super) fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.semaphore).try_acquire_owned().ok()
+    }
+
+    pub(super) fn max(&self) -> usize {
+        *self.max.lock()
+    }
+
+    /// Change the limit; open connections are never closed, so lowering it
+    /// below their number takes effect as they finish
+    pub(super) fn resize(&self, max: usize) {
+        let mut current = self.max.lock();
+        if max > *current {
+            self.semaphore.add_permits(max - *current);
+        } else if max < *current {
+            let excess = *current - max;
+            let held = excess - self.semaphore.forget_permits(excess);
+            if held > 0 {
+                // Claim the rest as connections release them; released
+                // permits go to this waiter before any new connection
+                let semaphore = Arc::clone(&self.semaphore);
+                let held = u32::try_from(held).unwrap_or(u32::MAX);
+                tokio::spawn(async move {
+                    if let Ok(permits) = semaphore.acquire_many_owned(held).await {
+                        permits.forget();
+                    }
+                });
+            }
+        }
+        *current = max;
+    }
+}
+
+/// Live connection counts per peer IP
+pub(super) struct IpLimiter {
+    /// Most connections per IP (0 = unlimited)
+    max_per_ip: AtomicUsize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+    /// Unix timestamp of the last rejection warning
+    last_warning: AtomicU64,
+}
+
+impl IpLimiter {
+    pub(super) fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip: AtomicUsize::new(max_per_ip),
+            counts: Mutex::new(HashMap::new()),
+            last_warning: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn max_per_ip(&self) -> usize {
+        self.max_per_ip.load(Ordering::Relaxed)
+    }
+
+    /// Change the limit; peers already over it keep their connections
+    pub(super) fn set_max_per_ip(&self, max_per_ip: usize) {
+        self.max_per_ip.store(max_per_ip, Ordering::Relaxed);
+    }
+
+    /// Take a connection slot for `ip`, or `None` if it already has
+    /// `max_per_ip` open connections
+    pub(super) fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let max = self.max_per_ip();
+        let mut counts = self.counts.lock();
+        let count = counts.entry(ip).or_insert(0);
+        if max > 0 && *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Whether a rejection should be logged now (at most once per
+    /// `WARN_INTERVAL_SECS`, so a connect loop can't flood the log)
+    pub(super) fn should_warn(&self) -> bool {
+        let now = current_timestamp();
+        let last = self.last_warning.load(Ordering::Relaxed);
+        now >= last + WARN_INTERVAL_SECS
+            && self
+                .last_warning
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
+
+    /// Open connections from `ip`
+    #[cfg(test)]
+    fn count(&self, ip: IpAddr) -> usize {
+        self.counts.lock().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+/// A connection's slot in its IP's count, released on drop (also when the
+/// connection task panics or is aborted)
+pub(super) struct IpSlot {
+    limiter: Arc<IpLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ServerConfig, StorageConfig};
+    use crate::testing::TestServer;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    #[test]
+    fn test_limit_per_ip() {
+        let limiter = Arc::new(IpLimiter::new(2));
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+
+        let first = limiter.try_acquire(a).unwrap();
+        let _second = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_none());
+        // Other peers are unaffected
+        let _other = limiter.try_acquire(b).unwrap();
+
+        drop(first);
+        assert_eq!(limiter.count(a), 1);
+        assert!(limiter.try_acquire(a).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_slot_released_on_panic() {
+        let limiter = Arc::new(IpLimiter::new(1));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        let slot = limiter.try_acquire(ip).unwrap();
+        let task = tokio::spawn(async move {
+            let _slot = slot;
+            panic!("connection handler panicked");
+        });
+        assert!(task.await.unwrap_err().is_panic());
+        assert_eq!(limiter.count(ip), 0);
+        assert!(limiter.counts.lock().is_empty());
+    }
+
+    #[test]
+    fn test_limit_changes() {
+        let limiter = Arc::new(IpLimiter::new(0));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let slots: Vec<IpSlot> = (0..3).map(|_| limiter.try_acquire(ip).unwrap()).collect();
+        assert_eq!(limiter.count(ip), 3);
+
+        // Open connections count against a limit set later
+        limiter.set_max_per_ip(3);
+        assert!(limiter.try_acquire(ip).is_none());
+        drop(slots);
+        assert!(limiter.try_acquire(ip).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit_resize() {
+        let limit = ConnectionLimit::new(3);
+        let mut permits: Vec<OwnedSemaphorePermit> =
+            (0..3).map(|_| limit.try_acquire().unwrap()).collect();
+        assert!(limit.try_acquire().is_none());
+
+        limit.resize(5);
+        permits.push(limit.try_acquire().unwrap());
+        permits.push(limit.try_acquire().unwrap());
+        assert!(limit.try_acquire().is_none());
+
+        // Down to 2 with 5 open: slots come back only below the new limit
+        limit.resize(2);
+        assert_eq!(limit.max(), 2);
+        tokio::task::yield_now().await;
+        for _ in 0..3 {
+            permits.pop();
+            tokio::task::yield_now().await;
+            assert!(limit.try_acquire().is_none());
+        }
+        permits.pop();
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn test_warnings_rate_limited() {
+        let limiter = IpLimiter::new(1);
+        assert!(limiter.should_warn());
+        assert!(!limiter.should_warn());
+    }
+
+    #[tokio::test]
+    async fn test_server_rejects_over_limit_peer() {
+        let server = TestServer::spawn_with(
+            ServerConfig {
+                max_connections_per_ip: 1,
+                ..ServerConfig::default()
+            },
+            StorageConfig::default(),
+        )
+        .await;
+
+        // Accepted in order, so the first holds the peer's only slot
+        let mut first = server.client().await;
+        let mut second = TcpStream::connect(server.addr()).await.unwrap();
+        let mut buf = [0u8; 64];
+        // Closed without a reply
+        assert!(matches!(second.read(&mut buf).await, Ok(0) | Err(_)));
+        assert_eq!(server.metrics().rejected_connections_per_ip.get(), 1);
+
+        assert!(first.version().await.unwrap().starts_with("petracache "));
+    }
+}
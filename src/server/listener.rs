@@ -0,0 +1,244 @@
+//! Listening sockets: TCP (optionally several with `SO_REUSEPORT`) and, on
+//! Unix, a Unix domain socket
+
+use crate::config::ServerConfig;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::info;
+
+/// Backlog for sockets bound through socket2 (what `TcpListener::bind` uses)
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// A freshly accepted client connection
+pub(super) enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// A socket the server accepts connections on; each gets its own accept loop
+pub(super) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(unix::UnixSocket),
+}
+
+impl Listener {
+    /// Bind the TCP listeners (unless `listen_addr` is empty) and the Unix
+    /// socket (if `unix_socket_path` is set)
+    ///
+    /// With `num_acceptors` > 1 that many TCP sockets share the port through
+    /// `SO_REUSEPORT`, and the kernel spreads incoming connections over them.
+    pub(super) async fn bind_all(config: &ServerConfig) -> anyhow::Result<Vec<Self>> {
+        let mut listeners = Vec::new();
+        if !config.listen_addr.is_empty() {
+            let addr: SocketAddr = config.listen_addr.parse()?;
+            if config.num_acceptors > 1 {
+                let first = bind_reuse_port(addr)?;
+                // Port 0 picks a port once; the other sockets join it
+                let addr = first.local_addr()?;
+                listeners.push(Self::Tcp(first));
+                for _ in 1..config.num_acceptors {
+                    listeners.push(Self::Tcp(bind_reuse_port(addr)?));
+                }
+                info!(
+                    "Server listening on {} ({} acceptors)",
+                    addr, config.num_acceptors
+                );
+            } else {
+                let listener = TcpListener::bind(addr).await?;
+                info!("Server listening on {}", listener.local_addr()?);
+                listeners.push(Self::Tcp(listener));
+            }
+        }
+
+        match &config.unix_socket_path {
+            #[cfg(unix)]
+            Some(path) => listeners.push(Self::Unix(unix::UnixSocket::bind(
+                path,
+                config.unix_socket_mode,
+            )?)),
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("unix_socket_path is only supported on Unix platforms"),
+            None => {}
+        }
+
+        if listeners.is_empty() {
+            anyhow::bail!("no listener configured: set listen_addr or unix_socket_path");
+        }
+        Ok(listeners)
+    }
+
+    /// Wait for the next connection
+    pub(super) async fn accept(&self) -> std::io::Result<Accepted> {
+        match self {
+            Self::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, addr)| Accepted::Tcp(stream, addr)),
+            #[cfg(unix)]
+            Self::Unix(socket) => socket.accept().await.map(Accepted::Unix),
+        }
+    }
+}
+
+/// Bind a TCP listener with `SO_REUSEPORT` so several can share `addr`
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    anyhow::bail!("num_acceptors > 1 needs SO_REUSEPORT, which is only supported on Unix platforms")
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use tokio::net::{UnixListener, UnixStream};
+    use tracing::{info, warn};
+
+    /// A bound Unix domain socket; the socket file is removed on drop
+    pub(in crate::server) struct UnixSocket {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl UnixSocket {
+        /// Bind `path`, replacing a stale socket file left by an earlier run,
+        /// and set the file's permission bits to `mode`
+        pub(super) fn bind(path: &Path, mode: u32) -> anyhow::Result<Self> {
+            match std::fs::symlink_metadata(path) {
+                Ok(meta) if meta.file_type().is_socket() => {
+                    warn!("Removing stale socket file {}", path.display());
+                    std::fs::remove_file(path)?;
+                }
+                Ok(_) => anyhow::bail!("{} exists and is not a socket", path.display()),
+                Err(_) => {}
+            }
+
+            let listener = UnixListener::bind(path)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+            info!("Server listening on {} (mode {:o})", path.display(), mode);
+            Ok(Self {
+                listener,
+                path: path.to_path_buf(),
+            })
+        }
+
+        /// Accept the next connection
+        pub(super) async fn accept(&self) -> std::io::Result<UnixStream> {
+            self.listener.accept().await.map(|(stream, _)| stream)
+        }
+    }
+
+    impl Drop for UnixSocket {
+        fn drop(&mut self) {
+            if let Err(e) = std::fs::remove_file(&self.path) {
+                warn!(
+                    "Failed to remove socket file {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_unix_socket_lifecycle() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("petracache.sock");
+        // Left behind by a previous run that didn't shut down cleanly
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let config = ServerConfig {
+            listen_addr: String::new(),
+            unix_socket_path: Some(path.clone()),
+            unix_socket_mode: 0o660,
+            ..ServerConfig::default()
+        };
+        let listeners = Listener::bind_all(&config).await.unwrap();
+        assert_eq!(listeners.len(), 1);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        assert!(matches!(listeners[0].accept().await, Ok(Accepted::Unix(_))));
+
+        drop(listeners);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_bind_refuses_regular_file_and_no_listener() {
+        let tmp_dir = TempDir::new().unwrap();
+        let path = tmp_dir.path().join("not-a-socket");
+        std::fs::write(&path, b"data").unwrap();
+
+        let config = ServerConfig {
+            listen_addr: String::new(),
+            unix_socket_path: Some(path.clone()),
+            ..ServerConfig::default()
+        };
+        assert!(Listener::bind_all(&config).await.is_err());
+        assert!(path.exists());
+
+        let config = ServerConfig {
+            listen_addr: String::new(),
+            ..ServerConfig::default()
+        };
+        assert!(Listener::bind_all(&config).await.is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reuse_port_acceptors_share_traffic() {
+        let config = ServerConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            num_acceptors: 2,
+            ..ServerConfig::default()
+        };
+        let listeners = Listener::bind_all(&config).await.unwrap();
+        assert_eq!(listeners.len(), 2);
+        let addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| match listener {
+                Listener::Tcp(tcp) => tcp.local_addr().unwrap(),
+                Listener::Unix(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(addrs[0], addrs[1]);
+
+        // The kernel hashes each connection to one socket; with enough
+        // clients both sockets get some
+        let mut clients = Vec::new();
+        for _ in 0..64 {
+            clients.push(TcpStream::connect(addrs[0]).await.unwrap());
+        }
+        let mut accepted = [0; 2];
+        for _ in 0..clients.len() {
+            tokio::select! {
+                result = listeners[0].accept() => { result.unwrap(); accepted[0] += 1; }
+                result = listeners[1].accept() => { result.unwrap(); accepted[1] += 1; }
+            }
+        }
+        assert!(accepted[0] > 0 && accepted[1] > 0, "{accepted:?}");
+    }
+}
@@ -0,0 +1,226 @@
+//! Handlers for the memcached meta text protocol (`mg`, `ms`, `md`, `ma`, `mn`)
+//!
+//! Meta commands map onto the same storage calls as their classic
+//! counterparts; only the reply format differs. With `q`, replies that carry
+//! no information (EN for `mg`, HD for mutations, NF for `md`) are omitted so
+//! pipelines can be fenced with a trailing `mn`.
+
+use super::Server;
+use crate::StorageError;
+use crate::protocol::{MetaFlags, MetaSetMode, ResponseWriter, base64};
+use crate::storage::{CasOutcome, StoredValue, current_timestamp};
+use std::sync::Arc;
+
+/// Echo the `k` and `O` return flags, then close the meta line
+///
+/// Base64 keys (`b`) are re-encoded and flagged with `b` in the reply.
+fn finish(response: &mut ResponseWriter, key: &[u8], flags: &MetaFlags<'_>, data: Option<&[u8]>) {
+    if flags.return_key {
+        if flags.base64 {
+            response.meta_flag(b'k', &base64::encode(key));
+            response.meta_flag(b'b', b"");
+        } else {
+            response.meta_flag(b'k', key);
+        }
+    }
+    if let Some(opaque) = &flags.opaque {
+        response.meta_flag(b'O', opaque);
+    }
+    response.meta_end(data);
+}
+
+/// Write a status-only meta reply (HD, EN, NS, EX, NF)
+fn status(response: &mut ResponseWriter, code: &str, key: &[u8], flags: &MetaFlags<'_>) {
+    response.meta_status(code);
+    finish(response, key, flags, None);
+}
+
+/// Handle mg (meta get); `T` also updates the expiration like `gat`
+pub fn handle_get(
+    server: &Arc<Server>,
+    key: &[u8],
+    flags: &MetaFlags<'_>,
+    response: &mut ResponseWriter,
+) {
+    let result = match flags.ttl {
+        Some(ttl) => {
+            server.metrics.cmd_touch.inc();
+            server.storage.get_and_touch(key, server.exptime(ttl))
+        }
+        None => server.storage.get(key),
+    };
+
+    let value = match result {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            server.record_get(key, None);
+            if !flags.quiet {
+                status(response, "EN", key, flags);
+            }
+            return;
+        }
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+            return;
+        }
+    };
+
+    server.record_get(key, Some(&value));
+    if flags.return_value {
+        response.meta_value(value.data.len());
+    } else {
+        response.meta_status("HD");
+    }
+    if flags.return_flags {
+        response.meta_flag_u64(b'f', u64::from(value.flags));
+    }
+    if flags.return_cas {
+        response.meta_flag_u64(b'c', value.cas);
+    }
+    if flags.return_ttl {
+        if value.expire_at == 0 {
+            response.meta_flag(b't', b"-1");
+        } else {
+            response.meta_flag_u64(b't', value.expire_at.saturating_sub(current_timestamp()));
+        }
+    }
+    if flags.return_size {
+        response.meta_flag_u64(b's', value.data.len() as u64);
+    }
+    let data = flags.return_value.then_some(value.data.as_slice());
+    finish(response, key, flags, data);
+}
+
+/// Handle ms (meta set); `C` turns a plain set into a compare-and-swap
+pub fn handle_set(
+    server: &Arc<Server>,
+    key: &[u8],
+    data: &[u8],
+    mode: MetaSetMode,
+    flags: &MetaFlags<'_>,
+    response: &mut ResponseWriter,
+) {
+    let value = StoredValue::new(
+        flags.client_flags.unwrap_or(0),
+        server.exptime(flags.ttl.unwrap_or(0)),
+        data.to_vec(),
+    );
+
+    let storage = &server.storage;
+    let metrics = &server.metrics;
+    metrics.observe_store(key, data.len());
+    let result = match (mode, flags.compare_cas) {
+        (MetaSetMode::Set, Some(cas_unique)) => {
+            metrics.cmd_cas.inc();
+            storage.cas(key, value, cas_unique)
+        }
+        (MetaSetMode::Set, None) => {
+            metrics.cmd_set.inc();
+            storage.set(key, value).map(|()| CasOutcome::Stored)
+        }
+        (MetaSetMode::Add, _) => {
+            metrics.cmd_add.inc();
+            storage.add(key, value).map(stored_or_not)
+        }
+        (MetaSetMode::Replace, _) => {
+            metrics.cmd_replace.inc();
+            storage.replace(key, value).map(stored_or_not)
+        }
+        (MetaSetMode::Append, _) => {
+            metrics.cmd_append.inc();
+            storage.append(key, data).map(stored_or_not)
+        }
+        (MetaSetMode::Prepend, _) => {
+            metrics.cmd_prepend.inc();
+            storage.prepend(key, data).map(stored_or_not)
+        }
+    };
+
+    match result {
+        Ok(CasOutcome::Stored) => {
+            if !flags.quiet {
+                status(response, "HD", key, flags);
+            }
+        }
+        // add/replace/append/prepend report NS; only cas distinguishes EX and NF
+        Ok(CasOutcome::Exists) if flags.compare_cas.is_some() => {
+            status(response, "EX", key, flags);
+        }
+        Ok(CasOutcome::Exists) => status(response, "NS", key, flags),
+        Ok(CasOutcome::NotFound) => status(response, "NF", key, flags),
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Map a conditional store result onto the shared outcome type
+fn stored_or_not(stored: bool) -> CasOutcome {
+    if stored {
+        CasOutcome::Stored
+    } else {
+        CasOutcome::Exists
+    }
+}
+
+/// Handle md (meta delete)
+pub fn handle_delete(
+    server: &Arc<Server>,
+    key: &[u8],
+    flags: &MetaFlags<'_>,
+    response: &mut ResponseWriter,
+) {
+    match server.storage.delete(key) {
+        Ok(deleted) => {
+            if !flags.quiet {
+                status(response, if deleted { "HD" } else { "NF" }, key, flags);
+            }
+        }
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
+
+/// Handle ma (meta arithmetic); `D` sets the delta (default 1)
+pub fn handle_arithmetic(
+    server: &Arc<Server>,
+    key: &[u8],
+    incr: bool,
+    flags: &MetaFlags<'_>,
+    response: &mut ResponseWriter,
+) {
+    if incr {
+        server.metrics.cmd_incr.inc();
+    } else {
+        server.metrics.cmd_decr.inc();
+    }
+
+    match server
+        .storage
+        .incr_decr(key, flags.delta.unwrap_or(1), incr)
+    {
+        Ok(Some(value)) if flags.return_value => {
+            let mut itoa_buf = itoa::Buffer::new();
+            let number = itoa_buf.format(value).as_bytes();
+            response.meta_value(number.len());
+            finish(response, key, flags, Some(number));
+        }
+        Ok(Some(_)) => {
+            if !flags.quiet {
+                status(response, "HD", key, flags);
+            }
+        }
+        Ok(None) => status(response, "NF", key, flags),
+        Err(StorageError::NotNumeric) => {
+            response.client_error("cannot increment or decrement non-numeric value");
+        }
+        Err(e) => {
+            server.metrics.storage_errors.inc();
+            response.server_error(&e.to_string());
+        }
+    }
+}
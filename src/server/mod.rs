@@ -1,25 +1,87 @@
 //! Main TCP server for memcached protocol
 
+mod acl;
+mod auth;
+mod batch;
+mod binary;
 mod connection;
+mod conns;
 mod handler;
+mod limiter;
+mod listener;
+mod meta;
+mod proxy;
+mod slow_log;
+mod tls;
 
+pub use conns::{ConnSnapshot, ConnState, ConnectionRegistry};
+
+use crate::audit::AuditLog;
 use crate::config::ServerConfig;
+use crate::metrics::HitWindow;
 use crate::metrics::Metrics;
-use crate::storage::RocksStorage;
+use crate::protocol::{CommandKind, CommandSet};
+use crate::storage::{RocksStorage, StoredValue, TtlPolicy, current_timestamp};
+use crate::storage_health::StorageHealth;
+use crate::upstream::Upstream;
+use acl::IpAllowlist;
+use limiter::{ConnectionLimit, IpLimiter};
+use listener::{Accepted, Listener};
+use prometheus::IntGauge;
+use slow_log::SlowLog;
+use std::fmt;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, warn};
 
 /// Main server struct
+///
+/// `config` is the configuration the server started with. The settings a
+/// SIGHUP reload may change (see `reconfigure`) are kept separately and read
+/// from there.
 pub struct Server {
     pub(crate) config: ServerConfig,
     pub(crate) storage: Arc<RocksStorage>,
     pub(crate) metrics: Arc<Metrics>,
-    connection_semaphore: Arc<Semaphore>,
+    /// Read-through for GET misses (`[upstream] addr` set)
+    pub(crate) upstream: Option<Arc<Upstream>>,
+    /// Reported by `stats` as `storage_healthy`, when set
+    pub(crate) storage_health: Option<Arc<StorageHealth>>,
+    /// `max_connections`
+    connection_limit: ConnectionLimit,
+    /// Open connections per peer IP, limited by `max_connections_per_ip`
+    ip_limiter: Arc<IpLimiter>,
+    /// `connection_timeout_secs`
+    connection_timeout_secs: AtomicU64,
+    /// Networks TCP clients may connect from, when `allowed_cidrs` is set
+    allowlist: Option<IpAllowlist>,
     pub(crate) cancel_token: CancellationToken,
+    /// Unix timestamp when the server was created (for `stats uptime`)
+    pub(crate) started_at: u64,
+    /// Window of the `petracache_hit_ratio` gauge, when computed
+    hit_ratio_window: Option<Duration>,
+    /// Logs commands over `slow_log_threshold_ms`
+    slow_log: SlowLog,
+    /// Open connections, for `stats conns` and `/debug/connections`
+    pub(crate) connections: Arc<ConnectionRegistry>,
+    /// `default_ttl_secs`, `max_ttl_secs` and `ttl_jitter_percent`, applied
+    /// to client exptimes
+    ttl_policy: TtlPolicy,
+    /// Commands refused by `disabled_commands`, as `CommandSet` bits
+    denied_commands: AtomicU32,
+    /// Refuse commands that change stored data (`read_only`, toggled by
+    /// `POST /admin/readonly`)
+    pub(crate) read_only: Arc<AtomicBool>,
+    /// Records destructive commands (`[audit] path` set)
+    pub(crate) audit: Option<Arc<AuditLog>>,
 }
 
 impl Server {
@@ -30,65 +92,469 @@ impl Server {
         metrics: Arc<Metrics>,
         cancel_token: CancellationToken,
     ) -> Self {
-        let connection_semaphore = Arc::new(Semaphore::new(config.max_connections));
+        let connection_limit = ConnectionLimit::new(config.max_connections);
+        let ip_limiter = Arc::new(IpLimiter::new(config.max_connections_per_ip));
+        let connection_timeout_secs = AtomicU64::new(config.connection_timeout_secs);
+        let allowlist = IpAllowlist::new(&config.allowed_cidrs);
+        let slow_log = SlowLog::new(&config);
+        let denied_commands = AtomicU32::new(config.disabled_commands.bits());
+        let read_only = Arc::new(AtomicBool::new(config.read_only));
+        let ttl_policy = TtlPolicy {
+            default_secs: storage.config().default_ttl_secs,
+            max_secs: storage.config().max_ttl_secs,
+            jitter_percent: storage.config().ttl_jitter_percent,
+        };
+        metrics.read_only.set(i64::from(config.read_only));
 
         Self {
             config,
             storage,
             metrics,
-            connection_semaphore,
+            upstream: None,
+            storage_health: None,
+            connection_limit,
+            ip_limiter,
+            connection_timeout_secs,
+            allowlist,
             cancel_token,
+            started_at: current_timestamp(),
+            hit_ratio_window: None,
+            slow_log,
+            connections: Arc::new(ConnectionRegistry::new()),
+            ttl_policy,
+            denied_commands,
+            read_only,
+            audit: None,
         }
     }
 
+    /// Count a get answered from storage, overall and per namespace
+    pub(crate) fn record_get(&self, key: &[u8], value: Option<&StoredValue>) {
+        match value {
+            Some(value) => {
+                self.metrics.get_hits.inc();
+                self.metrics.observe_hit(key, value.data.len());
+            }
+            None => self.metrics.get_misses.inc(),
+        }
+        self.storage.record_get(key, value.is_some());
+    }
+
+    /// Apply the settings of `config` that can change while running:
+    /// connection limits, the idle timeout, disabled commands and the slow
+    /// log. Everything else, `read_only` included, is left alone
+    pub fn reconfigure(&self, config: &ServerConfig) {
+        self.connection_limit.resize(config.max_connections);
+        self.ip_limiter
+            .set_max_per_ip(config.max_connections_per_ip);
+        self.connection_timeout_secs
+            .store(config.connection_timeout_secs, Ordering::Relaxed);
+        self.denied_commands
+            .store(config.disabled_commands.bits(), Ordering::Relaxed);
+        self.slow_log.reconfigure(config);
+    }
+
+    /// The configuration in effect: `config` with the settings changed by
+    /// `reconfigure` and the current read-only mode
+    pub fn settings(&self) -> ServerConfig {
+        let mut config = self.config.clone();
+        config.max_connections = self.connection_limit.max();
+        config.max_connections_per_ip = self.ip_limiter.max_per_ip();
+        config.connection_timeout_secs = self.connection_timeout_secs();
+        config.disabled_commands = self.denied_commands();
+        config.slow_log_threshold_ms = self.slow_log.threshold_ms();
+        config.slow_log_hash_keys = self.slow_log.hash_keys();
+        config.slow_log_max_per_sec = self.slow_log.max_per_sec();
+        config.read_only = self.is_read_only();
+        config
+    }
+
+    /// Turn read-only mode on or off
+    pub fn set_read_only(&self, enabled: bool) {
+        self.read_only.store(enabled, Ordering::SeqCst);
+        self.metrics.read_only.set(i64::from(enabled));
+    }
+
+    /// The exptime to store for a client's `exptime`, after
+    /// `default_ttl_secs`, `ttl_jitter_percent` and `max_ttl_secs`
+    pub(crate) fn exptime(&self, exptime: i64) -> i64 {
+        self.ttl_policy.apply(exptime)
+    }
+
+    pub(crate) fn connection_timeout_secs(&self) -> u64 {
+        self.connection_timeout_secs.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn denied_commands(&self) -> CommandSet {
+        CommandSet::from_bits(self.denied_commands.load(Ordering::Relaxed))
+    }
+
+    /// The slow log, if enabled
+    fn slow_log(&self) -> Option<&SlowLog> {
+        self.slow_log.enabled().then_some(&self.slow_log)
+    }
+
+    /// Whether `kind` is disabled; if so, counts it under `name`
+    pub(crate) fn is_denied(&self, kind: CommandKind, name: &str) -> bool {
+        if !self.denied_commands().contains(kind) {
+            return false;
+        }
+        self.metrics
+            .commands_denied
+            .with_label_values(&[name])
+            .inc();
+        true
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Whether `kind` is refused because the server is read-only; if so,
+    /// counts it under `name`
+    pub(crate) fn rejects_write(&self, kind: CommandKind, name: &str) -> bool {
+        if !kind.is_write() || !self.is_read_only() {
+            return false;
+        }
+        self.metrics
+            .commands_denied
+            .with_label_values(&[name])
+            .inc();
+        true
+    }
+
+    /// Fill GET misses from an upstream memcached
+    #[must_use]
+    pub fn with_upstream(mut self, upstream: Arc<Upstream>) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Report `storage_health` in `stats`
+    #[must_use]
+    pub fn with_storage_health(mut self, storage_health: Arc<StorageHealth>) -> Self {
+        self.storage_health = Some(storage_health);
+        self
+    }
+
+    /// Track connections in `connections`, shared with the health server
+    #[must_use]
+    pub fn with_connections(mut self, connections: Arc<ConnectionRegistry>) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Share the read-only flag with the health server's toggle
+    #[must_use]
+    pub fn with_read_only(mut self, read_only: Arc<AtomicBool>) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Record destructive commands in `audit`
+    #[must_use]
+    pub fn with_audit_log(mut self, audit: Arc<AuditLog>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Keep `petracache_hit_ratio` at the hit ratio of the last `window`
+    #[must_use]
+    pub fn with_hit_ratio_window(mut self, window: Duration) -> Self {
+        self.hit_ratio_window = (!window.is_zero()).then_some(window);
+        self
+    }
+
     /// Run the server: bind and accept connections until shutdown signal
+    ///
+    /// Every listener gets its own accept loop. On shutdown the listeners are
+    /// closed first; open connections finish the command they are running
+    /// and close, and whatever is left after `shutdown_grace_secs` is aborted.
     pub async fn run(self: Arc<Self>) -> anyhow::Result<()> {
-        let addr: SocketAddr = self.config.listen_addr.parse()?;
-        let listener = TcpListener::bind(addr).await?;
-        info!("Server listening on {}", addr);
+        let listeners = Listener::bind_all(&self.config).await?;
+        self.accept_on(listeners).await
+    }
+
+    /// Like `run`, accepting on `listener` instead of binding `listen_addr`
+    /// and `unix_socket_path` (e.g. a listener on port 0 whose port the
+    /// caller needs to know)
+    pub async fn run_on(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        self.accept_on(vec![Listener::Tcp(listener)]).await
+    }
+
+    async fn accept_on(self: Arc<Self>, listeners: Vec<Listener>) -> anyhow::Result<()> {
+        let tcp_handshake = Handshake {
+            proxy_protocol: self.config.proxy_protocol,
+            tls: self.config.tls.as_ref().map(tls::acceptor).transpose()?,
+        };
 
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            let handshake = match listener {
+                Listener::Tcp(_) => tcp_handshake.clone(),
+                #[cfg(unix)]
+                Listener::Unix(_) => Handshake::default(),
+            };
+            acceptors.spawn(Arc::clone(&self).accept_loop(listener, handshake));
+        }
+        if let Some(window) = self.hit_ratio_window {
+            tokio::spawn(Arc::clone(&self).track_hit_ratio(window));
+        }
+
+        self.cancel_token.cancelled().await;
+        info!("Server shutting down");
+        while let Some(result) = acceptors.join_next().await {
+            if let Err(e) = result {
+                error!("Accept loop failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sample the get counters every second until shutdown, updating the
+    /// hit ratio gauge; it keeps its value while nothing is read
+    async fn track_hit_ratio(self: Arc<Self>, window: Duration) {
+        let mut hits = HitWindow::new(window);
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
         loop {
             tokio::select! {
-                _ = self.cancel_token.cancelled() => {
-                    info!("Server shutting down");
-                    break;
+                () = self.cancel_token.cancelled() => break,
+                now = ticker.tick() => {
+                    let ratio = hits.sample(
+                        now.into_std(),
+                        self.metrics.get_hits.get(),
+                        self.metrics.get_misses.get(),
+                    );
+                    if let Some(ratio) = ratio {
+                        self.metrics.hit_ratio.set(ratio);
+                    }
                 }
+            }
+        }
+    }
+
+    /// Accept connections on one listener until shutdown, then drain them
+    async fn accept_loop(self: Arc<Self>, listener: Listener, handshake: Handshake) {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                _ = self.cancel_token.cancelled() => break,
                 result = listener.accept() => {
                     match result {
-                        Ok((stream, peer_addr)) => self.handle_new_connection(stream, peer_addr),
+                        Ok(Accepted::Tcp(stream, peer_addr)) => {
+                            if let Err(e) = stream.set_nodelay(true) {
+                                warn!("Failed to set TCP_NODELAY: {}", e);
+                            }
+                            self.handle_new_connection(&mut connections, stream, Some(peer_addr), &handshake);
+                        }
+                        #[cfg(unix)]
+                        Ok(Accepted::Unix(stream)) => {
+                            self.handle_new_connection(&mut connections, stream, None, &handshake);
+                        }
                         Err(e) => error!("Accept error: {}", e),
                     }
                 }
+                // Reap finished connection tasks
+                Some(_) = connections.join_next(), if !connections.is_empty() => {}
             }
         }
 
-        Ok(())
+        // Stop accepting (and unlink the Unix socket) before draining
+        drop(listener);
+        self.drain(connections).await;
     }
 
-    /// Set up a new connection: configure socket, check limits, spawn handler
-    fn handle_new_connection(self: &Arc<Self>, stream: TcpStream, peer_addr: SocketAddr) {
-        if let Err(e) = stream.set_nodelay(true) {
-            warn!("Failed to set TCP_NODELAY: {}", e);
+    /// Wait for open connections to close, aborting them after the grace period
+    async fn drain(&self, mut connections: JoinSet<()>) {
+        if connections.is_empty() {
+            return;
+        }
+        info!("Draining {} connections", connections.len());
+
+        let grace = Duration::from_secs(self.config.shutdown_grace_secs);
+        let drained = tokio::time::timeout(grace, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "Aborting {} connections after {}s shutdown grace period",
+                connections.len(),
+                self.config.shutdown_grace_secs
+            );
+            // Aborted handlers drop their `ActiveConnection`
+            connections.shutdown().await;
         }
+    }
 
-        match self.connection_semaphore.clone().try_acquire_owned() {
-            Ok(permit) => {
+    /// Set up a new connection: check limits, spawn handler
+    fn handle_new_connection<S>(
+        self: &Arc<Self>,
+        connections: &mut JoinSet<()>,
+        stream: S,
+        peer_addr: Option<SocketAddr>,
+        handshake: &Handshake,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // With PROXY headers the client address is only known in `serve`
+        if !handshake.proxy_protocol && !self.is_allowed(peer_addr) {
+            return;
+        }
+        match self.connection_limit.try_acquire() {
+            Some(permit) => {
                 self.metrics.total_connections.inc();
-                self.metrics.active_connections.inc();
-                debug!("Accepted connection from {}", peer_addr);
+                let active = ActiveConnection::new(&self.metrics.active_connections);
 
                 let server = Arc::clone(self);
-                tokio::spawn(async move {
-                    if let Err(e) = connection::handle(server, stream, permit).await {
-                        debug!("Connection error: {}", e);
-                    }
-                });
+                connections.spawn(server.serve(
+                    stream,
+                    peer_addr,
+                    handshake.clone(),
+                    (permit, active),
+                ));
             }
-            Err(_) => {
+            None => {
                 self.metrics.rejected_connections.inc();
-                warn!("Connection limit reached, rejecting {}", peer_addr);
+                warn!("Connection limit reached, rejecting {}", Peer(peer_addr));
                 drop(stream);
             }
         }
     }
+
+    /// Run the connection's handshakes, then its command loop
+    async fn serve<S>(
+        self: Arc<Self>,
+        mut stream: S,
+        mut peer_addr: Option<SocketAddr>,
+        handshake: Handshake,
+        (permit, _active): (OwnedSemaphorePermit, ActiveConnection),
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if handshake.proxy_protocol {
+            match self
+                .bounded("PROXY header", proxy::read_header(&mut stream))
+                .await
+            {
+                Ok(Some(client_addr)) => peer_addr = Some(client_addr),
+                Ok(None) => {}
+                Err(e) => {
+                    self.metrics.proxy_protocol_errors.inc();
+                    debug!("Bad PROXY header from {}: {}", Peer(peer_addr), e);
+                    return;
+                }
+            }
+        }
+        if handshake.proxy_protocol && !self.is_allowed(peer_addr) {
+            return;
+        }
+        let peer = Peer(peer_addr);
+
+        // Held until the connection is done, however it ends
+        let _ip_slot = match peer_addr {
+            Some(addr) => match self.ip_limiter.try_acquire(addr.ip()) {
+                Some(slot) => Some(slot),
+                None => {
+                    self.metrics.rejected_connections_per_ip.inc();
+                    if self.ip_limiter.should_warn() {
+                        warn!(
+                            "Per-IP connection limit ({}) reached, rejecting {}",
+                            self.ip_limiter.max_per_ip(),
+                            addr.ip()
+                        );
+                    }
+                    return;
+                }
+            },
+            None => None,
+        };
+        debug!("Accepted connection from {}", peer);
+
+        let result = match handshake.tls {
+            Some(acceptor) => match self.bounded("TLS handshake", acceptor.accept(stream)).await {
+                Ok(stream) => connection::handle(self, stream, peer_addr, permit).await,
+                Err(e) => {
+                    self.metrics.tls_handshake_errors.inc();
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            },
+            None => connection::handle(self, stream, peer_addr, permit).await,
+        };
+        if let Err(e) = result {
+            debug!("Connection error from {}: {}", peer, e);
+        }
+    }
+
+    /// Whether `allowed_cidrs` admits the client; Unix socket clients are
+    /// always admitted
+    fn is_allowed(&self, peer_addr: Option<SocketAddr>) -> bool {
+        match (&self.allowlist, peer_addr) {
+            (Some(allowlist), Some(addr)) if !allowlist.allows(addr.ip()) => {
+                self.metrics.rejected_connections_acl.inc();
+                debug!("Rejecting {}: not in allowed_cidrs", addr);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Bound a connection setup step by `connection_timeout_secs` when set
+    async fn bounded<T>(
+        &self,
+        step: &str,
+        fut: impl Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        match self.connection_timeout_secs() {
+            0 => fut.await,
+            secs => tokio::time::timeout(Duration::from_secs(secs), fut)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("{step} timed out"),
+                    ))
+                }),
+        }
+    }
+}
+
+/// Per-listener steps before a connection speaks memcached
+#[derive(Clone, Default)]
+struct Handshake {
+    /// Read a PROXY protocol header first (TCP only)
+    proxy_protocol: bool,
+    /// Then run a TLS handshake (TCP only)
+    tls: Option<TlsAcceptor>,
+}
+
+/// Counts a connection in `active_connections` until dropped, however the
+/// connection ends (including being aborted at shutdown)
+struct ActiveConnection(IntGauge);
+
+impl ActiveConnection {
+    fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// A connection's peer for log messages: its address, or the Unix socket
+#[derive(Clone, Copy)]
+struct Peer(Option<SocketAddr>);
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(addr) => addr.fmt(f),
+            None => f.write_str("unix socket"),
+        }
+    }
 }
@@ -0,0 +1,256 @@
+//! PROXY protocol (v1 and v2) header parsing
+//!
+//! Load balancers send the header before any client data. It is read with
+//! exact-length reads so nothing after it is consumed: the rest of the
+//! stream goes to the TLS handshake or the command parser untouched.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// First 12 bytes of a v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Fixed part of a v2 header: signature, version/command, family, length
+const V2_HEADER_LEN: usize = 16;
+/// Longest v1 header allowed by the spec, CRLF included
+const V1_MAX_LEN: usize = 107;
+/// Shortest v1 header: `PROXY UNKNOWN\r\n`
+const V1_MIN_LEN: usize = 15;
+
+/// Read the PROXY header at the start of `stream`
+///
+/// Returns the client address it carries, or `None` for health checks and
+/// other connections the proxy itself originates (v1 `UNKNOWN`, v2 `LOCAL`
+/// or a non-IP family), which keep the socket's peer address.
+pub(super) async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; V1_MIN_LEN];
+    stream.read_exact(&mut buf).await?;
+
+    if buf.starts_with(V2_SIGNATURE) {
+        buf.resize(V2_HEADER_LEN, 0);
+        stream.read_exact(&mut buf[V1_MIN_LEN..]).await?;
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await?;
+        parse_v2(&buf, &body)
+    } else if buf.starts_with(b"PROXY ") {
+        // v1 has no length prefix; byte reads keep the data after it intact
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() == V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            buf.push(stream.read_u8().await?);
+        }
+        parse_v1(&buf[..buf.len() - 2])
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Parse a v1 header line (without its CRLF)
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            proto @ ("TCP4" | "TCP6"),
+            src,
+            _dst,
+            src_port,
+            _dst_port,
+        ] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 address"))?;
+            if ip.is_ipv4() != (*proto == "TCP4") {
+                return Err(invalid("PROXY v1 address does not match protocol"));
+            }
+            let port: u16 = src_port.parse().map_err(|_| invalid("bad PROXY v1 port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+/// Parse a v2 header: the fixed 16 bytes and the address block after it
+fn parse_v2(header: &[u8], body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if header[12] >> 4 != 2 {
+        return Err(invalid("unsupported PROXY v2 version"));
+    }
+    match header[12] & 0x0f {
+        // LOCAL: sent by the proxy itself, e.g. health checks
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    match header[13] >> 4 {
+        // AF_INET: src addr, dst addr, src port, dst port
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).unwrap());
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        1 | 2 => Err(invalid("truncated PROXY v2 address block")),
+        // AF_UNSPEC, AF_UNIX: no IP address to report
+        0 | 3 => Ok(None),
+        _ => Err(invalid("unsupported PROXY v2 address family")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::server::Handshake;
+    use crate::testing::test_server;
+    use tempfile::TempDir;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinSet;
+
+    /// Build a v2 PROXY header for a TCP client
+    fn v2_header(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        let mut body = Vec::new();
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => {
+                header.extend_from_slice(&[0x21, 0x11]);
+                body.extend_from_slice(&s.octets());
+                body.extend_from_slice(&d.octets());
+            }
+            (IpAddr::V6(s), IpAddr::V6(d)) => {
+                header.extend_from_slice(&[0x21, 0x21]);
+                body.extend_from_slice(&s.octets());
+                body.extend_from_slice(&d.octets());
+            }
+            _ => unreachable!(),
+        }
+        body.extend_from_slice(&src.port().to_be_bytes());
+        body.extend_from_slice(&dst.port().to_be_bytes());
+        // A TLV the parser should skip
+        body.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        header.extend_from_slice(&u16::try_from(body.len()).unwrap().to_be_bytes());
+        header.extend_from_slice(&body);
+        header
+    }
+
+    /// Feed `input` to `read_header` and return its result and what's left
+    async fn read(input: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(input).await.unwrap();
+        drop(client);
+        let result = read_header(&mut server).await;
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        (result, rest)
+    }
+
+    #[tokio::test]
+    async fn test_v1() {
+        let (result, rest) =
+            read(b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 11211\r\nget foo\r\n").await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"get foo\r\n");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 11211\r\n").await;
+        assert_eq!(result.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nversion\r\n").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"version\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let src = "192.0.2.1:56324".parse().unwrap();
+        let mut input = v2_header(src, "10.0.0.1:11211".parse().unwrap());
+        input.extend_from_slice(b"get foo\r\n");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), Some(src));
+        assert_eq!(rest, b"get foo\r\n");
+
+        let src = "[2001:db8::1]:4000".parse().unwrap();
+        let input = v2_header(src, "[2001:db8::2]:11211".parse().unwrap());
+        assert_eq!(read(&input).await.0.unwrap(), Some(src));
+
+        // LOCAL with no address block
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        input.extend_from_slice(b"version\r\n");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"version\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_missing_or_malformed_header() {
+        for input in [
+            &b"get foo bar baz\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 10.0.0.1 56324 11211\r\n",
+            b"PROXY TCP4 192.0.2.1 10.0.0.1 99999 11211\r\n",
+            b"\r\n\r\n\0\r\nQUIT\n\x31\x11\x00\x00",
+            b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x04\x01\x02\x03\x04",
+            b"short",
+        ] {
+            let (result, _) = read(input).await;
+            assert!(result.is_err(), "accepted {input:?}");
+        }
+
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_server_requires_header() {
+        let tmp_dir = TempDir::new().unwrap();
+        let server = test_server(&tmp_dir, ServerConfig::default());
+        let handshake = Handshake {
+            proxy_protocol: true,
+            tls: None,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut connections = JoinSet::new();
+
+        for (input, reply) in [
+            (
+                &b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 11211\r\nversion\r\nquit\r\n"[..],
+                true,
+            ),
+            (b"version\r\nversion\r\n", false),
+        ] {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, peer_addr) = listener.accept().await.unwrap();
+            server.handle_new_connection(&mut connections, stream, Some(peer_addr), &handshake);
+
+            client.write_all(input).await.unwrap();
+            client.shutdown().await.unwrap();
+            let mut buf = Vec::new();
+            // A rejected connection may be reset, it still had unread input
+            let _ = client.read_to_end(&mut buf).await;
+            assert_eq!(buf.starts_with(b"VERSION "), reply, "{buf:?}");
+        }
+
+        while connections.join_next().await.is_some() {}
+        assert_eq!(server.metrics.proxy_protocol_errors.get(), 1);
+        // Whether they quit or were rejected, neither is still counted
+        assert_eq!(server.metrics.active_connections.get(), 0);
+    }
+}
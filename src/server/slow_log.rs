@@ -0,0 +1,326 @@
+//! Slow command log (`server.slow_log_threshold_ms`)
+//!
+//! Latency histograms show that p99 went up, not which keys or commands
+//! did it. Commands that take longer than the threshold are logged with
+//! their key, value size, duration and whether they hit. Entries are
+//! rate-limited to `slow_log_max_per_sec` so a stalled RocksDB can't flood
+//! the log; `petracache_slow_commands_total` counts every slow command
+//! regardless. The settings can change at runtime (SIGHUP reload), so they
+//! are atomics read per command.
+
+use crate::config::ServerConfig;
+use crate::metrics::Metrics;
+use crate::protocol::{Command, ReplyValues};
+use parking_lot::Mutex;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Longest key prefix written to the log
+const MAX_LOGGED_KEY_BYTES: usize = 64;
+
+/// Logs commands slower than the configured threshold
+pub(super) struct SlowLog {
+    /// 0 = disabled
+    threshold_ms: AtomicU64,
+    hash_keys: AtomicBool,
+    max_per_sec: AtomicU32,
+    window: Mutex<RateWindow>,
+}
+
+/// Entries logged in the current one-second window
+struct RateWindow {
+    start: Instant,
+    logged: u32,
+    /// Entries dropped since the last one logged
+    suppressed: u64,
+}
+
+/// What the log reports about a command, captured before it runs (running
+/// it consumes the command)
+pub(super) struct SlowCommand {
+    name: &'static str,
+    key: Option<LoggedKey>,
+    /// Data block size of a storage command
+    data_len: Option<usize>,
+    /// Whether hit or miss applies
+    retrieval: bool,
+}
+
+/// A key as written to the log
+enum LoggedKey {
+    /// The first `MAX_LOGGED_KEY_BYTES`, escaped
+    Prefix { bytes: Vec<u8>, truncated: bool },
+    /// With `slow_log_hash_keys`; the shard hash, so entries for one key
+    /// can be matched up without logging it
+    Hash(u32),
+}
+
+impl fmt::Display for LoggedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoggedKey::Prefix { bytes, truncated } => {
+                write!(f, "{}", bytes.escape_ascii())?;
+                if *truncated {
+                    f.write_str("...")?;
+                }
+                Ok(())
+            }
+            LoggedKey::Hash(hash) => write!(f, "crc32c:{hash:08x}"),
+        }
+    }
+}
+
+impl SlowLog {
+    /// The slow log configured in `config`
+    pub(super) fn new(config: &ServerConfig) -> Self {
+        Self {
+            threshold_ms: AtomicU64::new(config.slow_log_threshold_ms),
+            hash_keys: AtomicBool::new(config.slow_log_hash_keys),
+            max_per_sec: AtomicU32::new(config.slow_log_max_per_sec),
+            window: Mutex::new(RateWindow {
+                start: Instant::now(),
+                logged: 0,
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Whether commands are timed for the log at all
+    pub(super) fn enabled(&self) -> bool {
+        self.threshold_ms() > 0
+    }
+
+    pub(super) fn threshold_ms(&self) -> u64 {
+        self.threshold_ms.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn hash_keys(&self) -> bool {
+        self.hash_keys.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn max_per_sec(&self) -> u32 {
+        self.max_per_sec.load(Ordering::Relaxed)
+    }
+
+    /// Switch to the slow log settings in `config`
+    pub(super) fn reconfigure(&self, config: &ServerConfig) {
+        self.threshold_ms
+            .store(config.slow_log_threshold_ms, Ordering::Relaxed);
+        self.hash_keys
+            .store(config.slow_log_hash_keys, Ordering::Relaxed);
+        self.max_per_sec
+            .store(config.slow_log_max_per_sec, Ordering::Relaxed);
+    }
+
+    /// Capture what an ASCII or meta command's entry would report
+    pub(super) fn describe(&self, cmd: &Command<'_>) -> SlowCommand {
+        self.describe_parts(cmd.name(), cmd.key(), cmd.data_len(), cmd.is_retrieval())
+    }
+
+    pub(super) fn describe_parts(
+        &self,
+        name: &'static str,
+        key: Option<&[u8]>,
+        data_len: Option<usize>,
+        retrieval: bool,
+    ) -> SlowCommand {
+        let hash_keys = self.hash_keys();
+        let key = key.map(|key| {
+            if hash_keys {
+                LoggedKey::Hash(crc32c::crc32c(key))
+            } else {
+                LoggedKey::Prefix {
+                    bytes: key[..key.len().min(MAX_LOGGED_KEY_BYTES)].to_vec(),
+                    truncated: key.len() > MAX_LOGGED_KEY_BYTES,
+                }
+            }
+        });
+        SlowCommand {
+            name,
+            key,
+            data_len,
+            retrieval,
+        }
+    }
+
+    /// Count and log `cmd` if it took longer than the threshold; `reply`
+    /// holds the values it returned
+    pub(super) fn record(
+        &self,
+        metrics: &Metrics,
+        cmd: &SlowCommand,
+        reply: ReplyValues,
+        elapsed: Duration,
+    ) {
+        self.record_commands(metrics, cmd, reply, elapsed, 1);
+    }
+
+    /// Like `record` for `count` pipelined sets written together, which
+    /// all took the batch's time; `cmd` describes the first
+    pub(super) fn record_batch(
+        &self,
+        metrics: &Metrics,
+        cmd: &SlowCommand,
+        count: usize,
+        elapsed: Duration,
+    ) {
+        self.record_commands(metrics, cmd, ReplyValues::default(), elapsed, count);
+    }
+
+    fn record_commands(
+        &self,
+        metrics: &Metrics,
+        cmd: &SlowCommand,
+        reply: ReplyValues,
+        elapsed: Duration,
+        count: usize,
+    ) {
+        let threshold = Duration::from_millis(self.threshold_ms());
+        if threshold.is_zero() || elapsed <= threshold {
+            return;
+        }
+        metrics
+            .slow_commands
+            .with_label_values(&[cmd.name])
+            .inc_by(count as u64);
+        let Some(suppressed) = self.admit(Instant::now()) else {
+            metrics.slow_log_suppressed.inc();
+            return;
+        };
+
+        let value_bytes = cmd.data_len.or((reply.count > 0).then_some(reply.bytes));
+        let hit = cmd.retrieval.then_some(reply.count > 0);
+        warn!(
+            command = cmd.name,
+            key = cmd.key.as_ref().map(tracing::field::display),
+            value_bytes,
+            duration_ms = elapsed.as_secs_f64() * 1000.0,
+            hit,
+            batched = (count > 1).then_some(count),
+            suppressed,
+            "Slow command"
+        );
+    }
+
+    /// Whether an entry may be logged at `now`; if so, the number of
+    /// entries dropped since the last one logged
+    fn admit(&self, now: Instant) -> Option<u64> {
+        let mut window = self.window.lock();
+        if now.duration_since(window.start) >= Duration::from_secs(1) {
+            window.start = now;
+            window.logged = 0;
+        }
+        if window.logged >= self.max_per_sec() {
+            window.suppressed += 1;
+            return None;
+        }
+        window.logged += 1;
+        Some(std::mem::take(&mut window.suppressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn slow_log(hash_keys: bool, max_per_sec: u32) -> SlowLog {
+        SlowLog::new(&ServerConfig {
+            slow_log_threshold_ms: 10,
+            slow_log_hash_keys: hash_keys,
+            slow_log_max_per_sec: max_per_sec,
+            ..ServerConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        assert!(!SlowLog::new(&ServerConfig::default()).enabled());
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let log = SlowLog::new(&ServerConfig::default());
+        let metrics = Metrics::new().unwrap();
+        let cmd = log.describe_parts("get", Some(b"key"), None, true);
+        let slow = Duration::from_millis(20);
+        log.record(&metrics, &cmd, ReplyValues::default(), slow);
+        assert_eq!(metrics.slow_commands.with_label_values(&["get"]).get(), 0);
+
+        log.reconfigure(&ServerConfig {
+            slow_log_threshold_ms: 10,
+            slow_log_hash_keys: true,
+            ..ServerConfig::default()
+        });
+        assert!(log.enabled());
+        log.record(&metrics, &cmd, ReplyValues::default(), slow);
+        assert_eq!(metrics.slow_commands.with_label_values(&["get"]).get(), 1);
+        let described = log.describe_parts("get", Some(b"key"), None, true);
+        assert!(described.key.unwrap().to_string().starts_with("crc32c:"));
+    }
+
+    #[test]
+    fn test_logged_keys() {
+        let log = slow_log(false, 10);
+        let cmd = Command::Get {
+            keys: vec![Cow::Borrowed(&b"user:\x01"[..]), Cow::Borrowed(b"other")],
+        };
+        let described = log.describe(&cmd);
+        assert!(described.retrieval);
+        assert_eq!(described.key.unwrap().to_string(), "user:\\x01");
+
+        let long_key = vec![b'k'; 100];
+        let described = log.describe_parts("set", Some(&long_key), Some(3), false);
+        let logged = described.key.unwrap().to_string();
+        assert_eq!(logged, format!("{}...", "k".repeat(MAX_LOGGED_KEY_BYTES)));
+
+        let log = slow_log(true, 10);
+        let described = log.describe_parts("delete", Some(b"secret"), None, false);
+        let logged = described.key.unwrap().to_string();
+        assert_eq!(logged, format!("crc32c:{:08x}", crc32c::crc32c(b"secret")));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let log = slow_log(false, 2);
+        let start = Instant::now();
+        assert_eq!(log.admit(start), Some(0));
+        assert_eq!(log.admit(start), Some(0));
+        assert_eq!(log.admit(start + Duration::from_millis(500)), None);
+        assert_eq!(log.admit(start + Duration::from_millis(900)), None);
+        // The next window reports what was dropped
+        assert_eq!(log.admit(start + Duration::from_secs(1)), Some(2));
+        assert_eq!(log.admit(start + Duration::from_secs(1)), Some(0));
+    }
+
+    #[test]
+    fn test_counted_even_when_suppressed() {
+        let log = slow_log(false, 1);
+        let metrics = Metrics::new().unwrap();
+        let cmd = log.describe_parts("get", Some(b"key"), None, true);
+        let slow = Duration::from_millis(20);
+
+        log.record(
+            &metrics,
+            &cmd,
+            ReplyValues::default(),
+            Duration::from_millis(5),
+        );
+        assert_eq!(metrics.slow_commands.with_label_values(&["get"]).get(), 0);
+
+        for _ in 0..3 {
+            log.record(&metrics, &cmd, ReplyValues::default(), slow);
+        }
+        log.record_batch(
+            &metrics,
+            &log.describe_parts("set", None, Some(1), false),
+            4,
+            slow,
+        );
+        assert_eq!(metrics.slow_commands.with_label_values(&["get"]).get(), 3);
+        assert_eq!(metrics.slow_commands.with_label_values(&["set"]).get(), 4);
+        assert_eq!(metrics.slow_log_suppressed.get(), 3);
+    }
+}
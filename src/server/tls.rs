@@ -0,0 +1,201 @@
+//! TLS for the memcached port (rustls)
+
+use crate::config::TlsConfig;
+use anyhow::Context;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig, crypto};
+use tracing::info;
+
+/// Build the acceptor from the configured certificate, key and optional
+/// client CA bundle
+///
+/// With `client_ca_path` set, clients without a certificate signed by one
+/// of those CAs are rejected during the handshake.
+pub(super) fn acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let provider = Arc::new(crypto::ring::default_provider());
+    let certs = load_certs(&config.cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .with_context(|| format!("reading TLS key {}", config.key_path.display()))?;
+
+    let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+        .with_safe_default_protocol_versions()?;
+    let builder = match &config.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots.add(cert)?;
+            }
+            let verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+            info!("TLS client certificates required (CA {})", path.display());
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder.with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/// Read every certificate in a PEM file
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| format!("reading certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::config::ServerConfig;
+    use crate::server::{Handshake, Server};
+    use crate::testing::test_server;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinSet;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    /// A CA, a server certificate for `localhost` and a client certificate
+    struct Pki {
+        ca: Certificate,
+        client: (Certificate, KeyPair),
+        config: TlsConfig,
+    }
+
+    fn write_pki(dir: &Path) -> Pki {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::default();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec!["client".to_string()]).unwrap();
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let config = TlsConfig {
+            cert_path: dir.join("server.pem"),
+            key_path: dir.join("server.key"),
+            client_ca_path: Some(dir.join("ca.pem")),
+        };
+        std::fs::write(&config.cert_path, server.pem()).unwrap();
+        std::fs::write(&config.key_path, server_key.serialize_pem()).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+        Pki {
+            ca,
+            client: (client, client_key),
+            config,
+        }
+    }
+
+    /// Accept one TLS connection through the server's accept path
+    async fn serve(tmp_dir: &TempDir, tls: &TlsConfig) -> (TcpStream, Arc<Server>, JoinSet<()>) {
+        let server = test_server(tmp_dir, ServerConfig::default());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let mut connections = JoinSet::new();
+        let handshake = Handshake {
+            proxy_protocol: false,
+            tls: Some(acceptor(tls).unwrap()),
+        };
+        server.handle_new_connection(&mut connections, stream, Some(peer_addr), &handshake);
+        (client, server, connections)
+    }
+
+    fn connector(pki: &Pki, with_client_cert: bool) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(pki.ca.der().clone()).unwrap();
+        let builder =
+            ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+        let config = if with_client_cert {
+            let (cert, key) = &pki.client;
+            builder
+                .with_client_auth_cert(
+                    vec![cert.der().clone()],
+                    PrivateKeyDer::try_from(key.serialize_der()).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_tls_roundtrip() {
+        let tmp_dir = TempDir::new().unwrap();
+        let pki = write_pki(tmp_dir.path());
+        let (tcp, server, _connections) = serve(&tmp_dir, &pki.config).await;
+
+        let stream = connector(&pki, true)
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+            .unwrap();
+        let mut client = Client::new(stream);
+        assert!(client.version().await.unwrap().starts_with("petracache "));
+        assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+        assert_eq!(client.get(b"foo").await.unwrap().unwrap().data, b"bar");
+        assert_eq!(server.metrics.tls_handshake_errors.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_client_without_certificate_is_rejected() {
+        let tmp_dir = TempDir::new().unwrap();
+        let pki = write_pki(tmp_dir.path());
+        let (tcp, server, mut connections) = serve(&tmp_dir, &pki.config).await;
+
+        // With TLS 1.3 the client may only learn of the rejection on read
+        if let Ok(mut stream) = connector(&pki, false)
+            .connect(ServerName::try_from("localhost").unwrap(), tcp)
+            .await
+        {
+            let _ = stream.write_all(b"version\r\n").await;
+            let mut buf = Vec::new();
+            assert!(matches!(stream.read_to_end(&mut buf).await, Err(_) | Ok(0)));
+        }
+
+        connections.join_next().await.unwrap().unwrap();
+        assert_eq!(server.metrics.tls_handshake_errors.get(), 1);
+        assert_eq!(server.metrics.active_connections.get(), 0);
+    }
+
+    #[test]
+    fn test_acceptor_rejects_missing_files() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = TlsConfig {
+            cert_path: tmp_dir.path().join("missing.pem"),
+            key_path: tmp_dir.path().join("missing.key"),
+            client_ca_path: None,
+        };
+        assert!(acceptor(&config).is_err());
+    }
+}
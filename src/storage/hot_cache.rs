@@ -0,0 +1,242 @@
+//! Bounded in-process LRU of recently read values (`hot_cache_size_bytes`)
+//!
+//! Skewed workloads read a small set of keys most of the time; serving them
+//! from here skips RocksDB's lookup and the value decode. Entries are
+//! filled on reads and only ever invalidated by writes, never updated, so
+//! the cache cannot hold a value RocksDB has since replaced or deleted:
+//!
+//! - writers change RocksDB first, then call `invalidate`
+//! - readers take the shard's `generation` before reading RocksDB and pass it
+//!   to `insert`, which drops the value if any write invalidated the shard
+//!   in between
+
+use super::StoredValue;
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+
+/// Gets answered from the hot cache
+pub static HOT_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Gets that went to RocksDB with the hot cache enabled
+pub static HOT_CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of shards (power of two so the index is a mask)
+const SHARDS: usize = 64;
+
+/// Bookkeeping charged per entry on top of key and data
+const ENTRY_OVERHEAD: usize = 96;
+
+/// Sharded LRU of decoded values
+pub(crate) struct HotCache {
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    /// Byte budget of each shard
+    shard_capacity: usize,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<Arc<[u8]>, Entry>,
+    /// Keys by last use, oldest first
+    lru: BTreeMap<u64, Arc<[u8]>>,
+    /// Source of `Entry::last_used` values
+    clock: u64,
+    bytes: usize,
+    /// Bumped by every invalidation
+    generation: u64,
+}
+
+struct Entry {
+    value: StoredValue,
+    last_used: u64,
+}
+
+fn entry_size(key: &[u8], value: &StoredValue) -> usize {
+    key.len() + value.data.len() + ENTRY_OVERHEAD
+}
+
+impl HotCache {
+    /// A cache holding up to about `capacity` bytes of keys and values
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            shard_capacity: capacity / SHARDS,
+        }
+    }
+
+    #[inline]
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let index = self.hasher.hash_one(key) as usize & (SHARDS - 1);
+        &self.shards[index]
+    }
+
+    /// The cached value for `key`, marked as recently used
+    ///
+    /// Expiry is not checked here; the caller decides whether the value is
+    /// still live.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<StoredValue> {
+        let mut shard = self.shard(key).lock();
+        let shard = &mut *shard;
+        let entry = shard.entries.get_mut(key)?;
+        shard.clock += 1;
+        if let Some(key) = shard.lru.remove(&entry.last_used) {
+            shard.lru.insert(shard.clock, key);
+        }
+        entry.last_used = shard.clock;
+        Some(entry.value.clone())
+    }
+
+    /// Current generation of `key`'s shard, to pass to `insert`
+    pub(crate) fn generation(&self, key: &[u8]) -> u64 {
+        self.shard(key).lock().generation
+    }
+
+    /// Cache a value just read from RocksDB, unless the shard was
+    /// invalidated since `generation` was taken
+    ///
+    /// Least recently used entries are evicted to stay within the shard's
+    /// budget; values larger than the whole budget are not cached.
+    pub(crate) fn insert(&self, key: &[u8], value: &StoredValue, generation: u64) {
+        let size = entry_size(key, value);
+        if size > self.shard_capacity {
+            return;
+        }
+        let mut shard = self.shard(key).lock();
+        let shard = &mut *shard;
+        if shard.generation != generation {
+            return;
+        }
+        shard.remove(key);
+        while shard.bytes + size > self.shard_capacity {
+            let Some((_, oldest)) = shard.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = shard.entries.remove(&oldest) {
+                shard.bytes -= entry_size(&oldest, &entry.value);
+            }
+        }
+
+        shard.clock += 1;
+        let key: Arc<[u8]> = Arc::from(key);
+        shard.lru.insert(shard.clock, Arc::clone(&key));
+        let last_used = shard.clock;
+        shard.entries.insert(
+            key,
+            Entry {
+                value: value.clone(),
+                last_used,
+            },
+        );
+        shard.bytes += size;
+    }
+
+    /// Drop `key` after it was written or deleted in RocksDB, and fail any
+    /// concurrent `insert` into its shard
+    pub(crate) fn invalidate(&self, key: &[u8]) {
+        let mut shard = self.shard(key).lock();
+        shard.generation += 1;
+        shard.remove(key);
+    }
+
+    /// Drop every entry after RocksDB changed in bulk, and fail any
+    /// concurrent `insert`
+    pub(crate) fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock();
+            let generation = shard.generation + 1;
+            *shard = Shard {
+                generation,
+                ..Shard::default()
+            };
+        }
+    }
+
+    /// Bytes currently charged to the cache
+    #[cfg(test)]
+    fn bytes(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().bytes).sum()
+    }
+}
+
+impl Shard {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.bytes -= entry_size(key, &entry.value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(data: &[u8]) -> StoredValue {
+        StoredValue::new(0, 0, data.to_vec())
+    }
+
+    #[test]
+    fn test_insert_get_invalidate() {
+        let cache = HotCache::new(1024 * 1024);
+        assert!(cache.get(b"a").is_none());
+
+        let generation = cache.generation(b"a");
+        cache.insert(b"a", &value(b"1"), generation);
+        assert_eq!(cache.get(b"a").unwrap().data, b"1");
+
+        cache.invalidate(b"a");
+        assert!(cache.get(b"a").is_none());
+        assert_eq!(cache.bytes(), 0);
+    }
+
+    #[test]
+    fn test_insert_after_invalidation_is_dropped() {
+        let cache = HotCache::new(1024 * 1024);
+        // A reader took the generation, then a writer invalidated the key
+        // before the reader got to insert what it read
+        let generation = cache.generation(b"a");
+        cache.invalidate(b"a");
+        cache.insert(b"a", &value(b"stale"), generation);
+        assert!(cache.get(b"a").is_none());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // Room for about three entries per shard
+        let cache = HotCache::new(SHARDS * 3 * (ENTRY_OVERHEAD + 16));
+        let shard_keys: Vec<Vec<u8>> = (0u32..)
+            .map(|i| format!("key{i}").into_bytes())
+            .filter(|key| std::ptr::eq(cache.shard(key), cache.shard(b"key0")))
+            .take(4)
+            .collect();
+        for key in &shard_keys[..3] {
+            cache.insert(key, &value(b"v"), cache.generation(key));
+        }
+        // Touch the oldest so the second becomes the eviction candidate
+        assert!(cache.get(&shard_keys[0]).is_some());
+        cache.insert(
+            &shard_keys[3],
+            &value(b"v"),
+            cache.generation(&shard_keys[3]),
+        );
+
+        assert!(cache.get(&shard_keys[0]).is_some());
+        assert!(cache.get(&shard_keys[1]).is_none());
+        assert!(cache.get(&shard_keys[2]).is_some());
+        assert!(cache.get(&shard_keys[3]).is_some());
+    }
+
+    #[test]
+    fn test_oversized_value_not_cached() {
+        let cache = HotCache::new(SHARDS * 1024);
+        let generation = cache.generation(b"big");
+        cache.insert(b"big", &value(&[0u8; 2048]), generation);
+        assert!(cache.get(b"big").is_none());
+        assert_eq!(cache.bytes(), 0);
+    }
+}
@@ -1,9 +1,21 @@
 //! Storage layer for PetraCache
 
+mod hot_cache;
+mod locks;
+mod negative_cache;
 mod rocks;
+pub mod sst_writer;
 mod value;
 
+pub use hot_cache::{HOT_CACHE_HITS, HOT_CACHE_MISSES};
+pub use negative_cache::NEGATIVE_CACHE_HITS;
 pub use rocks::{
-    EXPIRED_KEYS_REMOVED, MemoryUsage, RocksStorage, TTL_COMPACTION_REMOVED, TtlStats,
+    BackupInfo, COMPRESSION_BYTES_SAVED, CORRUPT_VALUES_REMOVED, CasOutcome, DB_RECREATES,
+    DB_REPAIRS, DataPathStats, DbStats, Eviction, ExpiryScan, FULL_REJECTED_WRITES, HealthCheck,
+    IngestStats, ItemSample, MAX_SHARDS, MemoryUsage, NamespaceStats, OptionsUpdate, RocksStorage,
+    ScanCursor, TtlCounters, TtlStats, runtime_options,
+};
+pub use value::{
+    DecodeError, EncodeOptions, HEADER_SIZE, StoredValue, TtlPolicy, ValueHeader,
+    calculate_expire_at, current_timestamp, current_timestamp_micros,
 };
-pub use value::{StoredValue, calculate_expire_at, current_timestamp};
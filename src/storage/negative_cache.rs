@@ -0,0 +1,184 @@
+//! Short-lived memory of keys RocksDB didn't have (`negative_cache_size`)
+//!
+//! Clients that keep asking for keys that never exist pay a bloom filter
+//! and memtable lookup on every get. A miss is remembered here for
+//! `negative_cache_ttl_secs`, and gets for the key are answered as misses
+//! without touching RocksDB. Writes purge the key, with the same ordering
+//! as the hot cache so a writer is never masked:
+//!
+//! - writers change RocksDB first, then call `purge`
+//! - readers take the shard's `generation` before reading RocksDB and pass it
+//!   to `insert`, which drops the miss if any write purged the shard in
+//!   between
+
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::{Duration, Instant};
+
+/// Gets answered as misses by the negative cache, without a RocksDB lookup
+pub static NEGATIVE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of shards (power of two so the index is a mask)
+const SHARDS: usize = 16;
+
+/// Sharded set of recently missed keys, each kept for a fixed time
+pub(crate) struct NegativeCache {
+    shards: Box<[Mutex<Shard>]>,
+    hasher: RandomState,
+    /// Keys remembered per shard
+    shard_capacity: usize,
+    ttl: Duration,
+}
+
+#[derive(Default)]
+struct Shard {
+    /// Key -> when the miss stops being trusted
+    entries: HashMap<Arc<[u8]>, Instant>,
+    /// Insertion order, oldest first; may name keys purged since
+    order: VecDeque<(Arc<[u8]>, Instant)>,
+    /// Bumped by every purge
+    generation: u64,
+}
+
+impl NegativeCache {
+    /// A cache remembering up to about `capacity` keys for `ttl` each
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            shard_capacity: capacity.div_ceil(SHARDS),
+            ttl,
+        }
+    }
+
+    #[inline]
+    fn shard(&self, key: &[u8]) -> &Mutex<Shard> {
+        let index = self.hasher.hash_one(key) as usize & (SHARDS - 1);
+        &self.shards[index]
+    }
+
+    /// Whether `key` recently missed and hasn't been written since
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        let mut shard = self.shard(key).lock();
+        match shard.entries.get(key) {
+            Some(&expires) if expires > Instant::now() => true,
+            Some(_) => {
+                shard.entries.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Current generation of `key`'s shard, to pass to `insert`
+    pub(crate) fn generation(&self, key: &[u8]) -> u64 {
+        self.shard(key).lock().generation
+    }
+
+    /// Remember that RocksDB just missed `key`, unless the shard was purged
+    /// since `generation` was taken
+    ///
+    /// The oldest keys make room when the shard is full.
+    pub(crate) fn insert(&self, key: &[u8], generation: u64) {
+        let mut shard = self.shard(key).lock();
+        if shard.generation != generation {
+            return;
+        }
+        while shard.order.len() >= self.shard_capacity {
+            let Some((oldest, expires)) = shard.order.pop_front() else {
+                break;
+            };
+            // Skip keys purged or re-inserted since
+            if shard.entries.get(&oldest) == Some(&expires) {
+                shard.entries.remove(&oldest);
+            }
+        }
+
+        let expires = Instant::now() + self.ttl;
+        let key: Arc<[u8]> = Arc::from(key);
+        shard.order.push_back((Arc::clone(&key), expires));
+        shard.entries.insert(key, expires);
+    }
+
+    /// Forget `key` after it was written in RocksDB, and fail any concurrent
+    /// `insert` into its shard
+    pub(crate) fn purge(&self, key: &[u8]) {
+        let mut shard = self.shard(key).lock();
+        shard.generation += 1;
+        shard.entries.remove(key);
+    }
+
+    /// Forget every miss after RocksDB changed in bulk, and fail any
+    /// concurrent `insert`
+    pub(crate) fn clear(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock();
+            let generation = shard.generation + 1;
+            *shard = Shard {
+                generation,
+                ..Shard::default()
+            };
+        }
+    }
+
+    /// Keys currently remembered
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().entries.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_contains_purge() {
+        let cache = NegativeCache::new(1024, Duration::from_secs(60));
+        assert!(!cache.contains(b"a"));
+
+        cache.insert(b"a", cache.generation(b"a"));
+        assert!(cache.contains(b"a"));
+
+        cache.purge(b"a");
+        assert!(!cache.contains(b"a"));
+    }
+
+    #[test]
+    fn test_insert_after_purge_is_dropped() {
+        let cache = NegativeCache::new(1024, Duration::from_secs(60));
+        // A reader missed, then a writer stored the key before the reader
+        // got to remember the miss
+        let generation = cache.generation(b"a");
+        cache.purge(b"a");
+        cache.insert(b"a", generation);
+        assert!(!cache.contains(b"a"));
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = NegativeCache::new(1024, Duration::ZERO);
+        cache.insert(b"a", cache.generation(b"a"));
+        assert!(!cache.contains(b"a"));
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let cache = NegativeCache::new(SHARDS * 4, Duration::from_secs(60));
+        for i in 0..1000 {
+            let key = format!("key{i}");
+            cache.insert(key.as_bytes(), cache.generation(key.as_bytes()));
+        }
+        assert!(cache.len() <= SHARDS * 4);
+        // The newest key is still there
+        assert!(cache.contains(b"key999"));
+    }
+}
@@ -3,296 +3,4475 @@
 //! Simple key-value store with RocksDB.
 
 use crate::StorageError;
-use crate::config::StorageConfig;
-use crate::storage::value::{StoredValue, current_timestamp};
-use rust_rocksdb::{BlockBasedOptions, CompactionDecision, DB, DBCompactionStyle, LogLevel, Options, WriteOptions};
+use crate::config::{
+    CompactionStyle, Compression, IndexType, NamespaceConfig, OnCorruption, StorageConfig,
+    StorageMode, Wal,
+};
+use crate::protocol::MAX_KEY_LENGTH;
+use crate::storage::hot_cache::{HOT_CACHE_HITS, HOT_CACHE_MISSES, HotCache};
+use crate::storage::locks::KeyLocks;
+use crate::storage::negative_cache::{NEGATIVE_CACHE_HITS, NegativeCache};
+use crate::storage::value::{
+    self, EncodeOptions, StoredValue, ValueHeader, current_timestamp, current_timestamp_micros,
+};
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use rust_rocksdb::backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions};
+use rust_rocksdb::checkpoint::Checkpoint;
+use rust_rocksdb::statistics::{StatsLevel, Ticker};
+use rust_rocksdb::{
+    BlockBasedIndexType, BlockBasedOptions, BoundColumnFamily, Cache, ColumnFamilyDescriptor,
+    CompactionDecision, DB, DBCompactionStyle, DBPath, DEFAULT_COLUMN_FAMILY_NAME, Direction, Env,
+    ErrorKind, FifoCompactOptions, IteratorMode, LogLevel, Options, SstFileWriter, WriteBatch,
+    WriteOptions,
+};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
-use tracing::{info, trace};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
 
-/// Global counter for TTL compaction removals (accessible from compaction filter)
-pub static TTL_COMPACTION_REMOVED: AtomicU64 = AtomicU64::new(0);
+/// Global counter for writes rejected while the database is full
+pub static FULL_REJECTED_WRITES: AtomicU64 = AtomicU64::new(0);
 
-/// Global counter for expired keys removed (lazy expiration + background scan)
-pub static EXPIRED_KEYS_REMOVED: AtomicU64 = AtomicU64::new(0);
+/// Global counter for value bytes saved by `compress_values_over_bytes`
+pub static COMPRESSION_BYTES_SAVED: AtomicU64 = AtomicU64::new(0);
+
+/// Global counter for undecodable values deleted when read
+pub static CORRUPT_VALUES_REMOVED: AtomicU64 = AtomicU64::new(0);
+
+/// Global counter for shards repaired at startup (`on_corruption = "repair"`)
+pub static DB_REPAIRS: AtomicU64 = AtomicU64::new(0);
+
+/// Global counter for shards recreated empty at startup (`on_corruption =
+/// "recreate"`)
+pub static DB_RECREATES: AtomicU64 = AtomicU64::new(0);
+
+/// Subdirectories `on_corruption = "recreate"` moves a corrupt shard's
+/// files into, followed by the Unix time
+const CORRUPT_PREFIX: &str = "corrupt-";
+
+/// Prefix reserved for server-internal keys (client keys never contain control bytes)
+const INTERNAL_KEY_PREFIX: u8 = 0x00;
+
+/// LSM levels RocksDB uses (its `num_levels` default, which `open` keeps)
+const NUM_LEVELS: usize = 7;
+
+/// Reserved key persisting the last `flush_all` epoch
+const FLUSH_EPOCH_KEY: &[u8] = b"\x00flush_epoch";
+
+/// Reserved key holding the newest value encoding version the database
+/// may contain (one byte)
+const FORMAT_KEY: &[u8] = b"\x00format";
+
+/// Reserved key `check_health` writes to every shard
+const HEALTH_CANARY_KEY: &[u8] = b"\x00health_canary";
+
+/// Reserved key the readiness probe reads, writes and deletes
+const READINESS_PROBE_KEY: &[u8] = b"\x00readiness_probe";
+
+/// Ingestion fully decodes one value in this many
+const INGEST_SAMPLE_EVERY: u64 = 64;
+
+/// Keys ranked together by one eviction sample; at most half of them are
+/// evicted
+const EVICT_SAMPLE_KEYS: usize = 1000;
+
+/// Most shards `storage.shards` may ask for
+pub const MAX_SHARDS: usize = 256;
+
+/// Most namespaces `storage.namespaces` may list, which bounds the
+/// `namespace` metric label
+const MAX_NAMESPACES: usize = 32;
+
+/// Longest namespace name
+const MAX_NAMESPACE_LENGTH: usize = 64;
+
+/// How often the rate limiter hands out its budget (RocksDB's default)
+const RATE_LIMITER_REFILL_MICROS: i64 = 100_000;
+
+/// Weight of high- over low-priority I/O requests in the rate limiter
+/// (RocksDB's default)
+const RATE_LIMITER_FAIRNESS: i32 = 10;
+
+/// Column family options `set_options` may change on a live database
+/// (RocksDB's `SetOptions`): memtable sizing, compaction triggers and
+/// stall thresholds, nothing that changes the file format
+const MUTABLE_CF_OPTIONS: [&str; 12] = [
+    "write_buffer_size",
+    "max_write_buffer_number",
+    "target_file_size_base",
+    "target_file_size_multiplier",
+    "max_bytes_for_level_base",
+    "max_bytes_for_level_multiplier",
+    "max_compaction_bytes",
+    "level0_file_num_compaction_trigger",
+    "level0_slowdown_writes_trigger",
+    "level0_stop_writes_trigger",
+    "soft_pending_compaction_bytes_limit",
+    "hard_pending_compaction_bytes_limit",
+];
+
+/// Database-wide options `set_options` may change (`SetDBOptions`)
+const MUTABLE_DB_OPTIONS: [&str; 6] = [
+    "max_background_jobs",
+    "bytes_per_sync",
+    "wal_bytes_per_sync",
+    "delayed_write_rate",
+    "max_total_wal_size",
+    "stats_dump_period_sec",
+];
 
 /// Memory usage statistics
 #[derive(Debug, Clone, Default)]
 pub struct MemoryUsage {
     /// Block cache usage in bytes
     pub block_cache_usage: usize,
-    /// Total memory usage in bytes
+    /// Part of `block_cache_usage` pinned by open iterators and table
+    /// readers, which eviction cannot reclaim
+    pub block_cache_pinned_usage: usize,
+    /// Active and unflushed immutable memtables in bytes
+    pub memtables_unflushed: usize,
+    /// All memtables in bytes, including flushed ones still pinned by
+    /// iterators
+    pub memtables_total: usize,
+    /// Index and filter blocks held outside the block cache in bytes
+    pub table_readers: usize,
+    /// Total memory usage in bytes (block cache, all memtables and table
+    /// readers)
     pub total: usize,
 }
 
-/// RocksDB-backed storage
-pub struct RocksStorage {
-    db: Arc<DB>,
-    write_opts: WriteOptions,
-}
+/// Database statistics from RocksDB properties (`db_stats`)
+///
+/// `None` means RocksDB did not report the property. Namespaces are left
+/// out of everything but `namespaces`.
+#[derive(Debug, Clone, Default)]
+pub struct DbStats {
+    /// Estimated number of keys, including expired or flushed items that
+    /// have not been compacted away yet
+    pub estimated_keys: Option<u64>,
+    /// Total size of all SST files in bytes
+    pub total_sst_bytes: Option<u64>,
+    /// Estimated size of live data in bytes
+    pub live_data_bytes: Option<u64>,
+    /// Size of active and unflushed memtables in bytes
+    pub memtable_bytes: Option<u64>,
+    /// (level, SST file count) for each level RocksDB reported
+    pub files_per_level: Vec<(usize, u64)>,
+    /// Bytes compaction must rewrite to bring every level under its target
+    pub pending_compaction_bytes: Option<u64>,
+    pub running_compactions: Option<u64>,
+    pub running_flushes: Option<u64>,
+    /// Times FIFO compaction dropped the oldest SST files to stay under
+    /// `max_db_size_bytes` (`None` with leveled compaction)
+    pub fifo_drops: Option<u64>,
+    /// Times the rate limiter's budget ran out and flushes or compactions
+    /// waited for a refill (`None` without `rate_limit_bytes_per_sec`)
+    pub rate_limiter_drains: Option<u64>,
+    /// One entry per configured namespace
+    pub namespaces: Vec<NamespaceStats>,
+    /// One entry per `db_paths` entry
+    pub data_paths: Vec<DataPathStats>,
+    /// RocksDB's statistics dump, one per shard (empty unless
+    /// `enable_statistics`)
+    pub statistics: Vec<String>,
+}
+
+/// SST bytes in one of `storage.db_paths`, summed over shards
+#[derive(Debug, Clone, Default)]
+pub struct DataPathStats {
+    pub path: PathBuf,
+    pub used_bytes: u64,
+    pub target_bytes: u64,
+}
+
+/// Item statistics gathered from a bounded scan (`stats items` / `stats sizes`)
+#[derive(Debug, Clone, Default)]
+pub struct ItemSample {
+    /// Number of live items sampled
+    pub sampled: u64,
+    /// Items without an expiration time
+    pub no_ttl: u64,
+    /// Items expiring within an hour
+    pub ttl_under_hour: u64,
+    /// Items expiring within a day
+    pub ttl_under_day: u64,
+    /// Items expiring later than a day from now
+    pub ttl_over_day: u64,
+    /// Value size histogram: power-of-two upper bound (bytes) -> item count
+    pub sizes: BTreeMap<u64, u64>,
+}
+
+/// Outcome of a `cas` command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CasOutcome {
+    /// Token matched, value written
+    Stored,
+    /// Item was modified since the token was issued
+    Exists,
+    /// Item is missing or expired
+    NotFound,
+}
+
+/// Epoch-based invalidation set by `flush_all`
+///
+/// Rather than deleting every key, values whose CAS token is below
+/// `cas_threshold` are treated as misses once `effective_at` has passed.
+/// Both are zero when no flush was ever issued.
+#[derive(Debug, Default)]
+struct FlushEpoch {
+    /// Values written with a lower CAS token are invalidated
+    cas_threshold: AtomicU64,
+    /// Unix time in microseconds from which the invalidation applies
+    effective_at: AtomicU64,
+}
+
+impl FlushEpoch {
+    /// Check whether a value with this CAS token has been flushed
+    #[inline]
+    fn is_flushed(&self, cas: u64) -> bool {
+        cas < self.cas_threshold.load(Ordering::Relaxed)
+            && current_timestamp_micros() >= self.effective_at.load(Ordering::Relaxed)
+    }
+
+    fn encode(&self) -> [u8; 16] {
+        let mut buf = [0; 16];
+        buf[..8].copy_from_slice(&self.cas_threshold.load(Ordering::Relaxed).to_le_bytes());
+        buf[8..].copy_from_slice(&self.effective_at.load(Ordering::Relaxed).to_le_bytes());
+        buf
+    }
+
+    fn restore(&self, bytes: &[u8]) {
+        if let (Some(threshold), Some(effective_at)) = (bytes.get(..8), bytes.get(8..16)) {
+            let threshold = u64::from_le_bytes(threshold.try_into().unwrap_or([0; 8]));
+            let effective_at = u64::from_le_bytes(effective_at.try_into().unwrap_or([0; 8]));
+            self.cas_threshold.store(threshold, Ordering::Relaxed);
+            self.effective_at.store(effective_at, Ordering::Relaxed);
+        }
+    }
+}
+
+/// A key-prefix namespace stored in a column family of its own
+struct Namespace {
+    name: String,
+    /// `<name>:`, stripped from keys stored in the column family
+    prefix: Vec<u8>,
+    /// Column family name
+    cf: String,
+    /// Held for writing while `flush_namespace` recreates the column family
+    swap: RwLock<()>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// Quota in bytes (0 = unlimited)
+    max_bytes: u64,
+    /// Over quota: sets return `StorageError::QuotaExceeded`
+    over_quota: AtomicBool,
+    /// Sets rejected while over quota
+    rejected_writes: AtomicU64,
+}
+
+/// Where a client key is stored
+struct Route<'a, 'k> {
+    /// Index of the shard
+    shard: usize,
+    /// The shard's database
+    db: &'a DB,
+    /// The namespace's column family, `None` for the default one
+    cf: Option<Arc<BoundColumnFamily<'a>>>,
+    /// Key within the column family
+    key: &'k [u8],
+    /// Keeps `flush_namespace` from dropping the column family meanwhile
+    _swap: Option<RwLockReadGuard<'a, ()>>,
+}
+
+/// What `set_options` did, by option name
+#[derive(Debug, Clone, Default)]
+pub struct OptionsUpdate {
+    /// Options now in effect, with their values
+    pub applied: Vec<(String, String)>,
+    /// Options left unchanged, with the reason
+    pub rejected: Vec<(String, String)>,
+}
+
+/// Per-namespace statistics
+#[derive(Debug, Clone, Default)]
+pub struct NamespaceStats {
+    pub name: String,
+    /// Estimated keys in the namespace's column family
+    pub items: Option<u64>,
+    /// Gets answered from the namespace, as counted by `record_get`
+    pub hits: u64,
+    pub misses: u64,
+    /// SST and memtable bytes of the namespace's column family
+    pub used_bytes: u64,
+    /// Quota in bytes (0 = unlimited)
+    pub max_bytes: u64,
+    /// Sets rejected while over quota
+    pub rejected_writes: u64,
+}
+
+/// One RocksDB instance of `storage.shards`
+struct Shard {
+    db: DB,
+    /// Directory of the WAL files: the shard's path unless `wal_dir` is set
+    wal_path: PathBuf,
+    /// Options the database was opened with, kept for their statistics
+    /// (FIFO compaction or `enable_statistics`)
+    statistics: Option<Options>,
+    /// Where the next eviction sample starts (the beginning when `None`)
+    evict_cursor: Mutex<Option<Vec<u8>>>,
+}
+
+/// RocksDB-backed storage
+///
+/// Keys are spread over `storage.shards` independent databases by a hash
+/// of the key, so writes to different shards don't contend in RocksDB's
+/// write path.
+pub struct RocksStorage {
+    shards: Vec<Shard>,
+    write_opts: WriteOptions,
+    /// Serializes read-modify-write commands per key
+    key_locks: KeyLocks,
+    /// Last CAS unique token handed out (hybrid clock, see `next_cas`)
+    last_cas: AtomicU64,
+    /// Current `flush_all` epoch (shared with the compaction filter)
+    flush_epoch: Arc<FlushEpoch>,
+    /// Expired keys removed (shared with the compaction filter)
+    ttl_counters: Arc<TtlCounters>,
+    /// Samples lazily expired keys into the log
+    expired_log: ExpiredLog,
+    /// Recently read values (`hot_cache_size_bytes` > 0)
+    hot_cache: Option<HotCache>,
+    /// Recently missed keys (`negative_cache_size` > 0)
+    negative_cache: Option<NegativeCache>,
+    /// Opened as a secondary: writes return `StorageError::ReadOnly`
+    read_only: bool,
+    /// Over `max_db_size_bytes` with `on_full = "reject"`: sets return
+    /// `StorageError::Full`
+    full: AtomicBool,
+    /// Configured namespaces; keys matching none go to the default column
+    /// family
+    namespaces: Vec<Namespace>,
+    /// Options `flush_namespace` recreates column families with
+    namespace_options: Options,
+    /// Options changed by `set_options` since opening, by name
+    runtime_options: Mutex<BTreeMap<String, String>>,
+    /// Effective configuration (reported by `stats settings`)
+    config: StorageConfig,
+}
+
+impl RocksStorage {
+    /// Open or create the database: one RocksDB instance per
+    /// `storage.shards`, at `db_path` itself for a single shard and at
+    /// `db_path/shard-{i}` otherwise
+    pub fn open(config: &StorageConfig) -> Result<Self, StorageError> {
+        let namespaces = parse_namespaces(&config.namespaces)?;
+        if config.shards == 0 || config.shards > MAX_SHARDS {
+            return Err(StorageError::Internal(format!(
+                "shards must be between 1 and {MAX_SHARDS}"
+            )));
+        }
+
+        let read_only = config.mode == StorageMode::Secondary;
+        let primary_path = if read_only {
+            let primary_path = config.primary_path.as_deref().ok_or_else(|| {
+                StorageError::Internal("secondary mode needs primary_path".to_string())
+            })?;
+            if primary_path == config.db_path {
+                return Err(StorageError::Internal(
+                    "secondary db_path must differ from primary_path".to_string(),
+                ));
+            }
+            Some(primary_path)
+        } else {
+            None
+        };
+        check_shard_layout(primary_path.unwrap_or(&config.db_path), config.shards)?;
+
+        create_directories(config)?;
+
+        // One block cache for all shards: block_cache_size is the total
+        let cache = Cache::new_lru_cache(config.block_cache_size);
+        // Shared with the compaction filter of every column family
+        let flush_epoch = Arc::new(FlushEpoch::default());
+        let ttl_counters = Arc::new(TtlCounters::default());
+        let primary_paths = primary_path.map(|path| shard_paths(path, config.shards));
+        let shards = shard_paths(&config.db_path, config.shards)
+            .into_iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let primary = primary_paths.as_ref().map(|paths| paths[i].as_path());
+                open_shard(
+                    config,
+                    path,
+                    primary,
+                    &namespaces,
+                    &cache,
+                    (&flush_epoch, &ttl_counters),
+                )
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+
+        if config.enable_compression && config.compression.is_none() {
+            warn!("storage.enable_compression is deprecated; use compression = \"lz4\"");
+        }
+        info!(
+            "RocksDB opened: path={:?}, shards={}, mode={}, compaction={}, compression={}, block_cache={}MB",
+            config.db_path,
+            config.shards,
+            config.mode.as_str(),
+            config.compaction.as_str(),
+            config.effective_compression().as_str(),
+            config.block_cache_size / (1024 * 1024),
+        );
+
+        // Catching up changes data behind the caches' back, and they are
+        // only ever invalidated by our own writes
+        let mut config = config.clone();
+        if read_only && (config.hot_cache_size_bytes > 0 || config.negative_cache_size > 0) {
+            warn!("Hot and negative caches are disabled in secondary mode");
+            config.hot_cache_size_bytes = 0;
+            config.negative_cache_size = 0;
+        }
+
+        // storage.wal = disabled (the default): writes go to the memtable
+        // only and reach disk when it flushes, so a crash loses unflushed
+        // data (acceptable for a cache). enabled logs each write first;
+        // enabled_sync also fsyncs the log before acknowledging.
+        let mut write_opts = WriteOptions::default();
+        write_opts.disable_wal(config.wal == Wal::Disabled);
+        write_opts.set_sync(config.wal == Wal::EnabledSync);
+
+        // Internal keys live in the first shard only
+        check_format(&shards[0].db, &write_opts, read_only)?;
+        if let Some(bytes) = shards[0].db.get(FLUSH_EPOCH_KEY)? {
+            flush_epoch.restore(&bytes);
+        }
+
+        // For recreating a namespace's column family in `flush_namespace`
+        let (namespace_options, _) =
+            column_options(&config, &cache, (&flush_epoch, &ttl_counters))?;
+
+        Ok(Self {
+            shards,
+            write_opts,
+            key_locks: KeyLocks::new(),
+            last_cas: AtomicU64::new(0),
+            flush_epoch,
+            ttl_counters,
+            expired_log: ExpiredLog::new(&config),
+            hot_cache: (config.hot_cache_size_bytes > 0)
+                .then(|| HotCache::new(config.hot_cache_size_bytes)),
+            negative_cache: (config.negative_cache_size > 0).then(|| {
+                NegativeCache::new(
+                    config.negative_cache_size,
+                    Duration::from_secs(config.negative_cache_ttl_secs),
+                )
+            }),
+            read_only,
+            full: AtomicBool::new(false),
+            namespaces,
+            namespace_options,
+            runtime_options: Mutex::new(BTreeMap::new()),
+            config,
+        })
+    }
+
+    /// The shard `key` is stored in
+    fn shard(&self, key: &[u8]) -> usize {
+        shard_index(key, self.shards.len())
+    }
+
+    /// Get the configuration this storage was opened with
+    pub fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    /// Whether this is a secondary, which rejects writes
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The configuration with the `[storage]` settings `set_options` has
+    /// changed since opening
+    pub fn settings(&self) -> StorageConfig {
+        let mut config = self.config.clone();
+        for (name, value) in self.runtime_options.lock().iter() {
+            match name.as_str() {
+                "write_buffer_size" => update(&mut config.write_buffer_size, value),
+                "max_write_buffer_number" => update(&mut config.max_write_buffer_number, value),
+                "target_file_size_base" => update(&mut config.target_file_size_base, value),
+                "max_background_jobs" => update(&mut config.max_background_jobs, value),
+                _ => {}
+            }
+        }
+        config
+    }
+
+    /// Change RocksDB options on the live database, in every shard and
+    /// column family
+    ///
+    /// Only the options in `MUTABLE_CF_OPTIONS` and `MUTABLE_DB_OPTIONS`
+    /// are accepted; RocksDB parses and checks the values. Each option is
+    /// applied on its own, so one rejected option doesn't hold back the
+    /// others. Changes last until restart.
+    pub fn set_options(&self, options: &[(String, String)]) -> OptionsUpdate {
+        let mut update = OptionsUpdate::default();
+        for (name, value) in options {
+            match self.set_option(name, value) {
+                Ok(()) => {
+                    let previous = self
+                        .runtime_options
+                        .lock()
+                        .insert(name.clone(), value.clone());
+                    info!(
+                        option = %name,
+                        value = %value,
+                        previous = previous.as_deref().unwrap_or("(startup)"),
+                        "RocksDB option changed"
+                    );
+                    update.applied.push((name.clone(), value.clone()));
+                }
+                Err(reason) => {
+                    warn!(option = %name, value = %value, %reason, "RocksDB option rejected");
+                    update.rejected.push((name.clone(), reason));
+                }
+            }
+        }
+        update
+    }
+
+    fn set_option(&self, name: &str, value: &str) -> Result<(), String> {
+        let option = [(name, value)];
+        if MUTABLE_DB_OPTIONS.contains(&name) {
+            for shard in &self.shards {
+                shard
+                    .db
+                    .set_db_options(&option)
+                    .map_err(|e| e.to_string())?;
+            }
+        } else if MUTABLE_CF_OPTIONS.contains(&name) {
+            for shard in &self.shards {
+                shard.db.set_options(&option).map_err(|e| e.to_string())?;
+                for ns in &self.namespaces {
+                    let _swap = ns.swap.read();
+                    if let Some(cf) = shard.db.cf_handle(&ns.cf) {
+                        shard
+                            .db
+                            .set_options_cf(&cf, &option)
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
+            }
+        } else {
+            return Err("not an option that can be changed at runtime".to_string());
+        }
+        Ok(())
+    }
+
+    fn ensure_writable(&self) -> Result<(), StorageError> {
+        if self.read_only {
+            Err(StorageError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fail a write that stores a value while the database is full
+    fn ensure_space(&self) -> Result<(), StorageError> {
+        if self.full.load(Ordering::Relaxed) {
+            FULL_REJECTED_WRITES.fetch_add(1, Ordering::Relaxed);
+            Err(StorageError::Full)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fail a write that stores a value into a namespace over its quota
+    fn ensure_quota(&self, key: &[u8]) -> Result<(), StorageError> {
+        match self.namespace(key) {
+            Some(ns) if ns.over_quota.load(Ordering::Relaxed) => {
+                ns.rejected_writes.fetch_add(1, Ordering::Relaxed);
+                Err(StorageError::QuotaExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Start or stop rejecting sets, returning the previous state
+    pub fn set_full(&self, full: bool) -> bool {
+        self.full.swap(full, Ordering::Relaxed)
+    }
+
+    /// Whether sets are being rejected for lack of space
+    pub fn is_full(&self) -> bool {
+        self.full.load(Ordering::Relaxed)
+    }
+
+    /// The namespace `key` belongs to, if any
+    fn namespace(&self, key: &[u8]) -> Option<&Namespace> {
+        self.namespaces
+            .iter()
+            .find(|ns| key.starts_with(&ns.prefix))
+    }
+
+    /// Resolve a client key to its shard, column family and key within it
+    fn route<'k>(&self, key: &'k [u8]) -> Result<Route<'_, 'k>, StorageError> {
+        let shard = self.shard(key);
+        let db = &self.shards[shard].db;
+        let Some(ns) = self.namespace(key) else {
+            return Ok(Route {
+                shard,
+                db,
+                cf: None,
+                key,
+                _swap: None,
+            });
+        };
+        // Recursive: one thread may route several keys of a namespace
+        // while `flush_namespace` waits
+        let swap = ns.swap.read_recursive();
+        let cf = db
+            .cf_handle(&ns.cf)
+            .ok_or_else(|| StorageError::Internal(format!("column family {} is missing", ns.cf)))?;
+        Ok(Route {
+            shard,
+            db,
+            cf: Some(cf),
+            key: &key[ns.prefix.len()..],
+            _swap: Some(swap),
+        })
+    }
+
+    /// Read a client key's raw value from its shard and column family
+    fn db_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        let route = self.route(key)?;
+        Ok(match &route.cf {
+            Some(cf) => route.db.get_cf(cf, route.key)?,
+            None => route.db.get(route.key)?,
+        })
+    }
+
+    /// Count a get of `key` answered by the server as a hit or miss in its
+    /// namespace; keys outside namespaces are not counted here
+    pub fn record_get(&self, key: &[u8], hit: bool) {
+        if let Some(ns) = self.namespace(key) {
+            let counter = if hit { &ns.hits } else { &ns.misses };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether any namespace has a quota for `enforce_namespace_quotas`
+    pub fn has_namespace_quotas(&self) -> bool {
+        self.namespaces.iter().any(|ns| ns.max_bytes > 0)
+    }
+
+    /// Compare each namespace's size against its quota: sets are rejected
+    /// from 100% of it until the size drops below 95%
+    pub fn enforce_namespace_quotas(&self) {
+        for ns in self.namespaces.iter().filter(|ns| ns.max_bytes > 0) {
+            let used = self.namespace_bytes(ns);
+            let low_water = ns.max_bytes - ns.max_bytes / 20;
+            if used >= ns.max_bytes && !ns.over_quota.swap(true, Ordering::Relaxed) {
+                warn!(
+                    namespace = %ns.name,
+                    used,
+                    max_bytes = ns.max_bytes,
+                    "Namespace over quota, rejecting sets"
+                );
+            } else if used < low_water && ns.over_quota.swap(false, Ordering::Relaxed) {
+                info!(namespace = %ns.name, used, "Namespace below quota, accepting sets");
+            }
+        }
+    }
+
+    /// SST and memtable bytes of a namespace's column family in every shard
+    fn namespace_bytes(&self, ns: &Namespace) -> u64 {
+        [
+            "rocksdb.total-sst-files-size",
+            "rocksdb.cur-size-all-mem-tables",
+        ]
+        .iter()
+        .filter_map(|name| self.namespace_property(ns, name))
+        .sum()
+    }
+
+    /// Integer property of a namespace's column family, summed over shards
+    fn namespace_property(&self, ns: &Namespace, name: &str) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|shard| {
+                let cf = shard.db.cf_handle(&ns.cf)?;
+                shard.db.property_int_value_cf(&cf, name).unwrap_or(None)
+            })
+            .reduce(|a, b| a + b)
+    }
+
+    /// Integer property of the default column family, summed over shards
+    fn property(&self, name: &str) -> Option<u64> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.db.property_int_value(name).unwrap_or(None))
+            .reduce(|a, b| a + b)
+    }
+
+    /// Drop every item of namespace `name` at once by dropping and
+    /// recreating its column family
+    ///
+    /// Its files are deleted whole rather than compacted away. A secondary
+    /// must be restarted to see the recreated column family.
+    pub fn flush_namespace(&self, name: &str) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        let ns = self
+            .namespaces
+            .iter()
+            .find(|ns| ns.name == name)
+            .ok_or_else(|| StorageError::UnknownNamespace(name.to_string()))?;
+        {
+            let options = self.runtime_options.lock();
+            let _swap = ns.swap.write();
+            // Created with the startup options; keep set_options' changes
+            let changed: Vec<(&str, &str)> = options
+                .iter()
+                .filter(|(name, _)| MUTABLE_CF_OPTIONS.contains(&name.as_str()))
+                .map(|(name, value)| (name.as_str(), value.as_str()))
+                .collect();
+            for shard in &self.shards {
+                shard.db.drop_cf(&ns.cf)?;
+                shard.db.create_cf(&ns.cf, &self.namespace_options)?;
+                if let Some(cf) = shard.db.cf_handle(&ns.cf)
+                    && !changed.is_empty()
+                {
+                    shard.db.set_options_cf(&cf, &changed)?;
+                }
+            }
+        }
+        ns.over_quota.store(false, Ordering::Relaxed);
+        // Negative cache entries are still right: everything is a miss now
+        if let Some(cache) = &self.hot_cache {
+            cache.clear();
+        }
+        info!(namespace = name, "Namespace flushed");
+        Ok(())
+    }
+
+    /// Apply the primary's writes made since the last catch-up
+    ///
+    /// Only meaningful on a secondary; the `flush_all` epoch is reloaded
+    /// too, since the primary may have changed it.
+    pub fn catch_up_with_primary(&self) -> Result<(), StorageError> {
+        for shard in &self.shards {
+            shard.db.try_catch_up_with_primary()?;
+        }
+        if let Some(bytes) = self.shards[0].db.get(FLUSH_EPOCH_KEY)? {
+            self.flush_epoch.restore(&bytes);
+        }
+        Ok(())
+    }
+
+    /// Get a value by key (with lazy expiration)
+    pub fn get(&self, key: &[u8]) -> Result<Option<StoredValue>, StorageError> {
+        if let Some(cache) = &self.hot_cache {
+            if let Some(value) = cache.get(key).filter(|value| self.is_live(value)) {
+                HOT_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+                return Ok(Some(value));
+            }
+            HOT_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.is_known_miss(key) {
+            return Ok(None);
+        }
+
+        let generations = self.cache_generations(key);
+        let value = self.get_uncached(key)?;
+        self.remember(key, value.as_ref(), generations);
+        Ok(value)
+    }
+
+    /// Whether the negative cache answers `key` as a miss
+    fn is_known_miss(&self, key: &[u8]) -> bool {
+        let known = self
+            .negative_cache
+            .as_ref()
+            .is_some_and(|cache| cache.contains(key));
+        if known {
+            NEGATIVE_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        }
+        known
+    }
+
+    /// Generations of `key`'s hot and negative cache shards, taken before
+    /// reading RocksDB (see `remember`)
+    fn cache_generations(&self, key: &[u8]) -> (u64, u64) {
+        (
+            self.hot_cache
+                .as_ref()
+                .map_or(0, |cache| cache.generation(key)),
+            self.negative_cache
+                .as_ref()
+                .map_or(0, |cache| cache.generation(key)),
+        )
+    }
+
+    /// Record what RocksDB returned for `key` in the hot or negative cache,
+    /// unless a write invalidated it since `generations` were taken
+    fn remember(&self, key: &[u8], value: Option<&StoredValue>, generations: (u64, u64)) {
+        match (value, &self.hot_cache, &self.negative_cache) {
+            (Some(value), Some(cache), _) => cache.insert(key, value, generations.0),
+            (None, _, Some(cache)) => cache.insert(key, generations.1),
+            _ => {}
+        }
+    }
+
+    /// Drop `key` from both caches after writing or deleting it in RocksDB
+    fn invalidate_caches(&self, key: &[u8]) {
+        if let Some(cache) = &self.hot_cache {
+            cache.invalidate(key);
+        }
+        if let Some(cache) = &self.negative_cache {
+            cache.purge(key);
+        }
+    }
+
+    /// Get a value from RocksDB, skipping the hot and negative caches
+    fn get_uncached(&self, key: &[u8]) -> Result<Option<StoredValue>, StorageError> {
+        match self.db_get(key)? {
+            Some(bytes) => {
+                let value = match StoredValue::decode(&bytes) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        self.remove_corrupt(key, &bytes, &e);
+                        return Ok(None);
+                    }
+                };
+                if self.flush_epoch.is_flushed(value.cas) {
+                    self.delete_stale(key);
+                    Ok(None)
+                } else if value.is_expired() {
+                    self.ttl_counters
+                        .expired_removed
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.expired_log.record(key, value.expire_at);
+                    self.delete_stale(key);
+                    Ok(None)
+                } else {
+                    Ok(Some(value))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a value is neither expired nor invalidated by `flush_all`
+    #[inline]
+    fn is_live(&self, value: &StoredValue) -> bool {
+        !value.is_expired() && !self.flush_epoch.is_flushed(value.cas)
+    }
+
+    /// Get multiple values by keys using batched MultiGet API
+    ///
+    /// Results are positional: `results[i]` belongs to `keys[i]`, so callers
+    /// keep their own key slices and nothing is copied. With the hot cache
+    /// enabled only the keys it misses go to RocksDB.
+    pub fn get_multi<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<StoredValue>>, StorageError> {
+        let mut results = Vec::with_capacity(keys.len());
+        // Indexes into `keys` to look up in RocksDB, with the cache
+        // generations taken for each
+        let mut lookups = Vec::new();
+        for (i, key) in keys.iter().enumerate() {
+            let key = key.as_ref();
+            if let Some(cache) = &self.hot_cache {
+                if let Some(value) = cache.get(key).filter(|value| self.is_live(value)) {
+                    HOT_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+                    results.push(Some(value));
+                    continue;
+                }
+                HOT_CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+            }
+            results.push(None);
+            if !self.is_known_miss(key) {
+                lookups.push((i, self.cache_generations(key)));
+            }
+        }
+        if lookups.is_empty() {
+            return Ok(results);
+        }
+
+        // Use RocksDB's native multi_get for better performance
+        // (batches lookups, reduces mutex contention, enables parallel I/O)
+        let lookup_keys = lookups.iter().map(|&(i, _)| keys[i].as_ref());
+        let raw_results = if self.shards.len() == 1 && self.namespaces.is_empty() {
+            self.shards[0].db.multi_get(lookup_keys)
+        } else {
+            self.multi_get_routed(lookup_keys)?
+        };
+
+        // Indexes into `keys`
+        let mut expired_keys = Vec::new();
+        let mut expired_count = 0;
+
+        for (&(i, generations), raw_result) in lookups.iter().zip(raw_results) {
+            match raw_result {
+                Ok(Some(bytes)) => {
+                    let value = match StoredValue::decode(&bytes) {
+                        Ok(value) => value,
+                        // A miss for this key only, not the whole batch
+                        Err(e) => {
+                            self.remove_corrupt(keys[i].as_ref(), &bytes, &e);
+                            continue;
+                        }
+                    };
+                    if self.flush_epoch.is_flushed(value.cas) {
+                        expired_keys.push(i);
+                    } else if value.is_expired() {
+                        expired_count += 1;
+                        self.expired_log.record(keys[i].as_ref(), value.expire_at);
+                        expired_keys.push(i);
+                    } else {
+                        self.remember(keys[i].as_ref(), Some(&value), generations);
+                        results[i] = Some(value);
+                    }
+                }
+                Ok(None) => self.remember(keys[i].as_ref(), None, generations),
+                Err(e) => {
+                    return Err(StorageError::RocksDb(e));
+                }
+            }
+        }
+
+        // Batch delete expired (or flushed) keys (lazy expiration)
+        if !expired_keys.is_empty() {
+            self.ttl_counters
+                .expired_removed
+                .fetch_add(expired_count, Ordering::Relaxed);
+            for i in expired_keys {
+                self.delete_stale(keys[i].as_ref());
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// `multi_get` across shards and column families, each key routed to
+    /// its own; results come back in key order
+    fn multi_get_routed<'k>(
+        &self,
+        keys: impl Iterator<Item = &'k [u8]>,
+    ) -> Result<Vec<Result<Option<Vec<u8>>, rust_rocksdb::Error>>, StorageError> {
+        let routes = keys
+            .map(|key| self.route(key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut by_shard = vec![Vec::new(); self.shards.len()];
+        for (i, route) in routes.iter().enumerate() {
+            by_shard[route.shard].push(i);
+        }
+
+        let mut results: Vec<_> = routes.iter().map(|_| Ok(None)).collect();
+        for (shard, indexes) in self.shards.iter().zip(by_shard) {
+            if indexes.is_empty() {
+                continue;
+            }
+            let default = shard
+                .db
+                .cf_handle(DEFAULT_COLUMN_FAMILY_NAME)
+                .ok_or_else(|| StorageError::Internal("default column family is missing".into()))?;
+            let found = shard.db.multi_get_cf(indexes.iter().map(|&i| {
+                let route = &routes[i];
+                (route.cf.as_ref().unwrap_or(&default), route.key)
+            }));
+            for (i, result) in indexes.into_iter().zip(found) {
+                results[i] = result;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Set a value (into the memtable, and the WAL if `storage.wal` is
+    /// enabled)
+    ///
+    /// Every write is assigned a fresh CAS unique token.
+    pub fn set(&self, key: &[u8], mut value: StoredValue) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        self.ensure_space()?;
+        self.ensure_quota(key)?;
+        value.cas = self.next_cas();
+        self.put(key, &value)
+    }
+
+    /// Set several values with one RocksDB write
+    ///
+    /// Like `set` for each item in order (later items win for repeated
+    /// keys), but the write path is paid once per shard; within a shard
+    /// either all are written or none are. Returns one result per item: an
+    /// item whose namespace is over its quota fails alone, and a failed
+    /// shard write fails only that shard's items.
+    pub fn set_batch(
+        &self,
+        items: &mut [(Vec<u8>, StoredValue)],
+    ) -> Result<Vec<Result<(), StorageError>>, StorageError> {
+        self.ensure_writable()?;
+        self.ensure_space()?;
+        let mut results: Vec<_> = items
+            .iter()
+            .map(|(key, _)| self.ensure_quota(key))
+            .collect();
+        for ((_, value), result) in items.iter_mut().zip(&results) {
+            if result.is_ok() {
+                value.cas = self.next_cas();
+            }
+        }
+        let mut batches: Vec<_> = self.shards.iter().map(|_| WriteBatch::default()).collect();
+        let mut routes = Vec::with_capacity(items.len());
+        for (i, ((key, value), result)) in items.iter().zip(&mut results).enumerate() {
+            if result.is_err() {
+                continue;
+            }
+            let route = match self.route(key) {
+                Ok(route) => route,
+                Err(e) => {
+                    *result = Err(e);
+                    continue;
+                }
+            };
+            let encoded = self.encode(value);
+            let batch = &mut batches[route.shard];
+            match &route.cf {
+                Some(cf) => batch.put_cf(cf, route.key, encoded),
+                None => batch.put(route.key, encoded),
+            }
+            routes.push((i, route));
+        }
+        for (index, (shard, batch)) in self.shards.iter().zip(&batches).enumerate() {
+            if batch.is_empty() {
+                continue;
+            }
+            if let Err(e) = shard.db.write_opt(batch, &self.write_opts) {
+                for (i, route) in &routes {
+                    if route.shard == index {
+                        results[*i] = Err(StorageError::RocksDb(e.clone()));
+                    }
+                }
+            }
+        }
+        drop(routes);
+        for ((key, _), result) in items.iter().zip(&results) {
+            if result.is_ok() {
+                self.invalidate_caches(key);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Hand out the next CAS token
+    ///
+    /// Hybrid clock: strictly increasing, and never below the current time in
+    /// microseconds. This keeps tokens monotonic across restarts and lets
+    /// `flush_all` compare a value's token against the flush time.
+    fn next_cas(&self) -> u64 {
+        let now = current_timestamp_micros();
+        let (Ok(prev) | Err(prev)) =
+            self.last_cas
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |prev| {
+                    Some(prev.saturating_add(1).max(now))
+                });
+        prev.saturating_add(1).max(now)
+    }
+
+    /// Invalidate all items, now or `delay` seconds from now
+    ///
+    /// Items written before the flush takes effect become misses afterwards;
+    /// the compaction filter reclaims their space in the background.
+    pub fn flush_all(&self, delay: u64) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        let effective_at =
+            current_timestamp_micros().saturating_add(delay.saturating_mul(1_000_000));
+        // Everything already written must fall below the threshold, even if the
+        // CAS clock has run ahead of wall time
+        let next_cas = self.last_cas.load(Ordering::Relaxed).saturating_add(1);
+        let cas_threshold = effective_at.max(next_cas);
+
+        self.flush_epoch
+            .cas_threshold
+            .store(cas_threshold, Ordering::Relaxed);
+        self.flush_epoch
+            .effective_at
+            .store(effective_at, Ordering::Relaxed);
+        // Internal keys live in the first shard only
+        self.shards[0]
+            .db
+            .put_opt(FLUSH_EPOCH_KEY, self.flush_epoch.encode(), &self.write_opts)?;
+
+        info!(delay, "flush_all scheduled");
+        Ok(())
+    }
+
+    /// Write a value as-is, keeping its CAS token
+    ///
+    /// Every write of a client key goes through here, `delete_key` or
+    /// `set_batch`, which keep the hot and negative caches coherent.
+    fn put(&self, key: &[u8], value: &StoredValue) -> Result<(), StorageError> {
+        let encoded = self.encode(value);
+        let route = self.route(key)?;
+        match &route.cf {
+            Some(cf) => route
+                .db
+                .put_cf_opt(cf, route.key, &encoded, &self.write_opts)?,
+            None => route.db.put_opt(route.key, &encoded, &self.write_opts)?,
+        }
+        drop(route);
+        self.invalidate_caches(key);
+        Ok(())
+    }
+
+    /// Encode a value for RocksDB, compressing data longer than
+    /// `compress_values_over_bytes` and checksummed with `verify_checksums`
+    fn encode(&self, value: &StoredValue) -> Vec<u8> {
+        let (encoded, saved) = value.encode_with(EncodeOptions {
+            compress_over_bytes: self.config.compress_values_over_bytes,
+            checksum: self.config.verify_checksums,
+        });
+        if saved > 0 {
+            COMPRESSION_BYTES_SAVED.fetch_add(saved, Ordering::Relaxed);
+        }
+        encoded
+    }
+
+    /// Delete a key from RocksDB and the caches
+    fn delete_key(&self, key: &[u8]) -> Result<(), StorageError> {
+        let route = self.route(key)?;
+        match &route.cf {
+            Some(cf) => route.db.delete_cf_opt(cf, route.key, &self.write_opts)?,
+            None => route.db.delete_opt(route.key, &self.write_opts)?,
+        }
+        drop(route);
+        self.invalidate_caches(key);
+        Ok(())
+    }
+
+    /// Get a value and update its expiration time in one step
+    ///
+    /// Expired keys are not touched and return `None`. The CAS token is
+    /// unchanged, matching memcached where touch does not modify the item.
+    pub fn get_and_touch(
+        &self,
+        key: &[u8],
+        exptime: i64,
+    ) -> Result<Option<StoredValue>, StorageError> {
+        self.ensure_writable()?;
+        let _guard = self.key_locks.lock(key);
+        let Some(mut value) = self.get(key)? else {
+            return Ok(None);
+        };
+        value.touch(exptime);
+        self.put(key, &value)?;
+        Ok(Some(value))
+    }
+
+    /// Store a value only if the key does not already exist
+    ///
+    /// Expired keys count as absent. Returns `true` if the value was stored.
+    pub fn add(&self, key: &[u8], value: StoredValue) -> Result<bool, StorageError> {
+        self.ensure_writable()?;
+        let _guard = self.key_locks.lock(key);
+        if self.get(key)?.is_some() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Store a value only if the key already exists
+    ///
+    /// Expired keys count as absent. Returns `true` if the value was stored.
+    pub fn replace(&self, key: &[u8], value: StoredValue) -> Result<bool, StorageError> {
+        self.ensure_writable()?;
+        let _guard = self.key_locks.lock(key);
+        if self.get(key)?.is_none() {
+            return Ok(false);
+        }
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Store a value only if the item's CAS token still matches `cas_unique`
+    pub fn cas(
+        &self,
+        key: &[u8],
+        value: StoredValue,
+        cas_unique: u64,
+    ) -> Result<CasOutcome, StorageError> {
+        self.ensure_writable()?;
+        let _guard = self.key_locks.lock(key);
+        match self.get(key)? {
+            None => Ok(CasOutcome::NotFound),
+            Some(existing) if existing.cas != cas_unique => Ok(CasOutcome::Exists),
+            Some(_) => {
+                self.set(key, value)?;
+                Ok(CasOutcome::Stored)
+            }
+        }
+    }
+
+    /// Atomically increment or decrement a decimal value
+    ///
+    /// Increments wrap at 2^64 and decrements clamp at 0 (memcached semantics).
+    /// Returns the new value, or `None` if the key is missing or expired.
+    pub fn incr_decr(
+        &self,
+        key: &[u8],
+        delta: u64,
+        incr: bool,
+    ) -> Result<Option<u64>, StorageError> {
+        self.ensure_writable()?;
+        let _guard = self.key_locks.lock(key);
+        let Some(mut value) = self.get(key)? else {
+            return Ok(None);
+        };
+        let current = value.as_u64()?;
+        let updated = if incr {
+            current.wrapping_add(delta)
+        } else {
+            current.saturating_sub(delta)
+        };
+        value.set_numeric(updated);
+        self.set(key, value)?;
+        Ok(Some(updated))
+    }
+
+    /// Append data to an existing value, keeping its flags and expire_at
+    ///
+    /// Returns `false` if the key is missing or expired.
+    pub fn append(&self, key: &[u8], data: &[u8]) -> Result<bool, StorageError> {
+        self.concat(key, |existing| existing.extend_from_slice(data))
+    }
+
+    /// Prepend data to an existing value, keeping its flags and expire_at
+    ///
+    /// Returns `false` if the key is missing or expired.
+    pub fn prepend(&self, key: &[u8], data: &[u8]) -> Result<bool, StorageError> {
+        self.concat(key, |existing| {
+            existing.splice(0..0, data.iter().copied());
+        })
+    }
+
+    /// Read-modify-write the data of an existing value
+    fn concat(&self, key: &[u8], modify: impl FnOnce(&mut Vec<u8>)) -> Result<bool, StorageError> {
+        self.ensure_writable()?;
+        let _guard = self.key_locks.lock(key);
+        let Some(mut value) = self.get(key)? else {
+            return Ok(false);
+        };
+        modify(&mut value.data);
+        self.set(key, value)?;
+        Ok(true)
+    }
+
+    /// Delete a key
+    ///
+    /// Returns `true` if the key existed, `false` otherwise.
+    /// Note: This is not fully atomic - between get and delete another thread
+    /// could modify the key. For memcached semantics this is acceptable.
+    pub fn delete(&self, key: &[u8]) -> Result<bool, StorageError> {
+        self.ensure_writable()?;
+        // Expired or flushed items report NOT_FOUND
+        let existed = self.get(key)?.is_some();
+        // Always call delete - RocksDB delete is idempotent
+        // This avoids the race where key is deleted between get and delete
+        self.delete_key(key)?;
+        Ok(existed)
+    }
+
+    /// Best-effort delete of an expired or flushed key found by a read
+    ///
+    /// A secondary leaves it to the primary.
+    fn delete_stale(&self, key: &[u8]) {
+        if !self.read_only {
+            let _ = self.delete_key(key);
+        }
+    }
+
+    /// Delete a value that failed to decode, so it reads as a miss rather
+    /// than an error forever
+    ///
+    /// Callers may hold the key lock, so none is taken: the value is only
+    /// deleted if it is still the same bytes. A secondary leaves it to the
+    /// primary.
+    fn remove_corrupt(&self, key: &[u8], bytes: &[u8], error: &StorageError) {
+        warn!(
+            key = %String::from_utf8_lossy(key),
+            len = bytes.len(),
+            "Removing corrupt value: {}",
+            error
+        );
+        if self.read_only {
+            return;
+        }
+        let unchanged = matches!(self.db_get(key), Ok(Some(current)) if current == bytes);
+        if unchanged && self.delete_key(key).is_ok() {
+            CORRUPT_VALUES_REMOVED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Get memory usage statistics
+    ///
+    /// Memtables and table readers are summed over shards; the block cache
+    /// is shared, so it is counted once.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let property = |name: &str| self.property(name).unwrap_or(0) as usize;
+        let cache_property = |name: &str| {
+            self.shards[0]
+                .db
+                .property_int_value(name)
+                .unwrap_or(None)
+                .unwrap_or(0) as usize
+        };
+        let block_cache_usage = cache_property("rocksdb.block-cache-usage");
+        let memtables_total = property("rocksdb.size-all-mem-tables");
+        let table_readers = property("rocksdb.estimate-table-readers-mem");
+
+        MemoryUsage {
+            block_cache_usage,
+            block_cache_pinned_usage: cache_property("rocksdb.block-cache-pinned-usage"),
+            memtables_unflushed: property("rocksdb.cur-size-all-mem-tables"),
+            memtables_total,
+            table_readers,
+            total: block_cache_usage + memtables_total + table_readers,
+        }
+    }
+
+    /// Key count, data sizes and compaction state from RocksDB properties
+    ///
+    /// Cheap enough to call on every `stats` or scrape. Figures are summed
+    /// over shards.
+    pub fn db_stats(&self) -> DbStats {
+        let property = |name: &str| self.property(name);
+
+        DbStats {
+            estimated_keys: property("rocksdb.estimate-num-keys"),
+            total_sst_bytes: property("rocksdb.total-sst-files-size"),
+            live_data_bytes: property("rocksdb.estimate-live-data-size"),
+            memtable_bytes: property("rocksdb.cur-size-all-mem-tables"),
+            files_per_level: (0..NUM_LEVELS)
+                .filter_map(|level| {
+                    property(&format!("rocksdb.num-files-at-level{level}")).map(|n| (level, n))
+                })
+                .collect(),
+            pending_compaction_bytes: property("rocksdb.estimate-pending-compaction-bytes"),
+            running_compactions: property("rocksdb.num-running-compactions"),
+            running_flushes: property("rocksdb.num-running-flushes"),
+            fifo_drops: self
+                .shards
+                .iter()
+                .filter(|_| self.config.compaction == CompactionStyle::Fifo)
+                .filter_map(|shard| shard.statistics.as_ref())
+                .map(|opts| opts.get_ticker_count(Ticker::FifoMaxSizeCompactions))
+                .reduce(|a, b| a + b),
+            rate_limiter_drains: self
+                .shards
+                .iter()
+                .filter(|_| self.config.rate_limit_bytes_per_sec > 0)
+                .filter_map(|shard| shard.statistics.as_ref())
+                .map(|opts| opts.get_ticker_count(Ticker::NumberRateLimiterDrains))
+                .reduce(|a, b| a + b),
+            statistics: self
+                .shards
+                .iter()
+                .filter(|_| self.config.enable_statistics)
+                .filter_map(|shard| shard.statistics.as_ref()?.get_statistics())
+                .collect(),
+            data_paths: self
+                .config
+                .db_paths
+                .iter()
+                .map(|data_path| DataPathStats {
+                    path: data_path.path.clone(),
+                    used_bytes: shard_paths(&data_path.path, self.config.shards)
+                        .iter()
+                        .map(|dir| sst_bytes(dir))
+                        .sum(),
+                    target_bytes: data_path.target_size_bytes,
+                })
+                .collect(),
+            namespaces: self
+                .namespaces
+                .iter()
+                .map(|ns| NamespaceStats {
+                    name: ns.name.clone(),
+                    items: self.namespace_property(ns, "rocksdb.estimate-num-keys"),
+                    hits: ns.hits.load(Ordering::Relaxed),
+                    misses: ns.misses.load(Ordering::Relaxed),
+                    used_bytes: self.namespace_bytes(ns),
+                    max_bytes: ns.max_bytes,
+                    rejected_writes: ns.rejected_writes.load(Ordering::Relaxed),
+                })
+                .collect(),
+        }
+    }
+
+    /// Sample items for TTL and size statistics, inspecting up to `limit`
+    /// entries
+    ///
+    /// Every column family of every shard gets an equal share of `limit`,
+    /// walked from its start, so one large shard or namespace can't crowd
+    /// out the rest. Expired, flushed, corrupt and internal entries count
+    /// toward the limit but not into the sample. This blocks on RocksDB
+    /// I/O; call it from a blocking thread.
+    pub fn sample_items(&self, limit: usize) -> Result<ItemSample, StorageError> {
+        let mut sample = ItemSample::default();
+        let parts = self.shards.len() * (1 + self.namespaces.len());
+        let mut budgets = (0..parts).map(|i| limit / parts + usize::from(i < limit % parts));
+        for shard in &self.shards {
+            let budget = budgets.next().unwrap_or(0);
+            let entries = shard.db.iterator(IteratorMode::Start).take(budget);
+            self.sample_entries(entries, &mut sample)?;
+            for ns in &self.namespaces {
+                let budget = budgets.next().unwrap_or(0);
+                let _swap = ns.swap.read();
+                if let Some(cf) = shard.db.cf_handle(&ns.cf) {
+                    let entries = shard.db.iterator_cf(&cf, IteratorMode::Start).take(budget);
+                    self.sample_entries(entries, &mut sample)?;
+                }
+            }
+        }
+        Ok(sample)
+    }
+
+    /// Add the live items among `entries` to `sample`
+    fn sample_entries(
+        &self,
+        entries: impl Iterator<Item = Result<(Box<[u8]>, Box<[u8]>), rust_rocksdb::Error>>,
+        sample: &mut ItemSample,
+    ) -> Result<(), StorageError> {
+        const HOUR: u64 = 60 * 60;
+        const DAY: u64 = 24 * HOUR;
+
+        let now = current_timestamp();
+        for item in entries {
+            let (key, bytes) = item?;
+            // The header alone says whether the entry is live
+            if key.first() == Some(&INTERNAL_KEY_PREFIX)
+                || self.header_state(&bytes) != HeaderState::Live
+            {
+                continue;
+            }
+            // Corrupt values are left for a read to remove
+            let Ok(value) = StoredValue::decode(&bytes) else {
+                continue;
+            };
+
+            sample.sampled += 1;
+            match value.expire_at.saturating_sub(now) {
+                _ if value.expire_at == 0 => sample.no_ttl += 1,
+                remaining if remaining < HOUR => sample.ttl_under_hour += 1,
+                remaining if remaining < DAY => sample.ttl_under_day += 1,
+                _ => sample.ttl_over_day += 1,
+            }
+
+            let bucket = (value.data.len() as u64).next_power_of_two().max(32);
+            *sample.sizes.entry(bucket).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    /// Delete expired (or flushed) items in one bounded slice of the
+    /// keyspace, for the background expiry scan
+    ///
+    /// Starts at `from` (the beginning of the first shard when `None`),
+    /// walks the shards in order and stops once `max_keys` were inspected
+    /// or `deadline` has passed. Only value headers are read. Items are
+    /// re-checked under their key lock before deletion, so a concurrent
+    /// read-modify-write isn't undone.
+    pub fn scan_expired(
+        &self,
+        from: Option<&ScanCursor>,
+        max_keys: usize,
+        deadline: Instant,
+    ) -> Result<ExpiryScan, StorageError> {
+        self.ensure_writable()?;
+        let mut scan = ExpiryScan::default();
+        let first = from.map_or(0, |cursor| cursor.shard);
+        for (index, shard) in self.shards.iter().enumerate().skip(first) {
+            let mode = match from {
+                Some(cursor) if cursor.shard == index => {
+                    IteratorMode::From(&cursor.key, Direction::Forward)
+                }
+                _ => IteratorMode::Start,
+            };
+            if let Some(key) = self.scan_shard(shard, mode, &mut scan, max_keys, deadline)? {
+                scan.resume_from = Some(ScanCursor { shard: index, key });
+                return Ok(scan);
+            }
+        }
+        Ok(scan)
+    }
+
+    /// One shard's part of `scan_expired`; returns the key to resume from
+    /// when the budget ran out
+    fn scan_shard(
+        &self,
+        shard: &Shard,
+        mode: IteratorMode<'_>,
+        scan: &mut ExpiryScan,
+        max_keys: usize,
+        deadline: Instant,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        for item in shard.db.iterator(mode) {
+            let (key, bytes) = item?;
+            // At least one key per slice, so a tiny budget still makes progress
+            let out_of_budget = scan.scanned > 0 && Instant::now() >= deadline;
+            if scan.scanned as usize >= max_keys || out_of_budget {
+                return Ok(Some(key.into_vec()));
+            }
+            // Namespaced keys written here before their namespace was
+            // configured are unreachable, and deleting them would hit the
+            // namespace instead; the compaction filter drops them
+            if key.first() == Some(&INTERNAL_KEY_PREFIX) || self.namespace(&key).is_some() {
+                continue;
+            }
+            scan.scanned += 1;
+            if self.header_state(&bytes) == HeaderState::Live {
+                continue;
+            }
+
+            let _guard = self.key_locks.lock(&key);
+            let Some(current) = shard.db.get(&key)? else {
+                continue;
+            };
+            let state = self.header_state(&current);
+            if state == HeaderState::Live {
+                continue;
+            }
+            self.delete_key(&key)?;
+            scan.removed += 1;
+            if state == HeaderState::Expired {
+                self.ttl_counters
+                    .expired_removed
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+            trace!(
+                key = %String::from_utf8_lossy(&key),
+                "Expiry scan: removed expired key"
+            );
+        }
+
+        Ok(None)
+    }
+
+    /// Bytes the database occupies: SST files, WAL files and memtables of
+    /// every column family in every shard
+    pub fn db_size(&self) -> u64 {
+        let property = |name: &str| {
+            let namespaces: u64 = self
+                .namespaces
+                .iter()
+                .filter_map(|ns| self.namespace_property(ns, name))
+                .sum();
+            self.property(name).unwrap_or(0) + namespaces
+        };
+        let wal: u64 = self
+            .shards
+            .iter()
+            .map(|shard| {
+                std::fs::read_dir(&shard.wal_path).map_or(0, |entries| {
+                    entries
+                        .filter_map(Result::ok)
+                        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "log"))
+                        .filter_map(|entry| entry.metadata().ok())
+                        .map(|metadata| metadata.len())
+                        .sum()
+                })
+            })
+            .sum();
+        property("rocksdb.total-sst-files-size") + property("rocksdb.cur-size-all-mem-tables") + wal
+    }
+
+    /// Count RocksDB background errors so far and check that writes still
+    /// succeed
+    ///
+    /// A background error (I/O error or full disk in a flush or compaction)
+    /// can stop a database from accepting writes until RocksDB recovers.
+    /// A canary key is written to every shard to find out; a secondary
+    /// can't write and only counts errors. Blocks on I/O; call it from a
+    /// blocking thread.
+    pub fn check_health(&self) -> HealthCheck {
+        let background_errors = self.property("rocksdb.background-errors").unwrap_or(0);
+        let write_error = if self.read_only {
+            None
+        } else {
+            let now = current_timestamp().to_be_bytes();
+            self.shards
+                .iter()
+                .find_map(|shard| {
+                    shard
+                        .db
+                        .put_opt(HEALTH_CANARY_KEY, now, &self.write_opts)
+                        .err()
+                })
+                .map(|e| e.to_string())
+        };
+        HealthCheck {
+            background_errors,
+            write_error,
+        }
+    }
+
+    /// Read a reserved key from every shard, for the readiness probe
+    pub fn probe_read(&self) -> Result<(), StorageError> {
+        for shard in &self.shards {
+            shard.db.get(READINESS_PROBE_KEY)?;
+        }
+        Ok(())
+    }
+
+    /// Write and delete a reserved key in every shard, for the readiness
+    /// probe
+    pub fn probe_write(&self) -> Result<(), StorageError> {
+        self.ensure_writable()?;
+        let now = current_timestamp().to_be_bytes();
+        for shard in &self.shards {
+            shard
+                .db
+                .put_opt(READINESS_PROBE_KEY, now, &self.write_opts)?;
+            shard.db.delete_opt(READINESS_PROBE_KEY, &self.write_opts)?;
+        }
+        Ok(())
+    }
+
+    /// Delete entries until about `bytes_to_free` bytes of keys and values
+    /// are gone, or every key was sampled once
+    ///
+    /// Samples `EVICT_SAMPLE_KEYS` consecutive keys at a time, continuing
+    /// where the previous call stopped, and evicts up to half of each
+    /// sample: entries expiring soonest first, then entries without a TTL
+    /// by age (CAS token). The key range evicted from is compacted after,
+    /// since deletes alone free no disk space. Blocks on I/O; call it from
+    /// a blocking thread.
+    pub fn evict(&self, bytes_to_free: u64) -> Result<Eviction, StorageError> {
+        self.ensure_writable()?;
+        let mut eviction = Eviction::default();
+        // Keys hash evenly over shards, so each frees its share; what one
+        // falls short of is left to the next
+        for (index, shard) in self.shards.iter().enumerate() {
+            let remaining = bytes_to_free.saturating_sub(eviction.bytes);
+            if remaining == 0 {
+                break;
+            }
+            let share = remaining.div_ceil((self.shards.len() - index) as u64);
+            let freed = self.evict_shard(shard, share)?;
+            eviction.keys += freed.keys;
+            eviction.bytes += freed.bytes;
+        }
+        info!(
+            keys = eviction.keys,
+            bytes = eviction.bytes,
+            "Evicted entries to free space"
+        );
+        Ok(eviction)
+    }
+
+    /// One shard's part of `evict`
+    fn evict_shard(&self, shard: &Shard, bytes_to_free: u64) -> Result<Eviction, StorageError> {
+        let mut eviction = Eviction::default();
+        let start = shard.evict_cursor.lock().clone();
+        let mut from = start.clone();
+        let mut wrapped = false;
+        let mut span: Option<(Vec<u8>, Vec<u8>)> = None;
+
+        loop {
+            let (mut candidates, resume_from) = self.eviction_sample(shard, from.as_deref())?;
+            candidates.sort_by_key(|c| (c.expire_at == 0, c.expire_at, c.cas));
+            let budget = candidates.len().div_ceil(2);
+            for candidate in candidates.into_iter().take(budget) {
+                if eviction.bytes >= bytes_to_free {
+                    break;
+                }
+                let _guard = self.key_locks.lock(&candidate.key);
+                // Skip entries rewritten since the sample
+                let Some(current) = shard.db.get(&candidate.key)? else {
+                    continue;
+                };
+                if ValueHeader::read(&current).is_none_or(|header| header.cas != candidate.cas) {
+                    continue;
+                }
+                self.delete_key(&candidate.key)?;
+                eviction.keys += 1;
+                eviction.bytes += candidate.size;
+                span = Some(match span {
+                    None => (candidate.key.clone(), candidate.key),
+                    Some((first, last)) => {
+                        (first.min(candidate.key.clone()), last.max(candidate.key))
+                    }
+                });
+            }
+
+            from = resume_from;
+            if eviction.bytes >= bytes_to_free {
+                break;
+            }
+            match &from {
+                // Back at the first sampled key: everything was seen
+                Some(next) if wrapped && start.as_ref().is_some_and(|s| next >= s) => break,
+                Some(_) => {}
+                None if wrapped || start.is_none() => break,
+                None => wrapped = true,
+            }
+        }
+        *shard.evict_cursor.lock() = from;
+
+        if let Some((first, last)) = span {
+            shard.db.compact_range(Some(first), Some(last));
+        }
+        Ok(eviction)
+    }
+
+    /// Headers of up to `EVICT_SAMPLE_KEYS` client keys from `from` on,
+    /// and the key to continue at (`None` at the end)
+    fn eviction_sample(
+        &self,
+        shard: &Shard,
+        from: Option<&[u8]>,
+    ) -> Result<(Vec<EvictionCandidate>, Option<Vec<u8>>), StorageError> {
+        let mode = match from {
+            Some(key) => IteratorMode::From(key, Direction::Forward),
+            None => IteratorMode::Start,
+        };
+        let mut candidates = Vec::with_capacity(EVICT_SAMPLE_KEYS);
+        for item in shard.db.iterator(mode) {
+            let (key, bytes) = item?;
+            if candidates.len() >= EVICT_SAMPLE_KEYS {
+                return Ok((candidates, Some(key.into_vec())));
+            }
+            // See `scan_expired`
+            if key.first() == Some(&INTERNAL_KEY_PREFIX) || self.namespace(&key).is_some() {
+                continue;
+            }
+            let Some(header) = ValueHeader::read(&bytes) else {
+                continue;
+            };
+            candidates.push(EvictionCandidate {
+                expire_at: header.expire_at,
+                cas: header.cas,
+                size: (key.len() + bytes.len()) as u64,
+                key: key.into_vec(),
+            });
+        }
+        Ok((candidates, None))
+    }
+
+    /// Classify a raw value from its header alone
+    fn header_state(&self, bytes: &[u8]) -> HeaderState {
+        let Some(header) = ValueHeader::read(bytes) else {
+            return HeaderState::Live;
+        };
+        if header.expire_at != 0 && current_timestamp() >= header.expire_at {
+            HeaderState::Expired
+        } else if self.flush_epoch.is_flushed(header.cas) {
+            HeaderState::Flushed
+        } else {
+            HeaderState::Live
+        }
+    }
+
+    /// Get TTL expiration statistics of this database
+    pub fn ttl_stats(&self) -> TtlStats {
+        self.ttl_counters.stats()
+    }
+
+    /// The live TTL counters, for exporting them as metrics
+    pub fn ttl_counters(&self) -> Arc<TtlCounters> {
+        Arc::clone(&self.ttl_counters)
+    }
+
+    /// Flush memtables to SST files, returning the memtable bytes flushed
+    ///
+    /// Unless `storage.wal` is enabled, writes skip the WAL, so anything
+    /// still in a memtable is lost if the process exits without this; call
+    /// it on shutdown.
+    pub fn flush(&self) -> Result<u64, StorageError> {
+        let bytes = self
+            .property("rocksdb.cur-size-all-mem-tables")
+            .unwrap_or(0);
+        for shard in &self.shards {
+            shard.db.flush()?;
+        }
+        Ok(bytes)
+    }
+
+    /// Compact the whole keyspace, blocking until done
+    ///
+    /// Returns how many keys the TTL filter removed meanwhile; compactions
+    /// RocksDB ran in the background during the call are counted too.
+    pub fn compact(&self) -> u64 {
+        info!("Starting manual compaction");
+        let before = self.ttl_stats().compaction_removed;
+        for shard in &self.shards {
+            shard.db.compact_range::<&[u8], &[u8]>(None, None);
+            for ns in &self.namespaces {
+                // Not while flush_namespace swaps the column family
+                let _swap = ns.swap.read();
+                if let Some(cf) = shard.db.cf_handle(&ns.cf) {
+                    shard.db.compact_range_cf::<&[u8], &[u8]>(&cf, None, None);
+                }
+            }
+        }
+        let removed = self.ttl_stats().compaction_removed.saturating_sub(before);
+        info!(removed, "Manual compaction completed");
+        removed
+    }
+
+    /// Back the database up into `dir`, incrementally: SST files already in
+    /// an earlier backup there are shared, not copied again
+    ///
+    /// Memtables are flushed first (writes may skip the WAL, so a backup
+    /// without the flush would miss them); writes carry on meanwhile. With
+    /// several shards each is backed up to its own `shard-{i}` directory,
+    /// one after another. This blocks on I/O; call it from a blocking
+    /// thread.
+    pub fn create_backup(&self, dir: &Path) -> Result<BackupInfo, StorageError> {
+        let mut combined: Option<BackupInfo> = None;
+        for (shard, shard_dir) in self.shards.iter().zip(shard_paths(dir, self.shards.len())) {
+            let mut engine = Self::backup_engine(&shard_dir)?;
+            engine.create_new_backup_flush(&shard.db, true)?;
+            let info = Self::latest_backup(&engine)
+                .ok_or_else(|| StorageError::Internal("new backup is not listed".to_string()))?;
+            combined = Some(match combined {
+                None => info,
+                Some(first) => first.combine(info),
+            });
+        }
+        let info = combined.expect("at least one shard");
+        info!(
+            backup_id = info.backup_id,
+            size = info.size,
+            files = info.num_files,
+            "Backup created"
+        );
+        Ok(info)
+    }
+
+    /// Delete all but the `keep` newest backups in `dir`
+    pub fn purge_old_backups(&self, dir: &Path, keep: usize) -> Result<(), StorageError> {
+        for shard_dir in shard_paths(dir, self.shards.len()) {
+            Self::backup_engine(&shard_dir)?.purge_old_backups(keep)?;
+        }
+        Ok(())
+    }
+
+    /// Restore `db_path` from the newest backup in `backup_dir`, replacing
+    /// any files already there
+    ///
+    /// `shards` must match the layout the backup was made with. Call
+    /// before `open`; the database must not be open meanwhile.
+    pub fn restore_latest_backup(
+        backup_dir: &Path,
+        db_path: &Path,
+        shards: usize,
+    ) -> Result<BackupInfo, StorageError> {
+        let mut engines = shard_paths(backup_dir, shards)
+            .iter()
+            .map(|dir| {
+                let engine = Self::backup_engine(dir)?;
+                let info = Self::latest_backup(&engine).ok_or_else(|| {
+                    StorageError::Internal(format!("no backup in {}", dir.display()))
+                })?;
+                Ok((engine, info))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        let info = engines
+            .iter()
+            .map(|&(_, info)| info)
+            .reduce(BackupInfo::combine)
+            .expect("at least one shard");
+        info!(
+            backup_id = info.backup_id,
+            size = info.size,
+            files = info.num_files,
+            "Restoring database from backup"
+        );
+        let started = Instant::now();
+        if shards > 1 {
+            std::fs::create_dir_all(db_path).map_err(|e| {
+                StorageError::Internal(format!("cannot create {}: {e}", db_path.display()))
+            })?;
+        }
+        for ((engine, shard_info), path) in engines.iter_mut().zip(shard_paths(db_path, shards)) {
+            engine.restore_from_backup(
+                &path,
+                &path,
+                &RestoreOptions::default(),
+                shard_info.backup_id,
+            )?;
+        }
+        info!(
+            backup_id = info.backup_id,
+            elapsed_ms = started.elapsed().as_millis(),
+            "Database restored"
+        );
+        Ok(info)
+    }
+
+    /// Write a consistent snapshot of the database to `dir`, which must not
+    /// exist yet, returning its size in bytes
+    ///
+    /// Memtables are flushed first; SST files are hard-linked when `dir` is
+    /// on the same filesystem, so the snapshot costs almost no I/O. The
+    /// result opens as a regular database, or with several shards as a
+    /// `shard-{i}` directory per shard, the layout `open` expects.
+    pub fn create_checkpoint(&self, dir: &Path) -> Result<u64, StorageError> {
+        if self.shards.len() > 1 {
+            std::fs::create_dir_all(dir).map_err(|e| {
+                StorageError::Internal(format!("cannot create {}: {e}", dir.display()))
+            })?;
+        }
+        for (shard, shard_dir) in self.shards.iter().zip(shard_paths(dir, self.shards.len())) {
+            Checkpoint::new(&shard.db)?.create_checkpoint(&shard_dir)?;
+        }
+        let size = dir_size(dir)
+            .map_err(|e| StorageError::Internal(format!("cannot size checkpoint: {e}")))?;
+        info!(dir = ?dir, size, "Checkpoint created");
+        Ok(size)
+    }
+
+    /// Bulk-load SST files written by `SstBuilder`, returning how many
+    /// keys they hold
+    ///
+    /// Each file is first validated in a scratch database next to
+    /// `db_path`: every key must be a valid client key and every value
+    /// must carry a header, and one value in `INGEST_SAMPLE_EVERY` is
+    /// fully decoded. Only if all files pass are they ingested, in one
+    /// atomic step per shard; ingested entries replace existing ones. The
+    /// files are copied, not moved; with several shards their entries are
+    /// first split into per-shard files next to `db_path`. This blocks on
+    /// I/O; call it from a blocking thread.
+    pub fn ingest_sst_files(&self, files: &[PathBuf]) -> Result<IngestStats, StorageError> {
+        self.ensure_writable()?;
+        let started = Instant::now();
+        let mut split_path = self.config.db_path.clone().into_os_string();
+        split_path.push(".ingest-split");
+        let split_path = PathBuf::from(split_path);
+        let result = self.ingest_validated(files, &split_path);
+        if self.shards.len() > 1 {
+            let _ = std::fs::remove_dir_all(&split_path);
+        }
+        let stats = result?;
+        // Ingestion bypasses `put`, so nothing invalidated the caches
+        if let Some(cache) = &self.hot_cache {
+            cache.clear();
+        }
+        if let Some(cache) = &self.negative_cache {
+            cache.clear();
+        }
+        info!(
+            files = stats.files,
+            keys = stats.keys,
+            elapsed_ms = started.elapsed().as_millis(),
+            "SST files ingested"
+        );
+        Ok(stats)
+    }
+
+    /// Validate `files` and ingest them into their shards, splitting them
+    /// under `split_path` when there are several
+    fn ingest_validated(
+        &self,
+        files: &[PathBuf],
+        split_path: &Path,
+    ) -> Result<IngestStats, StorageError> {
+        let split_opts = Options::default();
+        let mut split = (self.shards.len() > 1)
+            .then(|| SstSplit::new(split_path, &split_opts, self.shards.len()))
+            .transpose()?;
+        let mut stats = IngestStats::default();
+        let mut max_cas = 0;
+        for file in files {
+            let (keys, file_max_cas) = self.validate_sst(file, split.as_mut())?;
+            stats.keys += keys;
+            max_cas = max_cas.max(file_max_cas);
+        }
+        match split {
+            Some(split) => {
+                for (shard, shard_files) in self.shards.iter().zip(split.files) {
+                    if !shard_files.is_empty() {
+                        shard.db.ingest_external_file(shard_files)?;
+                    }
+                }
+            }
+            None => self.shards[0].db.ingest_external_file(files.to_vec())?,
+        }
+        stats.files = files.len();
+        // Later tokens must stay above the ingested ones, so `flush_all`
+        // covers them
+        self.last_cas.fetch_max(max_cas, Ordering::Relaxed);
+        Ok(stats)
+    }
+
+    /// Check one external SST file, returning its key count and highest
+    /// CAS token; its entries are added to `split` if given
+    fn validate_sst(
+        &self,
+        file: &Path,
+        split: Option<&mut SstSplit<'_>>,
+    ) -> Result<(u64, u64), StorageError> {
+        let mut scratch_path = self.config.db_path.clone().into_os_string();
+        scratch_path.push(".ingest-check");
+        let scratch_path = PathBuf::from(scratch_path);
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let _ = DB::destroy(&opts, &scratch_path);
+
+        let result = Self::scan_sst(&opts, &scratch_path, file, split);
+        let _ = DB::destroy(&opts, &scratch_path);
+        let _ = std::fs::remove_dir_all(&scratch_path);
+        result
+    }
+
+    /// Ingest `file` into a scratch database at `scratch_path` and check
+    /// every entry
+    fn scan_sst(
+        opts: &Options,
+        scratch_path: &Path,
+        file: &Path,
+        mut split: Option<&mut SstSplit<'_>>,
+    ) -> Result<(u64, u64), StorageError> {
+        let scratch = DB::open(opts, scratch_path)?;
+        scratch.ingest_external_file(vec![file])?;
+        let invalid = |what: String| StorageError::Decoding(format!("{}: {what}", file.display()));
+        let (mut keys, mut max_cas) = (0, 0);
+        for item in scratch.iterator(IteratorMode::Start) {
+            let (key, bytes) = item?;
+            if !is_valid_key(&key) {
+                return Err(invalid(format!(
+                    "invalid key {:?}",
+                    String::from_utf8_lossy(&key)
+                )));
+            }
+            let Some(header) = ValueHeader::read(&bytes) else {
+                return Err(invalid("value without header".to_string()));
+            };
+            if keys % INGEST_SAMPLE_EVERY == 0 {
+                StoredValue::decode(&bytes).map_err(|e| invalid(e.to_string()))?;
+            }
+            if let Some(split) = split.as_deref_mut() {
+                split.add(&key, &bytes)?;
+            }
+            max_cas = max_cas.max(header.cas);
+            keys += 1;
+        }
+        if keys == 0 {
+            return Err(invalid("no entries".to_string()));
+        }
+        if let Some(split) = split {
+            split.finish()?;
+        }
+        Ok((keys, max_cas))
+    }
+
+    fn backup_engine(dir: &Path) -> Result<BackupEngine, StorageError> {
+        let options = BackupEngineOptions::new(dir)?;
+        Ok(BackupEngine::open(&options, &Env::new()?)?)
+    }
+
+    fn latest_backup(engine: &BackupEngine) -> Option<BackupInfo> {
+        engine
+            .get_backup_info()
+            .into_iter()
+            .max_by_key(|info| info.backup_id)
+            .map(BackupInfo::from)
+    }
+}
+
+/// Per-shard SST files that external files are split into for ingestion
+struct SstSplit<'a> {
+    dir: PathBuf,
+    opts: &'a Options,
+    /// Writers for the file being split, created on a shard's first key
+    writers: Vec<Option<SstFileWriter<'a>>>,
+    /// Files written so far, by shard
+    files: Vec<Vec<PathBuf>>,
+    /// Number of external files split so far
+    split: usize,
+}
+
+impl<'a> SstSplit<'a> {
+    fn new(dir: &Path, opts: &'a Options, shards: usize) -> Result<Self, StorageError> {
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir)
+            .map_err(|e| StorageError::Internal(format!("cannot create {}: {e}", dir.display())))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            opts,
+            writers: (0..shards).map(|_| None).collect(),
+            files: vec![Vec::new(); shards],
+            split: 0,
+        })
+    }
+
+    /// Add an entry of the current file; keys come in increasing order
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<(), StorageError> {
+        let shard = shard_index(key, self.writers.len());
+        if self.writers[shard].is_none() {
+            let path = self.dir.join(format!("{}-shard-{shard}.sst", self.split));
+            let writer = SstFileWriter::create(self.opts);
+            writer.open(&path)?;
+            self.writers[shard] = Some(writer);
+            self.files[shard].push(path);
+        }
+        if let Some(writer) = &mut self.writers[shard] {
+            writer.put(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Complete the current file's per-shard files
+    fn finish(&mut self) -> Result<(), StorageError> {
+        for writer in &mut self.writers {
+            if let Some(mut writer) = writer.take() {
+                writer.finish()?;
+            }
+        }
+        self.split += 1;
+        Ok(())
+    }
+}
+
+/// Result of `RocksStorage::check_health`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// Background errors RocksDB reported since the database was opened
+    pub background_errors: u64,
+    /// Why the canary write failed, if it did
+    pub write_error: Option<String>,
+}
+
+/// What one `evict` call deleted
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Eviction {
+    pub keys: u64,
+    /// Key and encoded value bytes of the deleted entries
+    pub bytes: u64,
+}
+
+/// An entry `evict` may delete
+struct EvictionCandidate {
+    key: Vec<u8>,
+    expire_at: u64,
+    cas: u64,
+    size: u64,
+}
+
+/// What `ingest_sst_files` loaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IngestStats {
+    pub files: usize,
+    pub keys: u64,
+}
+
+/// Whether `key` may be stored as a client key
+pub(crate) fn is_valid_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LENGTH && key[0] != INTERNAL_KEY_PREFIX
+}
+
+/// A backup made by `create_backup`
+#[derive(Debug, Clone, Copy)]
+pub struct BackupInfo {
+    pub backup_id: u32,
+    /// Bytes in the backup, including files shared with earlier ones
+    pub size: u64,
+    pub num_files: u32,
+    /// Creation time (Unix seconds)
+    pub timestamp: i64,
+}
+
+impl BackupInfo {
+    /// Merge the backups of two shards: sizes add up, the id and time are
+    /// the first one's
+    fn combine(self, other: Self) -> Self {
+        Self {
+            size: self.size + other.size,
+            num_files: self.num_files + other.num_files,
+            ..self
+        }
+    }
+}
+
+impl From<BackupEngineInfo> for BackupInfo {
+    fn from(info: BackupEngineInfo) -> Self {
+        Self {
+            backup_id: info.backup_id,
+            size: info.size,
+            num_files: info.num_files,
+            timestamp: info.timestamp,
+        }
+    }
+}
+
+/// Progress of one `scan_expired` slice
+#[derive(Debug, Clone, Default)]
+pub struct ExpiryScan {
+    /// Keys inspected
+    pub scanned: u64,
+    /// Expired or flushed keys deleted
+    pub removed: u64,
+    /// Where to continue, `None` once the end of the keyspace was reached
+    pub resume_from: Option<ScanCursor>,
+}
+
+/// Position of the expiry scan: a key within a shard
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanCursor {
+    /// Index of the shard
+    pub shard: usize,
+    /// Key to continue from within the shard
+    pub key: Vec<u8>,
+}
+
+/// Whether a stored value is still served (see `RocksStorage::header_state`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HeaderState {
+    Live,
+    Expired,
+    Flushed,
+}
+
+/// Log entries for keys removed by lazy expiration: every one at `trace`,
+/// one in `expired_log_sample_rate` at `debug`. The key is only included
+/// with `expired_log_keys`; `TtlCounters` remains the signal to alert on
+#[derive(Debug)]
+struct ExpiredLog {
+    sample_rate: u64,
+    log_keys: bool,
+    removed: AtomicU64,
+}
+
+impl ExpiredLog {
+    fn new(config: &StorageConfig) -> Self {
+        Self {
+            sample_rate: config.expired_log_sample_rate,
+            log_keys: config.expired_log_keys,
+            removed: AtomicU64::new(0),
+        }
+    }
+
+    /// Log the removal of `key`, which expired at `expire_at`
+    fn record(&self, key: &[u8], expire_at: u64) {
+        let key = self.log_keys.then(|| String::from_utf8_lossy(key));
+        let key = key.as_deref();
+        trace!(key, expire_at, "Lazy expiration: removed expired key");
+        if self.sampled() {
+            debug!(
+                key,
+                expire_at,
+                sample_rate = self.sample_rate,
+                "Lazy expiration: removed expired key (sampled)"
+            );
+        }
+    }
+
+    /// Whether this removal is one of the sampled ones (the first, then
+    /// every `sample_rate`-th)
+    fn sampled(&self) -> bool {
+        self.sample_rate > 0 && self.removed.fetch_add(1, Ordering::Relaxed) % self.sample_rate == 0
+    }
+}
+
+/// Expired keys removed from one `RocksStorage`, counted by reads, the
+/// expiry scan and the TTL compaction filter of its column families
+#[derive(Debug, Default)]
+pub struct TtlCounters {
+    expired_removed: AtomicU64,
+    compaction_removed: AtomicU64,
+}
+
+impl TtlCounters {
+    /// Current counter values
+    pub fn stats(&self) -> TtlStats {
+        TtlStats {
+            expired_removed: self.expired_removed.load(Ordering::Relaxed),
+            compaction_removed: self.compaction_removed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// TTL expiration statistics
+#[derive(Debug, Clone, Default)]
+pub struct TtlStats {
+    /// Keys removed by lazy expiration or background scan
+    pub expired_removed: u64,
+    /// Keys removed by compaction filter
+    pub compaction_removed: u64,
+}
+
+/// Set `field` to `value` if it parses
+fn update<T: std::str::FromStr>(field: &mut T, value: &str) {
+    if let Ok(value) = value.parse() {
+        *field = value;
+    }
+}
+
+/// The `[storage]` settings `set_options` can change, as option name and
+/// value
+pub fn runtime_options(config: &StorageConfig) -> [(&'static str, String); 4] {
+    [
+        ("write_buffer_size", config.write_buffer_size.to_string()),
+        (
+            "max_write_buffer_number",
+            config.max_write_buffer_number.to_string(),
+        ),
+        (
+            "target_file_size_base",
+            config.target_file_size_base.to_string(),
+        ),
+        (
+            "max_background_jobs",
+            config.max_background_jobs.to_string(),
+        ),
+    ]
+}
+
+fn parse_log_level(level: &str) -> LogLevel {
+    match level.to_lowercase().as_str() {
+        "debug" => LogLevel::Debug,
+        "info" => LogLevel::Info,
+        "warn" => LogLevel::Warn,
+        "fatal" => LogLevel::Fatal,
+        "header" => LogLevel::Header,
+        _ => LogLevel::Error, // "error" or any unknown value
+    }
+}
+
+/// Validate `storage.namespaces` and build their column family names
+fn parse_namespaces(configs: &[NamespaceConfig]) -> Result<Vec<Namespace>, StorageError> {
+    if configs.len() > MAX_NAMESPACES {
+        return Err(StorageError::Internal(format!(
+            "at most {MAX_NAMESPACES} namespaces are supported"
+        )));
+    }
+    let mut namespaces: Vec<Namespace> = Vec::with_capacity(configs.len());
+    for NamespaceConfig { name, max_bytes } in configs {
+        let valid = !name.is_empty()
+            && name.len() <= MAX_NAMESPACE_LENGTH
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+        if !valid {
+            return Err(StorageError::Internal(format!(
+                "invalid namespace {name:?}: use up to {MAX_NAMESPACE_LENGTH} letters, digits, '_' or '-'"
+            )));
+        }
+        if namespaces.iter().any(|ns| ns.name == *name) {
+            return Err(StorageError::Internal(format!(
+                "namespace {name:?} is listed twice"
+            )));
+        }
+        namespaces.push(Namespace {
+            name: name.clone(),
+            prefix: format!("{name}:").into_bytes(),
+            cf: format!("ns.{name}"),
+            swap: RwLock::new(()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            max_bytes: *max_bytes,
+            over_quota: AtomicBool::new(false),
+            rejected_writes: AtomicU64::new(0),
+        });
+    }
+    Ok(namespaces)
+}
+
+/// Which of `shards` shards `key` is stored in
+fn shard_index(key: &[u8], shards: usize) -> usize {
+    if shards <= 1 {
+        0
+    } else {
+        crc32c::crc32c(key) as usize % shards
+    }
+}
+
+/// Total size of the files under `dir`
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    std::fs::read_dir(dir)?
+        .map(|entry| {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dir_size(&entry.path())
+            } else {
+                Ok(metadata.len())
+            }
+        })
+        .sum()
+}
+
+/// Database directory of each of `shards` shards under `db_path`
+fn shard_paths(db_path: &Path, shards: usize) -> Vec<PathBuf> {
+    if shards <= 1 {
+        vec![db_path.to_path_buf()]
+    } else {
+        (0..shards)
+            .map(|i| db_path.join(format!("shard-{i}")))
+            .collect()
+    }
+}
+
+/// Create the parent of the database directories and every shard's
+/// directory in `db_paths`
+fn create_directories(config: &StorageConfig) -> Result<(), StorageError> {
+    let create = |dir: &Path| {
+        std::fs::create_dir_all(dir).map_err(|e| {
+            StorageError::Internal(format!("Failed to create directory {}: {e}", dir.display()))
+        })
+    };
+    let dir = if config.shards > 1 {
+        Some(config.db_path.as_path())
+    } else {
+        config.db_path.parent()
+    };
+    if let Some(dir) = dir {
+        create(dir)?;
+    }
+    for data_path in &config.db_paths {
+        for dir in shard_paths(&data_path.path, config.shards) {
+            create(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+/// The directory of shard `shard_path` under `dir`, another root laid
+/// out like `db_path` (`wal_dir`, `db_paths`)
+fn shard_subdir(dir: &Path, shard_path: &Path, shards: usize) -> PathBuf {
+    if shards > 1 {
+        dir.join(shard_path.file_name().unwrap_or_default())
+    } else {
+        dir.to_path_buf()
+    }
+}
+
+/// Total size of the SST files directly in `dir`
+fn sst_bytes(dir: &Path) -> u64 {
+    std::fs::read_dir(dir).map_or(0, |entries| {
+        entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "sst"))
+            .filter_map(|entry| entry.metadata().ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    })
+}
+
+/// Refuse a database written with a different `storage.shards`: its keys
+/// would be looked up in the wrong shard
+fn check_shard_layout(db_path: &Path, shards: usize) -> Result<(), StorageError> {
+    let unsharded = db_path.join("CURRENT").exists();
+    let existing = (0..MAX_SHARDS)
+        .take_while(|i| db_path.join(format!("shard-{i}")).exists())
+        .count();
+    let found = match (unsharded, existing) {
+        (true, _) => 1,
+        (false, 0) => return Ok(()),
+        (false, n) => n,
+    };
+    if found == shards {
+        Ok(())
+    } else {
+        Err(StorageError::Internal(format!(
+            "{} holds {found} shard(s) but storage.shards is {shards}",
+            db_path.display()
+        )))
+    }
+}
+
+/// Refuse a database written with a newer value encoding than this build
+/// decodes, and mark it with ours
+///
+/// Databases from before the marker hold v1 and v2 values, both of which
+/// `StoredValue::decode` reads.
+fn check_format(db: &DB, write_opts: &WriteOptions, read_only: bool) -> Result<(), StorageError> {
+    match db.get(FORMAT_KEY)?.as_deref() {
+        Some([version]) if *version <= value::VERSION => {}
+        Some([version]) => {
+            return Err(StorageError::Internal(format!(
+                "database holds values in format v{version}, this build reads up to v{}",
+                value::VERSION
+            )));
+        }
+        Some(bytes) => {
+            return Err(StorageError::Internal(format!(
+                "invalid value format marker {bytes:?}"
+            )));
+        }
+        None => {}
+    }
+    if !read_only {
+        db.put_opt(FORMAT_KEY, [value::VERSION], write_opts)?;
+    }
+    Ok(())
+}
+
+/// Open one shard's database at `path`, as a secondary following
+/// `primary_path` if set
+fn open_shard(
+    config: &StorageConfig,
+    path: PathBuf,
+    primary_path: Option<&Path>,
+    namespaces: &[Namespace],
+    cache: &Cache,
+    filter_state: (&Arc<FlushEpoch>, &Arc<TtlCounters>),
+) -> Result<Shard, StorageError> {
+    let (mut opts, fifo) = column_options(config, cache, filter_state)?;
+    if config.enable_statistics {
+        // Tickers and histograms for /metrics, on top of FIFO's drop ticker
+        opts.enable_statistics();
+        opts.set_statistics_level(StatsLevel::ExceptDetailedTimers);
+    }
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    opts.set_max_background_jobs(config.max_background_jobs);
+    configure_background_io(&mut opts, config)?;
+
+    // RocksDB LOG file settings
+    opts.set_log_level(parse_log_level(&config.rocksdb_log_level));
+    opts.set_max_log_file_size(config.rocksdb_max_log_file_size);
+    opts.set_keep_log_file_num(config.rocksdb_keep_log_file_num);
+
+    let wal_path = configure_paths(&mut opts, config, &path)?;
+    if config.max_total_wal_size > 0 {
+        opts.set_max_total_wal_size(config.max_total_wal_size);
+    }
+
+    if primary_path.is_some() {
+        // The primary deletes files on compaction; a secondary must keep
+        // every file it reads open to survive that
+        opts.set_max_open_files(-1);
+    }
+
+    let source = primary_path.unwrap_or(&path);
+    let open = || -> Result<DB, StorageError> {
+        let column_families = column_families(&opts, source, namespaces, primary_path.is_some())?;
+        let descriptors = column_families
+            .iter()
+            .map(|cf| {
+                let (cf_opts, _) = column_options(config, cache, filter_state)?;
+                Ok(ColumnFamilyDescriptor::new(cf, cf_opts))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        Ok(match primary_path {
+            Some(primary_path) => {
+                DB::open_cf_descriptors_as_secondary(&opts, primary_path, &path, descriptors)?
+            }
+            None => DB::open_cf_descriptors(&opts, &path, descriptors)?,
+        })
+    };
+    let db = match open() {
+        // A secondary can't repair what it doesn't own
+        Err(e) if primary_path.is_none() && is_corruption(&e) => {
+            recover_corrupt(config, &opts, &path, &wal_path, e)?;
+            open()?
+        }
+        result => result?,
+    };
+    let rate_limited = config.rate_limit_bytes_per_sec > 0;
+    Ok(Shard {
+        db,
+        wal_path,
+        statistics: (fifo || rate_limited || config.enable_statistics).then_some(opts),
+        evict_cursor: Mutex::new(None),
+    })
+}
+
+/// Point the WAL and SST files of shard `path` to `wal_dir` and `db_paths`;
+/// returns the directory of its WAL files
+fn configure_paths(
+    opts: &mut Options,
+    config: &StorageConfig,
+    path: &Path,
+) -> Result<PathBuf, StorageError> {
+    let wal_path = match &config.wal_dir {
+        Some(dir) => shard_subdir(dir, path, config.shards),
+        None => path.to_path_buf(),
+    };
+    if config.wal_dir.is_some() {
+        opts.set_wal_dir(&wal_path);
+    }
+    if !config.db_paths.is_empty() {
+        let db_paths = config
+            .db_paths
+            .iter()
+            .map(|data_path| {
+                let dir = shard_subdir(&data_path.path, path, config.shards);
+                let target_size = data_path.target_size_bytes / config.shards.max(1) as u64;
+                DBPath::new(dir, target_size.max(1))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        opts.set_db_paths(&db_paths);
+    }
+    Ok(wal_path)
+}
+
+fn is_corruption(error: &StorageError) -> bool {
+    matches!(error, StorageError::RocksDb(e) if e.kind() == ErrorKind::Corruption)
+}
+
+/// Act on `error`, a corruption found opening shard `path`, as
+/// `storage.on_corruption` says; the caller opens the shard again after
+/// `Ok`
+fn recover_corrupt(
+    config: &StorageConfig,
+    opts: &Options,
+    path: &Path,
+    wal_path: &Path,
+    error: StorageError,
+) -> Result<(), StorageError> {
+    match config.on_corruption {
+        OnCorruption::Fail => Err(error),
+        OnCorruption::Repair => {
+            error!(path = %path.display(), %error, "Database is corrupt, repairing it");
+            DB::repair(opts, path)?;
+            DB_REPAIRS.fetch_add(1, Ordering::Relaxed);
+            warn!(path = %path.display(), "Database repaired; data RocksDB couldn't read is gone");
+            Ok(())
+        }
+        OnCorruption::Recreate => {
+            error!(path = %path.display(), %error, "Database is corrupt, recreating it empty");
+            let aside = format!("{CORRUPT_PREFIX}{}", current_timestamp());
+            let mut dirs: Vec<PathBuf> = Vec::new();
+            let data_paths = config
+                .db_paths
+                .iter()
+                .map(|data_path| shard_subdir(&data_path.path, path, config.shards));
+            for dir in [path.to_path_buf(), wal_path.to_path_buf()]
+                .into_iter()
+                .chain(data_paths)
+            {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+            for dir in &dirs {
+                if let Some(aside) = move_aside(dir, &aside)? {
+                    error!(from = %dir.display(), to = %aside.display(), "Moved corrupt database files aside");
+                }
+            }
+            create_directories(config)?;
+            DB_RECREATES.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+}
+
+/// Move everything in `dir` into its subdirectory `name`, returning that
+/// subdirectory, or `None` if there was nothing to move
+///
+/// `dir` itself stays: with one shard it is `db_path`, `wal_dir` or a
+/// `db_paths` entry, often a mount point, which can't be renamed. Files
+/// moved aside by earlier recoveries stay where they are; RocksDB ignores
+/// the subdirectories.
+fn move_aside(dir: &Path, name: &str) -> Result<Option<PathBuf>, StorageError> {
+    let failed = |e: std::io::Error| {
+        StorageError::Internal(format!("Failed to move {} aside: {e}", dir.display()))
+    };
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.collect::<Result<Vec<_>, _>>().map_err(failed)?,
+        // Already moved along with a directory it was nested in
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(failed(e)),
+    };
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|entry| {
+            !entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(CORRUPT_PREFIX)
+        })
+        .collect();
+    if entries.is_empty() {
+        return Ok(None);
+    }
+    let aside = dir.join(name);
+    std::fs::create_dir_all(&aside).map_err(failed)?;
+    for entry in entries {
+        std::fs::rename(entry.path(), aside.join(entry.file_name())).map_err(failed)?;
+    }
+    Ok(Some(aside))
+}
+
+/// Rate limiter, readahead, subcompactions and I/O priority of flushes and
+/// compactions, which compete with gets for the disk
+fn configure_background_io(opts: &mut Options, config: &StorageConfig) -> Result<(), StorageError> {
+    if config.rate_limit_bytes_per_sec > 0 {
+        let per_shard = (config.rate_limit_bytes_per_sec / config.shards.max(1) as u64).max(1);
+        opts.set_ratelimiter(
+            i64::try_from(per_shard).unwrap_or(i64::MAX),
+            RATE_LIMITER_REFILL_MICROS,
+            RATE_LIMITER_FAIRNESS,
+        );
+        if !config.enable_statistics {
+            // For the drain ticker behind petracache_rocksdb_rate_limiter_drains_total
+            opts.enable_statistics();
+            opts.set_statistics_level(StatsLevel::ExceptHistogramOrTimers);
+        }
+    }
+    opts.set_compaction_readahead_size(config.compaction_readahead_size);
+    opts.set_max_subcompactions(config.max_subcompactions);
+    if config.low_priority_compaction_io {
+        // Compactions run in the low-priority pool of the process-wide
+        // default environment
+        let mut env = Env::new()?;
+        env.lower_thread_pool_io_priority();
+        opts.set_env(&env);
+    }
+    Ok(())
+}
+
+/// Column families to open besides the default one
+///
+/// Every existing column family must be opened, including those of
+/// namespaces no longer configured; a secondary can only open the ones the
+/// primary has.
+fn column_families(
+    opts: &Options,
+    primary_path: &Path,
+    namespaces: &[Namespace],
+    read_only: bool,
+) -> Result<Vec<String>, StorageError> {
+    let existing = if primary_path.join("CURRENT").exists() {
+        DB::list_cf(opts, primary_path)?
+    } else {
+        Vec::new()
+    };
+    let mut column_families: Vec<String> = if read_only {
+        Vec::new()
+    } else {
+        namespaces.iter().map(|ns| ns.cf.clone()).collect()
+    };
+    for cf in existing {
+        if cf == DEFAULT_COLUMN_FAMILY_NAME || column_families.contains(&cf) {
+            continue;
+        }
+        if !namespaces.iter().any(|ns| ns.cf == cf) {
+            warn!(column_family = %cf, "Opening column family of an unconfigured namespace");
+        }
+        column_families.push(cf);
+    }
+    Ok(column_families)
+}
+
+/// Block-based table options: block size, bloom filter and index layout,
+/// with index and filter blocks in the shared block cache if configured
+fn table_options(config: &StorageConfig, cache: &Cache) -> BlockBasedOptions {
+    let mut block_opts = BlockBasedOptions::default();
+    block_opts.set_block_cache(cache);
+    block_opts.set_block_size(config.block_size);
+    if config.bloom_bits_per_key > 0 {
+        block_opts.set_bloom_filter(f64::from(config.bloom_bits_per_key), false);
+    }
+    if config.cache_index_and_filter_blocks {
+        block_opts.set_cache_index_and_filter_blocks(true);
+        // Pin L0 filter and index blocks (prevents eviction of hot data)
+        block_opts.set_pin_l0_filter_and_index_blocks_in_cache(true);
+    }
+    if config.index_type == IndexType::Partitioned {
+        block_opts.set_index_type(BlockBasedIndexType::TwoLevelIndexSearch);
+        // The top level is small and on every lookup's path
+        block_opts.set_pin_top_level_index_and_filter(true);
+        block_opts.set_partition_filters(config.partition_filters);
+    }
+    if let Some(version) = config.format_version {
+        block_opts.set_format_version(version);
+    }
+    block_opts
+}
+
+/// Default window bits for the zstd compression options
+const ZSTD_WINDOW_BITS: i32 = -14;
+
+/// Compression for every level and, when set, the bottommost one; the zstd
+/// level applies to whichever of the two uses zstd
+fn configure_compression(opts: &mut Options, config: &StorageConfig) {
+    let compression = config.effective_compression();
+    opts.set_compression_type(compression_type(compression));
+    if let Some(bottommost) = config.bottommost_compression {
+        opts.set_bottommost_compression_type(compression_type(bottommost));
+    }
+    if let Some(level) = config.zstd_level {
+        if compression == Compression::Zstd {
+            opts.set_compression_options(ZSTD_WINDOW_BITS, level, 0, 0);
+        }
+        if config.bottommost_compression == Some(Compression::Zstd) {
+            opts.set_bottommost_compression_options(ZSTD_WINDOW_BITS, level, 0, 0, true);
+        }
+    }
+}
+
+fn compression_type(compression: Compression) -> rust_rocksdb::DBCompressionType {
+    match compression {
+        Compression::None => rust_rocksdb::DBCompressionType::None,
+        Compression::Lz4 => rust_rocksdb::DBCompressionType::Lz4,
+        Compression::Snappy => rust_rocksdb::DBCompressionType::Snappy,
+        Compression::Zstd => rust_rocksdb::DBCompressionType::Zstd,
+    }
+}
+
+/// Options for one column family: memtables, compaction, compression,
+/// the shared block cache and the TTL compaction filter, which reads the
+/// storage's flush epoch and counts into its `TtlCounters`
+///
+/// Returns whether compaction is FIFO.
+fn column_options(
+    config: &StorageConfig,
+    cache: &Cache,
+    (flush_epoch, ttl_counters): (&Arc<FlushEpoch>, &Arc<TtlCounters>),
+) -> Result<(Options, bool), StorageError> {
+    let mut opts = Options::default();
+    opts.set_write_buffer_size(config.write_buffer_size);
+    opts.set_max_write_buffer_number(config.max_write_buffer_number);
+    opts.set_target_file_size_base(config.target_file_size_base);
+    let fifo = configure_compaction(&mut opts, config)?;
+
+    configure_compression(&mut opts, config);
+
+    let block_opts = table_options(config, cache);
+    opts.set_block_based_table_factory(&block_opts);
+
+    // TTL compaction filter (also drops values invalidated by flush_all)
+    if config.enable_ttl_compaction {
+        let epoch = Arc::clone(flush_epoch);
+        let counters = Arc::clone(ttl_counters);
+        opts.set_compaction_filter("ttl_filter", move |level, key: &[u8], value: &[u8]| {
+            ttl_compaction_filter(level, key, value, &epoch, &counters)
+        });
+    }
+    Ok((opts, fifo))
+}
+
+/// Set the compaction style, returning whether it is FIFO
+fn configure_compaction(opts: &mut Options, config: &StorageConfig) -> Result<bool, StorageError> {
+    if config.compaction == CompactionStyle::Level {
+        opts.set_compaction_style(DBCompactionStyle::Level);
+        return Ok(false);
+    }
+    if config.max_db_size_bytes == 0 {
+        return Err(StorageError::Internal(
+            "fifo compaction needs max_db_size_bytes".to_string(),
+        ));
+    }
+    // Files are dropped whole, never rewritten: the TTL filter has no
+    // compaction to run in, and expired items hold their space until
+    // their file ages out. FIFO caps each column family on its own, so
+    // every one of them (default and namespaces, in every shard) gets an
+    // equal part of the limit.
+    let column_families = config.shards.max(1) * (1 + config.namespaces.len());
+    let mut fifo_opts = FifoCompactOptions::default();
+    fifo_opts.set_max_table_files_size(config.max_db_size_bytes / column_families as u64);
+    opts.set_compaction_style(DBCompactionStyle::Fifo);
+    opts.set_fifo_compaction_options(&fifo_opts);
+    // For the FIFO drop ticker behind `stats` evictions
+    opts.enable_statistics();
+    opts.set_statistics_level(StatsLevel::ExceptHistogramOrTimers);
+    Ok(true)
+}
+
+/// TTL compaction filter - removes expired and flushed entries during compaction
+fn ttl_compaction_filter(
+    _level: u32,
+    key: &[u8],
+    value: &[u8],
+    flush_epoch: &FlushEpoch,
+    counters: &TtlCounters,
+) -> CompactionDecision {
+    if key.first() == Some(&INTERNAL_KEY_PREFIX) {
+        return CompactionDecision::Keep;
+    }
+
+    // Unreadable headers are kept: reads report them, the filter can't judge
+    let Some(header) = ValueHeader::read(value) else {
+        return CompactionDecision::Keep;
+    };
+    if header.expire_at != 0 && current_timestamp() >= header.expire_at {
+        counters.compaction_removed.fetch_add(1, Ordering::Relaxed);
+        return CompactionDecision::Remove;
+    }
+    if flush_epoch.is_flushed(header.cas) {
+        return CompactionDecision::Remove;
+    }
+    CompactionDecision::Keep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{
+        CompactionScheduleConfig, CompactionStyle, DataPath, OnCorruption, OnFull, StorageMode, Wal,
+    };
+    use crate::storage::sst_writer::SstBuilder;
+    use std::borrow::Cow;
+    use tempfile::TempDir;
+
+    fn test_config(tmp_dir: &TempDir) -> StorageConfig {
+        StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            db_paths: Vec::new(),
+            block_cache_size: 8 * 1024 * 1024,
+            write_buffer_size: 4 * 1024 * 1024,
+            max_write_buffer_number: 2,
+            target_file_size_base: 4 * 1024 * 1024,
+            max_background_jobs: 2,
+            rate_limit_bytes_per_sec: 0,
+            compaction_readahead_size: 2 * 1024 * 1024,
+            max_subcompactions: 1,
+            low_priority_compaction_io: false,
+            enable_compression: false,
+            compression: None,
+            bottommost_compression: None,
+            zstd_level: None,
+            block_size: 16 * 1024,
+            bloom_bits_per_key: 10,
+            cache_index_and_filter_blocks: true,
+            index_type: IndexType::BinarySearch,
+            partition_filters: false,
+            format_version: None,
+            enable_ttl_compaction: false,
+            flush_on_shutdown: true,
+            wal: Wal::Disabled,
+            wal_dir: None,
+            max_total_wal_size: 0,
+            rocksdb_log_level: "error".to_string(),
+            rocksdb_max_log_file_size: 10 * 1024 * 1024,
+            rocksdb_keep_log_file_num: 5,
+            hot_cache_size_bytes: 0,
+            negative_cache_size: 0,
+            negative_cache_ttl_secs: 5,
+            expiry_scan_interval_ms: 0,
+            expiry_scan_batch_keys: 1000,
+            expiry_scan_budget_ms: 10,
+            expired_log_sample_rate: 1000,
+            expired_log_keys: false,
+            expired_log_interval_secs: 60,
+            default_ttl_secs: 0,
+            max_ttl_secs: 0,
+            ttl_jitter_percent: 0,
+            compaction_schedule: CompactionScheduleConfig::default(),
+            restore_from: None,
+            restore_overwrite: false,
+            on_corruption: OnCorruption::Fail,
+            mode: StorageMode::Primary,
+            primary_path: None,
+            catch_up_interval_ms: 1000,
+            catch_up_max_failures: 3,
+            ingest_dir: None,
+            max_db_size_bytes: 0,
+            on_full: OnFull::Reject,
+            disk_check_interval_ms: 1000,
+            health_check_interval_ms: 1000,
+            enable_statistics: false,
+            low_water_percent: 90,
+            compaction: CompactionStyle::Level,
+            compress_values_over_bytes: 0,
+            verify_checksums: false,
+            namespaces: Vec::new(),
+            shards: 1,
+        }
+    }
+
+    #[test]
+    fn test_set_get() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let value = StoredValue::new(42, 0, b"hello".to_vec());
+        storage.set(b"test_key", value).unwrap();
+
+        let result = storage.get(b"test_key").unwrap();
+        assert!(result.is_some());
+        let v = result.unwrap();
+        assert_eq!(v.flags, 42);
+        assert_eq!(v.data, b"hello");
+    }
+
+    #[test]
+    fn test_set_batch() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let mut items = vec![
+            (b"a".to_vec(), StoredValue::new(1, 0, b"first".to_vec())),
+            (b"b".to_vec(), StoredValue::new(2, 0, b"other".to_vec())),
+            (b"a".to_vec(), StoredValue::new(3, 0, b"second".to_vec())),
+        ];
+        let results = storage.set_batch(&mut items).unwrap();
+        assert!(results.iter().all(Result::is_ok));
+
+        let a = storage.get(b"a").unwrap().unwrap();
+        assert_eq!((a.flags, a.data.as_slice()), (3, &b"second"[..]));
+        assert_eq!(storage.get(b"b").unwrap().unwrap().data, b"other");
+        // Every item got its own CAS token, in order
+        assert!(items[0].1.cas < items[1].1.cas && items[1].1.cas < items[2].1.cas);
+        assert_eq!(a.cas, items[2].1.cas);
+    }
+
+    #[test]
+    fn test_compression() {
+        for (compression, bottommost, zstd_level) in [
+            (Compression::None, None, None),
+            (Compression::Lz4, None, None),
+            (Compression::Snappy, None, None),
+            (Compression::Zstd, None, None),
+            (Compression::Zstd, None, Some(9)),
+            (Compression::Lz4, Some(Compression::Zstd), Some(19)),
+        ] {
+            let tmp_dir = TempDir::new().unwrap();
+            let mut config = test_config(&tmp_dir);
+            config.compression = Some(compression);
+            config.bottommost_compression = bottommost;
+            config.zstd_level = zstd_level;
+            let storage = RocksStorage::open(&config).unwrap();
+
+            let data = b"compressible ".repeat(1000);
+            storage
+                .set(b"key", StoredValue::new(7, 0, data.clone()))
+                .unwrap();
+            storage.flush().unwrap();
+            storage.compact();
+            let value = storage.get(b"key").unwrap().unwrap();
+            assert_eq!((value.flags, value.data), (7, data), "{compression:?}");
+        }
+
+        // The deprecated flag still means LZ4, unless compression is set
+        let mut config = StorageConfig {
+            enable_compression: true,
+            ..StorageConfig::default()
+        };
+        assert_eq!(config.effective_compression(), Compression::Lz4);
+        config.compression = Some(Compression::None);
+        assert_eq!(config.effective_compression(), Compression::None);
+    }
+
+    #[test]
+    fn test_db_paths() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.shards = 2;
+        config.db_paths = ["nvme1", "nvme2"]
+            .iter()
+            .map(|name| DataPath {
+                path: tmp_dir.path().join(name),
+                target_size_bytes: 1 << 30,
+            })
+            .collect();
+        let storage = RocksStorage::open(&config).unwrap();
+        for dir in [
+            "nvme1/shard-0",
+            "nvme1/shard-1",
+            "nvme2/shard-0",
+            "nvme2/shard-1",
+        ] {
+            assert!(tmp_dir.path().join(dir).is_dir(), "{dir}");
+        }
+        for i in 0..100 {
+            let key = format!("key{i}");
+            storage
+                .set(key.as_bytes(), StoredValue::new(0, 0, vec![b'x'; 100]))
+                .unwrap();
+        }
+        storage.flush().unwrap();
+
+        // Flushes fill the first path; db_path keeps no SST files
+        let stats = storage.db_stats();
+        assert_eq!(stats.data_paths.len(), 2);
+        assert_eq!(stats.data_paths[0].path, tmp_dir.path().join("nvme1"));
+        assert!(stats.data_paths[0].used_bytes > 0);
+        assert_eq!(stats.data_paths[1].used_bytes, 0);
+        assert_eq!(stats.data_paths[1].target_bytes, 1 << 30);
+        for shard in ["shard-0", "shard-1"] {
+            assert_eq!(sst_bytes(&config.db_path.join(shard)), 0);
+        }
+        drop(storage);
+
+        let storage = RocksStorage::open(&config).unwrap();
+        assert!(storage.get(b"key42").unwrap().is_some());
+    }
+
+    /// A database at `config.db_path` holding `key`, closed with its
+    /// CURRENT file truncated as an unclean crash can leave it
+    fn corrupt_database(config: &StorageConfig) {
+        let storage = RocksStorage::open(config).unwrap();
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"value".to_vec()))
+            .unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        std::fs::File::create(config.db_path.join("CURRENT")).unwrap();
+    }
+
+    #[test]
+    fn test_on_corruption() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        corrupt_database(&config);
+        let error = RocksStorage::open(&config).err().unwrap();
+        assert!(is_corruption(&error), "{error}");
+
+        // Repaired in place, keeping the flushed data
+        config.on_corruption = OnCorruption::Repair;
+        let repairs = DB_REPAIRS.load(Ordering::Relaxed);
+        let storage = RocksStorage::open(&config).unwrap();
+        assert_eq!(storage.get(b"key").unwrap().unwrap().data, b"value");
+        assert!(DB_REPAIRS.load(Ordering::Relaxed) > repairs);
+        drop(storage);
+
+        // Moved aside, starting empty
+        corrupt_database(&config);
+        config.on_corruption = OnCorruption::Recreate;
+        let recreates = DB_RECREATES.load(Ordering::Relaxed);
+        let storage = RocksStorage::open(&config).unwrap();
+        assert!(storage.get(b"key").unwrap().is_none());
+        assert!(DB_RECREATES.load(Ordering::Relaxed) > recreates);
+        let aside = corrupt_dirs(&config.db_path);
+        assert_eq!(aside.len(), 1, "{aside:?}");
+        assert!(aside[0].join("CURRENT").exists());
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"new".to_vec()))
+            .unwrap();
+        assert_eq!(storage.get(b"key").unwrap().unwrap().data, b"new");
+    }
+
+    /// The `corrupt-*` directories recovery moved files into under `dir`
+    fn corrupt_dirs(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with(CORRUPT_PREFIX))
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_recreate_keeps_root_directories() {
+        use std::os::unix::fs::MetadataExt;
+
+        // One shard: db_path, wal_dir and the db_paths entry are the
+        // database's own directories, as mount points would be
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.wal = Wal::Enabled;
+        config.wal_dir = Some(tmp_dir.path().join("wal"));
+        config.db_paths = vec![DataPath {
+            path: tmp_dir.path().join("nvme1"),
+            target_size_bytes: 1 << 30,
+        }];
+        let roots = [
+            config.db_path.clone(),
+            tmp_dir.path().join("wal"),
+            tmp_dir.path().join("nvme1"),
+        ];
+        corrupt_database(&config);
+        // A log the flush left behind, to be moved aside with the rest
+        std::fs::write(roots[1].join("000009.log"), b"").unwrap();
+        let inodes: Vec<u64> = roots
+            .iter()
+            .map(|root| std::fs::metadata(root).unwrap().ino())
+            .collect();
+
+        config.on_corruption = OnCorruption::Recreate;
+        let storage = RocksStorage::open(&config).unwrap();
+        assert!(storage.get(b"key").unwrap().is_none());
+        for (root, inode) in roots.iter().zip(inodes) {
+            assert_eq!(std::fs::metadata(root).unwrap().ino(), inode, "{root:?}");
+            let aside = corrupt_dirs(root);
+            assert_eq!(aside.len(), 1, "{root:?}: {aside:?}");
+            assert!(std::fs::read_dir(&aside[0]).unwrap().next().is_some());
+        }
+        assert!(sst_bytes(&corrupt_dirs(&roots[2])[0]) > 0);
+
+        // A second recovery leaves the first one's files alone
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"new".to_vec()))
+            .unwrap();
+        storage.flush().unwrap();
+        drop(storage);
+        std::fs::File::create(config.db_path.join("CURRENT")).unwrap();
+        let first = corrupt_dirs(&config.db_path).remove(0);
+        std::fs::rename(&first, config.db_path.join("corrupt-1")).unwrap();
+        let storage = RocksStorage::open(&config).unwrap();
+        assert!(storage.get(b"key").unwrap().is_none());
+        let mut aside = corrupt_dirs(&config.db_path);
+        aside.sort();
+        assert_eq!(aside.len(), 2, "{aside:?}");
+        assert!(aside[0].ends_with("corrupt-1"));
+        assert!(!aside[1].join("corrupt-1").exists());
+    }
+
+    #[test]
+    fn test_background_io() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.rate_limit_bytes_per_sec = 1024 * 1024;
+        config.compaction_readahead_size = 4 * 1024 * 1024;
+        config.max_subcompactions = 2;
+        config.low_priority_compaction_io = true;
+        config.shards = 2;
+        let storage = RocksStorage::open(&config).unwrap();
+        for i in 0..100 {
+            let key = format!("key{i}");
+            storage
+                .set(key.as_bytes(), StoredValue::new(0, 0, vec![b'x'; 1024]))
+                .unwrap();
+        }
+        storage.flush().unwrap();
+        storage.compact();
+        assert!(storage.get(b"key42").unwrap().is_some());
+        assert!(storage.db_stats().rate_limiter_drains.is_some());
+    }
+
+    /// Copy `from` to `to` as a kill would leave it: only what the open
+    /// database has written to its files so far
+    fn crash_image(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                crash_image(&entry.path(), &target);
+            } else {
+                std::fs::copy(entry.path(), target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_wal_survives_crash() {
+        for (wal, survives) in [
+            (Wal::Disabled, false),
+            (Wal::Enabled, true),
+            (Wal::EnabledSync, true),
+        ] {
+            let tmp_dir = TempDir::new().unwrap();
+            let mut config = test_config(&tmp_dir);
+            config.wal = wal;
+            let storage = RocksStorage::open(&config).unwrap();
+            storage
+                .set(b"flushed", StoredValue::new(0, 0, b"a".to_vec()))
+                .unwrap();
+            storage.flush().unwrap();
+            storage
+                .set(b"unflushed", StoredValue::new(0, 0, b"b".to_vec()))
+                .unwrap();
+
+            let crashed = tmp_dir.path().join("crashed");
+            crash_image(&config.db_path, &crashed);
+            config.db_path = crashed;
+            let reopened = RocksStorage::open(&config).unwrap();
+            assert!(reopened.get(b"flushed").unwrap().is_some(), "{wal:?}");
+            assert_eq!(
+                reopened.get(b"unflushed").unwrap().is_some(),
+                survives,
+                "{wal:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_wal_dir() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.wal = Wal::Enabled;
+        config.wal_dir = Some(tmp_dir.path().join("wal"));
+        config.max_total_wal_size = 64 * 1024 * 1024;
+        config.shards = 2;
+        let storage = RocksStorage::open(&config).unwrap();
+        for i in 0..20 {
+            let key = format!("key{i}");
+            storage
+                .set(key.as_bytes(), StoredValue::new(0, 0, b"v".to_vec()))
+                .unwrap();
+        }
+        // Each shard logs to its own subdirectory, none to db_path
+        for shard in ["shard-0", "shard-1"] {
+            let logs = |dir: &Path| {
+                std::fs::read_dir(dir)
+                    .unwrap()
+                    .filter(|entry| {
+                        let name = entry.as_ref().unwrap().file_name();
+                        name.to_string_lossy().ends_with(".log")
+                    })
+                    .count()
+            };
+            assert!(logs(&tmp_dir.path().join("wal").join(shard)) > 0);
+            assert_eq!(logs(&config.db_path.join(shard)), 0);
+        }
+
+        let crashed = tmp_dir.path().join("crashed");
+        crash_image(&config.db_path, &crashed.join("db"));
+        crash_image(&tmp_dir.path().join("wal"), &crashed.join("wal"));
+        config.db_path = crashed.join("db");
+        config.wal_dir = Some(crashed.join("wal"));
+        let reopened = RocksStorage::open(&config).unwrap();
+        for i in 0..20 {
+            let key = format!("key{i}");
+            assert!(reopened.get(key.as_bytes()).unwrap().is_some(), "{key}");
+        }
+    }
+
+    #[test]
+    fn test_table_options() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.block_size = 4 * 1024;
+        config.index_type = IndexType::Partitioned;
+        config.partition_filters = true;
+        config.format_version = Some(5);
+        let storage = RocksStorage::open(&config).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{i}");
+            let value = StoredValue::new(0, 0, key.as_bytes().repeat(20));
+            storage.set(key.as_bytes(), value).unwrap();
+        }
+        storage.flush().unwrap();
+        assert_eq!(
+            storage.get(b"key500").unwrap().unwrap().data,
+            b"key500".repeat(20)
+        );
+        assert!(storage.get(b"key1000").unwrap().is_none());
+        drop(storage);
+
+        // Without a bloom filter or cached index blocks
+        config.bloom_bits_per_key = 0;
+        config.cache_index_and_filter_blocks = false;
+        config.index_type = IndexType::BinarySearch;
+        config.partition_filters = false;
+        let storage = RocksStorage::open(&config).unwrap();
+        assert_eq!(
+            storage.get(b"key999").unwrap().unwrap().data,
+            b"key999".repeat(20)
+        );
+    }
+
+    #[test]
+    fn test_get_multi() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        storage
+            .set(b"a", StoredValue::new(1, 0, b"1".to_vec()))
+            .unwrap();
+        storage
+            .set(b"c", StoredValue::new(3, 0, b"3".to_vec()))
+            .unwrap();
+
+        // Positional, with the borrowed keys the parser hands out
+        let keys: Vec<Cow<[u8]>> = vec![
+            Cow::Borrowed(b"a"),
+            Cow::Borrowed(b"b"),
+            Cow::Borrowed(b"c"),
+        ];
+        let results = storage.get_multi(&keys).unwrap();
+        let flags: Vec<Option<u32>> = results
+            .iter()
+            .map(|v| v.as_ref().map(|v| v.flags))
+            .collect();
+        assert_eq!(flags, [Some(1), None, Some(3)]);
+    }
+
+    fn open_with_hot_cache(tmp_dir: &TempDir) -> RocksStorage {
+        RocksStorage::open(&StorageConfig {
+            hot_cache_size_bytes: 1024 * 1024,
+            ..test_config(tmp_dir)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_hot_cache_follows_writes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = open_with_hot_cache(&tmp_dir);
+        let cache = storage.hot_cache.as_ref().unwrap();
+
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        assert!(cache.get(b"a").is_none());
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"1");
+        assert_eq!(cache.get(b"a").unwrap().data, b"1");
+
+        // Every kind of write drops the cached copy
+        storage.append(b"a", b"2").unwrap();
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"12");
+        storage.incr_decr(b"a", 1, true).unwrap();
+        assert_eq!(
+            storage.get_multi(&[b"a"]).unwrap()[0]
+                .as_ref()
+                .unwrap()
+                .data,
+            b"13"
+        );
+        storage
+            .set_batch(&mut [(b"a".to_vec(), StoredValue::new(0, 0, b"4".to_vec()))])
+            .unwrap();
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"4");
+        assert!(storage.delete(b"a").unwrap());
+        assert!(storage.get(b"a").unwrap().is_none());
+        assert!(cache.get(b"a").is_none());
+
+        // flush_all and TTLs are checked on every hit
+        storage
+            .set(b"b", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        assert!(storage.get(b"b").unwrap().is_some());
+        storage.flush_all(0).unwrap();
+        assert!(storage.get(b"b").unwrap().is_none());
+
+        let expired = StoredValue::with_expire_at(0, 1, b"stale".to_vec());
+        cache.insert(b"c", &expired, cache.generation(b"c"));
+        assert!(storage.get(b"c").unwrap().is_none());
+        assert!(storage.get_multi(&[b"c"]).unwrap()[0].is_none());
+    }
+
+    #[test]
+    fn test_hot_cache_never_returns_deleted_value() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(open_with_hot_cache(&tmp_dir));
+
+        for round in 0..200 {
+            storage
+                .set(b"key", StoredValue::new(0, 0, b"value".to_vec()))
+                .unwrap();
+            // Readers race to fill the cache while the key is deleted
+            let readers: Vec<_> = (0..2)
+                .map(|_| {
+                    let storage = Arc::clone(&storage);
+                    std::thread::spawn(move || {
+                        for _ in 0..20 {
+                            storage.get(b"key").unwrap();
+                        }
+                    })
+                })
+                .collect();
+            storage.delete(b"key").unwrap();
+            // Once the delete returned, nobody may see the value again
+            for reader in readers {
+                assert!(storage.get(b"key").unwrap().is_none(), "round {round}");
+                reader.join().unwrap();
+            }
+            assert!(storage.get(b"key").unwrap().is_none(), "round {round}");
+        }
+    }
+
+    #[test]
+    fn test_negative_cache_never_masks_writes() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&StorageConfig {
+            negative_cache_size: 1024,
+            negative_cache_ttl_secs: 60,
+            ..test_config(&tmp_dir)
+        })
+        .unwrap();
+        let cache = storage.negative_cache.as_ref().unwrap();
+
+        assert!(storage.get(b"a").unwrap().is_none());
+        assert!(cache.contains(b"a"));
+        assert!(
+            storage
+                .get_multi(&[b"a", b"b"])
+                .unwrap()
+                .iter()
+                .all(Option::is_none)
+        );
+        assert!(cache.contains(b"b"));
+
+        // set, add and batched sets are visible right away
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"1");
+        assert!(
+            storage
+                .add(b"b", StoredValue::new(0, 0, b"2".to_vec()))
+                .unwrap()
+        );
+        assert_eq!(
+            storage.get_multi(&[b"b"]).unwrap()[0]
+                .as_ref()
+                .unwrap()
+                .data,
+            b"2"
+        );
+        assert!(storage.get(b"c").unwrap().is_none());
+        storage
+            .set_batch(&mut [(b"c".to_vec(), StoredValue::new(0, 0, b"3".to_vec()))])
+            .unwrap();
+        assert_eq!(storage.get(b"c").unwrap().unwrap().data, b"3");
+
+        // A deleted key is a miss again, and may be remembered as one
+        assert!(storage.delete(b"a").unwrap());
+        assert!(storage.get(b"a").unwrap().is_none());
+        assert!(cache.contains(b"a"));
+    }
+
+    #[test]
+    fn test_get_nonexistent() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let result = storage.get(b"nonexistent").unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn test_db_stats() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        storage
+            .set(b"b", StoredValue::new(0, 0, b"2".to_vec()))
+            .unwrap();
+
+        let stats = storage.db_stats();
+        // Plus FORMAT_KEY
+        assert_eq!(stats.estimated_keys, Some(3));
+        assert_eq!(stats.files_per_level.len(), NUM_LEVELS);
+    }
+
+    #[test]
+    fn test_sample_items() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        storage
+            .set(b"a", StoredValue::new(0, 0, vec![0; 10]))
+            .unwrap();
+        storage
+            .set(b"b", StoredValue::new(0, 60, vec![0; 100]))
+            .unwrap();
+        storage
+            .set(b"c", StoredValue::new(0, 7200, vec![0; 100]))
+            .unwrap();
+        storage
+            .set(b"d", StoredValue::new(0, 2 * 86400, vec![0; 1000]))
+            .unwrap();
+        storage.flush_all(3600).unwrap(); // writes an internal key, must be skipped
+
+        let sample = storage.sample_items(100).unwrap();
+        assert_eq!(sample.sampled, 4);
+        assert_eq!(sample.no_ttl, 1);
+        assert_eq!(sample.ttl_under_hour, 1);
+        assert_eq!(sample.ttl_under_day, 1);
+        assert_eq!(sample.ttl_over_day, 1);
+        assert_eq!(sample.sizes.get(&32), Some(&1));
+        assert_eq!(sample.sizes.get(&128), Some(&2));
+        assert_eq!(sample.sizes.get(&1024), Some(&1));
+
+        // Scan is bounded by the limit, and the internal keys (format
+        // marker and flush epoch) sort first and count toward it
+        assert_eq!(storage.sample_items(2).unwrap().sampled, 0);
+        assert_eq!(storage.sample_items(4).unwrap().sampled, 2);
+        // So do expired items
+        storage
+            .set(b"0", StoredValue::with_expire_at(0, 1, vec![0; 10]))
+            .unwrap();
+        assert_eq!(storage.sample_items(4).unwrap().sampled, 1);
+    }
+
+    #[test]
+    fn test_add() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let value = StoredValue::new(1, 0, b"first".to_vec());
+        assert!(storage.add(b"key", value).unwrap());
+
+        let value = StoredValue::new(2, 0, b"second".to_vec());
+        assert!(!storage.add(b"key", value).unwrap());
+
+        let v = storage.get(b"key").unwrap().unwrap();
+        assert_eq!(v.flags, 1);
+        assert_eq!(v.data, b"first");
+    }
+
+    #[test]
+    fn test_add_over_expired_key() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let expired = StoredValue::with_expire_at(0, 1, b"stale".to_vec());
+        storage.set(b"key", expired).unwrap();
+
+        let value = StoredValue::new(0, 0, b"fresh".to_vec());
+        assert!(storage.add(b"key", value).unwrap());
+        assert_eq!(storage.get(b"key").unwrap().unwrap().data, b"fresh");
+    }
+
+    #[test]
+    fn test_replace() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let value = StoredValue::new(0, 0, b"nothing".to_vec());
+        assert!(!storage.replace(b"key", value).unwrap());
+        assert!(storage.get(b"key").unwrap().is_none());
+
+        storage
+            .set(b"key", StoredValue::new(1, 0, b"old".to_vec()))
+            .unwrap();
+        let value = StoredValue::new(2, 0, b"new".to_vec());
+        assert!(storage.replace(b"key", value).unwrap());
+
+        let v = storage.get(b"key").unwrap().unwrap();
+        assert_eq!(v.flags, 2);
+        assert_eq!(v.data, b"new");
+    }
+
+    #[test]
+    fn test_replace_expired_key() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let expired = StoredValue::with_expire_at(0, 1, b"stale".to_vec());
+        storage.set(b"key", expired).unwrap();
+
+        let value = StoredValue::new(0, 0, b"fresh".to_vec());
+        assert!(!storage.replace(b"key", value).unwrap());
+        assert!(storage.get(b"key").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_append_prepend() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        assert!(!storage.append(b"list", b",b").unwrap());
+        assert!(!storage.prepend(b"list", b"a,").unwrap());
+
+        storage
+            .set(b"list", StoredValue::new(5, 0, b"b".to_vec()))
+            .unwrap();
+        assert!(storage.append(b"list", b",c").unwrap());
+        assert!(storage.prepend(b"list", b"a,").unwrap());
+
+        let v = storage.get(b"list").unwrap().unwrap();
+        assert_eq!(v.data, b"a,b,c");
+        assert_eq!(v.flags, 5);
+    }
+
+    #[test]
+    fn test_append_preserves_ttl() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let expire_at = current_timestamp() + 3600;
+        let value = StoredValue::with_expire_at(0, expire_at, b"a".to_vec());
+        storage.set(b"list", value).unwrap();
+
+        assert!(storage.append(b"list", b",b").unwrap());
+        let v = storage.get(b"list").unwrap().unwrap();
+        assert_eq!(v.data, b"a,b");
+        assert_eq!(v.expire_at, expire_at);
+    }
+
+    #[test]
+    fn test_append_expired_key() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let expired = StoredValue::with_expire_at(0, 1, b"stale".to_vec());
+        storage.set(b"list", expired).unwrap();
+
+        assert!(!storage.append(b"list", b",more").unwrap());
+        assert!(storage.get(b"list").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_set_assigns_increasing_cas() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"a".to_vec()))
+            .unwrap();
+        let first = storage.get(b"key").unwrap().unwrap().cas;
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"b".to_vec()))
+            .unwrap();
+        let second = storage.get(b"key").unwrap().unwrap().cas;
+
+        assert!(first > 0);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_cas() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let value = StoredValue::new(0, 0, b"v".to_vec());
+        assert_eq!(storage.cas(b"key", value, 1).unwrap(), CasOutcome::NotFound);
+
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"v1".to_vec()))
+            .unwrap();
+        let token = storage.get(b"key").unwrap().unwrap().cas;
+
+        let value = StoredValue::new(0, 0, b"v2".to_vec());
+        assert_eq!(
+            storage.cas(b"key", value, token).unwrap(),
+            CasOutcome::Stored
+        );
+
+        // Same token again: item has changed since
+        let value = StoredValue::new(0, 0, b"v3".to_vec());
+        assert_eq!(
+            storage.cas(b"key", value, token).unwrap(),
+            CasOutcome::Exists
+        );
+        assert_eq!(storage.get(b"key").unwrap().unwrap().data, b"v2");
+    }
+
+    #[test]
+    fn test_cas_race_single_winner() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(RocksStorage::open(&test_config(&tmp_dir)).unwrap());
+
+        storage
+            .set(b"key", StoredValue::new(0, 0, b"v0".to_vec()))
+            .unwrap();
+        let token = storage.get(b"key").unwrap().unwrap().cas;
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let storage = Arc::clone(&storage);
+                std::thread::spawn(move || {
+                    let value = StoredValue::new(0, 0, format!("v{i}").into_bytes());
+                    storage.cas(b"key", value, token).unwrap()
+                })
+            })
+            .collect();
+
+        let stored = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|o| *o == CasOutcome::Stored)
+            .count();
+        assert_eq!(stored, 1);
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        assert_eq!(storage.incr_decr(b"counter", 1, true).unwrap(), None);
+
+        storage
+            .set(b"counter", StoredValue::new(0, 0, b"10".to_vec()))
+            .unwrap();
+        assert_eq!(storage.incr_decr(b"counter", 5, true).unwrap(), Some(15));
+        assert_eq!(storage.incr_decr(b"counter", 3, false).unwrap(), Some(12));
+        // Decrement clamps at zero
+        assert_eq!(storage.incr_decr(b"counter", 100, false).unwrap(), Some(0));
+        assert_eq!(storage.get(b"counter").unwrap().unwrap().data, b"0");
+    }
+
+    #[test]
+    fn test_incr_wraps_and_rejects_non_numeric() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let max = u64::MAX.to_string().into_bytes();
+        storage
+            .set(b"counter", StoredValue::new(0, 0, max))
+            .unwrap();
+        assert_eq!(storage.incr_decr(b"counter", 2, true).unwrap(), Some(1));
+
+        storage
+            .set(b"text", StoredValue::new(0, 0, b"hello".to_vec()))
+            .unwrap();
+        assert!(matches!(
+            storage.incr_decr(b"text", 1, true),
+            Err(StorageError::NotNumeric)
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_incr_no_lost_updates() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = Arc::new(RocksStorage::open(&test_config(&tmp_dir)).unwrap());
+        storage
+            .set(b"counter", StoredValue::new(0, 0, b"0".to_vec()))
+            .unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = Arc::clone(&storage);
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        storage.incr_decr(b"counter", 1, true).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        assert_eq!(storage.get(b"counter").unwrap().unwrap().data, b"800");
+    }
+
+    #[test]
+    fn test_get_and_touch() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        assert!(storage.get_and_touch(b"session", 60).unwrap().is_none());
+
+        storage
+            .set(b"session", StoredValue::new(3, 10, b"data".to_vec()))
+            .unwrap();
+        let cas = storage.get(b"session").unwrap().unwrap().cas;
+
+        let now = current_timestamp();
+        let v = storage.get_and_touch(b"session", 3600).unwrap().unwrap();
+        assert_eq!(v.data, b"data");
+        assert_eq!(v.flags, 3);
+        assert_eq!(v.cas, cas);
+        assert!(v.expire_at >= now + 3599);
+
+        let stored = storage.get(b"session").unwrap().unwrap();
+        assert_eq!(stored.expire_at, v.expire_at);
+        assert_eq!(stored.cas, cas);
+    }
+
+    #[test]
+    fn test_get_and_touch_expired_key() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+
+        let expired = StoredValue::with_expire_at(0, 1, b"stale".to_vec());
+        storage.set(b"session", expired).unwrap();
 
-impl RocksStorage {
-    /// Open or create a RocksDB database
-    pub fn open(config: &StorageConfig) -> Result<Self, StorageError> {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.set_max_background_jobs(config.max_background_jobs);
-        opts.set_write_buffer_size(config.write_buffer_size);
-        opts.set_max_write_buffer_number(config.max_write_buffer_number);
-        opts.set_target_file_size_base(config.target_file_size_base);
-        opts.set_compaction_style(DBCompactionStyle::Level);
+        assert!(storage.get_and_touch(b"session", 3600).unwrap().is_none());
+        assert!(storage.get(b"session").unwrap().is_none());
+    }
 
-        // RocksDB LOG file settings
-        opts.set_log_level(parse_log_level(&config.rocksdb_log_level));
-        opts.set_max_log_file_size(config.rocksdb_max_log_file_size);
-        opts.set_keep_log_file_num(config.rocksdb_keep_log_file_num);
+    #[test]
+    fn test_secondary_is_read_only() {
+        let tmp_dir = TempDir::new().unwrap();
+        let primary_config = test_config(&tmp_dir);
+        let primary = RocksStorage::open(&primary_config).unwrap();
+        primary
+            .set(b"key", StoredValue::new(0, 0, b"value".to_vec()))
+            .unwrap();
+        primary
+            .set(
+                b"expired",
+                StoredValue::with_expire_at(0, 1, b"old".to_vec()),
+            )
+            .unwrap();
+        primary.flush().unwrap();
 
-        if config.enable_compression {
-            opts.set_compression_type(rust_rocksdb::DBCompressionType::Lz4);
-        } else {
-            opts.set_compression_type(rust_rocksdb::DBCompressionType::None);
-        }
+        let mut config = test_config(&tmp_dir);
+        config.db_path = tmp_dir.path().join("secondary");
+        config.mode = StorageMode::Secondary;
+        config.primary_path = Some(primary_config.db_path.clone());
+        config.hot_cache_size_bytes = 1024 * 1024;
+        let secondary = RocksStorage::open(&config).unwrap();
+        assert!(secondary.is_read_only());
+        assert_eq!(secondary.config().hot_cache_size_bytes, 0);
 
-        // Block cache with optimized settings
-        let mut block_opts = BlockBasedOptions::default();
-        let cache = rust_rocksdb::Cache::new_lru_cache(config.block_cache_size);
-        block_opts.set_block_cache(&cache);
-        block_opts.set_bloom_filter(10.0, false);
-        // Cache index and filter blocks in block cache (reduces disk I/O)
-        block_opts.set_cache_index_and_filter_blocks(true);
-        // Pin L0 filter and index blocks (prevents eviction of hot data)
-        block_opts.set_pin_l0_filter_and_index_blocks_in_cache(true);
-        // Larger block size reduces metadata overhead
-        block_opts.set_block_size(16 * 1024);
-        opts.set_block_based_table_factory(&block_opts);
+        assert_eq!(secondary.get(b"key").unwrap().unwrap().data, b"value");
+        assert!(secondary.get(b"expired").unwrap().is_none());
+        let value = || StoredValue::new(0, 0, b"new".to_vec());
+        assert!(matches!(
+            secondary.set(b"key", value()),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            secondary.add(b"other", value()),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            secondary.delete(b"key"),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            secondary.incr_decr(b"key", 1, true),
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            secondary.flush_all(0),
+            Err(StorageError::ReadOnly)
+        ));
+        assert_eq!(StorageError::ReadOnly.to_string(), "read-only");
 
-        // TTL compaction filter
-        if config.enable_ttl_compaction {
-            opts.set_compaction_filter("ttl_filter", ttl_compaction_filter);
-        }
+        // The expired key is left for the primary to delete
+        assert!(primary.shards[0].db.get(b"expired").unwrap().is_some());
+    }
 
-        // Ensure the directory exists
-        if let Some(parent) = config.db_path.parent() {
-            std::fs::create_dir_all(parent).map_err(|e| {
-                StorageError::Internal(format!("Failed to create directory: {e}"))
-            })?;
-        }
+    #[test]
+    fn test_secondary_needs_distinct_primary_path() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.mode = StorageMode::Secondary;
+        assert!(RocksStorage::open(&config).is_err());
 
-        let db = DB::open(&opts, &config.db_path)?;
+        config.primary_path = Some(config.db_path.clone());
+        assert!(RocksStorage::open(&config).is_err());
+    }
 
-        info!(
-            "RocksDB opened: path={:?}, block_cache={}MB",
-            config.db_path,
-            config.block_cache_size / (1024 * 1024),
-        );
+    #[test]
+    fn test_fifo_compaction() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.compaction = CompactionStyle::Fifo;
+        assert!(RocksStorage::open(&config).is_err());
 
-        // Disable WAL: writes go directly to memtable (RAM only)
-        // Data reaches disk only when memtable flushes to SST file (~every few seconds)
-        // Trade-off: crash loses unflushed data (acceptable for a cache)
-        let mut write_opts = WriteOptions::default();
-        write_opts.disable_wal(true);
+        config.max_db_size_bytes = 64 * 1024 * 1024;
+        let storage = RocksStorage::open(&config).unwrap();
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"1");
+        assert_eq!(storage.db_stats().fifo_drops, Some(0));
+        drop(storage);
 
-        Ok(Self { db: Arc::new(db), write_opts })
+        // Only FIFO pays for statistics
+        config.compaction = CompactionStyle::Level;
+        let storage = RocksStorage::open(&config).unwrap();
+        assert_eq!(storage.db_stats().fifo_drops, None);
+        assert!(storage.db_stats().statistics.is_empty());
+        drop(storage);
+
+        // Statistics on request don't make leveled compaction drop files
+        config.enable_statistics = true;
+        let storage = RocksStorage::open(&config).unwrap();
+        let stats = storage.db_stats();
+        assert_eq!(stats.fifo_drops, None);
+        assert_eq!(stats.rate_limiter_drains, None);
+        assert_eq!(stats.statistics.len(), 1);
     }
 
-    /// Get a value by key (with lazy expiration)
-    pub fn get(&self, key: &[u8]) -> Result<Option<StoredValue>, StorageError> {
-        match self.db.get(key)? {
-            Some(bytes) => {
-                let value = StoredValue::decode(&bytes)?;
-                if value.is_expired() {
-                    EXPIRED_KEYS_REMOVED.fetch_add(1, Ordering::Relaxed);
-                    info!(
-                        key = %String::from_utf8_lossy(key),
-                        expire_at = value.expire_at,
-                        "Lazy expiration: removed expired key"
-                    );
-                    let _ = self.db.delete_opt(key, &self.write_opts);
-                    Ok(None)
-                } else {
-                    Ok(Some(value))
-                }
-            }
-            None => Ok(None),
+    #[test]
+    fn test_full_rejects_sets() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        assert!(storage.db_size() > 0);
+
+        assert!(!storage.set_full(true));
+        assert!(storage.is_full());
+        let before = FULL_REJECTED_WRITES.load(Ordering::Relaxed);
+        assert!(matches!(
+            storage.set(b"b", StoredValue::new(0, 0, b"2".to_vec())),
+            Err(StorageError::Full)
+        ));
+        assert!(matches!(
+            storage.add(b"b", StoredValue::new(0, 0, b"2".to_vec())),
+            Err(StorageError::Full)
+        ));
+        assert!(FULL_REJECTED_WRITES.load(Ordering::Relaxed) >= before + 2);
+
+        // Reads and deletes still work, so clients can free space
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"1");
+        assert!(storage.delete(b"a").unwrap());
+
+        assert!(storage.set_full(false));
+        storage
+            .set(b"b", StoredValue::new(0, 0, b"2".to_vec()))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_evict_prefers_soonest_expiring() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+        for i in 0..4i64 {
+            storage
+                .set(
+                    format!("plain{i}").as_bytes(),
+                    StoredValue::new(0, 0, b"v".to_vec()),
+                )
+                .unwrap();
+            // Later keys expire sooner
+            storage
+                .set(
+                    format!("ttl{i}").as_bytes(),
+                    StoredValue::new(0, 1000 - i * 100, b"v".to_vec()),
+                )
+                .unwrap();
         }
+
+        let eviction = storage.evict(1).unwrap();
+        assert_eq!(eviction.keys, 1);
+        assert!(eviction.bytes > 0);
+        assert!(storage.get(b"ttl3").unwrap().is_none());
+        assert!(storage.get(b"ttl0").unwrap().is_some());
+
+        // Half of the remaining sample: the TTL keys, then the oldest plain key
+        assert_eq!(storage.evict(u64::MAX).unwrap().keys, 4);
+        let remaining: Vec<bool> = ["plain0", "plain1", "plain2", "plain3", "ttl0"]
+            .iter()
+            .map(|key| storage.get(key.as_bytes()).unwrap().is_some())
+            .collect();
+        assert_eq!(remaining, [false, true, true, true, false]);
     }
 
-    /// Get multiple values by keys using batched MultiGet API
-    pub fn get_multi(
-        &self,
-        keys: &[Vec<u8>],
-    ) -> Result<Vec<(Vec<u8>, Option<StoredValue>)>, StorageError> {
-        // Use RocksDB's native multi_get for better performance
-        // (batches lookups, reduces mutex contention, enables parallel I/O)
-        let raw_results = self.db.multi_get(keys);
+    #[test]
+    fn test_namespaces() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            namespaces: vec![
+                NamespaceConfig::new("users"),
+                NamespaceConfig::new("sessions"),
+            ],
+            ..test_config(&tmp_dir)
+        };
+        let storage = RocksStorage::open(&config).unwrap();
+        let value = |data: &[u8]| StoredValue::new(0, 0, data.to_vec());
 
-        let mut results = Vec::with_capacity(keys.len());
-        let mut expired_keys = Vec::new();
+        storage.set(b"users:1", value(b"alice")).unwrap();
+        storage.set(b"sessions:1", value(b"s1")).unwrap();
+        // Unknown prefixes and unprefixed keys stay in the default column family
+        storage.set(b"orders:1", value(b"o1")).unwrap();
+        storage.set(b"1", value(b"plain")).unwrap();
+        let mut batch = vec![(b"users:2".to_vec(), value(b"bob"))];
+        storage.set_batch(&mut batch).unwrap();
 
-        for (key, raw_result) in keys.iter().zip(raw_results.into_iter()) {
-            match raw_result {
-                Ok(Some(bytes)) => {
-                    let value = StoredValue::decode(&bytes)?;
-                    if value.is_expired() {
-                        expired_keys.push(key.clone());
-                        results.push((key.clone(), None));
-                    } else {
-                        results.push((key.clone(), Some(value)));
-                    }
-                }
-                Ok(None) => {
-                    results.push((key.clone(), None));
-                }
-                Err(e) => {
-                    return Err(StorageError::RocksDb(e));
-                }
-            }
-        }
+        // The sample limit is split over the column families: the default
+        // one spends half its share on the format marker
+        assert_eq!(storage.sample_items(6).unwrap().sampled, 4);
 
-        // Batch delete expired keys (lazy expiration)
-        if !expired_keys.is_empty() {
-            EXPIRED_KEYS_REMOVED.fetch_add(expired_keys.len() as u64, Ordering::Relaxed);
-            for key in &expired_keys {
-                trace!(
-                    key = %String::from_utf8_lossy(key),
-                    "Lazy expiration: removed expired key"
-                );
-                let _ = self.db.delete_opt(key, &self.write_opts);
-            }
-        }
+        // Stored under the stripped key in the namespace's column family
+        let users = storage.shards[0].db.cf_handle("ns.users").unwrap();
+        assert!(storage.shards[0].db.get_cf(&users, b"1").unwrap().is_some());
+        assert!(storage.shards[0].db.get(b"users:1").unwrap().is_none());
+        drop(users);
 
-        Ok(results)
+        let keys: [&[u8]; 4] = [b"users:1", b"sessions:1", b"1", b"users:2"];
+        let data: Vec<_> = storage
+            .get_multi(&keys)
+            .unwrap()
+            .into_iter()
+            .map(|value| value.unwrap().data)
+            .collect();
+        assert_eq!(data, [&b"alice"[..], b"s1", b"plain", b"bob"]);
+
+        storage.record_get(b"users:1", true);
+        storage.record_get(b"users:3", false);
+        storage.record_get(b"orders:1", true);
+        let stats = storage.db_stats();
+        let users = &stats.namespaces[0];
+        assert_eq!(
+            (users.name.as_str(), users.hits, users.misses),
+            ("users", 1, 1)
+        );
+
+        storage.flush_namespace("users").unwrap();
+        assert!(storage.get(b"users:1").unwrap().is_none());
+        assert!(storage.get(b"users:2").unwrap().is_none());
+        assert!(storage.get(b"sessions:1").unwrap().is_some());
+        assert!(storage.get(b"1").unwrap().is_some());
+        storage.set(b"users:1", value(b"carol")).unwrap();
+        assert_eq!(storage.get(b"users:1").unwrap().unwrap().data, b"carol");
+        assert!(matches!(
+            storage.flush_namespace("orders"),
+            Err(StorageError::UnknownNamespace(_))
+        ));
+
+        // Column families of namespaces dropped from the config still open
+        drop(storage);
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+        assert!(storage.get(b"users:1").unwrap().is_none());
+        assert!(storage.get(b"1").unwrap().is_some());
     }
 
-    /// Set a value (WAL disabled — writes go to memtable only, flushed to disk async)
-    pub fn set(&self, key: &[u8], value: StoredValue) -> Result<(), StorageError> {
-        let encoded = value.encode();
-        self.db.put_opt(key, &encoded, &self.write_opts)?;
-        Ok(())
+    #[test]
+    fn test_set_options() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            namespaces: vec![NamespaceConfig::new("users")],
+            shards: 2,
+            ..test_config(&tmp_dir)
+        };
+        let storage = RocksStorage::open(&config).unwrap();
+        let option = |name: &str, value: &str| (name.to_string(), value.to_string());
+
+        let update = storage.set_options(&[
+            option("write_buffer_size", "8388608"),
+            option("max_background_jobs", "4"),
+            option("level0_slowdown_writes_trigger", "30"),
+            option("compression", "kNoCompression"),
+        ]);
+        let applied: Vec<&str> = update.applied.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            applied,
+            [
+                "write_buffer_size",
+                "max_background_jobs",
+                "level0_slowdown_writes_trigger"
+            ]
+        );
+        assert_eq!(update.rejected.len(), 1);
+        assert_eq!(update.rejected[0].0, "compression");
+
+        let settings = storage.settings();
+        assert_eq!(settings.write_buffer_size, 8 * 1024 * 1024);
+        assert_eq!(settings.max_background_jobs, 4);
+        assert_eq!(storage.config().write_buffer_size, config.write_buffer_size);
+
+        // A recreated column family gets the changed options too
+        storage.flush_namespace("users").unwrap();
+        storage
+            .set(b"users:1", StoredValue::new(0, 0, b"v".to_vec()))
+            .unwrap();
+        assert!(storage.get(b"users:1").unwrap().is_some());
     }
 
-    /// Delete a key
-    ///
-    /// Returns `true` if the key existed, `false` otherwise.
-    /// Note: This is not fully atomic - between get and delete another thread
-    /// could modify the key. For memcached semantics this is acceptable.
-    pub fn delete(&self, key: &[u8]) -> Result<bool, StorageError> {
-        let existed = self.db.get(key)?.is_some();
-        // Always call delete - RocksDB delete is idempotent
-        // This avoids the race where key is deleted between get and delete
-        self.db.delete_opt(key, &self.write_opts)?;
-        Ok(existed)
+    #[test]
+    fn test_namespace_quota() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            namespaces: vec![
+                NamespaceConfig {
+                    name: "users".to_string(),
+                    max_bytes: 1,
+                },
+                NamespaceConfig::new("sessions"),
+            ],
+            ..test_config(&tmp_dir)
+        };
+        let storage = RocksStorage::open(&config).unwrap();
+        let value = || StoredValue::new(0, 0, b"data".to_vec());
+        assert!(storage.has_namespace_quotas());
+
+        storage.set(b"users:1", value()).unwrap();
+        storage.enforce_namespace_quotas();
+        assert!(matches!(
+            storage.set(b"users:2", value()),
+            Err(StorageError::QuotaExceeded)
+        ));
+        // Only the batched item over quota fails
+        let mut batch = vec![
+            (b"sessions:1".to_vec(), value()),
+            (b"users:2".to_vec(), value()),
+        ];
+        let results = storage.set_batch(&mut batch).unwrap();
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(StorageError::QuotaExceeded)));
+        assert!(storage.get(b"sessions:1").unwrap().is_some());
+        assert!(storage.get(b"users:2").unwrap().is_none());
+        // Other namespaces, reads and deletes are unaffected
+        storage.set(b"sessions:2", value()).unwrap();
+        assert!(storage.get(b"users:1").unwrap().is_some());
+        assert!(storage.delete(b"users:1").unwrap());
+
+        let stats = storage.db_stats();
+        assert_eq!(
+            (
+                stats.namespaces[0].max_bytes,
+                stats.namespaces[0].rejected_writes
+            ),
+            (1, 2)
+        );
+
+        // Flushing the namespace frees it at once
+        storage.flush_namespace("users").unwrap();
+        storage.set(b"users:2", value()).unwrap();
     }
 
-    /// Get memory usage statistics
-    pub fn memory_usage(&self) -> MemoryUsage {
-        let block_cache_usage = self
-            .db
-            .property_int_value("rocksdb.block-cache-usage")
-            .unwrap_or(None)
-            .unwrap_or(0) as usize;
+    #[test]
+    fn test_invalid_namespaces() {
+        let tmp_dir = TempDir::new().unwrap();
+        for namespaces in [vec![""], vec!["a:b"], vec!["a b"], vec!["a", "a"]] {
+            let config = StorageConfig {
+                namespaces: namespaces
+                    .iter()
+                    .copied()
+                    .map(NamespaceConfig::new)
+                    .collect(),
+                ..test_config(&tmp_dir)
+            };
+            assert!(RocksStorage::open(&config).is_err(), "{namespaces:?}");
+        }
+    }
 
-        MemoryUsage {
-            block_cache_usage,
-            total: block_cache_usage,
+    #[test]
+    fn test_shards() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = StorageConfig {
+            shards: 4,
+            ..test_config(&tmp_dir)
+        };
+        let storage = RocksStorage::open(&config).unwrap();
+        let keys: Vec<Vec<u8>> = (0..100).map(|i| format!("key{i}").into_bytes()).collect();
+        for (i, key) in keys.iter().enumerate() {
+            let data = format!("value{i}").into_bytes();
+            storage.set(key, StoredValue::new(0, 0, data)).unwrap();
+        }
+        let mut batch: Vec<_> = (0..10)
+            .map(|i| {
+                let data = format!("batch{i}").into_bytes();
+                (format!("key{i}").into_bytes(), StoredValue::new(0, 0, data))
+            })
+            .collect();
+        storage.set_batch(&mut batch).unwrap();
+        for shard in &storage.shards {
+            assert!(shard.db.iterator(IteratorMode::Start).next().is_some());
+        }
+
+        // Results come back in request order whichever shard holds a key
+        let mut requested: Vec<&[u8]> = keys.iter().rev().map(Vec::as_slice).collect();
+        requested.push(b"missing");
+        let values = storage.get_multi(&requested).unwrap();
+        assert!(values[100].is_none());
+        for (key, value) in requested.iter().zip(&values).take(100) {
+            let i: usize = std::str::from_utf8(&key[3..]).unwrap().parse().unwrap();
+            let expected = if i < 10 {
+                format!("batch{i}")
+            } else {
+                format!("value{i}")
+            };
+            assert_eq!(value.as_ref().unwrap().data, expected.into_bytes());
+        }
+
+        // The expiry scan walks every shard
+        for key in &keys[..20] {
+            storage
+                .set(key, StoredValue::with_expire_at(0, 1, b"x".to_vec()))
+                .unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let (mut removed, mut from) = (0, None);
+        loop {
+            let scan = storage.scan_expired(from.as_ref(), 7, deadline).unwrap();
+            removed += scan.removed;
+            from = scan.resume_from;
+            if from.is_none() {
+                break;
+            }
+        }
+        assert_eq!(removed, 20);
+        assert!(storage.get(b"key20").unwrap().is_some());
+
+        // Ingested files are split over the shards
+        let sst = tmp_dir.path().join("ingest.sst");
+        let mut builder = SstBuilder::create(&sst).unwrap();
+        for i in 0..50 {
+            let value = StoredValue::new(0, 0, b"ingested".to_vec());
+            builder
+                .add(format!("new{i:02}").as_bytes(), &value)
+                .unwrap();
+        }
+        builder.finish().unwrap();
+        assert_eq!(storage.ingest_sst_files(&[sst]).unwrap().keys, 50);
+        for i in 0..50 {
+            let key = format!("new{i:02}");
+            assert!(storage.get(key.as_bytes()).unwrap().is_some(), "{key}");
+        }
+
+        // Backups and checkpoints keep the layout
+        let backups = tmp_dir.path().join("backups");
+        storage.create_backup(&backups).unwrap();
+        let checkpoint = tmp_dir.path().join("checkpoint");
+        assert!(storage.create_checkpoint(&checkpoint).unwrap() > 0);
+        drop(storage);
+        let restored_path = tmp_dir.path().join("restored");
+        RocksStorage::restore_latest_backup(&backups, &restored_path, 4).unwrap();
+        for db_path in [restored_path, checkpoint] {
+            let copy = RocksStorage::open(&StorageConfig {
+                db_path,
+                ..config.clone()
+            })
+            .unwrap();
+            assert_eq!(copy.get(b"key42").unwrap().unwrap().data, b"value42");
+        }
+
+        // A different shard count would look keys up in the wrong shard
+        for shards in [1, 2] {
+            let result = RocksStorage::open(&StorageConfig {
+                shards,
+                ..config.clone()
+            });
+            assert!(result.is_err());
         }
     }
 
-    /// Get TTL expiration statistics
-    pub fn ttl_stats() -> TtlStats {
-        TtlStats {
-            expired_removed: EXPIRED_KEYS_REMOVED.load(Ordering::Relaxed),
-            compaction_removed: TTL_COMPACTION_REMOVED.load(Ordering::Relaxed),
+    #[test]
+    fn test_corrupt_values_read_as_misses() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.verify_checksums = true;
+        let storage = RocksStorage::open(&config).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            storage
+                .set(key, StoredValue::new(0, 0, b"value".to_vec()))
+                .unwrap();
         }
+        // A flipped data bit, as a torn write might leave it
+        let corrupt = |key: &[u8]| {
+            let mut bytes = storage.shards[0].db.get(key).unwrap().unwrap();
+            *bytes.last_mut().unwrap() ^= 0x01;
+            storage.shards[0].db.put(key, &bytes).unwrap();
+        };
+
+        corrupt(b"b");
+        let before = CORRUPT_VALUES_REMOVED.load(Ordering::Relaxed);
+        assert!(storage.get(b"b").unwrap().is_none());
+        assert!(storage.shards[0].db.get(b"b").unwrap().is_none());
+        assert!(CORRUPT_VALUES_REMOVED.load(Ordering::Relaxed) > before);
+
+        storage
+            .set(b"b", StoredValue::new(0, 0, b"value".to_vec()))
+            .unwrap();
+        corrupt(b"b");
+        storage.shards[0].db.put(b"d", b"garbage").unwrap();
+        let results = storage.get_multi(&[b"a", b"b", b"c", b"d"]).unwrap();
+        let found: Vec<bool> = results.iter().map(Option::is_some).collect();
+        assert_eq!(found, [true, false, true, false]);
+        assert!(storage.shards[0].db.get(b"b").unwrap().is_none());
+        assert!(storage.shards[0].db.get(b"d").unwrap().is_none());
     }
 
-    /// Manually trigger compaction (useful for testing TTL compaction)
-    pub fn compact(&self) {
-        info!("Starting manual compaction");
-        self.db.compact_range::<&[u8], &[u8]>(None, None);
-        info!(
-            compaction_removed = TTL_COMPACTION_REMOVED.load(Ordering::Relaxed),
-            "Manual compaction completed"
+    #[test]
+    fn test_compress_large_values() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut config = test_config(&tmp_dir);
+        config.compress_values_over_bytes = 1024;
+        let storage = RocksStorage::open(&config).unwrap();
+
+        let blob = br#"{"user":42,"tags":["a","b"]}"#.repeat(1000);
+        let before = COMPRESSION_BYTES_SAVED.load(Ordering::Relaxed);
+        storage
+            .set(b"blob", StoredValue::new(5, 0, blob.clone()))
+            .unwrap();
+        storage
+            .set(b"small", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        assert!(COMPRESSION_BYTES_SAVED.load(Ordering::Relaxed) > before);
+
+        let raw = storage.shards[0].db.get(b"blob").unwrap().unwrap();
+        assert!(raw.len() < blob.len() / 4);
+        let value = storage.get(b"blob").unwrap().unwrap();
+        assert_eq!((value.flags, value.data), (5, blob.clone()));
+
+        // Read-modify-write paths see the plain data
+        storage.append(b"blob", b"!").unwrap();
+        assert_eq!(
+            storage.get(b"blob").unwrap().unwrap().data.len(),
+            blob.len() + 1
         );
+        assert_eq!(storage.incr_decr(b"small", 1, true).unwrap(), Some(2));
+        let value = storage.get(b"blob").unwrap().unwrap();
+        assert!(matches!(
+            storage.cas(b"blob", StoredValue::new(0, 0, b"x".to_vec()), value.cas),
+            Ok(CasOutcome::Stored)
+        ));
     }
-}
 
-/// TTL expiration statistics
-#[derive(Debug, Clone, Default)]
-pub struct TtlStats {
-    /// Keys removed by lazy expiration or background scan
-    pub expired_removed: u64,
-    /// Keys removed by compaction filter
-    pub compaction_removed: u64,
-}
+    #[test]
+    fn test_flush_all() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
 
-fn parse_log_level(level: &str) -> LogLevel {
-    match level.to_lowercase().as_str() {
-        "debug" => LogLevel::Debug,
-        "info" => LogLevel::Info,
-        "warn" => LogLevel::Warn,
-        "fatal" => LogLevel::Fatal,
-        "header" => LogLevel::Header,
-        _ => LogLevel::Error, // "error" or any unknown value
-    }
-}
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        storage
+            .set(b"b", StoredValue::new(0, 0, b"2".to_vec()))
+            .unwrap();
 
-/// TTL compaction filter - removes expired entries during compaction
-fn ttl_compaction_filter(_level: u32, _key: &[u8], value: &[u8]) -> CompactionDecision {
-    if value.len() >= 8 {
-        let expire_at = u64::from_le_bytes(value[0..8].try_into().unwrap_or([0; 8]));
+        storage.flush_all(0).unwrap();
+        assert!(storage.get(b"a").unwrap().is_none());
+        let results = storage.get_multi(&[b"a", b"b"]).unwrap();
+        assert!(results.iter().all(Option::is_none));
 
-        if expire_at != 0 && current_timestamp() >= expire_at {
-            TTL_COMPACTION_REMOVED.fetch_add(1, Ordering::Relaxed);
-            return CompactionDecision::Remove;
-        }
+        // Writes after the flush are visible
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"3".to_vec()))
+            .unwrap();
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"3");
     }
-    CompactionDecision::Keep
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+    #[test]
+    fn test_flush_all_delayed() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
 
-    fn test_config(tmp_dir: &TempDir) -> StorageConfig {
-        StorageConfig {
-            db_path: tmp_dir.path().join("test_db"),
-            block_cache_size: 8 * 1024 * 1024,
-            write_buffer_size: 4 * 1024 * 1024,
-            max_write_buffer_number: 2,
-            target_file_size_base: 4 * 1024 * 1024,
-            max_background_jobs: 2,
-            enable_compression: false,
-            enable_ttl_compaction: false,
-            rocksdb_log_level: "error".to_string(),
-            rocksdb_max_log_file_size: 10 * 1024 * 1024,
-            rocksdb_keep_log_file_num: 5,
-        }
+        storage
+            .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+            .unwrap();
+        storage.flush_all(3600).unwrap();
+
+        // Not yet in effect
+        assert!(storage.get(b"a").unwrap().is_some());
     }
 
     #[test]
-    fn test_set_get() {
+    fn test_flush_all_persists_across_reopen() {
         let tmp_dir = TempDir::new().unwrap();
-        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+        let config = test_config(&tmp_dir);
+        {
+            let storage = RocksStorage::open(&config).unwrap();
+            storage
+                .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+                .unwrap();
+            storage.flush_all(0).unwrap();
+        }
 
-        let value = StoredValue::new(42, 0, b"hello".to_vec());
-        storage.set(b"test_key", value).unwrap();
+        let storage = RocksStorage::open(&config).unwrap();
+        assert!(storage.get(b"a").unwrap().is_none());
+    }
 
-        let result = storage.get(b"test_key").unwrap();
-        assert!(result.is_some());
-        let v = result.unwrap();
-        assert_eq!(v.flags, 42);
-        assert_eq!(v.data, b"hello");
+    #[test]
+    fn test_flush_persists_across_reopen() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config = test_config(&tmp_dir);
+        {
+            let storage = RocksStorage::open(&config).unwrap();
+            storage
+                .set(b"a", StoredValue::new(0, 0, b"1".to_vec()))
+                .unwrap();
+            storage.flush().unwrap();
+        }
+
+        let storage = RocksStorage::open(&config).unwrap();
+        assert_eq!(storage.get(b"a").unwrap().unwrap().data, b"1");
     }
 
     #[test]
-    fn test_get_nonexistent() {
+    fn test_format_marker() {
         let tmp_dir = TempDir::new().unwrap();
-        let storage = RocksStorage::open(&test_config(&tmp_dir)).unwrap();
+        let config = test_config(&tmp_dir);
+        {
+            let storage = RocksStorage::open(&config).unwrap();
+            let db = &storage.shards[0].db;
+            assert_eq!(db.get(FORMAT_KEY).unwrap().unwrap(), [value::VERSION]);
+            // A value the first release stored is read as it was
+            db.put(
+                b"old",
+                [0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, b'b', b'a', b'r'],
+            )
+            .unwrap();
+            let old = storage.get(b"old").unwrap().unwrap();
+            assert_eq!((old.flags, &old.data[..]), (5, &b"bar"[..]));
+            db.put(FORMAT_KEY, [value::VERSION + 1]).unwrap();
+        }
 
-        let result = storage.get(b"nonexistent").unwrap();
-        assert!(result.is_none());
+        // Written by a newer build: refused rather than misread
+        let Err(error) = RocksStorage::open(&config) else {
+            panic!("opened a database in a newer format");
+        };
+        assert!(error.to_string().contains("format v3"), "{error}");
     }
 
     #[test]
@@ -315,7 +4494,13 @@ mod tests {
         let value = StoredValue::with_expire_at(0, 1, b"old".to_vec());
         let encoded = value.encode();
 
-        let decision = ttl_compaction_filter(0, b"key", &encoded);
+        let decision = ttl_compaction_filter(
+            0,
+            b"key",
+            &encoded,
+            &FlushEpoch::default(),
+            &TtlCounters::default(),
+        );
         assert!(matches!(decision, CompactionDecision::Remove));
     }
 
@@ -325,7 +4510,13 @@ mod tests {
         let value = StoredValue::with_expire_at(0, u64::MAX, b"fresh".to_vec());
         let encoded = value.encode();
 
-        let decision = ttl_compaction_filter(0, b"key", &encoded);
+        let decision = ttl_compaction_filter(
+            0,
+            b"key",
+            &encoded,
+            &FlushEpoch::default(),
+            &TtlCounters::default(),
+        );
         assert!(matches!(decision, CompactionDecision::Keep));
     }
 
@@ -335,14 +4526,171 @@ mod tests {
         let value = StoredValue::with_expire_at(0, 0, b"permanent".to_vec());
         let encoded = value.encode();
 
-        let decision = ttl_compaction_filter(0, b"key", &encoded);
+        let decision = ttl_compaction_filter(
+            0,
+            b"key",
+            &encoded,
+            &FlushEpoch::default(),
+            &TtlCounters::default(),
+        );
+        assert!(matches!(decision, CompactionDecision::Keep));
+    }
+
+    #[test]
+    fn test_compaction_filter_flushed() {
+        let mut value = StoredValue::with_expire_at(0, 0, b"old".to_vec());
+        value.cas = 10;
+        let encoded = value.encode();
+
+        let epoch = FlushEpoch::default();
+        epoch.cas_threshold.store(11, Ordering::Relaxed);
+        let decision = ttl_compaction_filter(0, b"key", &encoded, &epoch, &TtlCounters::default());
+        assert!(matches!(decision, CompactionDecision::Remove));
+
+        // Flush scheduled in the future: keep until it takes effect
+        epoch.effective_at.store(u64::MAX, Ordering::Relaxed);
+        let decision = ttl_compaction_filter(0, b"key", &encoded, &epoch, &TtlCounters::default());
+        assert!(matches!(decision, CompactionDecision::Keep));
+    }
+
+    #[test]
+    fn test_compaction_filter_keeps_internal_keys() {
+        let epoch = FlushEpoch::default();
+        epoch.cas_threshold.store(u64::MAX, Ordering::Relaxed);
+        let decision = ttl_compaction_filter(
+            0,
+            FLUSH_EPOCH_KEY,
+            &epoch.encode(),
+            &epoch,
+            &TtlCounters::default(),
+        );
+        assert!(matches!(decision, CompactionDecision::Keep));
+    }
+
+    #[test]
+    fn test_compaction_filter_v1_values() {
+        // [expire_at][flags][data], as the first release wrote them
+        let v1 = |expire_at: u64| {
+            let mut bytes = expire_at.to_le_bytes().to_vec();
+            bytes.extend_from_slice(&0u32.to_le_bytes());
+            bytes.extend_from_slice(b"data");
+            bytes
+        };
+        let epoch = FlushEpoch::default();
+        let future = current_timestamp() + 3600;
+        let decision = ttl_compaction_filter(0, b"key", &v1(1), &epoch, &TtlCounters::default());
+        assert!(matches!(decision, CompactionDecision::Remove));
+        let decision =
+            ttl_compaction_filter(0, b"key", &v1(future), &epoch, &TtlCounters::default());
         assert!(matches!(decision, CompactionDecision::Keep));
+
+        // No CAS token: older than any flush_all
+        epoch.cas_threshold.store(1, Ordering::Relaxed);
+        let decision =
+            ttl_compaction_filter(0, b"key", &v1(future), &epoch, &TtlCounters::default());
+        assert!(matches!(decision, CompactionDecision::Remove));
     }
 
     #[test]
     fn test_compaction_filter_short_value() {
         // Value too short to contain expire_at header
-        let decision = ttl_compaction_filter(0, b"key", &[0, 1, 2]);
+        let decision = ttl_compaction_filter(
+            0,
+            b"key",
+            &[0, 1, 2],
+            &FlushEpoch::default(),
+            &TtlCounters::default(),
+        );
         assert!(matches!(decision, CompactionDecision::Keep));
     }
+
+    #[test]
+    fn test_compaction_filter_counts_per_storage() {
+        let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+        let [expiring, other] = dirs.each_ref().map(|dir| {
+            RocksStorage::open(&StorageConfig {
+                enable_ttl_compaction: true,
+                shards: 2,
+                ..test_config(dir)
+            })
+            .unwrap()
+        });
+        for i in 0..10 {
+            let key = format!("expired{i}");
+            expiring
+                .set(
+                    key.as_bytes(),
+                    StoredValue::with_expire_at(0, 1, b"v".to_vec()),
+                )
+                .unwrap();
+            other
+                .set(key.as_bytes(), StoredValue::new(0, 0, b"v".to_vec()))
+                .unwrap();
+        }
+        expiring.flush().unwrap();
+        other.flush().unwrap();
+
+        // Every shard's column families count into their own storage
+        assert_eq!(expiring.compact(), 10);
+        assert_eq!(other.compact(), 0);
+        assert_eq!(expiring.ttl_stats().compaction_removed, 10);
+        assert_eq!(expiring.ttl_stats().expired_removed, 0);
+        assert_eq!(other.ttl_stats().compaction_removed, 0);
+        assert_eq!(other.sample_items(20).unwrap().sampled, 10);
+    }
+
+    #[test]
+    fn test_compaction_filter_counts_namespaces() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&StorageConfig {
+            enable_ttl_compaction: true,
+            namespaces: vec![crate::config::NamespaceConfig::new("users")],
+            ..test_config(&tmp_dir)
+        })
+        .unwrap();
+        for key in [&b"users:1"[..], b"plain"] {
+            storage
+                .set(key, StoredValue::with_expire_at(0, 1, b"v".to_vec()))
+                .unwrap();
+        }
+        storage.flush().unwrap();
+        assert_eq!(storage.compact(), 2);
+
+        // A recreated column family keeps counting into the same storage
+        storage.flush_namespace("users").unwrap();
+        storage
+            .set(b"users:2", StoredValue::with_expire_at(0, 1, b"v".to_vec()))
+            .unwrap();
+        storage.flush().unwrap();
+        assert_eq!(storage.compact(), 1);
+        assert_eq!(storage.ttl_stats().compaction_removed, 3);
+    }
+
+    #[test]
+    fn test_lazy_expiration_counts_per_storage() {
+        let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+        let [first, second] = dirs
+            .each_ref()
+            .map(|dir| RocksStorage::open(&test_config(dir)).unwrap());
+        first
+            .set(b"k", StoredValue::with_expire_at(0, 1, b"v".to_vec()))
+            .unwrap();
+        assert!(first.get(b"k").unwrap().is_none());
+        assert_eq!(first.ttl_stats().expired_removed, 1);
+        assert_eq!(second.ttl_stats().expired_removed, 0);
+    }
+
+    #[test]
+    fn test_expired_log_sampling() {
+        let sampled = |sample_rate| {
+            let log = ExpiredLog::new(&StorageConfig {
+                expired_log_sample_rate: sample_rate,
+                ..StorageConfig::default()
+            });
+            (0..7).map(|_| log.sampled()).collect::<Vec<_>>()
+        };
+        assert_eq!(sampled(3), [true, false, false, true, false, false, true]);
+        assert!(sampled(1).iter().all(|&s| s));
+        assert!(sampled(0).iter().all(|&s| !s));
+    }
 }
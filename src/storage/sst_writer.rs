@@ -0,0 +1,102 @@
+//! Writer for SST files that `RocksStorage::ingest_sst_files` accepts
+//!
+//! Offline jobs that precompute cache entries write them with `SstBuilder`
+//! instead of replaying them as sets: keys in strictly increasing byte
+//! order, values in the `StoredValue` encoding. Values without a CAS token
+//! get the time the builder was created, so a later `flush_all` covers
+//! them like any other item.
+
+use super::rocks::is_valid_key;
+use super::value::{StoredValue, current_timestamp_micros};
+use crate::StorageError;
+use rust_rocksdb::{Options, SstFileWriter};
+use std::path::Path;
+use std::sync::LazyLock;
+
+/// Table options for external files; RocksDB's defaults match what the
+/// database reads
+static SST_OPTIONS: LazyLock<Options> = LazyLock::new(Options::default);
+
+/// Builds one SST file of cache entries
+pub struct SstBuilder {
+    writer: SstFileWriter<'static>,
+    last_key: Option<Vec<u8>>,
+    keys: u64,
+    /// CAS token for values added without one
+    cas: u64,
+}
+
+impl SstBuilder {
+    /// Start a new SST file at `path`
+    pub fn create(path: &Path) -> Result<Self, StorageError> {
+        let writer = SstFileWriter::create(&SST_OPTIONS);
+        writer.open(path)?;
+        Ok(Self {
+            writer,
+            last_key: None,
+            keys: 0,
+            cas: current_timestamp_micros(),
+        })
+    }
+
+    /// Add an entry; `key` must sort after every key added before
+    pub fn add(&mut self, key: &[u8], value: &StoredValue) -> Result<(), StorageError> {
+        if !is_valid_key(key) {
+            return Err(StorageError::Encoding(format!(
+                "invalid key {:?}",
+                String::from_utf8_lossy(key)
+            )));
+        }
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(StorageError::Encoding(format!(
+                "key {:?} is not after the previous key",
+                String::from_utf8_lossy(key)
+            )));
+        }
+        let encoded = if value.cas == 0 {
+            value.encode_with_cas(self.cas)
+        } else {
+            value.encode()
+        };
+        self.writer.put(key, encoded)?;
+        self.last_key = Some(key.to_vec());
+        self.keys += 1;
+        Ok(())
+    }
+
+    /// Write the file out, returning how many entries it holds
+    ///
+    /// RocksDB refuses to write a file without entries.
+    pub fn finish(mut self) -> Result<u64, StorageError> {
+        self.writer.finish()?;
+        Ok(self.keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_keys_must_increase() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut builder = SstBuilder::create(&tmp_dir.path().join("a.sst")).unwrap();
+        let value = StoredValue::new(0, 0, b"v".to_vec());
+        builder.add(b"b", &value).unwrap();
+        assert!(builder.add(b"a", &value).is_err());
+        assert!(builder.add(b"b", &value).is_err());
+        builder.add(b"c", &value).unwrap();
+        assert_eq!(builder.finish().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_rejects_invalid_keys() {
+        let tmp_dir = TempDir::new().unwrap();
+        let mut builder = SstBuilder::create(&tmp_dir.path().join("a.sst")).unwrap();
+        let value = StoredValue::new(0, 0, b"v".to_vec());
+        assert!(builder.add(b"", &value).is_err());
+        assert!(builder.add(b"\x00internal", &value).is_err());
+        assert!(builder.add(&[b'k'; 251], &value).is_err());
+    }
+}
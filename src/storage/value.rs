@@ -1,6 +1,23 @@
 //! Value encoding/decoding for RocksDB storage
 //!
-//! Binary format: [8 bytes: expire_at][4 bytes: flags][N bytes: data]
+//! Binary format (v2):
+//! [1 byte: magic][1 byte: version][1 byte: features][4 bytes: flags][1 byte: magic]
+//! [8 bytes: expire_at][8 bytes: cas][N bytes: data]
+//!
+//! The magic byte (`0xFE`) opens and closes the 8-byte preamble. Read as
+//! the little-endian `expire_at` a v1 value starts with, the preamble would
+//! be a timestamp past 2^63, which v1 never stores, so the two formats
+//! can't be confused. Feature bits describe the data (`FEATURE_LZ4`:
+//! LZ4-compressed, prefixed by its uncompressed size; `FEATURE_CRC32C`: a
+//! 4-byte CRC32C of the header and stored data follows the header, before
+//! the data, and is verified on every decode); unknown bits or
+//! versions are corrupt rather than guessed at. `expire_at` and `cas` sit
+//! at fixed offsets so the compaction filter can read them (`ValueHeader`)
+//! without decoding the rest of the value.
+//!
+//! Values written by the first release, before v2, are still read:
+//! v1: [8 bytes: expire_at][4 bytes: flags][N bytes: data]
+//! They have no CAS token; it reads as 0, older than any `flush_all`.
 //!
 //! ## TTL Rules (memcached-compatible)
 //!
@@ -11,10 +28,14 @@
 //! - `0` = never expire
 //! - `1..=2592000` (up to 30 days) = relative seconds from now
 //! - `> 2592000` = absolute Unix timestamp
+//! - negative, or an absolute timestamp not in the future = already expired
 //!
 //! Reference: <https://github.com/memcached/memcached/wiki/Commands#standard-protocol>
 
 use crate::StorageError;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Maximum relative TTL value (30 days in seconds)
@@ -24,11 +45,107 @@ use std::time::{SystemTime, UNIX_EPOCH};
 /// treated as absolute Unix timestamps.
 const MAX_RELATIVE_TTL: u64 = 2_592_000;
 
+/// `expire_at` of an item stored already expired (negative or past exptime)
+///
+/// Any non-zero timestamp in the past would do; 1 keeps it recognizable.
+pub const EXPIRED: u64 = 1;
+
+/// Size of the fixed header preceding the data (preamble + expire_at + cas)
+pub const HEADER_SIZE: usize = 24;
+
+/// First and last byte of a v2 value's preamble
+const MAGIC: u8 = 0xFE;
+
+/// Current encoding version
+pub(crate) const VERSION: u8 = 2;
+
+/// Feature bit: data is LZ4-compressed, prefixed by its uncompressed size
+const FEATURE_LZ4: u8 = 0x01;
+
+/// Feature bit: a CRC32C of the header and data follows the header
+const FEATURE_CRC32C: u8 = 0x02;
+
+/// Every feature bit this build understands
+const KNOWN_FEATURES: u8 = FEATURE_LZ4 | FEATURE_CRC32C;
+
+/// Size of the checksum following the header with `FEATURE_CRC32C`
+const CHECKSUM_SIZE: usize = 4;
+
+/// Size of the v1 header (expire_at + flags)
+const V1_HEADER_SIZE: usize = 12;
+
+/// Why bytes don't decode as a current (v2) value
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    /// Written in the v1 format, before the magic preamble
+    #[error("legacy v1 value")]
+    LegacyV1,
+
+    /// Not a valid value in any format
+    #[error("corrupt value: {0}")]
+    Corrupt(String),
+}
+
+/// How `StoredValue::encode_with` stores a value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EncodeOptions {
+    /// LZ4-compress data longer than this many bytes, when that makes it
+    /// smaller (0 = never)
+    pub compress_over_bytes: usize,
+    /// Store a CRC32C of the value
+    pub checksum: bool,
+}
+
+/// The fields of a value readable without decoding its data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueHeader {
+    pub expire_at: u64,
+    pub cas: u64,
+}
+
+impl ValueHeader {
+    /// Read the header of an encoded v1 or v2 value; `None` if it is too
+    /// short or of an unknown version
+    pub fn read(bytes: &[u8]) -> Option<Self> {
+        let word = |at: usize| {
+            bytes
+                .get(at..at + 8)
+                .map(|b| u64::from_le_bytes(b.try_into().unwrap_or([0; 8])))
+        };
+        if is_v2(bytes) {
+            if bytes[1] != VERSION || bytes.len() < HEADER_SIZE {
+                return None;
+            }
+            Some(Self {
+                expire_at: word(8)?,
+                cas: word(16)?,
+            })
+        } else if bytes.len() < V1_HEADER_SIZE {
+            None
+        } else {
+            Some(Self {
+                expire_at: word(0)?,
+                cas: 0,
+            })
+        }
+    }
+}
+
+/// Whether `bytes` start with a v2 preamble
+fn is_v2(bytes: &[u8]) -> bool {
+    bytes.len() >= 8 && bytes[0] == MAGIC && bytes[7] == MAGIC
+}
+
 /// Stored value with metadata
 #[derive(Debug, Clone)]
 pub struct StoredValue {
     /// Expiration timestamp (0 = never expire)
     pub expire_at: u64,
+    /// CAS unique token, assigned by storage on every write
+    ///
+    /// Tokens come from a hybrid clock (never below the write's wall-clock
+    /// time in microseconds), so they also order writes against `flush_all`.
+    pub cas: u64,
     /// Memcached flags
     pub flags: u32,
     /// Actual data
@@ -37,10 +154,11 @@ pub struct StoredValue {
 
 impl StoredValue {
     /// Create a new stored value
-    pub fn new(flags: u32, exptime: u64, data: Vec<u8>) -> Self {
+    pub fn new(flags: u32, exptime: i64, data: Vec<u8>) -> Self {
         let expire_at = calculate_expire_at(exptime);
         Self {
             expire_at,
+            cas: 0,
             flags,
             data,
         }
@@ -50,6 +168,7 @@ impl StoredValue {
     pub fn with_expire_at(flags: u32, expire_at: u64, data: Vec<u8>) -> Self {
         Self {
             expire_at,
+            cas: 0,
             flags,
             data,
         }
@@ -57,39 +176,117 @@ impl StoredValue {
 
     /// Encode the value to bytes for storage
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(12 + self.data.len());
-        buf.extend_from_slice(&self.expire_at.to_le_bytes());
+        self.encode_as(self.cas, 0, &self.data)
+    }
+
+    /// Encode the value with `cas` in place of its own token
+    pub(crate) fn encode_with_cas(&self, cas: u64) -> Vec<u8> {
+        self.encode_as(cas, 0, &self.data)
+    }
+
+    /// Encode the value, compressed and checksummed as `options` ask
+    ///
+    /// Returns the encoding and the bytes compression saved.
+    pub fn encode_with(&self, options: EncodeOptions) -> (Vec<u8>, u64) {
+        let features = if options.checksum { FEATURE_CRC32C } else { 0 };
+        let threshold = options.compress_over_bytes;
+        if threshold > 0 && self.data.len() > threshold {
+            let compressed = lz4_flex::compress_prepend_size(&self.data);
+            if compressed.len() < self.data.len() {
+                let saved = (self.data.len() - compressed.len()) as u64;
+                let encoded = self.encode_as(self.cas, features | FEATURE_LZ4, &compressed);
+                return (encoded, saved);
+            }
+        }
+        (self.encode_as(self.cas, features, &self.data), 0)
+    }
+
+    fn encode_as(&self, cas: u64, features: u8, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE + CHECKSUM_SIZE + data.len());
+        buf.extend_from_slice(&[MAGIC, VERSION, features]);
         buf.extend_from_slice(&self.flags.to_le_bytes());
-        buf.extend_from_slice(&self.data);
+        buf.push(MAGIC);
+        buf.extend_from_slice(&self.expire_at.to_le_bytes());
+        buf.extend_from_slice(&cas.to_le_bytes());
+        if features & FEATURE_CRC32C != 0 {
+            let checksum = crc32c::crc32c_append(crc32c::crc32c(&buf), data);
+            buf.extend_from_slice(&checksum.to_le_bytes());
+        }
+        buf.extend_from_slice(data);
         buf
     }
 
-    /// Decode a stored value from bytes
+    /// Decode a stored value from bytes, in the current or the v1 format
     pub fn decode(bytes: &[u8]) -> Result<Self, StorageError> {
-        if bytes.len() < 12 {
-            return Err(StorageError::Decoding(
-                "Value too short to decode".to_string(),
-            ));
+        match Self::decode_v2(bytes) {
+            Err(DecodeError::LegacyV1) => Self::decode_v1(bytes),
+            result => result,
         }
+        .map_err(|e| StorageError::Decoding(e.to_string()))
+    }
 
-        let expire_at = u64::from_le_bytes(
-            bytes[0..8]
-                .try_into()
-                .map_err(|_| StorageError::Decoding("Invalid expire_at".to_string()))?,
-        );
+    /// Decode a value in the current format only
+    pub fn decode_v2(bytes: &[u8]) -> Result<Self, DecodeError> {
+        if !is_v2(bytes) {
+            return Err(DecodeError::LegacyV1);
+        }
+        let corrupt = |reason: &str| DecodeError::Corrupt(reason.to_string());
+        if bytes[1] != VERSION {
+            return Err(DecodeError::Corrupt(format!(
+                "unsupported version {}",
+                bytes[1]
+            )));
+        }
+        let features = bytes[2];
+        if features & !KNOWN_FEATURES != 0 {
+            return Err(DecodeError::Corrupt(format!(
+                "unknown feature bits {features:#04x}"
+            )));
+        }
+        let header = ValueHeader::read(bytes).ok_or_else(|| corrupt("value too short"))?;
+        let flags = u32::from_le_bytes(bytes[3..7].try_into().map_err(|_| corrupt("flags"))?);
+        let mut data = &bytes[HEADER_SIZE..];
+        if features & FEATURE_CRC32C != 0 {
+            if data.len() < CHECKSUM_SIZE {
+                return Err(corrupt("value too short for its checksum"));
+            }
+            let (stored, rest) = data.split_at(CHECKSUM_SIZE);
+            let stored = u32::from_le_bytes(stored.try_into().map_err(|_| corrupt("checksum"))?);
+            let actual = crc32c::crc32c_append(crc32c::crc32c(&bytes[..HEADER_SIZE]), rest);
+            if stored != actual {
+                return Err(DecodeError::Corrupt(format!(
+                    "checksum mismatch (stored {stored:#010x}, computed {actual:#010x})"
+                )));
+            }
+            data = rest;
+        }
+        let data = if features & FEATURE_LZ4 == 0 {
+            data.to_vec()
+        } else {
+            decompress(data)?
+        };
+        Ok(Self {
+            expire_at: header.expire_at,
+            cas: header.cas,
+            flags,
+            data,
+        })
+    }
 
+    /// Decode a value written in the v1 format
+    fn decode_v1(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let corrupt = |reason: &str| DecodeError::Corrupt(reason.to_string());
+        let header = ValueHeader::read(bytes).ok_or_else(|| corrupt("value too short"))?;
         let flags = u32::from_le_bytes(
-            bytes[8..12]
+            bytes[8..V1_HEADER_SIZE]
                 .try_into()
-                .map_err(|_| StorageError::Decoding("Invalid flags".to_string()))?,
+                .map_err(|_| corrupt("flags"))?,
         );
-
-        let data = bytes[12..].to_vec();
-
         Ok(Self {
-            expire_at,
+            expire_at: header.expire_at,
+            cas: header.cas,
             flags,
-            data,
+            data: bytes[V1_HEADER_SIZE..].to_vec(),
         })
     }
 
@@ -102,7 +299,7 @@ impl StoredValue {
     }
 
     /// Update the expiration time
-    pub fn touch(&mut self, exptime: u64) {
+    pub fn touch(&mut self, exptime: i64) {
         self.expire_at = calculate_expire_at(exptime);
     }
 
@@ -120,32 +317,126 @@ impl StoredValue {
     }
 }
 
+/// Decompress LZ4 data prefixed by its uncompressed size
+fn decompress(data: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    lz4_flex::decompress_size_prepended(data)
+        .map_err(|e| DecodeError::Corrupt(format!("invalid compressed data: {e}")))
+}
+
 /// Calculate the absolute expiration timestamp from memcached exptime
 ///
 /// Implements memcached TTL semantics:
 /// - `0` → never expire (returns 0)
 /// - `1..=2592000` → relative seconds, converted to absolute timestamp
 /// - `>2592000` → already an absolute Unix timestamp, used as-is
+/// - negative, or an absolute timestamp `<=` now → [`EXPIRED`]
 ///
 /// See: <https://github.com/memcached/memcached/wiki/Commands#standard-protocol>
-pub fn calculate_expire_at(exptime: u64) -> u64 {
-    if exptime == 0 {
-        0 // Never expire
-    } else if exptime <= MAX_RELATIVE_TTL {
+pub fn calculate_expire_at(exptime: i64) -> u64 {
+    match u64::try_from(exptime) {
+        // Negative: stored already expired ("delete but keep CAS" trick)
+        Err(_) => EXPIRED,
+        Ok(0) => 0, // Never expire
         // Relative time: add to current timestamp
-        current_timestamp().saturating_add(exptime)
-    } else {
+        Ok(secs) if secs <= MAX_RELATIVE_TTL => current_timestamp().saturating_add(secs),
         // Absolute Unix timestamp (value > 30 days treated as timestamp)
-        exptime
+        Ok(timestamp) if timestamp <= current_timestamp() => EXPIRED,
+        Ok(timestamp) => timestamp,
     }
 }
 
+/// Server-side adjustments to client exptimes: `storage.default_ttl_secs`,
+/// `storage.max_ttl_secs` and `storage.ttl_jitter_percent` (0 = off)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TtlPolicy {
+    /// TTL for items stored with exptime 0
+    pub default_secs: u64,
+    /// Longest TTL a client may ask for
+    pub max_secs: u64,
+    /// Relative TTLs are moved by a random offset of up to this percentage
+    /// either way
+    pub jitter_percent: u32,
+}
+
+impl TtlPolicy {
+    /// The exptime to store for a client's `exptime`: the default TTL for
+    /// 0, a jittered TTL for a relative exptime, and at most `max_secs`
+    /// from now. Absolute, negative and past exptimes within the bounds are
+    /// returned unchanged.
+    pub fn apply(self, exptime: i64) -> i64 {
+        if exptime == 0 {
+            return relative_exptime(self.default_secs);
+        }
+        let exptime = match u64::try_from(exptime) {
+            Ok(secs) if secs <= MAX_RELATIVE_TTL && self.jitter_percent > 0 => {
+                relative_exptime(jitter(secs, self.jitter_percent))
+            }
+            _ => exptime,
+        };
+        if self.max_secs == 0 {
+            return exptime;
+        }
+        match calculate_expire_at(exptime) {
+            EXPIRED => exptime,
+            expire_at if expire_at.saturating_sub(current_timestamp()) > self.max_secs => {
+                relative_exptime(self.max_secs)
+            }
+            _ => exptime,
+        }
+    }
+}
+
+/// `secs` moved by a uniformly random offset of up to `percent` of it
+/// either way, at least 1
+fn jitter(secs: u64, percent: u32) -> u64 {
+    let range = secs.saturating_mul(u64::from(percent)) / 100;
+    if range == 0 {
+        return secs;
+    }
+    let offset = random_u64() % (2 * range + 1);
+    (secs + offset).saturating_sub(range).max(1)
+}
+
+thread_local! {
+    /// xorshift64* state, seeded per thread
+    static RNG: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+/// A cheap, non-cryptographic random number
+fn random_u64() -> u64 {
+    RNG.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// An exptime `secs` from now: relative up to 30 days, an absolute
+/// timestamp beyond (0 stays "never expire")
+fn relative_exptime(secs: u64) -> i64 {
+    let exptime = if secs <= MAX_RELATIVE_TTL {
+        secs
+    } else {
+        current_timestamp().saturating_add(secs)
+    };
+    i64::try_from(exptime).unwrap_or(i64::MAX)
+}
+
 /// Get the current Unix timestamp
 pub fn current_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .map(|d| d.as_secs())
-        .unwrap_or(0)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Get the current Unix timestamp in microseconds
+pub fn current_timestamp_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
 }
 
 #[cfg(test)]
@@ -154,15 +445,170 @@ mod tests {
 
     #[test]
     fn test_encode_decode() {
-        let value = StoredValue::with_expire_at(42, 1234567890, b"hello".to_vec());
+        let mut value = StoredValue::with_expire_at(42, 1_234_567_890, b"hello".to_vec());
+        value.cas = 77;
         let encoded = value.encode();
+        assert_eq!(encoded.len(), HEADER_SIZE + 5);
         let decoded = StoredValue::decode(&encoded).unwrap();
 
-        assert_eq!(decoded.expire_at, 1234567890);
+        assert_eq!(decoded.expire_at, 1_234_567_890);
+        assert_eq!(decoded.cas, 77);
         assert_eq!(decoded.flags, 42);
         assert_eq!(decoded.data, b"hello");
     }
 
+    fn compressed(compress_over_bytes: usize) -> EncodeOptions {
+        EncodeOptions {
+            compress_over_bytes,
+            checksum: false,
+        }
+    }
+
+    fn checksummed(compress_over_bytes: usize) -> EncodeOptions {
+        EncodeOptions {
+            compress_over_bytes,
+            checksum: true,
+        }
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let mut value = StoredValue::with_expire_at(7, 0, b"{\"a\":1}".repeat(100));
+        value.cas = 1_700_000_000_000_000;
+
+        let (encoded, saved) = value.encode_with(compressed(64));
+        assert!(saved > 0);
+        assert_eq!(
+            encoded.len() as u64 + saved,
+            (HEADER_SIZE + value.data.len()) as u64
+        );
+        assert_eq!(ValueHeader::read(&encoded).unwrap().cas, value.cas);
+        let decoded = StoredValue::decode(&encoded).unwrap();
+        assert_eq!((decoded.cas, decoded.flags), (value.cas, 7));
+        assert_eq!(decoded.data, value.data);
+
+        // At or under the threshold, or incompressible: plain format
+        assert_eq!(value.encode_with(compressed(700)), (value.encode(), 0));
+        assert_eq!(value.encode_with(compressed(0)).1, 0);
+        let noise: Vec<u8> = (0..=255u8).collect();
+        let value = StoredValue::with_expire_at(0, 0, noise);
+        assert_eq!(value.encode_with(compressed(64)).1, 0);
+    }
+
+    /// `StoredValue::encode` of the first release, verbatim
+    fn encode_v1(value: &StoredValue) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(12 + value.data.len());
+        buf.extend_from_slice(&value.expire_at.to_le_bytes());
+        buf.extend_from_slice(&value.flags.to_le_bytes());
+        buf.extend_from_slice(&value.data);
+        buf
+    }
+
+    #[test]
+    fn test_decode_v1_bytes() {
+        // `set foo 5 0 3` + `bar`, and an empty value with a TTL, as the
+        // first release stored them
+        let bytes = [0, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, b'b', b'a', b'r'];
+        let value = StoredValue::decode(&bytes).unwrap();
+        assert_eq!(
+            (value.expire_at, value.cas, value.flags, &value.data[..]),
+            (0, 0, 5, &b"bar"[..])
+        );
+        let bytes = [0x80, 0x84, 0x3D, 0x71, 0, 0, 0, 0, 0, 0, 0, 0];
+        let value = StoredValue::decode(&bytes).unwrap();
+        assert_eq!((value.expire_at, value.flags), (1_899_857_024, 0));
+        assert!(value.data.is_empty());
+        assert_eq!(
+            ValueHeader::read(&bytes),
+            Some(ValueHeader {
+                expire_at: 1_899_857_024,
+                cas: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_format_compatibility() {
+        let expire_ats = [0, EXPIRED, 1_900_000_000, i64::MAX as u64];
+        let payloads = [Vec::new(), b"42".to_vec(), b"{\"k\":\"v\"}".repeat(50)];
+        for expire_at in expire_ats {
+            for data in &payloads {
+                let mut value = StoredValue::with_expire_at(0xDEAD_BEEF, expire_at, data.clone());
+                value.cas = 1_700_000_000_123_456;
+                let encodings = [
+                    ("v1", encode_v1(&value)),
+                    ("v2", value.encode()),
+                    ("v2 lz4", value.encode_with(compressed(1)).0),
+                    ("v2 crc", value.encode_with(checksummed(0)).0),
+                    ("v2 lz4 crc", value.encode_with(checksummed(1)).0),
+                ];
+                for (name, bytes) in encodings {
+                    // v1 has no CAS token
+                    let cas = if name == "v1" { 0 } else { value.cas };
+                    let header = ValueHeader::read(&bytes).unwrap();
+                    assert_eq!((header.expire_at, header.cas), (expire_at, cas), "{name}");
+                    let decoded = StoredValue::decode(&bytes).unwrap();
+                    assert_eq!(decoded.expire_at, expire_at, "{name}");
+                    assert_eq!(decoded.cas, cas, "{name}");
+                    assert_eq!(decoded.flags, 0xDEAD_BEEF, "{name}");
+                    assert_eq!(&decoded.data, data, "{name}");
+                    assert_eq!(
+                        StoredValue::decode_v2(&bytes).is_ok(),
+                        name.starts_with("v2"),
+                        "{name}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let value = StoredValue::with_expire_at(1, 0, b"data".to_vec());
+        assert_eq!(
+            StoredValue::decode_v2(&encode_v1(&value)).unwrap_err(),
+            DecodeError::LegacyV1
+        );
+
+        let encoded = value.encode();
+        let corrupt = |bytes: &[u8]| {
+            matches!(StoredValue::decode_v2(bytes), Err(DecodeError::Corrupt(_)))
+                && StoredValue::decode(bytes).is_err()
+        };
+        // Truncated header
+        assert!(corrupt(&encoded[..HEADER_SIZE - 1]));
+        // Unknown version
+        let mut bytes = encoded.clone();
+        bytes[1] = 3;
+        assert!(corrupt(&bytes));
+        assert!(ValueHeader::read(&bytes).is_none());
+        // Unknown feature bit
+        let mut bytes = encoded.clone();
+        bytes[2] = 0x80;
+        assert!(corrupt(&bytes));
+        // Compressed flag on data that isn't
+        let mut bytes = encoded;
+        bytes[2] = FEATURE_LZ4;
+        assert!(corrupt(&bytes));
+
+        // Any flipped byte of a checksummed value is caught
+        let (encoded, _) = value.encode_with(checksummed(0));
+        assert_eq!(encoded.len(), HEADER_SIZE + CHECKSUM_SIZE + 4);
+        assert!(StoredValue::decode(&encoded).is_ok());
+        for i in (1..encoded.len()).filter(|&i| i != 7) {
+            let mut bytes = encoded.clone();
+            bytes[i] ^= 0x01;
+            assert!(StoredValue::decode(&bytes).is_err(), "byte {i}");
+        }
+        // Partially written: truncated data, or no room for the checksum
+        assert!(corrupt(&encoded[..encoded.len() - 1]));
+        assert!(corrupt(&encoded[..HEADER_SIZE + 2]));
+
+        // v1: too short
+        assert!(StoredValue::decode(&[0; V1_HEADER_SIZE - 1]).is_err());
+        assert!(ValueHeader::read(&[0; V1_HEADER_SIZE - 1]).is_none());
+    }
+
     #[test]
     fn test_never_expire() {
         let value = StoredValue::new(0, 0, b"data".to_vec());
@@ -180,11 +626,113 @@ mod tests {
 
     #[test]
     fn test_absolute_timestamp() {
-        let future = current_timestamp() + 3000000;
-        let value = StoredValue::new(0, future, b"data".to_vec());
+        let future = current_timestamp() + 3_000_000;
+        let value = StoredValue::new(0, i64::try_from(future).unwrap(), b"data".to_vec());
         assert_eq!(value.expire_at, future);
     }
 
+    #[test]
+    fn test_negative_exptime() {
+        for exptime in [-1, i64::MIN] {
+            let value = StoredValue::new(0, exptime, b"data".to_vec());
+            assert_eq!(value.expire_at, EXPIRED);
+            assert!(value.is_expired());
+        }
+    }
+
+    #[test]
+    fn test_past_absolute_timestamp() {
+        let past = current_timestamp() - 60;
+        let value = StoredValue::new(0, i64::try_from(past).unwrap(), b"data".to_vec());
+        assert_eq!(value.expire_at, EXPIRED);
+        assert!(value.is_expired());
+    }
+
+    #[test]
+    fn test_relative_boundary() {
+        // Exactly 30 days is still relative...
+        let now = current_timestamp();
+        let value = StoredValue::new(0, 2_592_000, b"data".to_vec());
+        assert!(value.expire_at >= now + 2_592_000 && value.expire_at <= now + 2_592_001);
+        assert!(!value.is_expired());
+
+        // ...one second more is an absolute timestamp in 1970
+        let value = StoredValue::new(0, 2_592_001, b"data".to_vec());
+        assert_eq!(value.expire_at, EXPIRED);
+    }
+
+    #[test]
+    fn test_ttl_policy() {
+        let now = i64::try_from(current_timestamp()).unwrap();
+        let off = TtlPolicy::default();
+        for exptime in [0, 60, -1, now + 3_000_000] {
+            assert_eq!(off.apply(exptime), exptime);
+        }
+
+        let policy = TtlPolicy {
+            default_secs: 60,
+            max_secs: 3600,
+            jitter_percent: 0,
+        };
+        assert_eq!(policy.apply(0), 60);
+        // Within the cap: exactly what the client sent
+        assert_eq!(policy.apply(3600), 3600);
+        assert_eq!(policy.apply(now + 1800), now + 1800);
+        // Over it: the cap, relative
+        assert_eq!(policy.apply(3601), 3600);
+        assert_eq!(policy.apply(now + 7200), 3600);
+        // Expired stays expired
+        assert_eq!(policy.apply(-1), -1);
+        assert_eq!(policy.apply(now - 60), now - 60);
+
+        // Over 30 days becomes an absolute timestamp
+        let long = TtlPolicy {
+            default_secs: 90 * 86_400,
+            ..TtlPolicy::default()
+        };
+        let expire_at = calculate_expire_at(long.apply(0));
+        let expected = now.unsigned_abs() + 90 * 86_400;
+        assert!((expected..=expected + 1).contains(&expire_at));
+    }
+
+    #[test]
+    fn test_ttl_jitter() {
+        let policy = TtlPolicy {
+            jitter_percent: 10,
+            ..TtlPolicy::default()
+        };
+        let samples: Vec<i64> = (0..10_000).map(|_| policy.apply(86_400)).collect();
+        assert!(samples.iter().all(|ttl| (77_760..=95_040).contains(ttl)));
+        // Uniform over the window: each tenth of it gets about 1000
+        let mut buckets = [0; 10];
+        for ttl in &samples {
+            let bucket = usize::try_from((ttl - 77_760) * 10 / 17_281).unwrap();
+            buckets[bucket] += 1;
+        }
+        assert!(
+            buckets.iter().all(|n| (800..1200).contains(n)),
+            "{buckets:?}"
+        );
+        let mean = samples.iter().sum::<i64>() / 10_000;
+        assert!((86_000..86_800).contains(&mean), "{mean}");
+
+        // exptime 0, absolute and negative exptimes are left alone
+        let now = i64::try_from(current_timestamp()).unwrap();
+        assert_eq!(policy.apply(0), 0);
+        assert_eq!(policy.apply(now + 3_000_000), now + 3_000_000);
+        assert_eq!(policy.apply(-1), -1);
+        // Too short to move, and never below a second
+        assert_eq!(policy.apply(5), 5);
+        let wide = TtlPolicy {
+            jitter_percent: 100,
+            ..TtlPolicy::default()
+        };
+        assert!((0..1000).all(|_| (1..=20).contains(&wide.apply(10))));
+        // Past 30 days becomes an absolute timestamp
+        let ttl = calculate_expire_at(policy.apply(2_592_000)) - current_timestamp();
+        assert!((2_332_800..=2_851_201).contains(&ttl));
+    }
+
     #[test]
     fn test_expired() {
         let value = StoredValue::with_expire_at(0, 1, b"data".to_vec());
@@ -0,0 +1,160 @@
+//! RocksDB background error detection
+//!
+//! After a background error (full disk or an I/O error during a flush or
+//! compaction) RocksDB may refuse every write until it recovers, while the
+//! process and `/ready` look fine. This task checks every
+//! `health_check_interval_ms` for new background errors and whether a
+//! canary write still succeeds. While it fails the shared `StorageHealth`
+//! is unhealthy, `/ready` answers 503 so mcrouter moves traffic elsewhere,
+//! and `stats` reports `storage_healthy 0`. The first successful check
+//! after RocksDB recovers, on its own or after operator intervention,
+//! flips it back.
+
+use crate::config::StorageConfig;
+use crate::metrics::Metrics;
+use crate::storage::{HealthCheck, RocksStorage};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Storage health shared by the check task, `HealthServer` and `stats`
+#[derive(Debug)]
+pub struct StorageHealth {
+    healthy: AtomicBool,
+    /// Background errors seen by the last check
+    background_errors: AtomicU64,
+}
+
+impl Default for StorageHealth {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StorageHealth {
+    /// Healthy until a check says otherwise
+    pub fn new() -> Self {
+        Self {
+            healthy: AtomicBool::new(true),
+            background_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the last check found RocksDB accepting writes
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Apply one check's result, updating the metrics
+    pub fn record(&self, check: &HealthCheck, metrics: &Metrics) {
+        let previous = self
+            .background_errors
+            .swap(check.background_errors, Ordering::Relaxed);
+        let new_errors = check.background_errors.saturating_sub(previous);
+        if new_errors > 0 {
+            metrics.storage_background_errors.inc_by(new_errors);
+            warn!(
+                new_errors,
+                total = check.background_errors,
+                "RocksDB reported background errors"
+            );
+        }
+
+        let healthy = check.write_error.is_none();
+        let was_healthy = self.healthy.swap(healthy, Ordering::Relaxed);
+        metrics.storage_unhealthy.set(i64::from(!healthy));
+        match &check.write_error {
+            Some(e) if was_healthy => {
+                error!(
+                    "Storage is not accepting writes, reporting not ready: {}",
+                    e
+                );
+            }
+            None if !was_healthy => info!("Storage accepts writes again"),
+            _ => {}
+        }
+    }
+}
+
+/// Check storage health until `cancel` fires; returns immediately if
+/// `health_check_interval_ms` is 0
+pub async fn run(
+    storage: Arc<RocksStorage>,
+    health: Arc<StorageHealth>,
+    metrics: Arc<Metrics>,
+    config: &StorageConfig,
+    cancel: CancellationToken,
+) {
+    if config.health_check_interval_ms == 0 {
+        return;
+    }
+    let mut ticker = tokio::time::interval(Duration::from_millis(config.health_check_interval_ms));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            () = cancel.cancelled() => break,
+            _ = ticker.tick() => {}
+        }
+
+        let task_storage = Arc::clone(&storage);
+        match tokio::task::spawn_blocking(move || task_storage.check_health()).await {
+            Ok(check) => health.record(&check, &metrics),
+            Err(e) => error!("Storage health check task failed: {}", e),
+        }
+    }
+    debug!("Storage health check stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_unhealthy_until_writes_succeed() {
+        let health = StorageHealth::new();
+        let metrics = Metrics::new().unwrap();
+
+        health.record(
+            &HealthCheck {
+                background_errors: 2,
+                write_error: Some("IO error: No space left on device".to_string()),
+            },
+            &metrics,
+        );
+        assert!(!health.is_healthy());
+        assert_eq!(metrics.storage_unhealthy.get(), 1);
+        assert_eq!(metrics.storage_background_errors.get(), 2);
+
+        // Recovered: only new errors are counted
+        health.record(
+            &HealthCheck {
+                background_errors: 3,
+                write_error: None,
+            },
+            &metrics,
+        );
+        assert!(health.is_healthy());
+        assert_eq!(metrics.storage_unhealthy.get(), 0);
+        assert_eq!(metrics.storage_background_errors.get(), 3);
+    }
+
+    #[test]
+    fn test_check_healthy_storage() {
+        let tmp_dir = TempDir::new().unwrap();
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            shards: 2,
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        let check = storage.check_health();
+        assert_eq!(check.write_error, None);
+        // The canary is not a client key
+        assert_eq!(storage.sample_items(10).unwrap().sampled, 0);
+    }
+}
@@ -0,0 +1,224 @@
+//! Ephemeral servers for tests
+//!
+//! `TestServer::spawn()` opens a store in a temporary directory, serves it
+//! on `127.0.0.1:0` and hands out `client::Client`s for the port it got.
+//! A health server with `/admin/compact` and `/admin/flush_namespace` runs
+//! next to it on a port of its own.
+//!
+//! ```ignore
+//! let server = TestServer::spawn().await;
+//! let mut client = server.client().await;
+//! assert!(client.set(b"foo", 0, 0, b"bar").await?);
+//! ```
+//!
+//! `test_server()` builds just the `Server`, for tests that hand it
+//! connections themselves.
+//!
+//! Built for the crate's own tests and with the `testing` feature.
+
+use crate::client::Client;
+use crate::compaction::Compactor;
+use crate::config::{ServerConfig, StorageConfig};
+use crate::health::HealthServer;
+use crate::metrics::Metrics;
+use crate::server::Server;
+use crate::storage::RocksStorage;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tempfile::TempDir;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// A server on an ephemeral port, shut down when dropped
+pub struct TestServer {
+    server: Arc<Server>,
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+    health_addr: SocketAddr,
+    cancel: CancellationToken,
+    task: Option<JoinHandle<anyhow::Result<()>>>,
+    health_task: Option<JoinHandle<()>>,
+    /// Holds the store; removed after the server stops
+    dir: TempDir,
+}
+
+impl TestServer {
+    /// Spawn a server with the default configuration
+    ///
+    /// Panics if the store can't be opened or the port bound.
+    pub async fn spawn() -> Self {
+        Self::spawn_with(ServerConfig::default(), StorageConfig::default()).await
+    }
+
+    /// Spawn a server with custom configuration; `listen_addr`,
+    /// `unix_socket_path` and `db_path` are replaced
+    pub async fn spawn_with(config: ServerConfig, storage: StorageConfig) -> Self {
+        let dir = TempDir::new().expect("create temporary directory");
+        let server = Arc::new(test_server_with(
+            &dir,
+            ServerConfig {
+                listen_addr: "127.0.0.1:0".to_string(),
+                unix_socket_path: None,
+                ..config
+            },
+            storage,
+        ));
+        let storage = Arc::clone(&server.storage);
+        let metrics = Arc::clone(&server.metrics);
+        let cancel = server.cancel_token.clone();
+
+        // Bound before the server task starts, so connecting never races it
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind 127.0.0.1:0");
+        let addr = listener.local_addr().expect("listener address");
+        let task = tokio::spawn(Arc::clone(&server).run_on(listener));
+
+        let health = HealthServer::new(Arc::clone(&metrics))
+            .with_storage(Arc::clone(&storage))
+            .with_compactor(Arc::new(Compactor::new(
+                Arc::clone(&storage),
+                Arc::clone(&metrics),
+            )));
+        let health_listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind 127.0.0.1:0");
+        let health_addr = health_listener.local_addr().expect("listener address");
+        let health_task = tokio::spawn(Arc::new(health).run_on(health_listener, cancel.clone()));
+        Self {
+            server,
+            storage,
+            metrics,
+            addr,
+            health_addr,
+            cancel,
+            task: Some(task),
+            health_task: Some(health_task),
+            dir,
+        }
+    }
+
+    /// The address the server accepts on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// The health server's address, for `/metrics` and `/admin/...`
+    pub fn health_addr(&self) -> SocketAddr {
+        self.health_addr
+    }
+
+    /// The running server, e.g. for `set_read_only`
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    pub fn storage(&self) -> &Arc<RocksStorage> {
+        &self.storage
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// The temporary directory holding the store
+    pub fn dir(&self) -> &std::path::Path {
+        self.dir.path()
+    }
+
+    /// Connect a new client
+    pub async fn client(&self) -> Client<TcpStream> {
+        Client::connect(self.addr)
+            .await
+            .expect("connect to test server")
+    }
+
+    /// Stop accepting, let open connections finish and wait for the server
+    /// to exit
+    pub async fn shutdown(mut self) {
+        self.cancel.cancel();
+        if let Some(task) = self.task.take() {
+            task.await
+                .expect("server task panicked")
+                .expect("server failed");
+        }
+        if let Some(task) = self.health_task.take() {
+            task.await.expect("health server task panicked");
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        // Drop can't wait for a graceful shutdown; aborting the task drops
+        // the listener and its connections right away
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.health_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// A server over a store in `dir/test_db`, for tests that hand it
+/// connections themselves (in-memory pipes, handshakes) rather than
+/// through a port
+pub fn test_server(dir: &TempDir, config: ServerConfig) -> Arc<Server> {
+    Arc::new(test_server_with(dir, config, StorageConfig::default()))
+}
+
+/// Like `test_server`, with a custom storage configuration (`db_path` is
+/// replaced); not yet in an `Arc`, so the `with_*` builders still apply
+pub fn test_server_with(dir: &TempDir, config: ServerConfig, storage: StorageConfig) -> Server {
+    let storage = RocksStorage::open(&StorageConfig {
+        db_path: dir.path().join("test_db"),
+        ..storage
+    })
+    .expect("open test storage");
+    Server::new(
+        config,
+        Arc::new(storage),
+        Arc::new(Metrics::new().expect("create metrics")),
+        CancellationToken::new(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_spawn_and_shutdown() {
+        let first = TestServer::spawn().await;
+        let second = TestServer::spawn().await;
+        assert_ne!(first.port(), 0);
+        assert_ne!(first.port(), second.port());
+        assert_ne!(first.port(), first.health_addr().port());
+
+        let mut client = first.client().await;
+        assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+        assert!(second.client().await.get(b"foo").await.unwrap().is_none());
+        assert_eq!(first.metrics().cmd_set.get(), 1);
+
+        // Open connections are closed, and nothing is accepted any more
+        let addr = first.addr();
+        tokio::time::timeout(Duration::from_secs(5), first.shutdown())
+            .await
+            .unwrap();
+        assert!(client.version().await.is_err());
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        let dir = second.dir().to_path_buf();
+        drop(second);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,504 @@
+//! Read-through from an upstream memcached
+//!
+//! With `[upstream] addr` set, a GET miss is fetched from the upstream
+//! (memcached ASCII `get`), stored locally with `ttl_secs` and returned as
+//! a hit, so petracache can sit in front of a legacy tier during a
+//! migration. Concurrent misses for the same key share one fetch, and the
+//! upstream never turns into an error for the client: a failed, slow or
+//! skipped fetch is just a miss.
+//!
+//! Fetches block on the network; callers run on the blocking pool (the
+//! handler offloads GETs even with `inline_storage` while this is on).
+
+use crate::config::UpstreamConfig;
+use crate::metrics::Metrics;
+use crate::storage::{RocksStorage, StoredValue, current_timestamp};
+use parking_lot::{Condvar, Mutex};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Client for the upstream memcached, shared by all connections
+pub struct Upstream {
+    config: UpstreamConfig,
+    storage: Arc<RocksStorage>,
+    metrics: Arc<Metrics>,
+    /// Upstream values larger than this are treated as misses
+    max_item_size: usize,
+    timeout: Duration,
+    /// Idle connections, reused by later fetches
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+    /// Fetches in flight by key, joined by concurrent misses
+    in_flight: Mutex<HashMap<Vec<u8>, Arc<Flight>>>,
+    /// Upstream requests in flight (bounded by `max_concurrent_fetches`)
+    requests: AtomicUsize,
+}
+
+/// One fetch of one key
+#[derive(Default)]
+struct Flight {
+    state: Mutex<FlightState>,
+    done: Condvar,
+}
+
+#[derive(Default)]
+struct FlightState {
+    finished: bool,
+    value: Option<StoredValue>,
+}
+
+impl Upstream {
+    pub fn new(
+        config: UpstreamConfig,
+        storage: Arc<RocksStorage>,
+        metrics: Arc<Metrics>,
+        max_item_size: usize,
+    ) -> Self {
+        let timeout = Duration::from_millis(config.timeout_ms.max(1));
+        Self {
+            config,
+            storage,
+            metrics,
+            max_item_size,
+            timeout,
+            idle: Mutex::new(Vec::new()),
+            in_flight: Mutex::new(HashMap::new()),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the configuration this client was created with
+    pub fn config(&self) -> &UpstreamConfig {
+        &self.config
+    }
+
+    /// Fetch keys that missed locally, storing what the upstream has
+    ///
+    /// Results are positional like `RocksStorage::get_multi`. A key with a
+    /// fetch already in flight waits for that fetch instead of starting
+    /// another; the rest go to the upstream in one `get`. Blocks for up to
+    /// about `timeout_ms` per round trip.
+    pub fn fetch<K: AsRef<[u8]>>(&self, keys: &[K]) -> Vec<Option<StoredValue>> {
+        let mut flights = Vec::with_capacity(keys.len());
+        // Indexes into `keys` this call fetches itself
+        let mut leading = Vec::new();
+        {
+            let mut in_flight = self.in_flight.lock();
+            for (i, key) in keys.iter().enumerate() {
+                let key = key.as_ref();
+                if let Some(flight) = in_flight.get(key) {
+                    self.metrics.upstream_coalesced.inc();
+                    flights.push(Arc::clone(flight));
+                } else {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(key.to_vec(), Arc::clone(&flight));
+                    flights.push(flight);
+                    leading.push(i);
+                }
+            }
+        }
+
+        if !leading.is_empty() {
+            let fetched = self.fetch_leading(keys, &leading);
+            let mut in_flight = self.in_flight.lock();
+            for (&i, value) in leading.iter().zip(fetched) {
+                in_flight.remove(keys[i].as_ref());
+                *flights[i].state.lock() = FlightState {
+                    finished: true,
+                    value,
+                };
+                flights[i].done.notify_all();
+            }
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        flights
+            .iter()
+            .map(|flight| {
+                let mut state = flight.state.lock();
+                while !state.finished {
+                    if flight.done.wait_until(&mut state, deadline).timed_out() {
+                        break;
+                    }
+                }
+                state.value.clone()
+            })
+            .collect()
+    }
+
+    /// Fetch `keys[i]` for each `i` in `leading` and store the hits locally
+    fn fetch_leading<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        leading: &[usize],
+    ) -> Vec<Option<StoredValue>> {
+        let requested: Vec<&[u8]> = leading.iter().map(|&i| keys[i].as_ref()).collect();
+        let Some(fetched) = self.request(&requested) else {
+            return vec![None; leading.len()];
+        };
+        requested
+            .iter()
+            .zip(fetched)
+            .map(|(key, found)| {
+                let Some((flags, data)) = found else {
+                    self.metrics.upstream_misses.inc();
+                    return None;
+                };
+                self.metrics.upstream_hits.inc();
+                Some(self.store(key, flags, data))
+            })
+            .collect()
+    }
+
+    /// Store a fetched value unless the key was written locally meanwhile,
+    /// returning whatever is stored now
+    fn store(&self, key: &[u8], flags: u32, data: Vec<u8>) -> StoredValue {
+        let expire_at = match self.config.ttl_secs {
+            0 => 0,
+            ttl => current_timestamp().saturating_add(ttl),
+        };
+        let value = StoredValue::with_expire_at(flags, expire_at, data);
+        // `add` never overwrites a newer local write; read back for its CAS
+        let stored = self
+            .storage
+            .add(key, value.clone())
+            .and_then(|_| self.storage.get(key));
+        match stored {
+            Ok(Some(stored)) => stored,
+            Ok(None) => value,
+            Err(e) => {
+                debug!("Not storing upstream value: {}", e);
+                value
+            }
+        }
+    }
+
+    /// Send one `get` for `keys`, returning `(flags, data)` per key, or
+    /// `None` if the request failed or was skipped
+    fn request(&self, keys: &[&[u8]]) -> Option<Vec<Option<(u32, Vec<u8>)>>> {
+        let max = self.config.max_concurrent_fetches.max(1);
+        if self
+            .requests
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_err()
+        {
+            self.metrics.upstream_errors.inc();
+            return None;
+        }
+        let result = self.request_with_retry(keys);
+        self.requests.fetch_sub(1, Ordering::AcqRel);
+
+        match result {
+            Ok(values) => Some(values),
+            Err(e) => {
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) {
+                    self.metrics.upstream_timeouts.inc();
+                } else {
+                    self.metrics.upstream_errors.inc();
+                }
+                warn!("Upstream fetch failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Run a request on an idle connection, or a new one if there is none
+    /// or the idle one turns out to be closed
+    fn request_with_retry(&self, keys: &[&[u8]]) -> io::Result<Vec<Option<(u32, Vec<u8>)>>> {
+        let idle = self.idle.lock().pop();
+        if let Some(mut conn) = idle {
+            match self.roundtrip(&mut conn, keys) {
+                Ok(values) => {
+                    self.release(conn);
+                    return Ok(values);
+                }
+                Err(e) if !is_closed(&e) => return Err(e),
+                Err(_) => {}
+            }
+        }
+        let mut conn = self.connect()?;
+        let values = self.roundtrip(&mut conn, keys)?;
+        self.release(conn);
+        Ok(values)
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let addr = self.config.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "upstream address resolves to nothing",
+            )
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        Ok(BufReader::new(stream))
+    }
+
+    /// Keep a connection for reuse
+    fn release(&self, conn: BufReader<TcpStream>) {
+        let mut idle = self.idle.lock();
+        if idle.len() < self.config.max_concurrent_fetches {
+            idle.push(conn);
+        }
+    }
+
+    /// Write `get <keys>` and read the values up to `END`
+    ///
+    /// Any error leaves the connection in an unknown state; the caller
+    /// drops it.
+    fn roundtrip(
+        &self,
+        conn: &mut BufReader<TcpStream>,
+        keys: &[&[u8]],
+    ) -> io::Result<Vec<Option<(u32, Vec<u8>)>>> {
+        let mut request = b"get".to_vec();
+        for key in keys {
+            request.push(b' ');
+            request.extend_from_slice(key);
+        }
+        request.extend_from_slice(b"\r\n");
+        conn.get_mut().write_all(&request)?;
+
+        let mut values = vec![None; keys.len()];
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if conn.read_until(b'\n', &mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let header = line
+                .strip_suffix(b"\r\n")
+                .ok_or_else(|| invalid("unterminated line"))?;
+            if header == b"END" {
+                return Ok(values);
+            }
+            let (key, flags, len) = parse_value_header(header)?;
+            if len > self.max_item_size {
+                return Err(invalid("value exceeds max_item_size"));
+            }
+            let mut data = vec![0; len + 2];
+            conn.read_exact(&mut data)?;
+            if !data.ends_with(b"\r\n") {
+                return Err(invalid("bad data chunk"));
+            }
+            data.truncate(len);
+            if let Some(i) = keys.iter().position(|k| *k == key) {
+                values[i] = Some((flags, data));
+            }
+        }
+    }
+}
+
+/// Parse `VALUE <key> <flags> <bytes> [<cas>]`
+fn parse_value_header(header: &[u8]) -> io::Result<(&[u8], u32, usize)> {
+    let mut parts = header.split(|&b| b == b' ');
+    if parts.next() != Some(b"VALUE") {
+        return Err(invalid("unexpected reply"));
+    }
+    let key = parts.next().ok_or_else(|| invalid("missing key"))?;
+    let mut number = || {
+        parts
+            .next()
+            .and_then(|part| std::str::from_utf8(part).ok())
+            .ok_or_else(|| invalid("bad VALUE line"))
+    };
+    let flags = number()?.parse().map_err(|_| invalid("bad flags"))?;
+    let len = number()?.parse().map_err(|_| invalid("bad length"))?;
+    Ok((key, flags, len))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Whether an error means the peer closed an idle connection
+fn is_closed(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicU64;
+    use tempfile::TempDir;
+
+    /// Serve `get` from a fixed map, counting requests and sleeping
+    /// `delay` before each reply
+    fn fake_memcached(
+        values: HashMap<Vec<u8>, Vec<u8>>,
+        delay: Duration,
+    ) -> (String, Arc<AtomicU64>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(AtomicU64::new(0));
+        let counter = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                let (values, counter) = (values.clone(), Arc::clone(&counter));
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream);
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(delay);
+                        let mut reply = Vec::new();
+                        for key in line.trim_end().split(' ').skip(1) {
+                            if let Some(value) = values.get(key.as_bytes()) {
+                                reply.extend_from_slice(
+                                    format!("VALUE {key} 7 {}\r\n", value.len()).as_bytes(),
+                                );
+                                reply.extend_from_slice(value);
+                                reply.extend_from_slice(b"\r\n");
+                            }
+                        }
+                        reply.extend_from_slice(b"END\r\n");
+                        if reader.get_mut().write_all(&reply).is_err() {
+                            return;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (addr, requests)
+    }
+
+    fn upstream(tmp_dir: &TempDir, addr: String, timeout_ms: u64) -> Upstream {
+        let storage = RocksStorage::open(&StorageConfig {
+            db_path: tmp_dir.path().join("test_db"),
+            ..StorageConfig::default()
+        })
+        .unwrap();
+        let config = UpstreamConfig {
+            addr,
+            timeout_ms,
+            ..UpstreamConfig::default()
+        };
+        Upstream::new(
+            config,
+            Arc::new(storage),
+            Arc::new(Metrics::new().unwrap()),
+            1024,
+        )
+    }
+
+    #[test]
+    fn test_fetch_fills_local_storage() {
+        let tmp_dir = TempDir::new().unwrap();
+        let values = HashMap::from([(b"a".to_vec(), b"alpha".to_vec())]);
+        let (addr, requests) = fake_memcached(values, Duration::ZERO);
+        let upstream = upstream(&tmp_dir, addr, 1000);
+
+        let fetched = upstream.fetch(&[b"a".as_slice(), b"b"]);
+        let value = fetched[0].as_ref().unwrap();
+        assert_eq!(
+            (value.flags, value.data.as_slice()),
+            (7, b"alpha".as_slice())
+        );
+        assert_ne!(value.cas, 0);
+        assert!(value.expire_at > 0);
+        assert!(fetched[1].is_none());
+        assert_eq!(upstream.metrics.upstream_hits.get(), 1);
+        assert_eq!(upstream.metrics.upstream_misses.get(), 1);
+
+        // Stored locally, with the CAS handed back
+        let stored = upstream.storage.get(b"a").unwrap().unwrap();
+        assert_eq!(stored.cas, value.cas);
+
+        // The connection is reused
+        upstream.fetch(&[b"c"]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(upstream.idle.lock().len(), 1);
+    }
+
+    #[test]
+    fn test_fetch_keeps_newer_local_write() {
+        let tmp_dir = TempDir::new().unwrap();
+        let values = HashMap::from([(b"a".to_vec(), b"upstream".to_vec())]);
+        let (addr, _) = fake_memcached(values, Duration::ZERO);
+        let upstream = upstream(&tmp_dir, addr, 1000);
+        upstream
+            .storage
+            .set(b"a", StoredValue::new(0, 0, b"local".to_vec()))
+            .unwrap();
+
+        let fetched = upstream.fetch(&[b"a"]);
+        assert_eq!(fetched[0].as_ref().unwrap().data, b"local");
+    }
+
+    #[test]
+    fn test_concurrent_misses_share_one_fetch() {
+        let tmp_dir = TempDir::new().unwrap();
+        let values = HashMap::from([(b"hot".to_vec(), b"value".to_vec())]);
+        let (addr, requests) = fake_memcached(values, Duration::from_millis(200));
+        let upstream = Arc::new(upstream(&tmp_dir, addr, 2000));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let upstream = Arc::clone(&upstream);
+                std::thread::spawn(move || upstream.fetch(&[b"hot"]))
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap()[0].as_ref().unwrap().data, b"value");
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(upstream.metrics.upstream_coalesced.get(), 7);
+        assert!(upstream.in_flight.lock().is_empty());
+    }
+
+    #[test]
+    fn test_upstream_failures_are_misses() {
+        let tmp_dir = TempDir::new().unwrap();
+        let (addr, _) = fake_memcached(HashMap::new(), Duration::from_millis(500));
+        let slow = upstream(&tmp_dir, addr, 50);
+        assert!(slow.fetch(&[b"a"])[0].is_none());
+        assert_eq!(slow.metrics.upstream_timeouts.get(), 1);
+
+        // Nothing listens on a just-closed port
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let tmp_dir = TempDir::new().unwrap();
+        let down = upstream(&tmp_dir, addr, 50);
+        assert!(down.fetch(&[b"a"])[0].is_none());
+        assert_eq!(down.metrics.upstream_errors.get(), 1);
+    }
+
+    #[test]
+    fn test_parse_value_header() {
+        assert_eq!(
+            parse_value_header(b"VALUE key 5 10").unwrap(),
+            (b"key".as_slice(), 5, 10)
+        );
+        assert_eq!(
+            parse_value_header(b"VALUE key 0 3 99").unwrap(),
+            (b"key".as_slice(), 0, 3)
+        );
+        assert!(parse_value_header(b"SERVER_ERROR oops").is_err());
+        assert!(parse_value_header(b"VALUE key x 3").is_err());
+        assert!(parse_value_header(b"VALUE key 0").is_err());
+    }
+}
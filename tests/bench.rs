@@ -0,0 +1,93 @@
+//! `petracache-bench` against a `TestServer`
+
+use petracache::testing::TestServer;
+use tokio::process::Command;
+
+/// Run the benchmark against `server`; returns the exit code and stdout
+async fn bench(server: &TestServer, args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_petracache-bench"))
+        .arg("--server")
+        .arg(server.addr().to_string())
+        .args(["--duration", "1s"])
+        .args(args)
+        .output()
+        .await
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (output.status.code().unwrap(), stdout)
+}
+
+#[tokio::test]
+async fn test_verify_with_csv() {
+    let server = TestServer::spawn().await;
+    let csv = server.dir().join("bench.csv");
+
+    let (code, stdout) = bench(
+        &server,
+        &[
+            "--connections",
+            "4",
+            "--pipeline",
+            "8",
+            "--keys",
+            "100",
+            "--key-dist",
+            "zipfian",
+            "--ratio",
+            "1:1",
+            "--value-size",
+            "uniform:16-4KiB",
+            "--ttl",
+            "1h",
+            "--verify",
+            "--csv",
+            csv.to_str().unwrap(),
+        ],
+    )
+    .await;
+    assert_eq!(code, 0, "{stdout}");
+    assert!(stdout.contains("\nerrors                  0\n"), "{stdout}");
+    assert!(
+        stdout.contains(" 0 stale, 0 corrupt, 0 missing"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("p99.9"), "{stdout}");
+    assert!(server.metrics().cmd_set.get() > 0);
+
+    let csv = std::fs::read_to_string(csv).unwrap();
+    let mut lines = csv.lines();
+    assert!(lines.next().unwrap().starts_with("second,ops,gets,sets,"));
+    let row: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(row.len(), 12);
+    assert_eq!(row[0], "1");
+    assert!(row[1].parse::<u64>().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn test_verify_catches_lost_writes() {
+    let server = TestServer::spawn().await;
+    // A read-only server refuses every set: the run fails
+    server.server().set_read_only(true);
+
+    let (code, stdout) = bench(&server, &["-c", "2", "--ratio", "1:1", "--verify"]).await;
+    assert_eq!(code, 1, "{stdout}");
+    assert!(
+        !stdout.contains("\nerrors                  0\n"),
+        "{stdout}"
+    );
+}
+
+#[tokio::test]
+async fn test_connection_error() {
+    let server = TestServer::spawn().await;
+    let addr = server.addr().to_string();
+    server.shutdown().await;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_petracache-bench"))
+        .args(["--server", &addr, "--duration", "1s"])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&addr));
+}
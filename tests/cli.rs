@@ -0,0 +1,144 @@
+//! `petracache-cli` against a `TestServer`
+
+use petracache::testing::TestServer;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+/// Run the CLI against `server`, feeding `stdin`; returns the exit code and
+/// stdout
+async fn cli(server: &TestServer, args: &[&str], stdin: &[u8]) -> (i32, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_petracache-cli"))
+        .arg("--server")
+        .arg(server.addr().to_string())
+        .arg("--admin-addr")
+        .arg(server.health_addr().to_string())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut input = child.stdin.take().unwrap();
+    input.write_all(stdin).await.unwrap();
+    drop(input);
+    let output = child.wait_with_output().await.unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    (output.status.code().unwrap(), stdout)
+}
+
+fn json(stdout: &str) -> serde_json::Value {
+    serde_json::from_str(stdout).unwrap_or_else(|e| panic!("{e}: {stdout:?}"))
+}
+
+#[tokio::test]
+async fn test_get_set_delete() {
+    let server = TestServer::spawn().await;
+
+    let (code, stdout) = cli(
+        &server,
+        &["set", "foo", "--flags", "5", "--ttl", "1h"],
+        b"bar",
+    )
+    .await;
+    assert_eq!((code, stdout.as_str()), (0, "STORED\n"));
+    let value = server.storage().get(b"foo").unwrap().unwrap();
+    assert_eq!((value.flags, &value.data[..]), (5, &b"bar"[..]));
+    assert!(value.expire_at > 0);
+
+    // Text output is the raw value
+    assert_eq!(
+        cli(&server, &["get", "foo"], b"").await,
+        (0, "bar".to_string())
+    );
+    let (code, stdout) = cli(&server, &["get", "foo", "--json"], b"").await;
+    assert_eq!(code, 0);
+    assert_eq!(
+        json(&stdout),
+        serde_json::json!({ "key": "foo", "found": true, "flags": 5, "value": "bar" })
+    );
+
+    let (code, stdout) = cli(&server, &["--json", "delete", "foo"], b"").await;
+    assert_eq!((code, json(&stdout)["deleted"].as_bool()), (0, Some(true)));
+    // Misses exit with 1
+    assert_eq!(
+        cli(&server, &["delete", "foo"], b"").await,
+        (1, "NOT_FOUND\n".to_string())
+    );
+    let (code, stdout) = cli(&server, &["get", "foo", "--json"], b"").await;
+    assert_eq!((code, json(&stdout)["found"].as_bool()), (1, Some(false)));
+}
+
+#[tokio::test]
+async fn test_set_from_file() {
+    let server = TestServer::spawn().await;
+    let path = server.dir().join("value.bin");
+    std::fs::write(&path, [0u8, 1, 2, 255]).unwrap();
+
+    let (code, stdout) = cli(
+        &server,
+        &["set", "blob", "--file", path.to_str().unwrap(), "--json"],
+        b"",
+    )
+    .await;
+    assert_eq!(code, 0);
+    assert_eq!(json(&stdout)["bytes"], 4);
+    let value = server.storage().get(b"blob").unwrap().unwrap();
+    assert_eq!(value.data, [0, 1, 2, 255]);
+}
+
+#[tokio::test]
+async fn test_stats_and_ping() {
+    let server = TestServer::spawn().await;
+
+    let (code, stdout) = cli(&server, &["stats", "--json"], b"").await;
+    assert_eq!(code, 0);
+    let stats = json(&stdout);
+    assert_eq!(stats["cmd_set"], "0");
+    let (code, stdout) = cli(&server, &["stats", "settings"], b"").await;
+    assert_eq!(code, 0);
+    assert!(stdout.contains("\nread_only no\n"), "{stdout}");
+
+    let (code, stdout) = cli(&server, &["ping", "--json"], b"").await;
+    assert_eq!(code, 0);
+    let ping = json(&stdout);
+    assert_eq!(
+        ping["version"],
+        concat!("petracache ", env!("CARGO_PKG_VERSION"))
+    );
+    assert!(ping["latency_ms"].as_f64().unwrap() >= 0.0);
+}
+
+#[tokio::test]
+async fn test_admin() {
+    let server = TestServer::spawn().await;
+
+    let (code, stdout) = cli(&server, &["admin", "compact", "--json"], b"").await;
+    assert_eq!(code, 0);
+    assert_eq!(json(&stdout)["status"], "compacted");
+    let (code, stdout) = cli(&server, &["admin", "compact"], b"").await;
+    assert_eq!(code, 0);
+    assert!(stdout.contains("status: compacted\n"), "{stdout}");
+
+    // Refused tasks fail: no such namespace, backups not configured
+    let (code, stdout) = cli(&server, &["admin", "flush", "users", "--json"], b"").await;
+    assert_eq!(code, 2);
+    assert!(json(&stdout)["error"].as_str().unwrap().contains("HTTP "));
+    assert_eq!(cli(&server, &["admin", "backup"], b"").await.0, 2);
+}
+
+#[tokio::test]
+async fn test_connection_errors() {
+    let server = TestServer::spawn().await;
+    let addr = server.addr().to_string();
+    server.shutdown().await;
+
+    let output = Command::new(env!("CARGO_BIN_EXE_petracache-cli"))
+        .args(["--server", &addr, "--json", "ping"])
+        .output()
+        .await
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(json(&stdout)["error"].as_str().unwrap().contains(&addr));
+}
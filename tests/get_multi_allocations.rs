@@ -0,0 +1,61 @@
+//! Heap allocations of `RocksStorage::get_multi`
+//!
+//! Its own test binary: the counting allocator below replaces the global
+//! allocator for every test in the binary it is linked into.
+
+#![allow(unsafe_code)]
+
+use petracache::config::StorageConfig;
+use petracache::storage::{RocksStorage, StoredValue};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use tempfile::TempDir;
+
+/// Counts heap allocations made by the current thread
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_get_multi_does_not_copy_keys() {
+    const KEYS: usize = 100;
+    let tmp_dir = TempDir::new().unwrap();
+    let storage = RocksStorage::open(&StorageConfig {
+        db_path: tmp_dir.path().join("test_db"),
+        ..StorageConfig::default()
+    })
+    .unwrap();
+    let keys: Vec<Vec<u8>> = (0..KEYS).map(|i| format!("key:{i}").into_bytes()).collect();
+    for key in &keys {
+        storage
+            .set(key, StoredValue::new(0, 0, b"value".to_vec()))
+            .unwrap();
+    }
+
+    let before = ALLOCATIONS.with(Cell::get);
+    let results = storage.get_multi(&keys).unwrap();
+    let allocations = ALLOCATIONS.with(Cell::get) - before;
+    assert!(results.iter().all(Option::is_some));
+    // RocksDB's lookup and the decoded value are all that's left per key;
+    // copying keys into the results added two more
+    assert!(
+        allocations <= 3 * KEYS + 16,
+        "{allocations} allocations for {KEYS} keys"
+    );
+}
@@ -0,0 +1,217 @@
+//! End-to-end protocol behavior against a `TestServer`
+
+use petracache::client::{Client, ClientError, Reply};
+use petracache::config::ServerConfig;
+use petracache::protocol::Command;
+use petracache::testing::TestServer;
+use std::borrow::Cow;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Write `request` as is and read until the reply ends with `terminator`,
+/// for requests the client refuses to encode
+async fn raw(client: &mut Client<TcpStream>, request: &[u8], terminator: &[u8]) -> Vec<u8> {
+    let stream = client.get_mut();
+    stream.write_all(request).await.unwrap();
+    let mut buf = Vec::new();
+    while !buf.ends_with(terminator) {
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_buf(&mut buf))
+            .await
+            .unwrap_or_else(|_| panic!("no {terminator:?} in {buf:?}"));
+        assert_ne!(read.unwrap(), 0);
+    }
+    buf
+}
+
+#[tokio::test]
+async fn test_set_get_delete() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+
+    assert_eq!(client.get(b"foo").await.unwrap(), None);
+    assert!(client.set(b"foo", 7, 0, b"bar").await.unwrap());
+    let value = client.get(b"foo").await.unwrap().unwrap();
+    assert_eq!((value.flags, &value.data[..]), (7, &b"bar"[..]));
+
+    assert!(client.set(b"foo", 0, 0, b"baz").await.unwrap());
+    assert_eq!(client.get(b"foo").await.unwrap().unwrap().data, b"baz");
+    assert!(client.delete(b"foo").await.unwrap());
+    assert!(!client.delete(b"foo").await.unwrap());
+    assert_eq!(client.get(b"foo").await.unwrap(), None);
+    assert_eq!(server.metrics().cmd_set.get(), 2);
+}
+
+#[tokio::test]
+async fn test_version() {
+    let server = TestServer::spawn().await;
+    let version = server.client().await.version().await.unwrap();
+    assert_eq!(version, concat!("petracache ", env!("CARGO_PKG_VERSION")));
+}
+
+#[tokio::test]
+async fn test_utf8_key_roundtrip() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+    let key = "clé-键".as_bytes();
+
+    assert!(client.set(key, 0, 0, b"hi").await.unwrap());
+    let value = client.get(key).await.unwrap().unwrap();
+    assert_eq!((&value.key[..], &value.data[..]), (key, &b"hi"[..]));
+    assert!(client.delete(key).await.unwrap());
+    assert_eq!(client.get(key).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_negative_exptime_stores_expired() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+
+    assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+    assert!(client.set(b"foo", 0, -1, b"baz").await.unwrap());
+    assert_eq!(client.get(b"foo").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_unknown_command_keeps_connection_usable() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+
+    assert_eq!(raw(&mut client, b"bogus\r\n", b"\r\n").await, b"ERROR\r\n");
+    assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+    assert_eq!(client.get(b"foo").await.unwrap().unwrap().data, b"bar");
+}
+
+#[tokio::test]
+async fn test_bad_data_chunk_keeps_connection_in_sync() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+
+    assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+    assert_eq!(
+        raw(&mut client, b"set foo 0 0 3\r\nabcde", b"\r\n").await,
+        b"CLIENT_ERROR bad data chunk\r\n"
+    );
+
+    // Nothing of the bad block is left behind to be parsed as a command
+    assert_eq!(client.get(b"foo").await.unwrap().unwrap().data, b"bar");
+}
+
+#[tokio::test]
+async fn test_limits_keep_connection_usable() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+    let limits = ServerConfig::default();
+
+    let keys: Vec<Vec<u8>> = (0..=limits.max_get_keys)
+        .map(|i| format!("k{i}").into_bytes())
+        .collect();
+    let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+    assert!(matches!(
+        client.get_multi(&keys).await,
+        Err(ClientError::Server(Reply::ClientError(message))) if message == "too many keys"
+    ));
+    assert!(client.set(b"foo", 0, 0, b"bar").await.unwrap());
+
+    // An unterminated line is rejected once it outgrows the limit and
+    // skipped up to its eventual CRLF
+    let line = vec![b'x'; limits.max_command_line_bytes + 1];
+    assert_eq!(
+        raw(&mut client, &line, b"\r\n").await,
+        b"CLIENT_ERROR line too long\r\n"
+    );
+    client.get_mut().write_all(b"xxxx\r\n").await.unwrap();
+    assert_eq!(client.get(b"foo").await.unwrap().unwrap().data, b"bar");
+}
+
+#[tokio::test]
+async fn test_noreply_still_sends_errors() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+
+    client.set_noreply(b"foo", 0, 0, b"bar").await.unwrap();
+    let incr = Command::Incr {
+        key: Cow::Borrowed(b"foo"),
+        delta: 1,
+        noreply: true,
+    };
+    assert_eq!(client.request(&incr).await.unwrap(), None);
+
+    // The error for the noreply incr arrives ahead of the get's values
+    client
+        .send(&Command::Get {
+            keys: vec![Cow::Borrowed(b"foo")],
+        })
+        .unwrap();
+    assert_eq!(
+        client.recv().await.unwrap(),
+        Reply::ClientError("cannot increment or decrement non-numeric value".to_string())
+    );
+    // ...which shifts every later reply by one
+    client.send(&Command::Version).unwrap();
+    match client.recv().await.unwrap() {
+        Reply::Values(values) => assert_eq!(values[0].data, b"bar"),
+        reply => panic!("unexpected {reply:?}"),
+    }
+    assert!(matches!(
+        client.recv().await,
+        Err(ClientError::NothingPending)
+    ));
+}
+
+#[tokio::test]
+async fn test_pipelined_requests() {
+    let server = TestServer::spawn().await;
+    let mut client = server.client().await;
+
+    for i in 0..64u32 {
+        let key = format!("key:{i}").into_bytes();
+        client
+            .send(&Command::Set {
+                key: Cow::Borrowed(&key),
+                flags: i,
+                exptime: 0,
+                data: Cow::Borrowed(b"value"),
+                noreply: false,
+            })
+            .unwrap();
+        client
+            .send(&Command::Get {
+                keys: vec![Cow::Owned(key)],
+            })
+            .unwrap();
+    }
+    client.flush().await.unwrap();
+    for i in 0..64 {
+        assert_eq!(client.recv().await.unwrap(), Reply::Stored);
+        match client.recv().await.unwrap() {
+            Reply::Values(values) => assert_eq!(values[0].flags, i),
+            reply => panic!("unexpected {reply:?}"),
+        }
+    }
+    assert_eq!(server.metrics().cmd_set.get(), 64);
+}
+
+#[tokio::test]
+async fn test_binary_oversized_value_refused_from_header() {
+    let server = TestServer::spawn().await;
+    let mut stream = TcpStream::connect(server.addr()).await.unwrap();
+
+    // A set announcing a 4 GiB body: answered and closed on the header
+    // alone, without waiting for (or buffering) the body
+    let mut header = vec![0x80, 0x01, 0, 3, 8, 0, 0, 0];
+    header.extend_from_slice(&u32::MAX.to_be_bytes());
+    header.extend_from_slice(&42u32.to_be_bytes());
+    header.extend_from_slice(&[0; 8]);
+    stream.write_all(&header).await.unwrap();
+    let mut reply = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&reply[..2], [0x81, 0x01]);
+    // Status VALUE_TOO_LARGE, opaque echoed
+    assert_eq!(&reply[6..8], [0, 3]);
+    assert_eq!(&reply[12..16], 42u32.to_be_bytes());
+    assert_eq!(server.metrics().protocol_errors.get(), 1);
+}